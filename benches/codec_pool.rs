@@ -0,0 +1,69 @@
+//! Throughput of encoding and decoding batches of messages as the mixnet task does, on
+//! the calling task and on the codec worker pool, at the batch sizes it hands the pool.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use rust_libp2p_nym::bench::{CodecPool, DataMessage, MAX_CODEC_BATCH};
+use tokio::runtime::Runtime;
+
+const PAYLOAD_SIZE: usize = 16 * 1024;
+const BATCH_SIZES: [usize; 3] = [2, MAX_CODEC_BATCH / 4, MAX_CODEC_BATCH];
+const WORKERS: [Option<usize>; 3] = [None, Some(2), Some(4)];
+
+fn batch(size: usize) -> Vec<DataMessage> {
+    (0..size)
+        .map(|_| DataMessage::new(Bytes::from(vec![7u8; PAYLOAD_SIZE])))
+        .collect()
+}
+
+fn label(workers: Option<usize>, size: usize) -> String {
+    match workers {
+        Some(workers) => format!("{workers}_workers/{size}"),
+        None => format!("inline/{size}"),
+    }
+}
+
+fn encode(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("pool_encode");
+    for workers in WORKERS {
+        let pool = CodecPool::new(workers);
+        for size in BATCH_SIZES {
+            group.throughput(Throughput::Bytes((size * PAYLOAD_SIZE) as u64));
+            group.bench_function(BenchmarkId::from_parameter(label(workers, size)), |b| {
+                b.to_async(&rt).iter_batched(
+                    || batch(size),
+                    |messages| pool.encode(messages),
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("pool_decode");
+    for workers in WORKERS {
+        let pool = CodecPool::new(workers);
+        for size in BATCH_SIZES {
+            let bytes: Vec<Vec<u8>> = batch(size)
+                .iter()
+                .map(|message| message.to_bytes().to_vec())
+                .collect();
+            group.throughput(Throughput::Bytes((size * PAYLOAD_SIZE) as u64));
+            group.bench_function(BenchmarkId::from_parameter(label(workers, size)), |b| {
+                b.to_async(&rt).iter_batched(
+                    || bytes.clone(),
+                    |messages| pool.decode(messages),
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
@@ -0,0 +1,35 @@
+//! Throughput of encoding and decoding the messages carrying substream data.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_libp2p_nym::bench::DataMessage;
+
+const PAYLOAD_SIZES: [usize; 4] = [64, 1024, 16 * 1024, 256 * 1024];
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for size in PAYLOAD_SIZES {
+        let message = DataMessage::new(Bytes::from(vec![7u8; size]));
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &message, |b, message| {
+            b.iter(|| message.to_bytes())
+        });
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for size in PAYLOAD_SIZES {
+        let bytes = DataMessage::new(Bytes::from(vec![7u8; size])).to_bytes();
+        group.throughput(Throughput::Bytes(size as u64));
+        // cloning Bytes only bumps a reference count
+        group.bench_with_input(BenchmarkId::from_parameter(size), &bytes, |b, bytes| {
+            b.iter(|| DataMessage::decode(bytes.clone()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
@@ -0,0 +1,161 @@
+//! Throughput of a stream between two transports over the in-memory mixnet, and the
+//! latency of opening a substream on an established connection.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::future::{self, poll_fn};
+use futures::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use libp2p::core::{
+    muxing::StreamMuxer,
+    transport::{DialOpts, PortUse, TransportEvent},
+    Endpoint, Multiaddr, Transport,
+};
+use libp2p_identity::Keypair;
+use rust_libp2p_nym::config::NymTransportConfig;
+use rust_libp2p_nym::memory::{InMemoryConfig, InMemoryMixnet};
+use rust_libp2p_nym::stream::NymStreamTransport;
+use rust_libp2p_nym::transport::NymTransport;
+use std::fmt::Debug;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::runtime::Runtime;
+
+const CHUNK_SIZES: [usize; 3] = [1024, 16 * 1024, 256 * 1024];
+
+async fn new_transport(mixnet: &InMemoryMixnet) -> (NymTransport, Multiaddr) {
+    let transport = NymTransport::new_with_backend(
+        mixnet.client(),
+        Keypair::generate_ed25519(),
+        NymTransportConfig::default(),
+    )
+    .await
+    .unwrap();
+    let listen_addr = transport.listen_addr().clone();
+    (transport, listen_addr)
+}
+
+/// drive keeps a transport handling inbound mixnet messages for its connections.
+async fn drive<T: Transport + Unpin>(mut transport: T) {
+    loop {
+        poll_fn(|cx| Pin::new(&mut transport).poll(cx)).await;
+    }
+}
+
+/// connect dials `listener` from `dialer`, and returns both ends of the connection
+/// once it's established, leaving the transports driven in the background.
+async fn connect<T>(mut dialer: T, mut listener: T, addr: Multiaddr) -> (T::Output, T::Output)
+where
+    T: Transport + Unpin + Send + 'static,
+    T::Error: Debug,
+{
+    let dial = dialer
+        .dial(
+            addr,
+            DialOpts {
+                role: Endpoint::Dialer,
+                port_use: PortUse::Reuse,
+            },
+        )
+        .unwrap();
+    tokio::spawn(drive(dialer));
+    let upgrade = loop {
+        if let TransportEvent::Incoming { upgrade, .. } =
+            poll_fn(|cx| Pin::new(&mut listener).poll(cx)).await
+        {
+            break upgrade;
+        }
+    };
+    tokio::spawn(drive(listener));
+    let (dialed, accepted) = future::join(dial, upgrade).await;
+    (dialed.unwrap(), accepted.unwrap())
+}
+
+/// poll_connection handles the messages that have arrived on a connection.
+fn poll_connection<M: StreamMuxer + Unpin>(connection: &mut M, cx: &mut Context<'_>) {
+    while let Poll::Ready(Ok(_)) = Pin::new(&mut *connection).poll(cx) {}
+}
+
+fn loopback(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (_mixnet, mut dialer, mut listener) = rt.block_on(async {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let (dialer, _) = new_transport(&mixnet).await;
+        let (listener, addr) = new_transport(&mixnet).await;
+        let (dialer, listener) = connect(
+            NymStreamTransport::new(dialer),
+            NymStreamTransport::new(listener),
+            addr,
+        )
+        .await;
+        (mixnet, dialer, listener)
+    });
+
+    let mut group = c.benchmark_group("loopback");
+    for size in CHUNK_SIZES {
+        let chunk = vec![7u8; size];
+        let mut buf = vec![0u8; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter_custom(|iters| {
+                rt.block_on(async {
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        let (written, read) =
+                            future::join(dialer.write_all(&chunk), listener.read_exact(&mut buf))
+                                .await;
+                        written.unwrap();
+                        read.unwrap();
+                    }
+                    start.elapsed()
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+fn substream_open(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (_mixnet, (_, mut dialer), (_, mut listener)) = rt.block_on(async {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let (dialer, _) = new_transport(&mixnet).await;
+        let (listener, addr) = new_transport(&mixnet).await;
+        let (dialer, listener) = connect(dialer, listener, addr).await;
+        (mixnet, dialer, listener)
+    });
+
+    // from opening a substream until the listener has read the first byte written to it
+    c.bench_function("substream_open", |b| {
+        b.iter_custom(|iters| {
+            rt.block_on(async {
+                let start = Instant::now();
+                for _ in 0..iters {
+                    let mut outbound = poll_fn(|cx| Pin::new(&mut dialer).poll_outbound(cx))
+                        .await
+                        .unwrap();
+                    outbound.write_all(b"x").await.unwrap();
+                    let mut inbound = poll_fn(|cx| {
+                        poll_connection(&mut dialer, cx);
+                        poll_connection(&mut listener, cx);
+                        Pin::new(&mut listener).poll_inbound(cx)
+                    })
+                    .await
+                    .unwrap();
+                    let mut buf = [0u8; 1];
+                    poll_fn(|cx| {
+                        poll_connection(&mut dialer, cx);
+                        poll_connection(&mut listener, cx);
+                        Pin::new(&mut inbound).poll_read(cx, &mut buf)
+                    })
+                    .await
+                    .unwrap();
+                    outbound.close().await.unwrap();
+                }
+                start.elapsed()
+            })
+        })
+    });
+}
+
+criterion_group!(benches, loopback, substream_open);
+criterion_main!(benches);
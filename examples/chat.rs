@@ -8,12 +8,11 @@ use libp2p::{
 use libp2p::{Multiaddr, SwarmBuilder};
 use libp2p_identity::Keypair;
 use log::{info, LevelFilter};
-use rust_libp2p_nym::transport::NymTransport;
+use rust_libp2p_nym::{presets, transport::NymTransport};
 use std::{
     collections::hash_map::DefaultHasher,
     error::Error,
     hash::{Hash, Hasher},
-    time::Duration,
 };
 use tokio::{io, io::AsyncBufReadExt, select};
 
@@ -47,9 +46,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 gossipsub::MessageId::from(s.finish().to_string())
             };
 
-            // Set a custom gossipsub configuration
-            let gossipsub_config = gossipsub::ConfigBuilder::default()
-                .heartbeat_interval(Duration::from_secs(10)) // This is set to aid debugging by not cluttering the log space
+            // Start from gossipsub's configuration tuned for the mixnet's latency
+            let gossipsub_config = presets::gossipsub_config_for_nym()
                 .validation_mode(gossipsub::ValidationMode::Strict) // This sets the kind of message validation. The default is Strict (enforce message
                 // signing)
                 .message_id_fn(message_id_fn) // content-address messages. No two messages of the same content will be propagated.
@@ -0,0 +1,276 @@
+// Copyright TODO based on the rust libp2p examples check how to smush 2 together / if this is necessary
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{
+    request_response::{self, ProtocolSupport},
+    swarm::SwarmEvent,
+    Multiaddr, StreamProtocol, SwarmBuilder,
+};
+use libp2p_identity::Keypair;
+use log::{info, LevelFilter};
+use rust_libp2p_nym::{presets, transport::NymTransport};
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// the number of bytes fetched per request. Each chunk is larger than a sphinx packet,
+/// so it's fragmented by the transport, and several are in flight at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// the number of chunk requests the fetcher keeps in flight.
+const WINDOW: u64 = 4;
+
+const PROTOCOL: StreamProtocol = StreamProtocol::new("/nym-file-transfer/1.0.0");
+
+/// ChunkRequest asks for the chunk of the served file starting at the given offset.
+#[derive(Debug)]
+struct ChunkRequest(u64);
+
+/// Chunk is the piece of the served file at `offset`; it's empty past the end of the file.
+#[derive(Debug)]
+struct Chunk {
+    file_len: u64,
+    offset: u64,
+    data: Vec<u8>,
+}
+
+/// FileCodec frames requests as a u64 offset, and responses as the u64 file length
+/// and offset followed by the u32 length of the chunk and the chunk itself.
+#[derive(Clone, Default)]
+struct FileCodec;
+
+#[async_trait]
+impl request_response::Codec for FileCodec {
+    type Protocol = StreamProtocol;
+    type Request = ChunkRequest;
+    type Response = Chunk;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<ChunkRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut offset = [0u8; 8];
+        io.read_exact(&mut offset).await?;
+        Ok(ChunkRequest(u64::from_be_bytes(offset)))
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Chunk>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut file_len = [0u8; 8];
+        io.read_exact(&mut file_len).await?;
+        let mut offset = [0u8; 8];
+        io.read_exact(&mut offset).await?;
+        let mut len = [0u8; 4];
+        io.read_exact(&mut len).await?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "chunk too large",
+            ));
+        }
+        let mut data = vec![0u8; len];
+        io.read_exact(&mut data).await?;
+        Ok(Chunk {
+            file_len: u64::from_be_bytes(file_len),
+            offset: u64::from_be_bytes(offset),
+            data,
+        })
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        ChunkRequest(offset): ChunkRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&offset.to_be_bytes()).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        chunk: Chunk,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&chunk.file_len.to_be_bytes()).await?;
+        io.write_all(&chunk.offset.to_be_bytes()).await?;
+        io.write_all(&(chunk.data.len() as u32).to_be_bytes())
+            .await?;
+        io.write_all(&chunk.data).await?;
+        io.close().await
+    }
+}
+
+/// read_chunk reads the chunk of `file` starting at `offset`.
+fn read_chunk(file: &mut File, offset: u64) -> io::Result<Chunk> {
+    let file_len = file.metadata()?.len();
+    let mut data = vec![0u8; CHUNK_SIZE.min(file_len.saturating_sub(offset) as usize)];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(Chunk {
+        file_len,
+        offset,
+        data,
+    })
+}
+
+/// Fetch is the progress of a file being fetched.
+struct Fetch {
+    out: File,
+    next_offset: u64,
+    received: u64,
+    file_len: Option<u64>,
+    started: Instant,
+}
+
+impl Fetch {
+    fn is_done(&self) -> bool {
+        self.file_len == Some(self.received)
+    }
+
+    fn print_progress(&self) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let file_len = self.file_len.unwrap_or_default();
+        println!(
+            "{}/{} bytes ({:.1}%) in {:.1}s, {:.1} KiB/s",
+            self.received,
+            file_len,
+            100.0 * self.received as f64 / file_len.max(1) as f64,
+            elapsed,
+            self.received as f64 / 1024.0 / elapsed.max(f64::EPSILON),
+        );
+    }
+}
+
+fn usage() -> Box<dyn Error> {
+    "usage: file_transfer serve <path> | file_transfer fetch <multiaddr> <output path>".into()
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    pretty_env_logger::formatted_timed_builder()
+        .filter_level(LevelFilter::Info)
+        .init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (mut served, mut fetch, remote) = match args.as_slice() {
+        [mode, path] if mode == "serve" => (Some(File::open(path)?), None, None),
+        [mode, addr, path] if mode == "fetch" => {
+            let fetch = Fetch {
+                out: File::create(PathBuf::from(path))?,
+                next_offset: 0,
+                received: 0,
+                file_len: None,
+                started: Instant::now(),
+            };
+            (None, Some(fetch), Some(addr.parse::<Multiaddr>()?))
+        }
+        _ => return Err(usage()),
+    };
+
+    info!("Running `file_transfer` example using NymTransport");
+    let local_key = Keypair::generate_ed25519();
+    let client = nym_sdk::mixnet::MixnetClient::connect_new().await?;
+    let transport = NymTransport::new(client, local_key.clone()).await?;
+
+    let mut swarm = SwarmBuilder::with_existing_identity(local_key)
+        .with_tokio()
+        .with_other_transport(|_| transport)?
+        .with_behaviour(|_| {
+            request_response::Behaviour::with_codec(
+                FileCodec,
+                [(PROTOCOL, ProtocolSupport::Full)],
+                // every chunk crosses the mixnet twice, as a request and a response
+                request_response::Config::default().with_request_timeout(Duration::from_secs(120)),
+            )
+        })?
+        .with_swarm_config(presets::swarm_config_for_nym)
+        .build();
+
+    if let Some(remote) = remote {
+        swarm.dial(remote.clone())?;
+        info!("Dialed {remote}");
+    }
+
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::NewListenAddr { address, .. } if served.is_some() => {
+                println!("Serving on {address}")
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                if let Some(fetch) = &mut fetch {
+                    fetch.started = Instant::now();
+                    for _ in 0..WINDOW {
+                        swarm
+                            .behaviour_mut()
+                            .send_request(&peer_id, ChunkRequest(fetch.next_offset));
+                        fetch.next_offset += CHUNK_SIZE as u64;
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(request_response::Event::Message { peer, message, .. }) => {
+                match message {
+                    request_response::Message::Request {
+                        request: ChunkRequest(offset),
+                        channel,
+                        ..
+                    } => {
+                        let Some(file) = &mut served else {
+                            continue;
+                        };
+                        let chunk = read_chunk(file, offset)?;
+                        info!(
+                            "Sending {} bytes at offset {offset} to {peer}",
+                            chunk.data.len()
+                        );
+                        // the fetcher may have given up on the request
+                        swarm.behaviour_mut().send_response(channel, chunk).ok();
+                    }
+                    request_response::Message::Response { response, .. } => {
+                        let Some(fetch) = &mut fetch else {
+                            continue;
+                        };
+                        fetch.file_len = Some(response.file_len);
+                        if !response.data.is_empty() {
+                            // chunks may arrive out of order, so each is written in place
+                            fetch.out.seek(SeekFrom::Start(response.offset))?;
+                            fetch.out.write_all(&response.data)?;
+                            fetch.received += response.data.len() as u64;
+                        }
+                        fetch.print_progress();
+                        if fetch.is_done() {
+                            println!("Fetched {} bytes", fetch.received);
+                            return Ok(());
+                        }
+                        if fetch.next_offset < response.file_len {
+                            swarm
+                                .behaviour_mut()
+                                .send_request(&peer, ChunkRequest(fetch.next_offset));
+                            fetch.next_offset += CHUNK_SIZE as u64;
+                        }
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(request_response::Event::OutboundFailure { error, .. }) => {
+                return Err(format!("chunk request failed: {error}").into());
+            }
+            SwarmEvent::Behaviour(event) => info!("{event:?}"),
+            _ => {}
+        }
+    }
+}
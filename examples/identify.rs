@@ -0,0 +1,102 @@
+// Copyright TODO based on the rust libp2p examples check how to smush 2 together / if this is necessary
+
+use futures::prelude::*;
+use libp2p::{
+    identify, ping,
+    swarm::{NetworkBehaviour, SwarmEvent},
+    Multiaddr, SwarmBuilder,
+};
+use libp2p_identity::Keypair;
+use log::{info, LevelFilter};
+use rust_libp2p_nym::address::{address_translation, is_nym_multiaddr};
+use rust_libp2p_nym::config::{AnonymityMode, NymTransportConfig};
+use rust_libp2p_nym::presets;
+use rust_libp2p_nym::transport::NymTransport;
+use std::error::Error;
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    identify: identify::Behaviour,
+    ping: ping::Behaviour,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    pretty_env_logger::formatted_timed_builder()
+        .filter_level(LevelFilter::Info)
+        .init();
+
+    // the swarm and the transport have to share an identity, since identify checks
+    // that the key it's sent belongs to the peer on the other end of the connection.
+    let local_key = Keypair::generate_ed25519();
+
+    info!("Running `identify` example using NymTransport");
+    // dials have to expose our address for the remote to identify us: otherwise they
+    // use a throwaway identity, and only we learn about the remote.
+    let config = NymTransportConfig::default().with_anonymity(AnonymityMode::ExposeSelfAddress);
+    let client = nym_sdk::mixnet::MixnetClient::connect_new().await?;
+    let transport = NymTransport::new_with_config(client, local_key.clone(), config).await?;
+
+    let mut swarm = SwarmBuilder::with_existing_identity(local_key)
+        .with_tokio()
+        .with_other_transport(|_| transport)?
+        .with_behaviour(|key| Behaviour {
+            identify: identify::Behaviour::new(presets::identify_config_for_nym(
+                "/nym-identify-example/1.0.0".to_string(),
+                key.public(),
+            )),
+            ping: ping::Behaviour::new(presets::ping_config_for_nym()),
+        })?
+        .with_swarm_config(presets::swarm_config_for_nym)
+        .build();
+
+    // Dial the peer identified by the multi-address given as the second
+    // command-line argument, if any.
+    if let Some(addr) = std::env::args().nth(1) {
+        let remote: Multiaddr = addr.parse()?;
+        swarm.dial(remote)?;
+        info!("Dialed {addr}")
+    }
+
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                info!("Listening on {address}");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+                ..
+            })) => {
+                info!("Identified {peer_id} as {}", info.agent_version);
+
+                // the remote's nym addresses can be dialed later by its peer ID alone
+                for addr in info.listen_addrs.into_iter().filter(is_nym_multiaddr) {
+                    info!("Learned address {addr} of {peer_id}");
+                    swarm.add_peer_address(peer_id, addr);
+                }
+
+                // the address the remote dialed us at is advertised in the form of the
+                // listen address it reached, if it reached one
+                let translated = swarm
+                    .listeners()
+                    .find_map(|listen| address_translation(listen, &info.observed_addr));
+                if let Some(addr) = translated {
+                    swarm.add_external_address(addr);
+                }
+            }
+            SwarmEvent::ExternalAddrConfirmed { address } => {
+                info!("Advertising external address {address}");
+            }
+            SwarmEvent::ConnectionClosed { peer_id, .. } => {
+                // redial using the addresses learned through identify
+                info!("Connection to {peer_id} closed; redialing");
+                if let Err(e) = swarm.dial(peer_id) {
+                    info!("Failed to redial {peer_id}: {e}");
+                }
+            }
+            SwarmEvent::Behaviour(event) => info!("{event:?}"),
+            _ => {}
+        }
+    }
+}
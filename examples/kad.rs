@@ -0,0 +1,148 @@
+// Copyright TODO based on the rust libp2p examples check how to smush 2 together / if this is necessary
+
+use futures::prelude::*;
+use libp2p::{
+    identify, kad,
+    multiaddr::Protocol,
+    swarm::{NetworkBehaviour, SwarmEvent},
+    Multiaddr, PeerId, SwarmBuilder,
+};
+use libp2p_identity::Keypair;
+use log::{info, warn, LevelFilter};
+use rust_libp2p_nym::config::{AnonymityMode, NymTransportConfig};
+use rust_libp2p_nym::presets;
+use rust_libp2p_nym::transport::NymTransport;
+use std::{error::Error, time::Duration};
+
+/// the key every node of the example provides, and looks up the providers of.
+const PROVIDED_KEY: &str = "nym-kad-example";
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    kademlia: kad::Behaviour<kad::store::MemoryStore>,
+    identify: identify::Behaviour,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    pretty_env_logger::formatted_timed_builder()
+        .filter_level(LevelFilter::Info)
+        .init();
+
+    let local_key = Keypair::generate_ed25519();
+    let local_peer_id = PeerId::from(local_key.public());
+
+    info!("Running `kad` example using NymTransport");
+    // Kademlia only accepts provider records from the peer they name, so dials have to
+    // use our own identity, which they only do if they expose our address.
+    let config = NymTransportConfig::default().with_anonymity(AnonymityMode::ExposeSelfAddress);
+    let client = nym_sdk::mixnet::MixnetClient::connect_new().await?;
+    let transport = NymTransport::new_with_config(client, local_key.clone(), config).await?;
+
+    let mut swarm = SwarmBuilder::with_existing_identity(local_key)
+        .with_tokio()
+        .with_other_transport(|_| transport)?
+        .with_behaviour(|key| {
+            let peer_id = key.public().to_peer_id();
+            let mut config = kad::Config::new(kad::PROTOCOL_NAME);
+            // mixnet round trips take seconds rather than milliseconds
+            config.set_query_timeout(Duration::from_secs(5 * 60));
+            let mut kademlia =
+                kad::Behaviour::with_config(peer_id, kad::store::MemoryStore::new(peer_id), config);
+            // we're reachable at our nym address, so answer queries from other peers
+            kademlia.set_mode(Some(kad::Mode::Server));
+            Behaviour {
+                kademlia,
+                identify: identify::Behaviour::new(presets::identify_config_for_nym(
+                    "/nym-kad-example/1.0.0".to_string(),
+                    key.public(),
+                )),
+            }
+        })?
+        .with_swarm_config(presets::swarm_config_for_nym)
+        .build();
+
+    // Bootstrap from the multi-addresses given as command-line arguments, if any.
+    // Each has to end in the /p2p/<peer id> of the node, as printed by it on startup.
+    let mut bootstrapping = false;
+    for addr in std::env::args().skip(1) {
+        let addr: Multiaddr = addr.parse()?;
+        let Some(Protocol::P2p(peer_id)) = addr.iter().last() else {
+            return Err(format!("{addr} doesn't end in /p2p/<peer id>").into());
+        };
+        swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+        bootstrapping = true;
+    }
+    let key = kad::RecordKey::new(&PROVIDED_KEY);
+    if bootstrapping {
+        swarm.behaviour_mut().kademlia.bootstrap()?;
+    } else {
+        swarm
+            .behaviour_mut()
+            .kademlia
+            .start_providing(key.clone())?;
+    }
+
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                info!("Listening on {address}/p2p/{local_peer_id}");
+                // included in our provider records, so that peers can dial us
+                swarm.add_external_address(address);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
+                kad::Event::OutboundQueryProgressed { result, step, .. },
+            )) => match result {
+                kad::QueryResult::Bootstrap(Ok(ok)) => {
+                    info!(
+                        "Bootstrapped with {}; {} remaining",
+                        ok.peer, ok.num_remaining
+                    );
+                    if step.last {
+                        swarm
+                            .behaviour_mut()
+                            .kademlia
+                            .start_providing(key.clone())?;
+                    }
+                }
+                kad::QueryResult::Bootstrap(Err(e)) => warn!("Failed to bootstrap: {e}"),
+                kad::QueryResult::StartProviding(res) => {
+                    match res {
+                        Ok(_) => info!("Providing {PROVIDED_KEY}"),
+                        // the record is still stored locally, and handed out by us
+                        Err(e) => warn!("Failed to publish provider record: {e}"),
+                    }
+                    swarm.behaviour_mut().kademlia.get_providers(key.clone());
+                }
+                kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders {
+                    providers,
+                    ..
+                })) => {
+                    for provider in providers {
+                        info!("Found provider {provider} of {PROVIDED_KEY}");
+                    }
+                }
+                kad::QueryResult::GetProviders(Ok(_)) => {
+                    info!("Finished looking up providers of {PROVIDED_KEY}");
+                }
+                kad::QueryResult::GetProviders(Err(e)) => {
+                    warn!("Failed to look up providers: {e}")
+                }
+                result => info!("{result:?}"),
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+                ..
+            })) => {
+                // peers that dialed us are added to the routing table once we know
+                // where to reach them
+                for addr in info.listen_addrs {
+                    swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                }
+            }
+            SwarmEvent::Behaviour(event) => info!("{event:?}"),
+            _ => {}
+        }
+    }
+}
@@ -5,12 +5,70 @@ use libp2p::SwarmBuilder;
 use libp2p::{ping, swarm::SwarmEvent, Multiaddr};
 use libp2p_identity::{Keypair, PeerId};
 use log::LevelFilter;
-use nym_sdk::mixnet::{MixnetClientBuilder, StoragePaths};
+use nym_sdk::mixnet::StoragePaths;
+use rust_libp2p_nym::config::{NymTransportConfig, ReconnectConfig};
+use rust_libp2p_nym::presets;
 use rust_libp2p_nym::transport::NymTransport;
 use std::path::PathBuf;
 use std::{error::Error, time::Duration};
 use tempfile::TempDir;
 
+/// the number of round trips `--dial` waits for by default.
+const DEFAULT_COUNT: usize = 5;
+
+/// how long `--dial` waits for all of its round trips before giving up.
+const SMOKE_TEST_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Args are the example's command-line arguments: either a multiaddr to dial and ping
+/// forever, or `--dial <multiaddr> [--count <n>]` to exit after `n` round trips.
+struct Args {
+    remote: Option<Multiaddr>,
+    count: Option<usize>,
+}
+
+impl Args {
+    fn parse() -> Result<Self, Box<dyn Error>> {
+        let mut args = Args {
+            remote: None,
+            count: None,
+        };
+        let mut smoke_test = false;
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--dial" => {
+                    let addr = iter.next().ok_or("--dial needs a multiaddr")?;
+                    args.remote = Some(addr.parse()?);
+                    smoke_test = true;
+                }
+                "--count" => {
+                    let count = iter.next().ok_or("--count needs a number")?;
+                    args.count = Some(count.parse()?);
+                }
+                addr => args.remote = Some(addr.parse()?),
+            }
+        }
+        if smoke_test {
+            args.count = Some(args.count.unwrap_or(DEFAULT_COUNT));
+        } else if args.count.is_some() {
+            return Err("--count can only be used with --dial".into());
+        }
+        Ok(args)
+    }
+}
+
+/// print_rtts prints the minimum, average and maximum of `rtts`.
+fn print_rtts(rtts: &[Duration]) {
+    let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) else {
+        return;
+    };
+    let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+    println!(
+        "{} round trips: min {min:?}, avg {avg:?}, max {max:?}",
+        rtts.len()
+    );
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     pretty_env_logger::formatted_timed_builder()
@@ -18,48 +76,87 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .filter(Some("libp2p_ping"), LevelFilter::Debug)
         .init();
 
+    let args = Args::parse()?;
     let local_key = Keypair::generate_ed25519();
     let local_peer_id = PeerId::from(local_key.public());
     println!("Local peer id: {local_peer_id:?}");
 
     let mut swarm = {
         println!("Running `ping` example using NymTransport");
-        let config_dir = PathBuf::from(TempDir::new().unwrap().path().to_str().unwrap());
+        // Set NYM_STORAGE_DIR to keep the same nym address across runs; otherwise a
+        // temporary directory is used and every run gets a fresh address.
+        let config_dir = match std::env::var_os("NYM_STORAGE_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(TempDir::new().unwrap().path().to_str().unwrap()),
+        };
         let storage_paths = StoragePaths::new_from_dir(&config_dir).unwrap();
 
-        // Create the client with a storage backend, and enable it by giving it some paths. If keys
-        // exists at these paths, they will be loaded, otherwise they will be generated.
-        let client = MixnetClientBuilder::new_with_default_storage(storage_paths)
-            .await
-            .unwrap()
-            .build()
-            .unwrap();
+        // If the client loses its gateway, rebuild it from the same storage so our nym
+        // address stays the same.
+        let config = NymTransportConfig::default()
+            .with_reconnect(ReconnectConfig::from_storage(storage_paths));
 
-        let client = client.connect_to_mixnet().await.unwrap();
-
-        let transport = NymTransport::new(client, local_key.clone()).await?;
+        // Create the client with a storage backend. If keys exist in the directory,
+        // they will be loaded, otherwise they will be generated.
+        let transport =
+            NymTransport::new_from_storage_with_config(&config_dir, local_key.clone(), config)
+                .await?;
 
         SwarmBuilder::with_new_identity()
             .with_tokio()
             .with_other_transport(|_| transport)?
-            .with_behaviour(|_| ping::Behaviour::default())?
-            .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(90))) // TODO this sets the config timeout for the ping example - change for keepalive behaviour if possible
+            .with_behaviour(|_| {
+                // ping every second, rather than the preset's 30, to measure round trips
+                ping::Behaviour::new(
+                    presets::ping_config_for_nym().with_interval(Duration::from_secs(1)),
+                )
+            })?
+            .with_swarm_config(presets::swarm_config_for_nym)
             .build()
     };
 
-    // Dial the peer identified by the multi-address given as the second
-    // command-line argument, if any.
-    if let Some(addr) = std::env::args().nth(1) {
-        let remote: Multiaddr = addr.parse()?;
-        swarm.dial(remote)?;
-        println!("Dialed {addr}")
+    // Dial the peer identified by the multi-address given on the command line, if any.
+    if let Some(remote) = args.remote {
+        swarm.dial(remote.clone())?;
+        println!("Dialed {remote}")
     }
 
-    loop {
-        match swarm.select_next_some().await {
-            SwarmEvent::NewListenAddr { address, .. } => println!("Listening on {address:?}"),
-            SwarmEvent::Behaviour(event) => println!("{event:?}"),
-            _ => {}
+    let Some(count) = args.count else {
+        loop {
+            match swarm.select_next_some().await {
+                SwarmEvent::NewListenAddr { address, .. } => println!("Listening on {address:?}"),
+                SwarmEvent::Behaviour(event) => println!("{event:?}"),
+                _ => {}
+            }
         }
-    }
+    };
+
+    // As a smoke test, succeed once `count` pings have made it to the remote and back,
+    // and fail on the first one that doesn't.
+    let mut rtts = Vec::with_capacity(count);
+    let smoke_test = async {
+        while rtts.len() < count {
+            match swarm.select_next_some().await {
+                SwarmEvent::Behaviour(ping::Event {
+                    result: Ok(rtt), ..
+                }) => {
+                    rtts.push(rtt);
+                    println!("Round trip {}/{count}: {rtt:?}", rtts.len());
+                }
+                SwarmEvent::Behaviour(ping::Event { result: Err(e), .. }) => {
+                    return Err(format!("ping failed: {e}"));
+                }
+                SwarmEvent::OutgoingConnectionError { error, .. } => {
+                    return Err(format!("dial failed: {error}"));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    };
+    let res = tokio::time::timeout(SMOKE_TEST_TIMEOUT, smoke_test)
+        .await
+        .unwrap_or_else(|_| Err(format!("timed out after {SMOKE_TEST_TIMEOUT:?}")));
+    print_rtts(&rtts);
+    Ok(res?)
 }
@@ -0,0 +1,290 @@
+// Copyright TODO based on the rust libp2p examples check how to smush 2 together / if this is necessary
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{
+    multiaddr::Protocol,
+    request_response::{self, ProtocolSupport},
+    swarm::SwarmEvent,
+    Multiaddr, PeerId, StreamProtocol, SwarmBuilder,
+};
+use libp2p_identity::Keypair;
+use log::{info, warn, LevelFilter};
+use rust_libp2p_nym::config::{AnonymityMode, NymTransportConfig};
+use rust_libp2p_nym::{presets, transport::NymTransport};
+use std::{
+    collections::{BTreeSet, HashMap},
+    error::Error,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{io::AsyncBufReadExt, select};
+
+const PROTOCOL: StreamProtocol = StreamProtocol::new("/nym-private-chat/1.0.0");
+
+/// the longest message accepted from a peer.
+const MAX_MESSAGE_LEN: u64 = 16 * 1024;
+
+/// ChatCodec frames a message as everything written to the substream before it's
+/// closed, and acknowledges it with an empty response.
+#[derive(Clone, Default)]
+struct ChatCodec;
+
+#[async_trait]
+impl request_response::Codec for ChatCodec {
+    type Protocol = StreamProtocol;
+    type Request = String;
+    type Response = ();
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<String>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut message = String::new();
+        io.take(MAX_MESSAGE_LEN)
+            .read_to_string(&mut message)
+            .await?;
+        Ok(message)
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<()>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        io.read_to_end(&mut vec![]).await?;
+        Ok(())
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        message: String,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(message.as_bytes()).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(&mut self, _: &StreamProtocol, io: &mut T, _: ()) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.close().await
+    }
+}
+
+/// Profile is what the example keeps on disk, in the directory it's given, so that it
+/// comes back with the same identity and contacts after a restart:
+/// - `identity` holds the libp2p keypair, so the peer ID stays the same;
+/// - `nym/` holds the mixnet client's keys and gateway registration, so the nym
+///   address does too;
+/// - `address_book` holds the nym addresses of the peers we've connected to, so that
+///   they can be dialed again by peer ID alone;
+/// - `contacts` lists the peer IDs of the peers we've chatted with, which are
+///   reconnected to on startup.
+struct Profile {
+    dir: PathBuf,
+    contacts: BTreeSet<PeerId>,
+}
+
+impl Profile {
+    fn open(dir: impl Into<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let contacts = match fs::read_to_string(dir.join("contacts")) {
+            Ok(contacts) => contacts.lines().map(str::parse).collect::<Result<_, _>>()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Profile { dir, contacts })
+    }
+
+    /// identity returns the stored keypair, generating and storing one the first time.
+    fn identity(&self) -> Result<Keypair, Box<dyn Error>> {
+        let path = self.dir.join("identity");
+        match fs::read(&path) {
+            Ok(bytes) => Ok(Keypair::from_protobuf_encoding(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let keypair = Keypair::generate_ed25519();
+                write_private(&path, &keypair.to_protobuf_encoding()?)?;
+                Ok(keypair)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// add_contact remembers `peer_id`, so that it's reconnected to after a restart.
+    fn add_contact(&mut self, peer_id: PeerId) -> io::Result<()> {
+        if !self.contacts.insert(peer_id) {
+            return Ok(());
+        }
+        let contacts: Vec<String> = self.contacts.iter().map(PeerId::to_string).collect();
+        fs::write(self.dir.join("contacts"), contacts.join("\n"))
+    }
+}
+
+/// write_private writes `bytes` to a file only we can read, since it holds a secret key.
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(bytes)
+}
+
+/// p2p returns the multiaddr `/p2p/<peer id>`, which the transport dials at the
+/// addresses it knows the peer at.
+fn p2p(peer_id: PeerId) -> Multiaddr {
+    Multiaddr::empty().with(Protocol::P2p(peer_id))
+}
+
+fn usage() -> Box<dyn Error> {
+    "usage: private_chat <profile directory>".into()
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    pretty_env_logger::formatted_timed_builder()
+        .filter_level(LevelFilter::Info)
+        .init();
+
+    let dir = std::env::args().nth(1).ok_or_else(usage)?;
+    let mut profile = Profile::open(dir)?;
+    let local_key = profile.identity()?;
+    let local_peer_id = local_key.public().to_peer_id();
+
+    info!("Running `private_chat` example using NymTransport");
+    // contacts reach each other at their nym addresses, so that either side can
+    // reconnect to the other after a restart
+    let config = NymTransportConfig::default()
+        .with_anonymity(AnonymityMode::ExposeSelfAddress)
+        .with_address_book_path(profile.dir.join("address_book"));
+    let transport = NymTransport::new_from_storage_with_config(
+        profile.dir.join("nym"),
+        local_key.clone(),
+        config,
+    )
+    .await?;
+
+    let mut swarm = SwarmBuilder::with_existing_identity(local_key)
+        .with_tokio()
+        .with_other_transport(|_| transport)?
+        .with_behaviour(|_| {
+            request_response::Behaviour::with_codec(
+                ChatCodec,
+                [(PROTOCOL, ProtocolSupport::Full)],
+                // a message and its acknowledgement each cross the mixnet
+                request_response::Config::default().with_request_timeout(Duration::from_secs(120)),
+            )
+        })?
+        .with_swarm_config(presets::swarm_config_for_nym)
+        .build();
+
+    // the peers we chatted with before the restart are dialed by peer ID, at the
+    // addresses the address book kept for them
+    for &peer_id in &profile.contacts {
+        info!("Reconnecting to {peer_id}");
+        if let Err(e) = swarm.dial(p2p(peer_id)) {
+            warn!("Failed to reconnect to {peer_id}: {e}");
+        }
+    }
+
+    // messages to peers we're still connecting to
+    let mut outbox: HashMap<PeerId, Vec<String>> = HashMap::new();
+    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    println!("Commands: /connect <multiaddr>, /msg <peer id> <message>, /contacts, /quit");
+
+    loop {
+        select! {
+            Ok(Some(line)) = stdin.next_line() => {
+                let line = line.trim();
+                if let Some(addr) = line.strip_prefix("/connect ") {
+                    match addr.trim().parse::<Multiaddr>() {
+                        Ok(addr) => {
+                            if let Err(e) = swarm.dial(addr) {
+                                println!("Failed to dial: {e}");
+                            }
+                        }
+                        Err(e) => println!("Invalid multiaddr: {e}"),
+                    }
+                } else if let Some(rest) = line.strip_prefix("/msg ") {
+                    let Some((peer_id, message)) = rest.trim().split_once(' ') else {
+                        println!("usage: /msg <peer id> <message>");
+                        continue;
+                    };
+                    let peer_id: PeerId = match peer_id.parse() {
+                        Ok(peer_id) => peer_id,
+                        Err(e) => {
+                            println!("Invalid peer ID: {e}");
+                            continue;
+                        }
+                    };
+                    if swarm.is_connected(&peer_id) {
+                        swarm.behaviour_mut().send_request(&peer_id, message.to_string());
+                    } else {
+                        // sent once the connection is established
+                        outbox.entry(peer_id).or_default().push(message.to_string());
+                        if let Err(e) = swarm.dial(p2p(peer_id)) {
+                            println!("Failed to dial {peer_id}: {e}");
+                            outbox.remove(&peer_id);
+                        }
+                    }
+                } else if line == "/contacts" {
+                    for peer_id in &profile.contacts {
+                        let status = if swarm.is_connected(peer_id) { "connected" } else { "offline" };
+                        println!("{peer_id} ({status})");
+                    }
+                } else if line == "/quit" {
+                    return Ok(());
+                } else if !line.is_empty() {
+                    println!("Unknown command; try /connect, /msg, /contacts or /quit");
+                }
+            }
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    println!("Others can reach us at {}", address.with(Protocol::P2p(local_peer_id)));
+                }
+                SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                    println!("Connected to {peer_id}");
+                    profile.add_contact(peer_id)?;
+                    for message in outbox.remove(&peer_id).unwrap_or_default() {
+                        swarm.behaviour_mut().send_request(&peer_id, message);
+                    }
+                }
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                    println!("Disconnected from {peer_id}");
+                }
+                SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. } => {
+                    println!("Failed to connect to {peer_id}: {error}");
+                    if let Some(messages) = outbox.remove(&peer_id) {
+                        println!("{} message(s) to {peer_id} weren't sent", messages.len());
+                    }
+                }
+                SwarmEvent::Behaviour(request_response::Event::Message { peer, message, .. }) => {
+                    match message {
+                        request_response::Message::Request { request, channel, .. } => {
+                            println!("<{peer}> {request}");
+                            // the sender may have given up waiting
+                            swarm.behaviour_mut().send_response(channel, ()).ok();
+                        }
+                        request_response::Message::Response { .. } => {
+                            info!("{peer} received our message");
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(request_response::Event::OutboundFailure { peer, error, .. }) => {
+                    println!("Failed to send a message to {peer}: {error}");
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,414 @@
+use libp2p::core::multiaddr::{Multiaddr, Protocol};
+use libp2p_identity::PeerId;
+use nym_sphinx::addressing::clients::Recipient;
+use std::{fmt, str::FromStr};
+
+use super::error::Error;
+
+/// A nym address in a multiaddr ending in this suffix is dialed with our
+/// address exposed when the transport uses [`crate::config::AnonymityMode::PerDial`].
+pub const EXPOSE_SELF_ADDRESS_SUFFIX: &str = "?expose";
+
+/// NymMultiaddr is the parsed form of a `/nym/<address>` multiaddr.
+/// The nym address may carry the [`EXPOSE_SELF_ADDRESS_SUFFIX`], and the multiaddr may
+/// end in a `/p2p/<peer id>` component, as appended by libp2p when sharing addresses
+/// over e.g. identify or Kademlia.
+///
+/// The nym address may be followed by a service, `/nym/<address>/svc/<service>`, which
+/// works like a port: a single nym client can listen on several services, and a dial
+/// to one is only accepted by the listener on it.
+///
+/// A relayed address, `/nym/<relay>/p2p-circuit/p2p/<peer id>`, reaches the peer through
+/// the relay at `recipient`, without either end learning the other's nym address. Its
+/// `peer_id` is the peer's behind the relay; a `/p2p/<relay peer id>` component in front
+/// of `/p2p-circuit` is accepted, and dropped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NymMultiaddr {
+    pub recipient: Recipient,
+    pub expose_self_address: bool,
+    pub peer_id: Option<PeerId>,
+    pub relayed: bool,
+    pub service: Option<u16>,
+}
+
+impl NymMultiaddr {
+    /// New NymMultiaddr for the given nym address.
+    pub fn new(recipient: Recipient) -> Self {
+        NymMultiaddr {
+            recipient,
+            expose_self_address: false,
+            peer_id: None,
+            relayed: false,
+            service: None,
+        }
+    }
+
+    /// Set the expose suffix and return self.
+    pub fn with_expose_self_address(mut self, expose: bool) -> Self {
+        self.expose_self_address = expose;
+        self
+    }
+
+    /// Set the `/p2p/<peer id>` component and return self.
+    pub fn with_peer_id(mut self, peer_id: PeerId) -> Self {
+        self.peer_id = Some(peer_id);
+        self
+    }
+
+    /// Set whether the address is relayed through `recipient` and return self.
+    pub fn with_relayed(mut self, relayed: bool) -> Self {
+        self.relayed = relayed;
+        self
+    }
+
+    /// Set the service following the nym address and return self.
+    pub fn with_service(mut self, service: u16) -> Self {
+        self.service = Some(service);
+        self
+    }
+
+    pub fn to_multiaddr(&self) -> Result<Multiaddr, Error> {
+        let suffix = if self.expose_self_address {
+            EXPOSE_SELF_ADDRESS_SUFFIX
+        } else {
+            ""
+        };
+        let mut multiaddr = Multiaddr::from_str(&format!("/nym/{}{}", self.recipient, suffix))
+            .map_err(Error::FailedToFormatMultiaddr)?;
+        if let Some(service) = self.service {
+            multiaddr.push(Protocol::Svc(service));
+        }
+        if self.relayed {
+            multiaddr.push(Protocol::P2pCircuit);
+        }
+        if let Some(peer_id) = self.peer_id {
+            multiaddr.push(Protocol::P2p(peer_id));
+        }
+        Ok(multiaddr)
+    }
+}
+
+impl TryFrom<&Multiaddr> for NymMultiaddr {
+    type Error = Error;
+
+    fn try_from(multiaddr: &Multiaddr) -> Result<Self, Error> {
+        let mut protocols = multiaddr.iter();
+        let Some(Protocol::Nym(addr)) = protocols.next() else {
+            return Err(Error::InvalidProtocolForMultiaddr);
+        };
+
+        let (addr, expose_self_address) = match addr.strip_suffix(EXPOSE_SELF_ADDRESS_SUFFIX) {
+            Some(addr) => (addr, true),
+            None => (&*addr, false),
+        };
+        let recipient = Recipient::from_str(addr).map_err(Error::InvalidRecipientBytes)?;
+
+        let mut service = None;
+        let mut peer_id = None;
+        let mut relayed = false;
+        let mut next = protocols.next();
+        if let Some(Protocol::Svc(port)) = next {
+            service = Some(port);
+            next = protocols.next();
+        }
+        if let Some(Protocol::P2p(id)) = next {
+            peer_id = Some(id);
+            next = protocols.next();
+        }
+        if let Some(Protocol::P2pCircuit) = next {
+            // the PeerId in front of the circuit is the relay's, which isn't dialed
+            relayed = true;
+            peer_id = None;
+            next = protocols.next();
+            if let Some(Protocol::P2p(id)) = next {
+                peer_id = Some(id);
+                next = protocols.next();
+            }
+        }
+        if next.is_some() {
+            return Err(Error::InvalidProtocolForMultiaddr);
+        }
+
+        Ok(NymMultiaddr {
+            recipient,
+            expose_self_address,
+            peer_id,
+            relayed,
+            service,
+        })
+    }
+}
+
+impl FromStr for NymMultiaddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let multiaddr = Multiaddr::from_str(s).map_err(Error::FailedToFormatMultiaddr)?;
+        NymMultiaddr::try_from(&multiaddr)
+    }
+}
+
+impl fmt::Display for NymMultiaddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.to_multiaddr() {
+            Ok(multiaddr) => write!(f, "{}", multiaddr),
+            Err(_) => Err(fmt::Error),
+        }
+    }
+}
+
+/// nym_address_to_multiaddr formats a nym address as a `/nym/<address>` multiaddr.
+pub fn nym_address_to_multiaddr(recipient: Recipient) -> Result<Multiaddr, Error> {
+    NymMultiaddr::new(recipient).to_multiaddr()
+}
+
+/// nym_peer_multiaddr formats a nym address and the PeerId behind it as a
+/// `/nym/<address>/p2p/<peer id>` multiaddr, the form peers are shared in.
+pub fn nym_peer_multiaddr(recipient: Recipient, peer_id: PeerId) -> Result<Multiaddr, Error> {
+    NymMultiaddr::new(recipient)
+        .with_peer_id(peer_id)
+        .to_multiaddr()
+}
+
+/// multiaddr_to_nym_address returns the nym address of a `/nym/<address>` multiaddr,
+/// which may carry the expose suffix or a `/p2p/<peer id>` component.
+pub fn multiaddr_to_nym_address(multiaddr: &Multiaddr) -> Result<Recipient, Error> {
+    NymMultiaddr::try_from(multiaddr).map(|addr| addr.recipient)
+}
+
+/// is_nym_multiaddr returns true if the multiaddr can be dialed by the NymTransport.
+pub fn is_nym_multiaddr(multiaddr: &Multiaddr) -> bool {
+    NymMultiaddr::try_from(multiaddr).is_ok()
+}
+
+/// address_translation turns an address of ours observed by a remote peer, e.g. the
+/// `observed_addr` sent by identify, into the address to advertise for the listen
+/// address `listen`: our `/nym/<address>`, `/nym/<address>/svc/<service>` for a
+/// listener on a service, or `/nym/<relay>/p2p-circuit` for a listener on a relay. The
+/// observed address is the one the remote dialed, so it may carry the expose suffix or
+/// a `/p2p/<peer id>` component, neither of which belongs in a listen address.
+///
+/// It returns None if the remote didn't reach us at `listen`: a peer that we dialed
+/// anonymously doesn't know our address, and observes its own instead; and a peer that
+/// reached us through a relay observes the relayed address, which only translates for
+/// a listener on that relay. Likewise, a remote that dialed a service only observes
+/// the listen address on that service.
+pub fn address_translation(listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+    let listen = NymMultiaddr::try_from(listen).ok()?;
+    let observed = NymMultiaddr::try_from(observed).ok()?;
+    if listen.relayed != observed.relayed
+        || observed.recipient != listen.recipient
+        || observed.service != listen.service
+    {
+        return None;
+    }
+    // a listen address with a PeerId only stands for that peer
+    if matches!((listen.peer_id, observed.peer_id), (Some(a), Some(b)) if a != b) {
+        return None;
+    }
+    NymMultiaddr {
+        expose_self_address: false,
+        peer_id: None,
+        ..observed
+    }
+    .to_multiaddr()
+    .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ADDR: &str = "Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR";
+
+    #[test]
+    fn test_multiaddr_roundtrip() {
+        let recipient = Recipient::from_str(ADDR).unwrap();
+        let multiaddr = nym_address_to_multiaddr(recipient).unwrap();
+        assert_eq!(multiaddr.to_string(), format!("/nym/{}", ADDR));
+
+        // through the binary encoding used by identify and Kademlia
+        let decoded = Multiaddr::try_from(multiaddr.to_vec()).unwrap();
+        let parsed = NymMultiaddr::try_from(&decoded).unwrap();
+        assert_eq!(parsed, NymMultiaddr::new(recipient));
+    }
+
+    #[test]
+    fn test_multiaddr_with_peer_id() {
+        let recipient = Recipient::from_str(ADDR).unwrap();
+        let peer_id = PeerId::random();
+        let addr = NymMultiaddr::new(recipient)
+            .with_expose_self_address(true)
+            .with_peer_id(peer_id);
+        let multiaddr = addr.to_multiaddr().unwrap();
+        assert_eq!(
+            multiaddr.to_string(),
+            format!("/nym/{}?expose/p2p/{}", ADDR, peer_id)
+        );
+        assert_eq!(NymMultiaddr::try_from(&multiaddr).unwrap(), addr);
+        assert_eq!(addr.to_string().parse::<NymMultiaddr>().unwrap(), addr);
+    }
+
+    #[test]
+    fn test_nym_peer_multiaddr() {
+        let recipient = Recipient::from_str(ADDR).unwrap();
+        let peer_id = PeerId::random();
+        let multiaddr = nym_peer_multiaddr(recipient, peer_id).unwrap();
+        assert_eq!(
+            multiaddr.to_string(),
+            format!("/nym/{}/p2p/{}", ADDR, peer_id)
+        );
+        assert_eq!(multiaddr_to_nym_address(&multiaddr).unwrap(), recipient);
+        assert_eq!(
+            multiaddr_to_nym_address(&nym_address_to_multiaddr(recipient).unwrap()).unwrap(),
+            recipient
+        );
+        assert!(multiaddr_to_nym_address(&Multiaddr::empty()).is_err());
+    }
+
+    #[test]
+    fn test_relayed_multiaddr() {
+        let relay = Recipient::from_str(ADDR).unwrap();
+        let peer_id = PeerId::random();
+        let addr = NymMultiaddr::new(relay)
+            .with_relayed(true)
+            .with_peer_id(peer_id);
+        let multiaddr = addr.to_multiaddr().unwrap();
+        assert_eq!(
+            multiaddr.to_string(),
+            format!("/nym/{}/p2p-circuit/p2p/{}", ADDR, peer_id)
+        );
+        assert_eq!(NymMultiaddr::try_from(&multiaddr).unwrap(), addr);
+
+        // the relay's PeerId is dropped, leaving the peer's behind it
+        let with_relay_id = Multiaddr::from_str(&format!(
+            "/nym/{}/p2p/{}/p2p-circuit/p2p/{}",
+            ADDR,
+            PeerId::random(),
+            peer_id
+        ))
+        .unwrap();
+        assert_eq!(NymMultiaddr::try_from(&with_relay_id).unwrap(), addr);
+
+        // a listener's relayed address has no PeerId of its own
+        let listen = NymMultiaddr::from_str(&format!("/nym/{}/p2p-circuit", ADDR)).unwrap();
+        assert!(listen.relayed && listen.peer_id.is_none());
+
+        // the address a peer reached us at through the relay translates to the relayed
+        // listen address, but not to the relay's own
+        let listen = listen.to_multiaddr().unwrap();
+        assert_eq!(
+            address_translation(&listen, &multiaddr),
+            Some(listen.clone())
+        );
+        assert_eq!(
+            address_translation(&nym_address_to_multiaddr(relay).unwrap(), &multiaddr),
+            None
+        );
+        assert_eq!(
+            address_translation(&nym_address_to_multiaddr(relay).unwrap(), &listen),
+            None
+        );
+
+        assert!(NymMultiaddr::from_str(&format!("/nym/{}/p2p-circuit/p2p-circuit", ADDR)).is_err());
+    }
+
+    #[test]
+    fn test_multiaddr_expose_suffix() {
+        let parsed = NymMultiaddr::from_str(&format!("/nym/{}", ADDR)).unwrap();
+        assert_eq!(parsed.recipient.to_string(), ADDR);
+        assert!(!parsed.expose_self_address);
+
+        let parsed = NymMultiaddr::from_str(&format!("/nym/{}?expose", ADDR)).unwrap();
+        assert_eq!(parsed.recipient.to_string(), ADDR);
+        assert!(parsed.expose_self_address);
+    }
+
+    #[test]
+    fn test_multiaddr_with_service() {
+        let recipient = Recipient::from_str(ADDR).unwrap();
+        let peer_id = PeerId::random();
+        let addr = NymMultiaddr::new(recipient)
+            .with_service(8080)
+            .with_expose_self_address(true)
+            .with_peer_id(peer_id);
+        let multiaddr = addr.to_multiaddr().unwrap();
+        assert_eq!(
+            multiaddr.to_string(),
+            format!("/nym/{}?expose/svc/8080/p2p/{}", ADDR, peer_id)
+        );
+        assert_eq!(NymMultiaddr::try_from(&multiaddr).unwrap(), addr);
+        let decoded = Multiaddr::try_from(multiaddr.to_vec()).unwrap();
+        assert_eq!(NymMultiaddr::try_from(&decoded).unwrap(), addr);
+        assert_eq!(multiaddr_to_nym_address(&multiaddr).unwrap(), recipient);
+
+        let parsed = NymMultiaddr::from_str(&format!("/nym/{}/svc/0", ADDR)).unwrap();
+        assert_eq!(parsed.service, Some(0));
+        assert!(!parsed.expose_self_address);
+        for service in ["", "65536", "-1", "http"] {
+            assert!(matches!(
+                NymMultiaddr::from_str(&format!("/nym/{}/svc/{}", ADDR, service)),
+                Err(Error::FailedToFormatMultiaddr(_))
+            ));
+        }
+        // the service follows the nym address, before its peer ID
+        for addr in [
+            format!("/nym/{}/p2p/{}/svc/1", ADDR, peer_id),
+            format!("/svc/1/nym/{}", ADDR),
+        ] {
+            assert!(NymMultiaddr::from_str(&addr).is_err());
+        }
+
+        // a remote that dialed a service only observes our address on that service
+        let listen = NymMultiaddr::new(recipient)
+            .with_service(8080)
+            .to_multiaddr()
+            .unwrap();
+        assert_eq!(
+            address_translation(&listen, &multiaddr),
+            Some(listen.clone())
+        );
+        let plain = nym_address_to_multiaddr(recipient).unwrap();
+        assert_eq!(address_translation(&plain, &multiaddr), None);
+        assert_eq!(address_translation(&listen, &plain), None);
+    }
+
+    #[test]
+    fn test_address_translation() {
+        let recipient = Recipient::from_str(ADDR).unwrap();
+        let listen = nym_address_to_multiaddr(recipient).unwrap();
+
+        // the suffix and peer ID of the dialed address are dropped
+        let observed = NymMultiaddr::new(recipient)
+            .with_expose_self_address(true)
+            .with_peer_id(PeerId::random())
+            .to_multiaddr()
+            .unwrap();
+        assert_eq!(
+            address_translation(&listen, &observed),
+            Some(listen.clone())
+        );
+        assert_eq!(address_translation(&listen, &listen), Some(listen.clone()));
+
+        // another nym address, or an address of another transport, isn't ours
+        let other = Multiaddr::from_str(
+            "/nym/D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN",
+        )
+        .unwrap();
+        assert_eq!(address_translation(&listen, &other), None);
+        let tcp = Multiaddr::from_str("/ip4/127.0.0.1/tcp/4001").unwrap();
+        assert_eq!(address_translation(&listen, &tcp), None);
+        assert_eq!(address_translation(&tcp, &listen), None);
+    }
+
+    #[test]
+    fn test_multiaddr_not_nym() {
+        let multiaddr = Multiaddr::from_str("/ip4/127.0.0.1/tcp/4001").unwrap();
+        assert!(!is_nym_multiaddr(&multiaddr));
+        assert!(matches!(
+            NymMultiaddr::try_from(&multiaddr),
+            Err(Error::InvalidProtocolForMultiaddr)
+        ));
+        assert!(!is_nym_multiaddr(&Multiaddr::empty()));
+    }
+}
@@ -0,0 +1,358 @@
+use libp2p_identity::PeerId;
+use log::warn;
+use nym_sphinx::addressing::clients::Recipient;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use super::error::Error;
+
+/// maximum number of peers the address book remembers; the least recently
+/// learned address is forgotten first.
+pub(crate) const MAX_ADDRESS_BOOK_ENTRIES: usize = 4096;
+
+/// maximum number of nym addresses remembered for a single peer, e.g. one per mixnet
+/// client of a peer that runs several; the oldest is forgotten first.
+pub(crate) const MAX_ADDRESSES_PER_PEER: usize = 4;
+
+/// AddressBook maps the PeerIds of peers we've authenticated, or been told about,
+/// to their nym addresses, so that they can be dialed by PeerId alone.
+///
+/// A peer can be pinned to the addresses it's known at, after which it's only learned
+/// at new ones by completing a handshake there, which proves it holds its PeerId's key
+/// at that address; being told about a new address is rejected. A persistent book, which
+/// is saved to a file whenever it changes, pins every peer at the first address it's
+/// learned at (trust on first use).
+#[derive(Debug, Default)]
+pub(crate) struct AddressBook {
+    /// each peer's addresses, most recently learned first.
+    addresses: HashMap<PeerId, Vec<Recipient>>,
+    /// peers in the order their address was learned, oldest first.
+    order: VecDeque<PeerId>,
+    /// peers that are only learned at new addresses by a handshake.
+    pinned: HashSet<PeerId>,
+    /// file the book is saved to whenever it changes; None if it's only kept in memory.
+    path: Option<PathBuf>,
+}
+
+impl AddressBook {
+    /// open loads the address book saved at `path`, or starts an empty one if there's
+    /// no file there yet. The book is saved back to `path` whenever it changes, and
+    /// pins peers at the first address they're learned at.
+    pub(crate) fn open(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let mut book = match std::fs::read_to_string(&path) {
+            Ok(contents) => Self::parse(&contents).map_err(Error::AddressBook)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => AddressBook::default(),
+            Err(e) => return Err(Error::AddressBook(e)),
+        };
+        book.path = Some(path);
+        Ok(book)
+    }
+
+    /// insert records `recipient` as the most recent nym address of `peer_id`, alongside
+    /// any others it's known at. It's for addresses the peer completed a handshake at,
+    /// so it's accepted even if the peer is pinned.
+    pub(crate) fn insert(&mut self, peer_id: PeerId, recipient: Recipient) {
+        let known = self.addresses.contains_key(&peer_id);
+        if self.get(&peer_id) == Some(&recipient) {
+            // nothing to save, but the peer is still the most recently learned
+            self.touch(peer_id);
+            return;
+        }
+        self.record(peer_id, recipient);
+        if !known && self.path.is_some() {
+            self.pinned.insert(peer_id);
+        }
+        self.save();
+    }
+
+    /// insert_unverified records `recipient` as an address of `peer_id` that we've only
+    /// been told about. It fails with [`Error::AddressPinned`] if the peer is pinned to
+    /// other addresses.
+    pub(crate) fn insert_unverified(
+        &mut self,
+        peer_id: PeerId,
+        recipient: Recipient,
+    ) -> Result<(), Error> {
+        if self.pinned.contains(&peer_id) && !self.get_all(&peer_id).contains(&recipient) {
+            return Err(Error::AddressPinned(peer_id));
+        }
+        self.insert(peer_id, recipient);
+        Ok(())
+    }
+
+    /// pin records `recipient` as the most recent nym address of `peer_id`, and pins
+    /// the peer to the addresses it's known at.
+    pub(crate) fn pin(&mut self, peer_id: PeerId, recipient: Recipient) {
+        self.record(peer_id, recipient);
+        self.pinned.insert(peer_id);
+        self.save();
+    }
+
+    /// unpin lets `peer_id` be learned at new addresses however they're learned,
+    /// returning false if it wasn't pinned.
+    pub(crate) fn unpin(&mut self, peer_id: &PeerId) -> bool {
+        let pinned = self.pinned.remove(peer_id);
+        if pinned {
+            self.save();
+        }
+        pinned
+    }
+
+    /// is_pinned returns true if `peer_id` is only learned at new addresses by a handshake.
+    pub(crate) fn is_pinned(&self, peer_id: &PeerId) -> bool {
+        self.pinned.contains(peer_id)
+    }
+
+    /// remove forgets every address of `peer_id`, and unpins it, returning the most
+    /// recent address if there was one.
+    pub(crate) fn remove(&mut self, peer_id: &PeerId) -> Option<Recipient> {
+        self.pinned.remove(peer_id);
+        let addresses = self.addresses.remove(peer_id)?;
+        self.order.retain(|p| p != peer_id);
+        self.save();
+        addresses.first().copied()
+    }
+
+    /// len returns the number of peers whose address is known.
+    pub(crate) fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    /// get returns the most recent nym address of `peer_id`, if it's known.
+    pub(crate) fn get(&self, peer_id: &PeerId) -> Option<&Recipient> {
+        self.addresses.get(peer_id)?.first()
+    }
+
+    /// get_all returns every nym address `peer_id` is known at, most recent first.
+    pub(crate) fn get_all(&self, peer_id: &PeerId) -> &[Recipient] {
+        self.addresses.get(peer_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// record adds `recipient` to the front of the addresses of `peer_id`, forgetting the
+    /// least recently learned peer if the book is full.
+    fn record(&mut self, peer_id: PeerId, recipient: Recipient) {
+        match self.addresses.get_mut(&peer_id) {
+            Some(addresses) => {
+                addresses.retain(|r| *r != recipient);
+                addresses.insert(0, recipient);
+                addresses.truncate(MAX_ADDRESSES_PER_PEER);
+            }
+            None => {
+                self.addresses.insert(peer_id, vec![recipient]);
+            }
+        }
+        self.touch(peer_id);
+        while self.order.len() > MAX_ADDRESS_BOOK_ENTRIES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.addresses.remove(&oldest);
+                self.pinned.remove(&oldest);
+            }
+        }
+    }
+
+    /// touch marks `peer_id` as the most recently learned peer.
+    fn touch(&mut self, peer_id: PeerId) {
+        self.order.retain(|p| *p != peer_id);
+        self.order.push_back(peer_id);
+    }
+
+    /// save writes the book to its file, if it has one. A failure is logged rather than
+    /// returned, since the book in memory is still correct.
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(e) = write_atomically(path, &self.to_string()) {
+            warn!("failed to save address book to {}: {}", path.display(), e);
+        }
+    }
+
+    /// parse reads a book in the format written by `to_string`: a line per peer, least
+    /// recently learned first, of its PeerId, whether it's pinned, and its addresses.
+    fn parse(contents: &str) -> io::Result<Self> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid address book entry: {}", line),
+            )
+        };
+        let mut book = AddressBook::default();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let mut fields = line.split_whitespace();
+            let peer_id = fields
+                .next()
+                .and_then(|field| field.parse::<PeerId>().ok())
+                .ok_or_else(|| invalid(line))?;
+            let pinned = match fields.next() {
+                Some("pinned") => true,
+                Some("unpinned") => false,
+                _ => return Err(invalid(line)),
+            };
+            let addresses = fields
+                .map(Recipient::try_from_base58_string)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| invalid(line))?;
+            if addresses.is_empty() {
+                return Err(invalid(line));
+            }
+            // oldest first, so that the most recent ends up at the front
+            for recipient in addresses.into_iter().rev() {
+                book.record(peer_id, recipient);
+            }
+            if pinned {
+                book.pinned.insert(peer_id);
+            }
+        }
+        Ok(book)
+    }
+}
+
+impl std::fmt::Display for AddressBook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for peer_id in &self.order {
+            write!(
+                f,
+                "{} {}",
+                peer_id,
+                if self.is_pinned(peer_id) {
+                    "pinned"
+                } else {
+                    "unpinned"
+                }
+            )?;
+            for recipient in self.get_all(peer_id) {
+                write!(f, " {}", recipient)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// write_atomically replaces the file at `path` with `contents`, by way of a temporary
+/// file beside it, so that a crash mid-write doesn't leave a truncated file behind.
+pub(crate) fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn first_address() -> Recipient {
+        Recipient::try_from_base58_string(
+            "Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR",
+        )
+        .unwrap()
+    }
+
+    fn second_address() -> Recipient {
+        Recipient::try_from_base58_string(
+            "D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_address_book() {
+        let mut book = AddressBook::default();
+        let peer_id = PeerId::random();
+        assert!(book.get(&peer_id).is_none());
+
+        let first = first_address();
+        book.insert(peer_id, first);
+        assert_eq!(book.get(&peer_id), Some(&first));
+
+        // a newer address is preferred, and the old one kept
+        let second = second_address();
+        book.insert(peer_id, second);
+        assert_eq!(book.get(&peer_id), Some(&second));
+        assert_eq!(book.get_all(&peer_id), &[second, first]);
+        assert_eq!(book.order.len(), 1);
+
+        // learning a known address again makes it the most recent
+        book.insert(peer_id, first);
+        assert_eq!(book.get_all(&peer_id), &[first, second]);
+        book.insert(peer_id, second);
+
+        assert_eq!(book.remove(&peer_id), Some(second));
+        assert!(book.get(&peer_id).is_none());
+        assert!(book.get_all(&peer_id).is_empty());
+        assert!(book.order.is_empty());
+    }
+
+    #[test]
+    fn test_address_book_forgets_oldest() {
+        let mut book = AddressBook::default();
+        let oldest = PeerId::random();
+        let address = first_address();
+        book.insert(oldest, address);
+        for _ in 0..MAX_ADDRESS_BOOK_ENTRIES {
+            book.insert(PeerId::random(), address);
+        }
+        assert!(book.get(&oldest).is_none());
+        assert_eq!(book.addresses.len(), MAX_ADDRESS_BOOK_ENTRIES);
+    }
+
+    #[test]
+    fn test_address_book_pinning() {
+        let mut book = AddressBook::default();
+        let peer_id = PeerId::random();
+        let (first, second) = (first_address(), second_address());
+
+        // an in-memory book only pins peers it's told to
+        book.insert_unverified(peer_id, first).unwrap();
+        assert!(!book.is_pinned(&peer_id));
+        book.pin(peer_id, first);
+        assert!(book.is_pinned(&peer_id));
+
+        // a pinned peer isn't learned at a new address it's only been claimed to be at,
+        // but is at one it completed a handshake at
+        assert!(matches!(
+            book.insert_unverified(peer_id, second),
+            Err(Error::AddressPinned(p)) if p == peer_id
+        ));
+        assert_eq!(book.get_all(&peer_id), &[first]);
+        book.insert_unverified(peer_id, first).unwrap();
+        book.insert(peer_id, second);
+        assert_eq!(book.get_all(&peer_id), &[second, first]);
+
+        assert!(book.unpin(&peer_id));
+        assert!(!book.unpin(&peer_id));
+    }
+
+    #[test]
+    fn test_address_book_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("address-book");
+        let (first, second) = (first_address(), second_address());
+        let (pinned, unpinned) = (PeerId::random(), PeerId::random());
+
+        let mut book = AddressBook::open(&path).unwrap();
+        // peers are trusted on first use
+        book.insert(pinned, first);
+        book.insert(pinned, second);
+        assert!(book.is_pinned(&pinned));
+        book.insert_unverified(unpinned, first).unwrap();
+        book.unpin(&unpinned);
+
+        let mut book = AddressBook::open(&path).unwrap();
+        assert_eq!(book.get_all(&pinned), &[second, first]);
+        assert!(book.is_pinned(&pinned));
+        assert_eq!(book.get_all(&unpinned), &[first]);
+        assert!(!book.is_pinned(&unpinned));
+        assert_eq!(book.order, [pinned, unpinned]);
+        assert!(book.insert_unverified(unpinned, second).is_ok());
+
+        std::fs::write(&path, "not an address book").unwrap();
+        assert!(matches!(
+            AddressBook::open(&path),
+            Err(Error::AddressBook(_))
+        ));
+    }
+}
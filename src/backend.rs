@@ -0,0 +1,341 @@
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt, StreamExt,
+};
+use nym_sdk::mixnet::{
+    AnonymousSenderTag, IncludedSurbs, MixnetClient, MixnetClientSender, MixnetMessageSender,
+};
+use nym_sphinx::addressing::clients::Recipient;
+use nym_sphinx::receiver::ReconstructedMessage;
+
+use super::error::Error;
+
+/// MixnetBackend is the connection to the mixnet that the transport's background task
+/// reads from and writes to. It's implemented by the nym-sdk [`MixnetClient`]; other
+/// implementations can stand in for it, e.g. to test without a live mixnet.
+/// Pass one to [`NymTransport::new_with_backend`](crate::transport::NymTransport::new_with_backend).
+pub trait MixnetBackend: Send + 'static {
+    /// our_address returns the nym address messages sent to us are delivered to.
+    fn our_address(&self) -> Recipient;
+
+    /// sender returns a handle which writes to the mixnet, and which can be used
+    /// while the backend is being read from.
+    fn sender(&self) -> Box<dyn MixnetBackendSender>;
+
+    /// next returns the next message received from the mixnet. It returns None once
+    /// the backend has lost its connection to the mixnet, and won't receive anything else.
+    fn next(&mut self) -> BoxFuture<'_, Option<ReconstructedMessage>>;
+
+    /// disconnect closes the backend's connection to the mixnet.
+    fn disconnect(self: Box<Self>) -> BoxFuture<'static, ()>;
+
+    /// topology_epoch returns the epoch of the network topology the backend currently
+    /// routes its packets through, if it knows it. Backends that don't route through
+    /// the Nym network, such as the in-memory mixnet, don't have one.
+    fn topology_epoch(&mut self) -> BoxFuture<'_, Option<u64>> {
+        future::ready(None).boxed()
+    }
+}
+
+/// MixnetBackendSender writes messages to the mixnet on behalf of a [`MixnetBackend`].
+pub trait MixnetBackendSender: Send + Sync {
+    /// send sends `message` to `recipient`, along with the given SURBs (or our address)
+    /// for the recipient to reply with.
+    fn send<'a>(
+        &'a self,
+        recipient: Recipient,
+        message: &'a [u8],
+        surbs: IncludedSurbs,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// send_reply sends `message` using one of the SURBs we received with `sender_tag`.
+    fn send_reply<'a>(
+        &'a self,
+        sender_tag: AnonymousSenderTag,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+impl MixnetBackend for MixnetClient {
+    fn our_address(&self) -> Recipient {
+        *self.nym_address()
+    }
+
+    fn sender(&self) -> Box<dyn MixnetBackendSender> {
+        Box::new(self.split_sender())
+    }
+
+    fn next(&mut self) -> BoxFuture<'_, Option<ReconstructedMessage>> {
+        StreamExt::next(self).boxed()
+    }
+
+    fn disconnect(self: Box<Self>) -> BoxFuture<'static, ()> {
+        (*self).disconnect().boxed()
+    }
+
+    fn topology_epoch(&mut self) -> BoxFuture<'_, Option<u64>> {
+        async move {
+            let provider = self.read_current_route_provider().await?;
+            Some(provider.topology.metadata().absolute_epoch_id as u64)
+        }
+        .boxed()
+    }
+}
+
+impl MixnetBackendSender for MixnetClientSender {
+    fn send<'a>(
+        &'a self,
+        recipient: Recipient,
+        message: &'a [u8],
+        surbs: IncludedSurbs,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            self.send_message(recipient, message, surbs)
+                .await
+                .map_err(send_error)
+        }
+        .boxed()
+    }
+
+    fn send_reply<'a>(
+        &'a self,
+        sender_tag: AnonymousSenderTag,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            MixnetMessageSender::send_reply(self, sender_tag, message)
+                .await
+                .map_err(send_error)
+        }
+        .boxed()
+    }
+}
+
+/// send_error returns the error a write to the nym-sdk client failed with, by what
+/// went wrong, so that the write is retried, or the client reconnected, only when that helps.
+fn send_error(e: nym_sdk::Error) -> Error {
+    match e {
+        // the client's input channel is closed, so it has shut down
+        nym_sdk::Error::MessageSendingFailure => Error::MixnetClientDisconnected,
+        nym_sdk::Error::ClientCoreError(_) | nym_sdk::Error::IoError(_) => {
+            Error::GatewayUnreachable(e)
+        }
+        nym_sdk::Error::TomlSerializationError(_) | nym_sdk::Error::TomlDeserializationError(_) => {
+            Error::SerializationError(e)
+        }
+        e => Error::MixnetSendFailure(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::NymTransportConfig;
+    use super::super::datagram::DatagramRouter;
+    use super::super::events::{DropReason, EventSender, NymEvent};
+    use super::super::message::{
+        ConnectionCloseMessage, ConnectionId, Message, OutboundMessage, SubstreamId,
+        SubstreamMessage, SubstreamMessageType, TransportMessage,
+    };
+    use super::super::migration::MigrationTable;
+    use super::super::mixnet::initialize_mixnet;
+    use super::super::nonce::Nonce;
+    use super::super::relay::RelayTable;
+    use super::super::rtt::RttTable;
+    use super::super::stats::StatsTable;
+    use super::*;
+    use bytes::Bytes;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    /// Loopback is a backend which delivers every message sent through it to itself.
+    struct Loopback {
+        address: Recipient,
+        tx: UnboundedSender<ReconstructedMessage>,
+        rx: UnboundedReceiver<ReconstructedMessage>,
+    }
+
+    struct LoopbackSender(UnboundedSender<ReconstructedMessage>);
+
+    impl MixnetBackend for Loopback {
+        fn our_address(&self) -> Recipient {
+            self.address
+        }
+
+        fn sender(&self) -> Box<dyn MixnetBackendSender> {
+            Box::new(LoopbackSender(self.tx.clone()))
+        }
+
+        fn next(&mut self) -> BoxFuture<'_, Option<ReconstructedMessage>> {
+            self.rx.recv().boxed()
+        }
+
+        fn disconnect(self: Box<Self>) -> BoxFuture<'static, ()> {
+            async {}.boxed()
+        }
+    }
+
+    impl MixnetBackendSender for LoopbackSender {
+        fn send<'a>(
+            &'a self,
+            _recipient: Recipient,
+            message: &'a [u8],
+            _surbs: IncludedSurbs,
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            let res = self
+                .0
+                .send(ReconstructedMessage {
+                    message: message.to_vec(),
+                    sender_tag: None,
+                })
+                .map_err(|e| Error::OutboundSendFailure(e.to_string()));
+            async move { res }.boxed()
+        }
+
+        fn send_reply<'a>(
+            &'a self,
+            sender_tag: AnonymousSenderTag,
+            message: &'a [u8],
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            let res = self
+                .0
+                .send(ReconstructedMessage {
+                    message: message.to_vec(),
+                    sender_tag: Some(sender_tag),
+                })
+                .map_err(|e| Error::OutboundSendFailure(e.to_string()));
+            async move { res }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_backend() {
+        let (tx, rx) = unbounded_channel();
+        let address = Recipient::try_from_base58_string(
+            "Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR",
+        )
+        .unwrap();
+        let (self_address, mut inbound_rx, outbound_tx, mixnet_task) = initialize_mixnet(
+            Loopback { address, tx, rx },
+            None,
+            EventSender::default(),
+            DatagramRouter::default(),
+            RttTable::default(),
+            StatsTable::default(),
+            MigrationTable::default(),
+            RelayTable::default(),
+            &NymTransportConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(self_address, address);
+
+        // the message goes through the backend rather than the mixnet
+        let substream_id = SubstreamId::generate();
+        outbound_tx
+            .try_send(OutboundMessage {
+                message: Message::TransportMessage(TransportMessage {
+                    nonce: Nonce::FIRST,
+                    id: ConnectionId::generate(),
+                    message: SubstreamMessage::new_with_data(
+                        substream_id.clone(),
+                        Bytes::from_static(b"hello"),
+                    ),
+                    timestamp: None,
+                }),
+                recipient: Some(self_address),
+                sender_tag: None,
+            })
+            .unwrap();
+
+        let Message::TransportMessage(msg) = inbound_rx.recv().await.unwrap().0 else {
+            panic!("expected Message::TransportMessage");
+        };
+        assert_eq!(msg.message.substream_id, substream_id);
+        let SubstreamMessageType::Data(data) = msg.message.message_type else {
+            panic!("expected SubstreamMessageType::Data");
+        };
+        assert_eq!(&data[..], b"hello");
+
+        mixnet_task.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_messages_block_sender() {
+        let (tx, rx) = unbounded_channel();
+        let address = Recipient::try_from_base58_string(
+            "Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR",
+        )
+        .unwrap();
+        let events = EventSender::new(16);
+        let mut dropped = Box::pin(events.subscribe().filter_map(|event| async move {
+            match event {
+                NymEvent::MessageDropped { reason } => Some(reason),
+                _ => None,
+            }
+        }));
+        let (_, mut inbound_rx, _outbound_tx, mixnet_task) = initialize_mixnet(
+            Loopback {
+                address,
+                tx: tx.clone(),
+                rx,
+            },
+            None,
+            events,
+            DatagramRouter::default(),
+            RttTable::default(),
+            StatsTable::default(),
+            MigrationTable::default(),
+            RelayTable::default(),
+            &NymTransportConfig::default().with_max_invalid_messages(2),
+        )
+        .await
+        .unwrap();
+
+        // an unknown message type, twice, gets the sender blocked
+        let sender_tag = AnonymousSenderTag::new_random(&mut rand::thread_rng());
+        for _ in 0..2 {
+            tx.send(ReconstructedMessage {
+                message: vec![0xff, 0],
+                sender_tag: Some(sender_tag.clone()),
+            })
+            .unwrap();
+            assert_eq!(dropped.next().await, Some(DropReason::Invalid));
+        }
+
+        // so even its valid messages are dropped
+        let valid = Message::ConnectionClose(ConnectionCloseMessage {
+            id: ConnectionId::generate(),
+        })
+        .to_bytes();
+        tx.send(ReconstructedMessage {
+            message: valid.to_vec(),
+            sender_tag: Some(sender_tag),
+        })
+        .unwrap();
+        assert_eq!(dropped.next().await, Some(DropReason::BlockedSender));
+
+        // while other senders aren't affected
+        tx.send(ReconstructedMessage {
+            message: valid.to_vec(),
+            sender_tag: None,
+        })
+        .unwrap();
+        let Message::ConnectionClose(_) = inbound_rx.recv().await.unwrap().0 else {
+            panic!("expected Message::ConnectionClose");
+        };
+
+        mixnet_task.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn test_send_error() {
+        // a closed client is reconnected rather than written to again
+        let err = send_error(nym_sdk::Error::MessageSendingFailure);
+        assert!(matches!(err, Error::MixnetClientDisconnected));
+        assert!(!err.is_transient_send_failure());
+
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let err = send_error(nym_sdk::Error::IoError(io));
+        assert!(matches!(err, Error::GatewayUnreachable(_)));
+        assert!(err.is_transient_send_failure());
+    }
+}
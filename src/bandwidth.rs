@@ -0,0 +1,196 @@
+use futures::{future::BoxFuture, FutureExt};
+use log::{debug, warn};
+use nym_sdk::mixnet::{AnonymousSenderTag, IncludedSurbs};
+use nym_sphinx::addressing::clients::Recipient;
+use parking_lot::Mutex;
+use std::{sync::Arc, time::Duration};
+
+use super::backend::MixnetBackendSender;
+use super::config::CredentialsConfig;
+use super::error::Error;
+use super::events::{EventSender, NymEvent};
+use super::runtime::{sleep, spawn};
+
+/// Approximate number of message bytes carried by a regular sphinx packet.
+const PACKET_PAYLOAD_SIZE: u64 = 2048;
+
+/// Approximate size of a regular sphinx packet, which is what gateways charge for.
+const PACKET_SIZE: u64 = 2413;
+
+/// Time to wait after a failed top-up before trying again.
+const TOP_UP_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// packet_count returns the number of sphinx packets a message of `len` bytes is sent in.
+pub(crate) fn packet_count(len: usize) -> u64 {
+    (len as u64).div_ceil(PACKET_PAYLOAD_SIZE).max(1)
+}
+
+/// packet_cost returns the bandwidth, in bytes, that sending a message of `len` bytes costs.
+fn packet_cost(len: usize) -> u64 {
+    packet_count(len) * PACKET_SIZE
+}
+
+struct State {
+    /// estimated bytes of bandwidth left
+    remaining: u64,
+    /// whether a top-up is in progress (or waiting to be retried)
+    topping_up: bool,
+}
+
+/// Bandwidth estimates how much of a mixnet client's bandwidth credentials are left,
+/// emitting an event once it's low and topping it up with the configured callback.
+#[derive(Clone)]
+pub(crate) struct Bandwidth {
+    state: Arc<Mutex<State>>,
+    config: CredentialsConfig,
+    events: EventSender,
+}
+
+impl Bandwidth {
+    pub(crate) fn new(config: CredentialsConfig, events: EventSender) -> Self {
+        Bandwidth {
+            state: Arc::new(Mutex::new(State {
+                remaining: config.allowance,
+                topping_up: false,
+            })),
+            config,
+            events,
+        }
+    }
+
+    /// remaining returns the estimated bytes of bandwidth left.
+    pub(crate) fn remaining(&self) -> u64 {
+        self.state.lock().remaining
+    }
+
+    /// on_send charges a message of `len` bytes against the remaining bandwidth.
+    pub(crate) fn on_send(&self, len: usize) {
+        let mut state = self.state.lock();
+        let was_low = state.remaining < self.config.low_threshold;
+        state.remaining = state.remaining.saturating_sub(packet_cost(len));
+        if state.remaining >= self.config.low_threshold {
+            return;
+        }
+
+        if !was_low {
+            debug!("bandwidth low: {} bytes remaining", state.remaining);
+            self.events.emit(NymEvent::BandwidthLow {
+                remaining: state.remaining,
+            });
+        }
+        if self.config.top_up.is_some() && !state.topping_up {
+            state.topping_up = true;
+            spawn(self.clone().top_up());
+        }
+    }
+
+    /// top_up buys more bandwidth with the configured callback.
+    async fn top_up(self) {
+        let Some(top_up) = self.config.top_up.clone() else {
+            return;
+        };
+        match top_up().await {
+            Ok(bytes) => {
+                let mut state = self.state.lock();
+                state.remaining = state.remaining.saturating_add(bytes);
+                state.topping_up = false;
+                self.events.emit(NymEvent::BandwidthToppedUp {
+                    remaining: state.remaining,
+                });
+            }
+            Err(e) => {
+                warn!("failed to top up bandwidth: {}", e);
+                // the next message sent while bandwidth is low tries again
+                sleep(TOP_UP_RETRY_DELAY).await;
+                self.state.lock().topping_up = false;
+            }
+        }
+    }
+}
+
+/// MeteredSender is a [`MixnetBackendSender`] which charges every message it sends
+/// against the client's remaining bandwidth.
+pub(crate) struct MeteredSender {
+    inner: Box<dyn MixnetBackendSender>,
+    bandwidth: Bandwidth,
+}
+
+impl MeteredSender {
+    pub(crate) fn new(inner: Box<dyn MixnetBackendSender>, bandwidth: Bandwidth) -> Self {
+        MeteredSender { inner, bandwidth }
+    }
+}
+
+impl MixnetBackendSender for MeteredSender {
+    fn send<'a>(
+        &'a self,
+        recipient: Recipient,
+        message: &'a [u8],
+        surbs: IncludedSurbs,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            self.inner.send(recipient, message, surbs).await?;
+            self.bandwidth.on_send(message.len());
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn send_reply<'a>(
+        &'a self,
+        sender_tag: AnonymousSenderTag,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            self.inner.send_reply(sender_tag, message).await?;
+            self.bandwidth.on_send(message.len());
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn test_bandwidth_top_up() {
+        let top_ups = Arc::new(AtomicU32::new(0));
+        let config = {
+            let top_ups = top_ups.clone();
+            CredentialsConfig::new(10 * PACKET_SIZE)
+                .with_low_threshold(6 * PACKET_SIZE)
+                .with_top_up(move || {
+                    top_ups.fetch_add(1, Ordering::SeqCst);
+                    async { Ok(10 * PACKET_SIZE) }
+                })
+        };
+        let events = EventSender::new(16);
+        let mut subscriber = Box::pin(events.subscribe());
+        let bandwidth = Bandwidth::new(config, events);
+
+        // a message costs every sphinx packet it takes up
+        bandwidth.on_send(1);
+        bandwidth.on_send(2 * PACKET_PAYLOAD_SIZE as usize + 1);
+        assert_eq!(bandwidth.remaining(), 6 * PACKET_SIZE);
+
+        // dropping below the threshold is reported once, and tops up the bandwidth
+        bandwidth.on_send(1);
+        assert_eq!(
+            subscriber.next().await,
+            Some(NymEvent::BandwidthLow {
+                remaining: 5 * PACKET_SIZE
+            })
+        );
+        assert_eq!(
+            subscriber.next().await,
+            Some(NymEvent::BandwidthToppedUp {
+                remaining: 15 * PACKET_SIZE
+            })
+        );
+        assert_eq!(top_ups.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,214 @@
+use nym_sdk::mixnet::AnonymousSenderTag;
+use nym_sphinx::addressing::clients::Recipient;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::message::{BatchMessage, ConnectionId, Message, OutboundMessage, TransportMessage};
+use super::runtime::Instant;
+
+/// PendingBatch is the TransportMessages of one connection waiting to be sent together.
+struct PendingBatch {
+    messages: Vec<TransportMessage>,
+    recipient: Option<Recipient>,
+    sender_tag: Option<AnonymousSenderTag>,
+    /// total size of the messages' batch entries
+    len: usize,
+    /// when the batch is sent, however full it is
+    deadline: Instant,
+}
+
+impl PendingBatch {
+    /// into_outbound returns the message that sends the batch; a batch of one
+    /// is sent as a plain TransportMessage.
+    fn into_outbound(mut self, id: ConnectionId) -> OutboundMessage {
+        let message = if self.messages.len() == 1 {
+            Message::TransportMessage(self.messages.remove(0))
+        } else {
+            Message::Batch(BatchMessage {
+                id,
+                messages: self.messages,
+            })
+        };
+        OutboundMessage {
+            message,
+            recipient: self.recipient,
+            sender_tag: self.sender_tag,
+        }
+    }
+}
+
+/// Batcher holds small outbound TransportMessages for up to a flush window, so that
+/// several of them going over the same connection are sent in one mixnet message.
+/// Every other message is passed through, sending the batch of its connection first
+/// so that the connection's messages don't overtake each other needlessly.
+pub(crate) struct Batcher {
+    window: Duration,
+    /// maximum total size of a batch's entries
+    max_len: usize,
+
+    /// connection ID -> messages waiting to be sent
+    pending: HashMap<ConnectionId, PendingBatch>,
+}
+
+impl Batcher {
+    /// new returns a batcher that holds messages for up to `window`, in batches of at
+    /// most `max_len` bytes.
+    pub(crate) fn new(window: Duration, max_len: usize) -> Self {
+        Batcher {
+            window,
+            max_len: max_len.min(u16::MAX as usize),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// push queues `message` in its connection's batch if it's small enough, and returns
+    /// the messages that are ready to be written, in order.
+    pub(crate) fn push(&mut self, message: OutboundMessage, now: Instant) -> Vec<OutboundMessage> {
+        let Message::TransportMessage(msg) = &message.message else {
+            return vec![message];
+        };
+        let id = msg.id.clone();
+        let len = BatchMessage::entry_len(msg);
+        if len > self.max_len / 2 || msg.timestamp.is_some() {
+            // there's little to gain from batching large messages, and a batch entry
+            // has no room for a timestamp
+            let mut ready = self.flush(&id);
+            ready.push(message);
+            return ready;
+        }
+
+        let mut ready = vec![];
+        if let Some(batch) = self.pending.get(&id) {
+            if batch.len + len > self.max_len {
+                ready = self.flush(&id);
+            }
+        }
+
+        let Message::TransportMessage(msg) = message.message else {
+            unreachable!("checked above");
+        };
+        let deadline = now + self.window;
+        let batch = self.pending.entry(id).or_insert_with(|| PendingBatch {
+            messages: vec![],
+            recipient: message.recipient,
+            sender_tag: message.sender_tag,
+            len: 0,
+            deadline,
+        });
+        batch.messages.push(msg);
+        batch.len += len;
+        ready
+    }
+
+    /// next_deadline returns when the next batch is due to be sent, if any.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|batch| batch.deadline).min()
+    }
+
+    /// poll_due returns the batches due to be sent at `now`.
+    pub(crate) fn poll_due(&mut self, now: Instant) -> Vec<OutboundMessage> {
+        let due: Vec<ConnectionId> = self
+            .pending
+            .iter()
+            .filter(|(_, batch)| batch.deadline <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        due.into_iter().flat_map(|id| self.flush(&id)).collect()
+    }
+
+    /// drain returns every pending batch, e.g. on shutdown.
+    pub(crate) fn drain(&mut self) -> Vec<OutboundMessage> {
+        self.pending
+            .drain()
+            .map(|(id, batch)| batch.into_outbound(id))
+            .collect()
+    }
+
+    fn flush(&mut self, id: &ConnectionId) -> Vec<OutboundMessage> {
+        self.pending
+            .remove(id)
+            .map(|batch| batch.into_outbound(id.clone()))
+            .into_iter()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::message::{SubstreamId, SubstreamMessage};
+    use super::super::nonce::Nonce;
+    use super::*;
+    use bytes::Bytes;
+
+    fn outbound(id: &ConnectionId, nonce: u64, len: usize) -> OutboundMessage {
+        OutboundMessage {
+            message: Message::TransportMessage(TransportMessage {
+                nonce: Nonce::new(nonce),
+                id: id.clone(),
+                message: SubstreamMessage::new_with_data(
+                    SubstreamId::generate(),
+                    Bytes::from(vec![0u8; len]),
+                ),
+                timestamp: None,
+            }),
+            recipient: None,
+            sender_tag: None,
+        }
+    }
+
+    #[test]
+    fn test_batcher() {
+        let mut batcher = Batcher::new(Duration::from_millis(50), 1000);
+        let id = ConnectionId::generate();
+        let other = ConnectionId::generate();
+        let start = Instant::now();
+
+        // small messages are held until the window passes
+        assert!(batcher.push(outbound(&id, 1, 10), start).is_empty());
+        assert!(batcher.push(outbound(&id, 2, 10), start).is_empty());
+        assert!(batcher.push(outbound(&other, 1, 10), start).is_empty());
+        assert_eq!(
+            batcher.next_deadline(),
+            Some(start + Duration::from_millis(50))
+        );
+        assert!(batcher.poll_due(start).is_empty());
+
+        let mut due = batcher.poll_due(start + Duration::from_millis(50));
+        due.sort_by_key(|message| message.message.connection_id() == &id);
+        assert_eq!(due.len(), 2);
+        // a batch of one is sent as-is
+        assert!(matches!(due[0].message, Message::TransportMessage(_)));
+        let Message::Batch(batch) = &due[1].message else {
+            panic!("expected Message::Batch");
+        };
+        assert_eq!(batch.id, id);
+        assert_eq!(
+            batch
+                .messages
+                .iter()
+                .map(|msg| msg.nonce.get())
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(batcher.next_deadline(), None);
+
+        // a full batch is sent as soon as the next message doesn't fit
+        for nonce in 3..7 {
+            assert!(batcher.push(outbound(&id, nonce, 200), start).is_empty());
+        }
+        let ready = batcher.push(outbound(&id, 7, 200), start);
+        assert_eq!(ready.len(), 1);
+        assert!(matches!(&ready[0].message, Message::Batch(batch) if batch.messages.len() == 4));
+
+        // and a large message is sent straight away, after the batch ahead of it
+        let ready = batcher.push(outbound(&id, 8, 900), start);
+        assert_eq!(ready.len(), 2);
+        assert!(
+            matches!(&ready[0].message, Message::TransportMessage(msg) if msg.nonce.get() == 7)
+        );
+        assert!(
+            matches!(&ready[1].message, Message::TransportMessage(msg) if msg.nonce.get() == 8)
+        );
+        assert!(batcher.drain().is_empty());
+    }
+}
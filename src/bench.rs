@@ -0,0 +1,100 @@
+//! Entry points for the criterion benchmarks in `benches/`. The wire format is internal to
+//! the crate, so this module is only built with the `bench` feature, which the benchmarks
+//! that need it require.
+
+use bytes::Bytes;
+#[cfg(feature = "parallel-codec")]
+use nym_sphinx::receiver::ReconstructedMessage;
+
+#[cfg(feature = "parallel-codec")]
+use super::config::NymTransportConfig;
+use super::config::DEFAULT_MAX_MESSAGE_SIZE;
+#[cfg(feature = "parallel-codec")]
+use super::message::OutboundMessage;
+use super::message::{
+    parse_message_data, ConnectionId, Message, SubstreamId, SubstreamMessage, TransportMessage,
+};
+use super::nonce::Nonce;
+#[cfg(feature = "parallel-codec")]
+pub use super::pool::MAX_CODEC_BATCH;
+
+/// DataMessage is a TransportMessage carrying data on a substream, the message most of
+/// a connection's traffic is made of.
+pub struct DataMessage(Message);
+
+impl DataMessage {
+    /// new returns a message carrying `payload` on a new substream of a new connection.
+    pub fn new(payload: Bytes) -> Self {
+        DataMessage(Message::TransportMessage(TransportMessage {
+            nonce: Nonce::FIRST,
+            message: SubstreamMessage::new_with_data(SubstreamId::generate(), payload),
+            id: ConnectionId::generate(),
+            timestamp: None,
+        }))
+    }
+
+    /// to_bytes encodes the message as it's sent to the mixnet.
+    pub fn to_bytes(&self) -> Bytes {
+        self.0.to_bytes()
+    }
+
+    /// decode decodes a message as it's received from the mixnet, panicking if `data`
+    /// isn't a valid message.
+    pub fn decode(data: Bytes) -> Self {
+        let msg = parse_message_data(data, None, DEFAULT_MAX_MESSAGE_SIZE)
+            .expect("benchmark message decodes");
+        DataMessage(msg.0)
+    }
+}
+
+/// CodecPool encodes and decodes batches of messages as the mixnet task does, on a pool
+/// of worker threads, or on the calling task without one.
+#[cfg(feature = "parallel-codec")]
+pub struct CodecPool(super::pool::CodecPool);
+
+#[cfg(feature = "parallel-codec")]
+impl CodecPool {
+    /// new returns a pool of `workers` threads, or no pool if None.
+    pub fn new(workers: Option<usize>) -> Self {
+        let config = match workers {
+            Some(workers) => NymTransportConfig::default().with_codec_workers(workers),
+            None => NymTransportConfig::default(),
+        };
+        CodecPool(super::pool::CodecPool::new(&config).expect("codec pool starts"))
+    }
+
+    /// encode encodes `messages` together, returning their bytes in the same order.
+    pub async fn encode(&self, messages: Vec<DataMessage>) -> Vec<Bytes> {
+        let messages = messages
+            .into_iter()
+            .map(|message| OutboundMessage {
+                message: message.0,
+                recipient: None,
+                sender_tag: None,
+            })
+            .collect();
+        let encoded = self.0.encode(messages).await.expect("codec pool encodes");
+        encoded.into_iter().map(|(_, bytes)| bytes).collect()
+    }
+
+    /// decode decodes `messages` together, panicking if any of them isn't a valid
+    /// message.
+    pub async fn decode(&self, messages: Vec<Vec<u8>>) -> Vec<DataMessage> {
+        let messages = messages
+            .into_iter()
+            .map(|message| ReconstructedMessage {
+                message,
+                sender_tag: None,
+            })
+            .collect();
+        let decoded = self
+            .0
+            .decode(messages, DEFAULT_MAX_MESSAGE_SIZE)
+            .await
+            .expect("codec pool decodes");
+        decoded
+            .into_iter()
+            .map(|decoded| DataMessage(decoded.data.expect("benchmark message decodes").0))
+            .collect()
+    }
+}
@@ -0,0 +1,273 @@
+//! nym-libp2p-tool checks connectivity over the Nym mixnet without writing any code:
+//!
+//! - `addr` prints the nym multiaddr other peers can dial us at;
+//! - `ping <multiaddr>` pings a peer with libp2p's ping protocol, like the `ping`
+//!   example, and prints the round trips;
+//! - `probe <multiaddr>` dials a peer with the transport alone, and prints how long
+//!   the handshake took and the round trips of the connection's keepalives, which
+//!   don't depend on the remote running ping.
+//!
+//! `ping` and `probe` exit successfully once `--count` round trips (5 by default)
+//! have been measured, and with an error if the dial fails or they take longer than
+//! `--timeout` seconds (600 by default). With `--storage <dir>`, the mixnet client
+//! keeps its keys in `dir`, so the nym address stays the same across runs; otherwise
+//! every run gets a fresh one.
+
+use futures::future::poll_fn;
+use futures::StreamExt;
+use libp2p::core::muxing::StreamMuxerExt;
+use libp2p::core::transport::{DialOpts, PortUse};
+use libp2p::core::{Endpoint, Transport};
+use libp2p::{ping, swarm::SwarmEvent, Multiaddr, SwarmBuilder};
+use libp2p_identity::Keypair;
+use log::LevelFilter;
+use rust_libp2p_nym::events::NymEvent;
+use rust_libp2p_nym::{presets, NymTransport, NymTransportConfig};
+use std::error::Error;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage: nym-libp2p-tool [--storage <dir>] <command>
+
+commands:
+    addr                                  print our nym multiaddr
+    ping <multiaddr> [--count <n>] [--timeout <secs>]
+                                          ping a peer with libp2p ping
+    probe <multiaddr> [--count <n>] [--timeout <secs>]
+                                          measure the handshake and keepalive round trips";
+
+/// the number of round trips `ping` and `probe` measure by default.
+const DEFAULT_COUNT: usize = 5;
+
+/// how long `ping` and `probe` wait for all of their round trips by default.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// time between the pings, or keepalives, whose round trips are measured.
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Command {
+    Addr,
+    Ping(Multiaddr),
+    Probe(Multiaddr),
+}
+
+/// Args are the tool's command-line arguments.
+struct Args {
+    command: Command,
+    storage: Option<PathBuf>,
+    count: usize,
+    timeout: Duration,
+}
+
+impl Args {
+    fn parse() -> Result<Self, Box<dyn Error>> {
+        let mut command = None;
+        let mut remote = None;
+        let mut storage = None;
+        let mut count = None;
+        let mut timeout = None;
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--storage" => {
+                    let dir = iter.next().ok_or("--storage needs a directory")?;
+                    storage = Some(PathBuf::from(dir));
+                }
+                "--count" => {
+                    let n = iter.next().ok_or("--count needs a number")?;
+                    count = Some(n.parse()?);
+                }
+                "--timeout" => {
+                    let secs = iter.next().ok_or("--timeout needs a number of seconds")?;
+                    timeout = Some(Duration::from_secs(secs.parse()?));
+                }
+                "-h" | "--help" => return Err(USAGE.into()),
+                _ if command.is_none() => command = Some(arg),
+                _ if remote.is_none() => remote = Some(arg.parse::<Multiaddr>()?),
+                _ => return Err(format!("unexpected argument {arg}\n\n{USAGE}").into()),
+            }
+        }
+
+        let command = match (command.as_deref(), remote) {
+            (Some("addr"), None) => Command::Addr,
+            (Some("ping"), Some(remote)) => Command::Ping(remote),
+            (Some("probe"), Some(remote)) => Command::Probe(remote),
+            _ => return Err(USAGE.into()),
+        };
+        if matches!(command, Command::Addr) && (count.is_some() || timeout.is_some()) {
+            return Err("--count and --timeout can only be used with ping and probe".into());
+        }
+        Ok(Args {
+            command,
+            storage,
+            count: count.unwrap_or(DEFAULT_COUNT),
+            timeout: timeout.unwrap_or(DEFAULT_TIMEOUT),
+        })
+    }
+
+    /// transport returns a transport with `config`, whose mixnet client keeps its keys
+    /// in the storage directory, if there is one.
+    async fn transport(
+        &self,
+        keypair: &Keypair,
+        config: NymTransportConfig,
+    ) -> Result<NymTransport, Box<dyn Error>> {
+        let transport = match &self.storage {
+            Some(dir) => {
+                NymTransport::new_from_storage_with_config(dir, keypair.clone(), config).await?
+            }
+            None => NymTransport::new_ephemeral_with_config(keypair.clone(), config).await?,
+        };
+        Ok(transport)
+    }
+}
+
+/// print_rtts prints the minimum, average and maximum of `rtts`.
+fn print_rtts(rtts: &[Duration]) {
+    let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) else {
+        return;
+    };
+    let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+    println!(
+        "{} round trips: min {min:?}, avg {avg:?}, max {max:?}",
+        rtts.len()
+    );
+}
+
+/// ping pings `remote` with libp2p ping over a swarm, until `count` pings have made it
+/// there and back.
+async fn ping(
+    transport: NymTransport,
+    keypair: Keypair,
+    remote: Multiaddr,
+    count: usize,
+    rtts: &mut Vec<Duration>,
+) -> Result<(), Box<dyn Error>> {
+    let mut swarm = SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_other_transport(|_| transport)?
+        .with_behaviour(|_| {
+            ping::Behaviour::new(presets::ping_config_for_nym().with_interval(PROBE_INTERVAL))
+        })?
+        .with_swarm_config(presets::swarm_config_for_nym)
+        .build();
+    swarm.dial(remote.clone())?;
+    println!("Dialed {remote}");
+
+    while rtts.len() < count {
+        match swarm.select_next_some().await {
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                println!("Connected to {peer_id}");
+            }
+            SwarmEvent::Behaviour(ping::Event {
+                result: Ok(rtt), ..
+            }) => {
+                rtts.push(rtt);
+                println!("Round trip {}/{count}: {rtt:?}", rtts.len());
+            }
+            SwarmEvent::Behaviour(ping::Event { result: Err(e), .. }) => {
+                return Err(format!("ping failed: {e}").into());
+            }
+            SwarmEvent::OutgoingConnectionError { error, .. } => {
+                return Err(format!("dial failed: {error}").into());
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// probe dials `remote` with the transport alone, without a swarm, and measures the
+/// handshake, then the round trips of `count` keepalives on the connection.
+async fn probe(
+    mut transport: NymTransport,
+    remote: Multiaddr,
+    count: usize,
+    rtts: &mut Vec<Duration>,
+) -> Result<(), Box<dyn Error>> {
+    let mut events = Box::pin(transport.events());
+    let opts = DialOpts {
+        role: Endpoint::Dialer,
+        port_use: PortUse::Reuse,
+    };
+    let start = Instant::now();
+    let mut dial = transport.dial(remote.clone(), opts)?;
+    println!("Dialed {remote}");
+
+    // the transport only makes progress while it's polled
+    let (peer_id, mut connection) = loop {
+        tokio::select! {
+            res = &mut dial => break res?,
+            _ = poll_fn(|cx| Pin::new(&mut transport).poll(cx)) => {}
+        }
+    };
+    println!(
+        "Connected to {peer_id}: handshake took {:?}",
+        start.elapsed()
+    );
+
+    while rtts.len() < count {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(NymEvent::RoundTrip { peer_id: from, rtt, .. }) if from == peer_id => {
+                    rtts.push(rtt);
+                    println!("Round trip {}/{count}: {rtt:?}", rtts.len());
+                }
+                Some(_) => {}
+                None => return Err("transport shut down".into()),
+            },
+            _ = poll_fn(|cx| Pin::new(&mut transport).poll(cx)) => {}
+            res = poll_fn(|cx| connection.poll_unpin(cx)) => {
+                res.map_err(|e| format!("connection failed: {e}"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    pretty_env_logger::formatted_timed_builder()
+        .filter_level(LevelFilter::Warn)
+        .init();
+
+    let args = Args::parse()?;
+    let keypair = Keypair::generate_ed25519();
+    let (remote, probing) = match &args.command {
+        Command::Addr => {
+            let transport = args
+                .transport(&keypair, NymTransportConfig::default())
+                .await?;
+            println!("{}", transport.listen_addr());
+            return Ok(());
+        }
+        Command::Ping(remote) => (remote.clone(), false),
+        Command::Probe(remote) => (remote.clone(), true),
+    };
+
+    let mut rtts = Vec::with_capacity(args.count);
+    let res = if probing {
+        // a keepalive every interval, each of which measures a round trip
+        let config = NymTransportConfig::default();
+        let max_missed = config.keepalive_max_missed;
+        let config = config.with_keepalive(PROBE_INTERVAL, max_missed);
+        let transport = args.transport(&keypair, config).await?;
+        tokio::time::timeout(
+            args.timeout,
+            probe(transport, remote, args.count, &mut rtts),
+        )
+        .await
+    } else {
+        let transport = args
+            .transport(&keypair, NymTransportConfig::default())
+            .await?;
+        let ping = ping(transport, keypair, remote, args.count, &mut rtts);
+        tokio::time::timeout(args.timeout, ping).await
+    };
+    print_rtts(&rtts);
+    match res {
+        Ok(res) => res,
+        Err(_) => Err(format!("timed out after {:?}", args.timeout).into()),
+    }
+}
@@ -0,0 +1,198 @@
+use libp2p_identity::PeerId;
+use nym_sdk::mixnet::AnonymousSenderTag;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::message::ConnectionId;
+use super::runtime::Instant;
+
+/// SenderTagBindings binds each connection we can only reply to through SURBs to the
+/// sender tag of the remote that opened it, since the tag is all that tells its
+/// messages apart from anyone else's. Messages on the connection that come with
+/// another tag, or none, were sent by someone who learned the connection ID, and are
+/// dropped rather than let them close, or reply into, a connection that isn't theirs.
+///
+/// A binding lasts until its connection is closed, or until the remote goes longer
+/// than the TTL without sending on it, after which its SURBs are likely expired anyway.
+#[derive(Debug, Default)]
+pub(crate) struct SenderTagBindings {
+    bindings: HashMap<ConnectionId, Binding>,
+}
+
+#[derive(Debug)]
+struct Binding {
+    sender_tag: AnonymousSenderTag,
+    peer_id: PeerId,
+    last_seen: Instant,
+}
+
+impl SenderTagBindings {
+    /// bind binds connection `id` to `peer_id`'s `sender_tag`, which it was opened with
+    /// at `now`. It returns false, leaving the existing binding, if the connection is
+    /// already bound to another tag.
+    pub(crate) fn bind(
+        &mut self,
+        id: ConnectionId,
+        sender_tag: AnonymousSenderTag,
+        peer_id: PeerId,
+        now: Instant,
+    ) -> bool {
+        if let Some(binding) = self.bindings.get(&id) {
+            if binding.sender_tag != sender_tag {
+                return false;
+            }
+        }
+        self.bindings.insert(
+            id,
+            Binding {
+                sender_tag,
+                peer_id,
+                last_seen: now,
+            },
+        );
+        true
+    }
+
+    /// verify returns false if connection `id` is bound to a tag other than
+    /// `sender_tag`. A message with the bound tag refreshes the binding, and one on a
+    /// connection that isn't bound is always let through.
+    pub(crate) fn verify(
+        &mut self,
+        id: &ConnectionId,
+        sender_tag: Option<&AnonymousSenderTag>,
+        now: Instant,
+    ) -> bool {
+        let Some(binding) = self.bindings.get_mut(id) else {
+            return true;
+        };
+        if sender_tag != Some(&binding.sender_tag) {
+            return false;
+        }
+        binding.last_seen = now;
+        true
+    }
+
+    /// unbind forgets the binding of connection `id`, returning the peer it was bound to.
+    pub(crate) fn unbind(&mut self, id: &ConnectionId) -> Option<PeerId> {
+        self.bindings.remove(id).map(|binding| binding.peer_id)
+    }
+
+    /// expire forgets the bindings that haven't been refreshed for `ttl`, and returns
+    /// their connections and peers.
+    pub(crate) fn expire(&mut self, ttl: Duration, now: Instant) -> Vec<(ConnectionId, PeerId)> {
+        let mut expired = vec![];
+        self.bindings.retain(|id, binding| {
+            if now.saturating_duration_since(binding.last_seen) < ttl {
+                return true;
+            }
+            expired.push((id.clone(), binding.peer_id));
+            false
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::backend::MixnetBackend;
+    use super::super::codec::{Codec, WireCodec};
+    use super::super::config::NymTransportConfig;
+    use super::super::events::{DropReason, NymEvent};
+    use super::super::memory::{InMemoryConfig, InMemoryMixnet};
+    use super::super::message::{ConnectionCloseMessage, Message};
+    use super::super::snapshot::ConnectionStatus;
+    use super::super::testing::{self, connect, next_event};
+    use super::*;
+    use futures::StreamExt;
+    use nym_sdk::mixnet::IncludedSurbs;
+
+    fn sender_tag() -> AnonymousSenderTag {
+        AnonymousSenderTag::new_random(&mut rand::thread_rng())
+    }
+
+    #[test]
+    fn test_sender_tag_bindings() {
+        let mut bindings = SenderTagBindings::default();
+        let (id, tag, peer_id) = (ConnectionId::generate(), sender_tag(), PeerId::random());
+        let now = Instant::now();
+        assert!(bindings.bind(id.clone(), tag.clone(), peer_id, now));
+
+        assert!(bindings.verify(&id, Some(&tag), now));
+        // someone else's tag, or none, can't take over the connection
+        let other = sender_tag();
+        assert!(!bindings.verify(&id, Some(&other), now));
+        assert!(!bindings.verify(&id, None, now));
+        assert!(!bindings.bind(id.clone(), other.clone(), PeerId::random(), now));
+        assert!(bindings.verify(&id, Some(&tag), now));
+
+        // connections that aren't bound aren't checked
+        assert!(bindings.verify(&ConnectionId::generate(), Some(&other), now));
+        assert!(bindings.verify(&ConnectionId::generate(), None, now));
+
+        // once the connection is closed, its ID is free again
+        assert_eq!(bindings.unbind(&id), Some(peer_id));
+        assert_eq!(bindings.unbind(&id), None);
+        assert!(bindings.verify(&id, Some(&other), now));
+    }
+
+    #[test]
+    fn test_sender_tag_bindings_expire() {
+        let ttl = Duration::from_secs(60);
+        let mut bindings = SenderTagBindings::default();
+        let (id, tag, peer_id) = (ConnectionId::generate(), sender_tag(), PeerId::random());
+        let start = Instant::now();
+        bindings.bind(id.clone(), tag.clone(), peer_id, start);
+        assert!(bindings.expire(ttl, start + ttl / 2).is_empty());
+
+        // a message with the bound tag restarts its clock, and one without doesn't
+        assert!(bindings.verify(&id, Some(&tag), start + ttl / 2));
+        assert!(!bindings.verify(&id, Some(&sender_tag()), start + ttl));
+        assert!(bindings.expire(ttl, start + ttl).is_empty());
+
+        let now = start + ttl * 3 / 2;
+        assert_eq!(bindings.expire(ttl, now), vec![(id.clone(), peer_id)]);
+        assert!(bindings.expire(ttl, now).is_empty());
+        assert!(bindings.verify(&id, Some(&sender_tag()), now));
+    }
+
+    #[tokio::test]
+    async fn test_sender_tag_binding_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let mut dialer = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        let mut listener = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        let mut dropped = Box::pin(listener.events().filter_map(|event| async move {
+            match event {
+                NymEvent::MessageDropped { reason } => Some(reason),
+                _ => None,
+            }
+        }));
+        let listener_addr = listener.listen_addr().clone();
+        let (_dialed, _accepted) = connect(&mut dialer, &mut listener, listener_addr).await;
+
+        // someone who learned the ID of the anonymous dialer's connection can't close it
+        let snapshot = listener.debug_snapshot();
+        let id = ConnectionId::from_bytes(&hex::decode(&snapshot.connections[0].id).unwrap());
+        let close = Message::ConnectionClose(ConnectionCloseMessage { id });
+        mixnet
+            .client()
+            .sender()
+            .send(
+                listener.local_nym_address(),
+                &WireCodec::encode(&close),
+                IncludedSurbs::Amount(1),
+            )
+            .await
+            .unwrap();
+        let reason = loop {
+            tokio::select! {
+                reason = dropped.next() => break reason,
+                _ = next_event(&mut listener) => {}
+            }
+        };
+        assert_eq!(reason, Some(DropReason::SenderTagMismatch));
+        assert_eq!(
+            listener.debug_snapshot().connections[0].status,
+            ConnectionStatus::Established
+        );
+    }
+}
@@ -0,0 +1,591 @@
+use futures::Stream;
+use log::warn;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::poll_fn,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
+};
+
+use super::config::OverflowPolicy;
+
+/// TrySendError is returned by [`BoundedSender::try_send`] when an item could
+/// not be queued; the item is handed back to the caller.
+#[derive(Debug)]
+pub(crate) enum TrySendError<T> {
+    /// the channel is at capacity and the policy is `OverflowPolicy::Backpressure`.
+    Full(T),
+    /// the receiver has been dropped.
+    Closed(T),
+}
+
+impl<T> std::fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "channel full"),
+            TrySendError::Closed(_) => write!(f, "channel closed"),
+        }
+    }
+}
+
+/// QueueId identifies the queue a sender's items go into; senders created by
+/// [`BoundedSender::split`] each have their own.
+type QueueId = u64;
+
+/// DEFERRED_QUEUE is the queue that items for which `Shared::is_deferred` returns true
+/// go into, whichever sender sent them.
+const DEFERRED_QUEUE: QueueId = QueueId::MAX;
+
+struct State<T> {
+    /// regular items, by the queue they were sent into.
+    queues: HashMap<QueueId, VecDeque<T>>,
+    /// the queues holding regular items, in the order they're drained: the receiver
+    /// takes one item from the front queue, and moves it to the back.
+    round: VecDeque<QueueId>,
+    /// items for which `Shared::is_priority` returned true; always received
+    /// before anything in `queues`.
+    priority_queue: VecDeque<(QueueId, T)>,
+    /// number of items in each queue, counting both tiers.
+    lens: HashMap<QueueId, usize>,
+    next_queue: QueueId,
+    /// number of live senders; the receiver sees the end of the stream once
+    /// this reaches zero and the queue is drained.
+    senders: usize,
+    receiver_closed: bool,
+    /// whether the receiver leaves the deferred queue's items where they are.
+    holding: bool,
+    recv_waker: Option<Waker>,
+    /// senders waiting for capacity under `OverflowPolicy::Backpressure`.
+    send_wakers: Vec<Waker>,
+}
+
+impl<T> State<T> {
+    fn len(&self, queue: QueueId) -> usize {
+        self.lens.get(&queue).copied().unwrap_or(0)
+    }
+
+    fn push(&mut self, queue: QueueId, item: T, priority: bool) {
+        *self.lens.entry(queue).or_default() += 1;
+        if priority {
+            self.priority_queue.push_back((queue, item));
+            return;
+        }
+        let items = self.queues.entry(queue).or_default();
+        if items.is_empty() {
+            self.round.push_back(queue);
+        }
+        items.push_back(item);
+    }
+
+    fn pop_front(&mut self) -> Option<T> {
+        let (queue, item) = match self.priority_queue.pop_front() {
+            Some(entry) => entry,
+            None => {
+                let index = match self.holding {
+                    true => self.round.iter().position(|q| *q != DEFERRED_QUEUE)?,
+                    false => 0,
+                };
+                let queue = self.round.remove(index)?;
+                (queue, self.pop_regular(queue, false)?)
+            }
+        };
+        self.release(queue);
+        Some(item)
+    }
+
+    /// pop_oldest discards the oldest item in `queue`, preferring a regular item
+    /// over a priority one.
+    fn pop_oldest(&mut self, queue: QueueId) {
+        let popped = match self.pop_regular(queue, true) {
+            Some(_) => true,
+            None => match self.priority_queue.iter().position(|(q, _)| *q == queue) {
+                Some(i) => self.priority_queue.remove(i).is_some(),
+                None => false,
+            },
+        };
+        if popped {
+            self.release(queue);
+        }
+    }
+
+    /// pop_regular takes the next regular item from `queue`, keeping `round` in step.
+    /// `in_round` is whether the queue is still in `round`.
+    fn pop_regular(&mut self, queue: QueueId, in_round: bool) -> Option<T> {
+        let items = self.queues.get_mut(&queue)?;
+        let item = items.pop_front();
+        match (items.is_empty(), in_round) {
+            (true, true) => {
+                self.queues.remove(&queue);
+                self.round.retain(|q| *q != queue);
+            }
+            (true, false) => {
+                self.queues.remove(&queue);
+            }
+            (false, true) => {}
+            (false, false) => self.round.push_back(queue),
+        }
+        item
+    }
+
+    fn release(&mut self, queue: QueueId) {
+        if let Some(len) = self.lens.get_mut(&queue) {
+            *len -= 1;
+            if *len == 0 {
+                self.lens.remove(&queue);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.queues.clear();
+        self.round.clear();
+        self.priority_queue.clear();
+        self.lens.clear();
+    }
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// decides which items skip ahead of the rest of the queue, if any.
+    is_priority: Option<fn(&T) -> bool>,
+    /// decides which items go into the deferred queue, if any.
+    is_deferred: Option<fn(&T) -> bool>,
+    /// number of items discarded by the overflow policy.
+    dropped: AtomicU64,
+}
+
+/// bounded creates a multi-producer, single-consumer channel holding at most
+/// `capacity` items. What happens when the channel is full is determined by `policy`.
+/// Senders split off with [`BoundedSender::split`] get a queue of their own, holding
+/// up to `capacity` items too, and the receiver takes from the queues in turn.
+pub(crate) fn bounded<T>(
+    capacity: usize,
+    policy: OverflowPolicy,
+) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    new_bounded(capacity, policy, None, None)
+}
+
+/// bounded_with_priority creates a [`bounded`] channel with two tiers: items for
+/// which `is_priority` returns true are received ahead of all other queued items.
+/// Both tiers share the channel's capacity, and items within a tier stay in order.
+pub(crate) fn bounded_with_priority<T>(
+    capacity: usize,
+    policy: OverflowPolicy,
+    is_priority: fn(&T) -> bool,
+) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    new_bounded(capacity, policy, Some(is_priority), None)
+}
+
+/// bounded_with_deferred creates a [`bounded`] channel in which items for which
+/// `is_deferred` returns true go into a queue of their own, whoever sends them, with
+/// a capacity of its own. The receiver can leave them queued, with
+/// [`BoundedReceiver::hold_deferred`], while it goes on taking the other items.
+pub(crate) fn bounded_with_deferred<T>(
+    capacity: usize,
+    policy: OverflowPolicy,
+    is_deferred: fn(&T) -> bool,
+) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    new_bounded(capacity, policy, None, Some(is_deferred))
+}
+
+fn new_bounded<T>(
+    capacity: usize,
+    policy: OverflowPolicy,
+    is_priority: Option<fn(&T) -> bool>,
+    is_deferred: Option<fn(&T) -> bool>,
+) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queues: HashMap::new(),
+            round: VecDeque::new(),
+            priority_queue: VecDeque::new(),
+            lens: HashMap::new(),
+            next_queue: 1,
+            senders: 1,
+            receiver_closed: false,
+            holding: false,
+            recv_waker: None,
+            send_wakers: vec![],
+        }),
+        capacity: capacity.max(1),
+        policy,
+        is_priority,
+        is_deferred,
+        dropped: AtomicU64::new(0),
+    });
+
+    (
+        BoundedSender {
+            shared: shared.clone(),
+            queue: 0,
+        },
+        BoundedReceiver { shared },
+    )
+}
+
+/// BoundedSender is the sending half of a [`bounded`] channel.
+pub(crate) struct BoundedSender<T> {
+    shared: Arc<Shared<T>>,
+    /// the queue this sender and its clones send into.
+    queue: QueueId,
+}
+
+impl<T> BoundedSender<T> {
+    /// try_send queues an item without waiting.
+    /// If the channel is full, the overflow policy decides whether the oldest
+    /// queued item is discarded, the new item is discarded, or the item is
+    /// handed back with `TrySendError::Full`.
+    pub(crate) fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.shared.state.lock();
+        if state.receiver_closed {
+            return Err(TrySendError::Closed(item));
+        }
+
+        let queue = match self.shared.is_deferred {
+            Some(is_deferred) if is_deferred(&item) => DEFERRED_QUEUE,
+            _ => self.queue,
+        };
+        if state.len(queue) >= self.shared.capacity {
+            match self.shared.policy {
+                OverflowPolicy::Backpressure => return Err(TrySendError::Full(item)),
+                OverflowPolicy::DropOldest => {
+                    state.pop_oldest(queue);
+                    let dropped = self.shared.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                    warn!("channel full; dropped oldest queued message ({dropped} dropped so far)");
+                }
+                OverflowPolicy::DropNewest => {
+                    let dropped = self.shared.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                    warn!("channel full; dropped newest message ({dropped} dropped so far)");
+                    return Ok(());
+                }
+            }
+        }
+
+        let priority = self
+            .shared
+            .is_priority
+            .is_some_and(|is_priority| is_priority(&item));
+        state.push(queue, item, priority);
+        if let Some(waker) = state.recv_waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// len returns the number of items queued by this sender and its clones.
+    pub(crate) fn len(&self) -> usize {
+        self.shared.state.lock().len(self.queue)
+    }
+
+    /// room returns the number of items this sender and its clones can queue before
+    /// the channel is full.
+    pub(crate) fn room(&self) -> usize {
+        self.shared.capacity.saturating_sub(self.len())
+    }
+
+    /// channel_len returns the number of items queued by every sender of the channel.
+    pub(crate) fn channel_len(&self) -> usize {
+        self.shared.state.lock().lens.values().sum()
+    }
+
+    /// dropped returns the number of items discarded by the overflow policy so far.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// poll_ready resolves once an item can be queued without hitting the
+    /// overflow policy. It only ever returns Pending under `OverflowPolicy::Backpressure`.
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+        let mut state = self.shared.state.lock();
+        if state.receiver_closed {
+            return Poll::Ready(Err(()));
+        }
+
+        if self.shared.policy != OverflowPolicy::Backpressure
+            || state.len(self.queue) < self.shared.capacity
+        {
+            return Poll::Ready(Ok(()));
+        }
+
+        state.send_wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// ready waits until the channel has capacity (see [`BoundedSender::poll_ready`]).
+    pub(crate) async fn ready(&self) -> Result<(), ()> {
+        poll_fn(|cx| self.poll_ready(cx)).await
+    }
+
+    /// send queues an item, waiting for capacity if the policy is `OverflowPolicy::Backpressure`.
+    pub(crate) async fn send(&self, item: T) -> Result<(), TrySendError<T>> {
+        let mut item = item;
+        loop {
+            if self.ready().await.is_err() {
+                return Err(TrySendError::Closed(item));
+            }
+
+            match self.try_send(item) {
+                Err(TrySendError::Full(returned)) => item = returned,
+                res => return res,
+            }
+        }
+    }
+
+    /// split returns a sender with a queue of its own, so that the items it sends
+    /// aren't held up behind those of other senders, nor take up their capacity.
+    /// Priority items still skip ahead of every queue.
+    pub(crate) fn split(&self) -> BoundedSender<T> {
+        let mut state = self.shared.state.lock();
+        state.senders += 1;
+        let queue = state.next_queue;
+        state.next_queue += 1;
+        BoundedSender {
+            shared: self.shared.clone(),
+            queue,
+        }
+    }
+}
+
+impl<T> Clone for BoundedSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().senders += 1;
+        BoundedSender {
+            shared: self.shared.clone(),
+            queue: self.queue,
+        }
+    }
+}
+
+impl<T> Drop for BoundedSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            if let Some(waker) = state.recv_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for BoundedSender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BoundedSender")
+            .field("capacity", &self.shared.capacity)
+            .field("policy", &self.shared.policy)
+            .finish()
+    }
+}
+
+/// BoundedReceiver is the receiving half of a [`bounded`] channel.
+pub(crate) struct BoundedReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> BoundedReceiver<T> {
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut state = self.shared.state.lock();
+        if let Some(item) = state.pop_front() {
+            // a slot has been freed, let any waiting senders know
+            for waker in state.send_wakers.drain(..) {
+                waker.wake();
+            }
+            return Poll::Ready(Some(item));
+        }
+
+        if state.senders == 0 {
+            return Poll::Ready(None);
+        }
+
+        state.recv_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub(crate) async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// len returns the number of items waiting to be received.
+    pub(crate) fn len(&self) -> usize {
+        self.shared.state.lock().lens.values().sum()
+    }
+
+    /// hold_deferred sets whether the items in the deferred queue are left queued, so
+    /// that they're received only once they're no longer held. Senders of deferred
+    /// items meet the overflow policy once the deferred queue fills up.
+    pub(crate) fn hold_deferred(&self, hold: bool) {
+        self.shared.state.lock().holding = hold;
+    }
+
+    /// try_recv takes the next queued item, if there is one, without waiting.
+    pub(crate) fn try_recv(&mut self) -> Option<T> {
+        let mut state = self.shared.state.lock();
+        let item = state.pop_front()?;
+        for waker in state.send_wakers.drain(..) {
+            waker.wake();
+        }
+        Some(item)
+    }
+}
+
+impl<T> Stream for BoundedReceiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.poll_recv(cx)
+    }
+}
+
+impl<T> Drop for BoundedReceiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.receiver_closed = true;
+        state.clear();
+        for waker in state.send_wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for BoundedReceiver<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BoundedReceiver")
+            .field("capacity", &self.shared.capacity)
+            .field("policy", &self.shared.policy)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn test_drop_oldest() {
+        let (tx, mut rx) = bounded::<u8>(2, OverflowPolicy::DropOldest);
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        tx.try_send(3).unwrap();
+        assert_eq!(rx.recv().now_or_never(), Some(Some(2)));
+        assert_eq!(rx.recv().now_or_never(), Some(Some(3)));
+        assert_eq!(rx.recv().now_or_never(), None);
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let (tx, mut rx) = bounded::<u8>(2, OverflowPolicy::DropNewest);
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        tx.try_send(3).unwrap();
+        assert_eq!(rx.recv().now_or_never(), Some(Some(1)));
+        assert_eq!(rx.recv().now_or_never(), Some(Some(2)));
+        assert_eq!(rx.recv().now_or_never(), None);
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let (tx, mut rx) = bounded::<u8>(1, OverflowPolicy::Backpressure);
+        tx.try_send(1).unwrap();
+        assert!(matches!(tx.try_send(2), Err(TrySendError::Full(2))));
+        assert!(tx.ready().now_or_never().is_none());
+
+        let send = tokio::spawn(async move { tx.send(2).await.is_ok() });
+        assert_eq!(rx.recv().await, Some(1));
+        assert!(send.await.unwrap());
+        assert_eq!(rx.recv().await, Some(2));
+
+        // all senders are gone, so the stream ends
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[test]
+    fn test_priority() {
+        let (tx, mut rx) =
+            bounded_with_priority::<u8>(4, OverflowPolicy::Backpressure, |n| *n >= 10);
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        tx.try_send(10).unwrap();
+        tx.try_send(11).unwrap();
+        // both tiers share the capacity
+        assert!(matches!(tx.try_send(12), Err(TrySendError::Full(12))));
+        assert_eq!(rx.try_recv(), Some(10));
+        assert_eq!(rx.try_recv(), Some(11));
+        assert_eq!(rx.try_recv(), Some(1));
+        assert_eq!(rx.try_recv(), Some(2));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn test_split_queues_take_turns() {
+        let (tx, mut rx) =
+            bounded_with_priority::<u8>(3, OverflowPolicy::Backpressure, |n| *n >= 10);
+        let other = tx.split();
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        tx.try_send(3).unwrap();
+        // each queue has a capacity of its own
+        assert!(matches!(tx.try_send(4), Err(TrySendError::Full(4))));
+        other.try_send(5).unwrap();
+        other.try_send(6).unwrap();
+        other.try_send(10).unwrap();
+        assert!(matches!(other.try_send(7), Err(TrySendError::Full(7))));
+
+        assert_eq!(rx.try_recv(), Some(10));
+        assert_eq!(rx.try_recv(), Some(1));
+        assert_eq!(rx.try_recv(), Some(5));
+        assert_eq!(rx.try_recv(), Some(2));
+        assert_eq!(rx.try_recv(), Some(6));
+        assert_eq!(rx.try_recv(), Some(3));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn test_split_drop_oldest() {
+        let (tx, mut rx) = bounded::<u8>(1, OverflowPolicy::DropOldest);
+        let other = tx.split();
+        tx.try_send(1).unwrap();
+        other.try_send(2).unwrap();
+        other.try_send(3).unwrap();
+        // only the full queue loses its oldest item
+        assert_eq!(rx.try_recv(), Some(1));
+        assert_eq!(rx.try_recv(), Some(3));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn test_hold_deferred() {
+        let (tx, mut rx) =
+            bounded_with_deferred::<u8>(2, OverflowPolicy::Backpressure, |n| *n >= 10);
+        let other = tx.split();
+        tx.try_send(10).unwrap();
+        other.try_send(11).unwrap();
+        // deferred items share a queue, whoever sends them
+        assert!(matches!(tx.try_send(12), Err(TrySendError::Full(12))));
+        tx.try_send(1).unwrap();
+
+        // held items are skipped, and received in order once they're let go
+        rx.hold_deferred(true);
+        assert_eq!(rx.try_recv(), Some(1));
+        assert_eq!(rx.try_recv(), None);
+        assert_eq!(rx.len(), 2);
+        rx.hold_deferred(false);
+        assert_eq!(rx.try_recv(), Some(10));
+        assert_eq!(rx.try_recv(), Some(11));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn test_receiver_dropped() {
+        let (tx, rx) = bounded::<u8>(1, OverflowPolicy::Backpressure);
+        drop(rx);
+        assert!(matches!(tx.try_send(1), Err(TrySendError::Closed(1))));
+    }
+}
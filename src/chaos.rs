@@ -0,0 +1,381 @@
+use futures::{
+    future::{self, BoxFuture, Either},
+    FutureExt,
+};
+use log::debug;
+use nym_sdk::mixnet::{AnonymousSenderTag, IncludedSurbs};
+use nym_sphinx::addressing::clients::Recipient;
+use nym_sphinx::receiver::ReconstructedMessage;
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::fmt::{Debug, Formatter};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use super::backend::{MixnetBackend, MixnetBackendSender};
+use super::error::Error;
+use super::runtime::{sleep, spawn};
+
+/// ChaosConfig describes how a [`Chaos`] mistreats the messages going one way between
+/// the transport and the mixnet. The default config leaves every message alone.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChaosConfig {
+    /// probability of a message being lost.
+    pub drop_probability: f64,
+    /// probability of a message being held back for a delay chosen uniformly up to
+    /// `max_delay`, so that messages after it may overtake it.
+    pub delay_probability: f64,
+    pub max_delay: Duration,
+    /// probability of a message being handled twice, each copy with its own delay.
+    pub duplicate_probability: f64,
+    /// probability of a bit of a message being flipped.
+    pub corrupt_probability: f64,
+}
+
+impl ChaosConfig {
+    /// Set the drop probability and return self.
+    pub fn with_drops(mut self, probability: f64) -> Self {
+        self.drop_probability = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Set the delay probability and the longest delay, and return self.
+    pub fn with_delays(mut self, probability: f64, max_delay: Duration) -> Self {
+        self.delay_probability = probability.clamp(0.0, 1.0);
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Set the duplicate probability and return self.
+    pub fn with_duplicates(mut self, probability: f64) -> Self {
+        self.duplicate_probability = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Set the corruption probability and return self.
+    pub fn with_corruption(mut self, probability: f64) -> Self {
+        self.corrupt_probability = probability.clamp(0.0, 1.0);
+        self
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Inbound,
+    Outbound,
+}
+
+struct ChaosState {
+    inbound: ChaosConfig,
+    outbound: ChaosConfig,
+    rng: StdRng,
+}
+
+/// Chaos injects faults into the messages a transport exchanges with the mixnet, so
+/// that its reliability features can be exercised against the live network. Pass it to
+/// [`NymTransportConfig::with_chaos`](crate::config::NymTransportConfig::with_chaos).
+/// It's a handle: its configs can be changed through any clone while the transport
+/// runs, and its random choices are seeded, so that a run makes the same ones for
+/// the same sequence of messages.
+#[derive(Clone)]
+pub struct Chaos {
+    shared: Arc<Mutex<ChaosState>>,
+}
+
+impl Chaos {
+    /// New chaos with the given seed, which leaves every message alone until
+    /// it's configured.
+    pub fn new(seed: u64) -> Self {
+        Chaos {
+            shared: Arc::new(Mutex::new(ChaosState {
+                inbound: ChaosConfig::default(),
+                outbound: ChaosConfig::default(),
+                rng: StdRng::seed_from_u64(seed),
+            })),
+        }
+    }
+
+    /// set_inbound sets how messages received from the mixnet are mistreated.
+    pub fn set_inbound(&self, config: ChaosConfig) {
+        self.shared.lock().inbound = config;
+    }
+
+    /// set_outbound sets how messages sent to the mixnet are mistreated.
+    pub fn set_outbound(&self, config: ChaosConfig) {
+        self.shared.lock().outbound = config;
+    }
+
+    /// wrap returns `backend` with faults injected into everything it sends and receives.
+    pub(crate) fn wrap(&self, backend: Box<dyn MixnetBackend>) -> Box<dyn MixnetBackend> {
+        let (held_tx, held_rx) = unbounded_channel();
+        Box::new(ChaosBackend {
+            inner: backend,
+            chaos: self.clone(),
+            held_tx,
+            held_rx,
+        })
+    }
+
+    /// fates decides what happens to `message`, corrupting it in place. It returns the
+    /// delay of each copy of the message to be handled, which is none if it's dropped.
+    fn fates(&self, direction: Direction, message: &mut [u8]) -> Vec<Duration> {
+        let mut guard = self.shared.lock();
+        let state = &mut *guard;
+        let config = match direction {
+            Direction::Inbound => state.inbound,
+            Direction::Outbound => state.outbound,
+        };
+        let rng = &mut state.rng;
+        if rng.gen_bool(config.drop_probability) {
+            return vec![];
+        }
+        if !message.is_empty() && rng.gen_bool(config.corrupt_probability) {
+            let i = rng.gen_range(0..message.len());
+            message[i] ^= 1 << rng.gen_range(0..8);
+        }
+
+        let copies = if rng.gen_bool(config.duplicate_probability) {
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                if rng.gen_bool(config.delay_probability) {
+                    rng.gen_range(Duration::ZERO..=config.max_delay)
+                } else {
+                    Duration::ZERO
+                }
+            })
+            .collect()
+    }
+}
+
+impl Debug for Chaos {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let state = self.shared.lock();
+        f.debug_struct("Chaos")
+            .field("inbound", &state.inbound)
+            .field("outbound", &state.outbound)
+            .finish_non_exhaustive()
+    }
+}
+
+/// ChaosBackend is a [`MixnetBackend`] whose messages are mistreated by a [`Chaos`].
+struct ChaosBackend {
+    inner: Box<dyn MixnetBackend>,
+    chaos: Chaos,
+    /// messages received from `inner` which were delayed or duplicated, and have
+    /// already met their fate.
+    held_tx: UnboundedSender<ReconstructedMessage>,
+    held_rx: UnboundedReceiver<ReconstructedMessage>,
+}
+
+impl MixnetBackend for ChaosBackend {
+    fn our_address(&self) -> Recipient {
+        self.inner.our_address()
+    }
+
+    fn sender(&self) -> Box<dyn MixnetBackendSender> {
+        Box::new(ChaosSender {
+            inner: Arc::from(self.inner.sender()),
+            chaos: self.chaos.clone(),
+        })
+    }
+
+    fn next(&mut self) -> BoxFuture<'_, Option<ReconstructedMessage>> {
+        async move {
+            loop {
+                let mut message =
+                    match future::select(self.held_rx.recv().boxed(), self.inner.next()).await {
+                        // we hold a sender, so the channel is never closed
+                        Either::Left((held, _)) => return held,
+                        Either::Right((Some(message), _)) => message,
+                        Either::Right((None, _)) => return None,
+                    };
+
+                let delays = self.chaos.fates(Direction::Inbound, &mut message.message);
+                let mut ready = None;
+                for delay in delays {
+                    let copy = ReconstructedMessage {
+                        message: message.message.clone(),
+                        sender_tag: message.sender_tag.clone(),
+                    };
+                    if delay.is_zero() && ready.is_none() {
+                        ready = Some(copy);
+                        continue;
+                    }
+                    let held_tx = self.held_tx.clone();
+                    spawn(async move {
+                        sleep(delay).await;
+                        // the backend may have been dropped since
+                        held_tx.send(copy).ok();
+                    });
+                }
+                if ready.is_some() {
+                    return ready;
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn disconnect(self: Box<Self>) -> BoxFuture<'static, ()> {
+        self.inner.disconnect()
+    }
+
+    fn topology_epoch(&mut self) -> BoxFuture<'_, Option<u64>> {
+        self.inner.topology_epoch()
+    }
+}
+
+/// ChaosSender is a [`MixnetBackendSender`] whose messages are mistreated by a [`Chaos`].
+/// A dropped or delayed message is reported as sent, as it would be by the mixnet.
+struct ChaosSender {
+    inner: Arc<dyn MixnetBackendSender>,
+    chaos: Chaos,
+}
+
+impl MixnetBackendSender for ChaosSender {
+    fn send<'a>(
+        &'a self,
+        recipient: Recipient,
+        message: &'a [u8],
+        surbs: IncludedSurbs,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let mut message = message.to_vec();
+        let delays = self.chaos.fates(Direction::Outbound, &mut message);
+        async move {
+            for delay in delays {
+                if delay.is_zero() {
+                    self.inner.send(recipient, &message, surbs.clone()).await?;
+                    continue;
+                }
+                let (inner, message, surbs) = (self.inner.clone(), message.clone(), surbs.clone());
+                spawn(async move {
+                    sleep(delay).await;
+                    if let Err(e) = inner.send(recipient, &message, surbs).await {
+                        debug!("failed to send delayed message: {}", e);
+                    }
+                });
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn send_reply<'a>(
+        &'a self,
+        sender_tag: AnonymousSenderTag,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let mut message = message.to_vec();
+        let delays = self.chaos.fates(Direction::Outbound, &mut message);
+        async move {
+            for delay in delays {
+                if delay.is_zero() {
+                    self.inner.send_reply(sender_tag.clone(), &message).await?;
+                    continue;
+                }
+                let (inner, message, sender_tag) =
+                    (self.inner.clone(), message.clone(), sender_tag.clone());
+                spawn(async move {
+                    sleep(delay).await;
+                    if let Err(e) = inner.send_reply(sender_tag, &message).await {
+                        debug!("failed to send delayed reply: {}", e);
+                    }
+                });
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::memory::{InMemoryConfig, InMemoryMixnet};
+    use super::super::runtime::timeout;
+    use super::*;
+
+    /// received returns the messages delivered to `client` within a short while.
+    async fn received(client: &mut dyn MixnetBackend) -> Vec<Vec<u8>> {
+        let mut messages = vec![];
+        while let Ok(Some(message)) = timeout(Duration::from_millis(100), client.next()).await {
+            messages.push(message.message);
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_chaos() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let chaos = Chaos::new(7);
+        let mut alice = chaos.wrap(Box::new(mixnet.client()));
+        let mut bob = mixnet.client();
+        let sender = alice.sender();
+        let message = b"hello".to_vec();
+
+        // untouched until configured
+        sender
+            .send(bob.our_address(), &message, IncludedSurbs::Amount(1))
+            .await
+            .unwrap();
+        assert_eq!(received(&mut bob).await, vec![message.clone()]);
+
+        // a dropped message is still reported as sent
+        chaos.set_outbound(ChaosConfig::default().with_drops(1.0));
+        sender
+            .send(bob.our_address(), &message, IncludedSurbs::Amount(1))
+            .await
+            .unwrap();
+        assert!(received(&mut bob).await.is_empty());
+
+        // a duplicated, corrupted message arrives twice, with a single bit flipped
+        chaos.set_outbound(
+            ChaosConfig::default()
+                .with_duplicates(1.0)
+                .with_corruption(1.0),
+        );
+        sender
+            .send(bob.our_address(), &message, IncludedSurbs::Amount(1))
+            .await
+            .unwrap();
+        let copies = received(&mut bob).await;
+        assert_eq!(copies.len(), 2);
+        assert_eq!(copies[0], copies[1]);
+        let flipped: u32 = copies[0]
+            .iter()
+            .zip(&message)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        assert_eq!(flipped, 1);
+
+        // a delayed message still arrives
+        chaos.set_outbound(ChaosConfig::default());
+        chaos.set_inbound(ChaosConfig::default().with_delays(1.0, Duration::from_millis(20)));
+        bob.sender()
+            .send(alice.our_address(), &message, IncludedSurbs::Amount(1))
+            .await
+            .unwrap();
+        assert_eq!(received(alice.as_mut()).await, vec![message.clone()]);
+    }
+
+    #[test]
+    fn test_chaos_seeded() {
+        let corrupt = |seed| {
+            let chaos = Chaos::new(seed);
+            chaos.set_inbound(ChaosConfig::default().with_corruption(0.5));
+            (0..16)
+                .map(|_| {
+                    let mut message = vec![0u8; 32];
+                    chaos.fates(Direction::Inbound, &mut message);
+                    message
+                })
+                .collect::<Vec<_>>()
+        };
+        // the same seed makes the same choices
+        assert_eq!(corrupt(1), corrupt(1));
+        assert_ne!(corrupt(1), corrupt(2));
+    }
+}
@@ -0,0 +1,830 @@
+//! The encoding of messages on the wire. The hand-rolled [`NativeCodec`] is used by
+//! default; with the `borsh-codec` feature, messages are encoded with [`BorshCodec`]
+//! instead, whose schema other implementations can generate their encoders from.
+//! Both peers of a connection have to use the same codec.
+
+use bytes::Bytes;
+use nym_sdk::mixnet::AnonymousSenderTag;
+
+use super::error::Error;
+use super::message::{InboundMessage, Message};
+
+#[cfg(feature = "borsh-codec")]
+pub(crate) use self::borsh_codec::BorshCodec;
+
+/// WireCodec is the codec messages are sent and received with, chosen at compile time.
+#[cfg(not(feature = "borsh-codec"))]
+pub(crate) type WireCodec = NativeCodec;
+#[cfg(feature = "borsh-codec")]
+pub(crate) type WireCodec = BorshCodec;
+
+/// Codec encodes messages to, and decodes them from, the bytes sent over the mixnet.
+pub(crate) trait Codec {
+    fn encode(message: &Message) -> Bytes;
+
+    /// decode decodes a message. A ConnectionRequest or ConnectionResponse of another
+    /// protocol version is decoded as a [`Message::VersionMismatch`].
+    fn decode(data: Bytes) -> Result<Message, Error>;
+}
+
+/// NativeCodec is the transport's own compact format, described alongside each message
+/// in [`crate::message`]. Decoded payloads are slices of the received bytes.
+pub(crate) struct NativeCodec;
+
+impl Codec for NativeCodec {
+    fn encode(message: &Message) -> Bytes {
+        message.to_bytes()
+    }
+
+    fn decode(data: Bytes) -> Result<Message, Error> {
+        Message::try_from_bytes(data)
+    }
+}
+
+/// decode_inbound decodes a message received from the mixnet with the codec `C`,
+/// rejecting messages larger than `max_size` bytes before looking at them.
+pub(crate) fn decode_inbound<C: Codec>(
+    data: Bytes,
+    sender_tag: Option<AnonymousSenderTag>,
+    max_size: usize,
+) -> Result<InboundMessage, Error> {
+    if data.len() > max_size {
+        return Err(Error::InboundMessageTooLarge(data.len()));
+    }
+    if data.len() < 2 {
+        return Err(Error::InvalidMessageBytes);
+    }
+    let msg = C::decode(data)?;
+    Ok(InboundMessage(msg, sender_tag))
+}
+
+#[cfg(feature = "borsh-codec")]
+mod borsh_codec {
+    use borsh::{BorshDeserialize, BorshSerialize};
+    use bytes::Bytes;
+    use libp2p::core::PeerId;
+    use libp2p_identity::PublicKey;
+    use nym_sphinx::addressing::clients::Recipient;
+
+    use super::super::error::Error;
+    use super::super::handshake::HandshakePayload;
+    use super::super::message::{
+        AckMessage, BatchMessage, Capabilities, ChallengeResponseMessage, ConnectionCloseMessage,
+        ConnectionId, ConnectionInfo, ConnectionMessage, DatagramKind, DatagramMessage, Fragment,
+        KeepAliveMessage, KeepAliveType, Message, MigrateMessage, RelayHop, RelayMessage,
+        RelayReserveMessage, RelayStatus, RelayStatusMessage, ResumeRequestMessage,
+        ResumeResponseMessage, SessionTicketMessage, SubstreamId, SubstreamMessage,
+        SubstreamMessageType, TransportMessage, VersionMismatch, PROTOCOL_VERSION,
+    };
+    use super::super::nonce::Nonce;
+    use super::Codec;
+
+    /// length of the header shared by every version of a ConnectionMessage: the enum
+    /// tag, the version, the capabilities and the connection ID.
+    const CONNECTION_HEADER_LEN: usize = 1 + 1 + 4 + 32;
+
+    /// BorshCodec encodes messages as the [borsh](https://borsh.io) serialization of
+    /// [`WireMessage`]: integers are little-endian, and byte strings, strings and
+    /// lists are prefixed by their length as a u32. Payloads are copied on decoding.
+    pub(crate) struct BorshCodec;
+
+    /// WireMessage is the schema of a message. Its variants are numbered as the
+    /// native message types are.
+    #[derive(BorshSerialize, BorshDeserialize)]
+    enum WireMessage {
+        ConnectionRequest(WireConnection),
+        ConnectionResponse(WireConnection),
+        Transport(WireTransport),
+        KeepAlive(WireKeepAlive),
+        Ack(WireAck),
+        Datagram(WireDatagram),
+        Batch(WireBatch),
+        ConnectionClose(WireConnectionClose),
+        /// a TransportMessage stamped with the time it was sent, in milliseconds since
+        /// the UNIX epoch.
+        StampedTransport(u64, WireTransport),
+        /// a KeepAlive stamped with the time it was sent.
+        StampedKeepAlive(u64, WireKeepAlive),
+        Migrate(WireMigrate),
+        ChallengeResponse(WireChallengeResponse),
+        SessionTicket(WireSessionTicket),
+        ResumeRequest(WireResumeRequest),
+        ResumeResponse(WireResumeResponse),
+        RelayReserve(WireRelayReserve),
+        RelayStatus(WireRelayStatus),
+        Relay(WireRelay),
+    }
+
+    /// WireConnection is a ConnectionRequest or ConnectionResponse. Its first three
+    /// fields are the header which peers of every version can decode.
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireConnection {
+        version: u8,
+        capabilities: u32,
+        id: [u8; 32],
+        recipient: Option<Vec<u8>>,
+        handshake: Vec<u8>,
+        peer_id: Vec<u8>,
+        challenge: Option<[u8; 32]>,
+        observed: Option<Vec<u8>>,
+        max_message_size: u32,
+        service: Option<u16>,
+        agent_version: Option<String>,
+        extensions: Vec<String>,
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireTransport {
+        nonce: u64,
+        id: [u8; 32],
+        message: WireSubstream,
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireSubstream {
+        substream_id: [u8; 32],
+        message_type: WireSubstreamType,
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    enum WireSubstreamType {
+        OpenRequest(u32),
+        OpenResponse(u32),
+        Close,
+        Data(Vec<u8>),
+        Fragment {
+            payload_id: u32,
+            index: u16,
+            count: u16,
+            data: Vec<u8>,
+        },
+        WindowUpdate(u64),
+        Datagram(Vec<u8>),
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    enum WireKeepAliveType {
+        Ping,
+        Pong,
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireKeepAlive {
+        keepalive_type: WireKeepAliveType,
+        seq: u64,
+        id: [u8; 32],
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireAck {
+        nonce: u64,
+        id: [u8; 32],
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    enum WireDatagramKind {
+        Request,
+        Response,
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireDatagram {
+        kind: WireDatagramKind,
+        id: [u8; 32],
+        payload: Vec<u8>,
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireBatchEntry {
+        nonce: u64,
+        message: WireSubstream,
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireBatch {
+        id: [u8; 32],
+        messages: Vec<WireBatchEntry>,
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireConnectionClose {
+        id: [u8; 32],
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireMigrate {
+        id: [u8; 32],
+        seq: u64,
+        recipient: Vec<u8>,
+        signature: Vec<u8>,
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireChallengeResponse {
+        id: [u8; 32],
+        signature: Vec<u8>,
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireSessionTicket {
+        id: [u8; 32],
+        ticket: Vec<u8>,
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireResumeRequest {
+        id: [u8; 32],
+        timestamp: u64,
+        nonce: [u8; 32],
+        proof: [u8; 32],
+        ticket: Vec<u8>,
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireResumeResponse {
+        id: [u8; 32],
+        nonce: [u8; 32],
+        proof: [u8; 32],
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireRelayReserve {
+        id: [u8; 32],
+        /// the protobuf encoding of the identity key.
+        identity: Vec<u8>,
+        signature: Vec<u8>,
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireRelayStatus {
+        id: [u8; 32],
+        status: u8,
+        ttl_secs: u32,
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    enum WireRelayHop {
+        ToListener(Vec<u8>),
+        ToDialer,
+        Delivered(Vec<u8>),
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct WireRelay {
+        id: [u8; 32],
+        hop: WireRelayHop,
+        payload: Vec<u8>,
+    }
+
+    fn id_bytes(id: &ConnectionId) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(id.as_bytes());
+        bytes
+    }
+
+    fn connection_recipient(bytes: Vec<u8>) -> Result<Recipient, Error> {
+        let bytes: [u8; Recipient::LEN] = bytes
+            .try_into()
+            .map_err(|_| Error::ConnectionMessageBytesTooShort)?;
+        Ok(Recipient::try_from_bytes(bytes)?)
+    }
+
+    impl Codec for BorshCodec {
+        fn encode(message: &Message) -> Bytes {
+            let wire = match message {
+                Message::ConnectionRequest(msg) => {
+                    WireMessage::ConnectionRequest(WireConnection::from(msg))
+                }
+                Message::ConnectionResponse(msg) => {
+                    WireMessage::ConnectionResponse(WireConnection::from(msg))
+                }
+                Message::TransportMessage(msg) => {
+                    let wire = WireTransport {
+                        nonce: msg.nonce.get(),
+                        id: id_bytes(&msg.id),
+                        message: WireSubstream::from(&msg.message),
+                    };
+                    match msg.timestamp {
+                        Some(timestamp) => WireMessage::StampedTransport(timestamp, wire),
+                        None => WireMessage::Transport(wire),
+                    }
+                }
+                Message::KeepAlive(msg) => {
+                    let wire = WireKeepAlive {
+                        keepalive_type: match msg.keepalive_type {
+                            KeepAliveType::Ping => WireKeepAliveType::Ping,
+                            KeepAliveType::Pong => WireKeepAliveType::Pong,
+                        },
+                        seq: msg.seq,
+                        id: id_bytes(&msg.id),
+                    };
+                    match msg.timestamp {
+                        Some(timestamp) => WireMessage::StampedKeepAlive(timestamp, wire),
+                        None => WireMessage::KeepAlive(wire),
+                    }
+                }
+                Message::Ack(msg) => WireMessage::Ack(WireAck {
+                    nonce: msg.nonce.get(),
+                    id: id_bytes(&msg.id),
+                }),
+                Message::Datagram(msg) => WireMessage::Datagram(WireDatagram {
+                    kind: match msg.kind {
+                        DatagramKind::Request => WireDatagramKind::Request,
+                        DatagramKind::Response => WireDatagramKind::Response,
+                    },
+                    id: id_bytes(&msg.id),
+                    payload: msg.payload.to_vec(),
+                }),
+                Message::VersionMismatch(msg) => {
+                    // only the header, since that's all a peer of another version can parse
+                    let mut bytes = Vec::with_capacity(CONNECTION_HEADER_LEN);
+                    bytes.push(if msg.request { 0 } else { 1 });
+                    bytes.push(msg.version);
+                    bytes.extend_from_slice(&0u32.to_le_bytes());
+                    bytes.extend_from_slice(msg.id.as_bytes());
+                    return bytes.into();
+                }
+                Message::Batch(msg) => WireMessage::Batch(WireBatch {
+                    id: id_bytes(&msg.id),
+                    messages: msg
+                        .messages
+                        .iter()
+                        .map(|msg| WireBatchEntry {
+                            nonce: msg.nonce.get(),
+                            message: WireSubstream::from(&msg.message),
+                        })
+                        .collect(),
+                }),
+                Message::ConnectionClose(msg) => {
+                    WireMessage::ConnectionClose(WireConnectionClose {
+                        id: id_bytes(&msg.id),
+                    })
+                }
+                Message::Migrate(msg) => WireMessage::Migrate(WireMigrate {
+                    id: id_bytes(&msg.id),
+                    seq: msg.seq,
+                    recipient: msg.recipient.to_bytes().to_vec(),
+                    signature: msg.signature.clone(),
+                }),
+                Message::ChallengeResponse(msg) => {
+                    WireMessage::ChallengeResponse(WireChallengeResponse {
+                        id: id_bytes(&msg.id),
+                        signature: msg.signature.clone(),
+                    })
+                }
+                Message::SessionTicket(msg) => WireMessage::SessionTicket(WireSessionTicket {
+                    id: id_bytes(&msg.id),
+                    ticket: msg.ticket.clone(),
+                }),
+                Message::ResumeRequest(msg) => WireMessage::ResumeRequest(WireResumeRequest {
+                    id: id_bytes(&msg.id),
+                    timestamp: msg.timestamp,
+                    nonce: msg.nonce,
+                    proof: msg.proof,
+                    ticket: msg.ticket.clone(),
+                }),
+                Message::ResumeResponse(msg) => WireMessage::ResumeResponse(WireResumeResponse {
+                    id: id_bytes(&msg.id),
+                    nonce: msg.nonce,
+                    proof: msg.proof,
+                }),
+                Message::RelayReserve(msg) => WireMessage::RelayReserve(WireRelayReserve {
+                    id: id_bytes(&msg.id),
+                    identity: msg.identity.encode_protobuf(),
+                    signature: msg.signature.clone(),
+                }),
+                Message::RelayStatus(msg) => WireMessage::RelayStatus(WireRelayStatus {
+                    id: id_bytes(&msg.id),
+                    status: msg.status.to_u8(),
+                    ttl_secs: msg.ttl_secs,
+                }),
+                Message::Relay(msg) => WireMessage::Relay(WireRelay {
+                    id: id_bytes(&msg.id),
+                    hop: match &msg.hop {
+                        RelayHop::ToListener(peer_id) => {
+                            WireRelayHop::ToListener(peer_id.to_bytes())
+                        }
+                        RelayHop::ToDialer => WireRelayHop::ToDialer,
+                        RelayHop::Delivered(relay) => {
+                            WireRelayHop::Delivered(relay.to_bytes().to_vec())
+                        }
+                    },
+                    payload: msg.payload.to_vec(),
+                }),
+            };
+            borsh::to_vec(&wire)
+                .expect("serializing to a Vec can't fail")
+                .into()
+        }
+
+        fn decode(data: Bytes) -> Result<Message, Error> {
+            if data.len() < 2 {
+                return Err(Error::InvalidMessageBytes);
+            }
+            if data[0] > 17 {
+                return Err(Error::UnknownMessageType(data[0]));
+            }
+            let request = data[0] == 0;
+            if data[0] <= 1 && data[1] != PROTOCOL_VERSION {
+                if data.len() < CONNECTION_HEADER_LEN {
+                    return Err(Error::ConnectionMessageBytesTooShort);
+                }
+                return Ok(Message::VersionMismatch(VersionMismatch {
+                    id: ConnectionId::from_bytes(&data[6..CONNECTION_HEADER_LEN]),
+                    request,
+                    version: data[1],
+                }));
+            }
+
+            let wire: WireMessage =
+                borsh::from_slice(&data).map_err(|_| Error::InvalidMessageBytes)?;
+            Ok(match wire {
+                WireMessage::ConnectionRequest(msg) => Message::ConnectionRequest(msg.try_into()?),
+                WireMessage::ConnectionResponse(msg) => {
+                    Message::ConnectionResponse(msg.try_into()?)
+                }
+                WireMessage::Transport(msg) => {
+                    Message::TransportMessage(msg.try_into_message(None)?)
+                }
+                WireMessage::StampedTransport(timestamp, msg) => {
+                    Message::TransportMessage(msg.try_into_message(Some(timestamp))?)
+                }
+                WireMessage::KeepAlive(msg) => Message::KeepAlive(msg.into_message(None)),
+                WireMessage::StampedKeepAlive(timestamp, msg) => {
+                    Message::KeepAlive(msg.into_message(Some(timestamp)))
+                }
+                WireMessage::Ack(msg) => Message::Ack(AckMessage {
+                    id: ConnectionId::from_bytes(&msg.id),
+                    nonce: Nonce::new(msg.nonce),
+                }),
+                WireMessage::Datagram(msg) => Message::Datagram(DatagramMessage {
+                    id: ConnectionId::from_bytes(&msg.id),
+                    kind: match msg.kind {
+                        WireDatagramKind::Request => DatagramKind::Request,
+                        WireDatagramKind::Response => DatagramKind::Response,
+                    },
+                    payload: msg.payload.into(),
+                }),
+                WireMessage::Batch(msg) => {
+                    let id = ConnectionId::from_bytes(&msg.id);
+                    let messages = msg
+                        .messages
+                        .into_iter()
+                        .map(|entry| {
+                            Ok(TransportMessage {
+                                nonce: Nonce::new(entry.nonce),
+                                message: entry.message.try_into()?,
+                                id: id.clone(),
+                                timestamp: None,
+                            })
+                        })
+                        .collect::<Result<Vec<_>, Error>>()?;
+                    if messages.is_empty() {
+                        return Err(Error::InvalidBatchMessageBytes);
+                    }
+                    Message::Batch(BatchMessage { id, messages })
+                }
+                WireMessage::ConnectionClose(msg) => {
+                    Message::ConnectionClose(ConnectionCloseMessage {
+                        id: ConnectionId::from_bytes(&msg.id),
+                    })
+                }
+                WireMessage::Migrate(msg) => {
+                    let recipient: [u8; Recipient::LEN] = msg
+                        .recipient
+                        .try_into()
+                        .map_err(|_| Error::InvalidMigrateMessageBytes)?;
+                    Message::Migrate(MigrateMessage {
+                        id: ConnectionId::from_bytes(&msg.id),
+                        seq: msg.seq,
+                        recipient: Recipient::try_from_bytes(recipient)?,
+                        signature: msg.signature,
+                    })
+                }
+                WireMessage::ChallengeResponse(msg) => {
+                    Message::ChallengeResponse(ChallengeResponseMessage {
+                        id: ConnectionId::from_bytes(&msg.id),
+                        signature: msg.signature,
+                    })
+                }
+                WireMessage::SessionTicket(msg) => Message::SessionTicket(SessionTicketMessage {
+                    id: ConnectionId::from_bytes(&msg.id),
+                    ticket: msg.ticket,
+                }),
+                WireMessage::ResumeRequest(msg) => Message::ResumeRequest(ResumeRequestMessage {
+                    id: ConnectionId::from_bytes(&msg.id),
+                    timestamp: msg.timestamp,
+                    nonce: msg.nonce,
+                    proof: msg.proof,
+                    ticket: msg.ticket,
+                }),
+                WireMessage::ResumeResponse(msg) => {
+                    Message::ResumeResponse(ResumeResponseMessage {
+                        id: ConnectionId::from_bytes(&msg.id),
+                        nonce: msg.nonce,
+                        proof: msg.proof,
+                    })
+                }
+                WireMessage::RelayReserve(msg) => Message::RelayReserve(RelayReserveMessage {
+                    id: ConnectionId::from_bytes(&msg.id),
+                    identity: PublicKey::try_decode_protobuf(&msg.identity)
+                        .map_err(|_| Error::InvalidRelayMessageBytes)?,
+                    signature: msg.signature,
+                }),
+                WireMessage::RelayStatus(msg) => Message::RelayStatus(RelayStatusMessage {
+                    id: ConnectionId::from_bytes(&msg.id),
+                    status: RelayStatus::try_from_u8(msg.status)?,
+                    ttl_secs: msg.ttl_secs,
+                }),
+                WireMessage::Relay(msg) => Message::Relay(RelayMessage {
+                    id: ConnectionId::from_bytes(&msg.id),
+                    hop: match msg.hop {
+                        WireRelayHop::ToListener(peer_id) => RelayHop::ToListener(
+                            PeerId::from_bytes(&peer_id)
+                                .map_err(|_| Error::InvalidRelayMessageBytes)?,
+                        ),
+                        WireRelayHop::ToDialer => RelayHop::ToDialer,
+                        WireRelayHop::Delivered(relay) => {
+                            let relay: [u8; Recipient::LEN] = relay
+                                .try_into()
+                                .map_err(|_| Error::InvalidRelayMessageBytes)?;
+                            RelayHop::Delivered(
+                                Recipient::try_from_bytes(relay)
+                                    .map_err(|_| Error::InvalidRelayMessageBytes)?,
+                            )
+                        }
+                    },
+                    payload: msg.payload.into(),
+                }),
+            })
+        }
+    }
+
+    impl WireTransport {
+        fn try_into_message(self, timestamp: Option<u64>) -> Result<TransportMessage, Error> {
+            Ok(TransportMessage {
+                nonce: Nonce::new(self.nonce),
+                id: ConnectionId::from_bytes(&self.id),
+                message: self.message.try_into()?,
+                timestamp,
+            })
+        }
+    }
+
+    impl WireKeepAlive {
+        fn into_message(self, timestamp: Option<u64>) -> KeepAliveMessage {
+            KeepAliveMessage {
+                id: ConnectionId::from_bytes(&self.id),
+                keepalive_type: match self.keepalive_type {
+                    WireKeepAliveType::Ping => KeepAliveType::Ping,
+                    WireKeepAliveType::Pong => KeepAliveType::Pong,
+                },
+                seq: self.seq,
+                timestamp,
+            }
+        }
+    }
+
+    impl From<&ConnectionMessage> for WireConnection {
+        fn from(msg: &ConnectionMessage) -> Self {
+            WireConnection {
+                version: PROTOCOL_VERSION,
+                capabilities: msg.capabilities.0,
+                id: id_bytes(&msg.id),
+                recipient: msg
+                    .recipient
+                    .as_ref()
+                    .map(|recipient| recipient.to_bytes().to_vec()),
+                handshake: msg.handshake.to_bytes(),
+                peer_id: msg.peer_id.to_bytes(),
+                challenge: msg.challenge,
+                observed: msg
+                    .observed
+                    .as_ref()
+                    .map(|observed| observed.to_bytes().to_vec()),
+                max_message_size: msg.max_message_size,
+                service: msg.service,
+                agent_version: msg.info.agent_version.clone(),
+                extensions: msg.info.extensions.clone(),
+            }
+        }
+    }
+
+    impl TryFrom<WireConnection> for ConnectionMessage {
+        type Error = Error;
+
+        fn try_from(msg: WireConnection) -> Result<Self, Error> {
+            let recipient = msg.recipient.map(connection_recipient).transpose()?;
+            let observed = msg.observed.map(connection_recipient).transpose()?;
+            let (handshake, len) = HandshakePayload::try_from_bytes(&msg.handshake)?;
+            if len != msg.handshake.len() {
+                return Err(Error::InvalidHandshakeBytes);
+            }
+            Ok(ConnectionMessage {
+                peer_id: PeerId::from_bytes(&msg.peer_id).map_err(|_| Error::InvalidPeerIdBytes)?,
+                id: ConnectionId::from_bytes(&msg.id),
+                capabilities: Capabilities(msg.capabilities),
+                recipient,
+                handshake,
+                challenge: msg.challenge,
+                observed,
+                max_message_size: msg.max_message_size,
+                service: msg.service,
+                info: ConnectionInfo {
+                    agent_version: msg.agent_version.filter(|version| !version.is_empty()),
+                    extensions: msg.extensions,
+                },
+            })
+        }
+    }
+
+    impl From<&SubstreamMessage> for WireSubstream {
+        fn from(msg: &SubstreamMessage) -> Self {
+            WireSubstream {
+                substream_id: msg.substream_id.0,
+                message_type: match &msg.message_type {
+                    SubstreamMessageType::OpenRequest(window) => {
+                        WireSubstreamType::OpenRequest(*window)
+                    }
+                    SubstreamMessageType::OpenResponse(window) => {
+                        WireSubstreamType::OpenResponse(*window)
+                    }
+                    SubstreamMessageType::Close => WireSubstreamType::Close,
+                    SubstreamMessageType::Data(data) => WireSubstreamType::Data(data.to_vec()),
+                    SubstreamMessageType::Fragment(fragment) => WireSubstreamType::Fragment {
+                        payload_id: fragment.payload_id,
+                        index: fragment.index,
+                        count: fragment.count,
+                        data: fragment.data.to_vec(),
+                    },
+                    SubstreamMessageType::WindowUpdate(limit) => {
+                        WireSubstreamType::WindowUpdate(*limit)
+                    }
+                    SubstreamMessageType::Datagram(data) => {
+                        WireSubstreamType::Datagram(data.to_vec())
+                    }
+                },
+            }
+        }
+    }
+
+    impl TryFrom<WireSubstream> for SubstreamMessage {
+        type Error = Error;
+
+        /// try_from checks what the native format's decoder checks: that Data isn't
+        /// empty, and that a Fragment is a non-empty piece of a payload.
+        fn try_from(msg: WireSubstream) -> Result<Self, Error> {
+            let message_type = match msg.message_type {
+                WireSubstreamType::OpenRequest(window) => SubstreamMessageType::OpenRequest(window),
+                WireSubstreamType::OpenResponse(window) => {
+                    SubstreamMessageType::OpenResponse(window)
+                }
+                WireSubstreamType::Close => SubstreamMessageType::Close,
+                WireSubstreamType::Data(data) => {
+                    if data.is_empty() {
+                        return Err(Error::InvalidSubstreamMessageBytes);
+                    }
+                    SubstreamMessageType::Data(data.into())
+                }
+                WireSubstreamType::Fragment {
+                    payload_id,
+                    index,
+                    count,
+                    data,
+                } => {
+                    if data.is_empty() || index >= count {
+                        return Err(Error::InvalidFragmentBytes);
+                    }
+                    SubstreamMessageType::Fragment(Fragment {
+                        payload_id,
+                        index,
+                        count,
+                        data: data.into(),
+                    })
+                }
+                WireSubstreamType::WindowUpdate(limit) => SubstreamMessageType::WindowUpdate(limit),
+                WireSubstreamType::Datagram(data) => SubstreamMessageType::Datagram(data.into()),
+            };
+            Ok(SubstreamMessage {
+                substream_id: SubstreamId(msg.substream_id),
+                message_type,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::super::super::handshake::Handshake;
+        use super::*;
+
+        #[test]
+        fn test_borsh_transport_message_roundtrip() {
+            let id = ConnectionId::generate();
+            let substream_id = SubstreamId::generate();
+            let msg = Message::TransportMessage(TransportMessage {
+                nonce: Nonce::new(42),
+                id: id.clone(),
+                message: SubstreamMessage::new_with_data(
+                    substream_id.clone(),
+                    Bytes::from_static(b"hello"),
+                ),
+                timestamp: None,
+            });
+            let bytes = BorshCodec::encode(&msg);
+            // the variant tag, then the nonce, little-endian
+            assert_eq!(bytes[0], 2);
+            assert_eq!(bytes[1..9], 42u64.to_le_bytes());
+
+            let Message::TransportMessage(msg) = BorshCodec::decode(bytes).unwrap() else {
+                panic!("expected Message::TransportMessage");
+            };
+            assert_eq!(msg.nonce, Nonce::new(42));
+            assert_eq!(msg.id, id);
+            assert_eq!(msg.message.substream_id, substream_id);
+            let SubstreamMessageType::Data(data) = msg.message.message_type else {
+                panic!("expected SubstreamMessageType::Data");
+            };
+            assert_eq!(data, Bytes::from_static(b"hello"));
+        }
+
+        #[test]
+        fn test_borsh_stamped_keepalive_roundtrip() {
+            let msg = Message::KeepAlive(KeepAliveMessage {
+                id: ConnectionId::generate(),
+                keepalive_type: KeepAliveType::Ping,
+                seq: 7,
+                timestamp: Some(1_700_000_000_000),
+            });
+            let bytes = BorshCodec::encode(&msg);
+            // numbered as the native stamped keepalive is
+            assert_eq!(bytes[0], 9);
+            let Message::KeepAlive(msg) = BorshCodec::decode(bytes).unwrap() else {
+                panic!("expected Message::KeepAlive");
+            };
+            assert_eq!(msg.seq, 7);
+            assert_eq!(msg.timestamp, Some(1_700_000_000_000));
+        }
+
+        #[test]
+        fn test_borsh_connection_message_version() {
+            let keypair = libp2p_identity::Keypair::generate_ed25519();
+            let id = ConnectionId::generate();
+            let msg = ConnectionMessage {
+                peer_id: keypair.public().to_peer_id(),
+                id: id.clone(),
+                capabilities: Capabilities::RETRANSMIT,
+                recipient: None,
+                handshake: Handshake::new(&keypair, &id, None).unwrap().payload(),
+                challenge: None,
+                observed: None,
+                max_message_size: 4096,
+                service: None,
+                info: ConnectionInfo {
+                    agent_version: Some("test/1.0".to_string()),
+                    extensions: vec!["a".to_string()],
+                },
+            };
+            let bytes = BorshCodec::encode(&Message::ConnectionRequest(msg));
+            let Message::ConnectionRequest(decoded) = BorshCodec::decode(bytes.clone()).unwrap()
+            else {
+                panic!("expected Message::ConnectionRequest");
+            };
+            assert_eq!(decoded.id, id);
+            assert_eq!(decoded.peer_id, keypair.public().to_peer_id());
+            assert!(decoded.capabilities.contains(Capabilities::RETRANSMIT));
+            assert_eq!(decoded.info.agent_version.as_deref(), Some("test/1.0"));
+            assert_eq!(decoded.max_message_size, 4096);
+
+            // a message of another version is recognised from its header alone
+            let mut future = bytes.to_vec();
+            future[1] = PROTOCOL_VERSION + 1;
+            future.truncate(CONNECTION_HEADER_LEN);
+            let Message::VersionMismatch(mismatch) = BorshCodec::decode(future.into()).unwrap()
+            else {
+                panic!("expected Message::VersionMismatch");
+            };
+            assert_eq!(mismatch.id, id);
+            assert!(mismatch.request);
+
+            // and our own rejection is just the header
+            let reject = BorshCodec::encode(&Message::VersionMismatch(VersionMismatch {
+                id,
+                request: false,
+                version: PROTOCOL_VERSION,
+            }));
+            assert_eq!(reject.len(), CONNECTION_HEADER_LEN);
+        }
+
+        #[test]
+        fn test_borsh_rejects_invalid_messages() {
+            assert!(BorshCodec::decode(Bytes::from_static(&[9, 0])).is_err());
+            // trailing bytes
+            let mut bytes = BorshCodec::encode(&Message::ConnectionClose(ConnectionCloseMessage {
+                id: ConnectionId::generate(),
+            }))
+            .to_vec();
+            bytes.push(0);
+            assert!(BorshCodec::decode(bytes.into()).is_err());
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use super::error::Error;
+
+// flag byte in front of every payload on a compressed connection
+const FLAG_RAW: u8 = 0;
+const FLAG_LZ4: u8 = 1;
+
+// flag (u8) + decompressed length (u32)
+const COMPRESSED_HEADER_LEN: usize = 1 + 4;
+
+/// most that `compress` adds to a payload: its flag, since a payload is only sent
+/// compressed if that makes it smaller.
+pub(crate) const COMPRESSION_OVERHEAD: usize = 1;
+
+/// Compression compresses the substream payloads of a connection on which both peers
+/// advertised [`crate::message::Capabilities::COMPRESSION`]. Payloads are compressed
+/// before they're encrypted, since ciphertext doesn't compress, and each one is prefixed
+/// with a flag saying whether it was, so that small or incompressible payloads are sent as-is.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Compression {
+    #[cfg_attr(not(feature = "compression"), allow(dead_code))]
+    threshold: usize,
+}
+
+impl Compression {
+    /// new compresses payloads larger than `threshold` bytes.
+    pub(crate) fn new(threshold: usize) -> Self {
+        Compression { threshold }
+    }
+
+    /// compress returns `payload` with its flag, compressed if that makes it smaller.
+    pub(crate) fn compress(&self, payload: &[u8]) -> Bytes {
+        #[cfg(feature = "compression")]
+        if payload.len() > self.threshold {
+            let compressed = lz4_flex::block::compress(payload);
+            if compressed.len() + COMPRESSED_HEADER_LEN < payload.len() + 1 {
+                let mut bytes = BytesMut::with_capacity(COMPRESSED_HEADER_LEN + compressed.len());
+                bytes.put_u8(FLAG_LZ4);
+                bytes.put_u32(payload.len() as u32);
+                bytes.extend_from_slice(&compressed);
+                return bytes.freeze();
+            }
+        }
+
+        let mut bytes = BytesMut::with_capacity(1 + payload.len());
+        bytes.put_u8(FLAG_RAW);
+        bytes.extend_from_slice(payload);
+        bytes.freeze()
+    }
+
+    /// decompress strips the flag from a payload produced by `compress`, decompressing it
+    /// if needed. Payloads that would decompress to more than `max_len` bytes are rejected,
+    /// since the remote can't have written that much at once.
+    pub(crate) fn decompress(&self, payload: Bytes, max_len: usize) -> Result<Bytes, Error> {
+        match payload.first() {
+            Some(&FLAG_RAW) => Ok(payload.slice(1..)),
+            Some(&FLAG_LZ4) => {
+                if payload.len() < COMPRESSED_HEADER_LEN {
+                    return Err(Error::DecompressionFailure);
+                }
+                let len = u32::from_be_bytes(
+                    payload[1..COMPRESSED_HEADER_LEN]
+                        .try_into()
+                        .map_err(|_| Error::DecompressionFailure)?,
+                ) as usize;
+                if len > max_len {
+                    return Err(Error::DecompressionFailure);
+                }
+                decompress_lz4(&payload[COMPRESSED_HEADER_LEN..], len)
+            }
+            _ => Err(Error::DecompressionFailure),
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+fn decompress_lz4(compressed: &[u8], len: usize) -> Result<Bytes, Error> {
+    let payload =
+        lz4_flex::block::decompress(compressed, len).map_err(|_| Error::DecompressionFailure)?;
+    if payload.len() != len {
+        return Err(Error::DecompressionFailure);
+    }
+    Ok(payload.into())
+}
+
+// never advertised without the feature, so a well-behaved remote never sends these
+#[cfg(not(feature = "compression"))]
+fn decompress_lz4(_compressed: &[u8], _len: usize) -> Result<Bytes, Error> {
+    Err(Error::DecompressionFailure)
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compression_roundtrip() {
+        let compression = Compression::new(16);
+
+        // small payloads are sent as-is
+        let small = compression.compress(b"hello");
+        assert_eq!(small[0], FLAG_RAW);
+        assert_eq!(compression.decompress(small, 1024).unwrap(), &b"hello"[..]);
+
+        // as are large ones that don't compress
+        let random = (0..1024).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+        let bytes = compression.compress(&random);
+        assert_eq!(bytes[0], FLAG_RAW);
+        assert_eq!(compression.decompress(bytes, 1024).unwrap(), random);
+
+        // while large, compressible ones shrink
+        let repetitive = b"gossip".repeat(200);
+        let bytes = compression.compress(&repetitive);
+        assert_eq!(bytes[0], FLAG_LZ4);
+        assert!(bytes.len() < repetitive.len());
+        assert_eq!(
+            compression.decompress(bytes.clone(), 1200).unwrap(),
+            repetitive
+        );
+
+        // and are rejected if they'd decompress to more than the remote may send
+        assert!(matches!(
+            compression.decompress(bytes, 1199),
+            Err(Error::DecompressionFailure)
+        ));
+    }
+}
@@ -0,0 +1,1537 @@
+use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+use libp2p::core::Multiaddr;
+use libp2p_identity::PeerId;
+use nym_sdk::mixnet::{MixnetClientBuilder, StoragePaths};
+use nym_sdk::DebugConfig;
+use rand::seq::SliceRandom;
+use std::{fmt, future::Future, path::PathBuf, sync::Arc, time::Duration};
+
+use super::backend::MixnetBackend;
+#[cfg(feature = "chaos")]
+use super::chaos::Chaos;
+use super::message::ConnectionInfo;
+use super::metrics::Metrics;
+use super::DEFAULT_HANDSHAKE_TIMEOUT_SECS;
+
+/// The default capacity of the channel carrying inbound mixnet messages to the transport.
+const DEFAULT_INBOUND_CHANNEL_CAPACITY: usize = 1024;
+
+/// The default capacity of the channel carrying outbound messages to the mixnet.
+const DEFAULT_OUTBOUND_CHANNEL_CAPACITY: usize = 1024;
+
+/// The default delay before the first attempt to reconnect a disconnected mixnet client.
+const DEFAULT_RECONNECT_INITIAL_BACKOFF_MS: u64 = 500;
+
+/// The default upper bound on the delay between reconnection attempts.
+const DEFAULT_RECONNECT_MAX_BACKOFF_SECS: u64 = 30;
+
+/// The default interval between checks of the network topology the mixnet client routes through.
+const DEFAULT_TOPOLOGY_CHECK_INTERVAL_SECS: u64 = 60;
+
+/// The default interval between keepalive pings on an established connection.
+const DEFAULT_KEEPALIVE_INTERVAL_SECS: u64 = 30;
+
+/// The default number of unanswered keepalive pings after which a connection is closed.
+const DEFAULT_KEEPALIVE_MAX_MISSED: u32 = 3;
+
+/// The default number of reply SURBs attached to every message sent to a nym address.
+const DEFAULT_SURBS_PER_MESSAGE: u32 = 10;
+
+/// The default estimated number of unused SURBs held by a peer below which we replenish them.
+const DEFAULT_SURB_REPLENISH_THRESHOLD: u32 = 10;
+
+/// The default number of reply SURBs attached to a message when replenishing.
+const DEFAULT_SURB_REPLENISH_COUNT: u32 = 50;
+
+/// The default multiple of the bytes received from an unvalidated sender tag that we send it.
+const DEFAULT_AMPLIFICATION_FACTOR: u32 = 3;
+
+/// The default number of bytes received from a sender tag after which it's validated.
+const DEFAULT_AMPLIFICATION_VALIDATION_BYTES: u64 = 16 * 1024;
+
+/// The default maximum number of replies held for an unvalidated sender tag.
+const DEFAULT_AMPLIFICATION_MAX_HELD: usize = 64;
+
+/// The default minimum number of reply SURBs attached to the ConnectionRequest of a dial.
+const DEFAULT_SURB_INITIAL_COUNT: u32 = DEFAULT_SURB_REPLENISH_COUNT;
+
+/// The default time a connection we can only reply to through SURBs stays bound to its
+/// remote's sender tag without a message from it.
+const DEFAULT_SENDER_TAG_BINDING_TTL_SECS: u64 = 600;
+
+/// The default maximum number of payload bytes sent in a single Data message;
+/// larger writes are split into fragments. This is roughly what fits in a single
+/// regular-size sphinx packet alongside our own headers.
+pub(crate) const DEFAULT_MAX_FRAGMENT_SIZE: usize = 1400;
+
+/// The default time allowed for all fragments of a payload to arrive.
+pub(crate) const DEFAULT_REASSEMBLY_TIMEOUT_SECS: u64 = 60;
+
+/// The default number of unread bytes a substream lets the remote have in flight.
+/// Until the listener's OpenResponse arrives, the dialer of a substream assumes
+/// the listener uses this window.
+pub(crate) const DEFAULT_RECEIVE_WINDOW: u32 = 256 * 1024;
+
+/// The default number of unread frames a substream holds before the remote is cut off.
+pub(crate) const DEFAULT_MAX_BUFFERED_FRAMES: usize = 4096;
+
+/// The default number of nonces ahead of the next expected one for which messages
+/// that arrive out of order are queued; messages further ahead are dropped.
+pub(crate) const DEFAULT_REPLAY_WINDOW: u64 = 4096;
+
+/// The default number of recently handled messages remembered to drop their duplicates.
+const DEFAULT_DUPLICATE_CACHE_SIZE: usize = 8192;
+
+/// The default time a connection waits for a missing message before it's closed.
+const DEFAULT_GAP_TIMEOUT_SECS: u64 = 60;
+
+/// The default difference allowed between our clock and a remote's when telling how
+/// long ago a control frame was sent.
+const DEFAULT_CLOCK_SKEW_TOLERANCE_SECS: u64 = 10;
+
+/// The default time after which a ConnectionRequest is too old to be answered.
+const DEFAULT_HANDSHAKE_MAX_AGE_SECS: u64 = 300;
+
+/// The fraction of a client's initial bandwidth below which it's low, by default.
+const DEFAULT_BANDWIDTH_LOW_FRACTION: u64 = 10;
+
+/// The default time to wait for a message to be acknowledged before it's first retransmitted.
+const DEFAULT_RETRANSMIT_INITIAL_TIMEOUT_SECS: u64 = 10;
+
+/// The default upper bound on the time between retransmissions of a message.
+const DEFAULT_RETRANSMIT_MAX_TIMEOUT_SECS: u64 = 60;
+
+/// The default number of times a message is retransmitted before its connection is failed.
+const DEFAULT_RETRANSMIT_MAX_RETRIES: u32 = 5;
+
+/// The default number of attempts to write a message to the mixnet, when send retries are enabled.
+const DEFAULT_SEND_RETRY_MAX_ATTEMPTS: u32 = 3;
+
+/// The default delay before the second attempt to write a message to the mixnet.
+const DEFAULT_SEND_RETRY_INITIAL_BACKOFF_MS: u64 = 100;
+
+/// The default upper bound on the delay between attempts to write a message to the mixnet.
+const DEFAULT_SEND_RETRY_MAX_BACKOFF_MS: u64 = 2000;
+
+/// The default time a burst of writes at the throttle's full rate is sent without waiting,
+/// when throttling is enabled.
+const DEFAULT_THROTTLE_BURST_MS: u64 = 250;
+
+/// The default number of peers a relay holds reservations for at once.
+const DEFAULT_RELAY_MAX_RESERVATIONS: usize = 128;
+
+/// The default number of circuits a relay forwards at once.
+const DEFAULT_RELAY_MAX_CIRCUITS: usize = 512;
+
+/// The default time a relay holds a reservation for, unless it's renewed.
+const DEFAULT_RELAY_RESERVATION_TTL_SECS: u64 = 3600;
+
+/// The default time a relay keeps a circuit that nothing has been sent over.
+const DEFAULT_RELAY_CIRCUIT_IDLE_TIMEOUT_SECS: u64 = 300;
+
+/// The default time small outbound messages are held for, when batching is enabled.
+pub const DEFAULT_BATCH_WINDOW_MS: u64 = 20;
+
+/// The default size above which substream payloads are compressed, when enabled.
+/// Smaller payloads fit in a single sphinx packet either way.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 512;
+
+/// The default time an outbound substream waits for the remote to answer its OpenRequest.
+pub(crate) const DEFAULT_SUBSTREAM_OPEN_TIMEOUT_SECS: u64 = 60;
+
+/// The default time allowed for a dial to complete, from queueing its ConnectionRequest
+/// to the handshake, so that a request that can't be written for a while fails too.
+const DEFAULT_DIAL_TIMEOUT_SECS: u64 = 30;
+
+/// The default time a dial to one of a peer's addresses waits for the dial to the one
+/// before to complete, before it's sent too. About a mixnet round trip, so that the
+/// first address usually wins without the others being dialed at all.
+const DEFAULT_DIAL_RACE_STAGGER_MS: u64 = 1000;
+
+/// The default time a datagram request waits for its response.
+const DEFAULT_DATAGRAM_TIMEOUT_SECS: u64 = 60;
+
+/// The default maximum size of an inbound mixnet message. Connection messages are
+/// no larger than a fragment, so only large datagrams come anywhere near it.
+pub(crate) const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// The default number of accepted connections that may wait to be picked up by the swarm.
+const DEFAULT_MAX_PENDING_INBOUND: usize = 64;
+
+/// The default number of established connections accepted from a single sender.
+const DEFAULT_MAX_CONNECTIONS_PER_SENDER: usize = 16;
+
+/// The default average number of inbound connection requests accepted per second.
+const DEFAULT_CONNECTION_REQUESTS_PER_SECOND: u32 = 10;
+
+/// The default number of inbound connection requests accepted in a burst.
+const DEFAULT_CONNECTION_REQUEST_BURST: u32 = 20;
+
+/// OverflowPolicy decides what happens when a message arrives from the mixnet
+/// while the inbound channel is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// discard the oldest queued message to make room for the new one.
+    DropOldest,
+    /// discard the message that just arrived.
+    DropNewest,
+    /// stop reading from the mixnet client until the transport has caught up.
+    #[default]
+    Backpressure,
+}
+
+/// AnonymityMode decides how the remote side of a connection we dial replies to us.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AnonymityMode {
+    /// the remote only ever replies using SURBs, so it never learns our nym address.
+    #[default]
+    SenderAnonymous,
+    /// our nym address is sent in the connection request and the remote replies to it directly.
+    /// This saves sending SURBs, at the cost of revealing who is dialing.
+    ExposeSelfAddress,
+    /// decided per dial: a multiaddr ending in `?expose` (e.g. `/nym/<address>?expose`)
+    /// exposes our address, any other multiaddr is dialed sender-anonymously.
+    PerDial,
+}
+
+/// GatewaySelection decides which gateway a mixnet client built by the transport
+/// connects to. It has no effect on a client that's passed to the transport already connected.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum GatewaySelection {
+    /// let the client choose a gateway, or reuse the one in its storage.
+    #[default]
+    Any,
+    /// connect to the gateway with the given identity key (base58).
+    Identity(String),
+    /// connect to the gateway with the lowest measured latency.
+    LowestLatency,
+    /// connect to a gateway picked at random from the given identity keys (base58).
+    Allowlist(Vec<String>),
+}
+
+/// TrafficConfig shapes the traffic of a mixnet client built by the transport. It has
+/// no effect on a client that's passed to the transport already connected. Every knob
+/// left unset keeps the client's default; lowering the delays or disabling the Poisson
+/// process and cover traffic speeds up latency-sensitive protocols at the cost of anonymity.
+///
+/// With the `test-fast` feature, the default transport config uses [`TrafficConfig::fast`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrafficConfig {
+    /// average delay each mix node holds our packets for.
+    pub average_packet_delay: Option<Duration>,
+    /// average delay between the packets the client sends to its gateway, i.e. the
+    /// inverse of the rate of its Poisson process.
+    pub sending_average_delay: Option<Duration>,
+    /// average delay between the loop cover packets the client sends.
+    pub cover_traffic_average_delay: Option<Duration>,
+    /// send packets as soon as they're ready, rather than at the Poisson rate
+    /// (with cover packets filling the gaps).
+    pub disable_poisson_traffic: bool,
+    /// don't send loop cover traffic; useful for benchmarks.
+    pub disable_cover_traffic: bool,
+    /// keep routing through the network topology fetched when the client connected,
+    /// rather than refreshing it periodically.
+    pub disable_topology_refresh: bool,
+}
+
+impl TrafficConfig {
+    /// fast returns a config with no mixing delays, Poisson process, cover traffic or
+    /// topology refreshes, which cuts the time integration tests spend waiting on the
+    /// mixnet. It gives up all of the anonymity the mixnet provides.
+    pub fn fast() -> Self {
+        TrafficConfig::default()
+            .with_average_packet_delay(Duration::ZERO)
+            .with_sending_average_delay(Duration::ZERO)
+            .without_poisson_traffic()
+            .without_cover_traffic()
+            .without_topology_refresh()
+    }
+
+    /// Set the average per-hop packet delay and return self.
+    pub fn with_average_packet_delay(mut self, delay: Duration) -> Self {
+        self.average_packet_delay = Some(delay);
+        self
+    }
+
+    /// Set the average delay between packets sent to the gateway and return self.
+    pub fn with_sending_average_delay(mut self, delay: Duration) -> Self {
+        self.sending_average_delay = Some(delay);
+        self
+    }
+
+    /// Set the average delay between loop cover packets and return self.
+    pub fn with_cover_traffic_average_delay(mut self, delay: Duration) -> Self {
+        self.cover_traffic_average_delay = Some(delay);
+        self
+    }
+
+    /// Send packets as soon as they're ready instead of at the Poisson rate, and return self.
+    pub fn without_poisson_traffic(mut self) -> Self {
+        self.disable_poisson_traffic = true;
+        self
+    }
+
+    /// Disable loop cover traffic and return self.
+    pub fn without_cover_traffic(mut self) -> Self {
+        self.disable_cover_traffic = true;
+        self
+    }
+
+    /// Disable periodic topology refreshes and return self.
+    pub fn without_topology_refresh(mut self) -> Self {
+        self.disable_topology_refresh = true;
+        self
+    }
+
+    /// debug_config returns the client's default debug config with our knobs applied.
+    pub(crate) fn debug_config(&self) -> DebugConfig {
+        let mut config = DebugConfig::default();
+        if let Some(delay) = self.average_packet_delay {
+            config.traffic.average_packet_delay = delay;
+        }
+        if let Some(delay) = self.sending_average_delay {
+            config.traffic.message_sending_average_delay = delay;
+        }
+        if let Some(delay) = self.cover_traffic_average_delay {
+            config.cover_traffic.loop_cover_traffic_average_delay = delay;
+        }
+        if self.disable_poisson_traffic {
+            config.traffic.disable_main_poisson_packet_distribution = true;
+        }
+        if self.disable_cover_traffic {
+            config.cover_traffic.disable_loop_cover_traffic_stream = true;
+        }
+        if self.disable_topology_refresh {
+            config.topology.disable_refreshing = true;
+        }
+        config
+    }
+}
+
+impl GatewaySelection {
+    /// requested_gateway returns the identity of the gateway the client should connect to,
+    /// if the selection names one.
+    pub(crate) fn requested_gateway(&self) -> Option<String> {
+        match self {
+            GatewaySelection::Identity(identity) => Some(identity.clone()),
+            GatewaySelection::Allowlist(identities) => {
+                identities.choose(&mut rand::thread_rng()).cloned()
+            }
+            GatewaySelection::Any | GatewaySelection::LowestLatency => None,
+        }
+    }
+
+    /// is_latency_based returns true if the client should measure gateway latencies to pick one.
+    pub(crate) fn is_latency_based(&self) -> bool {
+        *self == GatewaySelection::LowestLatency
+    }
+}
+
+/// NymTransportConfig holds the tunable parameters of a [`crate::transport::NymTransport`].
+#[derive(Clone, Debug)]
+pub struct NymTransportConfig {
+    /// maximum number of inbound mixnet messages buffered before the overflow policy applies.
+    pub inbound_channel_capacity: usize,
+    /// maximum number of outbound messages buffered before writers are made to wait.
+    /// Every connection has a queue of this many messages of its own, and the queues
+    /// are written to the mixnet in turn. Outbound messages are never dropped, since
+    /// that would break the nonce ordering of the connection they belong to.
+    pub outbound_channel_capacity: usize,
+    /// what to do with inbound messages once the inbound channel is full.
+    pub overflow_policy: OverflowPolicy,
+    /// how to replace the mixnet client if it disconnects from its gateway.
+    /// If None, the listener is closed when the client disconnects.
+    pub reconnect: Option<ReconnectConfig>,
+    /// interval at which the epoch of the network topology the mixnet client routes
+    /// through is checked, for [`crate::transport::NymTransport::mixnet_status`] and
+    /// [`crate::events::NymEvent::TopologyChanged`]. If None, it's never checked.
+    pub topology_check_interval: Option<Duration>,
+    /// interval between keepalive pings on each established connection.
+    /// If None, no keepalives are sent.
+    pub keepalive_interval: Option<Duration>,
+    /// number of consecutive unanswered pings after which a connection is closed.
+    pub keepalive_max_missed: u32,
+    /// time the session tickets we issue to the remotes of our connections are valid for.
+    /// A remote holding one resumes the connection, rather than closing it, once we stop
+    /// answering its keepalives, e.g. because we restarted; we need the same identity
+    /// key to accept it. Tickets are only issued on connections the remote reaches us on
+    /// at our nym address. If None, no tickets are issued, and we resume no connections.
+    pub session_ticket_lifetime: Option<Duration>,
+    /// time an established connection may go without open substreams before it's closed
+    /// with [`crate::error::Error::IdleTimeout`], so that quiet connections don't hold on
+    /// to SURBs and memory. Keepalives don't count as activity. It can be overridden per
+    /// connection with [`crate::connection::Connection::set_idle_timeout`]. If None, idle
+    /// connections are kept open.
+    pub idle_timeout: Option<Duration>,
+    /// how many reply SURBs are attached to messages we send to a nym address.
+    pub surbs: SurbConfig,
+    /// age of the newest SURBs of a connection we can only reply to through them, after
+    /// which [`crate::events::NymEvent::ReplyRouteExpiring`] is emitted. SURBs stop
+    /// working once the mixnet rotates its keys, so it should be somewhat less than
+    /// the rotation period. If None, reply routes aren't tracked.
+    pub reply_route_max_age: Option<Duration>,
+    /// time a connection we can only reply to through SURBs may go without a message
+    /// from the sender tag it was opened with, after which it's closed with
+    /// [`crate::error::Error::SenderTagExpired`]. Keepalives refresh it. Messages on
+    /// the connection from any other sender tag are dropped either way. If None, the
+    /// connection stays bound to its sender tag until it's closed.
+    pub sender_tag_binding_ttl: Option<Duration>,
+    /// whether connections we dial reveal our nym address to the remote.
+    pub anonymity: AnonymityMode,
+    /// maximum number of payload bytes sent in a single message; a write to a
+    /// substream takes at most this much, less the compression and encryption
+    /// overhead, so larger payloads are streamed out a message at a time.
+    pub max_fragment_size: usize,
+    /// time allowed for all fragments of a payload to arrive before it's discarded.
+    pub reassembly_timeout: Duration,
+    /// number of unread bytes each substream lets the remote have in flight; writers
+    /// on the remote wait once it's used up, until we read enough to send it more.
+    pub receive_window: u32,
+    /// number of received frames a substream holds unread. A remote that sends more,
+    /// or more bytes than the receive window allows, has its connection closed with
+    /// [`crate::error::Error::ReceiveBufferExceeded`].
+    pub max_buffered_frames: usize,
+    /// number of messages ahead of the next expected one that a connection queues while
+    /// waiting for messages delivered out of order. Messages outside the window, or that
+    /// have already been received (the mixnet can deliver a message twice), are dropped.
+    pub replay_window: u64,
+    /// number of recently handled messages, across all connections, whose duplicates
+    /// are dropped before they reach their connection, even once it's closed. 0 disables
+    /// it, leaving duplicates to the replay window.
+    pub duplicate_cache_size: usize,
+    /// time a connection waits for a missing message, while later messages are queued
+    /// behind it, before the connection is closed with [`crate::error::Error::MessageGapTimeout`].
+    pub gap_timeout: Duration,
+    /// time after which an OpenRequest or keepalive ping that's still on its way through
+    /// the mixnet isn't worth handling, and is dropped once it arrives. The remote stamps
+    /// them with the time they're sent if both peers run a version that supports it.
+    /// If None, control frames are handled however late they arrive.
+    pub message_max_age: Option<Duration>,
+    /// how far a remote's clock may be off from ours before its control frames or
+    /// ConnectionRequests are mistaken for stale ones; added to `message_max_age` and
+    /// `handshake_max_age`.
+    pub clock_skew_tolerance: Duration,
+    /// how unacknowledged messages are retransmitted. If None, messages are sent once
+    /// and never acknowledged. It's advertised when a connection is opened, so the
+    /// remote acknowledges our messages whether or not it retransmits its own.
+    pub retransmit: Option<RetransmitConfig>,
+    /// how writes to the mixnet that fail with a transient error, e.g. because the
+    /// gateway is briefly unreachable, are retried. If None, a message whose write
+    /// fails is dropped, leaving its recovery to retransmission.
+    pub send_retry: Option<SendRetryConfig>,
+    /// rates that writes to the mixnet are paced to, so that a burst isn't written faster
+    /// than the gateway accepts and partly dropped. If None, writes aren't paced.
+    pub throttle: Option<ThrottleConfig>,
+    /// size above which substream payloads are compressed with LZ4. If None, or without
+    /// the `compression` feature, payloads are never compressed. Compression is only
+    /// used on connections where the remote enables it too.
+    pub compression_threshold: Option<usize>,
+    /// time small outbound messages are held for, so that those going over the same
+    /// connection can be sent together in one mixnet message. If None, every message
+    /// is sent on its own. Batches are no larger than `max_fragment_size`.
+    pub batch_window: Option<Duration>,
+    /// time allowed for a dial to complete, from queueing its ConnectionRequest, before
+    /// it fails with [`crate::error::Error::DialTimeout`]. It bounds dials whose request
+    /// waits a long time to be written to the mixnet, e.g. behind a throttle; a request
+    /// that fails to be written fails its dial at once.
+    pub dial_timeout: Duration,
+    /// time allowed for the remote to answer a handshake: a dial's ConnectionRequest,
+    /// from when it was written to the mixnet, before the dial fails with
+    /// [`crate::error::Error::HandshakeTimeout`], and the challenge we answer an
+    /// inbound request with, before it's dropped with
+    /// [`crate::error::Error::ChallengeTimeout`].
+    pub handshake_timeout: Duration,
+    /// maximum number of dials in progress at once. Dials beyond it wait, in the order
+    /// they were made, for an earlier one to complete before their ConnectionRequest is
+    /// sent, and the dial timeout only starts once it is. If None, dials aren't limited.
+    pub max_concurrent_dials: Option<usize>,
+    /// when a peer dialed by PeerId is known at several nym addresses, e.g. because it
+    /// runs several mixnet clients, every address is dialed, each this long after the one
+    /// before unless it's already been answered. The first handshake to complete wins and
+    /// the other dials are aborted, with the remote's end of any that's answered late closed.
+    pub dial_race_stagger: Duration,
+    /// time an outbound substream waits for the remote to answer its OpenRequest before
+    /// it fails with [`crate::error::Error::SubstreamOpenTimeout`].
+    pub substream_open_timeout: Duration,
+    /// time a datagram request waits for its response before it fails with
+    /// [`crate::error::Error::DatagramTimeout`].
+    pub datagram_timeout: Duration,
+    /// which gateway a mixnet client built by the transport connects to.
+    pub gateway: GatewaySelection,
+    /// delays and cover traffic of a mixnet client built by the transport.
+    pub traffic: TrafficConfig,
+    /// number of additional ephemeral mixnet clients built by the transport, each with
+    /// its own nym address and gateway connection. Connections we dial without exposing
+    /// our address are spread across them and the main client, so that they can't be
+    /// linked to one another and aren't all limited by one gateway's bandwidth.
+    pub dial_clients: usize,
+    /// bandwidth credentials for the mixnet clients. If None, clients built by the
+    /// transport don't use credentials, and bandwidth isn't tracked.
+    pub credentials: Option<CredentialsConfig>,
+    /// limits on inbound connections, which protect against a peer spamming connection requests.
+    pub limits: ConnectionLimits,
+    /// number of accepted inbound connections waiting to be picked up by the swarm at
+    /// which we stop reading ConnectionRequests, leaving them in the inbound channel
+    /// until the swarm catches up. Requests that don't fit in the channel meet the
+    /// overflow policy. If None, requests are read as they arrive, and rejected once
+    /// `limits.max_pending_inbound` connections are waiting.
+    pub accept_backlog: Option<usize>,
+    /// decides whether to accept the inbound connection requests that are within the
+    /// limits. If None, every such request is accepted.
+    pub admission: Option<AdmissionHook>,
+    /// decides which of our listen addresses are reported to the swarm, and so advertised
+    /// to peers, e.g. over identify. A listener whose address is filtered out still
+    /// accepts connections, from peers that learned its address some other way. If None,
+    /// every listen address is reported.
+    pub listen_filter: Option<ListenFilter>,
+    /// maximum size of an inbound mixnet message; larger messages are dropped unparsed.
+    /// It's advertised to the remote of every connection, which fragments what it sends
+    /// to fit, and shouldn't be set below 512 bytes, or remotes refuse our connections.
+    pub max_message_size: usize,
+    /// number of invalid messages (too large, or that fail to parse) after which a sender
+    /// tag is blocked, and everything else it sends dropped. If None, senders are never blocked.
+    pub max_invalid_messages: Option<u32>,
+    /// bounds what we send to a sender tag before it has sent us enough to show it isn't
+    /// using us to amplify its traffic. If None, replies aren't limited.
+    pub amplification_limit: Option<AmplificationLimit>,
+    /// whether, and how much, we relay connections for peers that listen on our nym
+    /// address rather than their own. If None, reservations are refused, and nothing
+    /// is relayed. Listening and dialing through other relays works either way.
+    pub relay: Option<RelayConfig>,
+    /// file the address book is loaded from when the transport is created, and saved to
+    /// whenever it changes, so that the nym addresses peers are known at survive restarts.
+    /// A persistent address book trusts the first address a peer is learned at: the peer
+    /// is pinned to it, and is only learned at others by completing a handshake there. If
+    /// None, the address book is only kept in memory, and peers are only pinned explicitly.
+    pub address_book_path: Option<PathBuf>,
+    /// file the connection IDs of the ConnectionRequests we've seen are loaded from when
+    /// the transport is created, and appended to as they arrive, so that a request
+    /// captured before a restart and replayed after it is dropped, like one replayed
+    /// while we're up. If None, the requests seen are only kept in memory.
+    pub replay_cache_path: Option<PathBuf>,
+    /// time after which a ConnectionRequest, stamped with the time its handshake was
+    /// signed, is dropped rather than answered. A request is only remembered to catch
+    /// its replays for this long, since a replay of it is dropped for its age after.
+    pub handshake_max_age: Duration,
+    /// what we tell the remote about ourselves when a connection is opened, which it
+    /// sees without an identify round trip. Empty by default, since an agent version
+    /// tells the peers of an anonymous dial what software it comes from.
+    pub info: ConnectionInfo,
+    /// where transport-level metrics are recorded; by default nothing is recorded.
+    pub metrics: Metrics,
+    /// faults injected into the messages exchanged with the mixnet, to exercise the
+    /// transport's reliability features. If None, messages are left alone.
+    #[cfg(feature = "chaos")]
+    pub chaos: Option<Chaos>,
+    /// number of worker threads that encode and decode the messages exchanged with the
+    /// mixnet, when more are waiting than the mixnet task keeps up with on its own,
+    /// e.g. during bulk transfers. Messages keep their order. If None, the mixnet task
+    /// encodes and decodes every message itself.
+    #[cfg(feature = "parallel-codec")]
+    pub codec_workers: Option<usize>,
+}
+
+impl Default for NymTransportConfig {
+    fn default() -> Self {
+        NymTransportConfig {
+            inbound_channel_capacity: DEFAULT_INBOUND_CHANNEL_CAPACITY,
+            outbound_channel_capacity: DEFAULT_OUTBOUND_CHANNEL_CAPACITY,
+            overflow_policy: OverflowPolicy::default(),
+            reconnect: None,
+            // the topology isn't refreshed, so there's nothing to check
+            #[cfg(feature = "test-fast")]
+            topology_check_interval: None,
+            #[cfg(not(feature = "test-fast"))]
+            topology_check_interval: Some(Duration::from_secs(
+                DEFAULT_TOPOLOGY_CHECK_INTERVAL_SECS,
+            )),
+            keepalive_interval: Some(Duration::from_secs(DEFAULT_KEEPALIVE_INTERVAL_SECS)),
+            keepalive_max_missed: DEFAULT_KEEPALIVE_MAX_MISSED,
+            session_ticket_lifetime: None,
+            idle_timeout: None,
+            surbs: SurbConfig::default(),
+            reply_route_max_age: None,
+            sender_tag_binding_ttl: Some(Duration::from_secs(DEFAULT_SENDER_TAG_BINDING_TTL_SECS)),
+            anonymity: AnonymityMode::default(),
+            max_fragment_size: DEFAULT_MAX_FRAGMENT_SIZE,
+            reassembly_timeout: Duration::from_secs(DEFAULT_REASSEMBLY_TIMEOUT_SECS),
+            receive_window: DEFAULT_RECEIVE_WINDOW,
+            max_buffered_frames: DEFAULT_MAX_BUFFERED_FRAMES,
+            replay_window: DEFAULT_REPLAY_WINDOW,
+            duplicate_cache_size: DEFAULT_DUPLICATE_CACHE_SIZE,
+            gap_timeout: Duration::from_secs(DEFAULT_GAP_TIMEOUT_SECS),
+            message_max_age: None,
+            clock_skew_tolerance: Duration::from_secs(DEFAULT_CLOCK_SKEW_TOLERANCE_SECS),
+            retransmit: None,
+            send_retry: None,
+            throttle: None,
+            compression_threshold: None,
+            batch_window: None,
+            dial_timeout: Duration::from_secs(DEFAULT_DIAL_TIMEOUT_SECS),
+            handshake_timeout: Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT_SECS),
+            max_concurrent_dials: None,
+            dial_race_stagger: Duration::from_millis(DEFAULT_DIAL_RACE_STAGGER_MS),
+            substream_open_timeout: Duration::from_secs(DEFAULT_SUBSTREAM_OPEN_TIMEOUT_SECS),
+            datagram_timeout: Duration::from_secs(DEFAULT_DATAGRAM_TIMEOUT_SECS),
+            limits: ConnectionLimits::default(),
+            accept_backlog: None,
+            admission: None,
+            listen_filter: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_invalid_messages: None,
+            amplification_limit: None,
+            relay: None,
+            gateway: GatewaySelection::default(),
+            #[cfg(feature = "test-fast")]
+            traffic: TrafficConfig::fast(),
+            #[cfg(not(feature = "test-fast"))]
+            traffic: TrafficConfig::default(),
+            dial_clients: 0,
+            credentials: None,
+            address_book_path: None,
+            replay_cache_path: None,
+            handshake_max_age: Duration::from_secs(DEFAULT_HANDSHAKE_MAX_AGE_SECS),
+            info: ConnectionInfo::default(),
+            metrics: Metrics::default(),
+            #[cfg(feature = "chaos")]
+            chaos: None,
+            #[cfg(feature = "parallel-codec")]
+            codec_workers: None,
+        }
+    }
+}
+
+impl NymTransportConfig {
+    /// Set the inbound channel capacity and return self.
+    pub fn with_inbound_channel_capacity(mut self, capacity: usize) -> Self {
+        self.inbound_channel_capacity = capacity;
+        self
+    }
+
+    /// Set the outbound channel capacity and return self.
+    pub fn with_outbound_channel_capacity(mut self, capacity: usize) -> Self {
+        self.outbound_channel_capacity = capacity;
+        self
+    }
+
+    /// Set the inbound overflow policy and return self.
+    pub fn with_overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Enable automatic reconnection of the mixnet client and return self.
+    pub fn with_reconnect(mut self, reconnect: ReconnectConfig) -> Self {
+        self.reconnect = Some(reconnect);
+        self
+    }
+
+    /// Set the interval between topology checks and return self.
+    pub fn with_topology_check_interval(mut self, interval: Duration) -> Self {
+        self.topology_check_interval = Some(interval);
+        self
+    }
+
+    /// Disable topology checks and return self.
+    pub fn without_topology_checks(mut self) -> Self {
+        self.topology_check_interval = None;
+        self
+    }
+
+    /// Set the keepalive interval and missed-ping limit and return self.
+    pub fn with_keepalive(mut self, interval: Duration, max_missed: u32) -> Self {
+        self.keepalive_interval = Some(interval);
+        self.keepalive_max_missed = max_missed;
+        self
+    }
+
+    /// Disable keepalives and return self.
+    pub fn without_keepalive(mut self) -> Self {
+        self.keepalive_interval = None;
+        self
+    }
+
+    /// Issue session tickets valid for `lifetime` and return self.
+    pub fn with_session_tickets(mut self, lifetime: Duration) -> Self {
+        self.session_ticket_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Close connections that go `timeout` without open substreams and return self.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the SURB replenishment config and return self.
+    pub fn with_surbs(mut self, surbs: SurbConfig) -> Self {
+        self.surbs = surbs;
+        self
+    }
+
+    /// Set the age at which reply routes are reported as expiring and return self.
+    pub fn with_reply_route_max_age(mut self, max_age: Duration) -> Self {
+        self.reply_route_max_age = Some(max_age);
+        self
+    }
+
+    /// Set the time a reply route stays bound to its sender tag without a message from
+    /// it and return self.
+    pub fn with_sender_tag_binding_ttl(mut self, ttl: Duration) -> Self {
+        self.sender_tag_binding_ttl = Some(ttl);
+        self
+    }
+
+    /// Set the anonymity mode for dialed connections and return self.
+    pub fn with_anonymity(mut self, anonymity: AnonymityMode) -> Self {
+        self.anonymity = anonymity;
+        self
+    }
+
+    /// Set the fragment size and reassembly timeout and return self.
+    pub fn with_fragmentation(mut self, max_fragment_size: usize, timeout: Duration) -> Self {
+        self.max_fragment_size = max_fragment_size;
+        self.reassembly_timeout = timeout;
+        self
+    }
+
+    /// Set the per-substream receive window and return self.
+    pub fn with_receive_window(mut self, window: u32) -> Self {
+        self.receive_window = window;
+        self
+    }
+
+    /// Set the number of unread frames a substream holds and return self.
+    pub fn with_max_buffered_frames(mut self, frames: usize) -> Self {
+        self.max_buffered_frames = frames;
+        self
+    }
+
+    /// Set the replay window and return self.
+    pub fn with_replay_window(mut self, window: u64) -> Self {
+        self.replay_window = window;
+        self
+    }
+
+    /// Set the number of messages remembered to drop duplicates and return self.
+    pub fn with_duplicate_cache_size(mut self, size: usize) -> Self {
+        self.duplicate_cache_size = size;
+        self
+    }
+
+    /// Set the gap timeout and return self.
+    pub fn with_gap_timeout(mut self, timeout: Duration) -> Self {
+        self.gap_timeout = timeout;
+        self
+    }
+
+    /// Drop control frames that arrive more than `max_age` after they were sent, allowing
+    /// for the remote's clock to be `clock_skew_tolerance` behind ours, and return self.
+    pub fn with_message_max_age(
+        mut self,
+        max_age: Duration,
+        clock_skew_tolerance: Duration,
+    ) -> Self {
+        self.message_max_age = Some(max_age);
+        self.clock_skew_tolerance = clock_skew_tolerance;
+        self
+    }
+
+    /// Enable acknowledgements and retransmission of lost messages and return self.
+    pub fn with_retransmit(mut self, retransmit: RetransmitConfig) -> Self {
+        self.retransmit = Some(retransmit);
+        self
+    }
+
+    /// Enable retries of writes to the mixnet that fail with a transient error and return self.
+    pub fn with_send_retry(mut self, send_retry: SendRetryConfig) -> Self {
+        self.send_retry = Some(send_retry);
+        self
+    }
+
+    /// Enable pacing of writes to the mixnet and return self.
+    pub fn with_throttle(mut self, throttle: ThrottleConfig) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    /// Enable batching of small outbound messages with the given flush window and return self.
+    /// See [`DEFAULT_BATCH_WINDOW_MS`].
+    pub fn with_batching(mut self, window: Duration) -> Self {
+        self.batch_window = Some(window);
+        self
+    }
+
+    /// Enable compression of substream payloads larger than `threshold` bytes and return self.
+    /// See [`DEFAULT_COMPRESSION_THRESHOLD`].
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compression_threshold = Some(threshold);
+        self
+    }
+
+    /// Set the dial timeout and return self.
+    pub fn with_dial_timeout(mut self, timeout: Duration) -> Self {
+        self.dial_timeout = timeout;
+        self
+    }
+
+    /// Set the handshake timeout and return self.
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Limit the number of dials in progress at once and return self.
+    pub fn with_max_concurrent_dials(mut self, max: usize) -> Self {
+        self.max_concurrent_dials = Some(max);
+        self
+    }
+
+    /// Set the delay between racing dials to a peer's addresses and return self.
+    pub fn with_dial_race_stagger(mut self, stagger: Duration) -> Self {
+        self.dial_race_stagger = stagger;
+        self
+    }
+
+    /// Set the substream open timeout and return self.
+    pub fn with_substream_open_timeout(mut self, timeout: Duration) -> Self {
+        self.substream_open_timeout = timeout;
+        self
+    }
+
+    /// Set the datagram request timeout and return self.
+    pub fn with_datagram_timeout(mut self, timeout: Duration) -> Self {
+        self.datagram_timeout = timeout;
+        self
+    }
+
+    /// Set how the gateway is selected and return self.
+    pub fn with_gateway(mut self, gateway: GatewaySelection) -> Self {
+        self.gateway = gateway;
+        self
+    }
+
+    /// Set the traffic shaping of mixnet clients built by the transport and return self.
+    pub fn with_traffic(mut self, traffic: TrafficConfig) -> Self {
+        self.traffic = traffic;
+        self
+    }
+
+    /// Set the number of additional mixnet clients dials are spread across and return self.
+    pub fn with_dial_clients(mut self, count: usize) -> Self {
+        self.dial_clients = count;
+        self
+    }
+
+    /// Set the maximum size of an inbound message and return self.
+    pub fn with_max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Block sender tags after `max` invalid messages and return self.
+    pub fn with_max_invalid_messages(mut self, max: u32) -> Self {
+        self.max_invalid_messages = Some(max);
+        self
+    }
+
+    /// Keep the address book in the file at `path` and return self.
+    pub fn with_address_book_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.address_book_path = Some(path.into());
+        self
+    }
+
+    /// Keep the ConnectionRequests seen in the file at `path` and return self.
+    pub fn with_replay_cache_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.replay_cache_path = Some(path.into());
+        self
+    }
+
+    /// Set the age after which a ConnectionRequest is dropped and return self.
+    pub fn with_handshake_max_age(mut self, max_age: Duration) -> Self {
+        self.handshake_max_age = max_age;
+        self
+    }
+
+    /// Enable bandwidth credentials and return self.
+    pub fn with_credentials(mut self, credentials: CredentialsConfig) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// credentials_mnemonic returns the mnemonic that clients built by the transport
+    /// buy a ticketbook with, if any.
+    pub(crate) fn credentials_mnemonic(&self) -> Option<String> {
+        self.credentials.as_ref()?.mnemonic.clone()
+    }
+
+    /// Set the inbound connection limits and return self.
+    pub fn with_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Set the number of pending inbound connections at which ConnectionRequests are
+    /// left unread and return self.
+    pub fn with_accept_backlog(mut self, backlog: usize) -> Self {
+        self.accept_backlog = Some(backlog);
+        self
+    }
+
+    /// Set the hook which decides whether to accept inbound connection requests and
+    /// return self.
+    pub fn with_admission<F, Fut>(mut self, admit: F) -> Self
+    where
+        F: Fn(&PeerId, &ConnectionInfo) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Decision> + Send + 'static,
+    {
+        self.admission = Some(AdmissionHook::new(admit));
+        self
+    }
+
+    /// Set the filter deciding which listen addresses are reported and return self.
+    pub fn with_listen_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&Multiaddr) -> bool + Send + Sync + 'static,
+    {
+        self.listen_filter = Some(ListenFilter::new(filter));
+        self
+    }
+
+    /// reports_listen_addr returns true if the listen address `addr` is reported to the
+    /// swarm, which it is unless the listen filter filters it out.
+    pub(crate) fn reports_listen_addr(&self, addr: &Multiaddr) -> bool {
+        self.listen_filter
+            .as_ref()
+            .map_or(true, |filter| filter.reports(addr))
+    }
+
+    /// Set the limit on replies to unvalidated sender tags and return self.
+    pub fn with_amplification_limit(mut self, limit: AmplificationLimit) -> Self {
+        self.amplification_limit = Some(limit);
+        self
+    }
+
+    /// Relay connections for other peers and return self.
+    pub fn with_relay(mut self, relay: RelayConfig) -> Self {
+        self.relay = Some(relay);
+        self
+    }
+
+    /// Set the agent version sent when a connection is opened and return self.
+    pub fn with_agent_version(mut self, agent_version: impl Into<String>) -> Self {
+        self.info.agent_version = Some(agent_version.into());
+        self
+    }
+
+    /// Set the extensions advertised when a connection is opened and return self.
+    pub fn with_extensions(mut self, extensions: Vec<String>) -> Self {
+        self.info.extensions = extensions;
+        self
+    }
+
+    /// Set the metrics to record into and return self.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Inject the faults of `chaos` into the messages exchanged with the mixnet and return self.
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Encode and decode messages on `workers` worker threads and return self.
+    #[cfg(feature = "parallel-codec")]
+    pub fn with_codec_workers(mut self, workers: usize) -> Self {
+        self.codec_workers = Some(workers);
+        self
+    }
+}
+
+/// SurbConfig controls how many reply SURBs are attached to messages sent to a nym address.
+/// A peer that we dialed can only answer us using the SURBs we've sent it, so we keep
+/// an estimate of how many it has left and send it more before it runs out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SurbConfig {
+    /// number of SURBs attached to every message.
+    pub per_message: u32,
+    /// estimated number of unused SURBs held by the peer below which we replenish them.
+    pub replenish_threshold: u32,
+    /// number of SURBs attached to a message when replenishing.
+    pub replenish_count: u32,
+    /// minimum number of SURBs attached to the ConnectionRequest of a dial. Sending
+    /// more up front saves the round trips of replenishing them later on connections
+    /// that receive a lot, at the cost of a larger first message.
+    pub initial_count: u32,
+}
+
+impl Default for SurbConfig {
+    fn default() -> Self {
+        SurbConfig {
+            per_message: DEFAULT_SURBS_PER_MESSAGE,
+            replenish_threshold: DEFAULT_SURB_REPLENISH_THRESHOLD,
+            replenish_count: DEFAULT_SURB_REPLENISH_COUNT,
+            initial_count: DEFAULT_SURB_INITIAL_COUNT,
+        }
+    }
+}
+
+impl SurbConfig {
+    /// Set the minimum number of SURBs sent with a dial and return self.
+    pub fn with_initial_count(mut self, count: u32) -> Self {
+        self.initial_count = count;
+        self
+    }
+}
+
+/// AmplificationLimit bounds the bytes we reply to an anonymous peer with, known only
+/// by its sender tag, so that a few small messages can't make us send it (or whoever
+/// it's spending SURBs for) a lot of traffic. Until the sender tag has sent us
+/// `validation_bytes`, we send it at most `factor` times as many bytes as we've received
+/// from it; replies beyond that are held until it sends more, and dropped once more
+/// than `max_held` are waiting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AmplificationLimit {
+    /// multiple of the bytes received from a sender tag that we send it before it's validated.
+    pub factor: u32,
+    /// bytes received from a sender tag after which what we send it isn't limited.
+    pub validation_bytes: u64,
+    /// maximum number of replies held per sender tag.
+    pub max_held: usize,
+}
+
+impl Default for AmplificationLimit {
+    fn default() -> Self {
+        AmplificationLimit {
+            factor: DEFAULT_AMPLIFICATION_FACTOR,
+            validation_bytes: DEFAULT_AMPLIFICATION_VALIDATION_BYTES,
+            max_held: DEFAULT_AMPLIFICATION_MAX_HELD,
+        }
+    }
+}
+
+/// ConnectionLimits bound the state a remote can make us hold by sending
+/// ConnectionRequests. Requests beyond the limits are dropped without a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// maximum number of accepted connections waiting to be picked up by the swarm.
+    pub max_pending_inbound: usize,
+    /// maximum number of established connections accepted from a single sender tag.
+    pub max_connections_per_sender: usize,
+    /// average number of ConnectionRequests accepted per second.
+    pub requests_per_second: u32,
+    /// number of ConnectionRequests accepted at once before the rate applies.
+    pub burst: u32,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_pending_inbound: DEFAULT_MAX_PENDING_INBOUND,
+            max_connections_per_sender: DEFAULT_MAX_CONNECTIONS_PER_SENDER,
+            requests_per_second: DEFAULT_CONNECTION_REQUESTS_PER_SECOND,
+            burst: DEFAULT_CONNECTION_REQUEST_BURST,
+        }
+    }
+}
+
+impl ConnectionLimits {
+    /// Set the maximum number of pending inbound connections and return self.
+    pub fn with_max_pending_inbound(mut self, max: usize) -> Self {
+        self.max_pending_inbound = max;
+        self
+    }
+
+    /// Set the maximum number of established connections per sender and return self.
+    pub fn with_max_connections_per_sender(mut self, max: usize) -> Self {
+        self.max_connections_per_sender = max;
+        self
+    }
+
+    /// Set the rate and burst of accepted ConnectionRequests and return self.
+    pub fn with_rate(mut self, requests_per_second: u32, burst: u32) -> Self {
+        self.requests_per_second = requests_per_second;
+        self.burst = burst;
+        self
+    }
+}
+
+/// SendRetryConfig controls how writes to the mixnet are retried when the nym client
+/// fails to send a message for a reason that may pass, such as its gateway being
+/// briefly unreachable. Errors that retrying can't fix are returned at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SendRetryConfig {
+    /// number of attempts to write a message, including the first, before giving up
+    /// with [`crate::error::Error::SendRetriesExhausted`].
+    pub max_attempts: u32,
+    /// delay before the second attempt; doubled after every failed attempt.
+    pub initial_backoff: Duration,
+    /// upper bound on the delay between attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for SendRetryConfig {
+    fn default() -> Self {
+        SendRetryConfig {
+            max_attempts: DEFAULT_SEND_RETRY_MAX_ATTEMPTS,
+            initial_backoff: Duration::from_millis(DEFAULT_SEND_RETRY_INITIAL_BACKOFF_MS),
+            max_backoff: Duration::from_millis(DEFAULT_SEND_RETRY_MAX_BACKOFF_MS),
+        }
+    }
+}
+
+impl SendRetryConfig {
+    /// Set the maximum number of attempts and return self.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Set the backoff bounds and return self.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// backoff returns the delay after the given failed attempt, counting from 1: the
+    /// exponential backoff, reduced by a random amount of up to half, so that writes
+    /// which failed together aren't retried together.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let backoff = std::cmp::min(backoff, self.max_backoff);
+        backoff.mul_f64(1.0 - rand::random::<f64>() / 2.0)
+    }
+}
+
+/// RelayConfig bounds what we hold as a relay for peers that don't want their own nym
+/// address known. A peer reserves with us, sending its SURBs rather than its address,
+/// and listens at `/nym/<our address>/p2p-circuit`; we forward the circuits that others
+/// open to it there between its SURBs and theirs, without learning either's address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RelayConfig {
+    /// maximum number of peers we hold reservations for; further reservations are refused.
+    pub max_reservations: usize,
+    /// maximum number of circuits we forward; further circuits are refused.
+    pub max_circuits: usize,
+    /// time a reservation is held for. Peers renew theirs halfway through.
+    pub reservation_ttl: Duration,
+    /// time a circuit is kept while nothing is sent over it.
+    pub circuit_idle_timeout: Duration,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        RelayConfig {
+            max_reservations: DEFAULT_RELAY_MAX_RESERVATIONS,
+            max_circuits: DEFAULT_RELAY_MAX_CIRCUITS,
+            reservation_ttl: Duration::from_secs(DEFAULT_RELAY_RESERVATION_TTL_SECS),
+            circuit_idle_timeout: Duration::from_secs(DEFAULT_RELAY_CIRCUIT_IDLE_TIMEOUT_SECS),
+        }
+    }
+}
+
+impl RelayConfig {
+    /// Set the maximum number of reservations and return self.
+    pub fn with_max_reservations(mut self, max: usize) -> Self {
+        self.max_reservations = max;
+        self
+    }
+
+    /// Set the maximum number of circuits and return self.
+    pub fn with_max_circuits(mut self, max: usize) -> Self {
+        self.max_circuits = max;
+        self
+    }
+
+    /// Set the reservation TTL and return self.
+    pub fn with_reservation_ttl(mut self, ttl: Duration) -> Self {
+        self.reservation_ttl = ttl;
+        self
+    }
+
+    /// Set the circuit idle timeout and return self.
+    pub fn with_circuit_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.circuit_idle_timeout = timeout;
+        self
+    }
+}
+
+/// ThrottleConfig sets the rates that writes to the mixnet are paced to. Gateways
+/// rate-limit their clients and silently drop the packets beyond the limit, so
+/// outbound messages wait for the throttle instead, and a burst of them is smoothed
+/// into a rate the gateway accepts. Retransmissions are paced along with everything else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThrottleConfig {
+    /// average number of sphinx packets written per second.
+    pub packets_per_second: u32,
+    /// average number of message bytes written per second.
+    pub bytes_per_second: u32,
+    /// how long a burst at the full rates is written without waiting, after a quiet spell.
+    pub burst: Duration,
+}
+
+impl ThrottleConfig {
+    /// New throttle config which paces writes to `packets_per_second` and `bytes_per_second`.
+    pub fn new(packets_per_second: u32, bytes_per_second: u32) -> Self {
+        ThrottleConfig {
+            packets_per_second,
+            bytes_per_second,
+            burst: Duration::from_millis(DEFAULT_THROTTLE_BURST_MS),
+        }
+    }
+
+    /// Set the burst length and return self.
+    pub fn with_burst(mut self, burst: Duration) -> Self {
+        self.burst = burst;
+        self
+    }
+}
+
+/// RetransmitConfig controls how messages sent over a connection are retransmitted
+/// until the remote acknowledges them, since the mixnet can drop packets silently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetransmitConfig {
+    /// time to wait for an acknowledgement before the first retransmission, until the
+    /// connection's round-trip time has been measured; doubled after every retransmission.
+    pub initial_timeout: Duration,
+    /// upper bound on the time to wait between retransmissions.
+    pub max_timeout: Duration,
+    /// number of retransmissions after which the message's connection is failed
+    /// with [`crate::error::Error::DeliveryFailed`].
+    pub max_retries: u32,
+}
+
+impl Default for RetransmitConfig {
+    fn default() -> Self {
+        RetransmitConfig {
+            initial_timeout: Duration::from_secs(DEFAULT_RETRANSMIT_INITIAL_TIMEOUT_SECS),
+            max_timeout: Duration::from_secs(DEFAULT_RETRANSMIT_MAX_TIMEOUT_SECS),
+            max_retries: DEFAULT_RETRANSMIT_MAX_RETRIES,
+        }
+    }
+}
+
+impl RetransmitConfig {
+    /// Set the retransmission timeout bounds and return self.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_timeout = initial;
+        self.max_timeout = max;
+        self
+    }
+
+    /// Set the maximum number of retransmissions and return self.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// next_timeout returns the time to wait after a retransmission that waited `current`.
+    pub(crate) fn next_timeout(&self, current: Duration) -> Duration {
+        std::cmp::min(current.saturating_mul(2), self.max_timeout)
+    }
+}
+
+/// Decision is what an [`AdmissionHook`] decided to do with an inbound connection request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// the request is answered, and the connection handed to the swarm once the
+    /// dialer has answered our challenge.
+    Accept,
+    /// the request is dropped without a response, like one beyond the limits.
+    Reject,
+}
+
+type AdmissionFn = dyn Fn(&PeerId, &ConnectionInfo) -> BoxFuture<'static, Decision> + Send + Sync;
+
+/// AdmissionHook decides whether to accept an inbound connection request, e.g. against
+/// an allowlist, a rate limit of its own, or the resources the application has left.
+/// It's called with the dialer's PeerId, once the dialer's handshake has shown it holds
+/// the key, and the info the dialer sent about itself. Requests beyond the
+/// [`ConnectionLimits`] are rejected before it's called, and a hook that hasn't decided
+/// within the handshake timeout rejects the request.
+#[derive(Clone)]
+pub struct AdmissionHook(Arc<AdmissionFn>);
+
+impl AdmissionHook {
+    /// New admission hook which decides with `admit`.
+    pub fn new<F, Fut>(admit: F) -> Self
+    where
+        F: Fn(&PeerId, &ConnectionInfo) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Decision> + Send + 'static,
+    {
+        AdmissionHook(Arc::new(move |peer_id: &PeerId, info: &ConnectionInfo| {
+            admit(peer_id, info).boxed()
+        }))
+    }
+
+    /// admit starts deciding on a request from `peer_id`.
+    pub(crate) fn admit(
+        &self,
+        peer_id: &PeerId,
+        info: &ConnectionInfo,
+    ) -> BoxFuture<'static, Decision> {
+        (self.0)(peer_id, info)
+    }
+}
+
+impl fmt::Debug for AdmissionHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdmissionHook").finish_non_exhaustive()
+    }
+}
+
+/// ListenFilter decides whether a listen address is reported to the swarm, which
+/// advertises the addresses it's told about to its peers. Filtering out our own nym
+/// address keeps a listener that's only meant to be reached through a relay, or by
+/// peers given its address out of band, from telling everyone it identifies with
+/// where it is; the address isn't reported as an observed address either.
+#[derive(Clone)]
+pub struct ListenFilter(Arc<dyn Fn(&Multiaddr) -> bool + Send + Sync>);
+
+impl ListenFilter {
+    /// New listen filter which reports the addresses `filter` returns true for.
+    pub fn new<F>(filter: F) -> Self
+    where
+        F: Fn(&Multiaddr) -> bool + Send + Sync + 'static,
+    {
+        ListenFilter(Arc::new(filter))
+    }
+
+    /// reports returns true if `addr` is reported to the swarm.
+    pub(crate) fn reports(&self, addr: &Multiaddr) -> bool {
+        (self.0)(addr)
+    }
+}
+
+impl fmt::Debug for ListenFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ListenFilter").finish_non_exhaustive()
+    }
+}
+
+type ConnectFn =
+    dyn Fn() -> BoxFuture<'static, Result<Box<dyn MixnetBackend>, nym_sdk::Error>> + Send + Sync;
+
+/// ReconnectConfig describes how a disconnected mixnet client is replaced.
+/// The replacement client should be built from the same stored keys as the
+/// original one, so that our nym address (and thus our listen address) stays the same.
+#[derive(Clone)]
+pub struct ReconnectConfig {
+    pub(crate) connect: Arc<ConnectFn>,
+    /// delay before the first reconnection attempt; doubled after every failed attempt.
+    pub initial_backoff: Duration,
+    /// upper bound on the delay between reconnection attempts.
+    pub max_backoff: Duration,
+}
+
+impl ReconnectConfig {
+    /// New reconnect config which builds replacement clients (or other mixnet backends)
+    /// with `connect`.
+    pub fn new<F, Fut, B>(connect: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<B, nym_sdk::Error>> + Send + 'static,
+        B: MixnetBackend,
+    {
+        ReconnectConfig {
+            connect: Arc::new(move || {
+                connect()
+                    .map_ok(|backend| Box::new(backend) as Box<dyn MixnetBackend>)
+                    .boxed()
+            }),
+            initial_backoff: Duration::from_millis(DEFAULT_RECONNECT_INITIAL_BACKOFF_MS),
+            max_backoff: Duration::from_secs(DEFAULT_RECONNECT_MAX_BACKOFF_SECS),
+        }
+    }
+
+    /// New reconnect config which rebuilds the client from the keys and gateway
+    /// registration persisted at `paths`.
+    pub fn from_storage(paths: StoragePaths) -> Self {
+        Self::new(move || {
+            let paths = paths.clone();
+            async move {
+                MixnetClientBuilder::new_with_default_storage(paths)
+                    .await?
+                    .build()?
+                    .connect_to_mixnet()
+                    .await
+            }
+        })
+    }
+
+    /// Set the backoff bounds and return self.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// next_backoff returns the delay to use after a failed attempt that waited `current`.
+    pub(crate) fn next_backoff(&self, current: Duration) -> Duration {
+        std::cmp::min(current.saturating_mul(2), self.max_backoff)
+    }
+}
+
+impl fmt::Debug for ReconnectConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectConfig")
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .finish()
+    }
+}
+
+type TopUpFn = dyn Fn() -> BoxFuture<'static, Result<u64, nym_sdk::Error>> + Send + Sync;
+
+/// CredentialsConfig supplies the bandwidth credentials that mixnet clients pay their
+/// gateways with. Mixnet clients built by the transport run in credentials mode, and
+/// buy a ticketbook with the configured mnemonic's account before they connect.
+///
+/// The transport estimates how much of a client's bandwidth is left from the messages
+/// it sends (not counting cover traffic). Once the estimate drops below `low_threshold`,
+/// it emits [`crate::events::NymEvent::BandwidthLow`] and calls the top-up callback,
+/// if any, which should buy more bandwidth for the client and return how many bytes it bought.
+#[derive(Clone)]
+pub struct CredentialsConfig {
+    pub(crate) mnemonic: Option<String>,
+    pub(crate) top_up: Option<Arc<TopUpFn>>,
+    /// estimated bandwidth, in bytes, a client has when it connects.
+    pub allowance: u64,
+    /// estimated bandwidth, in bytes, below which a client's bandwidth is low.
+    pub low_threshold: u64,
+}
+
+impl CredentialsConfig {
+    /// New credentials config for clients which start out with `allowance` bytes of bandwidth.
+    pub fn new(allowance: u64) -> Self {
+        CredentialsConfig {
+            mnemonic: None,
+            top_up: None,
+            allowance,
+            low_threshold: allowance / DEFAULT_BANDWIDTH_LOW_FRACTION,
+        }
+    }
+
+    /// Set the mnemonic of the account that ticketbooks are bought with and return self.
+    /// Without one, clients spend the ticketbooks already in their storage.
+    pub fn with_mnemonic(mut self, mnemonic: impl Into<String>) -> Self {
+        self.mnemonic = Some(mnemonic.into());
+        self
+    }
+
+    /// Set the callback which buys more bandwidth once it's low and return self.
+    pub fn with_top_up<F, Fut>(mut self, top_up: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<u64, nym_sdk::Error>> + Send + 'static,
+    {
+        self.top_up = Some(Arc::new(move || top_up().boxed()));
+        self
+    }
+
+    /// Set the low bandwidth threshold and return self.
+    pub fn with_low_threshold(mut self, bytes: u64) -> Self {
+        self.low_threshold = bytes;
+        self
+    }
+}
+
+impl fmt::Debug for CredentialsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the mnemonic controls the account's funds, so it's never printed
+        f.debug_struct("CredentialsConfig")
+            .field("allowance", &self.allowance)
+            .field("low_threshold", &self.low_threshold)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_gateway_selection() {
+        assert_eq!(GatewaySelection::Any.requested_gateway(), None);
+        assert!(!GatewaySelection::Any.is_latency_based());
+
+        let selection = GatewaySelection::Identity("gateway".to_string());
+        assert_eq!(selection.requested_gateway(), Some("gateway".to_string()));
+
+        assert_eq!(GatewaySelection::LowestLatency.requested_gateway(), None);
+        assert!(GatewaySelection::LowestLatency.is_latency_based());
+
+        let allowlist = vec!["a".to_string(), "b".to_string()];
+        let selection = GatewaySelection::Allowlist(allowlist.clone());
+        let gateway = selection.requested_gateway().unwrap();
+        assert!(allowlist.contains(&gateway));
+        assert_eq!(
+            GatewaySelection::Allowlist(vec![]).requested_gateway(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_traffic_config() {
+        // unset knobs keep the client's defaults
+        let default = DebugConfig::default();
+        let config = TrafficConfig::default().debug_config();
+        assert_eq!(
+            config.traffic.average_packet_delay,
+            default.traffic.average_packet_delay
+        );
+        assert!(!config.traffic.disable_main_poisson_packet_distribution);
+        assert!(!config.cover_traffic.disable_loop_cover_traffic_stream);
+
+        let config = TrafficConfig::default()
+            .with_average_packet_delay(Duration::from_millis(5))
+            .with_sending_average_delay(Duration::from_millis(1))
+            .with_cover_traffic_average_delay(Duration::from_millis(500))
+            .without_poisson_traffic()
+            .without_cover_traffic()
+            .debug_config();
+        assert_eq!(
+            config.traffic.average_packet_delay,
+            Duration::from_millis(5)
+        );
+        assert_eq!(
+            config.traffic.message_sending_average_delay,
+            Duration::from_millis(1)
+        );
+        assert_eq!(
+            config.cover_traffic.loop_cover_traffic_average_delay,
+            Duration::from_millis(500)
+        );
+        assert!(config.traffic.disable_main_poisson_packet_distribution);
+        assert!(config.cover_traffic.disable_loop_cover_traffic_stream);
+        assert!(!config.topology.disable_refreshing);
+
+        let config = TrafficConfig::fast().debug_config();
+        assert_eq!(config.traffic.average_packet_delay, Duration::ZERO);
+        assert_eq!(config.traffic.message_sending_average_delay, Duration::ZERO);
+        assert!(config.traffic.disable_main_poisson_packet_distribution);
+        assert!(config.cover_traffic.disable_loop_cover_traffic_stream);
+        assert!(config.topology.disable_refreshing);
+    }
+
+    #[test]
+    fn test_send_retry_backoff() {
+        let retry = SendRetryConfig::default()
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(300));
+        for _ in 0..100 {
+            // doubled after every attempt, up to the maximum, and jittered down by up to half
+            let backoff = retry.backoff(1);
+            assert!(backoff >= Duration::from_millis(50) && backoff <= Duration::from_millis(100));
+            let backoff = retry.backoff(2);
+            assert!(backoff >= Duration::from_millis(100) && backoff <= Duration::from_millis(200));
+            let backoff = retry.backoff(10);
+            assert!(backoff >= Duration::from_millis(150) && backoff <= Duration::from_millis(300));
+        }
+    }
+}
@@ -1,28 +1,338 @@
+use bytes::Bytes;
+use futures::ready;
 use libp2p::core::{muxing::StreamMuxerEvent, PeerId, StreamMuxer};
-use log::debug;
+use libp2p_identity::{Keypair, PublicKey};
 use nym_sdk::mixnet::AnonymousSenderTag;
 use nym_sphinx::addressing::clients::Recipient;
+use parking_lot::Mutex;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     pin::Pin,
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
-    },
+    sync::Arc,
     task::{Context, Poll, Waker},
+    time::Duration,
 };
 use tokio::sync::{
     mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
     oneshot,
 };
-use tracing::field::debug;
+use tracing::{debug, Span};
 
+use super::channel::BoundedSender;
+use super::compression::{Compression, COMPRESSION_OVERHEAD};
+use super::config::{
+    DEFAULT_MAX_BUFFERED_FRAMES, DEFAULT_MAX_FRAGMENT_SIZE, DEFAULT_MAX_MESSAGE_SIZE,
+    DEFAULT_REASSEMBLY_TIMEOUT_SECS, DEFAULT_RECEIVE_WINDOW, DEFAULT_SUBSTREAM_OPEN_TIMEOUT_SECS,
+};
 use super::error::Error;
+use super::events::{DropReason, EventSender, NymEvent};
+use super::handshake::{
+    new_resume_nonce, proofs_match, sign_migration, Handshake, ResumptionSecret, Role,
+    SessionCipher, CIPHER_OVERHEAD, RESUME_NONCE_LEN,
+};
 use super::message::{
-    ConnectionId, Message, OutboundMessage, SubstreamId, SubstreamMessage, SubstreamMessageType,
-    TransportMessage,
+    AckMessage, Capabilities, ConnectionCloseMessage, ConnectionId, ConnectionInfo,
+    KeepAliveMessage, KeepAliveType, Message, MigrateMessage, OutboundMessage, Reassembler,
+    ResumeRequestMessage, ResumeResponseMessage, SubstreamId, SubstreamMessage,
+    SubstreamMessageType, TransportMessage, DATAGRAM_NONCE,
 };
-use super::substream::Substream;
+use super::metrics::{Metrics, Tracked};
+use super::nonce::{Nonce, NonceCounter};
+use super::rtt::RttTable;
+use super::runtime::{interval_at, unix_millis, Instant, Interval, MissedTickBehavior};
+use super::stats::{ConnectionStats, StatsTable};
+use super::substream::{ReceiveBuffer, SendWindow, Substream};
+
+/// The shortest interval at which pending outbound substreams are checked for timeouts.
+const MIN_OPEN_CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+fn open_check_interval(open_timeout: Duration) -> Interval {
+    let period = std::cmp::max(open_timeout / 2, MIN_OPEN_CHECK_PERIOD);
+    let mut interval = interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+/// ConnectionEvent is delivered by the transport to an established Connection.
+#[derive(Debug)]
+pub(crate) enum ConnectionEvent {
+    /// an in-order message for one of the connection's substreams, with the span
+    /// the transport received it in.
+    Substream(SubstreamMessage, Span),
+    KeepAlive(KeepAliveMessage),
+    /// the connection failed for a reason the transport noticed, e.g. a message
+    /// the connection's queued messages were waiting on never arrived.
+    Failed(Error),
+    /// the remote closed the connection.
+    Reset,
+    /// the remote answered our ResumeRequest, and the connection carries on with
+    /// these keys, and its nonces start over.
+    Resumed(SessionCipher),
+}
+
+/// KeepAlive tracks the keepalive pings sent over a connection.
+#[derive(Debug)]
+struct KeepAlive {
+    interval: Interval,
+    /// number of pings sent since the last pong was received.
+    missed: u32,
+    max_missed: u32,
+    /// sequence number of the last ping sent.
+    seq: u64,
+    /// when the last ping was sent; used to measure the mixnet round-trip time.
+    sent_at: Option<Instant>,
+}
+
+/// ResumeState is what a connection needs to be resumed with a session ticket once the
+/// remote has lost its state. It's shared by the connection and its ConnectionHandle.
+#[derive(Debug, Default)]
+struct ResumeState {
+    /// the latest ticket the remote issued us; taken once it's used.
+    ticket: Option<Vec<u8>>,
+    /// our side of the ResumeRequest we sent, until it's answered.
+    pending: Option<PendingResume>,
+}
+
+#[derive(Debug)]
+struct PendingResume {
+    nonce: [u8; RESUME_NONCE_LEN],
+    secret: ResumptionSecret,
+    role: Role,
+}
+
+/// IdleTimeout closes a connection that's gone without open substreams for too long.
+#[derive(Debug)]
+struct IdleTimeout {
+    timeout: Duration,
+    /// ticks whenever the connection should be checked for idleness
+    check: Interval,
+    /// when the connection last had an open substream, or received a substream message
+    last_active: Instant,
+}
+
+impl IdleTimeout {
+    fn new(timeout: Duration) -> Self {
+        IdleTimeout {
+            timeout,
+            check: open_check_interval(timeout),
+            last_active: Instant::now(),
+        }
+    }
+}
+
+/// ConnectionHandle is the transport's side of an established Connection.
+/// It delivers inbound events to the connection, and can close the connection's
+/// substreams without the connection itself being polled, e.g. on transport shutdown.
+#[derive(Clone, Debug)]
+pub(crate) struct ConnectionHandle {
+    pub(crate) inbound_tx: UnboundedSender<ConnectionEvent>,
+    /// the channel to the mixnet client the connection's messages are sent through
+    pub(crate) outbound_tx: BoundedSender<OutboundMessage>,
+    id: ConnectionId,
+    remote_peer_id: PeerId,
+    remote_recipient: Option<Recipient>,
+    sender_tag: Option<AnonymousSenderTag>,
+    message_nonce: NonceCounter,
+    open_substreams: Arc<Mutex<HashSet<SubstreamId>>>,
+    pending_substreams: Arc<Mutex<HashMap<SubstreamId, Instant>>>,
+    /// the optional features the remote advertised when the connection was opened.
+    remote_capabilities: Capabilities,
+    /// the largest message the remote advertised it accepts.
+    remote_max_message_size: u32,
+    /// the identity we opened the connection with, which the remote knows us by.
+    local_key: Option<Keypair>,
+    /// the identity key the remote opened the connection with.
+    remote_identity: Option<PublicKey>,
+    /// whether the remote sends to our nym address, rather than through SURBs.
+    exposes_address: bool,
+    /// what the remote told us about itself when the connection was opened.
+    remote_info: Arc<ConnectionInfo>,
+    resume: Arc<Mutex<ResumeState>>,
+}
+
+impl ConnectionHandle {
+    pub(crate) fn with_remote_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.remote_capabilities = capabilities;
+        self
+    }
+
+    pub(crate) fn with_remote_max_message_size(mut self, max_message_size: u32) -> Self {
+        self.remote_max_message_size = max_message_size;
+        self
+    }
+
+    pub(crate) fn with_local_key(mut self, keypair: Keypair) -> Self {
+        self.local_key = Some(keypair);
+        self
+    }
+
+    pub(crate) fn with_remote_identity(mut self, identity: PublicKey) -> Self {
+        self.remote_identity = Some(identity);
+        self
+    }
+
+    /// with_exposed_address marks the connection as one the remote reaches us on at our
+    /// nym address, and return self.
+    pub(crate) fn with_exposed_address(mut self) -> Self {
+        self.exposes_address = true;
+        self
+    }
+
+    pub(crate) fn remote_peer_id(&self) -> PeerId {
+        self.remote_peer_id
+    }
+
+    pub(crate) fn remote_recipient(&self) -> Option<&Recipient> {
+        self.remote_recipient.as_ref()
+    }
+
+    pub(crate) fn local_key(&self) -> Option<&Keypair> {
+        self.local_key.as_ref()
+    }
+
+    pub(crate) fn remote_info(&self) -> &ConnectionInfo {
+        &self.remote_info
+    }
+
+    pub(crate) fn remote_identity(&self) -> Option<&PublicKey> {
+        self.remote_identity.as_ref()
+    }
+
+    pub(crate) fn remote_capabilities(&self) -> Capabilities {
+        self.remote_capabilities
+    }
+
+    pub(crate) fn remote_max_message_size(&self) -> u32 {
+        self.remote_max_message_size
+    }
+
+    pub(crate) fn exposes_address(&self) -> bool {
+        self.exposes_address
+    }
+
+    /// set_session_ticket keeps the latest session ticket the remote issued us, which
+    /// the connection is resumed with if the remote stops answering its keepalives.
+    pub(crate) fn set_session_ticket(&self, ticket: Vec<u8>) {
+        self.resume.lock().ticket = Some(ticket);
+    }
+
+    /// finish_resume checks the remote's answer to our ResumeRequest, and returns the
+    /// keys the connection is encrypted with from now on. A response without a valid
+    /// proof leaves the request pending, so a forged one can't cancel it.
+    pub(crate) fn finish_resume(
+        &self,
+        msg: &ResumeResponseMessage,
+    ) -> Result<SessionCipher, Error> {
+        let mut resume = self.resume.lock();
+        let Some(pending) = resume.pending.as_ref() else {
+            return Err(Error::NoConnectionForResumeResponse);
+        };
+        let proof = pending
+            .secret
+            .response_proof(&self.id, &pending.nonce, &msg.nonce);
+        if !proofs_match(&proof, &msg.proof) {
+            return Err(Error::InvalidResumptionProof);
+        }
+
+        let pending = resume.pending.take().expect("checked above");
+        Ok(pending
+            .secret
+            .resume(&self.id, &pending.nonce, &msg.nonce, pending.role))
+    }
+
+    /// open_substreams returns the number of substreams open on the connection.
+    pub(crate) fn open_substreams(&self) -> usize {
+        self.open_substreams.lock().len()
+    }
+
+    /// pending_substreams returns the number of substreams we opened on the connection
+    /// that the remote hasn't answered yet.
+    pub(crate) fn pending_substreams(&self) -> usize {
+        self.pending_substreams.lock().len()
+    }
+
+    /// wants_acks returns true if the remote retransmits its messages until they're acknowledged.
+    pub(crate) fn wants_acks(&self) -> bool {
+        self.remote_capabilities.contains(Capabilities::RETRANSMIT)
+    }
+
+    /// migrate_message returns a Migrate telling the remote that we moved to `address`,
+    /// signed with the identity the connection was opened with. It returns None if the
+    /// connection can't follow us: the remote has to support migration and know our
+    /// address, and we have to know its own, since the SURBs it sent to our old
+    /// address won't reach us at the new one.
+    pub(crate) fn migrate_message(
+        &self,
+        address: &Recipient,
+        seq: u64,
+    ) -> Result<Option<OutboundMessage>, Error> {
+        let Some(local_key) = &self.local_key else {
+            return Ok(None);
+        };
+        if !self.exposes_address
+            || self.remote_recipient.is_none()
+            || !self.remote_capabilities.contains(Capabilities::MIGRATION)
+        {
+            return Ok(None);
+        }
+
+        let signature = sign_migration(local_key, &self.id, seq, address)?;
+        Ok(Some(OutboundMessage {
+            message: Message::Migrate(MigrateMessage {
+                id: self.id.clone(),
+                seq,
+                recipient: *address,
+                signature,
+            }),
+            recipient: self.remote_recipient,
+            sender_tag: None,
+        }))
+    }
+
+    /// ack_message returns an Ack for the TransportMessage with the given nonce.
+    pub(crate) fn ack_message(&self, nonce: Nonce) -> OutboundMessage {
+        OutboundMessage {
+            message: Message::Ack(AckMessage {
+                id: self.id.clone(),
+                nonce,
+            }),
+            recipient: self.remote_recipient,
+            sender_tag: self.sender_tag.clone(),
+        }
+    }
+
+    /// is_closed returns true once the connection has been dropped.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.inbound_tx.is_closed()
+    }
+
+    /// sender_tag returns the tag that replies on the connection are sent with, if any.
+    pub(crate) fn sender_tag(&self) -> Option<&AnonymousSenderTag> {
+        self.sender_tag.as_ref()
+    }
+
+    /// close_messages returns a Close message for every substream that's still open,
+    /// and marks them all as closed so that the substreams don't send a second Close.
+    /// If the connection's nonces have run out, the Closes are left unsent.
+    pub(crate) fn close_messages(&self) -> Vec<OutboundMessage> {
+        let mut open_substreams = self.open_substreams.lock();
+        open_substreams
+            .drain()
+            .map_while(|substream_id| {
+                Some(OutboundMessage {
+                    recipient: self.remote_recipient,
+                    message: Message::TransportMessage(TransportMessage {
+                        nonce: self.message_nonce.next().ok()?,
+                        id: self.id.clone(),
+                        message: SubstreamMessage::new_close(substream_id),
+                        timestamp: None,
+                    }),
+                    sender_tag: self.sender_tag.clone(),
+                })
+            })
+            .collect()
+    }
+}
 
 /// Connection represents the result of a connection setup process.
 /// It implements `StreamMuxer` and thus has stream multiplexing built in.
@@ -34,22 +344,44 @@ pub struct Connection {
     pub(crate) id: ConnectionId,
 
     /// receive inbound messages from the `InnerConnection`
-    pub(crate) inbound_rx: UnboundedReceiver<SubstreamMessage>,
+    pub(crate) inbound_rx: UnboundedReceiver<ConnectionEvent>,
+
+    /// substream ID -> time the outbound pending substream's OpenRequest was sent
+    /// the key is deleted when the response is received, or the request times out;
+    /// shared with the ConnectionHandle
+    pending_substreams: Arc<Mutex<HashMap<SubstreamId, Instant>>>,
 
-    /// substream ID -> outbound pending substream exists
-    /// the key is deleted when the response is received, or the request times out
-    pending_substreams: HashSet<SubstreamId>,
+    /// time a pending substream waits for its OpenResponse before it's failed
+    open_timeout: Duration,
+    /// ticks whenever pending substreams should be checked for timeouts
+    open_check: Interval,
 
-    /// substream ID -> substream's inbound_tx channel
-    substream_inbound_txs: HashMap<SubstreamId, UnboundedSender<Vec<u8>>>,
+    /// substream ID -> substream's inbound_tx channel; dropped once the remote
+    /// closes its half of the substream
+    substream_inbound_txs: HashMap<SubstreamId, UnboundedSender<Bytes>>,
+
+    /// substreams the remote has closed its half of, and we haven't
+    remote_closed: HashSet<SubstreamId>,
 
     /// substream ID -> substream's close_tx channel
-    substream_close_txs: HashMap<SubstreamId, oneshot::Sender<()>>,
+    substream_close_txs: HashMap<SubstreamId, oneshot::Sender<Option<Error>>>,
+
+    /// substream ID -> how far the remote allows the substream to write
+    substream_send_windows: HashMap<SubstreamId, Arc<Mutex<SendWindow>>>,
+
+    /// substream ID -> what the substream has been handed and not read yet
+    substream_receive_buffers: HashMap<SubstreamId, Arc<Mutex<ReceiveBuffer>>>,
+
+    /// number of unread bytes each substream lets the remote have in flight
+    receive_window: u32,
+
+    /// number of unread frames each substream holds before the remote is cut off
+    max_buffered_frames: usize,
 
     /// send messages to the mixnet
     /// used for sending `SubstreamMessageType::OpenRequest` messages
     /// also passed to each substream so they can write to the mixnet
-    pub(crate) mixnet_outbound_tx: UnboundedSender<OutboundMessage>,
+    pub(crate) mixnet_outbound_tx: BoundedSender<OutboundMessage>,
 
     /// sender_tag for SURB replies to incoming messages
     pub(crate) sender_tag: Option<AnonymousSenderTag>,
@@ -62,9 +394,67 @@ pub struct Connection {
     close_tx: UnboundedSender<SubstreamId>,
     close_rx: UnboundedReceiver<SubstreamId>,
 
-    /// message nonce contains the next nonce that should be used when
-    /// sending a message over the connection
-    pub(crate) message_nonce: Arc<AtomicU64>,
+    /// hands out the nonces of the messages sent over the connection;
+    /// shared with the substreams and the ConnectionHandle
+    pub(crate) message_nonce: NonceCounter,
+
+    /// IDs of substreams whose half we haven't closed;
+    /// shared with the substreams and the ConnectionHandle
+    open_substreams: Arc<Mutex<HashSet<SubstreamId>>>,
+
+    /// keepalive state; None if keepalives are disabled
+    keepalive: Option<KeepAlive>,
+
+    /// idle timeout state; None if idle connections are kept open
+    idle: Option<IdleTimeout>,
+
+    /// set once the remote has closed the connection, so that dropping it
+    /// doesn't send a ConnectionClose back
+    reset: bool,
+
+    /// maximum payload size of a single message written by our substreams
+    max_fragment_size: usize,
+
+    /// partially received fragmented payloads, per substream
+    reassembler: Reassembler,
+
+    /// encrypts our substreams' payloads and decrypts the remote's;
+    /// None if payloads are sent in plaintext
+    cipher: Option<Arc<SessionCipher>>,
+
+    /// compresses our substreams' payloads and decompresses the remote's;
+    /// None unless both peers enabled compression
+    compression: Option<Compression>,
+
+    /// whether OpenRequests and keepalive pings are stamped with the time they're
+    /// sent, so that the remote can drop them if they arrive too late
+    timestamps: bool,
+
+    /// whether the remote understands datagrams sent on the connection
+    remote_datagrams: bool,
+    /// datagrams received from the remote and not read yet; holds at most
+    /// `max_buffered_frames`, and drops any more that arrive
+    datagrams: VecDeque<Bytes>,
+    /// woken when a datagram arrives for the last caller of poll_datagram
+    datagram_waker: Option<Waker>,
+
+    metrics: Metrics,
+    events: EventSender,
+    /// round-trip time estimates, which the connection's keepalives add samples to
+    rtt: RttTable,
+    /// traffic statistics, which the mixnet task counts the connection's messages in
+    stats: StatsTable,
+    /// what the remote told us about itself when the connection was opened
+    remote_info: Arc<ConnectionInfo>,
+    /// the session ticket the remote issued us, and our pending ResumeRequest;
+    /// shared with the ConnectionHandle
+    resume: Arc<Mutex<ResumeState>>,
+    /// set while we wait for the remote to answer our ResumeRequest
+    resuming: bool,
+    /// woken once the connection is resumed, for the caller of poll_outbound
+    outbound_waker: Option<Waker>,
+    /// counts this connection in the active connections gauge while it's alive
+    _tracked: Tracked,
 
     waker: Option<Waker>,
 }
@@ -74,37 +464,432 @@ impl Connection {
         peer_id: PeerId,
         remote_recipient: Option<Recipient>,
         id: ConnectionId,
-        inbound_rx: UnboundedReceiver<SubstreamMessage>,
-        mixnet_outbound_tx: UnboundedSender<OutboundMessage>,
+        inbound_rx: UnboundedReceiver<ConnectionEvent>,
+        mixnet_outbound_tx: BoundedSender<OutboundMessage>,
         sender_tag: Option<AnonymousSenderTag>,
     ) -> Self {
         let (inbound_open_tx, inbound_open_rx) = unbounded_channel();
         let (close_tx, close_rx) = unbounded_channel();
+        let open_timeout = Duration::from_secs(DEFAULT_SUBSTREAM_OPEN_TIMEOUT_SECS);
 
         Connection {
             peer_id,
             remote_recipient,
             id,
             inbound_rx,
-            pending_substreams: HashSet::new(),
+            pending_substreams: Arc::new(Mutex::new(HashMap::new())),
+            open_timeout,
+            open_check: open_check_interval(open_timeout),
             substream_inbound_txs: HashMap::new(),
+            remote_closed: HashSet::new(),
             substream_close_txs: HashMap::new(),
+            substream_send_windows: HashMap::new(),
+            substream_receive_buffers: HashMap::new(),
+            receive_window: DEFAULT_RECEIVE_WINDOW,
+            max_buffered_frames: DEFAULT_MAX_BUFFERED_FRAMES,
             mixnet_outbound_tx,
             sender_tag,
             inbound_open_tx,
             inbound_open_rx,
             close_tx,
             close_rx,
-            message_nonce: Arc::new(AtomicU64::new(1)),
+            message_nonce: NonceCounter::new(),
+            open_substreams: Arc::new(Mutex::new(HashSet::new())),
+            keepalive: None,
+            idle: None,
+            reset: false,
+            max_fragment_size: DEFAULT_MAX_FRAGMENT_SIZE,
+            reassembler: Reassembler::new(Duration::from_secs(DEFAULT_REASSEMBLY_TIMEOUT_SECS)),
+            cipher: None,
+            compression: None,
+            timestamps: false,
+            remote_datagrams: false,
+            datagrams: VecDeque::new(),
+            datagram_waker: None,
+            metrics: Metrics::default(),
+            events: EventSender::default(),
+            rtt: RttTable::default(),
+            stats: StatsTable::default(),
+            remote_info: Arc::default(),
+            resume: Arc::default(),
+            resuming: false,
+            outbound_waker: None,
+            _tracked: Tracked::default(),
             waker: None,
         }
     }
 
+    /// Enable keepalives on the connection and return self.
+    /// A ping is sent every `interval`, and the connection fails once
+    /// `max_missed` pings in a row go unanswered.
+    pub(crate) fn with_keepalive(mut self, interval: Duration, max_missed: u32) -> Self {
+        let mut interval = interval_at(Instant::now() + interval, interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.keepalive = Some(KeepAlive {
+            interval,
+            missed: 0,
+            max_missed,
+            seq: 0,
+            sent_at: None,
+        });
+        self
+    }
+
+    /// Close the connection once it's gone `timeout` without open substreams and return self.
+    pub(crate) fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.set_idle_timeout(Some(timeout));
+        self
+    }
+
+    /// set_idle_timeout overrides the transport's idle timeout for this connection, e.g.
+    /// to keep a connection used by a long-lived protocol open while it's quiet. Once the
+    /// connection has gone `timeout` without open substreams, it fails with
+    /// [`Error::IdleTimeout`], and the remote is sent a ConnectionClose. Keepalives don't
+    /// count as activity. If None, the connection is kept open however long it's idle.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle = timeout.map(IdleTimeout::new);
+    }
+
+    /// Set the maximum payload size of a single message and the reassembly
+    /// timeout for fragmented payloads, and return self.
+    pub(crate) fn with_fragmentation(
+        mut self,
+        max_fragment_size: usize,
+        timeout: Duration,
+    ) -> Self {
+        self.max_fragment_size = max_fragment_size;
+        self.reassembler = Reassembler::new(timeout);
+        self
+    }
+
+    /// Set the time an outbound substream waits for the remote to answer its
+    /// OpenRequest and return self.
+    pub(crate) fn with_open_timeout(mut self, timeout: Duration) -> Self {
+        self.open_timeout = timeout;
+        self.open_check = open_check_interval(timeout);
+        self
+    }
+
+    /// Set the per-substream receive window advertised to the remote and return self.
+    pub(crate) fn with_receive_window(mut self, window: u32) -> Self {
+        self.receive_window = window;
+        self
+    }
+
+    /// Set the number of unread frames each substream holds and return self.
+    pub(crate) fn with_max_buffered_frames(mut self, frames: usize) -> Self {
+        self.max_buffered_frames = frames;
+        self
+    }
+
+    /// Encrypt substream payloads with the cipher agreed in the handshake and return self.
+    pub(crate) fn with_cipher(mut self, cipher: SessionCipher) -> Self {
+        self.cipher = Some(Arc::new(cipher));
+        self
+    }
+
+    /// Compress substream payloads larger than `threshold` bytes and return self.
+    pub(crate) fn with_compression(mut self, threshold: usize) -> Self {
+        self.compression = Some(Compression::new(threshold));
+        self
+    }
+
+    /// Stamp OpenRequests and keepalive pings with the time they're sent and return self.
+    pub(crate) fn with_timestamps(mut self) -> Self {
+        self.timestamps = true;
+        self
+    }
+
+    /// Allow datagrams to be sent to the remote, which understands them, and return self.
+    pub(crate) fn with_datagrams(mut self) -> Self {
+        self.remote_datagrams = true;
+        self
+    }
+
+    /// timestamp returns the time to stamp a control frame with, if the remote wants them.
+    fn timestamp(&self) -> Option<u64> {
+        self.timestamps.then(unix_millis)
+    }
+
+    /// Record the connection and its substreams in the given metrics and return self.
+    pub(crate) fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self._tracked = metrics.track_connection();
+        self.metrics = metrics;
+        self
+    }
+
+    /// Emit events for the connection's substreams and return self.
+    pub(crate) fn with_events(mut self, events: EventSender) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Record the round-trip times measured by keepalives in the given table and return self.
+    pub(crate) fn with_rtt(mut self, rtt: RttTable) -> Self {
+        self.rtt = rtt;
+        self
+    }
+
+    /// Count the connection's traffic in the given table and return self.
+    pub(crate) fn with_stats(mut self, stats: StatsTable) -> Self {
+        stats.register(&self.id);
+        self.stats = stats;
+        self
+    }
+
+    /// Set what the remote told us about itself and return self.
+    pub(crate) fn with_remote_info(mut self, info: ConnectionInfo) -> Self {
+        self.remote_info = Arc::new(info);
+        self
+    }
+
+    /// remote_info returns what the remote told us about itself when the connection
+    /// was opened: its agent version and the extensions it supports.
+    pub fn remote_info(&self) -> &ConnectionInfo {
+        &self.remote_info
+    }
+
+    /// stats returns what's been sent and received on the connection so far.
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            open_substreams: self.open_substreams.lock().len(),
+            rtt: self.rtt.smoothed(&self.id),
+            ..self.stats.connection(&self.id)
+        }
+    }
+
+    /// handle returns a ConnectionHandle which delivers events to this connection via `inbound_tx`.
+    pub(crate) fn handle(&self, inbound_tx: UnboundedSender<ConnectionEvent>) -> ConnectionHandle {
+        ConnectionHandle {
+            inbound_tx,
+            outbound_tx: self.mixnet_outbound_tx.clone(),
+            id: self.id.clone(),
+            remote_peer_id: self.peer_id,
+            remote_recipient: self.remote_recipient,
+            sender_tag: self.sender_tag.clone(),
+            message_nonce: self.message_nonce.clone(),
+            open_substreams: self.open_substreams.clone(),
+            pending_substreams: self.pending_substreams.clone(),
+            remote_capabilities: Capabilities::default(),
+            remote_max_message_size: DEFAULT_MAX_MESSAGE_SIZE as u32,
+            local_key: None,
+            remote_identity: None,
+            exposes_address: false,
+            remote_info: self.remote_info.clone(),
+            resume: self.resume.clone(),
+        }
+    }
+
+    fn send_keepalive(&self, keepalive_type: KeepAliveType, seq: u64) -> Result<(), Error> {
+        self.mixnet_outbound_tx
+            .try_send(OutboundMessage {
+                recipient: self.remote_recipient,
+                message: Message::KeepAlive(KeepAliveMessage {
+                    id: self.id.clone(),
+                    keepalive_type,
+                    seq,
+                    // a pong is worth having however late, since it still answers its ping
+                    timestamp: match keepalive_type {
+                        KeepAliveType::Ping => self.timestamp(),
+                        KeepAliveType::Pong => None,
+                    },
+                }),
+                sender_tag: self.sender_tag.clone(),
+            })
+            .map_err(|e| Error::OutboundSendFailure(e.to_string()))
+    }
+
+    fn handle_keepalive(&mut self, msg: KeepAliveMessage) -> Result<(), Error> {
+        match msg.keepalive_type {
+            KeepAliveType::Ping => {
+                debug!("answering keepalive ping {}", msg.seq);
+                self.send_keepalive(KeepAliveType::Pong, msg.seq)
+            }
+            KeepAliveType::Pong => {
+                if let Some(keepalive) = self.keepalive.as_mut() {
+                    if msg.seq == keepalive.seq {
+                        keepalive.missed = 0;
+                        if let Some(sent_at) = keepalive.sent_at.take() {
+                            self.observe_round_trip(sent_at.elapsed());
+                        }
+                    } else {
+                        debug!("ignoring stale keepalive pong {}", msg.seq);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// observe_round_trip adds a round-trip time measured by a keepalive to the
+    /// connection's estimate, and reports both.
+    fn observe_round_trip(&self, rtt: Duration) {
+        let smoothed = self.rtt.observe(&self.id, rtt);
+        self.metrics.observe_round_trip(rtt);
+        self.metrics.set_smoothed_round_trip(smoothed);
+        self.events.emit(NymEvent::RoundTrip {
+            peer_id: self.peer_id,
+            rtt,
+            smoothed,
+        });
+    }
+
+    /// poll_keepalive sends a ping whenever the keepalive interval elapses, and
+    /// fails the connection once too many pings in a row have gone unanswered,
+    /// unless it can be resumed instead.
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        loop {
+            let Some(keepalive) = self.keepalive.as_mut() else {
+                return Ok(());
+            };
+
+            if keepalive.interval.poll_tick(cx).is_pending() {
+                return Ok(());
+            }
+
+            if keepalive.missed >= keepalive.max_missed {
+                debug!(
+                    "connection {:?} missed {} keepalives",
+                    self.id, keepalive.missed
+                );
+                // a resumption that goes unanswered as long fails the connection too
+                if self.resuming || !self.start_resume()? {
+                    return Err(Error::KeepAliveTimeout);
+                }
+                continue;
+            }
+
+            keepalive.missed += 1;
+            // the remote doesn't know the connection until it's resumed
+            if self.resuming {
+                continue;
+            }
+            keepalive.seq = keepalive.seq.wrapping_add(1);
+            keepalive.sent_at = Some(Instant::now());
+            let seq = keepalive.seq;
+            self.send_keepalive(KeepAliveType::Ping, seq)?;
+        }
+    }
+
+    /// start_resume sends the remote a ResumeRequest with the session ticket it issued
+    /// us, e.g. because it restarted and lost the connection's state. It returns false
+    /// if the connection can't be resumed: the remote never issued us a ticket, or we
+    /// don't know its address, since any SURBs we sent it are gone with its state.
+    fn start_resume(&mut self) -> Result<bool, Error> {
+        let (Some(cipher), Some(recipient)) = (&self.cipher, self.remote_recipient) else {
+            return Ok(false);
+        };
+        let mut resume = self.resume.lock();
+        let Some(ticket) = resume.ticket.take() else {
+            return Ok(false);
+        };
+        let secret = cipher.resumption_secret().clone();
+        let nonce = new_resume_nonce();
+        let timestamp = unix_millis();
+        let proof = secret.request_proof(&self.id, &nonce, timestamp);
+        resume.pending = Some(PendingResume {
+            nonce,
+            secret,
+            role: cipher.role(),
+        });
+        drop(resume);
+
+        debug!("resuming connection {:?}", self.id);
+        // whatever the remote lost of the substreams can't be recovered
+        let substream_ids: Vec<SubstreamId> = self.substream_close_txs.keys().cloned().collect();
+        for substream_id in substream_ids {
+            self.remove_substream(substream_id, Some(Error::ConnectionResumed))?;
+        }
+        self.resuming = true;
+        if let Some(keepalive) = self.keepalive.as_mut() {
+            keepalive.missed = 0;
+        }
+
+        self.mixnet_outbound_tx
+            .try_send(OutboundMessage {
+                recipient: Some(recipient),
+                message: Message::ResumeRequest(ResumeRequestMessage {
+                    id: self.id.clone(),
+                    timestamp,
+                    nonce,
+                    proof,
+                    ticket,
+                }),
+                sender_tag: None,
+            })
+            .map_err(|e| Error::OutboundSendFailure(e.to_string()))?;
+        Ok(true)
+    }
+
+    /// poll_idle fails the connection once it's gone the idle timeout without open
+    /// substreams or substream messages from the remote.
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        let Some(idle) = self.idle.as_mut() else {
+            return Ok(());
+        };
+        if !self.open_substreams.lock().is_empty() {
+            idle.last_active = Instant::now();
+        }
+
+        let mut ticked = false;
+        while idle.check.poll_tick(cx).is_ready() {
+            ticked = true;
+        }
+        if ticked && idle.last_active.elapsed() >= idle.timeout {
+            debug!("connection {:?} idle for {:?}", self.id, idle.timeout);
+            return Err(Error::IdleTimeout);
+        }
+        Ok(())
+    }
+
+    /// poll_open_timeouts fails the outbound substreams whose OpenRequest hasn't been
+    /// answered within the open timeout, e.g. because it was lost in the mixnet.
+    fn poll_open_timeouts(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        let mut ticked = false;
+        while self.open_check.poll_tick(cx).is_ready() {
+            ticked = true;
+        }
+        if !ticked {
+            return Ok(());
+        }
+
+        let expired: Vec<SubstreamId> = self
+            .pending_substreams
+            .lock()
+            .iter()
+            .filter(|(_, sent_at)| sent_at.elapsed() >= self.open_timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for substream_id in expired {
+            debug!("substream {:?} open timed out", substream_id);
+            self.metrics.substream_open_timed_out();
+
+            // the remote may have opened the substream, with only its response lost,
+            // so tell it to close its end too
+            self.mixnet_outbound_tx
+                .try_send(OutboundMessage {
+                    recipient: self.remote_recipient,
+                    message: Message::TransportMessage(TransportMessage {
+                        nonce: self.message_nonce.next()?,
+                        id: self.id.clone(),
+                        message: SubstreamMessage::new_close(substream_id.clone()),
+                        timestamp: None,
+                    }),
+                    sender_tag: self.sender_tag.clone(),
+                })
+                .map_err(|e| Error::OutboundSendFailure(e.to_string()))?;
+
+            let reason = Error::SubstreamOpenTimeout(substream_id.clone());
+            self.remove_substream(substream_id, Some(reason))?;
+        }
+        Ok(())
+    }
+
     fn new_outbound_substream(&mut self) -> Result<Substream, Error> {
         debug!("new_outbound_substream called");
         let substream_id = SubstreamId::generate();
         debug!("Generated substream_id: {:?}", substream_id);
-        let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
+        let nonce = self.message_nonce.next()?;
         debug!("Using nonce {}", nonce);
         debug!("Connection sender_tag: {:?}", self.sender_tag);
         debug!(
@@ -119,49 +904,63 @@ impl Connection {
                 id: self.id.clone(),
                 message: SubstreamMessage {
                     substream_id: substream_id.clone(),
-                    message_type: SubstreamMessageType::OpenRequest,
+                    message_type: SubstreamMessageType::OpenRequest(self.receive_window),
                 },
+                timestamp: self.timestamp(),
             }),
             sender_tag: self.sender_tag.clone(), // None for dialer, Some(sender_tag) for receiver
         };
 
         debug!("Sending OpenRequest for substream: {:?}", substream_id);
         // Send the outbound message
-        self.mixnet_outbound_tx.send(outbound_msg).map_err(|e| {
-            debug!("Failed to send outbound message: {}", e);
-            Error::OutboundSendFailure(e.to_string())
-        })?;
+        self.mixnet_outbound_tx
+            .try_send(outbound_msg)
+            .map_err(|e| {
+                debug!("Failed to send outbound message: {}", e);
+                Error::OutboundSendFailure(e.to_string())
+            })?;
 
         debug!("Creating substream");
         // track pending outbound substreams
         // TODO we should probably lock this? storing map values should be atomic
-        let res = self.new_substream(substream_id.clone());
+        // the remote's window is known once its OpenResponse arrives
+        let res = self.new_substream(substream_id.clone(), DEFAULT_RECEIVE_WINDOW);
         if res.is_ok() {
             debug!("Adding to pending_substreams");
-            self.pending_substreams.insert(substream_id);
+            self.pending_substreams
+                .lock()
+                .insert(substream_id, Instant::now());
         } else {
             debug!("Failed to create substream: {:?}", res);
         }
         res
     }
 
-    // creates a new substream instance with the given ID.
-    fn new_substream(&mut self, id: SubstreamId) -> Result<Substream, Error> {
+    // creates a new substream instance with the given ID, which may initially
+    // write `send_window` bytes.
+    fn new_substream(&mut self, id: SubstreamId, send_window: u32) -> Result<Substream, Error> {
         // check we don't already have a substream with this ID
-        if self.substream_inbound_txs.contains_key(&id) {
+        if self.substream_close_txs.contains_key(&id) {
             return Err(Error::SubstreamIdExists(id));
         }
 
-        let (inbound_tx, inbound_rx) = unbounded_channel::<Vec<u8>>();
-        let (close_tx, close_rx) = oneshot::channel::<()>();
+        let (inbound_tx, inbound_rx) = unbounded_channel::<Bytes>();
+        let (close_tx, close_rx) = oneshot::channel::<Option<Error>>();
         self.substream_inbound_txs.insert(id.clone(), inbound_tx);
         self.substream_close_txs.insert(id.clone(), close_tx);
+        self.open_substreams.lock().insert(id.clone());
+        let send_window = Arc::new(Mutex::new(SendWindow::new(send_window as u64)));
+        self.substream_send_windows
+            .insert(id.clone(), send_window.clone());
+        let receive_buffer = Arc::new(Mutex::new(ReceiveBuffer::default()));
+        self.substream_receive_buffers
+            .insert(id.clone(), receive_buffer.clone());
 
         if let Some(waker) = self.waker.take() {
             waker.wake();
         }
 
-        Ok(Substream::new_with_sender_tag(
+        let substream = Substream::new_with_sender_tag(
             self.remote_recipient,
             self.id.clone(),
             id,
@@ -170,17 +969,224 @@ impl Connection {
             close_rx,
             self.message_nonce.clone(),
             self.sender_tag.clone(), // Pass the connection's SURB directly
-        ))
+            self.open_substreams.clone(),
+        )
+        .with_max_fragment_size(self.max_fragment_size)
+        .with_flow_control(send_window, self.receive_window)
+        .with_receive_buffer(receive_buffer)
+        .with_metrics(&self.metrics)
+        .with_events(&self.events, self.peer_id);
+
+        let substream = match self.compression {
+            Some(compression) => substream.with_compression(compression),
+            None => substream,
+        };
+        Ok(match &self.cipher {
+            Some(cipher) => substream.with_cipher(cipher.clone()),
+            None => substream,
+        })
+    }
+
+    /// send_datagram sends `payload` to the remote in a single message, outside of any
+    /// substream. See [`crate::datagram::DatagramExt::send_datagram`].
+    pub(crate) fn send_datagram(&self, payload: Bytes) -> Result<(), Error> {
+        if !self.remote_datagrams {
+            return Err(Error::DatagramsUnsupported);
+        }
+        let len = payload.len();
+        let sealed = self.seal(payload)?;
+        // datagrams aren't fragmented, since losing any fragment would lose the datagram
+        if len.max(sealed.len()) > self.max_fragment_size {
+            return Err(Error::MessageTooLarge(len));
+        }
+
+        self.mixnet_outbound_tx
+            .try_send(OutboundMessage {
+                recipient: self.remote_recipient,
+                message: Message::TransportMessage(TransportMessage {
+                    nonce: DATAGRAM_NONCE,
+                    id: self.id.clone(),
+                    message: SubstreamMessage::new_datagram(sealed),
+                    timestamp: None,
+                }),
+                sender_tag: self.sender_tag.clone(),
+            })
+            .map_err(|e| Error::OutboundSendFailure(e.to_string()))
+    }
+
+    /// poll_datagram returns the next datagram received from the remote.
+    /// See [`crate::datagram::DatagramExt::poll_datagram`].
+    pub(crate) fn poll_datagram(&mut self, cx: &mut Context<'_>) -> Poll<Bytes> {
+        if let Some(datagram) = self.datagrams.pop_front() {
+            return Poll::Ready(datagram);
+        }
+        self.datagram_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// deliver_datagram holds a datagram received from the remote until it's read,
+    /// dropping it if too many are already waiting.
+    fn deliver_datagram(&mut self, datagram: Bytes) {
+        if self.datagrams.len() >= self.max_buffered_frames {
+            debug!(
+                "dropping datagram on connection {:?}; too many unread",
+                self.id
+            );
+            self.events.emit(NymEvent::MessageDropped {
+                reason: DropReason::DatagramBufferFull,
+            });
+            return;
+        }
+        self.datagrams.push_back(datagram);
+        if let Some(waker) = self.datagram_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// seal compresses a payload we send outside of a substream, if the connection is
+    /// compressed, and then encrypts it, if the connection is encrypted.
+    fn seal(&self, payload: Bytes) -> Result<Bytes, Error> {
+        let payload = match &self.compression {
+            Some(compression) => compression.compress(&payload),
+            None => payload,
+        };
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&payload).map(Bytes::from),
+            None => Ok(payload),
+        }
+    }
+
+    /// open decrypts a payload received from the remote, if the connection is encrypted,
+    /// and then decompresses it, if the connection is compressed.
+    fn open(&self, payload: Bytes) -> Result<Bytes, Error> {
+        let payload = match &self.cipher {
+            Some(cipher) => cipher.decrypt(&payload).map(Bytes::from)?,
+            None => payload,
+        };
+        match &self.compression {
+            Some(compression) => compression.decompress(payload, self.receive_window as usize),
+            None => Ok(payload),
+        }
+    }
+
+    /// max_unread_bytes returns the number of unread bytes the remote may send each
+    /// substream.
+    fn max_unread_bytes(&self) -> u64 {
+        // until our OpenResponse arrives, the remote assumes the default window
+        self.receive_window.max(DEFAULT_RECEIVE_WINDOW) as u64
+    }
+
+    /// max_fragmented_len returns the longest payload that may be reassembled for the
+    /// given substream: what the remote may still send it on top of what it holds unread,
+    /// as it's sent, compressed and encrypted. It returns None if the substream isn't
+    /// open to read, so that fragments for it aren't buffered at all.
+    fn max_fragmented_len(&self, substream_id: &SubstreamId) -> Option<usize> {
+        let inbound_tx = self.substream_inbound_txs.get(substream_id)?;
+        if inbound_tx.is_closed() {
+            return None;
+        }
+        let unread = self
+            .substream_receive_buffers
+            .get(substream_id)?
+            .lock()
+            .bytes();
+        let mut overhead = 0;
+        if self.compression.is_some() {
+            overhead += COMPRESSION_OVERHEAD;
+        }
+        if self.cipher.is_some() {
+            overhead += CIPHER_OVERHEAD;
+        }
+        let remaining = self.max_unread_bytes().saturating_sub(unread);
+        Some(usize::try_from(remaining).unwrap_or(usize::MAX) + overhead)
     }
 
+    /// deliver hands data received on a substream to it, failing if the substream
+    /// already holds as much unread data as the remote may send it.
+    fn deliver(&mut self, substream_id: &SubstreamId, data: Bytes) -> Result<(), Error> {
+        // the remote may have closed its half, or the substream was never opened
+        let Some(inbound_tx) = self.substream_inbound_txs.get(substream_id) else {
+            debug!("dropping data for closed substream {:?}", substream_id);
+            return Ok(());
+        };
+        // the substream might have been dropped, in which case nobody reads the data
+        if inbound_tx.is_closed() {
+            return Ok(());
+        }
+        if let Some(buffer) = self.substream_receive_buffers.get(substream_id) {
+            if !buffer.lock().push(
+                data.len(),
+                self.max_unread_bytes(),
+                self.max_buffered_frames,
+            ) {
+                debug!("substream {:?} receive buffer exceeded", substream_id);
+                return Err(Error::ReceiveBufferExceeded(substream_id.clone()));
+            }
+        }
+        inbound_tx.send(data).ok();
+        Ok(())
+    }
+
+    /// handle_close handles the remote closing its half of a substream. The substream
+    /// reads what the remote sent before, and then EOF, and can still be written to
+    /// until we close our half too. Once both halves are closed, it's forgotten.
     fn handle_close(&mut self, substream_id: SubstreamId) -> Result<(), Error> {
+        if !self.open_substreams.lock().contains(&substream_id) {
+            return self.remove_substream(substream_id, None);
+        }
         if self.substream_inbound_txs.remove(&substream_id).is_none() {
             return Err(Error::SubstreamIdDoesNotExist(substream_id));
         }
+        self.reassembler.remove(&substream_id);
+        self.substream_receive_buffers.remove(&substream_id);
+        self.remote_closed.insert(substream_id);
+        Ok(())
+    }
+
+    /// forget_closed forgets the substreams the remote closed its half of, once we've
+    /// closed ours too.
+    fn forget_closed(&mut self) -> Result<(), Error> {
+        if self.remote_closed.is_empty() {
+            return Ok(());
+        }
+        let closed: Vec<SubstreamId> = {
+            let open_substreams = self.open_substreams.lock();
+            self.remote_closed
+                .iter()
+                .filter(|id| !open_substreams.contains(*id))
+                .cloned()
+                .collect()
+        };
+        for substream_id in closed {
+            self.remove_substream(substream_id, None)?;
+        }
+        Ok(())
+    }
 
-        // notify substream that it's closed
-        let close_tx = self.substream_close_txs.remove(&substream_id);
-        close_tx.unwrap().send(()).unwrap();
+    /// remove_substream forgets a closed substream, and closes it with the error it failed
+    /// with, if any.
+    fn remove_substream(
+        &mut self,
+        substream_id: SubstreamId,
+        reason: Option<Error>,
+    ) -> Result<(), Error> {
+        let Some(close_tx) = self.substream_close_txs.remove(&substream_id) else {
+            return Err(Error::SubstreamIdDoesNotExist(substream_id));
+        };
+        // notify substream that it's closed, before its inbound channel is dropped, so
+        // that a reader doesn't mistake the end of the channel for an EOF.
+        // the substream may have been dropped already
+        close_tx.send(reason).ok();
+        self.substream_inbound_txs.remove(&substream_id);
+        self.remote_closed.remove(&substream_id);
+        self.open_substreams.lock().remove(&substream_id);
+        self.pending_substreams.lock().remove(&substream_id);
+        self.reassembler.remove(&substream_id);
+        self.substream_receive_buffers.remove(&substream_id);
+        if let Some(send_window) = self.substream_send_windows.remove(&substream_id) {
+            // a writer waiting for the window finds the substream closed instead
+            send_window.lock().wake();
+        }
 
         // notify poll_close that the substream is closed
         self.close_tx
@@ -189,6 +1195,29 @@ impl Connection {
     }
 }
 
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.rtt.remove(&self.id);
+        self.stats.remove(&self.id);
+        if self.reset {
+            return;
+        }
+
+        // we can't wait for room in the outbound channel here, so if it's full the
+        // remote finds out when its keepalives go unanswered instead.
+        let close = OutboundMessage {
+            recipient: self.remote_recipient,
+            message: Message::ConnectionClose(ConnectionCloseMessage {
+                id: self.id.clone(),
+            }),
+            sender_tag: self.sender_tag.clone(),
+        };
+        if let Err(e) = self.mixnet_outbound_tx.try_send(close) {
+            debug!("failed to queue ConnectionClose on drop: {}", e);
+        }
+    }
+}
+
 impl StreamMuxer for Connection {
     type Substream = Substream;
     type Error = Error;
@@ -206,9 +1235,20 @@ impl StreamMuxer for Connection {
 
     fn poll_outbound(
         mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<Result<Self::Substream, Self::Error>> {
         debug!("poll_outbound called");
+        // the substream would be lost along with the remote's state
+        if self.resuming {
+            self.outbound_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        // wait for room in the outbound channel before sending the OpenRequest
+        if ready!(self.mixnet_outbound_tx.poll_ready(cx)).is_err() {
+            return Poll::Ready(Err(Error::OutboundSendFailure(
+                "outbound channel closed".to_string(),
+            )));
+        }
         let result = self.new_outbound_substream();
         debug!("poll_outbound result: {:?}", result.is_ok());
         Poll::Ready(result)
@@ -226,20 +1266,68 @@ impl StreamMuxer for Connection {
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
-        while let Poll::Ready(Some(msg)) = self.inbound_rx.poll_recv(cx) {
-            debug!(
-                "Connection poll received message type: {:?} for substream: {:?}",
-                msg.message_type, msg.substream_id
-            );
+        loop {
+            // an inbound OpenRequest is answered with an OpenResponse, so stop
+            // consuming inbound messages while the outbound channel is full.
+            if ready!(self.mixnet_outbound_tx.poll_ready(cx)).is_err() {
+                return Poll::Ready(Err(Error::OutboundSendFailure(
+                    "outbound channel closed".to_string(),
+                )));
+            }
+
+            let Poll::Ready(Some(event)) = self.inbound_rx.poll_recv(cx) else {
+                break;
+            };
+
+            let (msg, span) = match event {
+                ConnectionEvent::Substream(msg, span) => (msg, span),
+                ConnectionEvent::KeepAlive(msg) => {
+                    self.handle_keepalive(msg)?;
+                    continue;
+                }
+                ConnectionEvent::Failed(e) => {
+                    return Poll::Ready(Err(e));
+                }
+                ConnectionEvent::Reset => {
+                    debug!("connection {:?} reset by the remote", self.id);
+                    self.reset = true;
+                    let substream_ids: Vec<SubstreamId> =
+                        self.substream_close_txs.keys().cloned().collect();
+                    for substream_id in substream_ids {
+                        self.remove_substream(substream_id, Some(Error::ConnectionReset))?;
+                    }
+                    return Poll::Ready(Err(Error::ConnectionReset));
+                }
+                ConnectionEvent::Resumed(cipher) => {
+                    debug!("connection {:?} resumed", self.id);
+                    self.cipher = Some(Arc::new(cipher));
+                    self.message_nonce.reset();
+                    self.resuming = false;
+                    if let Some(keepalive) = self.keepalive.as_mut() {
+                        keepalive.missed = 0;
+                        keepalive.sent_at = None;
+                    }
+                    if let Some(waker) = self.outbound_waker.take() {
+                        waker.wake();
+                    }
+                    continue;
+                }
+            };
+
+            let _entered = span.enter();
+            debug!("connection received message");
+            if let Some(idle) = self.idle.as_mut() {
+                idle.last_active = Instant::now();
+            }
             match msg.message_type {
-                SubstreamMessageType::OpenRequest => {
+                SubstreamMessageType::OpenRequest(send_window) => {
                     debug!(
                         "Processing OpenRequest for substream: {:?}",
                         msg.substream_id
                     );
                     // create a new substream with the given ID
-                    let substream = self.new_substream(msg.substream_id.clone())?;
-                    let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
+                    let substream = self.new_substream(msg.substream_id.clone(), send_window)?;
+                    let nonce = self.message_nonce.next()?;
 
                     debug!("About to send OpenResponse with nonce: {}", nonce);
                     debug!("Using sender_tag: {:?}", self.sender_tag);
@@ -252,18 +1340,23 @@ impl StreamMuxer for Connection {
                             id: self.id.clone(),
                             message: SubstreamMessage {
                                 substream_id: msg.substream_id.clone(),
-                                message_type: SubstreamMessageType::OpenResponse,
+                                message_type: SubstreamMessageType::OpenResponse(
+                                    self.receive_window,
+                                ),
                             },
+                            timestamp: None,
                         }),
                         sender_tag: self.sender_tag.clone(),
                     };
 
                     debug!("Created OutboundMessage: {:?}", response_msg);
 
-                    self.mixnet_outbound_tx.send(response_msg).map_err(|e| {
-                        debug!("FAILED to send OpenResponse: {}", e);
-                        Error::OutboundSendFailure(e.to_string())
-                    })?;
+                    self.mixnet_outbound_tx
+                        .try_send(response_msg)
+                        .map_err(|e| {
+                            debug!("FAILED to send OpenResponse: {}", e);
+                            Error::OutboundSendFailure(e.to_string())
+                        })?;
                     debug!("Queued OpenResponse for mixnet");
 
                     // send the substream to our own channel to be returned in poll_inbound
@@ -273,36 +1366,87 @@ impl StreamMuxer for Connection {
 
                     debug!("new inbound substream: {:?}", &msg.substream_id);
                 }
-                SubstreamMessageType::OpenResponse => {
+                SubstreamMessageType::OpenResponse(send_window) => {
                     debug!(
                         "Processing OpenResponse for substream: {:?}",
                         msg.substream_id
                     );
-                    if !self.pending_substreams.remove(&msg.substream_id) {
+                    if let Some(window) = self.substream_send_windows.get(&msg.substream_id) {
+                        window.lock().set_limit(send_window as u64);
+                    }
+                    if self
+                        .pending_substreams
+                        .lock()
+                        .remove(&msg.substream_id)
+                        .is_none()
+                    {
                         debug!(
                             "SubstreamMessageType::OpenResponse no substream pending for ID: {:?}",
                             &msg.substream_id
                         );
                     }
                 }
+                SubstreamMessageType::WindowUpdate(limit) => {
+                    debug!(
+                        "Processing WindowUpdate {} for substream: {:?}",
+                        limit, msg.substream_id
+                    );
+                    if let Some(window) = self.substream_send_windows.get(&msg.substream_id) {
+                        window.lock().raise(limit);
+                    }
+                }
                 SubstreamMessageType::Close => {
                     debug!("Processing Close for substream: {:?}", msg.substream_id);
                     self.handle_close(msg.substream_id)?;
                 }
                 SubstreamMessageType::Data(data) => {
                     debug!("Processing Data: {:?}", &data);
-                    let inbound_tx = self
-                        .substream_inbound_txs
-                        .get_mut(&msg.substream_id)
-                        .expect("must have a substream channel for substream");
-
-                    // NOTE: this ignores channel closed errors, which is fine because the substream
-                    // might have been closed/dropped
-                    inbound_tx.send(data).ok();
+                    let data = self.open(data)?;
+                    self.deliver(&msg.substream_id, data)?;
+                }
+                SubstreamMessageType::Fragment(fragment) => {
+                    debug!(
+                        "Processing Fragment {}/{} of payload {}",
+                        fragment.index + 1,
+                        fragment.count,
+                        fragment.payload_id
+                    );
+                    // a payload is only reassembled for a substream that's open to read it
+                    let Some(max_len) = self.max_fragmented_len(&msg.substream_id) else {
+                        debug!(
+                            "dropping fragment for unknown substream {:?}",
+                            msg.substream_id
+                        );
+                        continue;
+                    };
+                    let Some(data) = self
+                        .reassembler
+                        .push(&msg.substream_id, fragment, max_len)?
+                    else {
+                        continue;
+                    };
+                    let data = self.open(data)?;
+                    self.deliver(&msg.substream_id, data)?;
+                }
+                SubstreamMessageType::Datagram(datagram) => {
+                    debug!("Processing Datagram of {} bytes", datagram.len());
+                    let datagram = self.open(datagram)?;
+                    self.deliver_datagram(datagram);
                 }
             }
         }
 
+        // our nonces are running out, so the connection has to be redialed before
+        // they repeat
+        if self.message_nonce.peek().needs_rehandshake() {
+            return Poll::Ready(Err(Error::NoncesExhausted));
+        }
+
+        self.forget_closed()?;
+        self.poll_keepalive(cx)?;
+        self.poll_open_timeouts(cx)?;
+        self.poll_idle(cx)?;
+
         self.waker = Some(cx.waker().clone());
         Poll::Pending
     }
@@ -310,35 +1454,62 @@ impl StreamMuxer for Connection {
 
 /// PendingConnection represents a connection that's been initiated, but not completed.
 pub(crate) struct PendingConnection {
-    pub(crate) remote_recipient: Recipient,
-    pub(crate) connection_tx: oneshot::Sender<Connection>,
+    /// None if the dial was sent through a reply route, to a remote whose address we don't know
+    pub(crate) remote_recipient: Option<Recipient>,
+    /// the peer ID given in the dialed multiaddr, if any
+    pub(crate) remote_peer_id: Option<PeerId>,
+    /// the identity we dialed with, and our half of the handshake, which is finished
+    /// once the ConnectionResponse arrives
+    pub(crate) local_key: Keypair,
+    pub(crate) handshake: Handshake,
+    pub(crate) connection_tx: oneshot::Sender<Result<Connection, Error>>,
+    /// the channel to the mixnet client the dial was sent through, which the
+    /// connection keeps using once it's established
+    pub(crate) outbound_tx: BoundedSender<OutboundMessage>,
 }
 
 impl PendingConnection {
     pub(crate) fn new(
-        remote_recipient: Recipient,
-        connection_tx: oneshot::Sender<Connection>,
+        remote_recipient: Option<Recipient>,
+        remote_peer_id: Option<PeerId>,
+        local_key: Keypair,
+        handshake: Handshake,
+        connection_tx: oneshot::Sender<Result<Connection, Error>>,
+        outbound_tx: BoundedSender<OutboundMessage>,
     ) -> Self {
         PendingConnection {
             remote_recipient,
+            remote_peer_id,
+            local_key,
+            handshake,
             connection_tx,
+            outbound_tx,
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::super::message::InboundMessage;
+    use super::super::channel::{bounded, BoundedReceiver};
+    use super::super::config::{NymTransportConfig, OverflowPolicy};
+    use super::super::datagram::DatagramRouter;
+    use super::super::events::EventSender;
+    use super::super::message::{fragment, InboundMessage};
+    use super::super::migration::MigrationTable;
     use super::super::mixnet::initialize_mixnet;
+    use super::super::nonce::REHANDSHAKE_MARGIN;
+    use super::super::relay::RelayTable;
+    use super::super::rtt::RttTable;
+    use super::super::stats::StatsTable;
+    use super::super::transport::connect_test_client;
     use super::*;
     use futures::future::poll_fn;
     use futures::{AsyncReadExt, AsyncWriteExt, FutureExt};
-    use nym_sdk::mixnet::MixnetClient;
 
     async fn inbound_receive_and_send(
         connection_id: ConnectionId,
-        mixnet_inbound_rx: &mut UnboundedReceiver<InboundMessage>,
-        inbound_tx: &UnboundedSender<SubstreamMessage>,
+        mixnet_inbound_rx: &mut BoundedReceiver<InboundMessage>,
+        inbound_tx: &UnboundedSender<ConnectionEvent>,
         expected_nonce: u64,
     ) {
         let recv_msg = mixnet_inbound_rx.recv().await.unwrap();
@@ -347,10 +1518,13 @@ mod test {
                 nonce,
                 id,
                 message: msg,
+                ..
             }) => {
-                assert_eq!(nonce, expected_nonce);
+                assert_eq!(nonce.get(), expected_nonce);
                 assert_eq!(id, connection_id);
-                inbound_tx.send(msg).unwrap();
+                inbound_tx
+                    .send(ConnectionEvent::Substream(msg, Span::none()))
+                    .unwrap();
             }
             _ => panic!("unexpected message"),
         }
@@ -358,14 +1532,42 @@ mod test {
 
     #[tokio::test]
     async fn test_connection_stream_muxer() {
-        let client = MixnetClient::connect_new().await.unwrap();
-        let (sender_address, mut sender_mixnet_inbound_rx, sender_outbound_tx) =
-            initialize_mixnet(client, None).await.unwrap();
+        let client = connect_test_client().await;
+        let (sender_address, mut sender_mixnet_inbound_rx, sender_outbound_tx, _sender_task) =
+            initialize_mixnet(
+                client,
+                None,
+                EventSender::default(),
+                DatagramRouter::default(),
+                RttTable::default(),
+                StatsTable::default(),
+                MigrationTable::default(),
+                RelayTable::default(),
+                &NymTransportConfig::default(),
+            )
+            .await
+            .unwrap();
 
-        let client2 = MixnetClient::connect_new().await.unwrap();
+        let client2 = connect_test_client().await;
 
-        let (recipient_address, mut recipient_mixnet_inbound_rx, recipient_outbound_tx) =
-            initialize_mixnet(client2, None).await.unwrap();
+        let (
+            recipient_address,
+            mut recipient_mixnet_inbound_rx,
+            recipient_outbound_tx,
+            _recipient_task,
+        ) = initialize_mixnet(
+            client2,
+            None,
+            EventSender::default(),
+            DatagramRouter::default(),
+            RttTable::default(),
+            StatsTable::default(),
+            MigrationTable::default(),
+            RelayTable::default(),
+            &NymTransportConfig::default(),
+        )
+        .await
+        .unwrap();
 
         let connection_id = ConnectionId::generate();
 
@@ -373,7 +1575,7 @@ mod test {
         let sender_peer_id = PeerId::random();
 
         // create the connections
-        let (sender_inbound_tx, sender_inbound_rx) = unbounded_channel::<SubstreamMessage>();
+        let (sender_inbound_tx, sender_inbound_rx) = unbounded_channel::<ConnectionEvent>();
         let mut sender_connection = Connection::new_with_sender_tag(
             recipient_peer_id,
             Some(recipient_address),
@@ -382,7 +1584,7 @@ mod test {
             sender_outbound_tx,
             None,
         );
-        let (recipient_inbound_tx, recipient_inbound_rx) = unbounded_channel::<SubstreamMessage>();
+        let (recipient_inbound_tx, recipient_inbound_rx) = unbounded_channel::<ConnectionEvent>();
         let mut recipient_connection = Connection::new_with_sender_tag(
             sender_peer_id,
             Some(sender_address),
@@ -396,8 +1598,9 @@ mod test {
         let mut sender_substream = sender_connection.new_outbound_substream().unwrap();
         assert!(sender_connection
             .pending_substreams
-            .contains(&sender_substream.substream_id));
-        assert_eq!(sender_connection.message_nonce.load(Ordering::SeqCst), 2);
+            .lock()
+            .contains_key(&sender_substream.substream_id));
+        assert_eq!(sender_connection.message_nonce.peek(), Nonce::new(2));
 
         // poll the recipient inbound stream; should receive the OpenRequest and create the substream
         inbound_receive_and_send(
@@ -408,7 +1611,7 @@ mod test {
         )
         .await;
         poll_fn(|cx| Pin::new(&mut recipient_connection).as_mut().poll(cx)).now_or_never();
-        assert_eq!(recipient_connection.message_nonce.load(Ordering::SeqCst), 2);
+        assert_eq!(recipient_connection.message_nonce.peek(), Nonce::new(2));
 
         // poll recipient's poll_inbound to receive the substream
         let maybe_recipient_substream = poll_fn(|cx| {
@@ -430,12 +1633,12 @@ mod test {
 
         // poll sender's poll_outbound to get the substream
         poll_fn(|cx| Pin::new(&mut sender_connection).as_mut().poll(cx)).now_or_never();
-        assert!(sender_connection.pending_substreams.is_empty());
+        assert!(sender_connection.pending_substreams.lock().is_empty());
 
         // finally, write message to the substream
         let data = b"hello world";
         sender_substream.write_all(data).await.unwrap();
-        assert_eq!(sender_connection.message_nonce.load(Ordering::SeqCst), 3);
+        assert_eq!(sender_connection.message_nonce.peek(), Nonce::new(3));
 
         // receive message from the mixnet, push to the recipient Connection inbound channel
         inbound_receive_and_send(
@@ -459,7 +1662,7 @@ mod test {
 
         // test closing the stream; assert the stream is closed on both sides
         sender_substream.close().await.unwrap();
-        assert_eq!(sender_connection.message_nonce.load(Ordering::SeqCst), 4);
+        assert_eq!(sender_connection.message_nonce.peek(), Nonce::new(4));
         inbound_receive_and_send(
             connection_id.clone(),
             &mut recipient_mixnet_inbound_rx,
@@ -468,4 +1671,631 @@ mod test {
         )
         .await;
     }
+
+    #[tokio::test]
+    async fn test_connection_substream_open_timeout() {
+        let (outbound_tx, mut outbound_rx) = bounded(16, OverflowPolicy::Backpressure);
+        let (_inbound_tx, inbound_rx) = unbounded_channel::<ConnectionEvent>();
+        let mut connection = Connection::new_with_sender_tag(
+            PeerId::random(),
+            None,
+            ConnectionId::generate(),
+            inbound_rx,
+            outbound_tx,
+            None,
+        )
+        .with_open_timeout(Duration::from_millis(200));
+
+        let mut substream = connection.new_outbound_substream().unwrap();
+        let substream_id = substream.substream_id.clone();
+        let msg = outbound_rx.recv().now_or_never().unwrap().unwrap();
+        let Message::TransportMessage(msg) = msg.message else {
+            panic!("expected Message::TransportMessage");
+        };
+        assert!(matches!(
+            msg.message.message_type,
+            SubstreamMessageType::OpenRequest(_)
+        ));
+
+        // the OpenResponse never arrives
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+        assert!(connection.pending_substreams.lock().is_empty());
+        assert!(!connection.substream_inbound_txs.contains_key(&substream_id));
+
+        // the remote is told to close its end, in case only the response was lost
+        let msg = outbound_rx.recv().now_or_never().unwrap().unwrap();
+        let Message::TransportMessage(msg) = msg.message else {
+            panic!("expected Message::TransportMessage");
+        };
+        assert_eq!(msg.message.substream_id, substream_id);
+        assert!(matches!(
+            msg.message.message_type,
+            SubstreamMessageType::Close
+        ));
+
+        // and the substream fails with the reason
+        let mut buf = [0u8; 8];
+        let err = substream.read(&mut buf).await.unwrap_err();
+        assert!(matches!(
+            err.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+            Some(Error::SubstreamOpenTimeout(id)) if *id == substream_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connection_close() {
+        let (outbound_tx, mut outbound_rx) = bounded(16, OverflowPolicy::Backpressure);
+        let new_connection = |outbound_tx| {
+            let (inbound_tx, inbound_rx) = unbounded_channel::<ConnectionEvent>();
+            let connection = Connection::new_with_sender_tag(
+                PeerId::random(),
+                None,
+                ConnectionId::generate(),
+                inbound_rx,
+                outbound_tx,
+                None,
+            );
+            (connection, inbound_tx)
+        };
+
+        // dropping a connection tells the remote
+        let (connection, _inbound_tx) = new_connection(outbound_tx.clone());
+        let connection_id = connection.id.clone();
+        drop(connection);
+        let msg = outbound_rx.recv().now_or_never().unwrap().unwrap();
+        assert!(matches!(
+            msg.message,
+            Message::ConnectionClose(ConnectionCloseMessage { id }) if id == connection_id
+        ));
+
+        // the remote closing the connection fails its open substreams
+        let (mut connection, inbound_tx) = new_connection(outbound_tx);
+        let mut substream = connection.new_outbound_substream().unwrap();
+        outbound_rx.recv().now_or_never().unwrap().unwrap();
+        inbound_tx.send(ConnectionEvent::Reset).unwrap();
+        let res = poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .unwrap();
+        assert!(matches!(res, Err(Error::ConnectionReset)));
+        assert!(connection.substream_inbound_txs.is_empty());
+
+        let mut buf = [0u8; 8];
+        let err = substream.read(&mut buf).await.unwrap_err();
+        assert!(matches!(
+            err.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+            Some(Error::ConnectionReset)
+        ));
+
+        // and isn't answered with a ConnectionClose of our own
+        drop(connection);
+        assert!(outbound_rx.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_connection_keepalive() {
+        let (outbound_tx, mut outbound_rx) = bounded(16, OverflowPolicy::Backpressure);
+        let (inbound_tx, inbound_rx) = unbounded_channel::<ConnectionEvent>();
+        let connection_id = ConnectionId::generate();
+        let mut connection = Connection::new_with_sender_tag(
+            PeerId::random(),
+            None,
+            connection_id.clone(),
+            inbound_rx,
+            outbound_tx,
+            None,
+        )
+        .with_keepalive(Duration::from_millis(50), 2);
+
+        let mut expect_keepalive = |keepalive_type: KeepAliveType| {
+            let msg = outbound_rx.recv().now_or_never().unwrap().unwrap();
+            match msg.message {
+                Message::KeepAlive(ka) => {
+                    assert_eq!(ka.keepalive_type, keepalive_type);
+                    ka.seq
+                }
+                _ => panic!("expected Message::KeepAlive"),
+            }
+        };
+
+        // pings from the remote are answered with a pong carrying the same seq
+        inbound_tx
+            .send(ConnectionEvent::KeepAlive(KeepAliveMessage {
+                id: connection_id.clone(),
+                keepalive_type: KeepAliveType::Ping,
+                seq: 42,
+                timestamp: None,
+            }))
+            .unwrap();
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+        assert_eq!(expect_keepalive(KeepAliveType::Pong), 42);
+
+        // an answered ping resets the missed count
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+        let seq = expect_keepalive(KeepAliveType::Ping);
+        inbound_tx
+            .send(ConnectionEvent::KeepAlive(KeepAliveMessage {
+                id: connection_id.clone(),
+                keepalive_type: KeepAliveType::Pong,
+                seq,
+                timestamp: None,
+            }))
+            .unwrap();
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+        assert_eq!(connection.keepalive.as_ref().unwrap().missed, 0);
+
+        // two unanswered pings, then the connection fails
+        for _ in 0..2 {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+                .now_or_never()
+                .is_none());
+            expect_keepalive(KeepAliveType::Ping);
+        }
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let res = poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .unwrap();
+        assert!(matches!(res, Err(Error::KeepAliveTimeout)));
+    }
+
+    #[tokio::test]
+    async fn test_connection_resume() {
+        let (outbound_tx, mut outbound_rx) = bounded(16, OverflowPolicy::Backpressure);
+        let (inbound_tx, inbound_rx) = unbounded_channel::<ConnectionEvent>();
+        let connection_id = ConnectionId::generate();
+        let address = Recipient::try_from_base58_string("D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN").unwrap();
+        let cipher = ResumptionSecret::from_bytes([1u8; 32]).resume(
+            &connection_id,
+            &new_resume_nonce(),
+            &new_resume_nonce(),
+            Role::Dialer,
+        );
+        let secret = cipher.resumption_secret().clone();
+        let mut connection = Connection::new_with_sender_tag(
+            PeerId::random(),
+            Some(address),
+            connection_id.clone(),
+            inbound_rx,
+            outbound_tx,
+            None,
+        )
+        .with_keepalive(Duration::from_millis(50), 1)
+        .with_cipher(cipher);
+        let handle = connection.handle(inbound_tx.clone());
+        handle.set_session_ticket(b"ticket".to_vec());
+
+        let mut substream = connection.new_outbound_substream().unwrap();
+        outbound_rx.recv().now_or_never().unwrap().unwrap();
+
+        // one unanswered ping, then the connection is resumed rather than failed
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+        let msg = outbound_rx.recv().now_or_never().unwrap().unwrap();
+        assert!(matches!(msg.message, Message::KeepAlive(_)));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+        let msg = outbound_rx.recv().now_or_never().unwrap().unwrap();
+        assert_eq!(msg.recipient, Some(address));
+        let Message::ResumeRequest(request) = msg.message else {
+            panic!("expected Message::ResumeRequest");
+        };
+        assert_eq!(request.ticket, b"ticket");
+        assert!(proofs_match(
+            &secret.request_proof(&connection_id, &request.nonce, request.timestamp),
+            &request.proof
+        ));
+
+        // the open substreams fail, and no new ones open until the remote answers
+        let mut buf = [0u8; 8];
+        let err = substream.read(&mut buf).await.unwrap_err();
+        assert!(matches!(
+            err.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+            Some(Error::ConnectionResumed)
+        ));
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll_outbound(cx))
+            .now_or_never()
+            .is_none());
+
+        // a response without the right proof is ignored
+        let nonce = new_resume_nonce();
+        let mut response = ResumeResponseMessage {
+            id: connection_id.clone(),
+            nonce,
+            proof: [0u8; 32],
+        };
+        assert!(matches!(
+            handle.finish_resume(&response),
+            Err(Error::InvalidResumptionProof)
+        ));
+        response.proof = secret.response_proof(&connection_id, &request.nonce, &nonce);
+        let cipher = handle.finish_resume(&response).unwrap();
+        assert!(matches!(
+            handle.finish_resume(&response),
+            Err(Error::NoConnectionForResumeResponse)
+        ));
+
+        // once resumed, the connection's nonces start over
+        inbound_tx.send(ConnectionEvent::Resumed(cipher)).unwrap();
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+        assert_eq!(connection.message_nonce.peek(), Nonce::FIRST);
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll_outbound(cx))
+            .now_or_never()
+            .unwrap()
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connection_idle_timeout() {
+        let (outbound_tx, mut outbound_rx) = bounded(16, OverflowPolicy::Backpressure);
+        let new_connection = |outbound_tx| {
+            let (inbound_tx, inbound_rx) = unbounded_channel::<ConnectionEvent>();
+            let connection = Connection::new_with_sender_tag(
+                PeerId::random(),
+                None,
+                ConnectionId::generate(),
+                inbound_rx,
+                outbound_tx,
+                None,
+            )
+            .with_idle_timeout(Duration::from_millis(200));
+            (connection, inbound_tx)
+        };
+
+        // a connection with an open substream isn't idle
+        let (mut connection, inbound_tx) = new_connection(outbound_tx.clone());
+        let mut substream = connection.new_outbound_substream().unwrap();
+        outbound_rx.recv().now_or_never().unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+
+        // nor is one with a substream only the remote has closed its half of
+        inbound_tx
+            .send(ConnectionEvent::Substream(
+                SubstreamMessage::new_close(substream.substream_id.clone()),
+                Span::none(),
+            ))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+
+        // once we close ours too, the connection fails after the idle timeout
+        substream.close().await.unwrap();
+        outbound_rx.recv().now_or_never().unwrap().unwrap();
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let res = poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .unwrap();
+        assert!(matches!(res, Err(Error::IdleTimeout)));
+
+        // and the remote is told when the swarm drops it
+        let connection_id = connection.id.clone();
+        drop(connection);
+        let msg = outbound_rx.recv().now_or_never().unwrap().unwrap();
+        assert!(matches!(
+            msg.message,
+            Message::ConnectionClose(ConnectionCloseMessage { id }) if id == connection_id
+        ));
+
+        // a connection whose idle timeout is overridden is kept open
+        let (mut connection, _inbound_tx) = new_connection(outbound_tx);
+        connection.set_idle_timeout(None);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_connection_substream_half_close() {
+        let (outbound_tx, mut outbound_rx) = bounded(16, OverflowPolicy::Backpressure);
+        let (inbound_tx, inbound_rx) = unbounded_channel::<ConnectionEvent>();
+        let mut connection = Connection::new_with_sender_tag(
+            PeerId::random(),
+            None,
+            ConnectionId::generate(),
+            inbound_rx,
+            outbound_tx,
+            None,
+        );
+        let mut substream = connection.new_outbound_substream().unwrap();
+        let substream_id = substream.substream_id.clone();
+        outbound_rx.recv().now_or_never().unwrap().unwrap();
+        let send = |message_type| {
+            inbound_tx
+                .send(ConnectionEvent::Substream(
+                    SubstreamMessage {
+                        substream_id: substream_id.clone(),
+                        message_type,
+                    },
+                    Span::none(),
+                ))
+                .unwrap();
+        };
+
+        // the remote answers, and closes its half, as a request-response responder does
+        send(SubstreamMessageType::Data(Bytes::from_static(b"hello")));
+        send(SubstreamMessageType::Close);
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+
+        // which we read to EOF, and can still write after
+        let mut data = vec![];
+        substream.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"hello");
+        substream.write_all(b"world").await.unwrap();
+        let msg = outbound_rx.recv().now_or_never().unwrap().unwrap();
+        let Message::TransportMessage(msg) = msg.message else {
+            panic!("expected Message::TransportMessage");
+        };
+        assert!(matches!(
+            msg.message.message_type,
+            SubstreamMessageType::Data(_)
+        ));
+
+        // the substream is forgotten once we close our half too
+        substream.close().await.unwrap();
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+        assert!(connection.substream_close_txs.is_empty());
+        assert!(connection.remote_closed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connection_nonces_exhausted() {
+        let (outbound_tx, _outbound_rx) = bounded(16, OverflowPolicy::Backpressure);
+        let (_inbound_tx, inbound_rx) = unbounded_channel::<ConnectionEvent>();
+        let mut connection = Connection::new_with_sender_tag(
+            PeerId::random(),
+            None,
+            ConnectionId::generate(),
+            inbound_rx,
+            outbound_tx,
+            None,
+        );
+        connection.message_nonce =
+            NonceCounter::starting_at(Nonce::new(u64::MAX - REHANDSHAKE_MARGIN - 1));
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+
+        // the connection fails once it's within the margin of its last nonce,
+        // rather than waiting for its nonces to wrap around
+        let _substream = connection.new_outbound_substream().unwrap();
+        let res = poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .unwrap();
+        assert!(matches!(res, Err(Error::NoncesExhausted)));
+    }
+
+    #[tokio::test]
+    async fn test_connection_receive_buffer_limit() {
+        let (outbound_tx, _outbound_rx) = bounded(16, OverflowPolicy::Backpressure);
+        let (inbound_tx, inbound_rx) = unbounded_channel::<ConnectionEvent>();
+        let mut connection = Connection::new_with_sender_tag(
+            PeerId::random(),
+            None,
+            ConnectionId::generate(),
+            inbound_rx,
+            outbound_tx,
+            None,
+        )
+        .with_max_buffered_frames(2);
+        let substream_id = SubstreamId::generate();
+        let send = |message_type| {
+            inbound_tx
+                .send(ConnectionEvent::Substream(
+                    SubstreamMessage {
+                        substream_id: substream_id.clone(),
+                        message_type,
+                    },
+                    Span::none(),
+                ))
+                .unwrap();
+        };
+        let data = || SubstreamMessageType::Data(Bytes::from_static(b"hello"));
+
+        send(SubstreamMessageType::OpenRequest(DEFAULT_RECEIVE_WINDOW));
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+        let mut substream = poll_fn(|cx| Pin::new(&mut connection).poll_inbound(cx))
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        // frames up to the limit are held for the application
+        send(data());
+        send(data());
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+
+        // reading one makes room for another
+        let mut buf = [0u8; 5];
+        substream.read_exact(&mut buf).await.unwrap();
+        send(data());
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+
+        // and a remote that sends more while nothing is read is cut off
+        send(data());
+        let res = poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .unwrap();
+        assert!(matches!(
+            res,
+            Err(Error::ReceiveBufferExceeded(id)) if id == substream_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connection_fragment_limit() {
+        let (outbound_tx, _outbound_rx) = bounded(16, OverflowPolicy::Backpressure);
+        let (inbound_tx, inbound_rx) = unbounded_channel::<ConnectionEvent>();
+        let mut connection = Connection::new_with_sender_tag(
+            PeerId::random(),
+            None,
+            ConnectionId::generate(),
+            inbound_rx,
+            outbound_tx,
+            None,
+        );
+        let send = |substream_id: &SubstreamId, message_type| {
+            inbound_tx
+                .send(ConnectionEvent::Substream(
+                    SubstreamMessage {
+                        substream_id: substream_id.clone(),
+                        message_type,
+                    },
+                    Span::none(),
+                ))
+                .unwrap();
+        };
+        let window = DEFAULT_RECEIVE_WINDOW as usize;
+        let payload = Bytes::from(vec![7u8; window]);
+
+        // fragments for a substream that was never opened aren't buffered
+        let unknown = SubstreamId::generate();
+        let fragments = fragment(0, &payload, 1024).unwrap();
+        send(
+            &unknown,
+            SubstreamMessageType::Fragment(fragments[0].clone()),
+        );
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+        assert_eq!(connection.reassembler.buffered(&unknown), 0);
+
+        let substream_id = SubstreamId::generate();
+        send(
+            &substream_id,
+            SubstreamMessageType::OpenRequest(DEFAULT_RECEIVE_WINDOW),
+        );
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+        let mut substream = poll_fn(|cx| Pin::new(&mut connection).poll_inbound(cx))
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        // a payload the size of the window is reassembled
+        for fragment in fragments {
+            send(&substream_id, SubstreamMessageType::Fragment(fragment));
+        }
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+        let mut buf = vec![0u8; window];
+        substream.read_exact(&mut buf).await.unwrap();
+
+        // but once the window is partly taken by unread data, a payload that doesn't fit
+        // what's left cuts the remote off before it's all buffered
+        send(
+            &substream_id,
+            SubstreamMessageType::Data(Bytes::from_static(b"hello")),
+        );
+        for fragment in fragment(1, &payload, 1024).unwrap() {
+            send(&substream_id, SubstreamMessageType::Fragment(fragment));
+        }
+        let res = poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .unwrap();
+        assert!(matches!(
+            res,
+            Err(Error::ReceiveBufferExceeded(id)) if id == substream_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connection_datagram() {
+        let (outbound_tx, mut outbound_rx) = bounded(16, OverflowPolicy::Backpressure);
+        let (inbound_tx, inbound_rx) = unbounded_channel::<ConnectionEvent>();
+        let mut connection = Connection::new_with_sender_tag(
+            PeerId::random(),
+            None,
+            ConnectionId::generate(),
+            inbound_rx,
+            outbound_tx,
+            None,
+        )
+        .with_max_buffered_frames(2);
+
+        // a remote that doesn't understand datagrams isn't sent any
+        let payload = Bytes::from_static(b"heartbeat");
+        assert!(matches!(
+            connection.send_datagram(payload.clone()),
+            Err(Error::DatagramsUnsupported)
+        ));
+        let mut connection = connection.with_datagrams();
+
+        // a datagram is sent outside of the connection's nonce sequence
+        connection.send_datagram(payload.clone()).unwrap();
+        let msg = outbound_rx.recv().now_or_never().unwrap().unwrap();
+        let Message::TransportMessage(msg) = msg.message else {
+            panic!("expected Message::TransportMessage");
+        };
+        assert_eq!(msg.nonce, DATAGRAM_NONCE);
+        assert_eq!(connection.message_nonce.peek(), Nonce::new(1));
+        assert!(matches!(
+            &msg.message.message_type,
+            SubstreamMessageType::Datagram(data) if *data == payload
+        ));
+
+        // and one that doesn't fit in a single message isn't sent at all
+        let large = Bytes::from(vec![0u8; DEFAULT_MAX_FRAGMENT_SIZE + 1]);
+        assert!(matches!(
+            connection.send_datagram(large),
+            Err(Error::MessageTooLarge(_))
+        ));
+
+        // received datagrams are held until they're read, up to the buffer limit
+        for _ in 0..3 {
+            inbound_tx
+                .send(ConnectionEvent::Substream(
+                    msg.message.clone(),
+                    Span::none(),
+                ))
+                .unwrap();
+        }
+        assert!(poll_fn(|cx| Pin::new(&mut connection).poll(cx))
+            .now_or_never()
+            .is_none());
+        assert_eq!(connection.datagrams.len(), 2);
+        for _ in 0..2 {
+            let datagram = poll_fn(|cx| connection.poll_datagram(cx))
+                .now_or_never()
+                .unwrap();
+            assert_eq!(datagram, payload);
+        }
+        assert!(poll_fn(|cx| connection.poll_datagram(cx))
+            .now_or_never()
+            .is_none());
+    }
 }
@@ -0,0 +1,346 @@
+use bytes::Bytes;
+use futures::{ready, Stream};
+use libp2p::core::multiaddr::Multiaddr;
+use log::{debug, warn};
+use nym_sdk::mixnet::AnonymousSenderTag;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+
+use super::address::NymMultiaddr;
+use super::channel::BoundedSender;
+use super::config::DEFAULT_MAX_MESSAGE_SIZE;
+use super::connection::Connection;
+use super::error::Error;
+use super::message::{
+    max_datagram_payload, ConnectionId, DatagramKind, DatagramMessage, Message, OutboundMessage,
+};
+use super::runtime::timeout;
+
+/// request ID -> requester waiting for the response
+type PendingRequests = Arc<Mutex<HashMap<ConnectionId, oneshot::Sender<Bytes>>>>;
+
+/// inbound requests, along with the sender tag to respond to
+pub(crate) type RequestReceiver = mpsc::Receiver<(DatagramMessage, AnonymousSenderTag)>;
+
+/// DatagramRouter hands inbound datagrams from the mixnet task to the
+/// [`DatagramClient`] waiting on the response, or to the [`DatagramRequests`] stream.
+/// The default router has no stream, and drops every inbound request.
+#[derive(Clone, Default)]
+pub(crate) struct DatagramRouter {
+    pending: PendingRequests,
+    requests_tx: Option<mpsc::Sender<(DatagramMessage, AnonymousSenderTag)>>,
+}
+
+impl DatagramRouter {
+    /// new returns a router which buffers up to `capacity` inbound requests, along with
+    /// the receiver they're read from (see [`DatagramRequests::new`]).
+    pub(crate) fn new(capacity: usize) -> (Self, RequestReceiver) {
+        let (requests_tx, requests_rx) = mpsc::channel(capacity);
+        let router = DatagramRouter {
+            pending: PendingRequests::default(),
+            requests_tx: Some(requests_tx),
+        };
+        (router, requests_rx)
+    }
+
+    /// client returns a client whose responses are routed by this router.
+    pub(crate) fn client(
+        &self,
+        outbound_tx: BoundedSender<OutboundMessage>,
+        timeout: Duration,
+    ) -> DatagramClient {
+        DatagramClient {
+            outbound_tx,
+            pending: self.pending.clone(),
+            timeout,
+        }
+    }
+
+    /// route delivers an inbound datagram: a response to the request waiting on it, and
+    /// a request to the DatagramRequests stream. It returns false if a request was dropped
+    /// because the stream has fallen too far behind.
+    pub(crate) fn route(
+        &self,
+        msg: DatagramMessage,
+        sender_tag: Option<AnonymousSenderTag>,
+    ) -> bool {
+        match msg.kind {
+            DatagramKind::Response => {
+                let response_tx = self.pending.lock().remove(&msg.id);
+                match response_tx {
+                    // the requester may have given up since
+                    Some(response_tx) => {
+                        response_tx.send(msg.payload).ok();
+                    }
+                    None => {
+                        debug!(
+                            "ignoring datagram response for unknown request {:?}",
+                            msg.id
+                        )
+                    }
+                }
+                true
+            }
+            DatagramKind::Request => {
+                let Some(sender_tag) = sender_tag else {
+                    // requests are always sent with SURBs, which are the only way to respond
+                    debug!("ignoring datagram request {:?} without SURBs", msg.id);
+                    return true;
+                };
+                let Some(requests_tx) = &self.requests_tx else {
+                    debug!("ignoring datagram request {:?}; not listening", msg.id);
+                    return true;
+                };
+
+                match requests_tx.try_send((msg, sender_tag)) {
+                    Ok(()) => true,
+                    Err(mpsc::error::TrySendError::Full((msg, _))) => {
+                        warn!("dropping datagram request {:?}; too many queued", msg.id);
+                        false
+                    }
+                    // the DatagramRequests stream was dropped
+                    Err(mpsc::error::TrySendError::Closed(_)) => true,
+                }
+            }
+        }
+    }
+}
+
+/// DatagramClient sends requests to a nym address, each in a single mixnet message
+/// outside of any connection, and waits for the response to come back the same way.
+/// This saves the round trips of opening a connection and substream, which suit
+/// request-response protocols poorly. Datagrams are sent once: if the request or
+/// its response is lost, the request times out and may be retried by the caller.
+#[derive(Clone)]
+pub struct DatagramClient {
+    outbound_tx: BoundedSender<OutboundMessage>,
+    pending: PendingRequests,
+    timeout: Duration,
+}
+
+impl DatagramClient {
+    /// request sends `payload` to the nym address in `addr` and waits for the response.
+    /// The remote answers using the SURBs sent with the request, so it doesn't learn
+    /// our nym address. It fails with [`Error::MessageTooLarge`] if the payload wouldn't
+    /// fit in a message of the default maximum size, which is all a remote we have no
+    /// connection to can be assumed to accept.
+    pub async fn request(
+        &self,
+        addr: &Multiaddr,
+        payload: impl Into<Bytes>,
+    ) -> Result<Bytes, Error> {
+        let recipient = NymMultiaddr::try_from(addr)?.recipient;
+        let payload = payload.into();
+        if payload.len() > max_datagram_payload(DEFAULT_MAX_MESSAGE_SIZE) {
+            return Err(Error::MessageTooLarge(payload.len()));
+        }
+        let id = ConnectionId::generate();
+        let (response_tx, response_rx) = oneshot::channel();
+        self.pending.lock().insert(id.clone(), response_tx);
+        let _pending = RemoveOnDrop {
+            pending: &self.pending,
+            id: &id,
+        };
+
+        self.outbound_tx
+            .send(OutboundMessage {
+                message: Message::Datagram(DatagramMessage {
+                    id: id.clone(),
+                    kind: DatagramKind::Request,
+                    payload,
+                }),
+                recipient: Some(recipient),
+                sender_tag: None,
+            })
+            .await
+            .map_err(|e| Error::OutboundSendFailure(e.to_string()))?;
+
+        timeout(self.timeout, response_rx)
+            .await
+            .map_err(|_| Error::DatagramTimeout)?
+            .map_err(Error::OneshotRecvFailure)
+    }
+}
+
+/// RemoveOnDrop stops waiting for the response to a request once the request is
+/// answered, times out or is cancelled.
+struct RemoveOnDrop<'a> {
+    pending: &'a PendingRequests,
+    id: &'a ConnectionId,
+}
+
+impl Drop for RemoveOnDrop<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().remove(self.id);
+    }
+}
+
+/// DatagramRequests is a stream of the datagram requests sent to us by
+/// [`DatagramClient`]s, each of which should be answered with
+/// [`InboundRequest::respond`].
+pub struct DatagramRequests {
+    requests_rx: RequestReceiver,
+    outbound_tx: BoundedSender<OutboundMessage>,
+}
+
+impl DatagramRequests {
+    /// new returns a stream of the requests from `requests_rx`, whose responses
+    /// are sent to `outbound_tx`.
+    pub(crate) fn new(
+        requests_rx: RequestReceiver,
+        outbound_tx: BoundedSender<OutboundMessage>,
+    ) -> Self {
+        DatagramRequests {
+            requests_rx,
+            outbound_tx,
+        }
+    }
+}
+
+impl Stream for DatagramRequests {
+    type Item = InboundRequest;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Some((msg, sender_tag)) = ready!(self.requests_rx.poll_recv(cx)) else {
+            return Poll::Ready(None);
+        };
+        Poll::Ready(Some(InboundRequest {
+            payload: msg.payload,
+            id: msg.id,
+            sender_tag,
+            outbound_tx: self.outbound_tx.clone(),
+        }))
+    }
+}
+
+/// InboundRequest is a datagram request waiting for its response.
+pub struct InboundRequest {
+    /// the request, as passed to [`DatagramClient::request`].
+    pub payload: Bytes,
+    id: ConnectionId,
+    sender_tag: AnonymousSenderTag,
+    outbound_tx: BoundedSender<OutboundMessage>,
+}
+
+impl InboundRequest {
+    /// respond sends the response to the requester, using one of the SURBs it sent.
+    pub async fn respond(self, payload: impl Into<Bytes>) -> Result<(), Error> {
+        self.outbound_tx
+            .send(OutboundMessage {
+                message: Message::Datagram(DatagramMessage {
+                    id: self.id,
+                    kind: DatagramKind::Response,
+                    payload: payload.into(),
+                }),
+                recipient: None,
+                sender_tag: Some(self.sender_tag),
+            })
+            .await
+            .map_err(|e| Error::OutboundSendFailure(e.to_string()))
+    }
+}
+
+/// DatagramExt sends and receives datagrams on an established connection, for
+/// protocols such as gossip heartbeats that need neither ordering nor delivery. A
+/// datagram is sent in a single message outside of any substream: it's encrypted
+/// like substream data, but isn't acknowledged, retransmitted or held back until
+/// the messages before it arrive, and isn't counted against any receive window.
+/// It may be lost, or arrive out of order with the connection's other messages.
+pub trait DatagramExt {
+    /// send_datagram sends `payload` to the remote. It fails with
+    /// [`Error::DatagramsUnsupported`] if the remote doesn't understand datagrams,
+    /// with [`Error::MessageTooLarge`] if the payload doesn't fit in a single message,
+    /// and rather than waiting, if the outbound channel to the mixnet is full.
+    fn send_datagram(&mut self, payload: Bytes) -> Result<(), Error>;
+
+    /// poll_datagram returns the next datagram received from the remote. Datagrams
+    /// arrive while the connection is polled as a `StreamMuxer`, and once as many as
+    /// the connection buffers are unread, any more are dropped.
+    fn poll_datagram(&mut self, cx: &mut Context<'_>) -> Poll<Bytes>;
+}
+
+impl DatagramExt for Connection {
+    fn send_datagram(&mut self, payload: Bytes) -> Result<(), Error> {
+        Connection::send_datagram(self, payload)
+    }
+
+    fn poll_datagram(&mut self, cx: &mut Context<'_>) -> Poll<Bytes> {
+        Connection::poll_datagram(self, cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::channel::bounded;
+    use super::super::config::OverflowPolicy;
+    use super::*;
+    use futures::{FutureExt, StreamExt};
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_datagram_router() {
+        let (outbound_tx, mut outbound_rx) = bounded(16, OverflowPolicy::Backpressure);
+        let (router, requests_rx) = DatagramRouter::new(1);
+        let mut requests = DatagramRequests::new(requests_rx, outbound_tx.clone());
+        let client = router.client(outbound_tx, Duration::from_secs(60));
+        let addr = Multiaddr::from_str(
+            "/nym/Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR",
+        )
+        .unwrap();
+
+        // the request is sent to the mixnet, and waits for the response to be routed
+        let mut response = Box::pin(client.request(&addr, vec![1, 2, 3]));
+        assert!((&mut response).now_or_never().is_none());
+        let request = outbound_rx.recv().await.unwrap();
+        assert!(request.recipient.is_some());
+        let Message::Datagram(request) = request.message else {
+            panic!("expected Message::Datagram");
+        };
+        assert_eq!(request.kind, DatagramKind::Request);
+        assert_eq!(request.payload, vec![1, 2, 3]);
+
+        assert!(router.route(
+            DatagramMessage {
+                id: request.id.clone(),
+                kind: DatagramKind::Response,
+                payload: Bytes::from_static(&[4, 5, 6]),
+            },
+            None,
+        ));
+        assert_eq!(response.await.unwrap(), vec![4, 5, 6]);
+        assert!(router.pending.lock().is_empty());
+
+        // inbound requests go to the stream, and are dropped once it's full
+        let sender_tag = AnonymousSenderTag::new_random(&mut rand::thread_rng());
+        assert!(router.route(request.clone(), Some(sender_tag.clone())));
+        assert!(!router.route(request.clone(), Some(sender_tag.clone())));
+
+        // and are answered using the requester's SURBs
+        let inbound = requests.next().await.unwrap();
+        assert_eq!(inbound.payload, vec![1, 2, 3]);
+        inbound.respond(vec![4, 5, 6]).await.unwrap();
+        let response = outbound_rx.recv().await.unwrap();
+        assert_eq!(response.sender_tag, Some(sender_tag));
+        let Message::Datagram(response) = response.message else {
+            panic!("expected Message::Datagram");
+        };
+        assert_eq!(response.id, request.id);
+        assert_eq!(response.kind, DatagramKind::Response);
+
+        // a request too large for the remote to accept isn't sent at all
+        let payload = vec![0; DEFAULT_MAX_MESSAGE_SIZE];
+        assert!(matches!(
+            client.request(&addr, payload).await,
+            Err(Error::MessageTooLarge(len)) if len == DEFAULT_MAX_MESSAGE_SIZE
+        ));
+        assert!(outbound_rx.try_recv().is_none());
+        assert!(router.pending.lock().is_empty());
+    }
+}
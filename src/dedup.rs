@@ -0,0 +1,124 @@
+use std::collections::{HashMap, VecDeque};
+
+use super::message::ConnectionId;
+use super::nonce::Nonce;
+
+/// DuplicateFilter remembers the (connection ID, nonce) pairs of the TransportMessages
+/// handled most recently, so that copies delivered again by the mixnet, or retransmitted,
+/// are dropped before they reach a connection's message queue. Unlike the queue, it
+/// outlives the connection, so late copies don't look like messages on a new one.
+pub(crate) struct DuplicateFilter {
+    /// the stamp each pair was last seen with.
+    seen: HashMap<(ConnectionId, Nonce), u64>,
+    /// pairs in the order they were seen, least recently first. A pair seen again is
+    /// pushed with a new stamp, and its older entries are skipped when evicting.
+    order: VecDeque<((ConnectionId, Nonce), u64)>,
+    /// maximum number of pairs remembered; 0 disables the filter.
+    capacity: usize,
+    next_stamp: u64,
+}
+
+impl DuplicateFilter {
+    pub(crate) fn new(capacity: usize) -> Self {
+        DuplicateFilter {
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+            next_stamp: 0,
+        }
+    }
+
+    /// is_duplicate returns true if the pair was seen recently, making it the most
+    /// recently seen pair.
+    pub(crate) fn is_duplicate(&mut self, id: &ConnectionId, nonce: Nonce) -> bool {
+        let key = (id.clone(), nonce);
+        if !self.seen.contains_key(&key) {
+            return false;
+        }
+        self.insert_key(key);
+        true
+    }
+
+    /// insert records that the pair has been seen, forgetting the least recently
+    /// seen pairs beyond the capacity.
+    pub(crate) fn insert(&mut self, id: &ConnectionId, nonce: Nonce) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.insert_key((id.clone(), nonce));
+    }
+
+    /// forget forgets the pairs of connection `id`, once it's been resumed: its nonces
+    /// start over, so the old ones would be mistaken for duplicates.
+    pub(crate) fn forget(&mut self, id: &ConnectionId) {
+        // entries left in the order are skipped when evicting, since they aren't seen
+        self.seen.retain(|(seen_id, _), _| seen_id != id);
+    }
+
+    fn insert_key(&mut self, key: (ConnectionId, Nonce)) {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        self.seen.insert(key.clone(), stamp);
+        self.order.push_back((key, stamp));
+
+        while self.seen.len() > self.capacity {
+            let Some((key, stamp)) = self.order.pop_front() else {
+                break;
+            };
+            if self.seen.get(&key) == Some(&stamp) {
+                self.seen.remove(&key);
+            }
+        }
+        // drop the stale entries of pairs seen again, so they can't pile up
+        if self.order.len() > 2 * self.capacity {
+            let seen = &self.seen;
+            self.order
+                .retain(|(key, stamp)| seen.get(key) == Some(stamp));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_filter() {
+        let mut filter = DuplicateFilter::new(2);
+        let id = ConnectionId::generate();
+        assert!(!filter.is_duplicate(&id, Nonce::new(1)));
+        filter.insert(&id, Nonce::new(1));
+        assert!(filter.is_duplicate(&id, Nonce::new(1)));
+        assert!(!filter.is_duplicate(&ConnectionId::generate(), Nonce::new(1)));
+
+        // seeing 1 again made 2 the least recently seen, so it's forgotten first
+        filter.insert(&id, Nonce::new(2));
+        assert!(filter.is_duplicate(&id, Nonce::new(1)));
+        filter.insert(&id, Nonce::new(3));
+        assert!(!filter.is_duplicate(&id, Nonce::new(2)));
+        assert!(filter.is_duplicate(&id, Nonce::new(1)));
+        assert!(filter.is_duplicate(&id, Nonce::new(3)));
+
+        // repeated hits don't grow the filter past its bound
+        for _ in 0..100 {
+            assert!(filter.is_duplicate(&id, Nonce::new(1)));
+        }
+        assert_eq!(filter.seen.len(), 2);
+        assert!(filter.order.len() <= 4);
+
+        // a resumed connection's nonces start over
+        let other = ConnectionId::generate();
+        filter.insert(&other, Nonce::new(1));
+        filter.forget(&id);
+        assert!(!filter.is_duplicate(&id, Nonce::new(1)));
+        assert!(filter.is_duplicate(&other, Nonce::new(1)));
+    }
+
+    #[test]
+    fn test_duplicate_filter_disabled() {
+        let mut filter = DuplicateFilter::new(0);
+        let id = ConnectionId::generate();
+        filter.insert(&id, Nonce::new(1));
+        assert!(!filter.is_duplicate(&id, Nonce::new(1)));
+    }
+}
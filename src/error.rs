@@ -1,28 +1,43 @@
 use libp2p::core::multiaddr;
+use libp2p_identity::PeerId;
+use nym_sdk::mixnet::AnonymousSenderTag;
 use nym_sphinx::addressing::clients::RecipientFormattingError;
 
-use super::message::SubstreamId;
+use super::message::{RelayStatus, SubstreamId, PROTOCOL_VERSION};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("unimplemented")]
-    Unimplemented,
     #[error("failed to format multiaddress from nym address")]
     FailedToFormatMultiaddr(#[from] multiaddr::Error),
     #[error("unexpected protocol in multiaddress")]
     InvalidProtocolForMultiaddr,
     #[error("failed to decode message")]
     InvalidMessageBytes,
+    #[error("unknown message type {0}")]
+    UnknownMessageType(u8),
+    #[error("inbound message of {0} bytes exceeds the maximum message size")]
+    InboundMessageTooLarge(usize),
     #[error("no connection found for ConnectionResponse")]
     NoConnectionForResponse,
     #[error("received ConnectionResponse but connection was already established")]
     ConnectionAlreadyEstablished,
+    #[error("connection request rejected: {0}")]
+    ConnectionRejected(&'static str),
     #[error("cannot handle connection request; already have connection with given ID")]
     ConnectionIDExists,
+    #[error("invalid connection state transition: {0}")]
+    InvalidStateTransition(String),
     #[error("no connection found for TransportMessage")]
     NoConnectionForTransportMessage,
+    #[error("received a second ConnectionRequest or ConnectionResponse for a connection")]
+    ConnectionMessageReceivedTwice,
     #[error("failed to decode ConnectionMessage; too short")]
     ConnectionMessageBytesTooShort,
+    #[error(
+        "remote speaks protocol version {0}, but only version {} is supported",
+        PROTOCOL_VERSION
+    )]
+    UnsupportedVersion(u8),
     #[error("failed to decode ConnectionMessage; no peer ID")]
     ConnectionMessageBytesNoPeerId,
     #[error("invalid peer ID bytes")]
@@ -33,6 +48,110 @@ pub enum Error {
     TransportMessageBytesTooShort,
     #[error("failed to decode TransportMessage; invalid nonce")]
     InvalidNonce,
+    #[error("message with nonce {0} was already received")]
+    ReplayedNonce(u64),
+    #[error("message with nonce {0} is too far ahead of the next expected nonce")]
+    NonceOutsideWindow(u64),
+    #[error("connection timed out; a missing message never arrived")]
+    MessageGapTimeout,
+    #[error("connection closed; its nonces are nearly exhausted and it must be redialed")]
+    NoncesExhausted,
+    #[error("failed to decode KeepAliveMessage")]
+    InvalidKeepAliveMessageBytes,
+    #[error("failed to decode AckMessage")]
+    InvalidAckMessageBytes,
+    #[error("connection failed; a message was not acknowledged after the maximum number of retransmissions")]
+    DeliveryFailed,
+    #[error("failed to decode DatagramMessage")]
+    InvalidDatagramMessageBytes,
+    #[error("failed to decode BatchMessage")]
+    InvalidBatchMessageBytes,
+    #[error("failed to decode ConnectionCloseMessage")]
+    InvalidConnectionCloseBytes,
+    #[error("failed to decode MigrateMessage")]
+    InvalidMigrateMessageBytes,
+    #[error("invalid migration signature")]
+    InvalidMigrationSignature,
+    #[error("failed to decode ChallengeResponseMessage")]
+    InvalidChallengeResponseBytes,
+    #[error("invalid challenge signature; the connection request may have been replayed")]
+    InvalidChallengeSignature,
+    #[error("failed to decode session ticket or resumption message")]
+    InvalidResumptionBytes,
+    #[error("invalid session ticket")]
+    InvalidSessionTicket,
+    #[error("session ticket expired")]
+    SessionTicketExpired,
+    #[error("invalid resumption proof")]
+    InvalidResumptionProof,
+    #[error("failed to decode relay message")]
+    InvalidRelayMessageBytes,
+    #[error("invalid relay reservation signature")]
+    InvalidRelayReservationSignature,
+    #[error("relay refused: {0}")]
+    RelayRefused(RelayStatus),
+    #[error("relayed address does not end in the /p2p/<peer id> of the peer behind the relay")]
+    MissingRelayedPeerId,
+    #[error("no relay reservation or relayed connection found for RelayStatusMessage")]
+    NoRelayForRelayStatus,
+    #[error("connection reset; the remote closed the connection")]
+    ConnectionReset,
+    #[error("substream reset; the connection was resumed after the remote lost its state")]
+    ConnectionResumed,
+    #[error("datagram request timed out")]
+    DatagramTimeout,
+    #[error("remote does not support datagrams on connections")]
+    DatagramsUnsupported,
+    #[error("no connection found for KeepAliveMessage")]
+    NoConnectionForKeepAlive,
+    #[error("no connection found for MigrateMessage")]
+    NoConnectionForMigrate,
+    #[error("no connection awaiting a ChallengeResponseMessage")]
+    NoConnectionForChallengeResponse,
+    #[error("no connection found for SessionTicketMessage")]
+    NoConnectionForSessionTicket,
+    #[error("no connection awaiting a ResumeResponseMessage")]
+    NoConnectionForResumeResponse,
+    #[error("connection request timed out; the dialer never answered our challenge")]
+    ChallengeTimeout,
+    #[error("dial timed out; the remote never answered the connection request")]
+    HandshakeTimeout,
+    #[error("connection timed out; remote stopped answering keepalives")]
+    KeepAliveTimeout,
+    #[error("connection closed after going idle")]
+    IdleTimeout,
+    #[error("connection timed out; the sender tag it was opened with stopped sending")]
+    SenderTagExpired,
+    #[error("codec worker pool failed: {0}")]
+    CodecPoolFailure(String),
+    #[error("failed to decode Fragment")]
+    InvalidFragmentBytes,
+    #[error("payload of {0} bytes is too large to send")]
+    MessageTooLarge(usize),
+    #[error("failed to decode handshake")]
+    InvalidHandshakeBytes,
+    #[error("failed to sign handshake")]
+    HandshakeSigningFailure,
+    #[error("handshake identity key does not match the remote peer ID")]
+    HandshakeIdentityMismatch,
+    #[error("invalid handshake signature")]
+    InvalidHandshakeSignature,
+    #[error("handshake key exchange failed; invalid ephemeral key")]
+    InvalidHandshakeKey,
+    #[error("request to open or resume a connection sent at {0} is too old to be answered, or from the future")]
+    StaleConnectionRequest(u64),
+    #[error("request to open or resume a connection was already answered")]
+    ReplayedConnectionRequest,
+    #[error("remote peer ID does not match the dialed peer ID")]
+    UnexpectedPeerId,
+    #[error("remote accepts messages of at most {0} bytes, too few to fragment to")]
+    MaxMessageSizeTooSmall(u32),
+    #[error("failed to encrypt substream payload")]
+    EncryptionFailure,
+    #[error("failed to decrypt substream payload")]
+    DecryptionFailure,
+    #[error("failed to decompress substream payload")]
+    DecompressionFailure,
     #[error("invalid substream ID")]
     InvalidSubstreamMessageBytes,
     #[error("invalid substream message type byte")]
@@ -41,18 +160,104 @@ pub enum Error {
     SubstreamIdExists(SubstreamId),
     #[error("no substream found for given ID")]
     SubstreamIdDoesNotExist(SubstreamId),
+    #[error("substream open timed out; the remote never answered the OpenRequest")]
+    SubstreamOpenTimeout(SubstreamId),
+    #[error("remote sent more than substream {0:?} buffers unread")]
+    ReceiveBufferExceeded(SubstreamId),
     #[error("recv error: channel closed")]
     OneshotRecvFailure(#[from] tokio::sync::oneshot::error::RecvError),
     #[error("recv error: channel closed")]
     RecvFailure,
-    #[error("outbound send error")]
+    #[error("outbound send error: {0}")]
     OutboundSendFailure(String),
-    #[error("inbound send error")]
+    #[error("inbound send error: {0}")]
     InboundSendFailure(String),
+    #[error("failed to create mixnet client")]
+    MixnetClientFailure(#[source] nym_sdk::Error),
+    #[error("failed to write to the mixnet; gateway unreachable")]
+    GatewayUnreachable(#[source] nym_sdk::Error),
+    #[error("no reply SURBs left for sender tag {0}")]
+    SurbExhausted(AnonymousSenderTag),
+    #[error("failed to serialize the mixnet client's configuration")]
+    SerializationError(#[source] nym_sdk::Error),
+    #[error("failed to write to the mixnet")]
+    MixnetSendFailure(#[source] nym_sdk::Error),
+    #[error("failed to write to the mixnet after {attempts} attempts")]
+    SendRetriesExhausted {
+        attempts: u32,
+        #[source]
+        source: Box<Error>,
+    },
     #[error("failed to send new connection; receiver dropped")]
     ConnectionSendFailure,
     #[error("failed to send initial TransportEvent::NewAddress")]
     SendErrorTransportEvent,
     #[error("dial timed out")]
-    DialTimeout(#[from] tokio::time::error::Elapsed),
+    DialTimeout,
+    #[error("no connection to {0} to dial it through the SURBs it sent us")]
+    NoReplyRoute(PeerId),
+    #[error("dial to {0} aborted in favour of its simultaneous dial to us")]
+    SimultaneousDial(PeerId),
+    #[error("no known nym address for {0}")]
+    UnknownPeerAddress(PeerId),
+    #[error("{0} is pinned to other nym addresses")]
+    AddressPinned(PeerId),
+    #[error("failed to load or save the address book")]
+    AddressBook(#[source] std::io::Error),
+    #[error("failed to load or save the replay cache")]
+    ReplayCache(#[source] std::io::Error),
+    #[error("mixnet client disconnected from its gateway")]
+    MixnetClientDisconnected,
+    #[error("mixnet task shut down")]
+    MixnetTaskShutdown,
+    #[error("mixnet task panicked or was cancelled")]
+    MixnetTaskFailure,
+    #[error("service {0} is already bound to a listener")]
+    ServiceListenerExists(u16),
+}
+
+impl Error {
+    /// is_retryable returns true if the error was caused by the state of the mixnet
+    /// or the remote peer rather than by invalid input, so that the same operation
+    /// (e.g. a dial) may succeed if retried later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Error::GatewayUnreachable(_)
+                | Error::SurbExhausted(_)
+                | Error::DialTimeout
+                | Error::HandshakeTimeout
+                | Error::DatagramTimeout
+                | Error::KeepAliveTimeout
+                | Error::NoncesExhausted
+                | Error::SubstreamOpenTimeout(_)
+                | Error::MixnetClientDisconnected
+                | Error::OutboundSendFailure(_)
+                | Error::SendRetriesExhausted { .. }
+        )
+    }
+
+    /// is_transient_send_failure returns true if a write to the mixnet failed for a
+    /// reason that may pass within moments, such as the gateway being briefly
+    /// unreachable, so that the write is worth retrying. Other failures, e.g. a
+    /// recipient the backend can't route to, a sender tag out of SURBs, or a
+    /// disconnected client, which is reconnected instead, are permanent as far as
+    /// the write is concerned.
+    pub fn is_transient_send_failure(&self) -> bool {
+        match self {
+            // only I/O errors of a connection that may come back; not e.g. a refused one
+            Error::GatewayUnreachable(nym_sdk::Error::IoError(e)) => matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::NotConnected
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::WouldBlock
+            ),
+            Error::GatewayUnreachable(_) => true,
+            _ => false,
+        }
+    }
 }
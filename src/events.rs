@@ -0,0 +1,161 @@
+use futures::{future, Stream, StreamExt};
+use libp2p::Multiaddr;
+use libp2p_identity::PeerId;
+use nym_sphinx::addressing::clients::Recipient;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+
+/// NymEvent describes something that happened in a [`crate::transport::NymTransport`],
+/// so that applications can monitor the health of the transport.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NymEvent {
+    /// the mixnet client (re)connected to its gateway, with the given nym address.
+    MixnetConnected(Recipient),
+    /// the mixnet client lost its gateway connection and a replacement is being connected.
+    GatewayReconnecting,
+    /// the mixnet client lost its gateway connection and won't be replaced.
+    MixnetDisconnected,
+    /// the mixnet client started routing through the network topology of the given
+    /// epoch. Connections may be disrupted while the mixnet changes over.
+    TopologyChanged { epoch: u64 },
+    /// a message was dropped instead of being handled.
+    MessageDropped { reason: DropReason },
+    /// a peer we dialed is estimated to hold few of our reply SURBs, so more are being sent.
+    SurbLow { remaining: u64 },
+    /// the newest SURBs the given peer sent us, which are our only way of replying to it,
+    /// are `age` old and may soon expire; replies fail silently once they do, unless
+    /// the peer sends us more. Peers that stay connected send more with every message.
+    ReplyRouteExpiring { peer_id: PeerId, age: Duration },
+    /// a mixnet client is estimated to have few bytes of bandwidth credentials left.
+    BandwidthLow { remaining: u64 },
+    /// a mixnet client bought more bandwidth, and is estimated to have `remaining` bytes.
+    BandwidthToppedUp { remaining: u64 },
+    /// a substream was opened on a connection to the given peer.
+    SubstreamOpened { peer_id: PeerId },
+    /// a substream on a connection to the given peer was dropped.
+    SubstreamClosed { peer_id: PeerId },
+    /// a keepalive on a connection to the given peer measured the mixnet round-trip
+    /// time `rtt`; `smoothed` is the connection's moving average of its round-trip times.
+    RoundTrip {
+        peer_id: PeerId,
+        rtt: Duration,
+        smoothed: Duration,
+    },
+    /// the given peer moved to the nym address `address`, and its connection to us
+    /// followed it there.
+    ConnectionMigrated { peer_id: PeerId, address: Recipient },
+    /// the given peer, which we dialed with our nym address exposed, received our
+    /// ConnectionRequest from `address`, which other peers can likely reach us at too.
+    /// [`crate::observed::Behaviour`] hands these to the swarm as external address
+    /// candidates.
+    ObservedAddress { peer_id: PeerId, address: Multiaddr },
+}
+
+/// DropReason is why a message was dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// the inbound channel was full, and the overflow policy drops messages.
+    InboundChannelFull,
+    /// the message had already been received.
+    Replayed,
+    /// the message's nonce was too far ahead of the next expected one.
+    OutsideReplayWindow,
+    /// the message was a ConnectionRequest beyond the connection limits.
+    ConnectionRejected,
+    /// the message was a ConnectionRequest of a protocol version we don't speak.
+    UnsupportedVersion,
+    /// the message was too large, or failed to parse.
+    Invalid,
+    /// the message came from a sender tag blocked for sending invalid messages.
+    BlockedSender,
+    /// the message was a reply to a sender tag that hadn't sent us enough to be
+    /// sent more, and too many replies to it were already held.
+    AmplificationLimit,
+    /// the message was an OpenRequest or keepalive ping sent longer than the maximum
+    /// message age ago, or a ConnectionRequest signed longer than the maximum handshake
+    /// age ago.
+    Stale,
+    /// the message was a datagram on a connection already holding as many unread
+    /// datagrams as it buffers.
+    DatagramBufferFull,
+    /// the message was on a connection bound to another sender tag than the one it
+    /// came with.
+    SenderTagMismatch,
+}
+
+/// EventSender emits events to every stream returned by [`EventSender::subscribe`].
+/// The default sender has no subscribers and discards every event.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct EventSender {
+    tx: Option<broadcast::Sender<NymEvent>>,
+}
+
+impl EventSender {
+    /// new returns a sender which buffers up to `capacity` events for slow subscribers.
+    pub(crate) fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        EventSender { tx: Some(tx) }
+    }
+
+    /// subscribe returns a stream of the events emitted from now on.
+    /// A subscriber that falls more than the buffer capacity behind misses events.
+    pub(crate) fn subscribe(&self) -> impl Stream<Item = NymEvent> + Send + 'static {
+        let rx = match &self.tx {
+            Some(tx) => tx.subscribe(),
+            None => broadcast::channel(1).1,
+        };
+        BroadcastStream::new(rx).filter_map(|event| future::ready(event.ok()))
+    }
+
+    pub(crate) fn emit(&self, event: NymEvent) {
+        if let Some(tx) = &self.tx {
+            // fails only if nobody is subscribed
+            tx.send(event).ok();
+        }
+    }
+
+    /// emit_on_drop returns a guard which emits `event` once it's dropped.
+    pub(crate) fn emit_on_drop(&self, event: NymEvent) -> EmitOnDrop {
+        EmitOnDrop(Some((self.clone(), event)))
+    }
+}
+
+/// EmitOnDrop emits an event when it's dropped; see [`EventSender::emit_on_drop`].
+#[derive(Debug, Default)]
+pub(crate) struct EmitOnDrop(Option<(EventSender, NymEvent)>);
+
+impl Drop for EmitOnDrop {
+    fn drop(&mut self) {
+        if let Some((events, event)) = self.0.take() {
+            events.emit(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_events_subscribe() {
+        let events = EventSender::new(16);
+        let mut stream = Box::pin(events.subscribe());
+
+        events.emit(NymEvent::GatewayReconnecting);
+        let peer_id = PeerId::random();
+        drop(events.emit_on_drop(NymEvent::SubstreamClosed { peer_id }));
+
+        assert_eq!(stream.next().await, Some(NymEvent::GatewayReconnecting));
+        assert_eq!(
+            stream.next().await,
+            Some(NymEvent::SubstreamClosed { peer_id })
+        );
+
+        // the default sender discards events
+        let events = EventSender::default();
+        let mut stream = Box::pin(events.subscribe());
+        events.emit(NymEvent::GatewayReconnecting);
+        assert_eq!(stream.next().await, None);
+    }
+}
@@ -0,0 +1,52 @@
+//! Entry points for the cargo-fuzz targets in `fuzz/`. The wire format is internal to
+//! the crate, so this module is only built by `cargo fuzz`, which sets `--cfg fuzzing`.
+//! Each function decodes arbitrary bytes the way a message from the mixnet is decoded,
+//! and panics if a decoded message doesn't survive being encoded and decoded again.
+
+use bytes::{Bytes, BytesMut};
+
+use super::config::DEFAULT_MAX_MESSAGE_SIZE;
+use super::message::{parse_message_data, SubstreamMessage, TransportMessage};
+
+/// message decodes `data` as a mixnet message of any type.
+pub fn message(data: &[u8]) {
+    let Ok(msg) = parse_message_data(Bytes::copy_from_slice(data), None, DEFAULT_MAX_MESSAGE_SIZE)
+    else {
+        return;
+    };
+    // decoding normalises some fields (e.g. the handshake's public key encoding),
+    // so it's the first encoding that has to be stable
+    let bytes = msg.0.to_bytes();
+    let decoded = parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE)
+        .expect("encoded message decodes");
+    assert_eq!(decoded.0.to_bytes(), bytes);
+}
+
+/// transport_message decodes `data` as a TransportMessage, without the type byte.
+pub fn transport_message(data: &[u8]) {
+    let Ok(msg) = TransportMessage::try_from_bytes(Bytes::copy_from_slice(data)) else {
+        return;
+    };
+    let mut bytes = BytesMut::new();
+    msg.encode(&mut bytes);
+    let decoded =
+        TransportMessage::try_from_bytes(bytes.clone().freeze()).expect("encoded message decodes");
+    let mut reencoded = BytesMut::new();
+    decoded.encode(&mut reencoded);
+    assert_eq!(reencoded, bytes);
+}
+
+/// substream_message decodes `data` as a SubstreamMessage.
+pub fn substream_message(data: &[u8]) {
+    let Ok(msg) = SubstreamMessage::try_from_bytes(Bytes::copy_from_slice(data)) else {
+        return;
+    };
+    let mut bytes = BytesMut::new();
+    msg.encode(&mut bytes);
+    assert_eq!(bytes.len(), msg.encoded_len());
+    let decoded =
+        SubstreamMessage::try_from_bytes(bytes.clone().freeze()).expect("encoded message decodes");
+    let mut reencoded = BytesMut::new();
+    decoded.encode(&mut reencoded);
+    assert_eq!(reencoded, bytes);
+}
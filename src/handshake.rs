@@ -0,0 +1,873 @@
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit},
+    XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use libp2p_identity::{Keypair, PeerId, PublicKey};
+use nym_sphinx::addressing::clients::Recipient;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+use std::fmt::{Debug, Formatter};
+use x25519_dalek::{EphemeralSecret, PublicKey as EphemeralPublicKey};
+
+use super::error::Error;
+use super::message::{ChallengeResponseMessage, ConnectionId, MigrateMessage, RelayReserveMessage};
+use super::runtime::unix_millis;
+
+/// prefix of the bytes signed by each side's identity key, so that a handshake
+/// signature can't be mistaken for a signature made in some other protocol.
+const SIGNATURE_DOMAIN: &[u8] = b"libp2p-nym-handshake:";
+
+/// prefix of the bytes signed in a MigrateMessage, which keeps migration signatures
+/// apart from handshake signatures made by the same key.
+const MIGRATION_SIGNATURE_DOMAIN: &[u8] = b"libp2p-nym-migration:";
+
+/// prefix of the bytes signed in a ChallengeResponse, which keeps challenge signatures
+/// apart from the other signatures made by the same key.
+const CHALLENGE_SIGNATURE_DOMAIN: &[u8] = b"libp2p-nym-challenge:";
+
+/// prefix of the bytes signed in a RelayReserve, which keeps reservation signatures
+/// apart from the other signatures made by the same key.
+const RELAY_RESERVATION_SIGNATURE_DOMAIN: &[u8] = b"libp2p-nym-relay-reservation:";
+
+/// length of the random challenge a listener sends in its ConnectionResponse.
+pub(crate) const CHALLENGE_LEN: usize = 32;
+
+const EPHEMERAL_KEY_LEN: usize = 32;
+const TIMESTAMP_LEN: usize = 8; // length of u64
+const LENGTH_PREFIX_LEN: usize = 2; // length of u16
+const XNONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+
+/// how much longer `SessionCipher::encrypt` makes a payload: its nonce and tag.
+pub(crate) const CIPHER_OVERHEAD: usize = XNONCE_LEN + TAG_LEN;
+
+const DIALER_KEY_INFO: &[u8] = b"libp2p-nym dialer";
+const LISTENER_KEY_INFO: &[u8] = b"libp2p-nym listener";
+const RESUMPTION_KEY_INFO: &[u8] = b"libp2p-nym resumption";
+const RESUME_REQUEST_INFO: &[u8] = b"libp2p-nym resume request";
+const RESUME_RESPONSE_INFO: &[u8] = b"libp2p-nym resume response";
+
+/// length of the random nonce each side of a resumption contributes to its new keys.
+pub(crate) const RESUME_NONCE_LEN: usize = 32;
+/// length of the proof that a side of a resumption holds the connection's resumption secret.
+pub(crate) const RESUME_PROOF_LEN: usize = 32;
+
+/// Role is the side of the connection we're on, which decides
+/// which of the two derived keys we send with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Role {
+    Dialer,
+    Listener,
+}
+
+/// HandshakePayload is carried in a ConnectionRequest or ConnectionResponse.
+/// It holds an ephemeral X25519 key and the time it was made, signed together with
+/// the connection ID, the sender's PeerId and the sender's nym address (if the remote
+/// knows it) by the identity key that the sender's PeerId is derived from.
+#[derive(Clone, Debug)]
+pub(crate) struct HandshakePayload {
+    identity: PublicKey,
+    ephemeral: [u8; EPHEMERAL_KEY_LEN],
+    /// milliseconds since the UNIX epoch
+    timestamp: u64,
+    signature: Vec<u8>,
+}
+
+impl HandshakePayload {
+    /// sign signs the ephemeral key and `timestamp` with `keypair`, binding them to
+    /// `address`, if any.
+    pub(crate) fn sign(
+        keypair: &Keypair,
+        id: &ConnectionId,
+        ephemeral: [u8; EPHEMERAL_KEY_LEN],
+        timestamp: u64,
+        address: Option<&Recipient>,
+    ) -> Result<Self, Error> {
+        let peer_id = keypair.public().to_peer_id();
+        let signature = keypair
+            .sign(&signed_bytes(id, &ephemeral, timestamp, &peer_id, address))
+            .map_err(|_| Error::HandshakeSigningFailure)?;
+        Ok(HandshakePayload {
+            identity: keypair.public(),
+            ephemeral,
+            timestamp,
+            signature,
+        })
+    }
+
+    /// identity returns the identity key the payload was signed with.
+    pub(crate) fn identity(&self) -> &PublicKey {
+        &self.identity
+    }
+
+    /// timestamp returns the time the payload was signed at, in milliseconds since the
+    /// UNIX epoch, by the signer's clock. Since it's signed, a replayed request can't
+    /// claim to be more recent than it is.
+    pub(crate) fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// verify checks that the payload was signed for connection `id` by the key
+    /// `peer_id` is derived from, for `address` (the signer's nym address, if we know it).
+    pub(crate) fn verify(
+        &self,
+        id: &ConnectionId,
+        peer_id: &PeerId,
+        address: Option<&Recipient>,
+    ) -> Result<(), Error> {
+        if PeerId::from_public_key(&self.identity) != *peer_id {
+            return Err(Error::HandshakeIdentityMismatch);
+        }
+
+        let signed = signed_bytes(id, &self.ephemeral, self.timestamp, peer_id, address);
+        if !self.identity.verify(&signed, &self.signature) {
+            return Err(Error::InvalidHandshakeSignature);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let identity = self.identity.encode_protobuf();
+        let mut bytes = self.ephemeral.to_vec();
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&(identity.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&identity);
+        bytes.extend_from_slice(&(self.signature.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    /// try_from_bytes decodes a payload from the start of `bytes`, returning it
+    /// along with the number of bytes it took up.
+    pub(crate) fn try_from_bytes(bytes: &[u8]) -> Result<(Self, usize), Error> {
+        let ephemeral: [u8; EPHEMERAL_KEY_LEN] = bytes
+            .get(..EPHEMERAL_KEY_LEN)
+            .ok_or(Error::InvalidHandshakeBytes)?
+            .try_into()
+            .map_err(|_| Error::InvalidHandshakeBytes)?;
+        let timestamp: [u8; TIMESTAMP_LEN] = bytes
+            .get(EPHEMERAL_KEY_LEN..EPHEMERAL_KEY_LEN + TIMESTAMP_LEN)
+            .ok_or(Error::InvalidHandshakeBytes)?
+            .try_into()
+            .map_err(|_| Error::InvalidHandshakeBytes)?;
+        let (identity, offset) = read_length_prefixed(bytes, EPHEMERAL_KEY_LEN + TIMESTAMP_LEN)?;
+        let (signature, offset) = read_length_prefixed(bytes, offset)?;
+
+        let identity =
+            PublicKey::try_decode_protobuf(identity).map_err(|_| Error::InvalidHandshakeBytes)?;
+        Ok((
+            HandshakePayload {
+                identity,
+                ephemeral,
+                timestamp: u64::from_be_bytes(timestamp),
+                signature: signature.to_vec(),
+            },
+            offset,
+        ))
+    }
+}
+
+fn read_length_prefixed(bytes: &[u8], offset: usize) -> Result<(&[u8], usize), Error> {
+    let len_bytes: [u8; LENGTH_PREFIX_LEN] = bytes
+        .get(offset..offset + LENGTH_PREFIX_LEN)
+        .ok_or(Error::InvalidHandshakeBytes)?
+        .try_into()
+        .map_err(|_| Error::InvalidHandshakeBytes)?;
+    let start = offset + LENGTH_PREFIX_LEN;
+    let end = start + u16::from_be_bytes(len_bytes) as usize;
+    let value = bytes.get(start..end).ok_or(Error::InvalidHandshakeBytes)?;
+    Ok((value, end))
+}
+
+/// signed_bytes returns the bytes a handshake signature is made over. Signing the
+/// signer's nym address binds its PeerId to that address, so a peer can't present
+/// another peer's handshake as its own from a different address. A dialer which
+/// doesn't expose its address has none to bind, which is signed as an empty address.
+/// Signing the timestamp keeps a replayed payload from passing as a recent one.
+fn signed_bytes(
+    id: &ConnectionId,
+    ephemeral: &[u8],
+    timestamp: u64,
+    peer_id: &PeerId,
+    address: Option<&Recipient>,
+) -> Vec<u8> {
+    let mut bytes = SIGNATURE_DOMAIN.to_vec();
+    bytes.extend_from_slice(id.as_bytes());
+    bytes.extend_from_slice(ephemeral);
+    bytes.extend_from_slice(&timestamp.to_be_bytes());
+    let peer_id = peer_id.to_bytes();
+    bytes.extend_from_slice(&(peer_id.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(&peer_id);
+    match address {
+        Some(address) => {
+            bytes.push(1);
+            bytes.extend_from_slice(&address.to_bytes());
+        }
+        None => bytes.push(0),
+    }
+    bytes
+}
+
+/// sign_migration signs the move of connection `id` to our new nym address with
+/// `keypair`, the identity the connection was opened with, so that only the peer the
+/// remote shook hands with can redirect the connection.
+pub(crate) fn sign_migration(
+    keypair: &Keypair,
+    id: &ConnectionId,
+    seq: u64,
+    address: &Recipient,
+) -> Result<Vec<u8>, Error> {
+    keypair
+        .sign(&migration_bytes(id, seq, address))
+        .map_err(|_| Error::HandshakeSigningFailure)
+}
+
+/// verify_migration checks that `msg` was signed by `identity`, the identity key the
+/// remote opened the connection with.
+pub(crate) fn verify_migration(identity: &PublicKey, msg: &MigrateMessage) -> Result<(), Error> {
+    let signed = migration_bytes(&msg.id, msg.seq, &msg.recipient);
+    if !identity.verify(&signed, &msg.signature) {
+        return Err(Error::InvalidMigrationSignature);
+    }
+    Ok(())
+}
+
+/// sign_relay_reservation signs reservation `id` with the relay at `relay` with our
+/// identity key, so that only we can have the circuits opened to our PeerId forwarded
+/// to us, and the reservation can't be replayed to another relay.
+pub(crate) fn sign_relay_reservation(
+    keypair: &Keypair,
+    id: &ConnectionId,
+    relay: &Recipient,
+) -> Result<Vec<u8>, Error> {
+    keypair
+        .sign(&relay_reservation_bytes(id, relay))
+        .map_err(|_| Error::HandshakeSigningFailure)
+}
+
+/// verify_relay_reservation checks that `msg` was signed by the identity it reserves
+/// for, for the relay at `relay`, which is our own address.
+pub(crate) fn verify_relay_reservation(
+    msg: &RelayReserveMessage,
+    relay: &Recipient,
+) -> Result<(), Error> {
+    let signed = relay_reservation_bytes(&msg.id, relay);
+    if !msg.identity.verify(&signed, &msg.signature) {
+        return Err(Error::InvalidRelayReservationSignature);
+    }
+    Ok(())
+}
+
+fn relay_reservation_bytes(id: &ConnectionId, relay: &Recipient) -> Vec<u8> {
+    let mut bytes = RELAY_RESERVATION_SIGNATURE_DOMAIN.to_vec();
+    bytes.extend_from_slice(id.as_bytes());
+    bytes.extend_from_slice(&relay.to_bytes());
+    bytes
+}
+
+fn migration_bytes(id: &ConnectionId, seq: u64, address: &Recipient) -> Vec<u8> {
+    let mut bytes = MIGRATION_SIGNATURE_DOMAIN.to_vec();
+    bytes.extend_from_slice(id.as_bytes());
+    bytes.extend_from_slice(&seq.to_be_bytes());
+    bytes.extend_from_slice(&address.to_bytes());
+    bytes
+}
+
+/// new_challenge returns a random challenge for the dialer of a connection to sign.
+pub(crate) fn new_challenge() -> [u8; CHALLENGE_LEN] {
+    let mut challenge = [0u8; CHALLENGE_LEN];
+    OsRng.fill_bytes(&mut challenge);
+    challenge
+}
+
+/// sign_challenge signs the challenge the listener of connection `id` sent us with
+/// `keypair`, the identity we dialed it with. A replayed ConnectionRequest can't be
+/// followed by a ChallengeResponse, since the challenge is new for every request.
+pub(crate) fn sign_challenge(
+    keypair: &Keypair,
+    id: &ConnectionId,
+    challenge: &[u8; CHALLENGE_LEN],
+) -> Result<Vec<u8>, Error> {
+    keypair
+        .sign(&challenge_bytes(id, challenge))
+        .map_err(|_| Error::HandshakeSigningFailure)
+}
+
+/// verify_challenge checks that `msg` is `challenge` signed by `identity`, the identity
+/// key the dialer opened the connection with.
+pub(crate) fn verify_challenge(
+    identity: &PublicKey,
+    challenge: &[u8; CHALLENGE_LEN],
+    msg: &ChallengeResponseMessage,
+) -> Result<(), Error> {
+    if !identity.verify(&challenge_bytes(&msg.id, challenge), &msg.signature) {
+        return Err(Error::InvalidChallengeSignature);
+    }
+    Ok(())
+}
+
+fn challenge_bytes(id: &ConnectionId, challenge: &[u8; CHALLENGE_LEN]) -> Vec<u8> {
+    let mut bytes = CHALLENGE_SIGNATURE_DOMAIN.to_vec();
+    bytes.extend_from_slice(id.as_bytes());
+    bytes.extend_from_slice(challenge);
+    bytes
+}
+
+/// Handshake is our half of the key exchange for a single connection.
+pub(crate) struct Handshake {
+    id: ConnectionId,
+    secret: EphemeralSecret,
+    payload: HandshakePayload,
+    /// our nym address, if the payload binds it
+    address: Option<Recipient>,
+}
+
+impl Handshake {
+    /// new generates an ephemeral key for the connection and signs it with `keypair`,
+    /// binding it to `address`, our nym address, if the remote will know it.
+    pub(crate) fn new(
+        keypair: &Keypair,
+        id: &ConnectionId,
+        address: Option<&Recipient>,
+    ) -> Result<Self, Error> {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral = EphemeralPublicKey::from(&secret).to_bytes();
+        Ok(Handshake {
+            id: id.clone(),
+            secret,
+            payload: HandshakePayload::sign(keypair, id, ephemeral, unix_millis(), address)?,
+            address: address.copied(),
+        })
+    }
+
+    /// payload returns the payload to send to the remote.
+    pub(crate) fn payload(&self) -> HandshakePayload {
+        self.payload.clone()
+    }
+
+    /// address returns our nym address, if the remote knows it.
+    pub(crate) fn address(&self) -> Option<&Recipient> {
+        self.address.as_ref()
+    }
+
+    /// finish checks that the remote's payload was signed by the key `remote_peer_id`
+    /// is derived from, for `remote_address` (the remote's nym address, if we know it),
+    /// and derives the keys used to encrypt the connection's payloads.
+    pub(crate) fn finish(
+        self,
+        remote: &HandshakePayload,
+        remote_peer_id: &PeerId,
+        remote_address: Option<&Recipient>,
+        role: Role,
+    ) -> Result<SessionCipher, Error> {
+        remote.verify(&self.id, remote_peer_id, remote_address)?;
+
+        let shared = self
+            .secret
+            .diffie_hellman(&EphemeralPublicKey::from(remote.ephemeral));
+        if !shared.was_contributory() {
+            return Err(Error::InvalidHandshakeKey);
+        }
+
+        let hkdf = Hkdf::<Sha256>::new(Some(self.id.as_bytes()), shared.as_bytes());
+        Ok(SessionCipher::derive(&hkdf, role))
+    }
+}
+
+fn derive_key(hkdf: &Hkdf<Sha256>, info: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    hkdf.expand(info, &mut key)
+        .expect("32 bytes is a valid output length for HKDF-SHA256");
+    key
+}
+
+fn derive_cipher(hkdf: &Hkdf<Sha256>, info: &[u8]) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(&derive_key(hkdf, info).into())
+}
+
+/// new_resume_nonce returns a random nonce for our side of a resumption.
+pub(crate) fn new_resume_nonce() -> [u8; RESUME_NONCE_LEN] {
+    let mut nonce = [0u8; RESUME_NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+/// ResumptionSecret is derived from a connection's handshake along with its keys. The
+/// remote keeps it in the session ticket we issue it (see [`crate::resumption`]), so
+/// that if we lose the connection's state, both sides can still derive new keys for
+/// the connection from it, and prove to each other that they're the peers that shook
+/// hands, without another handshake.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct ResumptionSecret([u8; 32]);
+
+impl ResumptionSecret {
+    pub(crate) fn from_bytes(bytes: [u8; 32]) -> Self {
+        ResumptionSecret(bytes)
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// request_proof returns the proof sent in a ResumeRequest for connection `id`, sent
+    /// at `timestamp`, which shows the listener the request comes from the holder of the secret.
+    pub(crate) fn request_proof(
+        &self,
+        id: &ConnectionId,
+        request: &[u8; RESUME_NONCE_LEN],
+        timestamp: u64,
+    ) -> [u8; RESUME_PROOF_LEN] {
+        let mut salt = id.as_bytes().to_vec();
+        salt.extend_from_slice(request);
+        salt.extend_from_slice(&timestamp.to_be_bytes());
+        derive_key(
+            &Hkdf::<Sha256>::new(Some(&salt), &self.0),
+            RESUME_REQUEST_INFO,
+        )
+    }
+
+    /// response_proof returns the proof sent in the ResumeResponse to a request with
+    /// nonce `request`, which shows the resumer that the ticket was opened.
+    pub(crate) fn response_proof(
+        &self,
+        id: &ConnectionId,
+        request: &[u8; RESUME_NONCE_LEN],
+        response: &[u8; RESUME_NONCE_LEN],
+    ) -> [u8; RESUME_PROOF_LEN] {
+        derive_key(
+            &self.resume_hkdf(id, request, response),
+            RESUME_RESPONSE_INFO,
+        )
+    }
+
+    /// resume derives the keys connection `id` is encrypted with once it's resumed,
+    /// from both sides' nonces, so that they're new even if the ticket is used twice.
+    /// Each side keeps the role it had when the connection was opened.
+    pub(crate) fn resume(
+        &self,
+        id: &ConnectionId,
+        request: &[u8; RESUME_NONCE_LEN],
+        response: &[u8; RESUME_NONCE_LEN],
+        role: Role,
+    ) -> SessionCipher {
+        SessionCipher::derive(&self.resume_hkdf(id, request, response), role)
+    }
+
+    fn resume_hkdf(
+        &self,
+        id: &ConnectionId,
+        request: &[u8; RESUME_NONCE_LEN],
+        response: &[u8; RESUME_NONCE_LEN],
+    ) -> Hkdf<Sha256> {
+        let mut salt = id.as_bytes().to_vec();
+        salt.extend_from_slice(request);
+        salt.extend_from_slice(response);
+        Hkdf::<Sha256>::new(Some(&salt), &self.0)
+    }
+}
+
+impl Debug for ResumptionSecret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResumptionSecret").finish_non_exhaustive()
+    }
+}
+
+/// proofs_match compares two resumption proofs in constant time, so that a forged
+/// proof doesn't learn how much of it was right.
+pub(crate) fn proofs_match(a: &[u8; RESUME_PROOF_LEN], b: &[u8; RESUME_PROOF_LEN]) -> bool {
+    a.iter().zip(b).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// SessionCipher encrypts the substream payloads of a connection once the handshake
+/// has finished. Each direction uses its own key, and every payload a random nonce,
+/// so payloads can be encrypted by any substream without coordinating on a counter.
+pub(crate) struct SessionCipher {
+    send: XChaCha20Poly1305,
+    recv: XChaCha20Poly1305,
+    /// the side of the connection we're on, which a resumption keeps.
+    role: Role,
+    /// lets the connection be resumed with new keys (see [`ResumptionSecret`]).
+    resumption: ResumptionSecret,
+}
+
+impl SessionCipher {
+    /// derive derives the keys of both directions, and the resumption secret, from `hkdf`.
+    fn derive(hkdf: &Hkdf<Sha256>, role: Role) -> Self {
+        let dialer_cipher = derive_cipher(hkdf, DIALER_KEY_INFO);
+        let listener_cipher = derive_cipher(hkdf, LISTENER_KEY_INFO);
+        let resumption = ResumptionSecret(derive_key(hkdf, RESUMPTION_KEY_INFO));
+        match role {
+            Role::Dialer => SessionCipher {
+                send: dialer_cipher,
+                recv: listener_cipher,
+                role,
+                resumption,
+            },
+            Role::Listener => SessionCipher {
+                send: listener_cipher,
+                recv: dialer_cipher,
+                role,
+                resumption,
+            },
+        }
+    }
+
+    pub(crate) fn role(&self) -> Role {
+        self.role
+    }
+
+    /// resumption_secret returns the secret the connection can be resumed with.
+    pub(crate) fn resumption_secret(&self) -> &ResumptionSecret {
+        &self.resumption
+    }
+
+    /// encrypt returns the nonce followed by the ciphertext of `plaintext`.
+    pub(crate) fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .send
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| Error::EncryptionFailure)?;
+
+        let mut bytes = nonce.to_vec();
+        bytes.extend_from_slice(&ciphertext);
+        Ok(bytes)
+    }
+
+    /// decrypt reverses [`SessionCipher::encrypt`] on the remote's side, failing if
+    /// the payload was not encrypted by the remote or was modified along the way.
+    pub(crate) fn decrypt(&self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        if bytes.len() < XNONCE_LEN {
+            return Err(Error::DecryptionFailure);
+        }
+
+        let (nonce, ciphertext) = bytes.split_at(XNONCE_LEN);
+        self.recv
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Error::DecryptionFailure)
+    }
+}
+
+impl Debug for SessionCipher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionCipher").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn listener_address() -> Recipient {
+        Recipient::try_from_base58_string(
+            "Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR",
+        )
+        .unwrap()
+    }
+
+    /// handshake_pair returns the handshakes of an anonymous dialer and of a listener
+    /// at [`listener_address`].
+    fn handshake_pair() -> (Keypair, Handshake, Keypair, Handshake, ConnectionId) {
+        let id = ConnectionId::generate();
+        let dialer_key = Keypair::generate_ed25519();
+        let listener_key = Keypair::generate_ed25519();
+        let dialer = Handshake::new(&dialer_key, &id, None).unwrap();
+        let listener = Handshake::new(&listener_key, &id, Some(&listener_address())).unwrap();
+        (dialer_key, dialer, listener_key, listener, id)
+    }
+
+    #[test]
+    fn test_handshake_encrypt_decrypt() {
+        let (dialer_key, dialer, listener_key, listener, _) = handshake_pair();
+
+        // through the wire encoding
+        let bytes = dialer.payload().to_bytes();
+        let (dialer_payload, len) = HandshakePayload::try_from_bytes(&bytes).unwrap();
+        assert_eq!(len, bytes.len());
+        let listener_payload = listener.payload();
+
+        let dialer_cipher = dialer
+            .finish(
+                &listener_payload,
+                &listener_key.public().to_peer_id(),
+                Some(&listener_address()),
+                Role::Dialer,
+            )
+            .unwrap();
+        let listener_cipher = listener
+            .finish(
+                &dialer_payload,
+                &dialer_key.public().to_peer_id(),
+                None,
+                Role::Listener,
+            )
+            .unwrap();
+
+        let ciphertext = dialer_cipher.encrypt(b"hello").unwrap();
+        assert_eq!(ciphertext.len(), b"hello".len() + CIPHER_OVERHEAD);
+        assert_eq!(listener_cipher.decrypt(&ciphertext).unwrap(), b"hello");
+        let ciphertext = listener_cipher.encrypt(b"world").unwrap();
+        assert_eq!(dialer_cipher.decrypt(&ciphertext).unwrap(), b"world");
+
+        // a payload can't be decrypted with the key it was sent with
+        let ciphertext = dialer_cipher.encrypt(b"hello").unwrap();
+        assert!(matches!(
+            dialer_cipher.decrypt(&ciphertext),
+            Err(Error::DecryptionFailure)
+        ));
+
+        // nor once it's been modified
+        let mut ciphertext = dialer_cipher.encrypt(b"hello").unwrap();
+        *ciphertext.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            listener_cipher.decrypt(&ciphertext),
+            Err(Error::DecryptionFailure)
+        ));
+    }
+
+    #[test]
+    fn test_handshake_rejects_wrong_peer() {
+        let (_, dialer, listener_key, listener, _) = handshake_pair();
+        let payload = listener.payload();
+
+        // the payload is valid, but isn't from the peer we expected
+        let other_peer_id = Keypair::generate_ed25519().public().to_peer_id();
+        assert!(matches!(
+            dialer.finish(
+                &payload,
+                &other_peer_id,
+                Some(&listener_address()),
+                Role::Dialer
+            ),
+            Err(Error::HandshakeIdentityMismatch)
+        ));
+
+        // a payload signed for a different connection is rejected
+        let (_, dialer, _, _, _) = handshake_pair();
+        assert!(matches!(
+            dialer.finish(
+                &payload,
+                &listener_key.public().to_peer_id(),
+                Some(&listener_address()),
+                Role::Dialer
+            ),
+            Err(Error::InvalidHandshakeSignature)
+        ));
+
+        // as is one restamped with a later time than it was signed at
+        let (_, dialer, listener_key, listener, _) = handshake_pair();
+        let mut payload = listener.payload();
+        payload.timestamp += 1;
+        assert!(matches!(
+            dialer.finish(
+                &payload,
+                &listener_key.public().to_peer_id(),
+                Some(&listener_address()),
+                Role::Dialer
+            ),
+            Err(Error::InvalidHandshakeSignature)
+        ));
+    }
+
+    #[test]
+    fn test_handshake_binds_nym_address() {
+        let (dialer_key, dialer, listener_key, listener, id) = handshake_pair();
+        let listener_peer_id = listener_key.public().to_peer_id();
+        let payload = listener.payload();
+
+        // the listener's payload is only valid from the address it was signed for
+        let other_address = Recipient::try_from_base58_string(
+            "D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN",
+        )
+        .unwrap();
+        assert!(matches!(
+            dialer.finish(
+                &payload,
+                &listener_peer_id,
+                Some(&other_address),
+                Role::Dialer
+            ),
+            Err(Error::InvalidHandshakeSignature)
+        ));
+
+        // a dialer exposing its address binds its PeerId to it, so its payload
+        // can't be passed off as anonymous, nor as coming from another address
+        let dialer_payload = Handshake::new(&dialer_key, &id, Some(&other_address))
+            .unwrap()
+            .payload();
+        let dialer_peer_id = dialer_key.public().to_peer_id();
+        let listener = Handshake::new(&listener_key, &id, Some(&listener_address())).unwrap();
+        assert!(matches!(
+            listener.finish(&dialer_payload, &dialer_peer_id, None, Role::Listener),
+            Err(Error::InvalidHandshakeSignature)
+        ));
+        let listener = Handshake::new(&listener_key, &id, Some(&listener_address())).unwrap();
+        assert!(matches!(
+            listener.finish(
+                &dialer_payload,
+                &dialer_peer_id,
+                Some(&listener_address()),
+                Role::Listener
+            ),
+            Err(Error::InvalidHandshakeSignature)
+        ));
+        let listener = Handshake::new(&listener_key, &id, Some(&listener_address())).unwrap();
+        assert!(listener
+            .finish(
+                &dialer_payload,
+                &dialer_peer_id,
+                Some(&other_address),
+                Role::Listener
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_migration_signature() {
+        let keypair = Keypair::generate_ed25519();
+        let id = ConnectionId::generate();
+        let address = listener_address();
+        let mut msg = MigrateMessage {
+            id: id.clone(),
+            seq: 1,
+            recipient: address,
+            signature: sign_migration(&keypair, &id, 1, &address).unwrap(),
+        };
+        assert!(verify_migration(&keypair.public(), &msg).is_ok());
+
+        // it's only valid from the remote's identity
+        let other = Keypair::generate_ed25519();
+        assert!(matches!(
+            verify_migration(&other.public(), &msg),
+            Err(Error::InvalidMigrationSignature)
+        ));
+
+        // and can't be replayed with another sequence number
+        msg.seq = 2;
+        assert!(matches!(
+            verify_migration(&keypair.public(), &msg),
+            Err(Error::InvalidMigrationSignature)
+        ));
+    }
+
+    #[test]
+    fn test_relay_reservation_signature() {
+        let keypair = Keypair::generate_ed25519();
+        let id = ConnectionId::generate();
+        let relay = listener_address();
+        let mut msg = RelayReserveMessage {
+            id: id.clone(),
+            identity: keypair.public(),
+            signature: sign_relay_reservation(&keypair, &id, &relay).unwrap(),
+        };
+        assert!(verify_relay_reservation(&msg, &relay).is_ok());
+
+        // it can't be replayed to another relay
+        let other_relay = Recipient::try_from_base58_string(
+            "D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN",
+        )
+        .unwrap();
+        assert!(matches!(
+            verify_relay_reservation(&msg, &other_relay),
+            Err(Error::InvalidRelayReservationSignature)
+        ));
+
+        // nor claimed by another identity
+        msg.identity = Keypair::generate_ed25519().public();
+        assert!(matches!(
+            verify_relay_reservation(&msg, &relay),
+            Err(Error::InvalidRelayReservationSignature)
+        ));
+    }
+
+    #[test]
+    fn test_challenge_signature() {
+        let keypair = Keypair::generate_ed25519();
+        let id = ConnectionId::generate();
+        let challenge = new_challenge();
+        let msg = ChallengeResponseMessage {
+            id: id.clone(),
+            signature: sign_challenge(&keypair, &id, &challenge).unwrap(),
+        };
+        assert!(verify_challenge(&keypair.public(), &challenge, &msg).is_ok());
+
+        // it's only valid from the dialer's identity
+        let other = Keypair::generate_ed25519();
+        assert!(matches!(
+            verify_challenge(&other.public(), &challenge, &msg),
+            Err(Error::InvalidChallengeSignature)
+        ));
+
+        // and only for the challenge of this request
+        assert!(matches!(
+            verify_challenge(&keypair.public(), &new_challenge(), &msg),
+            Err(Error::InvalidChallengeSignature)
+        ));
+    }
+
+    #[test]
+    fn test_resumption() {
+        let (dialer_key, dialer, listener_key, listener, id) = handshake_pair();
+        let listener_payload = listener.payload();
+        let dialer_cipher = dialer
+            .finish(
+                &listener_payload,
+                &listener_key.public().to_peer_id(),
+                Some(&listener_address()),
+                Role::Dialer,
+            )
+            .unwrap();
+        let listener_cipher = Handshake::new(&listener_key, &id, Some(&listener_address()))
+            .unwrap()
+            .finish(
+                &Handshake::new(&dialer_key, &id, None).unwrap().payload(),
+                &dialer_key.public().to_peer_id(),
+                None,
+                Role::Listener,
+            )
+            .unwrap();
+        // a different handshake on the same connection has a different secret
+        assert_ne!(
+            dialer_cipher.resumption_secret(),
+            listener_cipher.resumption_secret()
+        );
+
+        let secret = dialer_cipher.resumption_secret().clone();
+        let request = new_resume_nonce();
+        let response = new_resume_nonce();
+        assert!(proofs_match(
+            &secret.request_proof(&id, &request, 1),
+            &ResumptionSecret::from_bytes(*secret.as_bytes()).request_proof(&id, &request, 1)
+        ));
+        // proofs are bound to the connection, to the nonces, and to the time of the request
+        assert!(!proofs_match(
+            &secret.request_proof(&id, &request, 1),
+            &secret.request_proof(&ConnectionId::generate(), &request, 1)
+        ));
+        assert!(!proofs_match(
+            &secret.request_proof(&id, &request, 1),
+            &secret.request_proof(&id, &request, 2)
+        ));
+        assert!(!proofs_match(
+            &secret.response_proof(&id, &request, &response),
+            &secret.response_proof(&id, &request, &new_resume_nonce())
+        ));
+
+        // both sides derive the same new keys, keeping their roles
+        let dialer_cipher = secret.resume(&id, &request, &response, Role::Dialer);
+        let listener_cipher = secret.resume(&id, &request, &response, Role::Listener);
+        assert_eq!(listener_cipher.role(), Role::Listener);
+        let ciphertext = dialer_cipher.encrypt(b"hello").unwrap();
+        assert_eq!(listener_cipher.decrypt(&ciphertext).unwrap(), b"hello");
+        assert_ne!(dialer_cipher.resumption_secret(), &secret);
+
+        // keys from another resumption of the same ticket don't decrypt them
+        let other = secret.resume(&id, &request, &new_resume_nonce(), Role::Listener);
+        assert!(matches!(
+            other.decrypt(&ciphertext),
+            Err(Error::DecryptionFailure)
+        ));
+    }
+}
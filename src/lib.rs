@@ -1,10 +1,80 @@
-pub(crate) mod connection;
+//! A libp2p [`Transport`](libp2p::core::Transport) over the Nym mixnet.
+//!
+//! The crate is layered so that its parts can be used on their own:
+//! - [`transport`] holds [`NymTransport`], which dials and listens on nym addresses
+//!   and hands each connection to the swarm;
+//! - [`connection`] holds the [`Connection`] it hands over, a
+//!   [`StreamMuxer`](libp2p::core::muxing::StreamMuxer) of [`Substream`]s;
+//! - [`substream`] holds [`Substream`], a reliable byte stream within a connection;
+//! - [`wire`] encodes and decodes the messages they exchange;
+//! - [`mixnet_io`] is the link to the mixnet the messages are sent over.
+//!
+//! Modules hidden from the documentation are kept for the paths existing code
+//! imports from; their contents are re-exported from the modules above.
+
+pub mod address;
+pub(crate) mod address_book;
+#[doc(hidden)]
+pub mod backend;
+pub(crate) mod bandwidth;
+pub(crate) mod batch;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub(crate) mod binding;
+pub(crate) mod channel;
+#[cfg(feature = "chaos")]
+pub(crate) mod chaos;
+pub(crate) mod codec;
+pub(crate) mod compression;
+pub mod config;
+pub mod connection;
+pub mod datagram;
+pub(crate) mod dedup;
 pub mod error;
-pub(crate) mod message;
+pub mod events;
+#[cfg(fuzzing)]
+pub mod fuzz;
+pub(crate) mod handshake;
+pub(crate) mod limit;
+#[doc(hidden)]
+pub mod memory;
+#[doc(hidden)]
+pub mod message;
+pub mod metrics;
+pub(crate) mod migration;
 pub(crate) mod mixnet;
+pub mod mixnet_io;
+pub(crate) mod nonce;
+pub mod nym_stream;
+pub mod observed;
+pub(crate) mod pool;
+pub mod presets;
 pub(crate) mod queue;
+pub(crate) mod relay;
+pub(crate) mod replay;
+pub(crate) mod resumption;
+pub(crate) mod retransmit;
+pub(crate) mod rtt;
+pub(crate) mod runtime;
+pub(crate) mod send_retry;
+pub(crate) mod shared;
+pub mod snapshot;
+pub(crate) mod state;
+pub mod stats;
+pub mod stream;
 pub mod substream;
+pub(crate) mod surb;
+#[cfg(test)]
+pub(crate) mod testing;
+pub(crate) mod throttle;
 pub mod transport;
+pub mod wire;
+
+pub use config::NymTransportConfig;
+pub use connection::Connection;
+pub use error::Error;
+pub use substream::Substream;
+pub use transport::NymTransport;
 
 /// The deafult timeout secs for [`transport::Upgrade`] future.
 const DEFAULT_HANDSHAKE_TIMEOUT_SECS: u64 = 15;
@@ -0,0 +1,372 @@
+use nym_sdk::mixnet::AnonymousSenderTag;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use super::config::AmplificationLimit;
+use super::message::OutboundMessage;
+use super::runtime::Instant;
+
+/// maximum number of sender tags whose invalid messages are counted at once, so that
+/// a peer making up sender tags can't grow the counts without bound.
+const MAX_TRACKED_SENDERS: usize = 4096;
+
+/// TokenBucket rate-limits events: every event takes a token, and tokens are
+/// refilled at a constant rate, up to a maximum that allows short bursts.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    /// tokens added per second
+    rate: f64,
+    /// maximum number of tokens held
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// new returns a full bucket.
+    pub(crate) fn new(rate: u32, capacity: u32) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// try_take takes a token at time `now`, returning false if there are none left.
+    pub(crate) fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// charge takes `tokens` at time `now`, going into debt if there aren't enough.
+    pub(crate) fn charge(&mut self, now: Instant, tokens: f64) {
+        self.refill(now);
+        self.tokens -= tokens;
+    }
+
+    /// delay returns how long after `now` the bucket's debt, if any, is repaid.
+    pub(crate) fn delay(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+/// InboundFilter drops inbound messages before they're parsed if they're too large,
+/// or come from a sender tag that has sent too many invalid messages. Messages without
+/// a sender tag can't be attributed to a sender, so they're never blocked.
+#[derive(Debug)]
+pub(crate) struct InboundFilter {
+    max_message_size: usize,
+    max_invalid_messages: Option<u32>,
+    invalid: HashMap<AnonymousSenderTag, u32>,
+    blocked: HashSet<AnonymousSenderTag>,
+}
+
+impl InboundFilter {
+    pub(crate) fn new(max_message_size: usize, max_invalid_messages: Option<u32>) -> Self {
+        InboundFilter {
+            max_message_size,
+            max_invalid_messages,
+            invalid: HashMap::new(),
+            blocked: HashSet::new(),
+        }
+    }
+
+    /// max_message_size returns the size above which messages are rejected.
+    pub(crate) fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// is_blocked returns true if messages from `sender_tag` are to be dropped.
+    pub(crate) fn is_blocked(&self, sender_tag: Option<&AnonymousSenderTag>) -> bool {
+        sender_tag.is_some_and(|sender_tag| self.blocked.contains(sender_tag))
+    }
+
+    /// on_invalid counts an invalid message from `sender_tag`, returning true if
+    /// it got the sender blocked.
+    pub(crate) fn on_invalid(&mut self, sender_tag: Option<&AnonymousSenderTag>) -> bool {
+        let (Some(max), Some(sender_tag)) = (self.max_invalid_messages, sender_tag) else {
+            return false;
+        };
+        if self.invalid.len() >= MAX_TRACKED_SENDERS && !self.invalid.contains_key(sender_tag) {
+            self.invalid.clear();
+        }
+
+        let count = self.invalid.entry(sender_tag.clone()).or_default();
+        *count += 1;
+        if *count < max {
+            return false;
+        }
+        self.invalid.remove(sender_tag);
+        self.blocked.insert(sender_tag.clone())
+    }
+}
+
+/// Admission is what [`ReplyLimiter::admit`] decided to do with a reply.
+#[derive(Debug)]
+pub(crate) enum Admission {
+    /// the reply can be sent now.
+    Send(OutboundMessage),
+    /// the reply is held until its sender tag sends us more.
+    Held,
+    /// too many replies to the sender tag are held already, so it's dropped.
+    Dropped,
+}
+
+/// Allowance is what we've exchanged with a sender tag that isn't validated yet.
+#[derive(Debug, Default)]
+struct Allowance {
+    received: u64,
+    sent: u64,
+    /// replies over the allowance, with their sizes, in the order they were written.
+    held: VecDeque<(OutboundMessage, u64)>,
+}
+
+/// ReplyLimiter enforces an [`AmplificationLimit`] on the replies the mixnet task
+/// writes to sender tags.
+#[derive(Debug)]
+pub(crate) struct ReplyLimiter {
+    limit: Option<AmplificationLimit>,
+    allowances: HashMap<AnonymousSenderTag, Allowance>,
+    validated: HashSet<AnonymousSenderTag>,
+    /// held replies that fit in their sender tag's allowance again.
+    released: Vec<OutboundMessage>,
+    waker: Option<Waker>,
+}
+
+impl ReplyLimiter {
+    pub(crate) fn new(limit: Option<AmplificationLimit>) -> Self {
+        ReplyLimiter {
+            limit,
+            allowances: HashMap::new(),
+            validated: HashSet::new(),
+            released: vec![],
+            waker: None,
+        }
+    }
+
+    /// on_receive counts `len` bytes received from `sender_tag`, releasing the replies
+    /// held for it that now fit in its allowance.
+    pub(crate) fn on_receive(&mut self, sender_tag: &AnonymousSenderTag, len: usize) {
+        let Some(limit) = self.limit else {
+            return;
+        };
+        if self.validated.contains(sender_tag) {
+            return;
+        }
+        if self.allowances.len() >= MAX_TRACKED_SENDERS && !self.allowances.contains_key(sender_tag)
+        {
+            // forget the senders nothing is held for, rather than lose held replies
+            self.allowances
+                .retain(|_, allowance| !allowance.held.is_empty());
+        }
+        if self.validated.len() >= MAX_TRACKED_SENDERS {
+            self.validated.clear();
+        }
+
+        let allowance = self.allowances.entry(sender_tag.clone()).or_default();
+        allowance.received += len as u64;
+        let validated = allowance.received >= limit.validation_bytes;
+        while let Some((_, size)) = allowance.held.front() {
+            if !validated && allowance.sent + size > allowance.received * limit.factor as u64 {
+                break;
+            }
+            let (message, size) = allowance.held.pop_front().expect("front exists");
+            allowance.sent += size;
+            self.released.push(message);
+        }
+        if validated {
+            self.allowances.remove(sender_tag);
+            self.validated.insert(sender_tag.clone());
+        }
+        if !self.released.is_empty() {
+            if let Some(waker) = self.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// admit decides whether `message`, a reply of `len` bytes to `sender_tag`, can be
+    /// sent now. Replies are kept in order, so one is held while others are.
+    pub(crate) fn admit(
+        &mut self,
+        sender_tag: &AnonymousSenderTag,
+        message: OutboundMessage,
+        len: usize,
+    ) -> Admission {
+        let Some(limit) = self.limit else {
+            return Admission::Send(message);
+        };
+        if self.validated.contains(sender_tag) {
+            return Admission::Send(message);
+        }
+
+        let allowance = self.allowances.entry(sender_tag.clone()).or_default();
+        let len = len as u64;
+        if allowance.held.is_empty()
+            && allowance.sent + len <= allowance.received * limit.factor as u64
+        {
+            allowance.sent += len;
+            return Admission::Send(message);
+        }
+        if allowance.held.len() >= limit.max_held {
+            return Admission::Dropped;
+        }
+        allowance.held.push_back((message, len));
+        Admission::Held
+    }
+
+    /// poll_released resolves with the held replies that can be sent now.
+    pub(crate) fn poll_released(&mut self, cx: &mut Context<'_>) -> Poll<Vec<OutboundMessage>> {
+        if self.released.is_empty() {
+            self.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(std::mem::take(&mut self.released))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::message::{ConnectionCloseMessage, ConnectionId, Message};
+    use super::*;
+    use futures::task::noop_waker;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(2, 3);
+        let now = Instant::now();
+
+        // a full bucket allows a burst
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+
+        // then one token per refill interval
+        let now = now + Duration::from_millis(500);
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+
+        // never more than the capacity
+        let now = now + Duration::from_secs(60);
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+    }
+
+    #[test]
+    fn test_token_bucket_debt() {
+        let mut bucket = TokenBucket::new(2, 3);
+        let now = Instant::now();
+
+        // charges beyond the tokens held are repaid at the refill rate
+        bucket.charge(now, 2.0);
+        assert_eq!(bucket.delay(now), Duration::ZERO);
+        bucket.charge(now, 3.0);
+        assert_eq!(bucket.delay(now), Duration::from_secs(1));
+        assert_eq!(bucket.delay(now + Duration::from_secs(1)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_inbound_filter() {
+        let sender_tag = AnonymousSenderTag::new_random(&mut rand::thread_rng());
+        let other = AnonymousSenderTag::new_random(&mut rand::thread_rng());
+
+        // without a limit nobody is blocked
+        let mut filter = InboundFilter::new(1024, None);
+        for _ in 0..10 {
+            assert!(!filter.on_invalid(Some(&sender_tag)));
+        }
+        assert!(!filter.is_blocked(Some(&sender_tag)));
+
+        let mut filter = InboundFilter::new(1024, Some(3));
+        assert!(!filter.on_invalid(Some(&sender_tag)));
+        assert!(!filter.on_invalid(Some(&sender_tag)));
+        assert!(!filter.on_invalid(Some(&other)));
+        assert!(filter.on_invalid(Some(&sender_tag)));
+        assert!(filter.is_blocked(Some(&sender_tag)));
+        assert!(!filter.is_blocked(Some(&other)));
+
+        // messages without a sender tag are never blocked
+        assert!(!filter.on_invalid(None));
+        assert!(!filter.is_blocked(None));
+    }
+
+    #[test]
+    fn test_reply_limiter() {
+        let sender_tag = AnonymousSenderTag::new_random(&mut rand::thread_rng());
+        let reply = || OutboundMessage {
+            message: Message::ConnectionClose(ConnectionCloseMessage {
+                id: ConnectionId::generate(),
+            }),
+            recipient: None,
+            sender_tag: Some(sender_tag.clone()),
+        };
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut limiter = ReplyLimiter::new(Some(AmplificationLimit {
+            factor: 2,
+            validation_bytes: 100,
+            max_held: 2,
+        }));
+
+        // nothing is sent to a sender tag we haven't heard from
+        assert!(matches!(
+            limiter.admit(&sender_tag, reply(), 10),
+            Admission::Held
+        ));
+        assert!(limiter.poll_released(&mut cx).is_pending());
+        limiter.on_receive(&sender_tag, 10);
+        assert!(
+            matches!(limiter.poll_released(&mut cx), Poll::Ready(released) if released.len() == 1)
+        );
+
+        // 10 of 20 bytes are used, and later replies queue behind a held one
+        assert!(matches!(
+            limiter.admit(&sender_tag, reply(), 15),
+            Admission::Held
+        ));
+        assert!(matches!(
+            limiter.admit(&sender_tag, reply(), 1),
+            Admission::Held
+        ));
+        assert!(matches!(
+            limiter.admit(&sender_tag, reply(), 1),
+            Admission::Dropped
+        ));
+
+        // once validated, everything held is released and nothing is limited
+        limiter.on_receive(&sender_tag, 90);
+        assert!(
+            matches!(limiter.poll_released(&mut cx), Poll::Ready(released) if released.len() == 2)
+        );
+        assert!(matches!(
+            limiter.admit(&sender_tag, reply(), 10_000),
+            Admission::Send(_)
+        ));
+
+        // without a limit, replies are always sent
+        let mut limiter = ReplyLimiter::new(None);
+        assert!(matches!(
+            limiter.admit(&sender_tag, reply(), 10_000),
+            Admission::Send(_)
+        ));
+    }
+}
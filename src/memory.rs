@@ -0,0 +1,366 @@
+use futures::{future::BoxFuture, FutureExt};
+use libp2p_identity::ed25519;
+use log::debug;
+use nym_sdk::mixnet::{AnonymousSenderTag, IncludedSurbs};
+use nym_sphinx::addressing::clients::Recipient;
+use nym_sphinx::receiver::ReconstructedMessage;
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use super::backend::{MixnetBackend, MixnetBackendSender};
+use super::error::Error;
+use super::runtime::{sleep, spawn};
+
+/// InMemoryConfig describes how an [`InMemoryMixnet`] mistreats the messages sent through it.
+/// The default config delivers every message once, immediately and in order.
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryConfig {
+    /// each message is delivered after a delay chosen uniformly between these bounds.
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    /// probability of a message being lost.
+    pub drop_probability: f64,
+    /// probability of a message being delivered twice, each copy with its own delay.
+    pub duplicate_probability: f64,
+    /// probability of a message being held back for `reorder_delay` on top of its
+    /// delay, so that messages sent after it overtake it.
+    pub reorder_probability: f64,
+    pub reorder_delay: Duration,
+    /// seed of the random choices above, so that a test makes the same ones every run.
+    pub seed: u64,
+}
+
+impl InMemoryConfig {
+    /// Set the delay bounds and return self.
+    pub fn with_delay(mut self, min: Duration, max: Duration) -> Self {
+        self.min_delay = min;
+        self.max_delay = max.max(min);
+        self
+    }
+
+    /// Set the drop probability and return self.
+    pub fn with_drops(mut self, probability: f64) -> Self {
+        self.drop_probability = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Set the duplicate probability and return self.
+    pub fn with_duplicates(mut self, probability: f64) -> Self {
+        self.duplicate_probability = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Set the reorder probability and how long reordered messages are held back, and return self.
+    pub fn with_reordering(mut self, probability: f64, delay: Duration) -> Self {
+        self.reorder_probability = probability.clamp(0.0, 1.0);
+        self.reorder_delay = delay;
+        self
+    }
+
+    /// Set the seed and return self.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+struct Shared {
+    config: InMemoryConfig,
+    rng: StdRng,
+
+    /// address -> channel of the messages delivered to the client
+    clients: HashMap<Recipient, UnboundedSender<ReconstructedMessage>>,
+
+    /// address -> tag which the client's SURBs are delivered with
+    tags: HashMap<Recipient, AnonymousSenderTag>,
+
+    /// tag -> address replies using the tag's SURBs are delivered to
+    surbs: HashMap<AnonymousSenderTag, Recipient>,
+}
+
+impl Shared {
+    fn delay(&mut self) -> Duration {
+        let mut delay = if self.config.max_delay > self.config.min_delay {
+            self.rng
+                .gen_range(self.config.min_delay..=self.config.max_delay)
+        } else {
+            self.config.min_delay
+        };
+        if self.rng.gen_bool(self.config.reorder_probability) {
+            delay += self.config.reorder_delay;
+        }
+        delay
+    }
+}
+
+/// InMemoryMixnet is a stand-in for the mixnet which passes messages between the
+/// [`InMemoryClient`]s it creates within the process. It can delay, reorder, drop and
+/// duplicate messages, so that the transport's handling of an unreliable network
+/// can be tested without the live mixnet.
+/// SURBs are never used up: a reply can always be sent to a client which sent us SURBs.
+#[derive(Clone)]
+pub struct InMemoryMixnet {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl InMemoryMixnet {
+    /// New in-memory mixnet, with no clients.
+    pub fn new(config: InMemoryConfig) -> Self {
+        let rng = StdRng::seed_from_u64(config.seed);
+        InMemoryMixnet {
+            shared: Arc::new(Mutex::new(Shared {
+                config,
+                rng,
+                clients: HashMap::new(),
+                tags: HashMap::new(),
+                surbs: HashMap::new(),
+            })),
+        }
+    }
+
+    /// client returns a new client of the mixnet, with its own nym address.
+    /// Pass it to [`NymTransport::new_with_backend`](crate::transport::NymTransport::new_with_backend).
+    pub fn client(&self) -> InMemoryClient {
+        let (tx, rx) = unbounded_channel();
+        let mut guard = self.shared.lock();
+        let shared = &mut *guard;
+        let address = generate_address(&mut shared.rng);
+        let tag = AnonymousSenderTag::new_random(&mut shared.rng);
+        shared.clients.insert(address, tx);
+        shared.tags.insert(address, tag.clone());
+        shared.surbs.insert(tag, address);
+        InMemoryClient {
+            address,
+            mixnet: self.clone(),
+            rx,
+        }
+    }
+
+    /// deliver hands `message` to the client at `recipient`, subject to the config's
+    /// delays and faults. Messages to unknown addresses are lost, as on the mixnet.
+    fn deliver(
+        &self,
+        recipient: &Recipient,
+        message: &[u8],
+        sender_tag: Option<AnonymousSenderTag>,
+    ) {
+        let mut guard = self.shared.lock();
+        let shared = &mut *guard;
+        let Some(tx) = shared.clients.get(recipient).cloned() else {
+            debug!("dropping message to unknown address {}", recipient);
+            return;
+        };
+        if shared.rng.gen_bool(shared.config.drop_probability) {
+            return;
+        }
+
+        let copies = if shared.rng.gen_bool(shared.config.duplicate_probability) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let delay = shared.delay();
+            let message = ReconstructedMessage {
+                message: message.to_vec(),
+                sender_tag: sender_tag.clone(),
+            };
+            if delay.is_zero() {
+                // the client may have disconnected since
+                tx.send(message).ok();
+                continue;
+            }
+            let tx = tx.clone();
+            spawn(async move {
+                sleep(delay).await;
+                tx.send(message).ok();
+            });
+        }
+    }
+}
+
+/// generate_address returns a random nym address.
+fn generate_address(rng: &mut StdRng) -> Recipient {
+    let mut bytes = [0u8; Recipient::LEN];
+    // the client and gateway identities have to be valid ed25519 public keys,
+    // while any bytes make an x25519 encryption key
+    rng.fill_bytes(&mut bytes);
+    for range in [0..32, 64..96] {
+        let mut secret = [0u8; 32];
+        rng.fill_bytes(&mut secret);
+        let secret =
+            ed25519::SecretKey::try_from_bytes(&mut secret).expect("any 32 bytes are a secret key");
+        bytes[range].copy_from_slice(&ed25519::Keypair::from(secret).public().to_bytes());
+    }
+    Recipient::try_from_bytes(bytes).expect("generated address is valid")
+}
+
+/// InMemoryClient is a client of an [`InMemoryMixnet`].
+pub struct InMemoryClient {
+    address: Recipient,
+    mixnet: InMemoryMixnet,
+    rx: UnboundedReceiver<ReconstructedMessage>,
+}
+
+impl InMemoryClient {
+    /// address returns the client's nym address.
+    pub fn address(&self) -> Recipient {
+        self.address
+    }
+}
+
+impl MixnetBackend for InMemoryClient {
+    fn our_address(&self) -> Recipient {
+        self.address
+    }
+
+    fn sender(&self) -> Box<dyn MixnetBackendSender> {
+        Box::new(InMemorySender {
+            address: self.address,
+            mixnet: self.mixnet.clone(),
+        })
+    }
+
+    fn next(&mut self) -> BoxFuture<'_, Option<ReconstructedMessage>> {
+        self.rx.recv().boxed()
+    }
+
+    fn disconnect(self: Box<Self>) -> BoxFuture<'static, ()> {
+        let mut shared = self.mixnet.shared.lock();
+        shared.clients.remove(&self.address);
+        if let Some(tag) = shared.tags.remove(&self.address) {
+            shared.surbs.remove(&tag);
+        }
+        async {}.boxed()
+    }
+}
+
+struct InMemorySender {
+    address: Recipient,
+    mixnet: InMemoryMixnet,
+}
+
+impl MixnetBackendSender for InMemorySender {
+    fn send<'a>(
+        &'a self,
+        recipient: Recipient,
+        message: &'a [u8],
+        surbs: IncludedSurbs,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let sender_tag = match surbs {
+            IncludedSurbs::ExposeSelfAddress => None,
+            _ => self.mixnet.shared.lock().tags.get(&self.address).cloned(),
+        };
+        self.mixnet.deliver(&recipient, message, sender_tag);
+        async { Ok(()) }.boxed()
+    }
+
+    fn send_reply<'a>(
+        &'a self,
+        sender_tag: AnonymousSenderTag,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let recipient = self.mixnet.shared.lock().surbs.get(&sender_tag).copied();
+        let res = match recipient {
+            Some(recipient) => {
+                self.mixnet.deliver(&recipient, message, None);
+                Ok(())
+            }
+            None => Err(Error::SurbExhausted(sender_tag)),
+        };
+        async move { res }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::testing::recv_all;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let mut alice = mixnet.client();
+        let mut bob = mixnet.client();
+        assert_ne!(alice.address(), bob.address());
+
+        // a message sent with SURBs arrives with a tag to reply with
+        let sender = alice.sender();
+        sender
+            .send(bob.address(), b"hello", IncludedSurbs::Amount(10))
+            .await
+            .unwrap();
+        let message = bob.next().await.unwrap();
+        assert_eq!(message.message, b"hello");
+        let sender_tag = message.sender_tag.unwrap();
+
+        // and the reply arrives without one
+        bob.sender().send_reply(sender_tag, b"world").await.unwrap();
+        let message = alice.next().await.unwrap();
+        assert_eq!(message.message, b"world");
+        assert!(message.sender_tag.is_none());
+
+        // a message sent exposing our address arrives without a tag
+        sender
+            .send(bob.address(), b"hello", IncludedSurbs::ExposeSelfAddress)
+            .await
+            .unwrap();
+        assert!(bob.next().await.unwrap().sender_tag.is_none());
+
+        // once alice disconnects, her SURBs lead nowhere
+        Box::new(alice).disconnect().await;
+        assert!(bob.sender().send_reply(sender_tag, b"world").await.is_err());
+    }
+
+    /// send_all sends 20 numbered messages between two clients of a mixnet with the
+    /// given config, and returns the numbers of the messages that arrived.
+    async fn send_all(config: InMemoryConfig) -> Vec<u8> {
+        let mixnet = InMemoryMixnet::new(config.with_seed(7));
+        let alice = mixnet.client();
+        let mut bob = mixnet.client();
+        let sender = alice.sender();
+        for i in 0..20u8 {
+            sender
+                .send(bob.address(), &[i], IncludedSurbs::Amount(1))
+                .await
+                .unwrap();
+        }
+        recv_all(&mut bob)
+            .await
+            .into_iter()
+            .map(|message| message.message[0])
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_mixnet_faults() {
+        let sent: Vec<u8> = (0..20).collect();
+
+        assert_eq!(send_all(InMemoryConfig::default()).await, sent);
+        assert!(send_all(InMemoryConfig::default().with_drops(1.0))
+            .await
+            .is_empty());
+        assert_eq!(
+            send_all(InMemoryConfig::default().with_duplicates(1.0))
+                .await
+                .len(),
+            40
+        );
+
+        // reordered messages all arrive, just not in the order they were sent
+        let mut received =
+            send_all(InMemoryConfig::default().with_reordering(0.5, Duration::from_millis(20)))
+                .await;
+        assert_ne!(received, sent);
+        received.sort();
+        assert_eq!(received, sent);
+
+        // and the same seed makes the same choices
+        let config = InMemoryConfig::default()
+            .with_drops(0.3)
+            .with_duplicates(0.3);
+        assert_eq!(send_all(config.clone()).await, send_all(config).await);
+    }
+}
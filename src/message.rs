@@ -1,18 +1,91 @@
+use bytes::{BufMut, Bytes, BytesMut};
 use libp2p::core::PeerId;
+use libp2p_identity::PublicKey;
+use log::warn;
 use nym_sdk::mixnet::AnonymousSenderTag;
 use nym_sphinx::addressing::clients::Recipient;
 use rand::rngs::OsRng;
 use rand::RngCore;
-use std::fmt::{Debug, Formatter};
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::time::Duration;
+use tracing::{debug_span, Span};
 
+#[cfg(any(test, fuzzing, feature = "bench"))]
+use super::codec::{decode_inbound, NativeCodec};
 use super::error::Error;
+use super::handshake::{HandshakePayload, CHALLENGE_LEN, RESUME_NONCE_LEN, RESUME_PROOF_LEN};
+use super::nonce::Nonce;
+use super::runtime::Instant;
 
-const CONNECTION_ID_LENGTH: usize = 32;
+pub mod test_vectors;
+
+/// PROTOCOL_VERSION is the version of the wire format spoken by this transport.
+/// It's sent at the start of every ConnectionMessage, and must be incremented
+/// whenever the framing changes in a way that older peers can't parse, or the
+/// handshake in a way that they'd reject.
+pub(crate) const PROTOCOL_VERSION: u8 = 8;
+
+pub(crate) const CONNECTION_ID_LENGTH: usize = 32;
 const SUBSTREAM_ID_LENGTH: usize = 32;
 
+const CAPABILITIES_BYTES_LEN: usize = 4; // length of u32
+
+// protocol version (u8) + capabilities (u32) + connection ID.
+// this layout must be kept in every protocol version, so that a peer can tell
+// which connection a message of another version belongs to.
+const CONNECTION_HEADER_LEN: usize = 1 + CAPABILITIES_BYTES_LEN + CONNECTION_ID_LENGTH;
+
 const NONCE_BYTES_LEN: usize = 8; // length of u64
 const MIN_CONNECTION_MESSAGE_LEN: usize = CONNECTION_ID_LENGTH + NONCE_BYTES_LEN;
 
+const KEEPALIVE_SEQ_BYTES_LEN: usize = 8; // length of u64
+const KEEPALIVE_MESSAGE_LEN: usize = 1 + KEEPALIVE_SEQ_BYTES_LEN + CONNECTION_ID_LENGTH;
+
+const ACK_MESSAGE_LEN: usize = NONCE_BYTES_LEN + CONNECTION_ID_LENGTH;
+
+const TIMESTAMP_BYTES_LEN: usize = 8; // length of u64
+
+const MIGRATE_SEQ_BYTES_LEN: usize = 8; // length of u64
+
+// reservation ID + status (u8) + reservation lifetime in seconds (u32)
+const RELAY_STATUS_MESSAGE_LEN: usize = CONNECTION_ID_LENGTH + 1 + 4;
+
+// datagram kind (u8) + request ID
+const DATAGRAM_HEADER_LEN: usize = 1 + CONNECTION_ID_LENGTH;
+
+// entry length (u16) + nonce, in front of every TransportMessage in a batch
+const BATCH_ENTRY_HEADER_LEN: usize = 2 + NONCE_BYTES_LEN;
+
+// payload ID (u32) + fragment index (u16) + fragment count (u16)
+const FRAGMENT_HEADER_LEN: usize = 4 + 2 + 2;
+
+// everything in front of the payload of a fragmented TransportMessage, which is the
+// longest header of the messages that carry substream data
+const MAX_TRANSPORT_HEADER_LEN: usize = 1
+    + TIMESTAMP_BYTES_LEN
+    + MIN_CONNECTION_MESSAGE_LEN
+    + SUBSTREAM_ID_LENGTH
+    + 1
+    + FRAGMENT_HEADER_LEN;
+
+// room a message needs around the data of a fragment, with either codec: the longest
+// transport header, plus the length the borsh codec prefixes the data with
+const MAX_FRAGMENT_OVERHEAD: usize = MAX_TRANSPORT_HEADER_LEN + 4;
+
+/// MIN_MAX_MESSAGE_SIZE is the smallest maximum message size a peer may advertise;
+/// a connection with a peer that advertises less is refused, since it'd take more
+/// fragments than a payload may be split into to send it anything.
+pub(crate) const MIN_MAX_MESSAGE_SIZE: u32 = 512;
+
+/// Direction is whether a message is one we received or one we're sending,
+/// as recorded on its tracing span.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Direction {
+    Inbound,
+    Outbound,
+}
+
 /// ConnectionId is a unique, randomly-generated per-connection ID that's used to
 /// identify which connection a message belongs to.
 #[derive(Clone, Default, Eq, Hash, PartialEq)]
@@ -25,11 +98,15 @@ impl ConnectionId {
         ConnectionId(bytes)
     }
 
-    fn from_bytes(bytes: &[u8]) -> Self {
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Self {
         let mut id = [0u8; 32];
         id[..].copy_from_slice(&bytes[0..CONNECTION_ID_LENGTH]);
         ConnectionId(id)
     }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
 }
 
 impl Debug for ConnectionId {
@@ -69,6 +146,60 @@ pub(crate) enum Message {
     ConnectionRequest(ConnectionMessage),
     ConnectionResponse(ConnectionMessage),
     TransportMessage(TransportMessage),
+    KeepAlive(KeepAliveMessage),
+    Ack(AckMessage),
+    Datagram(DatagramMessage),
+    VersionMismatch(VersionMismatch),
+    Batch(BatchMessage),
+    ConnectionClose(ConnectionCloseMessage),
+    Migrate(MigrateMessage),
+    ChallengeResponse(ChallengeResponseMessage),
+    SessionTicket(SessionTicketMessage),
+    ResumeRequest(ResumeRequestMessage),
+    ResumeResponse(ResumeResponseMessage),
+    RelayReserve(RelayReserveMessage),
+    RelayStatus(RelayStatusMessage),
+    Relay(RelayMessage),
+}
+
+/// Capabilities is a bitfield of the optional protocol features a peer uses,
+/// advertised in its ConnectionMessages. Unknown bits are ignored, so features
+/// can be added without bumping the protocol version.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Capabilities(pub(crate) u32);
+
+impl Capabilities {
+    /// the peer retransmits unacknowledged TransportMessages, so wants each one acknowledged.
+    pub(crate) const RETRANSMIT: Capabilities = Capabilities(1 << 0);
+    /// the peer can decompress LZ4-compressed substream payloads, and compresses its own
+    /// if the remote can too.
+    pub(crate) const COMPRESSION: Capabilities = Capabilities(1 << 1);
+    /// the ConnectionRequest was sent through a reply route: the SURBs of an existing
+    /// connection to a peer whose address the dialer doesn't know. The listener answers
+    /// with the identity it opened that connection with, and doesn't reveal its address.
+    pub(crate) const REPLY_ROUTE: Capabilities = Capabilities(1 << 2);
+    /// the peer drops control frames that took too long to arrive, so wants its remote
+    /// to stamp them with the time they were sent.
+    pub(crate) const TIMESTAMPS: Capabilities = Capabilities(1 << 3);
+    /// the peer understands datagrams sent on a connection, outside of its substreams.
+    pub(crate) const DATAGRAMS: Capabilities = Capabilities(1 << 4);
+    /// the peer follows its remote to a new nym address when told to by a MigrateMessage.
+    pub(crate) const MIGRATION: Capabilities = Capabilities(1 << 5);
+    /// the peer resumes a connection with the session ticket its remote issued it, if
+    /// the remote loses the connection's state; tickets are only issued to such peers.
+    pub(crate) const RESUMPTION: Capabilities = Capabilities(1 << 6);
+
+    pub(crate) fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
 }
 
 /// ConnectionMessage is exchanged to open a new connection.
@@ -76,11 +207,134 @@ pub(crate) enum Message {
 pub(crate) struct ConnectionMessage {
     pub(crate) peer_id: PeerId,
     pub(crate) id: ConnectionId,
-    // only required if this is a ConnectionRequest.
-    // this is the nym address of the initiator of a connection request, so the recipient could use it to reply. Lets keep that as a None for the moment.
-    // pub(crate) recipient: Option<Recipient>,
+    /// the optional features the sender uses on this connection.
+    pub(crate) capabilities: Capabilities,
+    /// only set on a ConnectionRequest from a dialer that exposes its address.
+    /// this is the nym address of the initiator of the connection request; if set,
+    /// the recipient replies to it directly instead of using SURBs.
+    pub(crate) recipient: Option<Recipient>,
+    /// the sender's half of the key exchange, which also proves it holds the
+    /// identity key `peer_id` is derived from.
+    pub(crate) handshake: HandshakePayload,
+    /// only set on a ConnectionResponse: random bytes the dialer signs and returns in a
+    /// ChallengeResponse, before the listener hands the connection over, to prove that
+    /// the ConnectionRequest wasn't replayed.
+    pub(crate) challenge: Option<[u8; CHALLENGE_LEN]>,
+    /// only set on a ConnectionResponse to a dialer that exposes its address: the nym
+    /// address the listener received the ConnectionRequest from, which tells the dialer
+    /// the address other peers can reach it at.
+    pub(crate) observed: Option<Recipient>,
+    /// the largest message the sender accepts; the remote splits what it sends on the
+    /// connection into fragments that fit.
+    pub(crate) max_message_size: u32,
+    /// only set on a ConnectionRequest to a `/nym/<address>/svc/<service>` multiaddr: the
+    /// service whose listener the connection is reported on.
+    pub(crate) service: Option<u16>,
+    /// what the sender tells the remote about itself.
+    pub(crate) info: ConnectionInfo,
+}
+
+/// negotiated_fragment_size returns the most payload bytes to send in a single message
+/// to a remote that accepts messages of up to `remote_max_message_size` bytes, when we
+/// send at most `max_fragment_size`: whichever leaves the smaller message.
+pub(crate) fn negotiated_fragment_size(
+    max_fragment_size: usize,
+    remote_max_message_size: u32,
+) -> usize {
+    let remote = (remote_max_message_size as usize).saturating_sub(MAX_FRAGMENT_OVERHEAD);
+    max_fragment_size.min(remote).max(1)
+}
+
+/// max_datagram_payload returns the most payload bytes a datagram sent outside of any
+/// connection may carry to a remote that accepts messages of up to `max_message_size` bytes.
+pub(crate) fn max_datagram_payload(max_message_size: usize) -> usize {
+    max_message_size.saturating_sub(MAX_FRAGMENT_OVERHEAD)
+}
+
+/// ConnectionInfo is what a peer tells the remote about itself when a connection is
+/// opened, which would otherwise take an identify round trip over the mixnet to learn.
+/// It isn't covered by the handshake signature.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// the peer's agent version, e.g. `my-app/1.0`; at most 255 bytes are sent.
+    pub agent_version: Option<String>,
+    /// application-defined extensions the peer supports; at most 255 are sent,
+    /// of at most 255 bytes each.
+    pub extensions: Vec<String>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn encode(&self, bytes: &mut BytesMut) {
+        put_str(bytes, self.agent_version.as_deref().unwrap_or_default());
+        let count = self.extensions.len().min(u8::MAX as usize);
+        bytes.put_u8(count as u8);
+        for extension in &self.extensions[..count] {
+            put_str(bytes, extension);
+        }
+    }
+
+    pub(crate) fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let (agent_version, mut rest) = take_str(bytes)?;
+        let (&count, tail) = rest
+            .split_first()
+            .ok_or(Error::ConnectionMessageBytesTooShort)?;
+        rest = tail;
+        let mut extensions = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let (extension, tail) = take_str(rest)?;
+            extensions.push(extension);
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            return Err(Error::InvalidMessageBytes);
+        }
+        Ok(ConnectionInfo {
+            agent_version: (!agent_version.is_empty()).then_some(agent_version),
+            extensions,
+        })
+    }
+}
+
+/// put_str writes `s` prefixed by its length as a u8, cut short at a char
+/// boundary if it's longer than that allows.
+fn put_str(bytes: &mut BytesMut, s: &str) {
+    let mut len = s.len().min(u8::MAX as usize);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    bytes.put_u8(len as u8);
+    bytes.extend_from_slice(&s.as_bytes()[..len]);
+}
+
+/// take_str reads a string written by [`put_str`], returning it and the bytes after it.
+fn take_str(bytes: &[u8]) -> Result<(String, &[u8]), Error> {
+    let (&len, rest) = bytes
+        .split_first()
+        .ok_or(Error::ConnectionMessageBytesTooShort)?;
+    if rest.len() < len as usize {
+        return Err(Error::ConnectionMessageBytesTooShort);
+    }
+    let (s, rest) = rest.split_at(len as usize);
+    let s = std::str::from_utf8(s).map_err(|_| Error::InvalidMessageBytes)?;
+    Ok((s.to_string(), rest))
+}
+
+/// VersionMismatch is a ConnectionRequest or ConnectionResponse of a protocol version
+/// other than ours, of which only the header can be parsed. A listener answers a
+/// request of another version with a VersionMismatch response of its own version,
+/// so that the dialer fails with [`Error::UnsupportedVersion`] instead of timing out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct VersionMismatch {
+    pub(crate) id: ConnectionId,
+    pub(crate) request: bool,
+    /// the protocol version the message was sent with.
+    pub(crate) version: u8,
 }
 
+/// DATAGRAM_NONCE is the nonce of every datagram. Sequenced messages start at nonce 1,
+/// so it's never mistaken for one of them.
+pub(crate) const DATAGRAM_NONCE: Nonce = Nonce::new(0);
+
 /// TransportMessage is sent over a connection after establishment.
 #[derive(Debug, Clone)]
 pub(crate) struct TransportMessage {
@@ -89,72 +343,379 @@ pub(crate) struct TransportMessage {
     /// ConnectionMessages do not need nonces, as we know that they will
     /// be the first messages sent over a connection.
     /// the first TransportMessage sent over a connection will have nonce 1.
-    pub(crate) nonce: u64,
+    /// datagrams aren't ordered, and are always sent with [`DATAGRAM_NONCE`].
+    pub(crate) nonce: Nonce,
     pub(crate) message: SubstreamMessage,
     pub(crate) id: ConnectionId,
+    /// when the message was sent, in milliseconds since the UNIX epoch; only set on
+    /// control frames sent to a peer that drops stale ones. A stamped message is sent
+    /// with its own message type, and never batched.
+    pub(crate) timestamp: Option<u64>,
 }
 
 impl Message {
-    fn try_from_bytes(bytes: Vec<u8>) -> Result<Self, Error> {
+    /// connection_id returns the ID of the connection the message belongs to.
+    pub(crate) fn connection_id(&self) -> &ConnectionId {
+        match self {
+            Message::ConnectionRequest(msg) | Message::ConnectionResponse(msg) => &msg.id,
+            Message::TransportMessage(msg) => &msg.id,
+            Message::KeepAlive(msg) => &msg.id,
+            Message::Ack(msg) => &msg.id,
+            Message::Datagram(msg) => &msg.id,
+            Message::VersionMismatch(msg) => &msg.id,
+            Message::Batch(msg) => &msg.id,
+            Message::ConnectionClose(msg) => &msg.id,
+            Message::Migrate(msg) => &msg.id,
+            Message::ChallengeResponse(msg) => &msg.id,
+            Message::SessionTicket(msg) => &msg.id,
+            Message::ResumeRequest(msg) => &msg.id,
+            Message::ResumeResponse(msg) => &msg.id,
+            Message::RelayReserve(msg) => &msg.id,
+            Message::RelayStatus(msg) => &msg.id,
+            Message::Relay(msg) => &msg.id,
+        }
+    }
+
+    /// kind returns a short name for the type of the message, used to label metrics.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Message::ConnectionRequest(_) => "connection_request",
+            Message::ConnectionResponse(_) => "connection_response",
+            Message::TransportMessage(msg) => msg.message.message_type.kind(),
+            Message::KeepAlive(_) => "keepalive",
+            Message::Ack(_) => "ack",
+            Message::Datagram(msg) => match msg.kind {
+                DatagramKind::Request => "datagram_request",
+                DatagramKind::Response => "datagram_response",
+            },
+            Message::VersionMismatch(_) => "version_mismatch",
+            Message::Batch(_) => "batch",
+            Message::ConnectionClose(_) => "connection_close",
+            Message::Migrate(_) => "migrate",
+            Message::ChallengeResponse(_) => "challenge_response",
+            Message::SessionTicket(_) => "session_ticket",
+            Message::ResumeRequest(_) => "resume_request",
+            Message::ResumeResponse(_) => "resume_response",
+            Message::RelayReserve(_) => "relay_reserve",
+            Message::RelayStatus(_) => "relay_status",
+            Message::Relay(_) => "relay",
+        }
+    }
+
+    /// span returns the tracing span the message is handled in, which carries its
+    /// connection ID, and its substream ID and nonce if it's a TransportMessage.
+    pub(crate) fn span(&self, direction: Direction) -> Span {
+        match self {
+            Message::TransportMessage(msg) => msg.span(direction),
+            msg => debug_span!(
+                "message",
+                ?direction,
+                kind = msg.kind(),
+                connection_id = ?msg.connection_id(),
+            ),
+        }
+    }
+
+    /// payload_len returns the number of payload bytes the message carries, if any.
+    fn payload_len(&self) -> usize {
+        match self {
+            Message::TransportMessage(msg) => match &msg.message.message_type {
+                SubstreamMessageType::Data(data) | SubstreamMessageType::Datagram(data) => {
+                    data.len()
+                }
+                SubstreamMessageType::Fragment(fragment) => fragment.data.len(),
+                _ => 0,
+            },
+            Message::Datagram(msg) => msg.payload.len(),
+            Message::Batch(msg) => msg.messages.iter().map(BatchMessage::entry_len).sum(),
+            Message::Relay(msg) => msg.payload.len(),
+            _ => 0,
+        }
+    }
+
+    /// try_from_bytes decodes a message. Payloads are slices of `bytes` rather than copies.
+    pub(crate) fn try_from_bytes(bytes: Bytes) -> Result<Self, Error> {
         if bytes.len() < 2 {
             return Err(Error::InvalidMessageBytes);
         }
 
         Ok(match bytes[0] {
-            0 => Message::ConnectionRequest(ConnectionMessage::try_from_bytes(&bytes[1..])?),
-            1 => Message::ConnectionResponse(ConnectionMessage::try_from_bytes(&bytes[1..])?),
-            2 => Message::TransportMessage(TransportMessage::try_from_bytes(&bytes[1..])?),
-            _ => return Err(Error::InvalidMessageBytes),
+            0 | 1 => {
+                let request = bytes[0] == 0;
+                match ConnectionMessage::try_from_bytes(&bytes[1..]) {
+                    Ok(msg) if request => Message::ConnectionRequest(msg),
+                    Ok(msg) => Message::ConnectionResponse(msg),
+                    Err(Error::UnsupportedVersion(version)) => {
+                        Message::VersionMismatch(VersionMismatch {
+                            id: ConnectionId::from_bytes(&bytes[1 + 1 + CAPABILITIES_BYTES_LEN..]),
+                            request,
+                            version,
+                        })
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            2 => Message::TransportMessage(TransportMessage::try_from_bytes(bytes.slice(1..))?),
+            3 => Message::KeepAlive(KeepAliveMessage::try_from_bytes(&bytes[1..])?),
+            4 => Message::Ack(AckMessage::try_from_bytes(&bytes[1..])?),
+            5 => Message::Datagram(DatagramMessage::try_from_bytes(bytes.slice(1..))?),
+            6 => Message::Batch(BatchMessage::try_from_bytes(bytes.slice(1..))?),
+            7 => Message::ConnectionClose(ConnectionCloseMessage::try_from_bytes(&bytes[1..])?),
+            8 => {
+                let (timestamp, bytes) = take_timestamp(bytes.slice(1..))?;
+                let mut msg = TransportMessage::try_from_bytes(bytes)?;
+                msg.timestamp = Some(timestamp);
+                Message::TransportMessage(msg)
+            }
+            9 => {
+                let (timestamp, bytes) = take_timestamp(bytes.slice(1..))?;
+                let mut msg = KeepAliveMessage::try_from_bytes(&bytes)?;
+                msg.timestamp = Some(timestamp);
+                Message::KeepAlive(msg)
+            }
+            10 => Message::Migrate(MigrateMessage::try_from_bytes(&bytes[1..])?),
+            11 => {
+                Message::ChallengeResponse(ChallengeResponseMessage::try_from_bytes(&bytes[1..])?)
+            }
+            12 => Message::SessionTicket(SessionTicketMessage::try_from_bytes(&bytes[1..])?),
+            13 => Message::ResumeRequest(ResumeRequestMessage::try_from_bytes(&bytes[1..])?),
+            14 => Message::ResumeResponse(ResumeResponseMessage::try_from_bytes(&bytes[1..])?),
+            15 => Message::RelayReserve(RelayReserveMessage::try_from_bytes(&bytes[1..])?),
+            16 => Message::RelayStatus(RelayStatusMessage::try_from_bytes(&bytes[1..])?),
+            17 => Message::Relay(RelayMessage::try_from_bytes(bytes.slice(1..))?),
+            kind => return Err(Error::UnknownMessageType(kind)),
         })
     }
 }
 
+/// take_timestamp splits the timestamp off the front of a stamped message.
+fn take_timestamp(bytes: Bytes) -> Result<(u64, Bytes), Error> {
+    if bytes.len() < TIMESTAMP_BYTES_LEN {
+        return Err(Error::InvalidMessageBytes);
+    }
+    let timestamp = u64::from_be_bytes(
+        bytes[..TIMESTAMP_BYTES_LEN]
+            .try_into()
+            .map_err(|_| Error::InvalidMessageBytes)?,
+    );
+    Ok((timestamp, bytes.slice(TIMESTAMP_BYTES_LEN..)))
+}
+
+/// is_stale returns true if a message stamped with `timestamp` was sent more than
+/// `max_age` before `now`, both in milliseconds since the UNIX epoch, allowing for the
+/// sender's clock to be up to `clock_skew` behind ours. A message stamped in the future
+/// is never stale.
+pub(crate) fn is_stale(timestamp: u64, now: u64, max_age: Duration, clock_skew: Duration) -> bool {
+    let age = Duration::from_millis(now.saturating_sub(timestamp));
+    age > max_age.saturating_add(clock_skew)
+}
+
 impl ConnectionMessage {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = self.id.0.to_vec();
-        bytes.append(&mut self.peer_id.to_bytes());
-        bytes
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.put_u8(PROTOCOL_VERSION);
+        bytes.put_u32(self.capabilities.0);
+        bytes.extend_from_slice(&self.id.0);
+        match &self.recipient {
+            Some(recipient) => {
+                bytes.put_u8(1);
+                bytes.extend_from_slice(&recipient.to_bytes());
+            }
+            None => bytes.put_u8(0),
+        }
+        bytes.extend_from_slice(&self.handshake.to_bytes());
+        let peer_id = self.peer_id.to_bytes();
+        bytes.put_u8(peer_id.len() as u8);
+        bytes.extend_from_slice(&peer_id);
+        match &self.challenge {
+            Some(challenge) => {
+                bytes.put_u8(1);
+                bytes.extend_from_slice(challenge);
+            }
+            None => bytes.put_u8(0),
+        }
+        match &self.observed {
+            Some(observed) => {
+                bytes.put_u8(1);
+                bytes.extend_from_slice(&observed.to_bytes());
+            }
+            None => bytes.put_u8(0),
+        }
+        bytes.put_u32(self.max_message_size);
+        match self.service {
+            Some(service) => {
+                bytes.put_u8(1);
+                bytes.put_u16(service);
+            }
+            None => bytes.put_u8(0),
+        }
+        self.info.encode(bytes);
     }
 
+    /// try_from_bytes decodes a ConnectionMessage, or returns [`Error::UnsupportedVersion`]
+    /// without looking past the header if it's of another protocol version.
     fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
-        if bytes.len() < CONNECTION_ID_LENGTH + 1 {
+        if bytes.len() < CONNECTION_HEADER_LEN {
+            return Err(Error::ConnectionMessageBytesTooShort);
+        }
+        if bytes[0] != PROTOCOL_VERSION {
+            return Err(Error::UnsupportedVersion(bytes[0]));
+        }
+        let capabilities = Capabilities(u32::from_be_bytes(
+            bytes[1..1 + CAPABILITIES_BYTES_LEN]
+                .try_into()
+                .map_err(|_| Error::ConnectionMessageBytesTooShort)?,
+        ));
+
+        let bytes = &bytes[1 + CAPABILITIES_BYTES_LEN..];
+        if bytes.len() < CONNECTION_ID_LENGTH + 2 {
             return Err(Error::ConnectionMessageBytesTooShort);
         }
 
         let id = ConnectionId::from_bytes(&bytes[0..CONNECTION_ID_LENGTH]);
 
-        let peer_id = PeerId::from_bytes(&bytes[CONNECTION_ID_LENGTH..])
-            .map_err(|_| Error::InvalidPeerIdBytes)?;
+        let (recipient, handshake_start) = match bytes[CONNECTION_ID_LENGTH] {
+            0 => (None, CONNECTION_ID_LENGTH + 1),
+            1 => {
+                let end = CONNECTION_ID_LENGTH + 1 + Recipient::LEN;
+                if bytes.len() < end + 1 {
+                    return Err(Error::ConnectionMessageBytesTooShort);
+                }
+                let recipient_bytes: [u8; Recipient::LEN] = bytes[CONNECTION_ID_LENGTH + 1..end]
+                    .try_into()
+                    .map_err(|_| Error::ConnectionMessageBytesTooShort)?;
+                (Some(Recipient::try_from_bytes(recipient_bytes)?), end)
+            }
+            _ => return Err(Error::InvalidMessageBytes),
+        };
+
+        let (handshake, handshake_len) =
+            HandshakePayload::try_from_bytes(&bytes[handshake_start..])?;
+        let bytes = &bytes[handshake_start + handshake_len..];
+        let (&peer_id_len, bytes) = bytes
+            .split_first()
+            .ok_or(Error::ConnectionMessageBytesTooShort)?;
+        if bytes.len() < peer_id_len as usize {
+            return Err(Error::ConnectionMessageBytesTooShort);
+        }
+        let (peer_id, bytes) = bytes.split_at(peer_id_len as usize);
+        let peer_id = PeerId::from_bytes(peer_id).map_err(|_| Error::InvalidPeerIdBytes)?;
+        let (&has_challenge, bytes) = bytes
+            .split_first()
+            .ok_or(Error::ConnectionMessageBytesTooShort)?;
+        let (challenge, bytes) = match has_challenge {
+            0 => (None, bytes),
+            1 => {
+                let challenge: [u8; CHALLENGE_LEN] = bytes
+                    .get(..CHALLENGE_LEN)
+                    .ok_or(Error::ConnectionMessageBytesTooShort)?
+                    .try_into()
+                    .map_err(|_| Error::ConnectionMessageBytesTooShort)?;
+                (Some(challenge), &bytes[CHALLENGE_LEN..])
+            }
+            _ => return Err(Error::InvalidMessageBytes),
+        };
+        let (&has_observed, bytes) = bytes
+            .split_first()
+            .ok_or(Error::ConnectionMessageBytesTooShort)?;
+        let (observed, bytes) = match has_observed {
+            0 => (None, bytes),
+            1 => {
+                let observed: [u8; Recipient::LEN] = bytes
+                    .get(..Recipient::LEN)
+                    .ok_or(Error::ConnectionMessageBytesTooShort)?
+                    .try_into()
+                    .map_err(|_| Error::ConnectionMessageBytesTooShort)?;
+                (
+                    Some(Recipient::try_from_bytes(observed)?),
+                    &bytes[Recipient::LEN..],
+                )
+            }
+            _ => return Err(Error::InvalidMessageBytes),
+        };
+        let max_message_size: [u8; 4] = bytes
+            .get(..4)
+            .ok_or(Error::ConnectionMessageBytesTooShort)?
+            .try_into()
+            .map_err(|_| Error::ConnectionMessageBytesTooShort)?;
+        let bytes = &bytes[4..];
+        let (&has_service, bytes) = bytes
+            .split_first()
+            .ok_or(Error::ConnectionMessageBytesTooShort)?;
+        let (service, bytes) = match has_service {
+            0 => (None, bytes),
+            1 => {
+                let service: [u8; 2] = bytes
+                    .get(..2)
+                    .ok_or(Error::ConnectionMessageBytesTooShort)?
+                    .try_into()
+                    .map_err(|_| Error::ConnectionMessageBytesTooShort)?;
+                (Some(u16::from_be_bytes(service)), &bytes[2..])
+            }
+            _ => return Err(Error::InvalidMessageBytes),
+        };
+        let info = ConnectionInfo::try_from_bytes(bytes)?;
         Ok(ConnectionMessage {
             peer_id,
-            // recipient,
+            recipient,
             id,
+            capabilities,
+            handshake,
+            challenge,
+            observed,
+            max_message_size: u32::from_be_bytes(max_message_size),
+            service,
+            info,
         })
     }
 }
 
+impl VersionMismatch {
+    /// encode writes only the header, since that's all a peer of another version can parse.
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.put_u8(self.version);
+        bytes.put_u32(0);
+        bytes.extend_from_slice(&self.id.0);
+    }
+}
+
 impl TransportMessage {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = self.nonce.to_be_bytes().to_vec();
-        bytes.extend_from_slice(self.id.0.as_ref());
-        bytes.extend_from_slice(&self.message.to_bytes());
-        bytes
+    /// span returns the tracing span the message is handled in; see [`Message::span`].
+    pub(crate) fn span(&self, direction: Direction) -> Span {
+        debug_span!(
+            "message",
+            ?direction,
+            kind = self.message.message_type.kind(),
+            connection_id = ?self.id,
+            substream_id = ?self.message.substream_id,
+            nonce = self.nonce.get(),
+        )
     }
 
-    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+    pub(crate) fn encode(&self, bytes: &mut BytesMut) {
+        bytes.put_u64(self.nonce.get());
+        bytes.extend_from_slice(&self.id.0);
+        self.message.encode(bytes);
+    }
+
+    pub(crate) fn try_from_bytes(bytes: Bytes) -> Result<Self, Error> {
         if bytes.len() < MIN_CONNECTION_MESSAGE_LEN + 1 {
             return Err(Error::TransportMessageBytesTooShort);
         }
 
-        let nonce = u64::from_be_bytes(
+        let nonce = Nonce::new(u64::from_be_bytes(
             bytes[0..NONCE_BYTES_LEN]
                 .to_vec()
                 .try_into()
                 .map_err(|_| Error::InvalidNonce)?,
-        );
+        ));
         let id = ConnectionId::from_bytes(&bytes[NONCE_BYTES_LEN..MIN_CONNECTION_MESSAGE_LEN]);
-        let message = SubstreamMessage::try_from_bytes(&bytes[MIN_CONNECTION_MESSAGE_LEN..])?;
-        Ok(TransportMessage { nonce, message, id })
+        let message = SubstreamMessage::try_from_bytes(bytes.slice(MIN_CONNECTION_MESSAGE_LEN..))?;
+        Ok(TransportMessage {
+            nonce,
+            message,
+            id,
+            timestamp: None,
+        })
     }
 }
 
@@ -178,122 +739,1911 @@ impl std::cmp::PartialEq for TransportMessage {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KeepAliveType {
+    Ping,
+    Pong,
+}
+
+/// KeepAliveMessage is exchanged over an established connection to check that
+/// the remote peer is still reachable.
+/// KeepAlives do not carry a nonce; they are handled as soon as they arrive,
+/// so a gap in the TransportMessage sequence doesn't hold them back.
 #[derive(Debug, Clone)]
-pub(crate) enum SubstreamMessageType {
-    OpenRequest,
-    OpenResponse,
-    Close,
-    Data(Vec<u8>),
+pub(crate) struct KeepAliveMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) keepalive_type: KeepAliveType,
+    /// chosen by the sender of a ping and echoed back in the pong.
+    pub(crate) seq: u64,
+    /// when the keepalive was sent, in milliseconds since the UNIX epoch; see
+    /// [`TransportMessage::timestamp`].
+    pub(crate) timestamp: Option<u64>,
 }
 
-impl SubstreamMessageType {
-    fn to_u8(&self) -> u8 {
-        match self {
-            SubstreamMessageType::OpenRequest => 0,
-            SubstreamMessageType::OpenResponse => 1,
-            SubstreamMessageType::Close => 2,
-            SubstreamMessageType::Data(_) => 3,
+impl KeepAliveMessage {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.put_u8(match self.keepalive_type {
+            KeepAliveType::Ping => 0,
+            KeepAliveType::Pong => 1,
+        });
+        bytes.put_u64(self.seq);
+        bytes.extend_from_slice(&self.id.0);
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < KEEPALIVE_MESSAGE_LEN {
+            return Err(Error::InvalidKeepAliveMessageBytes);
         }
+
+        let keepalive_type = match bytes[0] {
+            0 => KeepAliveType::Ping,
+            1 => KeepAliveType::Pong,
+            _ => return Err(Error::InvalidKeepAliveMessageBytes),
+        };
+        let seq = u64::from_be_bytes(
+            bytes[1..1 + KEEPALIVE_SEQ_BYTES_LEN]
+                .try_into()
+                .map_err(|_| Error::InvalidKeepAliveMessageBytes)?,
+        );
+        let id = ConnectionId::from_bytes(&bytes[1 + KEEPALIVE_SEQ_BYTES_LEN..]);
+        Ok(KeepAliveMessage {
+            id,
+            keepalive_type,
+            seq,
+            timestamp: None,
+        })
     }
 }
 
-/// SubstreamMessage is a message sent over a substream.
-#[derive(Debug, Clone)]
-pub(crate) struct SubstreamMessage {
-    pub(crate) substream_id: SubstreamId,
-    pub(crate) message_type: SubstreamMessageType,
+/// AckMessage acknowledges that a TransportMessage was received, so that the sender
+/// stops retransmitting it. Like KeepAlives, Acks do not carry a nonce of their own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AckMessage {
+    pub(crate) id: ConnectionId,
+    /// nonce of the acknowledged TransportMessage.
+    pub(crate) nonce: Nonce,
 }
 
-impl SubstreamMessage {
-    pub(crate) fn new_with_data(substream_id: SubstreamId, message: Vec<u8>) -> Self {
-        SubstreamMessage {
-            substream_id,
-            message_type: SubstreamMessageType::Data(message),
-        }
+impl AckMessage {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.put_u64(self.nonce.get());
+        bytes.extend_from_slice(&self.id.0);
     }
 
-    pub(crate) fn new_close(substream_id: SubstreamId) -> Self {
-        SubstreamMessage {
-            substream_id,
-            message_type: SubstreamMessageType::Close,
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < ACK_MESSAGE_LEN {
+            return Err(Error::InvalidAckMessageBytes);
         }
+
+        let nonce = Nonce::new(u64::from_be_bytes(
+            bytes[..NONCE_BYTES_LEN]
+                .try_into()
+                .map_err(|_| Error::InvalidAckMessageBytes)?,
+        ));
+        let id = ConnectionId::from_bytes(&bytes[NONCE_BYTES_LEN..]);
+        Ok(AckMessage { id, nonce })
+    }
+}
+
+/// ConnectionCloseMessage is sent when a Connection is dropped, so that the remote
+/// tears down its side of the connection, failing its open substreams, rather than
+/// keeping it until a keepalive or gap times out. Like KeepAlives, it does not carry
+/// a nonce, so it's handled as soon as it arrives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ConnectionCloseMessage {
+    pub(crate) id: ConnectionId,
+}
+
+impl ConnectionCloseMessage {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&self.id.0);
     }
 
-    pub(crate) fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = self.substream_id.0.clone().to_vec();
-        bytes.push(self.message_type.to_u8());
-        if let SubstreamMessageType::Data(message) = &self.message_type {
-            bytes.extend_from_slice(message);
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH {
+            return Err(Error::InvalidConnectionCloseBytes);
         }
-        bytes
+
+        Ok(ConnectionCloseMessage {
+            id: ConnectionId::from_bytes(bytes),
+        })
     }
+}
 
-    pub(crate) fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
-        if bytes.len() < SUBSTREAM_ID_LENGTH + 1 {
-            return Err(Error::InvalidSubstreamMessageBytes);
+/// MigrateMessage tells the remote that we've moved to a new nym address, e.g. because
+/// our mixnet client reconnected through another gateway, so that it sends the rest of
+/// the connection's messages there instead of tearing the connection down. It's signed
+/// by the identity key the connection was opened with (see
+/// [`crate::handshake::sign_migration`]). Like a ConnectionClose, it does not carry a
+/// nonce, so it's handled as soon as it arrives.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MigrateMessage {
+    pub(crate) id: ConnectionId,
+    /// increases with every move the sender makes, so that a MigrateMessage delayed
+    /// in the mixnet can't move the connection back to an older address.
+    pub(crate) seq: u64,
+    /// the sender's new nym address.
+    pub(crate) recipient: Recipient,
+    pub(crate) signature: Vec<u8>,
+}
+
+impl MigrateMessage {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&self.id.0);
+        bytes.put_u64(self.seq);
+        bytes.extend_from_slice(&self.recipient.to_bytes());
+        bytes.put_u16(self.signature.len() as u16);
+        bytes.extend_from_slice(&self.signature);
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        const HEADER_LEN: usize = CONNECTION_ID_LENGTH + MIGRATE_SEQ_BYTES_LEN + Recipient::LEN;
+        if bytes.len() < HEADER_LEN + 2 {
+            return Err(Error::InvalidMigrateMessageBytes);
         }
 
-        let substream_id = SubstreamId::from_bytes(&bytes[0..SUBSTREAM_ID_LENGTH]);
-        let message_type = match bytes[SUBSTREAM_ID_LENGTH] {
-            0 => SubstreamMessageType::OpenRequest,
-            1 => SubstreamMessageType::OpenResponse,
-            2 => SubstreamMessageType::Close,
-            3 => {
-                if bytes.len() < SUBSTREAM_ID_LENGTH + 2 {
-                    return Err(Error::InvalidSubstreamMessageBytes);
-                }
-                SubstreamMessageType::Data(bytes[SUBSTREAM_ID_LENGTH + 1..].to_vec())
-            }
-            _ => return Err(Error::InvalidSubstreamMessageType),
-        };
+        let id = ConnectionId::from_bytes(bytes);
+        let seq = u64::from_be_bytes(
+            bytes[CONNECTION_ID_LENGTH..CONNECTION_ID_LENGTH + MIGRATE_SEQ_BYTES_LEN]
+                .try_into()
+                .map_err(|_| Error::InvalidMigrateMessageBytes)?,
+        );
+        let recipient_bytes: [u8; Recipient::LEN] = bytes
+            [CONNECTION_ID_LENGTH + MIGRATE_SEQ_BYTES_LEN..HEADER_LEN]
+            .try_into()
+            .map_err(|_| Error::InvalidMigrateMessageBytes)?;
+        let recipient = Recipient::try_from_bytes(recipient_bytes)?;
+        let signature_len = u16::from_be_bytes([bytes[HEADER_LEN], bytes[HEADER_LEN + 1]]) as usize;
+        let signature = bytes
+            .get(HEADER_LEN + 2..HEADER_LEN + 2 + signature_len)
+            .ok_or(Error::InvalidMigrateMessageBytes)?;
+        Ok(MigrateMessage {
+            id,
+            seq,
+            recipient,
+            signature: signature.to_vec(),
+        })
+    }
+}
 
-        Ok(SubstreamMessage {
-            substream_id,
-            message_type,
+/// ChallengeResponseMessage is the dialer's answer to the challenge in a
+/// ConnectionResponse: the challenge signed by the identity key the dialer opened the
+/// connection with (see [`crate::handshake::sign_challenge`]). The listener only hands
+/// the connection over, and lets its substreams open, once it's checked it. It does
+/// not carry a nonce, so it's handled as soon as it arrives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ChallengeResponseMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) signature: Vec<u8>,
+}
+
+impl ChallengeResponseMessage {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&self.id.0);
+        bytes.put_u16(self.signature.len() as u16);
+        bytes.extend_from_slice(&self.signature);
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + 2 {
+            return Err(Error::InvalidChallengeResponseBytes);
+        }
+
+        let id = ConnectionId::from_bytes(bytes);
+        let signature_len =
+            u16::from_be_bytes([bytes[CONNECTION_ID_LENGTH], bytes[CONNECTION_ID_LENGTH + 1]])
+                as usize;
+        let signature = bytes
+            .get(CONNECTION_ID_LENGTH + 2..CONNECTION_ID_LENGTH + 2 + signature_len)
+            .ok_or(Error::InvalidChallengeResponseBytes)?;
+        Ok(ChallengeResponseMessage {
+            id,
+            signature: signature.to_vec(),
         })
     }
 }
 
-impl Message {
-    pub(crate) fn to_bytes(&self) -> Vec<u8> {
-        match self {
-            Message::ConnectionRequest(msg) => {
-                let mut bytes = 0_u8.to_be_bytes().to_vec();
-                bytes.append(&mut msg.to_bytes());
-                bytes
-            }
-            Message::ConnectionResponse(msg) => {
-                let mut bytes = 1_u8.to_be_bytes().to_vec();
-                bytes.append(&mut msg.to_bytes());
-                bytes
-            }
-            Message::TransportMessage(msg) => {
-                let mut bytes = 2_u8.to_be_bytes().to_vec();
-                bytes.append(&mut msg.to_bytes());
-                bytes
-            }
+/// SessionTicketMessage carries a session ticket for a connection: what it was opened
+/// with, sealed under a key only the issuer can derive (see [`crate::resumption`]). If
+/// the issuer loses the connection's state, e.g. because it restarted, the remote can
+/// resume the connection with a ResumeRequest carrying the ticket, rather than dial it
+/// again. It does not carry a nonce, so it's handled as soon as it arrives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SessionTicketMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) ticket: Vec<u8>,
+}
+
+impl SessionTicketMessage {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&self.id.0);
+        bytes.put_u16(self.ticket.len() as u16);
+        bytes.extend_from_slice(&self.ticket);
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + 2 {
+            return Err(Error::InvalidResumptionBytes);
         }
+
+        let id = ConnectionId::from_bytes(bytes);
+        let (ticket, _) = take_ticket(&bytes[CONNECTION_ID_LENGTH..])?;
+        Ok(SessionTicketMessage {
+            id,
+            ticket: ticket.to_vec(),
+        })
     }
 }
 
-/// InboundMessage represents an inbound mixnet message.
-pub(crate) struct InboundMessage(pub(crate) Message, pub(crate) Option<AnonymousSenderTag>);
+/// take_ticket reads a ticket prefixed by its length as a u16, returning it and
+/// the bytes after it.
+fn take_ticket(bytes: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    if bytes.len() < 2 {
+        return Err(Error::InvalidResumptionBytes);
+    }
+    let len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+    let ticket = bytes.get(2..2 + len).ok_or(Error::InvalidResumptionBytes)?;
+    Ok((ticket, &bytes[2 + len..]))
+}
 
-/// OutboundMessage represents an outbound mixnet message.
-#[derive(Debug)]
-pub(crate) struct OutboundMessage {
-    pub(crate) message: Message,
-    pub(crate) recipient: Option<Recipient>,
-    pub(crate) sender_tag: Option<AnonymousSenderTag>,
+/// ResumeRequestMessage resumes a connection whose remote stopped answering, in case
+/// it's because the remote lost the connection's state. It carries the session ticket
+/// the remote issued, and proves the sender holds the resumption secret sealed in it
+/// (see [`crate::handshake::ResumptionSecret`]). It does not carry a nonce, so it's
+/// handled as soon as it arrives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ResumeRequestMessage {
+    pub(crate) id: ConnectionId,
+    /// when the request was sent, in milliseconds since the UNIX epoch; covered by the
+    /// proof, so that a replayed request can be told apart once it's too old to remember.
+    pub(crate) timestamp: u64,
+    /// the resumer's half of the new keys' nonce.
+    pub(crate) nonce: [u8; RESUME_NONCE_LEN],
+    pub(crate) proof: [u8; RESUME_PROOF_LEN],
+    pub(crate) ticket: Vec<u8>,
 }
 
-pub(crate) fn parse_message_data(
-    data: &[u8],
-    sender_tag: Option<AnonymousSenderTag>,
-) -> Result<InboundMessage, Error> {
-    if data.len() < 2 {
-        return Err(Error::InvalidMessageBytes);
+impl ResumeRequestMessage {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&self.id.0);
+        bytes.put_u64(self.timestamp);
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.proof);
+        bytes.put_u16(self.ticket.len() as u16);
+        bytes.extend_from_slice(&self.ticket);
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        const NONCE_START: usize = CONNECTION_ID_LENGTH + TIMESTAMP_BYTES_LEN;
+        const HEADER_LEN: usize = NONCE_START + RESUME_NONCE_LEN + RESUME_PROOF_LEN;
+        if bytes.len() < HEADER_LEN + 2 {
+            return Err(Error::InvalidResumptionBytes);
+        }
+
+        let timestamp = u64::from_be_bytes(
+            bytes[CONNECTION_ID_LENGTH..NONCE_START]
+                .try_into()
+                .expect("checked above"),
+        );
+        let (nonce, proof) = take_nonce_and_proof(&bytes[NONCE_START..])?;
+        let (ticket, _) = take_ticket(&bytes[HEADER_LEN..])?;
+        Ok(ResumeRequestMessage {
+            id: ConnectionId::from_bytes(bytes),
+            timestamp,
+            nonce,
+            proof,
+            ticket: ticket.to_vec(),
+        })
+    }
+}
+
+/// ResumeResponseMessage accepts a ResumeRequest, once the ticket it carried has been
+/// opened and its proof checked. It carries the other half of the new keys' nonce, and
+/// proves in turn that the sender opened the ticket. It does not carry a nonce, so it's
+/// handled as soon as it arrives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ResumeResponseMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) nonce: [u8; RESUME_NONCE_LEN],
+    pub(crate) proof: [u8; RESUME_PROOF_LEN],
+}
+
+impl ResumeResponseMessage {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&self.id.0);
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.proof);
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + RESUME_NONCE_LEN + RESUME_PROOF_LEN {
+            return Err(Error::InvalidResumptionBytes);
+        }
+
+        let (nonce, proof) = take_nonce_and_proof(&bytes[CONNECTION_ID_LENGTH..])?;
+        Ok(ResumeResponseMessage {
+            id: ConnectionId::from_bytes(bytes),
+            nonce,
+            proof,
+        })
+    }
+}
+
+/// take_nonce_and_proof reads the nonce and proof of a ResumeRequest or ResumeResponse.
+fn take_nonce_and_proof(
+    bytes: &[u8],
+) -> Result<([u8; RESUME_NONCE_LEN], [u8; RESUME_PROOF_LEN]), Error> {
+    let nonce = bytes
+        .get(..RESUME_NONCE_LEN)
+        .and_then(|nonce| nonce.try_into().ok())
+        .ok_or(Error::InvalidResumptionBytes)?;
+    let proof = bytes
+        .get(RESUME_NONCE_LEN..RESUME_NONCE_LEN + RESUME_PROOF_LEN)
+        .and_then(|proof| proof.try_into().ok())
+        .ok_or(Error::InvalidResumptionBytes)?;
+    Ok((nonce, proof))
+}
+
+/// RelayReserveMessage asks a relay to forward us the circuits that peers open to us
+/// through it, so that they can reach us knowing only the relay's nym address (see
+/// [`crate::relay`]). It's sent with SURBs, which the relay forwards the circuits to us
+/// with, so that the relay doesn't learn our address either. It's signed by our identity
+/// key over the relay's address (see [`crate::handshake::sign_relay_reservation`]), so
+/// that no one else can take the reservation, nor replay it to another relay. It's
+/// answered with a RelayStatus, and sent again before the reservation expires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RelayReserveMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) identity: PublicKey,
+    pub(crate) signature: Vec<u8>,
+}
+
+impl RelayReserveMessage {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&self.id.0);
+        let identity = self.identity.encode_protobuf();
+        bytes.put_u16(identity.len() as u16);
+        bytes.extend_from_slice(&identity);
+        bytes.put_u16(self.signature.len() as u16);
+        bytes.extend_from_slice(&self.signature);
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH {
+            return Err(Error::InvalidRelayMessageBytes);
+        }
+
+        let id = ConnectionId::from_bytes(bytes);
+        let (identity, rest) = take_relay_field(&bytes[CONNECTION_ID_LENGTH..])?;
+        let identity = PublicKey::try_decode_protobuf(identity)
+            .map_err(|_| Error::InvalidRelayMessageBytes)?;
+        let (signature, _) = take_relay_field(rest)?;
+        Ok(RelayReserveMessage {
+            id,
+            identity,
+            signature: signature.to_vec(),
+        })
+    }
+
+    /// peer_id returns the PeerId the reservation is made for.
+    pub(crate) fn peer_id(&self) -> PeerId {
+        self.identity.to_peer_id()
+    }
+}
+
+/// take_relay_field reads a field prefixed by its length as a u16, returning it and
+/// the bytes after it.
+fn take_relay_field(bytes: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    if bytes.len() < 2 {
+        return Err(Error::InvalidRelayMessageBytes);
+    }
+    let len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+    let field = bytes
+        .get(2..2 + len)
+        .ok_or(Error::InvalidRelayMessageBytes)?;
+    Ok((field, &bytes[2 + len..]))
+}
+
+/// RelayStatus is a relay's answer to a reservation, or to a circuit it can't open.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelayStatus {
+    /// the reservation was accepted.
+    Reserved,
+    /// the peer the circuit was opened to holds no reservation with the relay.
+    NoReservation,
+    /// the relay holds as many reservations or circuits as it allows.
+    ResourceLimit,
+    /// the relay doesn't relay for anyone, or the reservation wasn't signed by the
+    /// identity it was made for.
+    Refused,
+}
+
+impl RelayStatus {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            RelayStatus::Reserved => 0,
+            RelayStatus::NoReservation => 1,
+            RelayStatus::ResourceLimit => 2,
+            RelayStatus::Refused => 3,
+        }
+    }
+
+    pub(crate) fn try_from_u8(status: u8) -> Result<Self, Error> {
+        Ok(match status {
+            0 => RelayStatus::Reserved,
+            1 => RelayStatus::NoReservation,
+            2 => RelayStatus::ResourceLimit,
+            3 => RelayStatus::Refused,
+            _ => return Err(Error::InvalidRelayMessageBytes),
+        })
+    }
+}
+
+impl fmt::Display for RelayStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RelayStatus::Reserved => "reserved",
+            RelayStatus::NoReservation => "no reservation",
+            RelayStatus::ResourceLimit => "resource limit exceeded",
+            RelayStatus::Refused => "refused",
+        })
+    }
+}
+
+/// RelayStatusMessage is a relay's answer to a RelayReserve, with the reservation's ID.
+/// It also answers the first message of a circuit the relay can't open, with the
+/// circuit's ID, so that its dial fails rather than times out. It's sent through the
+/// SURBs of the message it answers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RelayStatusMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) status: RelayStatus,
+    /// the time the reservation is held for, in seconds; 0 unless it was accepted.
+    pub(crate) ttl_secs: u32,
+}
+
+impl RelayStatusMessage {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&self.id.0);
+        bytes.put_u8(self.status.to_u8());
+        bytes.put_u32(self.ttl_secs);
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < RELAY_STATUS_MESSAGE_LEN {
+            return Err(Error::InvalidRelayMessageBytes);
+        }
+
+        let ttl = &bytes[CONNECTION_ID_LENGTH + 1..RELAY_STATUS_MESSAGE_LEN];
+        Ok(RelayStatusMessage {
+            id: ConnectionId::from_bytes(bytes),
+            status: RelayStatus::try_from_u8(bytes[CONNECTION_ID_LENGTH])?,
+            ttl_secs: u32::from_be_bytes([ttl[0], ttl[1], ttl[2], ttl[3]]),
+        })
+    }
+}
+
+/// RelayHop is where a relayed message is on its way across a circuit.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum RelayHop {
+    /// sent by the dialer of a circuit to the relay, to be forwarded to the listener,
+    /// the peer with this PeerId.
+    ToListener(PeerId),
+    /// sent by the listener of a circuit to the relay, to be forwarded to the dialer.
+    ToDialer,
+    /// forwarded by the relay to either end of the circuit; carries the relay's nym
+    /// address, which the circuit's messages are sent back to.
+    Delivered(Recipient),
+}
+
+/// RelayMessage carries a message of a connection relayed between two peers that only
+/// know the relay's nym address (see [`crate::relay`]). The connection's ID is the ID
+/// of its circuit. The relay forwards the message between the peers' SURBs without
+/// looking at it; it's handled by the peer it's delivered to as if it had been sent
+/// directly.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RelayMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) hop: RelayHop,
+    /// the relayed message, encoded with the wire codec.
+    pub(crate) payload: Bytes,
+}
+
+impl RelayMessage {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&self.id.0);
+        match &self.hop {
+            RelayHop::ToListener(peer_id) => {
+                bytes.put_u8(0);
+                let peer_id = peer_id.to_bytes();
+                bytes.put_u16(peer_id.len() as u16);
+                bytes.extend_from_slice(&peer_id);
+            }
+            RelayHop::ToDialer => bytes.put_u8(1),
+            RelayHop::Delivered(relay) => {
+                bytes.put_u8(2);
+                bytes.extend_from_slice(&relay.to_bytes());
+            }
+        }
+        bytes.extend_from_slice(&self.payload);
+    }
+
+    fn try_from_bytes(bytes: Bytes) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + 1 {
+            return Err(Error::InvalidRelayMessageBytes);
+        }
+
+        let id = ConnectionId::from_bytes(&bytes);
+        let rest = &bytes[CONNECTION_ID_LENGTH + 1..];
+        let (hop, hop_len) = match bytes[CONNECTION_ID_LENGTH] {
+            0 => {
+                let (field, _) = take_relay_field(rest)?;
+                let peer_id =
+                    PeerId::from_bytes(field).map_err(|_| Error::InvalidRelayMessageBytes)?;
+                (RelayHop::ToListener(peer_id), 2 + field.len())
+            }
+            1 => (RelayHop::ToDialer, 0),
+            2 => {
+                let relay: [u8; Recipient::LEN] = rest
+                    .get(..Recipient::LEN)
+                    .and_then(|relay| relay.try_into().ok())
+                    .ok_or(Error::InvalidRelayMessageBytes)?;
+                let relay = Recipient::try_from_bytes(relay)
+                    .map_err(|_| Error::InvalidRelayMessageBytes)?;
+                (RelayHop::Delivered(relay), Recipient::LEN)
+            }
+            _ => return Err(Error::InvalidRelayMessageBytes),
+        };
+        Ok(RelayMessage {
+            id,
+            hop,
+            payload: bytes.slice(CONNECTION_ID_LENGTH + 1 + hop_len..),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DatagramKind {
+    Request,
+    Response,
+}
+
+/// DatagramMessage carries a complete request or response on its own, outside of
+/// any connection (see [`crate::datagram`]). The request ID is chosen by the requester
+/// and echoed back in the response. It's a ConnectionId so that the SURBs sent with
+/// a request are accounted for in the same way as those sent on a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DatagramMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) kind: DatagramKind,
+    pub(crate) payload: Bytes,
+}
+
+impl DatagramMessage {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.put_u8(match self.kind {
+            DatagramKind::Request => 0,
+            DatagramKind::Response => 1,
+        });
+        bytes.extend_from_slice(&self.id.0);
+        bytes.extend_from_slice(&self.payload);
+    }
+
+    fn try_from_bytes(bytes: Bytes) -> Result<Self, Error> {
+        if bytes.len() < DATAGRAM_HEADER_LEN {
+            return Err(Error::InvalidDatagramMessageBytes);
+        }
+
+        let kind = match bytes[0] {
+            0 => DatagramKind::Request,
+            1 => DatagramKind::Response,
+            _ => return Err(Error::InvalidDatagramMessageBytes),
+        };
+        let id = ConnectionId::from_bytes(&bytes[1..DATAGRAM_HEADER_LEN]);
+        Ok(DatagramMessage {
+            id,
+            kind,
+            payload: bytes.slice(DATAGRAM_HEADER_LEN..),
+        })
+    }
+}
+
+/// BatchMessage carries several TransportMessages of one connection in a single mixnet
+/// message, so that small frames don't each take up a whole sphinx packet.
+/// The batched messages keep their own nonces, and are handled as if they'd arrived
+/// one by one.
+#[derive(Debug, Clone)]
+pub(crate) struct BatchMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) messages: Vec<TransportMessage>,
+}
+
+impl BatchMessage {
+    /// entry_len returns the number of bytes `msg` takes up in a batch.
+    pub(crate) fn entry_len(msg: &TransportMessage) -> usize {
+        BATCH_ENTRY_HEADER_LEN + msg.message.encoded_len()
+    }
+
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&self.id.0);
+        for msg in &self.messages {
+            bytes.put_u16((NONCE_BYTES_LEN + msg.message.encoded_len()) as u16);
+            bytes.put_u64(msg.nonce.get());
+            msg.message.encode(bytes);
+        }
+    }
+
+    fn try_from_bytes(bytes: Bytes) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + BATCH_ENTRY_HEADER_LEN {
+            return Err(Error::InvalidBatchMessageBytes);
+        }
+
+        let id = ConnectionId::from_bytes(&bytes[..CONNECTION_ID_LENGTH]);
+        let mut messages = vec![];
+        let mut offset = CONNECTION_ID_LENGTH;
+        while offset < bytes.len() {
+            if bytes.len() < offset + BATCH_ENTRY_HEADER_LEN {
+                return Err(Error::InvalidBatchMessageBytes);
+            }
+            let len = u16::from_be_bytes([bytes[offset], bytes[offset + 1]]) as usize;
+            let end = offset + 2 + len;
+            if len < NONCE_BYTES_LEN || bytes.len() < end {
+                return Err(Error::InvalidBatchMessageBytes);
+            }
+
+            let nonce = Nonce::new(u64::from_be_bytes(
+                bytes[offset + 2..offset + BATCH_ENTRY_HEADER_LEN]
+                    .try_into()
+                    .map_err(|_| Error::InvalidBatchMessageBytes)?,
+            ));
+            let message = SubstreamMessage::try_from_bytes(
+                bytes.slice(offset + BATCH_ENTRY_HEADER_LEN..end),
+            )?;
+            messages.push(TransportMessage {
+                nonce,
+                message,
+                id: id.clone(),
+                timestamp: None,
+            });
+            offset = end;
+        }
+        Ok(BatchMessage { id, messages })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum SubstreamMessageType {
+    /// carries the number of bytes the dialer will buffer for the substream.
+    OpenRequest(u32),
+    /// carries the number of bytes the listener will buffer for the substream.
+    OpenResponse(u32),
+    Close,
+    Data(Bytes),
+    Fragment(Fragment),
+    /// raises the total number of bytes the remote may send on the substream.
+    WindowUpdate(u64),
+    /// a payload sent on the connection outside of any substream. Datagrams are
+    /// handed over as they arrive, without being acknowledged, retransmitted or
+    /// counted against a receive window.
+    Datagram(Bytes),
+}
+
+impl SubstreamMessageType {
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            SubstreamMessageType::OpenRequest(_) => "open_request",
+            SubstreamMessageType::OpenResponse(_) => "open_response",
+            SubstreamMessageType::Close => "close",
+            SubstreamMessageType::Data(_) => "data",
+            SubstreamMessageType::Fragment(_) => "fragment",
+            SubstreamMessageType::WindowUpdate(_) => "window_update",
+            SubstreamMessageType::Datagram(_) => "datagram",
+        }
+    }
+
+    fn to_u8(&self) -> u8 {
+        match self {
+            SubstreamMessageType::OpenRequest(_) => 0,
+            SubstreamMessageType::OpenResponse(_) => 1,
+            SubstreamMessageType::Close => 2,
+            SubstreamMessageType::Data(_) => 3,
+            SubstreamMessageType::Fragment(_) => 4,
+            SubstreamMessageType::WindowUpdate(_) => 5,
+            SubstreamMessageType::Datagram(_) => 6,
+        }
+    }
+}
+
+/// SubstreamMessage is a message sent over a substream.
+#[derive(Debug, Clone)]
+pub(crate) struct SubstreamMessage {
+    pub(crate) substream_id: SubstreamId,
+    pub(crate) message_type: SubstreamMessageType,
+}
+
+impl SubstreamMessage {
+    pub(crate) fn new_with_data(substream_id: SubstreamId, message: Bytes) -> Self {
+        SubstreamMessage {
+            substream_id,
+            message_type: SubstreamMessageType::Data(message),
+        }
+    }
+
+    pub(crate) fn new_close(substream_id: SubstreamId) -> Self {
+        SubstreamMessage {
+            substream_id,
+            message_type: SubstreamMessageType::Close,
+        }
+    }
+
+    /// new_datagram returns a datagram carrying `payload`. Datagrams don't belong to
+    /// a substream, so they're sent with the all-zero substream ID.
+    pub(crate) fn new_datagram(payload: Bytes) -> Self {
+        SubstreamMessage {
+            substream_id: SubstreamId::default(),
+            message_type: SubstreamMessageType::Datagram(payload),
+        }
+    }
+
+    /// is_datagram returns true if the message is a datagram, which is handled as
+    /// soon as it arrives rather than in nonce order.
+    pub(crate) fn is_datagram(&self) -> bool {
+        matches!(self.message_type, SubstreamMessageType::Datagram(_))
+    }
+
+    /// encoded_len returns the number of bytes written by `encode`.
+    pub(crate) fn encoded_len(&self) -> usize {
+        SUBSTREAM_ID_LENGTH
+            + 1
+            + match &self.message_type {
+                SubstreamMessageType::OpenRequest(_) | SubstreamMessageType::OpenResponse(_) => 4,
+                SubstreamMessageType::Data(message) | SubstreamMessageType::Datagram(message) => {
+                    message.len()
+                }
+                SubstreamMessageType::Fragment(fragment) => {
+                    FRAGMENT_HEADER_LEN + fragment.data.len()
+                }
+                SubstreamMessageType::WindowUpdate(_) => 8,
+                SubstreamMessageType::Close => 0,
+            }
+    }
+
+    pub(crate) fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&self.substream_id.0);
+        bytes.put_u8(self.message_type.to_u8());
+        match &self.message_type {
+            SubstreamMessageType::OpenRequest(window)
+            | SubstreamMessageType::OpenResponse(window) => bytes.put_u32(*window),
+            SubstreamMessageType::Data(message) | SubstreamMessageType::Datagram(message) => {
+                bytes.extend_from_slice(message)
+            }
+            SubstreamMessageType::Fragment(fragment) => fragment.encode(bytes),
+            SubstreamMessageType::WindowUpdate(limit) => bytes.put_u64(*limit),
+            SubstreamMessageType::Close => {}
+        }
+    }
+
+    pub(crate) fn try_from_bytes(bytes: Bytes) -> Result<Self, Error> {
+        if bytes.len() < SUBSTREAM_ID_LENGTH + 1 {
+            return Err(Error::InvalidSubstreamMessageBytes);
+        }
+
+        let substream_id = SubstreamId::from_bytes(&bytes[0..SUBSTREAM_ID_LENGTH]);
+        let payload = bytes.slice(SUBSTREAM_ID_LENGTH + 1..);
+        let message_type = match bytes[SUBSTREAM_ID_LENGTH] {
+            0 => SubstreamMessageType::OpenRequest(u32::from_be_bytes(
+                payload
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| Error::InvalidSubstreamMessageBytes)?,
+            )),
+            1 => SubstreamMessageType::OpenResponse(u32::from_be_bytes(
+                payload
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| Error::InvalidSubstreamMessageBytes)?,
+            )),
+            2 => SubstreamMessageType::Close,
+            3 => {
+                if payload.is_empty() {
+                    return Err(Error::InvalidSubstreamMessageBytes);
+                }
+                SubstreamMessageType::Data(payload)
+            }
+            4 => SubstreamMessageType::Fragment(Fragment::try_from_bytes(payload)?),
+            5 => SubstreamMessageType::WindowUpdate(u64::from_be_bytes(
+                payload
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| Error::InvalidSubstreamMessageBytes)?,
+            )),
+            6 => SubstreamMessageType::Datagram(payload),
+            _ => return Err(Error::InvalidSubstreamMessageType),
+        };
+
+        Ok(SubstreamMessage {
+            substream_id,
+            message_type,
+        })
+    }
+}
+
+/// Fragment is one numbered piece of a Data payload that was too large to be
+/// sent in a single message. Fragments of a payload are sent in order with
+/// consecutive nonces, so the connection's message queue delivers them in order.
+#[derive(Debug, Clone)]
+pub(crate) struct Fragment {
+    /// identifies the payload the fragment belongs to; unique within a substream.
+    pub(crate) payload_id: u32,
+    pub(crate) index: u16,
+    pub(crate) count: u16,
+    pub(crate) data: Bytes,
+}
+
+impl Fragment {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.put_u32(self.payload_id);
+        bytes.put_u16(self.index);
+        bytes.put_u16(self.count);
+        bytes.extend_from_slice(&self.data);
+    }
+
+    fn try_from_bytes(bytes: Bytes) -> Result<Self, Error> {
+        if bytes.len() < FRAGMENT_HEADER_LEN + 1 {
+            return Err(Error::InvalidFragmentBytes);
+        }
+
+        let payload_id = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
+        let index = u16::from_be_bytes(bytes[4..6].try_into().unwrap());
+        let count = u16::from_be_bytes(bytes[6..8].try_into().unwrap());
+        if index >= count {
+            return Err(Error::InvalidFragmentBytes);
+        }
+
+        Ok(Fragment {
+            payload_id,
+            index,
+            count,
+            data: bytes.slice(FRAGMENT_HEADER_LEN..),
+        })
+    }
+}
+
+/// fragment splits a payload into fragments carrying at most `max_size` bytes each.
+/// The fragments are slices of `payload` rather than copies.
+pub(crate) fn fragment(
+    payload_id: u32,
+    payload: &Bytes,
+    max_size: usize,
+) -> Result<Vec<Fragment>, Error> {
+    let max_size = max_size.max(1);
+    let count = payload.len().div_ceil(max_size);
+    let count = u16::try_from(count).map_err(|_| Error::MessageTooLarge(payload.len()))?;
+
+    Ok((0..count)
+        .map(|index| {
+            let start = index as usize * max_size;
+            let end = std::cmp::min(start + max_size, payload.len());
+            Fragment {
+                payload_id,
+                index,
+                count,
+                data: payload.slice(start..end),
+            }
+        })
+        .collect())
+}
+
+/// PartialPayload is a payload of which only some fragments have been received.
+struct PartialPayload {
+    payload_id: u32,
+    count: u16,
+    /// index of the next fragment we expect
+    next_index: u16,
+    data: BytesMut,
+    started: Instant,
+}
+
+/// Reassembler rebuilds fragmented payloads, with one buffer per substream.
+/// Payloads that aren't completed within `timeout` are discarded.
+pub(crate) struct Reassembler {
+    buffers: HashMap<SubstreamId, PartialPayload>,
+    timeout: Duration,
+}
+
+impl Reassembler {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Reassembler {
+            buffers: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// push adds a fragment received on the given substream, and returns the
+    /// full payload once its last fragment has been received. A payload is only buffered
+    /// while it fits in `max_len` bytes, which is what the substream may still be sent
+    /// unread, so it fails with [`Error::ReceiveBufferExceeded`] once it can't, or if its
+    /// first fragment announces more fragments than could fit.
+    pub(crate) fn push(
+        &mut self,
+        substream_id: &SubstreamId,
+        fragment: Fragment,
+        max_len: usize,
+    ) -> Result<Option<Bytes>, Error> {
+        self.expire();
+
+        if fragment.index == 0 {
+            // every fragment but the last is as long as the first, and the last holds
+            // at least a byte
+            let min_len = (fragment.count as usize - 1) * fragment.data.len() + 1;
+            if min_len > max_len {
+                self.buffers.remove(substream_id);
+                return Err(Error::ReceiveBufferExceeded(substream_id.clone()));
+            }
+            if let Some(partial) = self.buffers.remove(substream_id) {
+                warn!(
+                    "substream {:?}: payload {} replaced before it was complete",
+                    substream_id, partial.payload_id
+                );
+            }
+            self.buffers.insert(
+                substream_id.clone(),
+                PartialPayload {
+                    payload_id: fragment.payload_id,
+                    count: fragment.count,
+                    next_index: 0,
+                    data: BytesMut::new(),
+                    started: Instant::now(),
+                },
+            );
+        }
+
+        let Some(partial) = self.buffers.get_mut(substream_id) else {
+            warn!(
+                "substream {:?}: dropping fragment {} of unknown payload {}",
+                substream_id, fragment.index, fragment.payload_id
+            );
+            return Ok(None);
+        };
+
+        if fragment.payload_id != partial.payload_id
+            || fragment.count != partial.count
+            || fragment.index != partial.next_index
+        {
+            warn!(
+                "substream {:?}: unexpected fragment {} of payload {}; discarding payload",
+                substream_id, fragment.index, fragment.payload_id
+            );
+            self.buffers.remove(substream_id);
+            return Ok(None);
+        }
+        if partial.data.len() + fragment.data.len() > max_len {
+            self.buffers.remove(substream_id);
+            return Err(Error::ReceiveBufferExceeded(substream_id.clone()));
+        }
+
+        partial.data.extend_from_slice(&fragment.data);
+        partial.next_index += 1;
+        if partial.next_index < partial.count {
+            return Ok(None);
+        }
+
+        Ok(self
+            .buffers
+            .remove(substream_id)
+            .map(|partial| partial.data.freeze()))
+    }
+
+    /// buffered returns the number of bytes of the partial payload held for the given
+    /// substream.
+    pub(crate) fn buffered(&self, substream_id: &SubstreamId) -> usize {
+        self.buffers
+            .get(substream_id)
+            .map_or(0, |partial| partial.data.len())
+    }
+
+    /// remove discards any partial payload for the given substream, e.g. once it's closed.
+    pub(crate) fn remove(&mut self, substream_id: &SubstreamId) {
+        self.buffers.remove(substream_id);
+    }
+
+    fn expire(&mut self) {
+        let timeout = self.timeout;
+        self.buffers.retain(|substream_id, partial| {
+            let expired = partial.started.elapsed() > timeout;
+            if expired {
+                warn!(
+                    "substream {:?}: payload {} timed out after {} of {} fragments",
+                    substream_id, partial.payload_id, partial.next_index, partial.count
+                );
+            }
+            !expired
+        });
+    }
+}
+
+impl Message {
+    /// to_bytes encodes the message into a single buffer, into which the payload
+    /// (if any) is copied once.
+    pub(crate) fn to_bytes(&self) -> Bytes {
+        let mut bytes = BytesMut::with_capacity(MAX_TRANSPORT_HEADER_LEN + self.payload_len());
+        match self {
+            Message::ConnectionRequest(msg) => {
+                bytes.put_u8(0);
+                msg.encode(&mut bytes);
+            }
+            Message::ConnectionResponse(msg) => {
+                bytes.put_u8(1);
+                msg.encode(&mut bytes);
+            }
+            Message::TransportMessage(msg) => {
+                match msg.timestamp {
+                    Some(timestamp) => {
+                        bytes.put_u8(8);
+                        bytes.put_u64(timestamp);
+                    }
+                    None => bytes.put_u8(2),
+                }
+                msg.encode(&mut bytes);
+            }
+            Message::KeepAlive(msg) => {
+                match msg.timestamp {
+                    Some(timestamp) => {
+                        bytes.put_u8(9);
+                        bytes.put_u64(timestamp);
+                    }
+                    None => bytes.put_u8(3),
+                }
+                msg.encode(&mut bytes);
+            }
+            Message::Ack(msg) => {
+                bytes.put_u8(4);
+                msg.encode(&mut bytes);
+            }
+            Message::Datagram(msg) => {
+                bytes.put_u8(5);
+                msg.encode(&mut bytes);
+            }
+            Message::VersionMismatch(msg) => {
+                bytes.put_u8(if msg.request { 0 } else { 1 });
+                msg.encode(&mut bytes);
+            }
+            Message::Batch(msg) => {
+                bytes.put_u8(6);
+                msg.encode(&mut bytes);
+            }
+            Message::ConnectionClose(msg) => {
+                bytes.put_u8(7);
+                msg.encode(&mut bytes);
+            }
+            Message::Migrate(msg) => {
+                bytes.put_u8(10);
+                msg.encode(&mut bytes);
+            }
+            Message::ChallengeResponse(msg) => {
+                bytes.put_u8(11);
+                msg.encode(&mut bytes);
+            }
+            Message::SessionTicket(msg) => {
+                bytes.put_u8(12);
+                msg.encode(&mut bytes);
+            }
+            Message::ResumeRequest(msg) => {
+                bytes.put_u8(13);
+                msg.encode(&mut bytes);
+            }
+            Message::ResumeResponse(msg) => {
+                bytes.put_u8(14);
+                msg.encode(&mut bytes);
+            }
+            Message::RelayReserve(msg) => {
+                bytes.put_u8(15);
+                msg.encode(&mut bytes);
+            }
+            Message::RelayStatus(msg) => {
+                bytes.put_u8(16);
+                msg.encode(&mut bytes);
+            }
+            Message::Relay(msg) => {
+                bytes.put_u8(17);
+                msg.encode(&mut bytes);
+            }
+        }
+        bytes.freeze()
+    }
+}
+
+/// InboundMessage represents an inbound mixnet message.
+pub(crate) struct InboundMessage(pub(crate) Message, pub(crate) Option<AnonymousSenderTag>);
+
+/// OutboundMessage represents an outbound mixnet message.
+#[derive(Debug)]
+pub(crate) struct OutboundMessage {
+    pub(crate) message: Message,
+    pub(crate) recipient: Option<Recipient>,
+    pub(crate) sender_tag: Option<AnonymousSenderTag>,
+}
+
+impl OutboundMessage {
+    /// is_control returns true for messages that set up or tear down connections and
+    /// substreams, which are sent to the mixnet ahead of any queued substream data.
+    pub(crate) fn is_control(&self) -> bool {
+        match &self.message {
+            Message::TransportMessage(msg) => !matches!(
+                msg.message.message_type,
+                SubstreamMessageType::Data(_)
+                    | SubstreamMessageType::Fragment(_)
+                    | SubstreamMessageType::Datagram(_)
+            ),
+            // relayed messages are mostly the substream data of the circuits they belong to
+            Message::Datagram(_) | Message::Relay(_) => false,
+            _ => true,
+        }
+    }
+}
+
+/// parse_message_data decodes a message in the native format, rejecting messages
+/// larger than `max_size` bytes before looking at them. Payloads are slices of `data`,
+/// so they aren't copied on the way to the substream. Messages received from the
+/// mixnet are decoded with the configured [`WireCodec`](crate::codec::WireCodec) instead.
+#[cfg(any(test, fuzzing, feature = "bench"))]
+pub(crate) fn parse_message_data(
+    data: Bytes,
+    sender_tag: Option<AnonymousSenderTag>,
+    max_size: usize,
+) -> Result<InboundMessage, Error> {
+    decode_inbound::<NativeCodec>(data, sender_tag, max_size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::DEFAULT_MAX_MESSAGE_SIZE;
+    use super::super::handshake::Handshake;
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_fragment_roundtrip() {
+        let substream_id = SubstreamId::generate();
+        let payload = (0..=255u8).cycle().take(1000).collect::<Bytes>();
+        let fragments = fragment(7, &payload, 300).unwrap();
+        assert_eq!(fragments.len(), 4);
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(60));
+        let mut result = None;
+        for fragment in fragments {
+            assert!(result.is_none());
+            let msg = SubstreamMessage {
+                substream_id: substream_id.clone(),
+                message_type: SubstreamMessageType::Fragment(fragment),
+            };
+            let mut bytes = BytesMut::new();
+            msg.encode(&mut bytes);
+            let msg = SubstreamMessage::try_from_bytes(bytes.freeze()).unwrap();
+            let SubstreamMessageType::Fragment(fragment) = msg.message_type else {
+                panic!("expected SubstreamMessageType::Fragment");
+            };
+            result = reassembler.push(&substream_id, fragment, 1000).unwrap();
+        }
+        assert_eq!(result, Some(payload));
+    }
+
+    #[test]
+    fn test_reassembly_limit() {
+        let substream_id = SubstreamId::generate();
+        let payload = Bytes::from_static(&[0u8; 30]);
+        let mut reassembler = Reassembler::new(Duration::from_secs(60));
+
+        // a payload announcing more fragments than could fit is rejected up front
+        let mut fragments = fragment(1, &payload, 10).unwrap();
+        assert!(matches!(
+            reassembler.push(&substream_id, fragments.remove(0), 20),
+            Err(Error::ReceiveBufferExceeded(id)) if id == substream_id
+        ));
+        assert!(reassembler.buffers.is_empty());
+
+        // as is one whose fragments turn out longer than the first
+        let mut fragments = fragment(2, &payload, 10).unwrap();
+        fragments[1].data = Bytes::from_static(&[0u8; 15]);
+        let mut fragments = fragments.into_iter();
+        assert!(reassembler
+            .push(&substream_id, fragments.next().unwrap(), 30)
+            .unwrap()
+            .is_none());
+        assert_eq!(reassembler.buffered(&substream_id), 10);
+        assert!(reassembler
+            .push(&substream_id, fragments.next().unwrap(), 30)
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            reassembler.push(&substream_id, fragments.next().unwrap(), 30),
+            Err(Error::ReceiveBufferExceeded(_))
+        ));
+        assert_eq!(reassembler.buffered(&substream_id), 0);
+    }
+
+    #[test]
+    fn test_negotiated_fragment_size() {
+        use super::super::codec::{Codec, WireCodec};
+
+        // the smaller limit wins
+        assert_eq!(negotiated_fragment_size(1400, 1 << 20), 1400);
+        let remote = MIN_MAX_MESSAGE_SIZE;
+        let size = negotiated_fragment_size(1400, remote);
+        assert!(size < 1400);
+
+        // and a fragment of that size, with the longest header, fits the remote's limit
+        let payload = Bytes::from(vec![7u8; size * 2]);
+        let fragments = fragment(u32::MAX, &payload, size).unwrap();
+        let msg = Message::TransportMessage(TransportMessage {
+            nonce: Nonce::new(u64::MAX),
+            id: ConnectionId::generate(),
+            message: SubstreamMessage {
+                substream_id: SubstreamId::generate(),
+                message_type: SubstreamMessageType::Fragment(fragments[0].clone()),
+            },
+            timestamp: Some(u64::MAX),
+        });
+        assert!(WireCodec::encode(&msg).len() <= remote as usize);
+    }
+
+    #[test]
+    fn test_reassembly_out_of_order() {
+        let substream_id = SubstreamId::generate();
+        let mut fragments = fragment(1, &Bytes::from_static(&[0u8; 30]), 10).unwrap();
+        let mut reassembler = Reassembler::new(Duration::from_secs(60));
+
+        // a payload missing its middle fragment is discarded
+        let last = fragments.pop().unwrap();
+        assert!(reassembler
+            .push(&substream_id, fragments.remove(0), 30)
+            .unwrap()
+            .is_none());
+        assert!(reassembler.push(&substream_id, last, 30).unwrap().is_none());
+        assert!(reassembler.buffers.is_empty());
+    }
+
+    #[test]
+    fn test_reassembly_timeout() {
+        let substream_id = SubstreamId::generate();
+        let mut fragments = fragment(1, &Bytes::from_static(&[0u8; 20]), 10).unwrap();
+        let mut reassembler = Reassembler::new(Duration::ZERO);
+
+        assert!(reassembler
+            .push(&substream_id, fragments.remove(0), 20)
+            .unwrap()
+            .is_none());
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(reassembler
+            .push(&substream_id, fragments.remove(0), 20)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_ack_roundtrip() {
+        let ack = AckMessage {
+            id: ConnectionId::generate(),
+            nonce: Nonce::new(42),
+        };
+        let bytes = Message::Ack(ack.clone()).to_bytes();
+        match parse_message_data(bytes, None, DEFAULT_MAX_MESSAGE_SIZE)
+            .unwrap()
+            .0
+        {
+            Message::Ack(decoded) => assert_eq!(decoded, ack),
+            msg => panic!("expected Message::Ack, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn test_stamped_message_roundtrip() {
+        let id = ConnectionId::generate();
+        let msg = Message::TransportMessage(TransportMessage {
+            nonce: Nonce::new(1),
+            id: id.clone(),
+            message: SubstreamMessage {
+                substream_id: SubstreamId::generate(),
+                message_type: SubstreamMessageType::OpenRequest(1024),
+            },
+            timestamp: Some(1_700_000_000_000),
+        });
+        let bytes = msg.to_bytes();
+        assert_eq!(bytes[0], 8);
+        let Message::TransportMessage(decoded) =
+            parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE)
+                .unwrap()
+                .0
+        else {
+            panic!("expected Message::TransportMessage");
+        };
+        assert_eq!(decoded.nonce, Nonce::FIRST);
+        assert_eq!(decoded.timestamp, Some(1_700_000_000_000));
+
+        let keepalive = Message::KeepAlive(KeepAliveMessage {
+            id,
+            keepalive_type: KeepAliveType::Ping,
+            seq: 3,
+            timestamp: Some(1_700_000_000_000),
+        });
+        let bytes = keepalive.to_bytes();
+        assert_eq!(bytes[0], 9);
+        let Message::KeepAlive(decoded) =
+            parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE)
+                .unwrap()
+                .0
+        else {
+            panic!("expected Message::KeepAlive");
+        };
+        assert_eq!(decoded.seq, 3);
+        assert_eq!(decoded.timestamp, Some(1_700_000_000_000));
+
+        // a stamped message too short for its timestamp is rejected
+        assert!(parse_message_data(bytes.slice(..5), None, DEFAULT_MAX_MESSAGE_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let max_age = Duration::from_secs(10);
+        let skew = Duration::from_secs(2);
+        let now = 1_700_000_000_000;
+        assert!(!is_stale(now, now, max_age, skew));
+        assert!(!is_stale(now - 12_000, now, max_age, skew));
+        assert!(is_stale(now - 12_001, now, max_age, skew));
+        // stamped by a clock ahead of ours
+        assert!(!is_stale(now + 60_000, now, max_age, skew));
+    }
+
+    #[test]
+    fn test_connection_close_roundtrip() {
+        let close = ConnectionCloseMessage {
+            id: ConnectionId::generate(),
+        };
+        let bytes = Message::ConnectionClose(close.clone()).to_bytes();
+        match parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE)
+            .unwrap()
+            .0
+        {
+            Message::ConnectionClose(decoded) => assert_eq!(decoded, close),
+            msg => panic!("expected Message::ConnectionClose, got {:?}", msg),
+        }
+        assert!(parse_message_data(
+            bytes.slice(..bytes.len() - 1),
+            None,
+            DEFAULT_MAX_MESSAGE_SIZE
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_migrate_roundtrip() {
+        let migrate = MigrateMessage {
+            id: ConnectionId::generate(),
+            seq: 3,
+            recipient: Recipient::try_from_base58_string("D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN").unwrap(),
+            signature: vec![5u8; 64],
+        };
+        let bytes = Message::Migrate(migrate.clone()).to_bytes();
+        match parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE)
+            .unwrap()
+            .0
+        {
+            Message::Migrate(decoded) => assert_eq!(decoded, migrate),
+            msg => panic!("expected Message::Migrate, got {:?}", msg),
+        }
+        assert!(parse_message_data(
+            bytes.slice(..bytes.len() - 1),
+            None,
+            DEFAULT_MAX_MESSAGE_SIZE
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_challenge_response_roundtrip() {
+        let response = ChallengeResponseMessage {
+            id: ConnectionId::generate(),
+            signature: vec![9u8; 64],
+        };
+        let bytes = Message::ChallengeResponse(response.clone()).to_bytes();
+        match parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE)
+            .unwrap()
+            .0
+        {
+            Message::ChallengeResponse(decoded) => assert_eq!(decoded, response),
+            msg => panic!("expected Message::ChallengeResponse, got {:?}", msg),
+        }
+        assert!(parse_message_data(
+            bytes.slice(..bytes.len() - 1),
+            None,
+            DEFAULT_MAX_MESSAGE_SIZE
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_resumption_roundtrip() {
+        let id = ConnectionId::generate();
+        let messages = vec![
+            Message::SessionTicket(SessionTicketMessage {
+                id: id.clone(),
+                ticket: vec![1u8; 150],
+            }),
+            Message::ResumeRequest(ResumeRequestMessage {
+                id: id.clone(),
+                timestamp: 1_700_000_000_000,
+                nonce: [2u8; RESUME_NONCE_LEN],
+                proof: [3u8; RESUME_PROOF_LEN],
+                ticket: vec![1u8; 150],
+            }),
+            Message::ResumeResponse(ResumeResponseMessage {
+                id,
+                nonce: [4u8; RESUME_NONCE_LEN],
+                proof: [5u8; RESUME_PROOF_LEN],
+            }),
+        ];
+        for message in messages {
+            let bytes = message.to_bytes();
+            let decoded = parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE)
+                .unwrap()
+                .0;
+            match (&message, decoded) {
+                (Message::SessionTicket(msg), Message::SessionTicket(decoded)) => {
+                    assert_eq!(*msg, decoded)
+                }
+                (Message::ResumeRequest(msg), Message::ResumeRequest(decoded)) => {
+                    assert_eq!(*msg, decoded)
+                }
+                (Message::ResumeResponse(msg), Message::ResumeResponse(decoded)) => {
+                    assert_eq!(*msg, decoded)
+                }
+                (_, decoded) => panic!("expected {}, got {:?}", message.kind(), decoded),
+            }
+            assert!(parse_message_data(
+                bytes.slice(..bytes.len() - 1),
+                None,
+                DEFAULT_MAX_MESSAGE_SIZE
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_relay_roundtrip() {
+        let id = ConnectionId::generate();
+        let keypair = libp2p_identity::Keypair::generate_ed25519();
+        let relay = Recipient::try_from_base58_string("Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR").unwrap();
+        let reserve = RelayReserveMessage {
+            id: id.clone(),
+            identity: keypair.public(),
+            signature: vec![6u8; 64],
+        };
+        let status = RelayStatusMessage {
+            id: id.clone(),
+            status: RelayStatus::Reserved,
+            ttl_secs: 3600,
+        };
+        let relayed =
+            Message::ConnectionClose(ConnectionCloseMessage { id: id.clone() }).to_bytes();
+        let hops = [
+            RelayHop::ToListener(keypair.public().to_peer_id()),
+            RelayHop::ToDialer,
+            RelayHop::Delivered(relay),
+        ];
+
+        let bytes = Message::RelayReserve(reserve.clone()).to_bytes();
+        match parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE)
+            .unwrap()
+            .0
+        {
+            Message::RelayReserve(decoded) => {
+                assert_eq!(decoded, reserve);
+                assert_eq!(decoded.peer_id(), keypair.public().to_peer_id());
+            }
+            msg => panic!("expected Message::RelayReserve, got {:?}", msg),
+        }
+        assert!(parse_message_data(
+            bytes.slice(..bytes.len() - 1),
+            None,
+            DEFAULT_MAX_MESSAGE_SIZE
+        )
+        .is_err());
+
+        let bytes = Message::RelayStatus(status.clone()).to_bytes();
+        match parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE)
+            .unwrap()
+            .0
+        {
+            Message::RelayStatus(decoded) => assert_eq!(decoded, status),
+            msg => panic!("expected Message::RelayStatus, got {:?}", msg),
+        }
+        assert!(parse_message_data(
+            bytes.slice(..bytes.len() - 1),
+            None,
+            DEFAULT_MAX_MESSAGE_SIZE
+        )
+        .is_err());
+
+        for hop in hops {
+            let msg = RelayMessage {
+                id: id.clone(),
+                hop,
+                payload: relayed.clone(),
+            };
+            let bytes = Message::Relay(msg.clone()).to_bytes();
+            match parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE)
+                .unwrap()
+                .0
+            {
+                Message::Relay(decoded) => assert_eq!(decoded, msg),
+                msg => panic!("expected Message::Relay, got {:?}", msg),
+            }
+        }
+
+        // the relayed message is everything after the hop, so only a cut into the hop fails
+        let bytes = Message::Relay(RelayMessage {
+            id,
+            hop: RelayHop::Delivered(relay),
+            payload: Bytes::new(),
+        })
+        .to_bytes();
+        assert!(parse_message_data(
+            bytes.slice(..bytes.len() - 1),
+            None,
+            DEFAULT_MAX_MESSAGE_SIZE
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_datagram_roundtrip() {
+        let datagram = DatagramMessage {
+            id: ConnectionId::generate(),
+            kind: DatagramKind::Response,
+            payload: Bytes::from_static(&[1, 2, 3]),
+        };
+        let bytes = Message::Datagram(datagram.clone()).to_bytes();
+        match parse_message_data(bytes, None, DEFAULT_MAX_MESSAGE_SIZE)
+            .unwrap()
+            .0
+        {
+            Message::Datagram(decoded) => assert_eq!(decoded, datagram),
+            msg => panic!("expected Message::Datagram, got {:?}", msg),
+        }
+
+        // an empty payload is allowed, but the header isn't optional
+        let datagram = DatagramMessage {
+            payload: Bytes::new(),
+            ..datagram
+        };
+        let bytes = Message::Datagram(datagram.clone()).to_bytes();
+        assert!(parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE).is_ok());
+        assert!(parse_message_data(
+            bytes.slice(..bytes.len() - 1),
+            None,
+            DEFAULT_MAX_MESSAGE_SIZE
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_connection_message_version() {
+        let keypair = libp2p_identity::Keypair::generate_ed25519();
+        let id = ConnectionId::generate();
+        let msg = ConnectionMessage {
+            peer_id: keypair.public().to_peer_id(),
+            id: id.clone(),
+            capabilities: Capabilities::RETRANSMIT,
+            recipient: None,
+            handshake: Handshake::new(&keypair, &id, None).unwrap().payload(),
+            challenge: None,
+            observed: None,
+            max_message_size: 4096,
+            service: Some(8080),
+            info: ConnectionInfo {
+                agent_version: Some("test/1.0".to_string()),
+                extensions: vec!["a".to_string(), "b".to_string()],
+            },
+        };
+        let bytes = Message::ConnectionRequest(msg).to_bytes();
+        let Message::ConnectionRequest(decoded) =
+            parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE)
+                .unwrap()
+                .0
+        else {
+            panic!("expected Message::ConnectionRequest");
+        };
+        assert_eq!(decoded.id, id);
+        assert!(decoded.capabilities.contains(Capabilities::RETRANSMIT));
+        assert_eq!(decoded.peer_id, keypair.public().to_peer_id());
+        assert_eq!(decoded.info.agent_version.as_deref(), Some("test/1.0"));
+        assert_eq!(decoded.info.extensions, vec!["a", "b"]);
+        assert_eq!(decoded.max_message_size, 4096);
+        assert_eq!(decoded.service, Some(8080));
+        assert!(decoded.challenge.is_none());
+        assert!(decoded.observed.is_none());
+
+        // a response carries the listener's challenge, and the address it observed
+        let observed = Recipient::try_from_base58_string(
+            "Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR",
+        )
+        .unwrap();
+        let mut response = decoded;
+        response.challenge = Some([3u8; CHALLENGE_LEN]);
+        response.observed = Some(observed);
+        response.service = None;
+        let response = Message::ConnectionResponse(response).to_bytes();
+        let Message::ConnectionResponse(decoded) =
+            parse_message_data(response, None, DEFAULT_MAX_MESSAGE_SIZE)
+                .unwrap()
+                .0
+        else {
+            panic!("expected Message::ConnectionResponse");
+        };
+        assert_eq!(decoded.challenge, Some([3u8; CHALLENGE_LEN]));
+        assert_eq!(decoded.observed, Some(observed));
+        assert_eq!(decoded.service, None);
+
+        // a message of another version is recognised from its header alone
+        let mut future = bytes.to_vec();
+        future[1] = PROTOCOL_VERSION + 1;
+        future.truncate(1 + CONNECTION_HEADER_LEN);
+        match parse_message_data(future.into(), None, DEFAULT_MAX_MESSAGE_SIZE)
+            .unwrap()
+            .0
+        {
+            Message::VersionMismatch(msg) => {
+                assert_eq!(msg.id, id);
+                assert!(msg.request);
+                assert_eq!(msg.version, PROTOCOL_VERSION + 1);
+            }
+            msg => panic!("expected Message::VersionMismatch, got {:?}", msg),
+        }
+
+        // and rejected with a header of our own version
+        let reject = VersionMismatch {
+            id: id.clone(),
+            request: false,
+            version: PROTOCOL_VERSION,
+        };
+        let bytes = Message::VersionMismatch(reject).to_bytes();
+        assert_eq!(bytes.len(), 1 + CONNECTION_HEADER_LEN);
+        assert_eq!(bytes[1], PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn test_connection_info_roundtrip() {
+        let empty = ConnectionInfo::default();
+        let mut bytes = BytesMut::new();
+        empty.encode(&mut bytes);
+        assert_eq!(ConnectionInfo::try_from_bytes(&bytes).unwrap(), empty);
+
+        // strings too long for their length prefix are cut short at a char boundary
+        let info = ConnectionInfo {
+            agent_version: Some("é".repeat(200)),
+            extensions: vec!["x".repeat(300)],
+        };
+        let mut bytes = BytesMut::new();
+        info.encode(&mut bytes);
+        let decoded = ConnectionInfo::try_from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.agent_version, Some("é".repeat(127)));
+        assert_eq!(decoded.extensions, vec!["x".repeat(255)]);
+
+        // trailing bytes are rejected
+        bytes.put_u8(0);
+        assert!(ConnectionInfo::try_from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_batch_roundtrip() {
+        let id = ConnectionId::generate();
+        let substream_id = SubstreamId::generate();
+        let messages = vec![
+            TransportMessage {
+                nonce: Nonce::new(1),
+                id: id.clone(),
+                message: SubstreamMessage {
+                    substream_id: substream_id.clone(),
+                    message_type: SubstreamMessageType::OpenRequest(1024),
+                },
+                timestamp: None,
+            },
+            TransportMessage {
+                nonce: Nonce::new(2),
+                id: id.clone(),
+                message: SubstreamMessage::new_with_data(
+                    substream_id.clone(),
+                    Bytes::from_static(b"hello"),
+                ),
+                timestamp: None,
+            },
+            TransportMessage {
+                nonce: Nonce::new(3),
+                id: id.clone(),
+                message: SubstreamMessage::new_close(substream_id.clone()),
+                timestamp: None,
+            },
+        ];
+        let batch = Message::Batch(BatchMessage {
+            id: id.clone(),
+            messages: messages.clone(),
+        });
+        let bytes = batch.to_bytes();
+        assert_eq!(
+            bytes.len(),
+            1 + CONNECTION_ID_LENGTH + messages.iter().map(BatchMessage::entry_len).sum::<usize>()
+        );
+
+        let Message::Batch(decoded) =
+            parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE)
+                .unwrap()
+                .0
+        else {
+            panic!("expected Message::Batch");
+        };
+        assert_eq!(decoded.id, id);
+        assert_eq!(decoded.messages, messages);
+        let SubstreamMessageType::Data(data) = &decoded.messages[1].message.message_type else {
+            panic!("expected SubstreamMessageType::Data");
+        };
+        assert_eq!(data, &b"hello"[..]);
+
+        // a truncated entry invalidates the batch
+        assert!(parse_message_data(
+            bytes.slice(..bytes.len() - 1),
+            None,
+            DEFAULT_MAX_MESSAGE_SIZE
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_does_not_copy_payload() {
+        let msg = Message::TransportMessage(TransportMessage {
+            nonce: Nonce::new(1),
+            id: ConnectionId::generate(),
+            message: SubstreamMessage::new_with_data(
+                SubstreamId::generate(),
+                Bytes::from(vec![7u8; 4096]),
+            ),
+            timestamp: None,
+        });
+        let bytes = msg.to_bytes();
+        let range = bytes.as_ptr_range();
+
+        let Message::TransportMessage(msg) =
+            parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE)
+                .unwrap()
+                .0
+        else {
+            panic!("expected Message::TransportMessage");
+        };
+        let SubstreamMessageType::Data(data) = msg.message.message_type else {
+            panic!("expected SubstreamMessageType::Data");
+        };
+        assert_eq!(data, vec![7u8; 4096]);
+        assert!(range.contains(&data.as_ptr()));
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_messages() {
+        let msg = Message::TransportMessage(TransportMessage {
+            nonce: Nonce::new(1),
+            id: ConnectionId::generate(),
+            message: SubstreamMessage::new_with_data(
+                SubstreamId::generate(),
+                Bytes::from(vec![7u8; 4096]),
+            ),
+            timestamp: None,
+        });
+        let bytes = msg.to_bytes();
+        assert!(matches!(
+            parse_message_data(bytes.clone(), None, bytes.len() - 1),
+            Err(Error::InboundMessageTooLarge(len)) if len == bytes.len()
+        ));
+        assert!(parse_message_data(bytes.clone(), None, bytes.len()).is_ok());
+
+        let mut unknown = BytesMut::from(&bytes[..]);
+        unknown[0] = 0xff;
+        assert!(matches!(
+            parse_message_data(unknown.freeze(), None, DEFAULT_MAX_MESSAGE_SIZE),
+            Err(Error::UnknownMessageType(0xff))
+        ));
+    }
+
+    fn substream_message() -> impl Strategy<Value = SubstreamMessage> {
+        let message_type = prop_oneof![
+            any::<u32>().prop_map(SubstreamMessageType::OpenRequest),
+            any::<u32>().prop_map(SubstreamMessageType::OpenResponse),
+            Just(SubstreamMessageType::Close),
+            vec(any::<u8>(), 1..512).prop_map(|data| SubstreamMessageType::Data(data.into())),
+            (
+                any::<u32>(),
+                any::<u16>(),
+                1..=u16::MAX,
+                vec(any::<u8>(), 1..512)
+            )
+                .prop_map(|(payload_id, index, count, data)| {
+                    SubstreamMessageType::Fragment(Fragment {
+                        payload_id,
+                        index: index % count,
+                        count,
+                        data: data.into(),
+                    })
+                }),
+            any::<u64>().prop_map(SubstreamMessageType::WindowUpdate),
+            vec(any::<u8>(), 0..512).prop_map(|data| SubstreamMessageType::Datagram(data.into())),
+        ];
+        (any::<[u8; 32]>(), message_type).prop_map(|(id, message_type)| SubstreamMessage {
+            substream_id: SubstreamId(id),
+            message_type,
+        })
+    }
+
+    fn transport_message(id: ConnectionId) -> impl Strategy<Value = TransportMessage> {
+        (any::<u64>(), substream_message()).prop_map(move |(nonce, message)| TransportMessage {
+            nonce: Nonce::new(nonce),
+            message,
+            id: id.clone(),
+            timestamp: None,
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_parse_arbitrary_bytes(data in vec(any::<u8>(), 0..1024)) {
+            // malformed messages are rejected, never panicked on
+            let _ = parse_message_data(data.into(), None, DEFAULT_MAX_MESSAGE_SIZE);
+        }
+
+        #[test]
+        fn test_parse_arbitrary_bytes_of_each_type(
+            message_type in 0u8..18,
+            version in prop_oneof![Just(PROTOCOL_VERSION), any::<u8>()],
+            data in vec(any::<u8>(), 0..1024),
+        ) {
+            // past the type byte, and the version byte of connection messages, so
+            // that every message's own parsing is reached
+            let mut bytes = vec![message_type, version];
+            bytes.extend_from_slice(&data);
+            let _ = parse_message_data(bytes.into(), None, DEFAULT_MAX_MESSAGE_SIZE);
+        }
+
+        #[test]
+        fn test_substream_message_roundtrip(msg in substream_message()) {
+            let mut bytes = BytesMut::new();
+            msg.encode(&mut bytes);
+            prop_assert_eq!(bytes.len(), msg.encoded_len());
+            let decoded = SubstreamMessage::try_from_bytes(bytes.clone().freeze()).unwrap();
+            let mut reencoded = BytesMut::new();
+            decoded.encode(&mut reencoded);
+            prop_assert_eq!(reencoded, bytes);
+        }
+
+        #[test]
+        fn test_transport_message_roundtrip(
+            msg in transport_message(ConnectionId::generate()),
+            timestamp in proptest::option::of(any::<u64>()),
+        ) {
+            let bytes = Message::TransportMessage(TransportMessage { timestamp, ..msg }).to_bytes();
+            let decoded = parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE).unwrap().0;
+            prop_assert!(matches!(decoded, Message::TransportMessage(_)));
+            prop_assert_eq!(decoded.to_bytes(), bytes);
+        }
+
+        #[test]
+        fn test_batch_message_roundtrip(
+            messages in vec(transport_message(ConnectionId::default()), 1..8),
+        ) {
+            let bytes = Message::Batch(BatchMessage {
+                id: ConnectionId::default(),
+                messages,
+            })
+            .to_bytes();
+            let decoded = parse_message_data(bytes.clone(), None, DEFAULT_MAX_MESSAGE_SIZE).unwrap().0;
+            prop_assert!(matches!(decoded, Message::Batch(_)));
+            prop_assert_eq!(decoded.to_bytes(), bytes);
+        }
     }
-    let msg = Message::try_from_bytes(data.to_vec())?;
-    Ok(InboundMessage(msg, sender_tag))
 }
@@ -0,0 +1,350 @@
+//! Encodings of every kind of message in the native wire format, built from fixed
+//! inputs, for other implementations of the transport to check theirs against. The
+//! same vectors are checked against golden copies in `tests/wire_format.rs`, so a
+//! change to the wire format that breaks them shows up as a failing test.
+//!
+//! Handshake signatures are real: ed25519 signatures are deterministic, so they're
+//! the same every time the vectors are built from [`IDENTITY_SEED`]. So are the
+//! resumption proofs, which are derived from [`RESUMPTION_SECRET`].
+
+use libp2p_identity::Keypair;
+
+use super::super::codec::{Codec, NativeCodec};
+use super::super::handshake::{
+    sign_challenge, sign_migration, sign_relay_reservation, ResumptionSecret,
+};
+use super::*;
+
+/// IDENTITY_SEED is the ed25519 secret key the ConnectionMessages, the Migrate, the
+/// ChallengeResponse and the RelayReserve are signed with; the relayed messages are
+/// sent to its PeerId.
+pub const IDENTITY_SEED: [u8; 32] = [7; 32];
+
+/// EPHEMERAL_KEY is the X25519 key the ConnectionMessages offer in their handshake.
+pub const EPHEMERAL_KEY: [u8; 32] = [0x42; 32];
+
+/// CONNECTION_ID is the ID of the connection every message belongs to.
+pub const CONNECTION_ID: [u8; 32] = sequence(0);
+
+/// SUBSTREAM_ID is the ID of the substream every SubstreamMessage belongs to.
+pub const SUBSTREAM_ID: [u8; 32] = sequence(32);
+
+/// CHALLENGE is the challenge sent in the ConnectionResponse, and signed in the ChallengeResponse.
+pub const CHALLENGE: [u8; 32] = sequence(64);
+
+/// MAX_MESSAGE_SIZE is the maximum message size the ConnectionMessages advertise.
+pub const MAX_MESSAGE_SIZE: u32 = 1024 * 1024;
+
+/// SESSION_TICKET is the sealed ticket sent in the SessionTicket and the ResumeRequest;
+/// it's opaque to everyone but its issuer.
+pub const SESSION_TICKET: &[u8] = b"sealed session ticket";
+
+/// RESUMPTION_SECRET is the resumption secret the ResumeRequest and ResumeResponse
+/// prove they hold.
+pub const RESUMPTION_SECRET: [u8; 32] = [9; 32];
+
+/// RESUME_REQUEST_NONCE is the nonce sent in the ResumeRequest.
+pub const RESUME_REQUEST_NONCE: [u8; 32] = sequence(96);
+
+/// RESUME_RESPONSE_NONCE is the nonce sent in the ResumeResponse.
+pub const RESUME_RESPONSE_NONCE: [u8; 32] = sequence(128);
+
+/// AGENT_VERSION is the agent version sent in the ConnectionRequest.
+pub const AGENT_VERSION: &str = "test-vectors/1.0";
+
+/// MIGRATION_ADDRESS is the nym address the Migrate moves the connection to.
+pub const MIGRATION_ADDRESS: &str = "Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR";
+
+/// RELAY_ADDRESS is the nym address of the relay the RelayReserve is signed for, and
+/// the relayed messages are delivered from.
+pub const RELAY_ADDRESS: &str = "D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN";
+
+/// TIMESTAMP is the time the stamped messages were sent, and the handshakes signed, in
+/// milliseconds since the UNIX epoch.
+pub const TIMESTAMP: u64 = 1_700_000_000_000;
+
+const fn sequence(start: u8) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    let mut i = 0;
+    while i < 32 {
+        bytes[i] = start + i as u8;
+        i += 1;
+    }
+    bytes
+}
+
+/// TestVector is a message and its encoding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestVector {
+    /// the kind of message, e.g. `transport_data`.
+    pub name: &'static str,
+    /// the encoded message, hex-encoded.
+    pub hex: String,
+}
+
+/// test_vectors returns the encoding of every kind of message and substream message.
+/// ConnectionMessages are signed as if the remote didn't know the sender's nym address,
+/// and carry no nym addresses.
+pub fn test_vectors() -> Vec<TestVector> {
+    let id = ConnectionId(CONNECTION_ID);
+    let keypair = Keypair::ed25519_from_bytes(IDENTITY_SEED).expect("valid ed25519 secret key");
+    let connection = |challenge: Option<[u8; 32]>, info: ConnectionInfo| ConnectionMessage {
+        peer_id: keypair.public().to_peer_id(),
+        id: id.clone(),
+        capabilities: Capabilities::RETRANSMIT,
+        recipient: None,
+        handshake: HandshakePayload::sign(&keypair, &id, EPHEMERAL_KEY, TIMESTAMP, None)
+            .expect("ed25519 signing doesn't fail"),
+        challenge,
+        observed: None,
+        max_message_size: MAX_MESSAGE_SIZE,
+        service: None,
+        info,
+    };
+    let migration_address =
+        Recipient::try_from_base58_string(MIGRATION_ADDRESS).expect("valid nym address");
+    let transport = |nonce: u64, message_type: SubstreamMessageType| TransportMessage {
+        nonce: Nonce::new(nonce),
+        message: SubstreamMessage {
+            substream_id: SubstreamId(SUBSTREAM_ID),
+            message_type,
+        },
+        id: id.clone(),
+        timestamp: None,
+    };
+    let secret = ResumptionSecret::from_bytes(RESUMPTION_SECRET);
+    let relay_address =
+        Recipient::try_from_base58_string(RELAY_ADDRESS).expect("valid nym address");
+    // the relayed message is a ConnectionClose, which is short
+    let relay = |hop: RelayHop| RelayMessage {
+        id: id.clone(),
+        hop,
+        payload: NativeCodec::encode(&Message::ConnectionClose(ConnectionCloseMessage {
+            id: id.clone(),
+        })),
+    };
+    let fragment = Fragment {
+        payload_id: 9,
+        index: 1,
+        count: 3,
+        data: Bytes::from_static(b"frag"),
+    };
+
+    let messages = vec![
+        (
+            "connection_request",
+            Message::ConnectionRequest(connection(
+                None,
+                ConnectionInfo {
+                    agent_version: Some(AGENT_VERSION.to_string()),
+                    extensions: vec!["ext-a".to_string(), "ext-b".to_string()],
+                },
+            )),
+        ),
+        (
+            "connection_response",
+            Message::ConnectionResponse(connection(Some(CHALLENGE), ConnectionInfo::default())),
+        ),
+        (
+            "version_mismatch",
+            Message::VersionMismatch(VersionMismatch {
+                id: id.clone(),
+                request: false,
+                version: PROTOCOL_VERSION,
+            }),
+        ),
+        (
+            "transport_open_request",
+            Message::TransportMessage(transport(1, SubstreamMessageType::OpenRequest(262144))),
+        ),
+        (
+            "transport_open_response",
+            Message::TransportMessage(transport(2, SubstreamMessageType::OpenResponse(65536))),
+        ),
+        (
+            "transport_data",
+            Message::TransportMessage(transport(
+                3,
+                SubstreamMessageType::Data(Bytes::from_static(b"hello")),
+            )),
+        ),
+        (
+            "transport_fragment",
+            Message::TransportMessage(transport(4, SubstreamMessageType::Fragment(fragment))),
+        ),
+        (
+            "transport_window_update",
+            Message::TransportMessage(transport(5, SubstreamMessageType::WindowUpdate(524288))),
+        ),
+        (
+            "transport_close",
+            Message::TransportMessage(transport(6, SubstreamMessageType::Close)),
+        ),
+        (
+            "keepalive_ping",
+            Message::KeepAlive(KeepAliveMessage {
+                id: id.clone(),
+                keepalive_type: KeepAliveType::Ping,
+                seq: 1,
+                timestamp: None,
+            }),
+        ),
+        (
+            "keepalive_pong",
+            Message::KeepAlive(KeepAliveMessage {
+                id: id.clone(),
+                keepalive_type: KeepAliveType::Pong,
+                seq: 1,
+                timestamp: None,
+            }),
+        ),
+        (
+            "ack",
+            Message::Ack(AckMessage {
+                id: id.clone(),
+                nonce: Nonce::new(3),
+            }),
+        ),
+        (
+            "datagram_request",
+            Message::Datagram(DatagramMessage {
+                id: id.clone(),
+                kind: DatagramKind::Request,
+                payload: Bytes::from_static(b"ping"),
+            }),
+        ),
+        (
+            "datagram_response",
+            Message::Datagram(DatagramMessage {
+                id: id.clone(),
+                kind: DatagramKind::Response,
+                payload: Bytes::from_static(b"pong"),
+            }),
+        ),
+        (
+            "batch",
+            Message::Batch(BatchMessage {
+                id: id.clone(),
+                messages: vec![
+                    transport(7, SubstreamMessageType::Data(Bytes::from_static(b"a"))),
+                    transport(8, SubstreamMessageType::Close),
+                ],
+            }),
+        ),
+        (
+            "connection_close",
+            Message::ConnectionClose(ConnectionCloseMessage { id: id.clone() }),
+        ),
+        (
+            "stamped_transport_open_request",
+            Message::TransportMessage(TransportMessage {
+                timestamp: Some(TIMESTAMP),
+                ..transport(1, SubstreamMessageType::OpenRequest(262144))
+            }),
+        ),
+        (
+            "stamped_keepalive_ping",
+            Message::KeepAlive(KeepAliveMessage {
+                id: id.clone(),
+                keepalive_type: KeepAliveType::Ping,
+                seq: 1,
+                timestamp: Some(TIMESTAMP),
+            }),
+        ),
+        (
+            "transport_datagram",
+            Message::TransportMessage(TransportMessage {
+                nonce: DATAGRAM_NONCE,
+                message: SubstreamMessage::new_datagram(Bytes::from_static(b"beat")),
+                id: id.clone(),
+                timestamp: None,
+            }),
+        ),
+        (
+            "migrate",
+            Message::Migrate(MigrateMessage {
+                id: id.clone(),
+                seq: 1,
+                recipient: migration_address,
+                signature: sign_migration(&keypair, &id, 1, &migration_address)
+                    .expect("ed25519 signing doesn't fail"),
+            }),
+        ),
+        (
+            "challenge_response",
+            Message::ChallengeResponse(ChallengeResponseMessage {
+                id: id.clone(),
+                signature: sign_challenge(&keypair, &id, &CHALLENGE)
+                    .expect("ed25519 signing doesn't fail"),
+            }),
+        ),
+        (
+            "session_ticket",
+            Message::SessionTicket(SessionTicketMessage {
+                id: id.clone(),
+                ticket: SESSION_TICKET.to_vec(),
+            }),
+        ),
+        (
+            "resume_request",
+            Message::ResumeRequest(ResumeRequestMessage {
+                id: id.clone(),
+                timestamp: TIMESTAMP,
+                nonce: RESUME_REQUEST_NONCE,
+                proof: secret.request_proof(&id, &RESUME_REQUEST_NONCE, TIMESTAMP),
+                ticket: SESSION_TICKET.to_vec(),
+            }),
+        ),
+        (
+            "resume_response",
+            Message::ResumeResponse(ResumeResponseMessage {
+                id: id.clone(),
+                nonce: RESUME_RESPONSE_NONCE,
+                proof: secret.response_proof(&id, &RESUME_REQUEST_NONCE, &RESUME_RESPONSE_NONCE),
+            }),
+        ),
+        (
+            "relay_reserve",
+            Message::RelayReserve(RelayReserveMessage {
+                id: id.clone(),
+                identity: keypair.public(),
+                signature: sign_relay_reservation(&keypair, &id, &relay_address)
+                    .expect("ed25519 signing doesn't fail"),
+            }),
+        ),
+        (
+            "relay_status",
+            Message::RelayStatus(RelayStatusMessage {
+                id: id.clone(),
+                status: RelayStatus::Reserved,
+                ttl_secs: 3600,
+            }),
+        ),
+        (
+            "relay_to_listener",
+            Message::Relay(relay(RelayHop::ToListener(keypair.public().to_peer_id()))),
+        ),
+        ("relay_to_dialer", Message::Relay(relay(RelayHop::ToDialer))),
+        (
+            "relay_delivered",
+            Message::Relay(relay(RelayHop::Delivered(relay_address))),
+        ),
+    ];
+
+    messages
+        .into_iter()
+        .map(|(name, message)| TestVector {
+            name,
+            hex: hex::encode(NativeCodec::encode(&message)),
+        })
+        .collect()
+}
+
+/// roundtrip decodes a message in the native wire format and encodes it again, so an
+/// implementation can check that the transport accepts the messages it encodes, and
+/// reads them as it meant them. A VersionMismatch of our own version, which only peers
+/// of other versions can read, fails to decode.
+pub fn roundtrip(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let message = NativeCodec::decode(Bytes::copy_from_slice(bytes))?;
+    Ok(NativeCodec::encode(&message).to_vec())
+}
@@ -0,0 +1,443 @@
+#[cfg(feature = "metrics")]
+use prometheus_client::{
+    encoding::EncodeLabelSet,
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+#[cfg(feature = "metrics")]
+use std::sync::{atomic::AtomicU64, Arc};
+use std::time::Duration;
+
+/// Metrics records transport-level metrics into a `prometheus-client` registry.
+/// Without the `metrics` feature, or when created with `Metrics::default()`,
+/// nothing is recorded.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    #[cfg(feature = "metrics")]
+    inner: Option<Arc<Inner>>,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct MessageLabels {
+    kind: String,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RejectionLabels {
+    reason: String,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Debug)]
+struct Inner {
+    messages_sent: Family<MessageLabels, Counter>,
+    messages_received: Family<MessageLabels, Counter>,
+    bytes_sent: Counter,
+    bytes_received: Counter,
+    active_connections: Gauge,
+    open_substreams: Gauge,
+    surb_stock: Gauge,
+    round_trip_seconds: Histogram,
+    dial_queue_seconds: Histogram,
+    throttle_seconds: Histogram,
+    smoothed_round_trip_seconds: Gauge<f64, AtomicU64>,
+    rejected_connections: Family<RejectionLabels, Counter>,
+    invalid_messages: Family<RejectionLabels, Counter>,
+    substream_open_timeouts: Counter,
+    duplicate_messages: Counter,
+    stale_messages: Family<MessageLabels, Counter>,
+    inbound_queue_depth: Gauge,
+    outbound_queue_depth: Gauge,
+    pending_dials: Gauge,
+    pending_substreams: Gauge,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    /// New metrics, registered in `registry` under the `nym` prefix.
+    pub fn new(registry: &mut Registry) -> Self {
+        let registry = registry.sub_registry_with_prefix("nym");
+
+        let inner = Inner {
+            messages_sent: Family::default(),
+            messages_received: Family::default(),
+            bytes_sent: Counter::default(),
+            bytes_received: Counter::default(),
+            active_connections: Gauge::default(),
+            open_substreams: Gauge::default(),
+            surb_stock: Gauge::default(),
+            // mixnet round trips take anywhere from ~100ms to tens of seconds
+            round_trip_seconds: Histogram::new(exponential_buckets(0.1, 2.0, 10)),
+            // most dials aren't queued at all
+            dial_queue_seconds: Histogram::new(exponential_buckets(0.001, 4.0, 10)),
+            throttle_seconds: Histogram::new(exponential_buckets(0.001, 4.0, 10)),
+            smoothed_round_trip_seconds: Gauge::default(),
+            rejected_connections: Family::default(),
+            invalid_messages: Family::default(),
+            substream_open_timeouts: Counter::default(),
+            duplicate_messages: Counter::default(),
+            stale_messages: Family::default(),
+            inbound_queue_depth: Gauge::default(),
+            outbound_queue_depth: Gauge::default(),
+            pending_dials: Gauge::default(),
+            pending_substreams: Gauge::default(),
+        };
+
+        registry.register(
+            "messages_sent",
+            "Messages written to the mixnet, by message type",
+            inner.messages_sent.clone(),
+        );
+        registry.register(
+            "messages_received",
+            "Messages read from the mixnet, by message type",
+            inner.messages_received.clone(),
+        );
+        registry.register(
+            "bytes_sent",
+            "Bytes written to the mixnet",
+            inner.bytes_sent.clone(),
+        );
+        registry.register(
+            "bytes_received",
+            "Bytes read from the mixnet",
+            inner.bytes_received.clone(),
+        );
+        registry.register(
+            "active_connections",
+            "Established connections",
+            inner.active_connections.clone(),
+        );
+        registry.register(
+            "open_substreams",
+            "Open substreams across all connections",
+            inner.open_substreams.clone(),
+        );
+        registry.register(
+            "surb_stock",
+            "Estimated number of our reply SURBs held by the peers we dialed",
+            inner.surb_stock.clone(),
+        );
+        registry.register(
+            "round_trip_seconds",
+            "Mixnet round-trip time, measured by connection keepalives",
+            inner.round_trip_seconds.clone(),
+        );
+        registry.register(
+            "dial_queue_seconds",
+            "Time dials waited for one of the concurrent dial slots",
+            inner.dial_queue_seconds.clone(),
+        );
+        registry.register(
+            "throttle_seconds",
+            "Time outbound messages waited for the throttle before being written to the mixnet",
+            inner.throttle_seconds.clone(),
+        );
+        registry.register(
+            "smoothed_round_trip_seconds",
+            "Moving average of the mixnet round-trip time of the connection measured last",
+            inner.smoothed_round_trip_seconds.clone(),
+        );
+        registry.register(
+            "rejected_connections",
+            "Inbound connection requests rejected by connection limits, by reason",
+            inner.rejected_connections.clone(),
+        );
+        registry.register(
+            "invalid_messages",
+            "Inbound messages dropped as too large or malformed, by reason",
+            inner.invalid_messages.clone(),
+        );
+        registry.register(
+            "substream_open_timeouts",
+            "Outbound substreams failed because the remote never answered their OpenRequest",
+            inner.substream_open_timeouts.clone(),
+        );
+        registry.register(
+            "duplicate_messages",
+            "Inbound messages dropped as duplicates of ones handled recently",
+            inner.duplicate_messages.clone(),
+        );
+        registry.register(
+            "stale_messages",
+            "Inbound control frames dropped for arriving too long after they were sent, by message type",
+            inner.stale_messages.clone(),
+        );
+        registry.register(
+            "inbound_queue_depth",
+            "Messages read from the mixnet and waiting for the transport to handle them",
+            inner.inbound_queue_depth.clone(),
+        );
+        registry.register(
+            "outbound_queue_depth",
+            "Messages waiting to be written to the mixnet",
+            inner.outbound_queue_depth.clone(),
+        );
+        registry.register(
+            "pending_dials",
+            "Dials waiting for the remote's response",
+            inner.pending_dials.clone(),
+        );
+        registry.register(
+            "pending_substreams",
+            "Outbound substreams waiting for the remote's OpenResponse",
+            inner.pending_substreams.clone(),
+        );
+
+        Metrics {
+            inner: Some(Arc::new(inner)),
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+impl Metrics {
+    /// is_enabled returns true if metrics are recorded, so that callers can skip
+    /// working out values that would be discarded.
+    pub(crate) fn is_enabled(&self) -> bool {
+        #[cfg(feature = "metrics")]
+        {
+            self.inner.is_some()
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            false
+        }
+    }
+
+    pub(crate) fn message_sent(&self, kind: &str, bytes: usize) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner
+                .messages_sent
+                .get_or_create(&MessageLabels {
+                    kind: kind.to_string(),
+                })
+                .inc();
+            inner.bytes_sent.inc_by(bytes as u64);
+        }
+    }
+
+    pub(crate) fn connection_rejected(&self, reason: &str) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner
+                .rejected_connections
+                .get_or_create(&RejectionLabels {
+                    reason: reason.to_string(),
+                })
+                .inc();
+        }
+    }
+
+    pub(crate) fn message_invalid(&self, reason: &str) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner
+                .invalid_messages
+                .get_or_create(&RejectionLabels {
+                    reason: reason.to_string(),
+                })
+                .inc();
+        }
+    }
+
+    pub(crate) fn message_received(&self, kind: &str, bytes: usize) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner
+                .messages_received
+                .get_or_create(&MessageLabels {
+                    kind: kind.to_string(),
+                })
+                .inc();
+            inner.bytes_received.inc_by(bytes as u64);
+        }
+    }
+
+    pub(crate) fn substream_open_timed_out(&self) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.substream_open_timeouts.inc();
+        }
+    }
+
+    pub(crate) fn message_duplicate(&self) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.duplicate_messages.inc();
+        }
+    }
+
+    pub(crate) fn message_stale(&self, kind: &str) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner
+                .stale_messages
+                .get_or_create(&MessageLabels {
+                    kind: kind.to_string(),
+                })
+                .inc();
+        }
+    }
+
+    pub(crate) fn set_surb_stock(&self, stock: u64) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.surb_stock.set(stock as i64);
+        }
+    }
+
+    pub(crate) fn observe_round_trip(&self, rtt: Duration) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.round_trip_seconds.observe(rtt.as_secs_f64());
+        }
+    }
+
+    pub(crate) fn observe_dial_queue_time(&self, time: Duration) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.dial_queue_seconds.observe(time.as_secs_f64());
+        }
+    }
+
+    pub(crate) fn observe_throttle_time(&self, time: Duration) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.throttle_seconds.observe(time.as_secs_f64());
+        }
+    }
+
+    pub(crate) fn set_smoothed_round_trip(&self, rtt: Duration) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.smoothed_round_trip_seconds.set(rtt.as_secs_f64());
+        }
+    }
+
+    pub(crate) fn set_queue_depths(&self, depths: QueueDepths) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.inbound_queue_depth.set(depths.inbound as i64);
+            inner.outbound_queue_depth.set(depths.outbound as i64);
+            inner.pending_dials.set(depths.pending_dials as i64);
+            inner
+                .pending_substreams
+                .set(depths.pending_substreams as i64);
+        }
+    }
+
+    /// track_connection counts an established connection until the returned guard is dropped.
+    pub(crate) fn track_connection(&self) -> Tracked {
+        #[cfg(feature = "metrics")]
+        let gauge = self
+            .inner
+            .as_ref()
+            .map(|inner| inner.active_connections.clone());
+        Tracked::new(
+            #[cfg(feature = "metrics")]
+            gauge,
+        )
+    }
+
+    /// track_substream counts an open substream until the returned guard is dropped.
+    pub(crate) fn track_substream(&self) -> Tracked {
+        #[cfg(feature = "metrics")]
+        let gauge = self
+            .inner
+            .as_ref()
+            .map(|inner| inner.open_substreams.clone());
+        Tracked::new(
+            #[cfg(feature = "metrics")]
+            gauge,
+        )
+    }
+}
+
+/// QueueDepths is how much work is waiting in the transport, sampled whenever it's
+/// polled, so that backpressure can be seen building up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct QueueDepths {
+    /// inbound messages queued by every mixnet client.
+    pub(crate) inbound: usize,
+    /// outbound messages queued for every mixnet client.
+    pub(crate) outbound: usize,
+    pub(crate) pending_dials: usize,
+    pub(crate) pending_substreams: usize,
+}
+
+/// Tracked keeps a gauge incremented for as long as it's alive.
+#[derive(Debug, Default)]
+pub(crate) struct Tracked {
+    #[cfg(feature = "metrics")]
+    gauge: Option<Gauge>,
+}
+
+impl Tracked {
+    fn new(#[cfg(feature = "metrics")] gauge: Option<Gauge>) -> Self {
+        #[cfg(feature = "metrics")]
+        if let Some(gauge) = &gauge {
+            gauge.inc();
+        }
+        Tracked {
+            #[cfg(feature = "metrics")]
+            gauge,
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        if let Some(gauge) = &self.gauge {
+            gauge.dec();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod test {
+    use super::*;
+    use prometheus_client::encoding::text::encode;
+
+    #[test]
+    fn test_metrics_encode() {
+        let mut registry = Registry::default();
+        let metrics = Metrics::new(&mut registry);
+        metrics.message_sent("data", 100);
+        metrics.connection_rejected("rate_limited");
+        metrics.substream_open_timed_out();
+        metrics.set_smoothed_round_trip(Duration::from_millis(1500));
+        metrics.set_queue_depths(QueueDepths {
+            inbound: 3,
+            outbound: 5,
+            pending_dials: 1,
+            pending_substreams: 2,
+        });
+        let connection = metrics.track_connection();
+        let _substream = metrics.track_substream();
+        drop(connection);
+
+        let mut out = String::new();
+        encode(&mut out, &registry).unwrap();
+        assert!(out.contains("nym_messages_sent_total{kind=\"data\"} 1"));
+        assert!(out.contains("nym_bytes_sent_total 100"));
+        assert!(out.contains("nym_active_connections 0"));
+        assert!(out.contains("nym_open_substreams 1"));
+        assert!(out.contains("nym_rejected_connections_total{reason=\"rate_limited\"} 1"));
+        assert!(out.contains("nym_substream_open_timeouts_total 1"));
+        assert!(out.contains("nym_smoothed_round_trip_seconds 1.5"));
+        assert!(out.contains("nym_inbound_queue_depth 3"));
+        assert!(out.contains("nym_outbound_queue_depth 5"));
+        assert!(out.contains("nym_pending_dials 1"));
+        assert!(out.contains("nym_pending_substreams 2"));
+    }
+}
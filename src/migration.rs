@@ -0,0 +1,103 @@
+use nym_sphinx::addressing::clients::Recipient;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::message::{ConnectionId, OutboundMessage};
+
+/// Migration is the nym address a connection's remote last moved to.
+#[derive(Clone, Copy, Debug)]
+struct Migration {
+    recipient: Recipient,
+    seq: u64,
+}
+
+/// MigrationTable holds the nym addresses that the remotes of connections have moved
+/// to since the connections were opened. It's shared by the transport, which records
+/// the moves the remotes tell it about, and the mixnet tasks, which send the messages
+/// of those connections to the new addresses. The connections and their substreams
+/// keep the route they were opened with, so they don't need to know about the move.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MigrationTable(Arc<Mutex<HashMap<ConnectionId, Migration>>>);
+
+impl MigrationTable {
+    /// migrate records that the remote of connection `id` moved to `recipient`,
+    /// unless it's already told us about a later move. It returns false if the
+    /// move was ignored.
+    pub(crate) fn migrate(&self, id: &ConnectionId, recipient: Recipient, seq: u64) -> bool {
+        let mut migrations = self.0.lock();
+        if let Some(migration) = migrations.get(id) {
+            if seq <= migration.seq {
+                return false;
+            }
+        }
+        migrations.insert(id.clone(), Migration { recipient, seq });
+        true
+    }
+
+    /// route sends `message` to the new address of its connection's remote, if the
+    /// remote has moved. The SURBs of its old address are of no use anymore, so the
+    /// message is no longer sent as a reply.
+    pub(crate) fn route(&self, message: &mut OutboundMessage) {
+        let migrations = self.0.lock();
+        if migrations.is_empty() {
+            return;
+        }
+        if let Some(migration) = migrations.get(message.message.connection_id()) {
+            message.recipient = Some(migration.recipient);
+            message.sender_tag = None;
+        }
+    }
+
+    /// remove forgets the moves of a closed connection's remote.
+    pub(crate) fn remove(&self, id: &ConnectionId) {
+        self.0.lock().remove(id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::message::{ConnectionCloseMessage, Message};
+    use super::*;
+    use nym_sdk::mixnet::AnonymousSenderTag;
+
+    fn address(s: &str) -> Recipient {
+        Recipient::try_from_base58_string(s).unwrap()
+    }
+
+    #[test]
+    fn test_migration_table() {
+        let table = MigrationTable::default();
+        let id = ConnectionId::generate();
+        let old = address("Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR");
+        let new = address("D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN");
+        let outbound = || OutboundMessage {
+            message: Message::ConnectionClose(ConnectionCloseMessage { id: id.clone() }),
+            recipient: None,
+            sender_tag: Some(AnonymousSenderTag::new_random(&mut rand::thread_rng())),
+        };
+
+        // messages of connections whose remote hasn't moved keep their route
+        let mut message = outbound();
+        table.route(&mut message);
+        assert!(message.recipient.is_none() && message.sender_tag.is_some());
+
+        // once it has, they're sent to its new address rather than as replies
+        assert!(table.migrate(&id, new, 2));
+        let mut message = outbound();
+        table.route(&mut message);
+        assert_eq!(message.recipient, Some(new));
+        assert!(message.sender_tag.is_none());
+
+        // a move older than the last one is ignored
+        assert!(!table.migrate(&id, old, 1));
+        let mut message = outbound();
+        table.route(&mut message);
+        assert_eq!(message.recipient, Some(new));
+
+        table.remove(&id);
+        let mut message = outbound();
+        table.route(&mut message);
+        assert!(message.recipient.is_none());
+    }
+}
@@ -1,209 +1,1378 @@
-use futures::{pin_mut, select};
+use bytes::Bytes;
+use futures::{future, future::poll_fn, pin_mut, select};
 use futures::{FutureExt, StreamExt};
-use log::debug;
-use nym_sdk::mixnet::{
-    AnonymousSenderTag, IncludedSurbs, MixnetClient, MixnetClientSender, MixnetMessageSender,
-};
+use nym_sdk::mixnet::{AnonymousSenderTag, IncludedSurbs};
 use nym_sphinx::addressing::clients::Recipient;
-use nym_sphinx::receiver::ReconstructedMessage;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tracing::info;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc::UnboundedSender, oneshot};
+use tracing::{debug, info, warn, Instrument, Span};
 
+use super::backend::{MixnetBackend, MixnetBackendSender};
+use super::bandwidth::{Bandwidth, MeteredSender};
+use super::batch::Batcher;
+use super::channel::{
+    bounded_with_deferred, bounded_with_priority, BoundedReceiver, BoundedSender,
+};
+#[cfg(feature = "chaos")]
+use super::chaos::Chaos;
+use super::codec::{Codec, WireCodec};
+use super::config::{NymTransportConfig, OverflowPolicy, ReconnectConfig, SendRetryConfig};
+use super::datagram::DatagramRouter;
 use super::error::Error;
+use super::events::{DropReason, EventSender, NymEvent};
+use super::limit::{Admission, InboundFilter, ReplyLimiter};
 use super::message::*;
+use super::metrics::Metrics;
+use super::migration::MigrationTable;
+use super::pool::{CodecJob, CodecPool, Decoded, MAX_CODEC_BATCH};
+use super::relay::RelayTable;
+use super::retransmit::Retransmitter;
+use super::rtt::RttTable;
+use super::runtime::{sleep, sleep_until, spawn, Instant, TaskHandle};
+use super::send_retry::RetryingSender;
+use super::stats::StatsTable;
+use super::surb::SurbBudget;
+use super::throttle::{Throttle, ThrottledSender};
+
+/// MixnetStatus is sent from the mixnet task to the transport when the state
+/// of the underlying mixnet client changes, or a message the transport has to
+/// follow up on is written or lost.
+#[derive(Debug)]
+pub(crate) enum MixnetStatus {
+    /// the client disconnected and a replacement is being connected.
+    Reconnecting,
+    /// a replacement client is connected, with the given nym address.
+    Reconnected(Recipient),
+    /// the client disconnected and won't be replaced; the task has exited.
+    Disconnected,
+    /// a message on the given connection was never acknowledged, despite retransmissions.
+    DeliveryFailed(ConnectionId),
+    /// the ConnectionRequest of the given dial was written to the mixnet.
+    RequestSent(ConnectionId),
+    /// the ConnectionRequest of the given dial couldn't be written to the mixnet, for
+    /// the given reason.
+    RequestFailed(ConnectionId, String),
+    /// the client started routing through the topology of the given epoch.
+    TopologyChanged(u64),
+}
+
+/// MixnetTask is a handle to the background task started by [`initialize_mixnet`].
+/// Dropping it has the same effect as calling [`MixnetTask::shutdown`], except
+/// that nothing waits for the task to finish.
+pub(crate) struct MixnetTask {
+    shutdown_tx: oneshot::Sender<()>,
+    handle: TaskHandle,
+    /// the SURBs the task has sent, shared with it
+    surbs: Arc<Mutex<SurbBudget>>,
+}
+
+impl MixnetTask {
+    /// surbs_remaining returns the estimated number of the task's SURBs held by each
+    /// peer it's sent them to.
+    pub(crate) fn surbs_remaining(&self) -> Vec<(Recipient, u64)> {
+        self.surbs
+            .lock()
+            .remaining()
+            .map(|(recipient, remaining)| (*recipient, remaining))
+            .collect()
+    }
+
+    /// forget_connection stops attributing replies to connection `id`, once it's closed.
+    pub(crate) fn forget_connection(&self, id: &ConnectionId) {
+        self.surbs.lock().remove(id);
+    }
 
-/// initialize_mixnet initializes a read/write connection to a Nym Client.
+    /// shutdown makes the task write out every queued outbound message,
+    /// disconnect the mixnet client and exit, and waits for it to do so.
+    pub(crate) async fn shutdown(self) -> Result<(), Error> {
+        // the task may have already exited, in which case there's nothing to signal
+        self.shutdown_tx.send(()).ok();
+        self.handle
+            .join()
+            .await
+            .map_err(|_| Error::MixnetTaskFailure)
+    }
+}
+
+/// initialize_mixnet initializes a read/write connection to a mixnet backend, usually a Nym Client.
 /// It starts a task that listens for inbound messages from the endpoint and writes outbound messages to the endpoint.
+/// If the client disconnects and `config.reconnect` is set, the task replaces it and carries on.
+/// Inbound datagrams are handed to `datagrams` rather than the inbound channel.
+/// Outbound messages of connections whose remote moved are sent to the address in `migrations`.
+/// Messages of relayed connections are wrapped for, and unwrapped from, their relay in `relays`.
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn initialize_mixnet(
-    client: MixnetClient,
-    notify_inbound_tx: Option<UnboundedSender<()>>,
+    client: impl MixnetBackend,
+    status_tx: Option<UnboundedSender<MixnetStatus>>,
+    events: EventSender,
+    datagrams: DatagramRouter,
+    rtt: RttTable,
+    stats: StatsTable,
+    migrations: MigrationTable,
+    relays: RelayTable,
+    config: &NymTransportConfig,
 ) -> Result<
     (
         Recipient,
-        UnboundedReceiver<InboundMessage>,
-        UnboundedSender<OutboundMessage>,
+        BoundedReceiver<InboundMessage>,
+        BoundedSender<OutboundMessage>,
+        MixnetTask,
     ),
     Error,
 > {
-    let recipient = *client.nym_address();
+    let recipient = client.our_address();
 
     // a channel of inbound messages from the mixnet..
     // the transport reads from (listens) to the inbound_rx.
-    // TODO: this is probably a DOS vector; we should limit the size of the channel.
-    let (inbound_tx, inbound_rx) = unbounded_channel::<InboundMessage>();
+    // the channel is bounded so a flood of mixnet packets can't exhaust memory;
+    // the configured overflow policy decides what happens once it's full.
+    // connection requests are queued apart, so that the transport can leave them
+    // unread while the swarm is behind on accepting connections.
+    let (inbound_tx, inbound_rx) = bounded_with_deferred::<InboundMessage>(
+        config.inbound_channel_capacity,
+        config.overflow_policy,
+        |msg| matches!(msg.0, Message::ConnectionRequest(_)),
+    );
 
     // a channel of outbound messages to be written to the mixnet.
     // the transport writes to outbound_tx.
-    let (outbound_tx, mut outbound_rx) = unbounded_channel::<OutboundMessage>();
+    // outbound messages are never dropped, so writers wait for capacity instead.
+    // control messages skip ahead of queued substream data, so that handshakes
+    // and closes aren't held up by bulk transfers.
+    let (outbound_tx, mut outbound_rx) = bounded_with_priority::<OutboundMessage>(
+        config.outbound_channel_capacity,
+        OverflowPolicy::Backpressure,
+        OutboundMessage::is_control,
+    );
 
-    let sink = client.split_sender();
-    let mut stream = client;
+    // with credentials, every message sent is charged against the client's bandwidth
+    let bandwidth = config
+        .credentials
+        .clone()
+        .map(|credentials| Bandwidth::new(credentials, events.clone()));
+    let send_retry = config.send_retry;
+    // shared across reconnects, like the bandwidth estimate
+    let throttle = config
+        .throttle
+        .map(|throttle| Throttle::new(throttle, config.metrics.clone()));
+    #[cfg(feature = "chaos")]
+    let chaos = config.chaos.clone();
+    let mut stream: Box<dyn MixnetBackend> = Box::new(client);
+    #[cfg(feature = "chaos")]
+    {
+        stream = chaotic(stream, &chaos);
+    }
+    let mut sink = retrying(
+        throttled(metered(stream.sender(), &bandwidth), &throttle),
+        send_retry,
+    );
+    let reconnect = config.reconnect.clone();
+    let surbs = Arc::new(Mutex::new(SurbBudget::new(config.surbs)));
+    let task_surbs = surbs.clone();
+    let retransmitter = config
+        .retransmit
+        .map(|retransmit| Mutex::new(Retransmitter::new(retransmit, rtt)));
+    let batcher = config
+        .batch_window
+        .map(|window| Mutex::new(Batcher::new(window, config.max_fragment_size)));
+    let mut filter = InboundFilter::new(config.max_message_size, config.max_invalid_messages);
+    let limiter = Mutex::new(ReplyLimiter::new(config.amplification_limit));
+    let metrics = config.metrics.clone();
+    let pool = CodecPool::new(config)?;
+    // messages handed to the pool, which are kept across iterations of the loop until
+    // they're done, and those it encoded together, which are written one at a time
+    let mut decoding = None;
+    let mut encoding = None;
+    let mut encoded = VecDeque::new();
+    let mut topology = config.topology_check_interval.map(TopologyWatch::new);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
 
-    tokio::task::spawn(async move {
+    let handle = spawn(async move {
+        let mut shutdown_rx = shutdown_rx.fuse();
         loop {
-            let t1 = check_inbound(&mut stream, &inbound_tx, &notify_inbound_tx).fuse();
-            let t2 = check_outbound(&sink, &mut outbound_rx).fuse();
+            // checked between the other tasks, since it needs the client to itself
+            if let Some(watch) = &mut topology {
+                if let Some(epoch) = watch.check(stream.as_mut()).await {
+                    info!("mixnet client is routing through topology epoch {}", epoch);
+                    send_status(&status_tx, MixnetStatus::TopologyChanged(epoch));
+                }
+            }
+
+            let res = {
+                let t1 = check_inbound(
+                    stream.as_mut(),
+                    &inbound_tx,
+                    &pool,
+                    &mut decoding,
+                    &mut filter,
+                    &limiter,
+                    &surbs,
+                    retransmitter.as_ref(),
+                    &datagrams,
+                    &relays,
+                    &metrics,
+                    &stats,
+                    &events,
+                )
+                .fuse();
+                let t2 = check_outbound(
+                    sink.as_ref(),
+                    &mut outbound_rx,
+                    &pool,
+                    &mut encoding,
+                    &mut encoded,
+                    throttle.as_ref(),
+                    &surbs,
+                    &limiter,
+                    retransmitter.as_ref(),
+                    batcher.as_ref(),
+                    &migrations,
+                    &relays,
+                    &metrics,
+                    &stats,
+                    &events,
+                    &status_tx,
+                )
+                .fuse();
+                let t3 = check_retransmit(
+                    sink.as_ref(),
+                    &surbs,
+                    &limiter,
+                    retransmitter.as_ref(),
+                    &migrations,
+                    &relays,
+                    &metrics,
+                    &stats,
+                    &events,
+                    &status_tx,
+                )
+                .fuse();
+                let t4 = check_batches(
+                    sink.as_ref(),
+                    &surbs,
+                    &limiter,
+                    batcher.as_ref(),
+                    &relays,
+                    &metrics,
+                    &stats,
+                    &events,
+                )
+                .fuse();
+                let t5 = check_released(
+                    sink.as_ref(),
+                    &surbs,
+                    &limiter,
+                    &relays,
+                    &metrics,
+                    &stats,
+                    &events,
+                )
+                .fuse();
+                let t6 = check_topology(topology.as_ref()).fuse();
+
+                pin_mut!(t1, t2, t3, t4, t5, t6);
+
+                select! {
+                    res = t1 => res,
+                    res = t2 => res,
+                    res = t3 => res,
+                    res = t4 => res,
+                    res = t5 => res,
+                    res = t6 => res,
+                    // either an explicit shutdown, or the MixnetTask handle was dropped
+                    _ = &mut shutdown_rx => Err(Error::MixnetTaskShutdown),
+                }
+            };
 
-            pin_mut!(t1, t2);
+            match res {
+                Err(Error::MixnetClientDisconnected) => {}
+                Err(Error::MixnetTaskShutdown) | Err(Error::RecvFailure) => {
+                    // RecvFailure means every outbound sender is gone, so nothing
+                    // can be written to the mixnet anymore either.
+                    debug!("shutting down mixnet task");
+                    if let Some(Encoding { spans, job }) = encoding.take() {
+                        match job.await {
+                            Ok(messages) => encoded.extend(encoded_with(spans, messages)),
+                            Err(e) => warn!("failed to encode messages on shutdown: {}", e),
+                        }
+                    }
+                    for Encoded {
+                        span,
+                        message,
+                        bytes,
+                    } in encoded.drain(..)
+                    {
+                        if let Err(e) = write_message(
+                            sink.as_ref(),
+                            message,
+                            bytes,
+                            &surbs,
+                            Some(&limiter),
+                            &metrics,
+                            &stats,
+                            &events,
+                        )
+                        .instrument(span)
+                        .await
+                        {
+                            warn!("failed to flush outbound message on shutdown: {}", e);
+                        }
+                    }
+                    let batched = match &batcher {
+                        Some(batcher) => batcher.lock().drain(),
+                        None => vec![],
+                    };
+                    for message in batched {
+                        if let Err(e) = write_outbound(
+                            sink.as_ref(),
+                            message,
+                            &surbs,
+                            Some(&limiter),
+                            &relays,
+                            &metrics,
+                            &stats,
+                            &events,
+                        )
+                        .await
+                        {
+                            warn!("failed to flush batched message on shutdown: {}", e);
+                        }
+                    }
+                    while let Some(message) = outbound_rx.try_recv() {
+                        if let Err(e) = write_outbound(
+                            sink.as_ref(),
+                            message,
+                            &surbs,
+                            Some(&limiter),
+                            &relays,
+                            &metrics,
+                            &stats,
+                            &events,
+                        )
+                        .await
+                        {
+                            warn!("failed to flush outbound message on shutdown: {}", e);
+                        }
+                    }
+                    stream.disconnect().await;
+                    return;
+                }
+                _ => continue,
+            }
 
-            select! {
-                _ = t1 => {},
-                _ = t2 => {},
+            let Some(reconnect) = &reconnect else {
+                warn!("mixnet client disconnected; no reconnect configured");
+                send_status(&status_tx, MixnetStatus::Disconnected);
+                return;
             };
+
+            warn!("mixnet client disconnected; reconnecting");
+            send_status(&status_tx, MixnetStatus::Reconnecting);
+            let client = reconnect_client(reconnect).await;
+            #[cfg(feature = "chaos")]
+            let client = chaotic(client, &chaos);
+            let address = client.our_address();
+            if address != recipient {
+                warn!(
+                    "reconnected with a different nym address {}; expected {}",
+                    address, recipient
+                );
+            }
+
+            sink = retrying(
+                throttled(metered(client.sender(), &bandwidth), &throttle),
+                send_retry,
+            );
+            let old = std::mem::replace(&mut stream, client);
+            old.disconnect().await;
+            info!("mixnet client reconnected as {}", address);
+            send_status(&status_tx, MixnetStatus::Reconnected(address));
         }
     });
 
-    Ok((recipient, inbound_rx, outbound_tx))
+    Ok((
+        recipient,
+        inbound_rx,
+        outbound_tx,
+        MixnetTask {
+            shutdown_tx,
+            handle,
+            surbs: task_surbs,
+        },
+    ))
 }
 
-async fn check_inbound(
-    client: &mut MixnetClient,
-    inbound_tx: &UnboundedSender<InboundMessage>,
-    notify_inbound_tx: &Option<UnboundedSender<()>>,
-) -> Result<(), Error> {
-    if let Some(msg) = client.next().await {
-        if let Some(notify_tx) = notify_inbound_tx {
-            notify_tx
-                .send(())
-                .map_err(|e| Error::InboundSendFailure(e.to_string()))?;
+/// chaotic wraps `client` so that `chaos`, if any, injects faults into the messages
+/// it sends and receives.
+#[cfg(feature = "chaos")]
+fn chaotic(client: Box<dyn MixnetBackend>, chaos: &Option<Chaos>) -> Box<dyn MixnetBackend> {
+    match chaos {
+        Some(chaos) => chaos.wrap(client),
+        None => client,
+    }
+}
+
+/// metered wraps `sink` so that it charges the messages it sends against `bandwidth`, if any.
+fn metered(
+    sink: Box<dyn MixnetBackendSender>,
+    bandwidth: &Option<Bandwidth>,
+) -> Box<dyn MixnetBackendSender> {
+    match bandwidth {
+        Some(bandwidth) => Box::new(MeteredSender::new(sink, bandwidth.clone())),
+        None => sink,
+    }
+}
+
+/// throttled wraps `sink` so that it charges the messages it sends against `throttle`, if any.
+fn throttled(
+    sink: Box<dyn MixnetBackendSender>,
+    throttle: &Option<Throttle>,
+) -> Box<dyn MixnetBackendSender> {
+    match throttle {
+        Some(throttle) => Box::new(ThrottledSender::new(sink, throttle.clone())),
+        None => sink,
+    }
+}
+
+/// retrying wraps `sink` so that it retries writes that fail with a transient error,
+/// if send retries are enabled.
+fn retrying(
+    sink: Box<dyn MixnetBackendSender>,
+    send_retry: Option<SendRetryConfig>,
+) -> Box<dyn MixnetBackendSender> {
+    match send_retry {
+        Some(send_retry) => Box::new(RetryingSender::new(sink, send_retry)),
+        None => sink,
+    }
+}
+
+/// TopologyWatch checks which epoch's network topology a mixnet client routes through,
+/// every `interval`, so that the transport can report when it changes.
+struct TopologyWatch {
+    interval: Duration,
+    next_check: Instant,
+    epoch: Option<u64>,
+}
+
+impl TopologyWatch {
+    fn new(interval: Duration) -> Self {
+        TopologyWatch {
+            interval,
+            next_check: Instant::now(),
+            epoch: None,
         }
+    }
 
-        handle_inbound(msg, inbound_tx).await?;
+    /// check reads the epoch of `client`'s topology if a check is due, and returns it
+    /// if it's changed since the last check.
+    async fn check(&mut self, client: &mut dyn MixnetBackend) -> Option<u64> {
+        let now = Instant::now();
+        if now < self.next_check {
+            return None;
+        }
+        self.next_check = now + self.interval;
+        let epoch = client.topology_epoch().await?;
+        if self.epoch == Some(epoch) {
+            return None;
+        }
+        self.epoch = Some(epoch);
+        Some(epoch)
     }
+}
 
-    Err(Error::Unimplemented)
+/// check_topology waits until the topology is due to be checked, which is done before
+/// the tasks are polled again. It never resolves if the topology isn't checked.
+async fn check_topology(watch: Option<&TopologyWatch>) -> Result<(), Error> {
+    let Some(watch) = watch else {
+        return future::pending().await;
+    };
+    sleep_until(watch.next_check).await;
+    Ok(())
 }
 
-async fn handle_inbound(
-    msg: ReconstructedMessage,
-    inbound_tx: &UnboundedSender<InboundMessage>,
+fn send_status(status_tx: &Option<UnboundedSender<MixnetStatus>>, status: MixnetStatus) {
+    if let Some(status_tx) = status_tx {
+        // the transport may have been dropped, in which case no one is listening
+        status_tx.send(status).ok();
+    }
+}
+
+/// reconnect_client builds a replacement mixnet client, retrying with
+/// exponential backoff until it succeeds.
+async fn reconnect_client(reconnect: &ReconnectConfig) -> Box<dyn MixnetBackend> {
+    let mut backoff = reconnect.initial_backoff;
+    loop {
+        sleep(backoff).await;
+        match (reconnect.connect)().await {
+            Ok(client) => return client,
+            Err(e) => {
+                warn!("failed to reconnect mixnet client: {}", e);
+                backoff = reconnect.next_backoff(backoff);
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn check_inbound(
+    client: &mut dyn MixnetBackend,
+    inbound_tx: &BoundedSender<InboundMessage>,
+    pool: &CodecPool,
+    decoding: &mut Option<CodecJob<Vec<Decoded>>>,
+    filter: &mut InboundFilter,
+    limiter: &Mutex<ReplyLimiter>,
+    surbs: &Mutex<SurbBudget>,
+    retransmitter: Option<&Mutex<Retransmitter>>,
+    datagrams: &DatagramRouter,
+    relays: &RelayTable,
+    metrics: &Metrics,
+    stats: &StatsTable,
+    events: &EventSender,
 ) -> Result<(), Error> {
-    let sender_tag = msg.sender_tag.clone();
+    // messages read before we last lost a race to another task are still being decoded
+    if let Some(job) = decoding {
+        let decoded = job.await;
+        *decoding = None;
+        return handle_decoded(
+            decoded?,
+            inbound_tx,
+            filter,
+            limiter,
+            surbs,
+            retransmitter,
+            datagrams,
+            relays,
+            metrics,
+            stats,
+            events,
+        )
+        .await;
+    }
 
-    let data = parse_message_data(&msg.message, sender_tag)?;
+    // wait for room in the inbound channel before reading from the client, so that
+    // with OverflowPolicy::Backpressure we stop pulling messages off the mixnet
+    // instead of buffering them.
     inbound_tx
-        .send(data)
-        .map_err(|e| Error::InboundSendFailure(e.to_string()))?;
+        .ready()
+        .await
+        .map_err(|_| Error::InboundSendFailure("inbound channel closed".to_string()))?;
+
+    let Some(msg) = client.next().await else {
+        // the client's stream only ends once it has lost its gateway connection
+        return Err(Error::MixnetClientDisconnected);
+    };
+    let mut messages = vec![msg];
+    // with a worker pool, the messages that have already arrived are decoded together,
+    // as many as the inbound channel has room for
+    if pool.is_parallel() {
+        let max = inbound_tx.room().clamp(1, MAX_CODEC_BATCH);
+        while messages.len() < max {
+            match client.next().now_or_never() {
+                Some(Some(msg)) => messages.push(msg),
+                _ => break,
+            }
+        }
+    }
+    // what blocked senders send is dropped unparsed
+    messages.retain(|msg| {
+        let blocked = filter.is_blocked(msg.sender_tag.as_ref());
+        if blocked {
+            events.emit(NymEvent::MessageDropped {
+                reason: DropReason::BlockedSender,
+            });
+        }
+        !blocked
+    });
+
+    let job = decoding.insert(pool.decode(messages, filter.max_message_size()));
+    let decoded = job.await;
+    *decoding = None;
+    handle_decoded(
+        decoded?,
+        inbound_tx,
+        filter,
+        limiter,
+        surbs,
+        retransmitter,
+        datagrams,
+        relays,
+        metrics,
+        stats,
+        events,
+    )
+    .await
+}
+
+/// handle_decoded routes the messages decoded together, in order.
+#[allow(clippy::too_many_arguments)]
+async fn handle_decoded(
+    decoded: Vec<Decoded>,
+    inbound_tx: &BoundedSender<InboundMessage>,
+    filter: &mut InboundFilter,
+    limiter: &Mutex<ReplyLimiter>,
+    surbs: &Mutex<SurbBudget>,
+    retransmitter: Option<&Mutex<Retransmitter>>,
+    datagrams: &DatagramRouter,
+    relays: &RelayTable,
+    metrics: &Metrics,
+    stats: &StatsTable,
+    events: &EventSender,
+) -> Result<(), Error> {
+    for decoded in decoded {
+        handle_inbound(
+            decoded,
+            inbound_tx,
+            filter,
+            limiter,
+            surbs,
+            retransmitter,
+            datagrams,
+            relays,
+            metrics,
+            stats,
+            events,
+        )
+        .await?;
+    }
     Ok(())
 }
 
-async fn check_outbound(
-    mixnet_sender: &MixnetClientSender,
-    outbound_rx: &mut UnboundedReceiver<OutboundMessage>,
+/// handle_inbound routes a message read from the mixnet. Messages that are too large or
+/// failed to parse are dropped, and count against their sender tag. Messages a relay
+/// delivers to us are unwrapped, and routed as if they'd been sent to us directly.
+#[allow(clippy::too_many_arguments)]
+async fn handle_inbound(
+    decoded: Decoded,
+    inbound_tx: &BoundedSender<InboundMessage>,
+    filter: &mut InboundFilter,
+    limiter: &Mutex<ReplyLimiter>,
+    surbs: &Mutex<SurbBudget>,
+    retransmitter: Option<&Mutex<Retransmitter>>,
+    datagrams: &DatagramRouter,
+    relays: &RelayTable,
+    metrics: &Metrics,
+    stats: &StatsTable,
+    events: &EventSender,
 ) -> Result<(), Error> {
-    match outbound_rx.recv().await {
-        Some(message) => {
-            match &message.message {
-                Message::TransportMessage(tm) => match &tm.message.message_type {
-                    SubstreamMessageType::OpenResponse => {
-                        debug!("Outbound OpenResponse: nonce={}, substream={:?}, has_surb={}, has_recipient={}",
-                                               tm.nonce, tm.message.substream_id,
-                                               message.sender_tag.is_some(), message.recipient.is_some());
-                    }
-                    SubstreamMessageType::OpenRequest => {
-                        debug!("Outbound OpenRequest: nonce={}, substream={:?}, has_surb={}, has_recipient={}",
-                                               tm.nonce, tm.message.substream_id,
-                                               message.sender_tag.is_some(), message.recipient.is_some());
-                    }
-                    SubstreamMessageType::Data(_) => {
-                        debug!(
-                            "Outbound Data nonce={}, substream={:?}",
-                            tm.nonce, tm.message.substream_id
-                        );
-                    }
-                    SubstreamMessageType::Close => {
-                        debug!(
-                            "Outbound Close nonce={}, substream={:?}",
-                            tm.nonce, tm.message.substream_id
-                        );
-                    }
-                },
-                Message::ConnectionRequest(_) => debug!("OUTBOUND ConnectionRequest"),
-                Message::ConnectionResponse(_) => debug!("OUTBOUND ConnectionResponse"),
+    let Decoded {
+        len,
+        sender_tag,
+        data,
+    } = decoded;
+    // the sender may have been blocked by a message decoded along with this one
+    if filter.is_blocked(sender_tag.as_ref()) {
+        events.emit(NymEvent::MessageDropped {
+            reason: DropReason::BlockedSender,
+        });
+        return Ok(());
+    }
+
+    let data = match data {
+        Ok(data) => data,
+        Err(e) => {
+            debug!("dropping invalid message of {} bytes: {}", len, e);
+            metrics.message_invalid(invalid_reason(&e));
+            events.emit(NymEvent::MessageDropped {
+                reason: DropReason::Invalid,
+            });
+            if filter.on_invalid(sender_tag.as_ref()) {
+                warn!("blocking sender tag after repeated invalid messages");
             }
-            match (&message.recipient, &message.sender_tag) {
-                (_, Some(sender_tag)) => {
-                    // sender_tag for anonymous replies
-                    debug!(
-                        "writing reply to sender_tag {:?}",
-                        sender_tag.to_base58_string()
-                    );
-                    write_reply_bytes(
-                        mixnet_sender,
-                        sender_tag.clone(),
-                        &message.message.to_bytes(),
-                    )
-                    .await
+            return Ok(());
+        }
+    };
+    // what a sender tag sends us lets us send it more
+    if let Some(sender_tag) = &sender_tag {
+        limiter.lock().on_receive(sender_tag, len);
+    }
+    // messages forwarded to the relay's other peers are left to the transport
+    let data = match data {
+        InboundMessage(Message::Relay(msg), sender_tag)
+            if matches!(msg.hop, RelayHop::Delivered(_)) =>
+        {
+            match relays.deliver(msg) {
+                Some(msg) => InboundMessage(msg, sender_tag),
+                None => {
+                    debug!("dropping relayed message of {} bytes off our circuits", len);
+                    events.emit(NymEvent::MessageDropped {
+                        reason: DropReason::Invalid,
+                    });
+                    return Ok(());
                 }
-                (Some(recipient), None) => {
-                    // recipient for initial messages
-                    debug!("sending message to recipient {:}", recipient);
-                    write_bytes(
-                        mixnet_sender,
-                        recipient.clone(),
-                        &message.message.to_bytes(),
-                    )
+            }
+        }
+        data => data,
+    };
+    let span = data.0.span(Direction::Inbound);
+    route_inbound(
+        data,
+        len,
+        inbound_tx,
+        surbs,
+        retransmitter,
+        datagrams,
+        metrics,
+        stats,
+        events,
+    )
+    .instrument(span)
+    .await
+}
+
+/// invalid_reason labels why a message failed to parse, for metrics.
+fn invalid_reason(e: &Error) -> &'static str {
+    match e {
+        Error::InboundMessageTooLarge(_) => "too_large",
+        Error::UnknownMessageType(_) | Error::InvalidSubstreamMessageType => "unknown_type",
+        _ => "malformed",
+    }
+}
+
+/// route_inbound hands a message read from the mixnet to whichever part of the
+/// transport handles it.
+#[allow(clippy::too_many_arguments)]
+async fn route_inbound(
+    data: InboundMessage,
+    len: usize,
+    inbound_tx: &BoundedSender<InboundMessage>,
+    surbs: &Mutex<SurbBudget>,
+    retransmitter: Option<&Mutex<Retransmitter>>,
+    datagrams: &DatagramRouter,
+    metrics: &Metrics,
+    stats: &StatsTable,
+    events: &EventSender,
+) -> Result<(), Error> {
+    debug!(len, has_sender_tag = data.1.is_some(), "read message");
+    metrics.message_received(data.0.kind(), len);
+    stats.on_received(&data.0, len);
+    match &data.0 {
+        // a request sent through a reply route carries the dialer's address, but the
+        // listener mustn't reveal its own in return
+        Message::ConnectionRequest(req)
+            if req.recipient.is_some() && !req.capabilities.contains(Capabilities::REPLY_ROUTE) =>
+        {
+            surbs.lock().expose_self_address(&req.id);
+        }
+        // no sender_tag means the message is a reply sent using one of our SURBs,
+        // unless it's on a connection where the dialer exposed its address
+        msg if data.1.is_none() => {
+            let mut surbs = surbs.lock();
+            if !surbs.is_exposed(msg.connection_id()) {
+                surbs.on_reply(msg.connection_id());
+                // a datagram request is answered once, and has no connection to close
+                if let Message::Datagram(DatagramMessage {
+                    id,
+                    kind: DatagramKind::Response,
+                    ..
+                }) = msg
+                {
+                    surbs.remove(id);
+                }
+                metrics.set_surb_stock(surbs.total_remaining());
+            }
+        }
+        _ => {}
+    }
+
+    // acks are only of interest to the retransmitter, so they stop here
+    if let Message::Ack(ack) = &data.0 {
+        if let Some(retransmitter) = retransmitter {
+            retransmitter.lock().on_ack(ack);
+        }
+        return Ok(());
+    }
+
+    // datagrams don't belong to any connection, so they're routed from here
+    if let Message::Datagram(datagram) = data.0 {
+        if !datagrams.route(datagram, data.1) {
+            events.emit(NymEvent::MessageDropped {
+                reason: DropReason::InboundChannelFull,
+            });
+        }
+        return Ok(());
+    }
+
+    let InboundMessage(msg, sender_tag) = data;
+    let dropped = inbound_tx.dropped();
+    match msg {
+        // batches are unpacked here, so the transport handles each TransportMessage on its own
+        Message::Batch(batch) => {
+            for msg in batch.messages {
+                debug!(parent: &msg.span(Direction::Inbound), "unpacked from batch");
+                inbound_tx
+                    .send(InboundMessage(
+                        Message::TransportMessage(msg),
+                        sender_tag.clone(),
+                    ))
                     .await
+                    .map_err(|e| Error::InboundSendFailure(e.to_string()))?;
+            }
+        }
+        msg => inbound_tx
+            .try_send(InboundMessage(msg, sender_tag))
+            .map_err(|e| Error::InboundSendFailure(e.to_string()))?,
+    }
+    if inbound_tx.dropped() > dropped {
+        events.emit(NymEvent::MessageDropped {
+            reason: DropReason::InboundChannelFull,
+        });
+    }
+    Ok(())
+}
+
+/// Encoding is a batch of outbound messages handed to the codec pool, along with the
+/// spans they're written in.
+struct Encoding {
+    spans: Vec<Span>,
+    job: CodecJob<Vec<(OutboundMessage, Bytes)>>,
+}
+
+/// Encoded is an outbound message encoded by the codec pool, waiting to be written.
+struct Encoded {
+    span: Span,
+    message: OutboundMessage,
+    bytes: Bytes,
+}
+
+/// check_outbound writes the next queued outbound message to the mixnet, or hands it
+/// to the batcher if batching is enabled. With a throttle, the message waits in the
+/// queue until the throttle allows it.
+/// The outbound channel hands out control messages before substream data. With a
+/// worker pool, the messages already queued are taken along with it and encoded
+/// together; they wait in `encoding` until the pool is done with them, then in
+/// `encoded`, and are written one per call.
+/// Whether a dial's ConnectionRequest was written is reported to the transport, which
+/// starts the dial's handshake timeout once it is.
+#[allow(clippy::too_many_arguments)]
+async fn check_outbound(
+    mixnet_sender: &dyn MixnetBackendSender,
+    outbound_rx: &mut BoundedReceiver<OutboundMessage>,
+    pool: &CodecPool,
+    encoding: &mut Option<Encoding>,
+    encoded: &mut VecDeque<Encoded>,
+    throttle: Option<&Throttle>,
+    surbs: &Mutex<SurbBudget>,
+    limiter: &Mutex<ReplyLimiter>,
+    retransmitter: Option<&Mutex<Retransmitter>>,
+    batcher: Option<&Mutex<Batcher>>,
+    migrations: &MigrationTable,
+    relays: &RelayTable,
+    metrics: &Metrics,
+    stats: &StatsTable,
+    events: &EventSender,
+    status_tx: &Option<UnboundedSender<MixnetStatus>>,
+) -> Result<(), Error> {
+    if let Some(throttle) = throttle {
+        throttle.ready().await;
+    }
+    if encoded.is_empty() && encoding.is_none() {
+        let Some(message) = outbound_rx.recv().await else {
+            return Err(Error::RecvFailure);
+        };
+        let mut messages = vec![message];
+        if pool.is_parallel() {
+            while messages.len() < MAX_CODEC_BATCH {
+                let Some(message) = outbound_rx.try_recv() else {
+                    break;
+                };
+                messages.push(message);
+            }
+        }
+
+        let mut ready = vec![];
+        for mut message in messages {
+            migrations.route(&mut message);
+            // tracked before writing, so that a message the gateway didn't accept is
+            // retried too. retransmissions are sent on their own rather than batched.
+            if let Some(retransmitter) = retransmitter {
+                retransmitter.lock().on_send(&message, Instant::now());
+            }
+            match batcher {
+                Some(batcher) => ready.extend(batcher.lock().push(message, Instant::now())),
+                None => ready.push(message),
+            }
+        }
+        *encoding = Some(encode_outbound(pool, relays, ready));
+    }
+    if let Some(Encoding { spans, job }) = encoding {
+        let messages = job.await;
+        let spans = std::mem::take(spans);
+        *encoding = None;
+        encoded.extend(encoded_with(spans, messages?));
+    }
+
+    // the batcher may be holding on to every message
+    let Some(Encoded {
+        span,
+        message,
+        bytes,
+    }) = encoded.pop_front()
+    else {
+        return Ok(());
+    };
+    // requests aren't batched, so they're only written here
+    let request = match &message.message {
+        Message::ConnectionRequest(req) => Some(req.id.clone()),
+        _ => None,
+    };
+    let res = write_message(
+        mixnet_sender,
+        message,
+        bytes,
+        surbs,
+        Some(limiter),
+        metrics,
+        stats,
+        events,
+    )
+    .instrument(span)
+    .await;
+    if let Some(id) = request {
+        let status = match &res {
+            Ok(()) => MixnetStatus::RequestSent(id),
+            Err(e) => MixnetStatus::RequestFailed(id, e.to_string()),
+        };
+        send_status(status_tx, status);
+    }
+    res
+}
+
+/// encode_outbound wraps `messages` for their connections' relays, if any, and hands
+/// them to `pool` to be encoded, keeping their order.
+fn encode_outbound(
+    pool: &CodecPool,
+    relays: &RelayTable,
+    messages: Vec<OutboundMessage>,
+) -> Encoding {
+    let spans = messages
+        .iter()
+        .map(|message| message.message.span(Direction::Outbound))
+        .collect();
+    let messages = messages
+        .into_iter()
+        .map(|message| relays.route(message))
+        .collect();
+    Encoding {
+        spans,
+        job: pool.encode(messages),
+    }
+}
+
+/// encoded_with pairs the messages the pool encoded with the spans they're written in.
+fn encoded_with(
+    spans: Vec<Span>,
+    messages: Vec<(OutboundMessage, Bytes)>,
+) -> impl Iterator<Item = Encoded> {
+    spans
+        .into_iter()
+        .zip(messages)
+        .map(|(span, (message, bytes))| Encoded {
+            span,
+            message,
+            bytes,
+        })
+}
+
+/// check_batches waits until the next batch of outbound messages is due and writes
+/// every batch that's due. It never resolves if batching is disabled or nothing is batched.
+#[allow(clippy::too_many_arguments)]
+async fn check_batches(
+    mixnet_sender: &dyn MixnetBackendSender,
+    surbs: &Mutex<SurbBudget>,
+    limiter: &Mutex<ReplyLimiter>,
+    batcher: Option<&Mutex<Batcher>>,
+    relays: &RelayTable,
+    metrics: &Metrics,
+    stats: &StatsTable,
+    events: &EventSender,
+) -> Result<(), Error> {
+    let Some(batcher) = batcher else {
+        return future::pending().await;
+    };
+    let Some(deadline) = batcher.lock().next_deadline() else {
+        return future::pending().await;
+    };
+    sleep_until(deadline).await;
+
+    let due = batcher.lock().poll_due(Instant::now());
+    for message in due {
+        write_outbound(
+            mixnet_sender,
+            message,
+            surbs,
+            Some(limiter),
+            relays,
+            metrics,
+            stats,
+            events,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// check_released waits until replies held by the amplification limit can be sent,
+/// and writes them. It never resolves if nothing is held.
+async fn check_released(
+    mixnet_sender: &dyn MixnetBackendSender,
+    surbs: &Mutex<SurbBudget>,
+    limiter: &Mutex<ReplyLimiter>,
+    relays: &RelayTable,
+    metrics: &Metrics,
+    stats: &StatsTable,
+    events: &EventSender,
+) -> Result<(), Error> {
+    let released = poll_fn(|cx| limiter.lock().poll_released(cx)).await;
+    for message in released {
+        // their sender tag's allowance was already charged for them
+        write_outbound(
+            mixnet_sender,
+            message,
+            surbs,
+            None,
+            relays,
+            metrics,
+            stats,
+            events,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// check_retransmit waits until the next unacknowledged message is due and
+/// retransmits every message that's due, reporting connections that have failed.
+/// It never resolves if retransmission is disabled or nothing is waiting on an ack.
+#[allow(clippy::too_many_arguments)]
+async fn check_retransmit(
+    mixnet_sender: &dyn MixnetBackendSender,
+    surbs: &Mutex<SurbBudget>,
+    limiter: &Mutex<ReplyLimiter>,
+    retransmitter: Option<&Mutex<Retransmitter>>,
+    migrations: &MigrationTable,
+    relays: &RelayTable,
+    metrics: &Metrics,
+    stats: &StatsTable,
+    events: &EventSender,
+    status_tx: &Option<UnboundedSender<MixnetStatus>>,
+) -> Result<(), Error> {
+    let Some(retransmitter) = retransmitter else {
+        return future::pending().await;
+    };
+    let Some(deadline) = retransmitter.lock().next_deadline() else {
+        return future::pending().await;
+    };
+    sleep_until(deadline).await;
+
+    let (due, failed) = retransmitter.lock().poll_due(Instant::now());
+    for id in failed {
+        send_status(status_tx, MixnetStatus::DeliveryFailed(id));
+    }
+    for mut message in due {
+        // the remote may have moved since the message was first sent
+        migrations.route(&mut message);
+        debug!(
+            "retransmitting {} on connection {:?}",
+            message.message.kind(),
+            message.message.connection_id()
+        );
+        write_outbound(
+            mixnet_sender,
+            message,
+            surbs,
+            Some(limiter),
+            relays,
+            metrics,
+            stats,
+            events,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// write_outbound writes a message to the mixnet, wrapped for its connection's relay
+/// if the connection is relayed.
+#[allow(clippy::too_many_arguments)]
+async fn write_outbound(
+    mixnet_sender: &dyn MixnetBackendSender,
+    message: OutboundMessage,
+    surbs: &Mutex<SurbBudget>,
+    limiter: Option<&Mutex<ReplyLimiter>>,
+    relays: &RelayTable,
+    metrics: &Metrics,
+    stats: &StatsTable,
+    events: &EventSender,
+) -> Result<(), Error> {
+    let span = message.message.span(Direction::Outbound);
+    let message = relays.route(message);
+    let bytes = WireCodec::encode(&message.message);
+    write_message(
+        mixnet_sender,
+        message,
+        bytes,
+        surbs,
+        limiter,
+        metrics,
+        stats,
+        events,
+    )
+    .instrument(span)
+    .await
+}
+
+/// write_message writes a message, encoded as `bytes`, to the mixnet, to its recipient
+/// or using the SURBs of its sender_tag. Replies are subject to `limiter`, which may
+/// hold them to be written later, or drop them.
+#[allow(clippy::too_many_arguments)]
+async fn write_message(
+    mixnet_sender: &dyn MixnetBackendSender,
+    message: OutboundMessage,
+    bytes: Bytes,
+    surbs: &Mutex<SurbBudget>,
+    limiter: Option<&Mutex<ReplyLimiter>>,
+    metrics: &Metrics,
+    stats: &StatsTable,
+    events: &EventSender,
+) -> Result<(), Error> {
+    debug!(
+        has_sender_tag = message.sender_tag.is_some(),
+        has_recipient = message.recipient.is_some(),
+        "writing message"
+    );
+    let message = match (limiter, message.sender_tag.clone()) {
+        // what we forward as a relay is no more than what the circuit's other end sent us
+        (Some(_), Some(_)) if matches!(message.message, Message::Relay(_)) => message,
+        (Some(limiter), Some(sender_tag)) => {
+            match limiter.lock().admit(&sender_tag, message, bytes.len()) {
+                Admission::Send(message) => message,
+                Admission::Held => {
+                    debug!("holding reply until its sender tag sends more");
+                    return Ok(());
                 }
-                (None, None) => {
-                    debug!("No recipient or sender_tag provided, cannot route messag");
-                    return Err(Error::OutboundSendFailure(
-                        "No recipient or sender_tag provided, cannot route message".to_string(),
-                    ));
+                Admission::Dropped => {
+                    debug!("dropping reply over the amplification limit");
+                    events.emit(NymEvent::MessageDropped {
+                        reason: DropReason::AmplificationLimit,
+                    });
+                    return Ok(());
                 }
             }
         }
-        None => Err(Error::RecvFailure),
+        _ => message,
+    };
+    let res = match (&message.recipient, &message.sender_tag) {
+        (_, Some(sender_tag)) => {
+            // sender_tag for anonymous replies
+            debug!(
+                "writing reply to sender_tag {:?}",
+                sender_tag.to_base58_string()
+            );
+            write_reply_bytes(mixnet_sender, sender_tag.clone(), &bytes).await
+        }
+        (Some(recipient), None) => {
+            // recipient for initial messages
+            debug!("sending message to recipient {:}", recipient);
+            let included_surbs = {
+                let mut surbs = surbs.lock();
+                let id = message.message.connection_id();
+                if let Message::ConnectionRequest(req) = &message.message {
+                    if req.recipient.is_some() {
+                        surbs.expose_self_address(id);
+                    }
+                }
+
+                if surbs.is_exposed(id) {
+                    IncludedSurbs::ExposeSelfAddress
+                } else {
+                    if let Some(remaining) = surbs.low_remaining(recipient) {
+                        events.emit(NymEvent::SurbLow { remaining });
+                    }
+                    let count = match &message.message {
+                        Message::ConnectionRequest(_) => surbs.on_dial(*recipient, id),
+                        _ => surbs.on_send(*recipient, id),
+                    };
+                    metrics.set_surb_stock(surbs.total_remaining());
+                    IncludedSurbs::Amount(count)
+                }
+            };
+            write_bytes(mixnet_sender, recipient.clone(), &bytes, included_surbs).await
+        }
+        (None, None) => {
+            debug!("No recipient or sender_tag provided, cannot route messag");
+            Err(Error::OutboundSendFailure(
+                "No recipient or sender_tag provided, cannot route message".to_string(),
+            ))
+        }
+    };
+
+    if res.is_ok() {
+        metrics.message_sent(message.message.kind(), bytes.len());
+        stats.on_sent(&message.message, bytes.len());
     }
+    res
 }
 
 async fn write_bytes(
-    mixnet_sender: &MixnetClientSender,
+    mixnet_sender: &dyn MixnetBackendSender,
     recipient: Recipient,
     message: &[u8],
+    included_surbs: IncludedSurbs,
 ) -> Result<(), Error> {
-    if let Err(_err) = mixnet_sender
-        .send_message(recipient, message, IncludedSurbs::default()) // was IncludedSurbs::ExposeSelfAddress
-        .await
-    {
-        return Err(Error::Unimplemented);
-    }
+    mixnet_sender
+        .send(recipient, message, included_surbs)
+        .await?;
     debug!("wrote message to recipient: {:?}", recipient.to_string());
     Ok(())
 }
 
 async fn write_reply_bytes(
-    mixnet_sender: &MixnetClientSender,
+    mixnet_sender: &dyn MixnetBackendSender,
     sender_tag: AnonymousSenderTag,
     message: &[u8],
 ) -> Result<(), Error> {
-    if let Err(_err) = mixnet_sender.send_reply(sender_tag, message).await {
-        return Err(Error::Unimplemented);
-    }
+    mixnet_sender.send_reply(sender_tag, message).await?;
     debug!("wrote reply to sender_tag: {:?}", sender_tag.to_string());
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
+    use super::super::config::{NymTransportConfig, ReconnectConfig};
+    use super::super::datagram::DatagramRouter;
+    use super::super::error::Error;
+    use super::super::events::EventSender;
     use super::super::message::{
         self, ConnectionId, Message, SubstreamId, SubstreamMessage, SubstreamMessageType,
         TransportMessage,
     };
+    use super::super::migration::MigrationTable;
     use super::super::mixnet::initialize_mixnet;
-    use nym_sdk::mixnet::MixnetClient;
+    use super::super::nonce::Nonce;
+    use super::super::relay::RelayTable;
+    use super::super::rtt::RttTable;
+    use super::super::stats::StatsTable;
+    use super::super::transport::connect_test_client;
+    use super::{MixnetBackend, MixnetBackendSender, TopologyWatch};
+    use bytes::Bytes;
+    use futures::future::{self, BoxFuture, FutureExt};
+    use nym_sdk::mixnet::{AnonymousSenderTag, IncludedSurbs, MixnetClient};
+    use nym_sphinx::addressing::clients::Recipient;
+    use nym_sphinx::receiver::ReconstructedMessage;
+    use std::time::Duration;
+
+    /// Discard is a sender which drops everything written to it.
+    struct Discard;
+
+    impl MixnetBackendSender for Discard {
+        fn send<'a>(
+            &'a self,
+            _recipient: Recipient,
+            _message: &'a [u8],
+            _surbs: IncludedSurbs,
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            future::ready(Ok(())).boxed()
+        }
+
+        fn send_reply<'a>(
+            &'a self,
+            _sender_tag: AnonymousSenderTag,
+            _message: &'a [u8],
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            future::ready(Ok(())).boxed()
+        }
+    }
+
+    /// Epochs is a backend whose topology epoch is set by the test. Nothing is ever
+    /// read from it, and anything written to it is dropped.
+    struct Epochs {
+        address: Recipient,
+        epoch: Option<u64>,
+    }
+
+    impl MixnetBackend for Epochs {
+        fn our_address(&self) -> Recipient {
+            self.address
+        }
+
+        fn sender(&self) -> Box<dyn MixnetBackendSender> {
+            Box::new(Discard)
+        }
+
+        fn next(&mut self) -> BoxFuture<'_, Option<ReconstructedMessage>> {
+            future::pending().boxed()
+        }
+
+        fn disconnect(self: Box<Self>) -> BoxFuture<'static, ()> {
+            future::ready(()).boxed()
+        }
+
+        fn topology_epoch(&mut self) -> BoxFuture<'_, Option<u64>> {
+            future::ready(self.epoch).boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_topology_watch() {
+        let mut client = Epochs {
+            address: Recipient::try_from_base58_string("D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN").unwrap(),
+            epoch: Some(7),
+        };
+        let mut watch = TopologyWatch::new(Duration::ZERO);
+        assert_eq!(watch.check(&mut client).await, Some(7));
+        // an epoch is only reported when it changes
+        assert_eq!(watch.check(&mut client).await, None);
+        client.epoch = Some(8);
+        assert_eq!(watch.check(&mut client).await, Some(8));
+
+        // and the topology isn't checked again until the interval is up
+        let mut watch = TopologyWatch::new(Duration::from_secs(3600));
+        assert_eq!(watch.check(&mut client).await, Some(8));
+        client.epoch = Some(9);
+        assert_eq!(watch.check(&mut client).await, None);
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let reconnect = ReconnectConfig::new(MixnetClient::connect_new)
+            .with_backoff(Duration::from_secs(1), Duration::from_secs(5));
+        let backoff = reconnect.next_backoff(reconnect.initial_backoff);
+        assert_eq!(backoff, Duration::from_secs(2));
+        let backoff = reconnect.next_backoff(backoff);
+        assert_eq!(backoff, Duration::from_secs(4));
+        let backoff = reconnect.next_backoff(backoff);
+        assert_eq!(backoff, Duration::from_secs(5));
+    }
 
     #[tokio::test]
     async fn test_mixnet_poll_inbound_and_outbound() {
-        let client = MixnetClient::connect_new().await.unwrap();
-        let (self_address, mut inbound_rx, outbound_tx) =
-            initialize_mixnet(client, None).await.unwrap();
+        let client = connect_test_client().await;
+        let (self_address, mut inbound_rx, outbound_tx, _mixnet_task) = initialize_mixnet(
+            client,
+            None,
+            EventSender::default(),
+            DatagramRouter::default(),
+            RttTable::default(),
+            StatsTable::default(),
+            MigrationTable::default(),
+            RelayTable::default(),
+            &NymTransportConfig::default(),
+        )
+        .await
+        .unwrap();
         let msg_inner = "hello".as_bytes();
         let substream_id = SubstreamId::generate();
         let msg = Message::TransportMessage(TransportMessage {
-            nonce: 1, // arbitrary
+            nonce: Nonce::FIRST, // arbitrary
             id: ConnectionId::generate(),
-            message: SubstreamMessage::new_with_data(substream_id.clone(), msg_inner.to_vec()),
+            message: SubstreamMessage::new_with_data(
+                substream_id.clone(),
+                Bytes::from_static(msg_inner),
+            ),
+            timestamp: None,
         });
 
         // send a message to ourselves through the mixnet
@@ -213,14 +1382,14 @@ mod test {
             sender_tag: None,
         };
 
-        outbound_tx.send(out_msg).unwrap();
+        outbound_tx.try_send(out_msg).unwrap();
 
         // receive the message from ourselves over the mixnet
         let received_msg = inbound_rx.recv().await.unwrap();
         if let Message::TransportMessage(recv_msg) = received_msg.0 {
             assert_eq!(substream_id, recv_msg.message.substream_id);
             if let SubstreamMessageType::Data(data) = recv_msg.message.message_type {
-                assert_eq!(msg_inner, data.as_slice());
+                assert_eq!(msg_inner, &data[..]);
             } else {
                 panic!("expected SubstreamMessage::Data")
             }
@@ -0,0 +1,12 @@
+//! The transport's link to the mixnet. A [`NymTransport`](crate::transport::NymTransport)
+//! sends and receives its messages through a [`MixnetBackend`]: a nym-sdk
+//! [`MixnetClient`](nym_sdk::mixnet::MixnetClient), or a client of an
+//! [`InMemoryMixnet`] to test an application without a live mixnet. With the `chaos`
+//! feature, a [`Chaos`] can inject faults into the messages exchanged with either.
+//! A [`SharedNymTransportFactory`] lets several transports share one client.
+
+pub use super::backend::{MixnetBackend, MixnetBackendSender};
+#[cfg(feature = "chaos")]
+pub use super::chaos::{Chaos, ChaosConfig};
+pub use super::memory::{InMemoryClient, InMemoryConfig, InMemoryMixnet};
+pub use super::shared::{SharedBackend, SharedNymTransportFactory};
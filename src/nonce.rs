@@ -0,0 +1,133 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use super::error::Error;
+
+/// REHANDSHAKE_MARGIN is the number of nonces left in either direction of a
+/// connection at which the connection is closed, so that it's redialed with a
+/// fresh handshake long before a nonce could repeat.
+pub(crate) const REHANDSHAKE_MARGIN: u64 = 1 << 32;
+
+/// Nonce orders the TransportMessages sent in one direction of a connection.
+/// Nonces never wrap around: a connection whose nonces run out is closed and
+/// redialed instead, since a repeated nonce would be dropped as a replay.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct Nonce(u64);
+
+impl Nonce {
+    /// the nonce of the first TransportMessage sent over a connection. Nonce 0
+    /// stands for the ConnectionRequest or ConnectionResponse.
+    pub(crate) const FIRST: Nonce = Nonce(1);
+    /// the last nonce; it's never sent, so that the nonce after every nonce a
+    /// connection handles exists.
+    pub(crate) const MAX: Nonce = Nonce(u64::MAX);
+
+    pub(crate) const fn new(nonce: u64) -> Self {
+        Nonce(nonce)
+    }
+
+    pub(crate) const fn get(self) -> u64 {
+        self.0
+    }
+
+    /// checked_next returns the nonce after this one, or None if this is the last.
+    pub(crate) fn checked_next(self) -> Option<Nonce> {
+        self.0.checked_add(1).map(Nonce)
+    }
+
+    /// offset_from returns how many nonces this one is ahead of `base`, or None
+    /// if it comes before it.
+    pub(crate) fn offset_from(self, base: Nonce) -> Option<u64> {
+        self.0.checked_sub(base.0)
+    }
+
+    /// needs_rehandshake returns true once fewer than [`REHANDSHAKE_MARGIN`]
+    /// nonces are left after this one.
+    pub(crate) fn needs_rehandshake(self) -> bool {
+        u64::MAX - self.0 <= REHANDSHAKE_MARGIN
+    }
+}
+
+impl fmt::Display for Nonce {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// NonceCounter hands out the nonces of the TransportMessages a connection sends.
+/// It's shared by the connection, its substreams and its handle; the nonces of the
+/// messages it receives are tracked by its message queue.
+#[derive(Clone, Debug)]
+pub(crate) struct NonceCounter(Arc<AtomicU64>);
+
+impl NonceCounter {
+    pub(crate) fn new() -> Self {
+        Self::starting_at(Nonce::FIRST)
+    }
+
+    pub(crate) fn starting_at(nonce: Nonce) -> Self {
+        NonceCounter(Arc::new(AtomicU64::new(nonce.get())))
+    }
+
+    /// next returns the nonce of the next message sent over the connection, or
+    /// [`Error::NoncesExhausted`] once only [`Nonce::MAX`] is left.
+    pub(crate) fn next(&self) -> Result<Nonce, Error> {
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |nonce| {
+                nonce.checked_add(1)
+            })
+            .map(Nonce)
+            .map_err(|_| Error::NoncesExhausted)
+    }
+
+    /// reset starts the nonces over from [`Nonce::FIRST`] for every holder of the
+    /// counter, once the connection has been resumed with fresh keys.
+    pub(crate) fn reset(&self) {
+        self.0.store(Nonce::FIRST.get(), Ordering::SeqCst);
+    }
+
+    /// peek returns the nonce the next message will be sent with.
+    pub(crate) fn peek(&self) -> Nonce {
+        Nonce(self.0.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nonce() {
+        assert_eq!(Nonce::FIRST.checked_next(), Some(Nonce::new(2)));
+        assert_eq!(Nonce::MAX.checked_next(), None);
+
+        assert_eq!(Nonce::new(5).offset_from(Nonce::new(3)), Some(2));
+        assert_eq!(Nonce::new(3).offset_from(Nonce::new(3)), Some(0));
+        assert_eq!(Nonce::new(3).offset_from(Nonce::new(5)), None);
+
+        assert!(!Nonce::FIRST.needs_rehandshake());
+        assert!(!Nonce::new(u64::MAX - REHANDSHAKE_MARGIN - 1).needs_rehandshake());
+        assert!(Nonce::new(u64::MAX - REHANDSHAKE_MARGIN).needs_rehandshake());
+        assert!(Nonce::MAX.needs_rehandshake());
+    }
+
+    #[test]
+    fn test_nonce_counter() {
+        let counter = NonceCounter::new();
+        assert_eq!(counter.peek(), Nonce::FIRST);
+        assert_eq!(counter.next().unwrap(), Nonce::FIRST);
+        assert_eq!(counter.clone().next().unwrap(), Nonce::new(2));
+        assert_eq!(counter.peek(), Nonce::new(3));
+        counter.clone().reset();
+        assert_eq!(counter.next().unwrap(), Nonce::FIRST);
+
+        // the counter stops short of the last nonce rather than wrapping around
+        let counter = NonceCounter::starting_at(Nonce::new(u64::MAX - 2));
+        assert_eq!(counter.next().unwrap(), Nonce::new(u64::MAX - 2));
+        assert_eq!(counter.next().unwrap(), Nonce::new(u64::MAX - 1));
+        assert!(matches!(counter.next(), Err(Error::NoncesExhausted)));
+        assert!(matches!(counter.next(), Err(Error::NoncesExhausted)));
+        assert_eq!(counter.peek(), Nonce::MAX);
+    }
+}
@@ -0,0 +1,207 @@
+//! Byte streams over the mixnet without a libp2p swarm. A [`NymListener`] runs a
+//! [`NymTransport`] in the background, accepting streams from peers that dial it and
+//! dialing streams of its own; each [`NymStream`] is a connection carrying a single
+//! substream, read and written like a TCP stream.
+
+use futures::{
+    future::{poll_fn, Either},
+    pin_mut, select, FutureExt,
+};
+use libp2p::core::{
+    multiaddr::Multiaddr,
+    transport::{DialOpts, ListenerId, PortUse, TransportError, TransportEvent},
+    Endpoint, Transport,
+};
+use libp2p_identity::PeerId;
+use log::debug;
+use std::pin::Pin;
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+
+use super::error::Error;
+use super::runtime::spawn;
+use super::transport::NymTransport;
+
+pub use super::stream::NymStream;
+
+/// DialRequest asks the background task to dial `addr`.
+struct DialRequest {
+    addr: Multiaddr,
+    stream_tx: oneshot::Sender<Result<NymStream, Error>>,
+}
+
+/// NymListener listens for streams at the nym address of the transport it's bound to,
+/// and dials streams to other listeners. The transport is driven by a background task,
+/// which shuts it down once the listener is dropped; streams stop receiving data then.
+pub struct NymListener {
+    local_addr: Multiaddr,
+    peer_id: PeerId,
+    dial_tx: UnboundedSender<DialRequest>,
+    incoming_rx: UnboundedReceiver<NymStream>,
+}
+
+impl NymListener {
+    /// bind starts listening on the transport's nym address.
+    pub fn bind(mut transport: NymTransport) -> Result<Self, Error> {
+        let local_addr = transport.listen_addr().clone();
+        transport
+            .listen_on(ListenerId::next(), local_addr.clone())
+            .map_err(from_transport_error)?;
+
+        let peer_id = transport.peer_id();
+        let (dial_tx, dial_rx) = unbounded_channel();
+        let (incoming_tx, incoming_rx) = unbounded_channel();
+        spawn(drive(transport, dial_rx, incoming_tx));
+        Ok(NymListener {
+            local_addr,
+            peer_id,
+            dial_tx,
+            incoming_rx,
+        })
+    }
+
+    /// local_addr returns the multiaddr other listeners connect to us at.
+    pub fn local_addr(&self) -> &Multiaddr {
+        &self.local_addr
+    }
+
+    /// peer_id returns the PeerId we authenticate as to peers that dial us.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// accept waits for a peer to open a stream to us. Connections whose handshake
+    /// fails are skipped.
+    pub async fn accept(&mut self) -> Result<NymStream, Error> {
+        self.incoming_rx.recv().await.ok_or(Error::RecvFailure)
+    }
+
+    /// connect opens a stream to the listener at `addr`.
+    pub async fn connect(&self, addr: Multiaddr) -> Result<NymStream, Error> {
+        let (stream_tx, stream_rx) = oneshot::channel();
+        self.dial_tx
+            .send(DialRequest { addr, stream_tx })
+            .map_err(|_| Error::RecvFailure)?;
+        stream_rx.await?
+    }
+}
+
+/// drive polls the transport, which routes inbound messages to the connections,
+/// until the listener is dropped.
+async fn drive(
+    mut transport: NymTransport,
+    mut dial_rx: UnboundedReceiver<DialRequest>,
+    incoming_tx: UnboundedSender<NymStream>,
+) {
+    loop {
+        let next = {
+            let request = dial_rx.recv().fuse();
+            let event = poll_fn(|cx| Pin::new(&mut transport).poll(cx)).fuse();
+            pin_mut!(request, event);
+            select! {
+                request = request => Either::Left(request),
+                event = event => Either::Right(event),
+            }
+        };
+
+        match next {
+            Either::Left(Some(DialRequest { addr, stream_tx })) => {
+                let dial_opts = DialOpts {
+                    role: Endpoint::Dialer,
+                    port_use: PortUse::Reuse,
+                };
+                match transport.dial(addr, dial_opts) {
+                    Ok(dial) => {
+                        spawn(async move {
+                            let res = match dial.await {
+                                Ok((_, connection)) => NymStream::open(connection).await,
+                                Err(e) => Err(e),
+                            };
+                            // the connect future may have been dropped
+                            stream_tx.send(res).ok();
+                        });
+                    }
+                    Err(e) => {
+                        stream_tx.send(Err(from_transport_error(e))).ok();
+                    }
+                }
+            }
+            Either::Left(None) => {
+                debug!("listener dropped; shutting down transport");
+                if let Err(e) = transport.shutdown().await {
+                    debug!("failed to shut down transport: {}", e);
+                }
+                return;
+            }
+            Either::Right(TransportEvent::Incoming { upgrade, .. }) => {
+                let incoming_tx = incoming_tx.clone();
+                spawn(async move {
+                    let res = match upgrade.await {
+                        Ok((_, connection)) => NymStream::accept(connection).await,
+                        Err(e) => Err(e),
+                    };
+                    match res {
+                        Ok(stream) => {
+                            incoming_tx.send(stream).ok();
+                        }
+                        Err(e) => debug!("failed to accept stream: {}", e),
+                    }
+                });
+            }
+            Either::Right(event) => debug!("ignoring transport event {:?}", event),
+        }
+    }
+}
+
+fn from_transport_error(e: TransportError<Error>) -> Error {
+    match e {
+        TransportError::MultiaddrNotSupported(_) => Error::InvalidProtocolForMultiaddr,
+        TransportError::Other(e) => e,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::NymTransportConfig;
+    use super::super::memory::{InMemoryConfig, InMemoryMixnet};
+    use super::super::testing;
+    use super::*;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_nym_listener_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let listener = || async {
+            let transport = testing::transport(&mixnet, NymTransportConfig::default()).await;
+            NymListener::bind(transport).unwrap()
+        };
+        let dialer = listener().await;
+        let mut listener = listener().await;
+
+        let (dialer_stream, listener_stream) = tokio::join!(
+            dialer.connect(listener.local_addr().clone()),
+            listener.accept()
+        );
+        let mut dialer_stream = dialer_stream.unwrap();
+        let mut listener_stream = listener_stream.unwrap();
+        assert_eq!(dialer_stream.peer_id(), listener.peer_id());
+
+        dialer_stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        listener_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        listener_stream.write_all(b"world").await.unwrap();
+        dialer_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+
+        // a multiaddr that isn't a nym address can't be dialed
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        assert!(matches!(
+            dialer.connect(addr).await,
+            Err(Error::InvalidProtocolForMultiaddr)
+        ));
+    }
+}
@@ -0,0 +1,132 @@
+use futures::{Stream, StreamExt};
+use libp2p::core::{transport::PortUse, Endpoint, Multiaddr};
+use libp2p::swarm::{
+    dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent,
+    THandlerOutEvent, ToSwarm,
+};
+use libp2p_identity::PeerId;
+use std::convert::Infallible;
+use std::fmt::{Debug, Formatter};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::events::NymEvent;
+use super::transport::NymTransport;
+
+/// Behaviour hands the swarm the nym addresses the peers we dial have received our
+/// ConnectionRequests from, as [`ToSwarm::NewExternalAddrCandidate`]s, like the
+/// observed addresses identify reports for TCP. It has no protocol of its own, so it
+/// can be combined with any other behaviour, e.g. one confirming the candidates.
+///
+/// Only dials that expose our nym address (see [`crate::config::AnonymityMode`])
+/// are answered with the address they were received from.
+pub struct Behaviour {
+    events: Pin<Box<dyn Stream<Item = NymEvent> + Send>>,
+}
+
+impl Behaviour {
+    /// new returns a behaviour reporting the addresses observed by `transport`'s remotes,
+    /// from now on; create it before handing the transport to the swarm.
+    pub fn new(transport: &NymTransport) -> Self {
+        Behaviour {
+            events: Box::pin(transport.events()),
+        }
+    }
+}
+
+impl Debug for Behaviour {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Behaviour").finish_non_exhaustive()
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+        _: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, _: FromSwarm) {}
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        // the stream only ends once the transport is dropped, along with the swarm
+        while let Poll::Ready(Some(event)) = self.events.poll_next_unpin(cx) {
+            if let NymEvent::ObservedAddress { address, .. } = event {
+                return Poll::Ready(ToSwarm::NewExternalAddrCandidate(address));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::{AnonymityMode, NymTransportConfig};
+    use super::super::memory::{InMemoryConfig, InMemoryMixnet};
+    use super::super::testing::{swarm, swarm_with_transport};
+    use super::*;
+    use libp2p::swarm::SwarmEvent;
+    use libp2p_identity::Keypair;
+
+    #[tokio::test]
+    async fn test_observed_address_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let keypair = Keypair::generate_ed25519();
+        let transport = NymTransport::new_with_backend(
+            mixnet.client(),
+            keypair.clone(),
+            NymTransportConfig::default().with_anonymity(AnonymityMode::ExposeSelfAddress),
+        )
+        .await
+        .unwrap();
+        let dialer_addr = transport.listen_addr().clone();
+        let behaviour = Behaviour::new(&transport);
+        let mut dialer = swarm_with_transport(keypair, transport, |_| behaviour);
+        let (mut listener, addr) = swarm(&mixnet, |_| dummy::Behaviour).await;
+        dialer.dial(addr).unwrap();
+
+        // the listener answers with the address it received the dial from
+        let address = loop {
+            tokio::select! {
+                event = dialer.select_next_some() => {
+                    if let SwarmEvent::NewExternalAddrCandidate { address } = event {
+                        break address;
+                    }
+                }
+                _ = listener.select_next_some() => {}
+            }
+        };
+        assert_eq!(address, dialer_addr);
+    }
+}
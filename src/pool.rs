@@ -0,0 +1,291 @@
+use bytes::Bytes;
+use nym_sdk::mixnet::AnonymousSenderTag;
+use nym_sphinx::receiver::ReconstructedMessage;
+#[cfg(feature = "parallel-codec")]
+use rayon::prelude::*;
+use std::future::Future;
+#[cfg(feature = "parallel-codec")]
+use std::sync::Arc;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(feature = "parallel-codec")]
+use tokio::sync::oneshot;
+
+use super::codec::{decode_inbound, Codec, WireCodec};
+use super::config::NymTransportConfig;
+use super::error::Error;
+use super::message::{InboundMessage, OutboundMessage};
+
+/// maximum number of messages the mixnet task hands the pool at once; more would
+/// only hold up the first of them.
+pub(crate) const MAX_CODEC_BATCH: usize = 64;
+
+/// Decoded is a message read from the mixnet, once it's been decoded.
+pub(crate) struct Decoded {
+    /// the length of the message as it was read.
+    pub(crate) len: usize,
+    pub(crate) sender_tag: Option<AnonymousSenderTag>,
+    pub(crate) data: Result<InboundMessage, Error>,
+}
+
+/// decode decodes a message read from the mixnet, rejecting it if it's larger than
+/// `max_size` bytes. The message's buffer is handed over as-is, so with the native
+/// codec payloads are never copied out of it.
+pub(crate) fn decode(msg: ReconstructedMessage, max_size: usize) -> Decoded {
+    let len = msg.message.len();
+    let sender_tag = msg.sender_tag;
+    let data = decode_inbound::<WireCodec>(msg.message.into(), sender_tag.clone(), max_size);
+    Decoded {
+        len,
+        sender_tag,
+        data,
+    }
+}
+
+/// CodecJob is the work handed to a [`CodecPool`], which resolves to its result once
+/// it's done. The worker threads carry on with it while the job isn't polled, so a job
+/// raced against other futures is kept and awaited again rather than dropped, which
+/// would lose its messages.
+pub(crate) enum CodecJob<T> {
+    /// done on the calling task; taken when the job resolves.
+    Done(Option<T>),
+    #[cfg(feature = "parallel-codec")]
+    Pending(oneshot::Receiver<T>),
+}
+
+// the result is moved out, never pinned
+impl<T> Unpin for CodecJob<T> {}
+
+impl<T> Future for CodecJob<T> {
+    type Output = Result<T, Error>;
+
+    #[cfg_attr(not(feature = "parallel-codec"), allow(unused_variables))]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.get_mut() {
+            CodecJob::Done(result) => {
+                Poll::Ready(Ok(result.take().expect("CodecJob polled after completion")))
+            }
+            #[cfg(feature = "parallel-codec")]
+            CodecJob::Pending(rx) => Pin::new(rx).poll(cx).map_err(|_| {
+                // the job's sender is only dropped unsent if the worker panicked
+                Error::CodecPoolFailure("a worker panicked".to_string())
+            }),
+        }
+    }
+}
+
+/// CodecPool encodes and decodes the messages the mixnet task exchanges with the mixnet
+/// on a pool of worker threads, with the `parallel-codec` feature and
+/// `NymTransportConfig::codec_workers` set. Otherwise, or for a single message, the
+/// work is done on the calling task.
+///
+/// The messages handed to the pool together are returned in the order they were handed
+/// over, so the messages of each connection keep their order. The workers never run on
+/// the async runtime's threads: the caller awaits the returned [`CodecJob`], leaving
+/// its thread free for other tasks in the meantime.
+#[derive(Clone, Default)]
+pub(crate) struct CodecPool {
+    #[cfg(feature = "parallel-codec")]
+    pool: Option<Arc<rayon::ThreadPool>>,
+}
+
+#[cfg_attr(not(feature = "parallel-codec"), allow(unused_variables))]
+impl CodecPool {
+    /// new returns the pool configured by `config`.
+    pub(crate) fn new(config: &NymTransportConfig) -> Result<Self, Error> {
+        #[cfg(feature = "parallel-codec")]
+        if let Some(workers) = config.codec_workers {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(workers)
+                .thread_name(|i| format!("nym-codec-{i}"))
+                .build()
+                .map_err(|e| Error::CodecPoolFailure(format!("failed to start: {e}")))?;
+            return Ok(CodecPool {
+                pool: Some(Arc::new(pool)),
+            });
+        }
+        Ok(CodecPool::default())
+    }
+
+    /// is_parallel returns true if the pool has worker threads, so that it's worth
+    /// handing it several messages at once.
+    pub(crate) fn is_parallel(&self) -> bool {
+        #[cfg(feature = "parallel-codec")]
+        {
+            self.pool.is_some()
+        }
+        #[cfg(not(feature = "parallel-codec"))]
+        {
+            false
+        }
+    }
+
+    /// decode decodes `messages` (see [`decode`]), returning them in the same order.
+    pub(crate) fn decode(
+        &self,
+        messages: Vec<ReconstructedMessage>,
+        max_size: usize,
+    ) -> CodecJob<Vec<Decoded>> {
+        #[cfg(feature = "parallel-codec")]
+        if let Some(pool) = self.pool.as_ref().filter(|_| messages.len() > 1) {
+            return spawn_job(pool, move || {
+                messages
+                    .into_par_iter()
+                    .map(|msg| decode(msg, max_size))
+                    .collect()
+            });
+        }
+        CodecJob::Done(Some(
+            messages
+                .into_iter()
+                .map(|msg| decode(msg, max_size))
+                .collect(),
+        ))
+    }
+
+    /// encode encodes `messages`, returning each with its bytes in the same order.
+    pub(crate) fn encode(
+        &self,
+        messages: Vec<OutboundMessage>,
+    ) -> CodecJob<Vec<(OutboundMessage, Bytes)>> {
+        #[cfg(feature = "parallel-codec")]
+        if let Some(pool) = self.pool.as_ref().filter(|_| messages.len() > 1) {
+            return spawn_job(pool, move || {
+                messages
+                    .into_par_iter()
+                    .map(|message| {
+                        let bytes = WireCodec::encode(&message.message);
+                        (message, bytes)
+                    })
+                    .collect()
+            });
+        }
+        CodecJob::Done(Some(
+            messages
+                .into_iter()
+                .map(|message| {
+                    let bytes = WireCodec::encode(&message.message);
+                    (message, bytes)
+                })
+                .collect(),
+        ))
+    }
+}
+
+/// spawn_job runs `work` on `pool`, whose threads it's parallelised over.
+#[cfg(feature = "parallel-codec")]
+fn spawn_job<T: Send + 'static>(
+    pool: &rayon::ThreadPool,
+    work: impl FnOnce() -> T + Send + 'static,
+) -> CodecJob<T> {
+    let (tx, rx) = oneshot::channel();
+    pool.spawn(move || {
+        // the job may have been dropped, along with the mixnet task
+        tx.send(work()).ok();
+    });
+    CodecJob::Pending(rx)
+}
+
+#[cfg(all(test, feature = "parallel-codec"))]
+mod test {
+    use super::super::message::{
+        ConnectionId, Message, SubstreamId, SubstreamMessage, TransportMessage,
+    };
+    use super::super::nonce::Nonce;
+    use super::*;
+
+    fn outbound(id: &ConnectionId, nonce: u64) -> OutboundMessage {
+        OutboundMessage {
+            message: Message::TransportMessage(TransportMessage {
+                nonce: Nonce::new(nonce),
+                id: id.clone(),
+                message: SubstreamMessage::new_with_data(
+                    SubstreamId::generate(),
+                    Bytes::from(vec![nonce as u8; 1000]),
+                ),
+                timestamp: None,
+            }),
+            recipient: None,
+            sender_tag: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_codec_pool_keeps_order() {
+        let pool = CodecPool::new(&NymTransportConfig::default().with_codec_workers(4)).unwrap();
+        assert!(pool.is_parallel());
+        let ids = [ConnectionId::generate(), ConnectionId::generate()];
+        let messages: Vec<OutboundMessage> = (0..MAX_CODEC_BATCH as u64)
+            .map(|nonce| outbound(&ids[nonce as usize % 2], nonce))
+            .collect();
+        let expected: Vec<Bytes> = messages
+            .iter()
+            .map(|message| WireCodec::encode(&message.message))
+            .collect();
+
+        let encoded = pool.encode(messages).await.unwrap();
+        for ((message, bytes), expected) in encoded.iter().zip(&expected) {
+            assert_eq!(bytes, expected);
+            assert_eq!(&WireCodec::encode(&message.message), expected);
+        }
+
+        // the messages of each connection come back in the order they were sent
+        let received = encoded
+            .into_iter()
+            .map(|(_, bytes)| ReconstructedMessage {
+                message: bytes.to_vec(),
+                sender_tag: None,
+            })
+            .collect();
+        let decoded = pool.decode(received, usize::MAX).await.unwrap();
+        assert_eq!(decoded.len(), expected.len());
+        for (decoded, expected) in decoded.into_iter().zip(&expected) {
+            assert_eq!(&WireCodec::encode(&decoded.data.unwrap().0), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_codec_job_outlives_a_lost_race() {
+        let pool = CodecPool::new(&NymTransportConfig::default().with_codec_workers(2)).unwrap();
+        let id = ConnectionId::generate();
+        let messages = (0..MAX_CODEC_BATCH as u64)
+            .map(|nonce| outbound(&id, nonce))
+            .collect();
+        let mut job = pool.encode(messages);
+        assert!(matches!(job, CodecJob::Pending(_)));
+
+        // a job raced and polled elsewhere is still there to be awaited
+        let _ = futures::poll!(&mut job);
+        let encoded = (&mut job).await.unwrap();
+        assert_eq!(encoded.len(), MAX_CODEC_BATCH);
+    }
+
+    #[tokio::test]
+    async fn test_codec_pool_rejects_invalid_messages() {
+        let pool = CodecPool::new(&NymTransportConfig::default().with_codec_workers(2)).unwrap();
+        let valid = WireCodec::encode(&outbound(&ConnectionId::generate(), 1).message);
+        let received = vec![
+            ReconstructedMessage {
+                message: valid.to_vec(),
+                sender_tag: None,
+            },
+            ReconstructedMessage {
+                message: vec![0xff, 0],
+                sender_tag: None,
+            },
+            ReconstructedMessage {
+                message: valid.to_vec(),
+                sender_tag: None,
+            },
+        ];
+        let decoded = pool.decode(received, valid.len()).await.unwrap();
+        assert!(decoded[0].data.is_ok());
+        assert!(matches!(
+            decoded[1].data,
+            Err(Error::UnknownMessageType(0xff))
+        ));
+        assert!(decoded[2].data.is_ok());
+    }
+}
@@ -0,0 +1,338 @@
+//! Configurations of libp2p protocols tuned for the mixnet. Their defaults assume
+//! round trips well under a second, while a message through the mixnet takes seconds,
+//! and the retransmission of a lost one a few more.
+
+use libp2p::{gossipsub, identify, identity::PublicKey, ping, swarm};
+use std::time::Duration;
+
+/// time between gossipsub heartbeats, which maintain the mesh and gossip IHAVEs. Each
+/// GRAFT or PRUNE takes seconds to arrive, so heartbeats every second (the default)
+/// would act on mesh changes that haven't reached the remote yet.
+pub const GOSSIPSUB_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// number of heartbeats messages stay in the message cache, and are served to IWANTs:
+/// 2 minutes, rather than 5 seconds, so that a message can still be fetched after the
+/// IHAVE and the IWANT have crossed the mixnet.
+pub const GOSSIPSUB_HISTORY_LENGTH: usize = 12;
+
+/// number of heartbeats of the message cache gossiped in IHAVEs: the last minute.
+pub const GOSSIPSUB_HISTORY_GOSSIP: usize = 6;
+
+/// time a requested message has to arrive before the peer we sent the IWANT to is
+/// penalised for breaking its promise. It covers a round trip plus retransmissions,
+/// where the default of 3 seconds doesn't cover a single round trip.
+pub const GOSSIPSUB_IWANT_FOLLOWUP_TIME: Duration = Duration::from_secs(30);
+
+/// time the IDs of seen messages are remembered, so that copies arriving late through
+/// slower routes or other mesh peers aren't handled as new messages.
+pub const GOSSIPSUB_DUPLICATE_CACHE_TIME: Duration = Duration::from_secs(5 * 60);
+
+/// time fanout peers of a topic we publish to without subscribing are kept. Finding new
+/// ones takes round trips, so they're kept for 5 minutes rather than 1.
+pub const GOSSIPSUB_FANOUT_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// time a pruned peer waits before grafting us again. Our PRUNE and its GRAFT may cross
+/// in the mixnet, so it's longer than the default minute.
+pub const GOSSIPSUB_PRUNE_BACKOFF: Duration = Duration::from_secs(2 * 60);
+
+/// time within the prune backoff during which a GRAFT is penalised as a flood. A GRAFT
+/// sent before our PRUNE arrived can still be in flight, so it's 30 seconds, not 10.
+pub const GOSSIPSUB_GRAFT_FLOOD_THRESHOLD: Duration = Duration::from_secs(30);
+
+/// seconds a peer waits before grafting us again after we unsubscribe.
+pub const GOSSIPSUB_UNSUBSCRIBE_BACKOFF_SECS: u64 = 60;
+
+/// time a message we publish may wait to be sent to a peer before it's dropped. Messages
+/// wait behind the connection's other traffic in the transport's outbound queue, which
+/// drains at the mixnet's pace, so the defaults of 5 seconds for published and 1 second
+/// for forwarded messages drop them while the mixnet is busy.
+pub const GOSSIPSUB_PUBLISH_QUEUE_DURATION: Duration = Duration::from_secs(60);
+
+/// time a message we forward may wait to be sent to a peer before it's dropped.
+pub const GOSSIPSUB_FORWARD_QUEUE_DURATION: Duration = Duration::from_secs(30);
+
+/// number of peers in each topic's mesh; every message is sent to each of them, and
+/// mixnet bandwidth is scarce, so it's 4 rather than 6, within 3 to 8.
+pub const GOSSIPSUB_MESH_N: usize = 4;
+pub const GOSSIPSUB_MESH_N_LOW: usize = 3;
+pub const GOSSIPSUB_MESH_N_HIGH: usize = 8;
+/// minimum number of mesh peers we dialed, rather than that dialed us; at most half
+/// of `GOSSIPSUB_MESH_N`.
+pub const GOSSIPSUB_MESH_OUTBOUND_MIN: usize = 2;
+
+/// gossipsub_config_for_nym returns a gossipsub config builder with its timers and
+/// mesh sizes tuned for the mixnet, as described by the constants above. Everything
+/// else is gossipsub's default, and it can be changed before the config is built.
+pub fn gossipsub_config_for_nym() -> gossipsub::ConfigBuilder {
+    let mut builder = gossipsub::ConfigBuilder::default();
+    builder
+        .heartbeat_interval(GOSSIPSUB_HEARTBEAT_INTERVAL)
+        .history_length(GOSSIPSUB_HISTORY_LENGTH)
+        .history_gossip(GOSSIPSUB_HISTORY_GOSSIP)
+        .iwant_followup_time(GOSSIPSUB_IWANT_FOLLOWUP_TIME)
+        .duplicate_cache_time(GOSSIPSUB_DUPLICATE_CACHE_TIME)
+        .fanout_ttl(GOSSIPSUB_FANOUT_TTL)
+        .prune_backoff(GOSSIPSUB_PRUNE_BACKOFF)
+        .graft_flood_threshold(GOSSIPSUB_GRAFT_FLOOD_THRESHOLD)
+        .unsubscribe_backoff(GOSSIPSUB_UNSUBSCRIBE_BACKOFF_SECS)
+        .publish_queue_duration(GOSSIPSUB_PUBLISH_QUEUE_DURATION)
+        .forward_queue_duration(GOSSIPSUB_FORWARD_QUEUE_DURATION)
+        .mesh_n(GOSSIPSUB_MESH_N)
+        .mesh_n_low(GOSSIPSUB_MESH_N_LOW)
+        .mesh_n_high(GOSSIPSUB_MESH_N_HIGH)
+        .mesh_outbound_min(GOSSIPSUB_MESH_OUTBOUND_MIN);
+    builder
+}
+
+/// time between pings. Each ping crosses the mixnet twice, so they're sent every 30
+/// seconds rather than 15; the transport's own keepalives already detect dead connections.
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// time a ping has to be answered before it's reported as failed. The default of 20
+/// seconds is exceeded by a slow round trip, or one with a retransmission, so healthy
+/// connections reported failures.
+pub const PING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// time between the periodic identify requests on a connection. The remote pushes changes
+/// to its listen addresses anyway, and a nym address rarely changes, so they're sent every
+/// 10 minutes rather than 5.
+pub const IDENTIFY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// time a connection without open substreams, or behaviours keeping it alive, stays open.
+/// A new connection costs a handshake round trip over the mixnet, so idle connections are
+/// kept for 5 minutes rather than closed immediately.
+pub const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// ping_config_for_nym returns a ping config with the interval and timeout tuned for the
+/// mixnet.
+pub fn ping_config_for_nym() -> ping::Config {
+    ping::Config::new()
+        .with_interval(PING_INTERVAL)
+        .with_timeout(PING_TIMEOUT)
+}
+
+/// identify_config_for_nym returns an identify config for the given protocol version and
+/// local public key, with the interval tuned for the mixnet.
+pub fn identify_config_for_nym(
+    protocol_version: String,
+    local_public_key: PublicKey,
+) -> identify::Config {
+    identify::Config::new(protocol_version, local_public_key).with_interval(IDENTIFY_INTERVAL)
+}
+
+/// swarm_config_for_nym sets the idle connection timeout of a swarm config for the mixnet.
+/// It can be passed to `SwarmBuilder::with_swarm_config` as is.
+pub fn swarm_config_for_nym(config: swarm::Config) -> swarm::Config {
+    config.with_idle_connection_timeout(IDLE_CONNECTION_TIMEOUT)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::NymTransportConfig;
+    use super::super::memory::{InMemoryConfig, InMemoryMixnet};
+    use super::super::testing::{swarm, swarm_with_config};
+    use super::*;
+    use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, StreamExt};
+    use libp2p::request_response::{self, ProtocolSupport};
+    use libp2p::swarm::SwarmEvent;
+    use libp2p::StreamProtocol;
+    use libp2p_identity::Keypair;
+
+    #[test]
+    fn test_gossipsub_config_for_nym() {
+        // the builder checks the mesh sizes and backoffs are consistent
+        let config = gossipsub_config_for_nym().build().unwrap();
+        assert_eq!(config.heartbeat_interval(), GOSSIPSUB_HEARTBEAT_INTERVAL);
+        assert_eq!(config.history_length(), GOSSIPSUB_HISTORY_LENGTH);
+        assert_eq!(config.mesh_n(), GOSSIPSUB_MESH_N);
+        // gossip can't cover more of the message cache than it holds
+        assert!(config.history_gossip() <= config.history_length());
+    }
+
+    /// EchoCodec frames a request or response as everything written to the substream,
+    /// so that reading one relies on the writer closing its half of the substream.
+    #[derive(Clone, Default)]
+    struct EchoCodec;
+
+    #[async_trait::async_trait]
+    impl request_response::Codec for EchoCodec {
+        type Protocol = StreamProtocol;
+        type Request = Vec<u8>;
+        type Response = Vec<u8>;
+
+        async fn read_request<T>(
+            &mut self,
+            _: &StreamProtocol,
+            io: &mut T,
+        ) -> std::io::Result<Vec<u8>>
+        where
+            T: AsyncRead + Unpin + Send,
+        {
+            let mut buf = vec![];
+            io.read_to_end(&mut buf).await?;
+            Ok(buf)
+        }
+
+        async fn read_response<T>(
+            &mut self,
+            _: &StreamProtocol,
+            io: &mut T,
+        ) -> std::io::Result<Vec<u8>>
+        where
+            T: AsyncRead + Unpin + Send,
+        {
+            let mut buf = vec![];
+            io.read_to_end(&mut buf).await?;
+            Ok(buf)
+        }
+
+        async fn write_request<T>(
+            &mut self,
+            _: &StreamProtocol,
+            io: &mut T,
+            request: Vec<u8>,
+        ) -> std::io::Result<()>
+        where
+            T: AsyncWrite + Unpin + Send,
+        {
+            io.write_all(&request).await
+        }
+
+        async fn write_response<T>(
+            &mut self,
+            _: &StreamProtocol,
+            io: &mut T,
+            response: Vec<u8>,
+        ) -> std::io::Result<()>
+        where
+            T: AsyncWrite + Unpin + Send,
+        {
+            io.write_all(&response).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let behaviour = |_: &Keypair| ping::Behaviour::new(ping_config_for_nym());
+        let (mut dialer, _) = swarm(&mixnet, behaviour).await;
+        let (mut listener, addr) = swarm(&mixnet, behaviour).await;
+        dialer.dial(addr).unwrap();
+
+        // multistream-select negotiates the protocol, then ping round-trips its payload
+        loop {
+            tokio::select! {
+                event = dialer.select_next_some() => {
+                    if let SwarmEvent::Behaviour(ping::Event { result, .. }) = event {
+                        result.unwrap();
+                        break;
+                    }
+                }
+                _ = listener.select_next_some() => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_identify_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let behaviour = |key: &Keypair| {
+            identify::Behaviour::new(identify_config_for_nym(
+                "/test/1.0.0".to_string(),
+                key.public(),
+            ))
+        };
+        let (mut dialer, _) = swarm(&mixnet, behaviour).await;
+        let (mut listener, addr) = swarm(&mixnet, behaviour).await;
+        let listener_peer_id = *listener.local_peer_id();
+        dialer.dial(addr).unwrap();
+
+        let info = loop {
+            tokio::select! {
+                event = dialer.select_next_some() => {
+                    if let SwarmEvent::Behaviour(identify::Event::Received { info, .. }) = event {
+                        break info;
+                    }
+                }
+                _ = listener.select_next_some() => {}
+            }
+        };
+        assert_eq!(info.public_key.to_peer_id(), listener_peer_id);
+        assert_eq!(info.protocol_version, "/test/1.0.0");
+    }
+
+    #[tokio::test]
+    async fn test_listen_filter_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let behaviour = |key: &Keypair| {
+            identify::Behaviour::new(identify_config_for_nym(
+                "/test/1.0.0".to_string(),
+                key.public(),
+            ))
+        };
+        let (mut dialer, _) = swarm(&mixnet, behaviour).await;
+        // the listener keeps its nym address to itself
+        let config = NymTransportConfig::default().with_listen_filter(|_| false);
+        let (mut listener, addr) = swarm_with_config(&mixnet, config, behaviour).await;
+        dialer.dial(addr).unwrap();
+
+        // but still accepts dials to it, from peers identify tells nothing about it
+        let info = loop {
+            tokio::select! {
+                event = dialer.select_next_some() => {
+                    if let SwarmEvent::Behaviour(identify::Event::Received { info, .. }) = event {
+                        break info;
+                    }
+                }
+                _ = listener.select_next_some() => {}
+            }
+        };
+        assert!(info.listen_addrs.is_empty());
+        assert_eq!(listener.listeners().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_request_response_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let behaviour = |_: &Keypair| {
+            request_response::Behaviour::<EchoCodec>::new(
+                [(StreamProtocol::new("/echo/1.0.0"), ProtocolSupport::Full)],
+                request_response::Config::default(),
+            )
+        };
+        let (mut dialer, _) = swarm(&mixnet, behaviour).await;
+        let (mut listener, addr) = swarm(&mixnet, behaviour).await;
+        dialer.dial(addr).unwrap();
+
+        // each side closes its half once it's written, which the other reads as the end
+        // of the request or response
+        let response = loop {
+            tokio::select! {
+                event = dialer.select_next_some() => match event {
+                    SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                        dialer.behaviour_mut().send_request(&peer_id, b"hello".to_vec());
+                    }
+                    SwarmEvent::Behaviour(request_response::Event::Message {
+                        message: request_response::Message::Response { response, .. },
+                        ..
+                    }) => break response,
+                    SwarmEvent::Behaviour(request_response::Event::OutboundFailure {
+                        error, ..
+                    }) => panic!("request failed: {}", error),
+                    _ => {}
+                },
+                event = listener.select_next_some() => {
+                    if let SwarmEvent::Behaviour(request_response::Event::Message {
+                        message: request_response::Message::Request { request, channel, .. },
+                        ..
+                    }) = event
+                    {
+                        assert_eq!(request, b"hello");
+                        listener.behaviour_mut().send_response(channel, request).unwrap();
+                    }
+                }
+            }
+        };
+        assert_eq!(response, b"hello");
+    }
+}
@@ -1,15 +1,23 @@
 use log::{debug, warn};
 use std::collections::BTreeSet;
+use std::time::Duration;
 
+use super::error::Error;
 use super::message::TransportMessage;
+use super::nonce::Nonce;
+use super::runtime::Instant;
 
 /// MessageQueue is a queue of messages, ordered by nonce, that we've
 /// received but are not yet able to process because we're waiting for
 /// a message with the next expected nonce first.
 /// This is required because Nym does not guarantee any sort of message
 /// ordering, only delivery.
-/// TODO: is there a DOS vector here where a malicious peer sends us
-/// messages only with nonce higher than the next expected nonce?
+///
+/// Nonces are also how replayed or duplicated messages are detected: every
+/// nonce is handled exactly once, and only nonces within a window ahead of
+/// the next expected nonce are queued, which bounds the size of the queue.
+/// If a missing message doesn't arrive within the gap timeout, the connection
+/// is given up on rather than left waiting forever.
 pub(crate) struct MessageQueue {
     /// nonce of the next message we expect to receive on the
     /// connection.
@@ -17,122 +25,361 @@ pub(crate) struct MessageQueue {
     /// the queue.
     /// if we get a message with a nonce equal to this, then we
     /// immediately handle it in the transport and increment the nonce.
-    next_expected_nonce: u64,
+    next_expected_nonce: Nonce,
 
     /// the actual queue of messages, ordered by nonce.
     /// the head of the queue's nonce is always greater
     /// than the next expected nonce.
     queue: BTreeSet<TransportMessage>,
+
+    /// number of nonces, starting at the next expected nonce, that are accepted.
+    /// messages with a nonce past the window are dropped.
+    window: u64,
+
+    /// time allowed for the next expected message to arrive while others are queued.
+    gap_timeout: Duration,
+
+    /// when we started waiting for the next expected message; None if nothing is queued.
+    gap_since: Option<Instant>,
 }
 
 impl MessageQueue {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(window: u64, gap_timeout: Duration) -> Self {
         MessageQueue {
-            next_expected_nonce: 0,
+            next_expected_nonce: Nonce::default(),
             queue: BTreeSet::new(),
+            window,
+            gap_timeout,
+            gap_since: None,
         }
     }
 
+    /// len returns the number of messages queued behind a missing one.
+    pub(crate) fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// next_expected_nonce returns the nonce of the next message to be handled.
+    pub(crate) fn next_expected_nonce(&self) -> Nonce {
+        self.next_expected_nonce
+    }
+
     pub(crate) fn print_nonces(&self) {
-        let nonces = self.queue.iter().map(|msg| msg.nonce).collect::<Vec<_>>();
+        let nonces = self
+            .queue
+            .iter()
+            .map(|msg| msg.nonce.get())
+            .collect::<Vec<_>>();
         debug!("MessageQueue: {:?}", nonces);
     }
 
     /// sets the next expected nonce to 1, indicating that we've received
-    /// a ConnectionRequest or ConnectionResponse.
-    pub(crate) fn set_connection_message_received(&mut self) {
-        if self.next_expected_nonce != 0 {
-            panic!("connection message received twice");
+    /// a ConnectionRequest or ConnectionResponse. Returns an error if it's
+    /// already been received, which fails the connection.
+    pub(crate) fn set_connection_message_received(&mut self) -> Result<(), Error> {
+        if self.next_expected_nonce != Nonce::default() {
+            return Err(Error::ConnectionMessageReceivedTwice);
         }
 
-        self.next_expected_nonce = self.next_expected_nonce.wrapping_add(1);
+        self.advance()
+    }
+
+    /// advance increments the next expected nonce, restarting the gap timer
+    /// if messages are still queued behind it. [`Nonce::MAX`] is never accepted,
+    /// so the nonce after the next expected one exists; if it didn't, the
+    /// connection would have to be redialed.
+    fn advance(&mut self) -> Result<(), Error> {
+        self.next_expected_nonce = self
+            .next_expected_nonce
+            .checked_next()
+            .ok_or(Error::NoncesExhausted)?;
+        self.gap_since = if self.queue.is_empty() {
+            None
+        } else {
+            Some(Instant::now())
+        };
+        Ok(())
+    }
+
+    /// gap_expired returns true if messages have been queued waiting on a missing
+    /// message for longer than the gap timeout.
+    pub(crate) fn gap_expired(&self) -> bool {
+        self.gap_since
+            .is_some_and(|since| since.elapsed() >= self.gap_timeout)
+    }
+
+    /// check_nonce returns an error if a message with the given nonce has already
+    /// been handled or queued, or is too far ahead of the next expected nonce.
+    /// [`Nonce::MAX`] is never sent, so it's always outside the window, and nonces
+    /// before [`Nonce::FIRST`] belong to the connection message, so they're replays.
+    pub(crate) fn check_nonce(&self, nonce: Nonce) -> Result<(), Error> {
+        if nonce < Nonce::FIRST {
+            return Err(Error::ReplayedNonce(nonce.get()));
+        }
+
+        let Some(offset) = nonce.offset_from(self.next_expected_nonce) else {
+            return Err(Error::ReplayedNonce(nonce.get()));
+        };
+
+        if offset >= self.window || nonce == Nonce::MAX {
+            return Err(Error::NonceOutsideWindow(nonce.get()));
+        }
+
+        if self.queue.iter().any(|msg| msg.nonce == nonce) {
+            return Err(Error::ReplayedNonce(nonce.get()));
+        }
+
+        Ok(())
     }
 
     /// tries to push a message into the queue.
     /// if the message has the next expected nonce, then the message is returned,
     /// and should be processed by the caller.
     /// in that case, the internal queue's next expected nonce is incremented.
-    pub(crate) fn try_push(&mut self, msg: TransportMessage) -> Option<TransportMessage> {
+    /// messages that fail [`MessageQueue::check_nonce`] are dropped.
+    /// returns an error if the nonces have run out, which fails the connection.
+    pub(crate) fn try_push(
+        &mut self,
+        msg: TransportMessage,
+    ) -> Result<Option<TransportMessage>, Error> {
+        if let Err(e) = self.check_nonce(msg.nonce) {
+            // the mixnet can deliver a message more than once, so this
+            // isn't necessarily the other node misbehaving
+            warn!("dropping message: {}", e);
+            return Ok(None);
+        }
+
         if msg.nonce == self.next_expected_nonce {
-            self.next_expected_nonce = self.next_expected_nonce.wrapping_add(1);
-            Some(msg)
+            self.advance()?;
+            Ok(Some(msg))
         } else {
-            if msg.nonce < self.next_expected_nonce {
-                // this shouldn't happen normally, only if the other node
-                // is not following the protocol
-                warn!("received a message with a nonce that is too low");
-                return None;
-            }
-
-            if !self.queue.insert(msg) {
-                // this shouldn't happen normally, only if the other node
-                // is not following the protocol
-                warn!("received a message with a duplicate nonce");
-                return None;
-            }
-
-            None
+            self.queue.insert(msg);
+            self.gap_since.get_or_insert_with(Instant::now);
+            Ok(None)
         }
     }
 
-    pub(crate) fn pop(&mut self) -> Option<TransportMessage> {
-        let head = self.queue.first()?;
+    /// pop returns the head of the queue if it has the next expected nonce, like
+    /// [`MessageQueue::try_push`].
+    pub(crate) fn pop(&mut self) -> Result<Option<TransportMessage>, Error> {
+        let Some(head) = self.queue.first() else {
+            return Ok(None);
+        };
 
         if head.nonce == self.next_expected_nonce {
-            self.next_expected_nonce = self.next_expected_nonce.wrapping_add(1);
-            Some(self.queue.pop_first().unwrap())
+            let msg = self.queue.pop_first().unwrap();
+            self.advance()?;
+            Ok(Some(msg))
         } else {
-            None
+            Ok(None)
         }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use super::super::config::DEFAULT_REPLAY_WINDOW;
     use super::super::message::{ConnectionId, SubstreamId, SubstreamMessage};
+    use bytes::Bytes;
 
     use super::*;
 
     impl TransportMessage {
         fn new(nonce: u64, message: SubstreamMessage, id: ConnectionId) -> Self {
-            TransportMessage { nonce, message, id }
+            TransportMessage {
+                nonce: Nonce::new(nonce),
+                message,
+                id,
+                timestamp: None,
+            }
         }
     }
 
     #[test]
     fn test_message_queue() {
-        let mut queue = MessageQueue::new();
+        let mut queue = MessageQueue::new(DEFAULT_REPLAY_WINDOW, Duration::from_secs(60));
 
-        let test_substream_message =
-            SubstreamMessage::new_with_data(SubstreamId::generate(), vec![1, 2, 3]);
+        let test_substream_message = SubstreamMessage::new_with_data(
+            SubstreamId::generate(),
+            Bytes::from_static(&[1, 2, 3]),
+        );
         let connection_id = ConnectionId::generate();
 
         let msg1 = TransportMessage::new(1, test_substream_message.clone(), connection_id.clone());
         let msg2 = TransportMessage::new(2, test_substream_message.clone(), connection_id.clone());
         let msg3 = TransportMessage::new(3, test_substream_message.clone(), connection_id.clone());
 
-        assert_eq!(queue.try_push(msg1.clone()), None);
-        assert_eq!(queue.try_push(msg3.clone()), None);
-        assert_eq!(queue.try_push(msg2.clone()), None);
+        assert_eq!(queue.try_push(msg1.clone()).unwrap(), None);
+        assert_eq!(queue.try_push(msg3.clone()).unwrap(), None);
+        assert_eq!(queue.try_push(msg2.clone()).unwrap(), None);
 
-        assert_eq!(queue.pop(), None);
+        assert_eq!(queue.pop().unwrap(), None);
 
         // set expected nonce to 1
-        queue.set_connection_message_received();
-        assert_eq!(queue.pop(), Some(msg1));
+        queue.set_connection_message_received().unwrap();
+        assert_eq!(queue.pop().unwrap(), Some(msg1));
 
         let msg4 = TransportMessage::new(4, test_substream_message.clone(), connection_id.clone());
-        assert_eq!(queue.try_push(msg4.clone()), None);
+        assert_eq!(queue.try_push(msg4.clone()).unwrap(), None);
 
-        assert_eq!(queue.pop(), Some(msg2));
-        assert_eq!(queue.pop(), Some(msg3));
-        assert_eq!(queue.pop(), Some(msg4));
-        assert_eq!(queue.pop(), None);
-        assert_eq!(queue.next_expected_nonce, 5);
+        assert_eq!(queue.pop().unwrap(), Some(msg2));
+        assert_eq!(queue.pop().unwrap(), Some(msg3));
+        assert_eq!(queue.pop().unwrap(), Some(msg4));
+        assert_eq!(queue.pop().unwrap(), None);
+        assert_eq!(queue.next_expected_nonce, Nonce::new(5));
 
         // should just return the message and increment nonce when message nonce = next expected nonce
         let msg5 = TransportMessage::new(5, test_substream_message, connection_id);
-        assert_eq!(queue.try_push(msg5.clone()), Some(msg5));
-        assert_eq!(queue.next_expected_nonce, 6);
+        assert_eq!(queue.try_push(msg5.clone()).unwrap(), Some(msg5));
+        assert_eq!(queue.next_expected_nonce, Nonce::new(6));
+    }
+
+    #[test]
+    fn test_message_queue_drops_replays() {
+        let mut queue = MessageQueue::new(4, Duration::from_secs(60));
+        queue.set_connection_message_received().unwrap();
+
+        let test_substream_message = SubstreamMessage::new_with_data(
+            SubstreamId::generate(),
+            Bytes::from_static(&[1, 2, 3]),
+        );
+        let connection_id = ConnectionId::generate();
+        let msg = |nonce| {
+            TransportMessage::new(nonce, test_substream_message.clone(), connection_id.clone())
+        };
+
+        // a message already handled is dropped
+        assert_eq!(queue.try_push(msg(1)).unwrap(), Some(msg(1)));
+        assert!(matches!(
+            queue.check_nonce(Nonce::new(1)),
+            Err(Error::ReplayedNonce(1))
+        ));
+        assert_eq!(queue.try_push(msg(1)).unwrap(), None);
+        assert_eq!(queue.pop().unwrap(), None);
+
+        // as is a message already queued
+        assert_eq!(queue.try_push(msg(3)).unwrap(), None);
+        assert!(matches!(
+            queue.check_nonce(Nonce::new(3)),
+            Err(Error::ReplayedNonce(3))
+        ));
+        assert_eq!(queue.try_push(msg(3)).unwrap(), None);
+        assert_eq!(queue.queue.len(), 1);
+
+        // and a message past the window
+        assert!(matches!(
+            queue.check_nonce(Nonce::new(6)),
+            Err(Error::NonceOutsideWindow(6))
+        ));
+        assert_eq!(queue.try_push(msg(6)).unwrap(), None);
+        assert_eq!(queue.queue.len(), 1);
+
+        // the window slides forward as messages are handled
+        assert_eq!(queue.try_push(msg(2)).unwrap(), Some(msg(2)));
+        assert_eq!(queue.pop().unwrap(), Some(msg(3)));
+        assert_eq!(queue.pop().unwrap(), None);
+        assert_eq!(queue.try_push(msg(6)).unwrap(), None);
+        assert_eq!(queue.try_push(msg(5)).unwrap(), None);
+        assert_eq!(queue.try_push(msg(4)).unwrap(), Some(msg(4)));
+        assert_eq!(queue.pop().unwrap(), Some(msg(5)));
+        assert_eq!(queue.pop().unwrap(), Some(msg(6)));
+        assert_eq!(queue.pop().unwrap(), None);
+    }
+
+    #[test]
+    fn test_message_queue_drops_connection_message_nonce() {
+        let mut queue = MessageQueue::new(DEFAULT_REPLAY_WINDOW, Duration::from_secs(60));
+
+        let test_substream_message = SubstreamMessage::new_with_data(
+            SubstreamId::generate(),
+            Bytes::from_static(&[1, 2, 3]),
+        );
+        let connection_id = ConnectionId::generate();
+        let msg = |nonce| {
+            TransportMessage::new(nonce, test_substream_message.clone(), connection_id.clone())
+        };
+
+        // nonce 0 is the connection message's, so a TransportMessage sent with it before
+        // the connection message has been received is dropped rather than handled
+        assert!(matches!(
+            queue.check_nonce(Nonce::default()),
+            Err(Error::ReplayedNonce(0))
+        ));
+        assert_eq!(queue.try_push(msg(0)).unwrap(), None);
+        assert_eq!(queue.next_expected_nonce(), Nonce::default());
+
+        // so the connection message is still received once, and fails the connection
+        // if it's received again
+        queue.set_connection_message_received().unwrap();
+        assert!(matches!(
+            queue.set_connection_message_received(),
+            Err(Error::ConnectionMessageReceivedTwice)
+        ));
+        assert_eq!(queue.try_push(msg(0)).unwrap(), None);
+        assert_eq!(queue.try_push(msg(1)).unwrap(), Some(msg(1)));
+    }
+
+    #[test]
+    fn test_message_queue_last_nonces() {
+        let mut queue = MessageQueue::new(4, Duration::from_secs(60));
+        queue.next_expected_nonce = Nonce::new(u64::MAX - 2);
+
+        let test_substream_message = SubstreamMessage::new_with_data(
+            SubstreamId::generate(),
+            Bytes::from_static(&[1, 2, 3]),
+        );
+        let connection_id = ConnectionId::generate();
+        let msg = |nonce| {
+            TransportMessage::new(nonce, test_substream_message.clone(), connection_id.clone())
+        };
+
+        // the last nonce is never sent, so it's dropped even within the window
+        assert!(matches!(
+            queue.check_nonce(Nonce::MAX),
+            Err(Error::NonceOutsideWindow(u64::MAX))
+        ));
+        assert_eq!(queue.try_push(msg(u64::MAX)).unwrap(), None);
+
+        // rather than the next expected nonce wrapping around to 0
+        assert_eq!(queue.try_push(msg(u64::MAX - 1)).unwrap(), None);
+        assert_eq!(
+            queue.try_push(msg(u64::MAX - 2)).unwrap(),
+            Some(msg(u64::MAX - 2))
+        );
+        assert_eq!(queue.pop().unwrap(), Some(msg(u64::MAX - 1)));
+        assert_eq!(queue.next_expected_nonce(), Nonce::MAX);
+        assert!(queue.next_expected_nonce().needs_rehandshake());
+        assert!(matches!(
+            queue.check_nonce(Nonce::FIRST),
+            Err(Error::ReplayedNonce(1))
+        ));
+    }
+
+    #[test]
+    fn test_message_queue_gap_timeout() {
+        let mut queue = MessageQueue::new(DEFAULT_REPLAY_WINDOW, Duration::ZERO);
+        queue.set_connection_message_received().unwrap();
+
+        let test_substream_message = SubstreamMessage::new_with_data(
+            SubstreamId::generate(),
+            Bytes::from_static(&[1, 2, 3]),
+        );
+        let connection_id = ConnectionId::generate();
+        let msg = |nonce| {
+            TransportMessage::new(nonce, test_substream_message.clone(), connection_id.clone())
+        };
+
+        // nothing is waiting on a gap while messages arrive in order
+        assert_eq!(queue.try_push(msg(1)).unwrap(), Some(msg(1)));
+        assert!(!queue.gap_expired());
+
+        // message 2 is missing
+        assert_eq!(queue.try_push(msg(3)).unwrap(), None);
+        assert!(queue.gap_expired());
+
+        // the gap is filled
+        assert_eq!(queue.try_push(msg(2)).unwrap(), Some(msg(2)));
+        assert_eq!(queue.pop().unwrap(), Some(msg(3)));
+        assert!(!queue.gap_expired());
     }
 }
@@ -0,0 +1,479 @@
+use libp2p_identity::PeerId;
+use nym_sdk::mixnet::AnonymousSenderTag;
+use nym_sphinx::addressing::clients::Recipient;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::codec::{Codec, WireCodec};
+use super::config::RelayConfig;
+use super::message::{ConnectionId, Message, OutboundMessage, RelayHop, RelayMessage, RelayStatus};
+use super::runtime::Instant;
+
+/// Route is how the messages of a circuit we're an end of are sent: to the relay,
+/// with the hop that tells it which way to forward them.
+#[derive(Clone, Debug)]
+struct Route {
+    relay: Recipient,
+    hop: RelayHop,
+}
+
+#[derive(Debug, Default)]
+struct Routes {
+    circuits: HashMap<ConnectionId, Route>,
+    /// relays we hold a reservation with, and the number of our listeners that hold it.
+    relays: HashMap<Recipient, usize>,
+}
+
+/// RelayTable holds the circuits of the connections we've dialed through a relay, or
+/// accepted through one, and the relays we hold reservations with. It's shared by the
+/// transport, which opens circuits for its relayed dials and closes them with their
+/// connections, and the mixnet tasks, which wrap the messages of relayed connections
+/// for their relay and unwrap the ones relays deliver. The connections themselves
+/// don't know they're relayed: their messages have neither a recipient nor a sender
+/// tag until they're wrapped.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RelayTable(Arc<Mutex<Routes>>);
+
+impl RelayTable {
+    /// dial opens circuit `id` to `peer_id` through `relay`.
+    pub(crate) fn dial(&self, id: &ConnectionId, relay: Recipient, peer_id: PeerId) {
+        self.0.lock().circuits.insert(
+            id.clone(),
+            Route {
+                relay,
+                hop: RelayHop::ToListener(peer_id),
+            },
+        );
+    }
+
+    /// reserve records a reservation with `relay`, from then on accepting the circuits
+    /// that are opened to us through it.
+    pub(crate) fn reserve(&self, relay: Recipient) {
+        *self.0.lock().relays.entry(relay).or_default() += 1;
+    }
+
+    /// release drops a reservation with `relay`. Circuits already opened through it
+    /// are kept until their connections close.
+    pub(crate) fn release(&self, relay: &Recipient) {
+        let mut routes = self.0.lock();
+        if let Some(count) = routes.relays.get_mut(relay) {
+            *count -= 1;
+            if *count == 0 {
+                routes.relays.remove(relay);
+            }
+        }
+    }
+
+    /// relay_of returns the relay of connection `id`, if it's relayed.
+    pub(crate) fn relay_of(&self, id: &ConnectionId) -> Option<Recipient> {
+        self.0.lock().circuits.get(id).map(|route| route.relay)
+    }
+
+    /// route wraps `message` in a RelayMessage sent to its connection's relay, if the
+    /// connection is relayed; otherwise it's returned as it is.
+    pub(crate) fn route(&self, message: OutboundMessage) -> OutboundMessage {
+        // a message that's already wrapped is one we're forwarding as a relay
+        if matches!(message.message, Message::Relay(_)) {
+            return message;
+        }
+        let routes = self.0.lock();
+        if routes.circuits.is_empty() {
+            return message;
+        }
+        let Some(route) = routes.circuits.get(message.message.connection_id()) else {
+            return message;
+        };
+        OutboundMessage {
+            message: Message::Relay(RelayMessage {
+                id: message.message.connection_id().clone(),
+                hop: route.hop.clone(),
+                payload: WireCodec::encode(&message.message),
+            }),
+            recipient: Some(route.relay),
+            sender_tag: None,
+        }
+    }
+
+    /// deliver unwraps a message that a relay delivered to us. It returns None if the
+    /// message isn't on one of our circuits through that relay, unless it's the
+    /// ConnectionRequest that opens a circuit to us through a relay we've reserved with.
+    pub(crate) fn deliver(&self, msg: RelayMessage) -> Option<Message> {
+        let RelayHop::Delivered(relay) = msg.hop else {
+            return None;
+        };
+        let message = WireCodec::decode(msg.payload).ok()?;
+        // the relayed message has to be the circuit's, and isn't relayed any further
+        if message.connection_id() != &msg.id || matches!(message, Message::Relay(_)) {
+            return None;
+        }
+
+        let mut routes = self.0.lock();
+        match (routes.circuits.get(&msg.id), &message) {
+            (Some(route), _) => (route.relay == relay).then_some(message),
+            // a dialer reaching us through a relay doesn't learn our address, so it
+            // can't have us reply to its own
+            (None, Message::ConnectionRequest(req))
+                if req.recipient.is_none() && routes.relays.contains_key(&relay) =>
+            {
+                routes.circuits.insert(
+                    msg.id,
+                    Route {
+                        relay,
+                        hop: RelayHop::ToDialer,
+                    },
+                );
+                Some(message)
+            }
+            _ => None,
+        }
+    }
+
+    /// remove closes the circuit of a closed connection, if it was relayed.
+    pub(crate) fn remove(&self, id: &ConnectionId) {
+        self.0.lock().circuits.remove(id);
+    }
+}
+
+/// Reservation is a peer's reservation with us as a relay.
+#[derive(Debug)]
+struct Reservation {
+    /// the sender tag of the SURBs the peer reserved with, which its circuits are
+    /// forwarded to it through.
+    sender_tag: AnonymousSenderTag,
+    expires: Instant,
+}
+
+/// Circuit is a connection we relay between a dialer and a peer with a reservation.
+#[derive(Debug)]
+struct Circuit {
+    /// the sender tag of the dialer's SURBs, which the listener's messages are
+    /// forwarded to it through.
+    dialer: AnonymousSenderTag,
+    listener: PeerId,
+    last_used: Instant,
+}
+
+/// RelayServer is what we hold as a relay for other peers: their reservations, and
+/// the circuits opened to them through us. Both ends of a circuit only ever send us
+/// SURBs, so we forward its messages without learning either's address; and its
+/// connection is encrypted end to end, so we can't read or alter what we forward.
+#[derive(Debug)]
+pub(crate) struct RelayServer {
+    config: RelayConfig,
+    reservations: HashMap<PeerId, Reservation>,
+    circuits: HashMap<ConnectionId, Circuit>,
+}
+
+impl RelayServer {
+    pub(crate) fn new(config: RelayConfig) -> Self {
+        RelayServer {
+            config,
+            reservations: HashMap::new(),
+            circuits: HashMap::new(),
+        }
+    }
+
+    /// reserve makes or renews the reservation of `peer_id`, whose circuits are
+    /// forwarded to it through the SURBs of `sender_tag`. It returns the time the
+    /// reservation is held for, or why it was refused.
+    pub(crate) fn reserve(
+        &mut self,
+        peer_id: PeerId,
+        sender_tag: AnonymousSenderTag,
+        now: Instant,
+    ) -> Result<Duration, RelayStatus> {
+        if !self.reservations.contains_key(&peer_id)
+            && self.reservations.len() >= self.config.max_reservations
+        {
+            return Err(RelayStatus::ResourceLimit);
+        }
+        self.reservations.insert(
+            peer_id,
+            Reservation {
+                sender_tag,
+                expires: now + self.config.reservation_ttl,
+            },
+        );
+        Ok(self.config.reservation_ttl)
+    }
+
+    /// forward returns the sender tag that a message on circuit `id`, sent to us by
+    /// `sender_tag` with `hop`, is forwarded through, or why it can't be. A dialer's
+    /// first message opens the circuit.
+    pub(crate) fn forward(
+        &mut self,
+        id: &ConnectionId,
+        hop: &RelayHop,
+        sender_tag: &AnonymousSenderTag,
+        now: Instant,
+    ) -> Result<AnonymousSenderTag, RelayStatus> {
+        match hop {
+            RelayHop::ToListener(peer_id) => {
+                let reservation = self
+                    .reservations
+                    .get(peer_id)
+                    .filter(|reservation| reservation.expires > now)
+                    .ok_or(RelayStatus::NoReservation)?;
+                match self.circuits.get_mut(id) {
+                    Some(circuit)
+                        if circuit.dialer == *sender_tag && circuit.listener == *peer_id =>
+                    {
+                        circuit.last_used = now;
+                    }
+                    // someone else's circuit
+                    Some(_) => return Err(RelayStatus::Refused),
+                    None if self.circuits.len() >= self.config.max_circuits => {
+                        return Err(RelayStatus::ResourceLimit);
+                    }
+                    None => {
+                        self.circuits.insert(
+                            id.clone(),
+                            Circuit {
+                                dialer: sender_tag.clone(),
+                                listener: *peer_id,
+                                last_used: now,
+                            },
+                        );
+                    }
+                }
+                Ok(reservation.sender_tag.clone())
+            }
+            RelayHop::ToDialer => {
+                let circuit = self
+                    .circuits
+                    .get_mut(id)
+                    .ok_or(RelayStatus::NoReservation)?;
+                let from_listener = self
+                    .reservations
+                    .get(&circuit.listener)
+                    .is_some_and(|reservation| reservation.sender_tag == *sender_tag);
+                if !from_listener {
+                    return Err(RelayStatus::Refused);
+                }
+                circuit.last_used = now;
+                Ok(circuit.dialer.clone())
+            }
+            RelayHop::Delivered(_) => Err(RelayStatus::Refused),
+        }
+    }
+
+    /// expire drops the reservations that have expired by `now`, and the circuits
+    /// that have been idle for longer than the circuit idle timeout.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        self.reservations
+            .retain(|_, reservation| reservation.expires > now);
+        let idle_timeout = self.config.circuit_idle_timeout;
+        self.circuits
+            .retain(|_, circuit| now.duration_since(circuit.last_used) < idle_timeout);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::handshake::Handshake;
+    use super::super::message::{
+        Capabilities, ConnectionCloseMessage, ConnectionInfo, ConnectionMessage,
+        MIN_MAX_MESSAGE_SIZE,
+    };
+    use super::*;
+    use libp2p_identity::Keypair;
+
+    fn address(s: &str) -> Recipient {
+        Recipient::try_from_base58_string(s).unwrap()
+    }
+
+    fn tag() -> AnonymousSenderTag {
+        AnonymousSenderTag::new_random(&mut rand::thread_rng())
+    }
+
+    fn close(id: &ConnectionId) -> Message {
+        Message::ConnectionClose(ConnectionCloseMessage { id: id.clone() })
+    }
+
+    fn request(id: &ConnectionId, recipient: Option<Recipient>) -> Message {
+        let keypair = Keypair::generate_ed25519();
+        Message::ConnectionRequest(ConnectionMessage {
+            peer_id: keypair.public().to_peer_id(),
+            id: id.clone(),
+            capabilities: Capabilities::default(),
+            recipient,
+            handshake: Handshake::new(&keypair, id, None).unwrap().payload(),
+            challenge: None,
+            observed: None,
+            max_message_size: MIN_MAX_MESSAGE_SIZE,
+            service: None,
+            info: ConnectionInfo::default(),
+        })
+    }
+
+    fn delivered(relay: Recipient, id: &ConnectionId, message: &Message) -> RelayMessage {
+        RelayMessage {
+            id: id.clone(),
+            hop: RelayHop::Delivered(relay),
+            payload: WireCodec::encode(message),
+        }
+    }
+
+    #[test]
+    fn test_relay_table() {
+        let table = RelayTable::default();
+        let relay = address("Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR");
+        let other = address("D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN");
+        let peer_id = Keypair::generate_ed25519().public().to_peer_id();
+        let id = ConnectionId::generate();
+        let outbound = |id: &ConnectionId| OutboundMessage {
+            message: close(id),
+            recipient: None,
+            sender_tag: Some(tag()),
+        };
+
+        // messages of connections that aren't relayed keep their route
+        let message = table.route(outbound(&id));
+        assert!(message.recipient.is_none() && message.sender_tag.is_some());
+
+        // those of relayed ones are wrapped and sent to the relay
+        table.dial(&id, relay, peer_id);
+        assert_eq!(table.relay_of(&id), Some(relay));
+        let message = table.route(outbound(&id));
+        assert_eq!(message.recipient, Some(relay));
+        assert!(message.sender_tag.is_none());
+        match message.message {
+            Message::Relay(msg) => {
+                assert_eq!(msg.id, id);
+                assert_eq!(msg.hop, RelayHop::ToListener(peer_id));
+                assert!(matches!(
+                    WireCodec::decode(msg.payload).unwrap(),
+                    Message::ConnectionClose(msg) if msg.id == id
+                ));
+            }
+            msg => panic!("expected Message::Relay, got {:?}", msg),
+        }
+
+        // messages delivered on the circuit are unwrapped, unless another relay delivered
+        // them, or they aren't the circuit's
+        assert!(matches!(
+            table.deliver(delivered(relay, &id, &close(&id))),
+            Some(Message::ConnectionClose(msg)) if msg.id == id
+        ));
+        assert!(table.deliver(delivered(other, &id, &close(&id))).is_none());
+        let other_id = ConnectionId::generate();
+        assert!(table
+            .deliver(delivered(relay, &id, &close(&other_id)))
+            .is_none());
+
+        table.remove(&id);
+        assert_eq!(table.relay_of(&id), None);
+        assert!(table.deliver(delivered(relay, &id, &close(&id))).is_none());
+
+        // a ConnectionRequest opens a circuit to us, but only through a relay we've
+        // reserved with, and only if it doesn't expose the dialer's address
+        let id = ConnectionId::generate();
+        assert!(table
+            .deliver(delivered(relay, &id, &request(&id, None)))
+            .is_none());
+        table.reserve(relay);
+        table.reserve(relay);
+        table.release(&relay);
+        assert!(table
+            .deliver(delivered(other, &id, &request(&id, None)))
+            .is_none());
+        assert!(table
+            .deliver(delivered(relay, &id, &request(&id, Some(other))))
+            .is_none());
+        assert!(matches!(
+            table.deliver(delivered(relay, &id, &request(&id, None))),
+            Some(Message::ConnectionRequest(_))
+        ));
+        let message = table.route(outbound(&id));
+        assert!(matches!(
+            message.message,
+            Message::Relay(RelayMessage {
+                hop: RelayHop::ToDialer,
+                ..
+            })
+        ));
+
+        // once the last reservation is released, no new circuits are accepted, but
+        // those already open are kept
+        table.release(&relay);
+        let new_id = ConnectionId::generate();
+        assert!(table
+            .deliver(delivered(relay, &new_id, &request(&new_id, None)))
+            .is_none());
+        assert_eq!(table.relay_of(&id), Some(relay));
+    }
+
+    #[test]
+    fn test_relay_server() {
+        let config = RelayConfig::default()
+            .with_max_reservations(1)
+            .with_max_circuits(1);
+        let mut server = RelayServer::new(config);
+        let now = Instant::now();
+        let (dialer, listener) = (tag(), tag());
+        let peer_id = Keypair::generate_ed25519().public().to_peer_id();
+        let id = ConnectionId::generate();
+
+        // a circuit can't be opened to a peer without a reservation
+        assert_eq!(
+            server.forward(&id, &RelayHop::ToListener(peer_id), &dialer, now),
+            Err(RelayStatus::NoReservation)
+        );
+
+        assert_eq!(
+            server.reserve(peer_id, listener.clone(), now),
+            Ok(config.reservation_ttl)
+        );
+        assert_eq!(
+            server.reserve(
+                Keypair::generate_ed25519().public().to_peer_id(),
+                tag(),
+                now
+            ),
+            Err(RelayStatus::ResourceLimit)
+        );
+
+        // the dialer's messages go to the listener, and the listener's back to the dialer
+        assert_eq!(
+            server.forward(&id, &RelayHop::ToListener(peer_id), &dialer, now),
+            Ok(listener.clone())
+        );
+        assert_eq!(
+            server.forward(&id, &RelayHop::ToDialer, &listener, now),
+            Ok(dialer.clone())
+        );
+
+        // no one else can send over the circuit
+        assert_eq!(
+            server.forward(&id, &RelayHop::ToListener(peer_id), &tag(), now),
+            Err(RelayStatus::Refused)
+        );
+        assert_eq!(
+            server.forward(&id, &RelayHop::ToDialer, &tag(), now),
+            Err(RelayStatus::Refused)
+        );
+        assert_eq!(
+            server.forward(
+                &ConnectionId::generate(),
+                &RelayHop::ToListener(peer_id),
+                &tag(),
+                now
+            ),
+            Err(RelayStatus::ResourceLimit)
+        );
+
+        // idle circuits and expired reservations are dropped
+        server.expire(now + config.circuit_idle_timeout);
+        assert_eq!(
+            server.forward(&id, &RelayHop::ToDialer, &listener, now),
+            Err(RelayStatus::NoReservation)
+        );
+        server.expire(now + config.reservation_ttl);
+        assert_eq!(
+            server.forward(&id, &RelayHop::ToListener(peer_id), &dialer, now),
+            Err(RelayStatus::NoReservation)
+        );
+    }
+}
@@ -0,0 +1,459 @@
+use log::warn;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError, TryLockError};
+use std::time::Duration;
+
+use super::address_book::write_atomically;
+use super::error::Error;
+use super::message::{is_stale, ConnectionId, CONNECTION_ID_LENGTH};
+use super::runtime::spawn_blocking;
+
+/// ReplayCache remembers the connection IDs of the ConnectionRequests we've answered,
+/// so that a request replayed by someone who captured it is dropped rather than
+/// answered again. A dialer never sends a request twice, and the handshake it signs
+/// covers the connection ID and the time it was signed at, so a replay can't come with
+/// a new one of either. ResumeRequests are remembered alongside, by the resumer's
+/// nonce, which their proof covers along with the time they were sent.
+///
+/// A request is only remembered until it's older than the maximum handshake age, since
+/// from then on it's dropped for its age, so an attacker can't push a request out of
+/// the cache by flooding it with others. A request stamped further in the future than
+/// the clock skew allows is dropped too, since it would have to be remembered for longer.
+///
+/// The challenge already keeps a replayed request from being handed to the swarm, but
+/// answering it costs a response, and tells whoever replayed it that we're up. A cache
+/// kept in memory is forgotten when we restart, so a persistent one also saves the
+/// requests it sees to a file, which is loaded back when the transport is created.
+#[derive(Debug)]
+pub(crate) struct ReplayCache {
+    /// connection IDs of the requests we've answered, and the times they were signed at.
+    seen: HashMap<ConnectionId, u64>,
+    max_age: Duration,
+    clock_skew: Duration,
+    /// file the requests are saved to; None if the cache is only kept in memory.
+    file: Option<ReplayFile>,
+}
+
+/// ReplayFile is where a persistent ReplayCache is saved. Requests are saved in batches
+/// by [`ReplayCache::flush`], on a blocking thread, so that a flood of them doesn't
+/// hold up the transport's poll with file I/O.
+#[derive(Debug)]
+struct ReplayFile {
+    path: Arc<PathBuf>,
+    /// requests seen since the last batch was written.
+    pending: Vec<(ConnectionId, u64)>,
+    /// number of requests in the file, including those expired since it was rewritten.
+    saved: usize,
+    /// sequence number of the last write started.
+    seq: u64,
+    /// sequence number of the last write made, held while it's being made.
+    written: Arc<Mutex<u64>>,
+}
+
+impl ReplayCache {
+    /// new returns an empty cache, kept only in memory, which remembers the requests
+    /// it's seen until they're older than `max_age`, allowing for the dialer's clock to
+    /// be up to `clock_skew` off from ours.
+    pub(crate) fn new(max_age: Duration, clock_skew: Duration) -> Self {
+        ReplayCache {
+            seen: HashMap::new(),
+            max_age,
+            clock_skew,
+            file: None,
+        }
+    }
+
+    /// open loads the cache saved at `path`, or starts an empty one if there's no file
+    /// there yet. The requests seen from now on are appended to `path` whenever the
+    /// cache is flushed, and the file is rewritten with only the requests that haven't
+    /// expired once they're fewer than half of it.
+    pub(crate) fn open(
+        path: impl Into<PathBuf>,
+        max_age: Duration,
+        clock_skew: Duration,
+        now: u64,
+    ) -> Result<Self, Error> {
+        let path = path.into();
+        let mut cache = ReplayCache::new(max_age, clock_skew);
+        let saved = match std::fs::read_to_string(&path) {
+            Ok(contents) => cache.parse(&contents, now).map_err(Error::ReplayCache)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(Error::ReplayCache(e)),
+        };
+        cache.file = Some(ReplayFile {
+            path: Arc::new(path),
+            pending: vec![],
+            saved,
+            seq: 0,
+            written: Arc::new(Mutex::new(0)),
+        });
+        Ok(cache)
+    }
+
+    /// insert records that a ConnectionRequest signed at `timestamp` was answered on
+    /// connection `id`, unless it's too old or too far in the future to be answered at
+    /// `now`, or it was answered before. It should only be called once the request's
+    /// signature has been checked, so that a forged request can't take up the cache.
+    pub(crate) fn insert(
+        &mut self,
+        id: &ConnectionId,
+        timestamp: u64,
+        now: u64,
+    ) -> Result<(), Error> {
+        let skew = u64::try_from(self.clock_skew.as_millis()).unwrap_or(u64::MAX);
+        if timestamp > now.saturating_add(skew) || self.is_expired(timestamp, now) {
+            return Err(Error::StaleConnectionRequest(timestamp));
+        }
+        match self.seen.entry(id.clone()) {
+            Entry::Occupied(_) => return Err(Error::ReplayedConnectionRequest),
+            Entry::Vacant(entry) => entry.insert(timestamp),
+        };
+        if let Some(file) = &mut self.file {
+            file.pending.push((id.clone(), timestamp));
+        }
+        Ok(())
+    }
+
+    /// flush forgets the requests that have expired by `now`, and saves the ones seen
+    /// since the last flush, unless the last batch is still being written, in which case
+    /// they're saved by the next flush.
+    pub(crate) fn flush(&mut self, now: u64) {
+        let (max_age, clock_skew) = (self.max_age, self.clock_skew);
+        self.seen
+            .retain(|_, timestamp| !is_stale(*timestamp, now, max_age, clock_skew));
+
+        let ReplayCache {
+            seen,
+            file: Some(file),
+            ..
+        } = self
+        else {
+            return;
+        };
+        if file.is_writing() || file.pending.is_empty() && file.saved <= 2 * seen.len() {
+            return;
+        }
+        let write = file.next_write(seen);
+        let (path, written, seq) = (file.path.clone(), file.written.clone(), file.seq);
+        // dropping the handle detaches the write
+        spawn_blocking(move || ReplayFile::write(&path, &written, seq, write));
+    }
+
+    /// is_expired returns true if a request signed at `timestamp` is too old to be
+    /// answered at `now`.
+    fn is_expired(&self, timestamp: u64, now: u64) -> bool {
+        is_stale(timestamp, now, self.max_age, self.clock_skew)
+    }
+
+    /// parse loads a cache in the format written by `flush`: a line per request, holding
+    /// its connection ID in hex and the time it was signed at, returning the number of
+    /// lines read. Requests that have expired by `now` are skipped.
+    fn parse(&mut self, contents: &str, now: u64) -> io::Result<usize> {
+        let mut saved = 0;
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let invalid = || {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid replay cache entry: {}", line),
+                )
+            };
+            let (id, timestamp) = line.trim().split_once(' ').ok_or_else(invalid)?;
+            let id = hex::decode(id)
+                .ok()
+                .filter(|bytes| bytes.len() == CONNECTION_ID_LENGTH)
+                .ok_or_else(invalid)?;
+            let timestamp = timestamp.parse::<u64>().map_err(|_| invalid())?;
+            if !self.is_expired(timestamp, now) {
+                self.seen.insert(ConnectionId::from_bytes(&id), timestamp);
+            }
+            saved += 1;
+        }
+        Ok(saved)
+    }
+}
+
+impl Drop for ReplayCache {
+    /// drop saves the requests that haven't been yet, which are otherwise lost on a
+    /// restart. It's the only write made on the caller's thread, since it's made once.
+    fn drop(&mut self) {
+        let ReplayCache {
+            seen,
+            file: Some(file),
+            ..
+        } = self
+        else {
+            return;
+        };
+        if !file.is_writing() && file.pending.is_empty() {
+            return;
+        }
+        // a write still being made would be missing the pending requests, so the file is
+        // rewritten, which supersedes it
+        file.seq += 1;
+        let write = ReplayWrite::Rewrite(entries(seen.iter()));
+        ReplayFile::write(&file.path, &file.written, file.seq, write);
+    }
+}
+
+/// ReplayWrite is a write to a ReplayFile.
+enum ReplayWrite {
+    /// lines appended to the file.
+    Append(String),
+    /// the file's new contents.
+    Rewrite(String),
+}
+
+impl ReplayFile {
+    /// next_write takes the requests waiting to be saved, returning the write that saves
+    /// them: an append to the file, or a rewrite of it with the requests in `seen` once
+    /// it's mostly expired ones.
+    fn next_write(&mut self, seen: &HashMap<ConnectionId, u64>) -> ReplayWrite {
+        self.seq += 1;
+        let pending = std::mem::take(&mut self.pending);
+        if self.saved + pending.len() > 2 * seen.len() {
+            self.saved = seen.len();
+            ReplayWrite::Rewrite(entries(seen.iter()))
+        } else {
+            self.saved += pending.len();
+            ReplayWrite::Append(entries(pending.iter().map(|(id, t)| (id, t))))
+        }
+    }
+
+    /// is_writing returns true if the last write started hasn't been made yet.
+    fn is_writing(&self) -> bool {
+        match self.written.try_lock() {
+            Ok(written) => *written < self.seq,
+            Err(TryLockError::Poisoned(written)) => *written.into_inner() < self.seq,
+            Err(TryLockError::WouldBlock) => true,
+        }
+    }
+
+    /// write makes `write`, the `seq`th write to the file at `path`, unless a later one
+    /// has already been made. A failure is logged rather than returned, since the cache
+    /// in memory is still correct.
+    fn write(path: &Path, written: &Mutex<u64>, seq: u64, write: ReplayWrite) {
+        let mut written = written.lock().unwrap_or_else(PoisonError::into_inner);
+        if *written > seq {
+            return;
+        }
+        *written = seq;
+        let res = match write {
+            ReplayWrite::Append(lines) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(lines.as_bytes())),
+            ReplayWrite::Rewrite(contents) => write_atomically(path, &contents),
+        };
+        if let Err(e) = res {
+            warn!("failed to save replay cache to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// entries returns the lines `requests` are saved as.
+fn entries<'a>(requests: impl Iterator<Item = (&'a ConnectionId, &'a u64)>) -> String {
+    requests
+        .map(|(id, timestamp)| format!("{} {}\n", hex::encode(id.as_bytes()), timestamp))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::backend::MixnetBackend;
+    use super::super::codec::{Codec, WireCodec};
+    use super::super::config::NymTransportConfig;
+    use super::super::events::{DropReason, NymEvent};
+    use super::super::memory::{InMemoryConfig, InMemoryMixnet};
+    use super::super::message::Message;
+    use super::super::runtime::timeout;
+    use super::super::testing::{self, connection_request, next_event, poll_incoming, recv_all};
+    use super::*;
+    use futures::StreamExt;
+    use libp2p_identity::Keypair;
+    use nym_sdk::mixnet::IncludedSurbs;
+
+    const MAX_AGE: Duration = Duration::from_secs(60);
+    const CLOCK_SKEW: Duration = Duration::from_secs(10);
+    const NOW: u64 = 1_700_000_000_000;
+
+    #[test]
+    fn test_replay_cache() {
+        let mut cache = ReplayCache::new(MAX_AGE, CLOCK_SKEW);
+        let id = ConnectionId::generate();
+        cache.insert(&id, NOW, NOW).unwrap();
+        assert!(matches!(
+            cache.insert(&id, NOW, NOW),
+            Err(Error::ReplayedConnectionRequest)
+        ));
+        cache.insert(&ConnectionId::generate(), NOW, NOW).unwrap();
+
+        // requests too old to be answered, or signed too far in the future, are dropped
+        // without being remembered
+        let old = NOW - 71_000;
+        let future = NOW + 11_000;
+        for timestamp in [old, future] {
+            let id = ConnectionId::generate();
+            assert!(matches!(
+                cache.insert(&id, timestamp, NOW),
+                Err(Error::StaleConnectionRequest(t)) if t == timestamp
+            ));
+            assert!(!cache.seen.contains_key(&id));
+        }
+        cache
+            .insert(&ConnectionId::generate(), NOW + 9_000, NOW)
+            .unwrap();
+
+        // a request is remembered however many are seen after it, until it's expired
+        for _ in 0..10_000 {
+            cache.insert(&ConnectionId::generate(), NOW, NOW).unwrap();
+        }
+        cache.flush(NOW + 70_000);
+        assert!(cache.insert(&id, NOW, NOW + 70_000).is_err());
+        cache.flush(NOW + 71_000);
+        assert_eq!(cache.seen.len(), 1);
+        assert!(matches!(
+            cache.insert(&id, NOW, NOW + 71_000),
+            Err(Error::StaleConnectionRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_replay_cache_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replay-cache");
+        let (first, second) = (ConnectionId::generate(), ConnectionId::generate());
+
+        // requests are only saved once the cache is flushed, or dropped
+        let mut cache = ReplayCache::open(&path, MAX_AGE, CLOCK_SKEW, NOW).unwrap();
+        cache.insert(&first, NOW, NOW).unwrap();
+        assert!(!path.exists());
+        cache.flush(NOW);
+        cache.insert(&second, NOW + 1_000, NOW).unwrap();
+        drop(cache);
+
+        // the requests seen before a restart are still dropped after it
+        let mut cache = ReplayCache::open(&path, MAX_AGE, CLOCK_SKEW, NOW).unwrap();
+        assert_eq!(cache.seen.len(), 2);
+        assert!(matches!(
+            cache.insert(&first, NOW, NOW),
+            Err(Error::ReplayedConnectionRequest)
+        ));
+
+        // the file is rewritten once it's mostly expired requests
+        let (later, third) = (NOW + 71_500, ConnectionId::generate());
+        cache.insert(&third, later, later).unwrap();
+        cache.flush(later);
+        while cache.file.as_ref().unwrap().is_writing() {
+            tokio::task::yield_now().await;
+        }
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        let reopened = ReplayCache::open(&path, MAX_AGE, CLOCK_SKEW, later).unwrap();
+        assert!(reopened.seen.contains_key(&third));
+        drop((cache, reopened));
+
+        std::fs::write(&path, "not a replay cache").unwrap();
+        assert!(matches!(
+            ReplayCache::open(&path, MAX_AGE, CLOCK_SKEW, NOW),
+            Err(Error::ReplayCache(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_replayed_connection_request_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let config = NymTransportConfig::default()
+            .with_dial_timeout(Duration::from_millis(200))
+            .with_gap_timeout(Duration::from_millis(200));
+        let mut listener = testing::transport(&mixnet, config).await;
+
+        // a ConnectionRequest sent again by someone who saw it on its way to the listener,
+        // and doesn't hold the key of the dialer that signed it
+        let request = Message::ConnectionRequest(connection_request(
+            &Keypair::generate_ed25519(),
+            &ConnectionId::generate(),
+        ));
+        let mut replayer = mixnet.client();
+        replayer
+            .sender()
+            .send(
+                listener.local_nym_address(),
+                &WireCodec::encode(&request),
+                IncludedSurbs::Amount(10),
+            )
+            .await
+            .unwrap();
+
+        // the listener answers it, but never hands the connection to the swarm
+        assert_eq!(
+            poll_incoming(&mut listener, Duration::from_millis(600)).await,
+            0
+        );
+        let responses = recv_all(&mut replayer).await;
+        assert_eq!(responses.len(), 1);
+        let Message::ConnectionResponse(response) =
+            WireCodec::decode(responses[0].message.clone().into()).unwrap()
+        else {
+            panic!("expected Message::ConnectionResponse");
+        };
+        assert!(response.challenge.is_some());
+
+        // and forgets it once the challenge has gone unanswered for the handshake timeout
+        assert!(listener.debug_snapshot().connections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replay_cache_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let dir = tempfile::tempdir().unwrap();
+        let config =
+            NymTransportConfig::default().with_replay_cache_path(dir.path().join("replays"));
+        let request = WireCodec::encode(&Message::ConnectionRequest(connection_request(
+            &Keypair::generate_ed25519(),
+            &ConnectionId::generate(),
+        )));
+        let mut replayer = mixnet.client();
+
+        // a listener answers a request once, and drops it when it's replayed, even by a
+        // listener that started after it was first seen, which loads the requests the
+        // first saved when it was dropped
+        let mut answered = vec![];
+        for _ in 0..2 {
+            let mut listener = testing::transport(&mixnet, config.clone()).await;
+            let mut events = Box::pin(listener.events());
+            for _ in 0..2 {
+                replayer
+                    .sender()
+                    .send(
+                        listener.local_nym_address(),
+                        &request,
+                        IncludedSurbs::Amount(10),
+                    )
+                    .await
+                    .unwrap();
+            }
+            let replayed = async {
+                loop {
+                    tokio::select! {
+                        event = events.next() => {
+                            if let Some(NymEvent::MessageDropped { reason: DropReason::Replayed }) = event {
+                                break;
+                            }
+                        }
+                        _ = next_event(&mut listener) => {}
+                    }
+                }
+            };
+            timeout(Duration::from_secs(5), replayed)
+                .await
+                .expect("the replayed request wasn't dropped");
+            answered.push(recv_all(&mut replayer).await.len());
+        }
+        assert_eq!(answered, [1, 0]);
+    }
+}
@@ -0,0 +1,442 @@
+use bytes::{BufMut, BytesMut};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit},
+    XChaCha20Poly1305, XNonce,
+};
+use libp2p_identity::{Keypair, PublicKey};
+use nym_sphinx::addressing::clients::Recipient;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::fmt::{Debug, Formatter};
+
+use super::error::Error;
+use super::handshake::{ResumptionSecret, Role};
+use super::message::{Capabilities, ConnectionId, ConnectionInfo};
+
+/// domain the ticket key is derived from the transport's identity key in.
+const TICKET_KEY_DOMAIN: &[u8] = b"libp2p-nym session tickets";
+
+const CONNECTION_ID_LEN: usize = 32;
+const EXPIRES_LEN: usize = 8; // length of u64
+const SECRET_LEN: usize = 32;
+const CAPABILITIES_LEN: usize = 4; // length of u32
+const MAX_MESSAGE_SIZE_LEN: usize = 4; // length of u32
+const XNONCE_LEN: usize = 24;
+
+/// SessionTicket is what we need to take a connection back up after losing its state,
+/// e.g. because we restarted. We don't keep it: it's sealed with our [`TicketKey`] and
+/// handed to the remote in a SessionTicketMessage, which hands it back in a
+/// ResumeRequest if we stop answering its keepalives.
+#[derive(Clone, Debug)]
+pub(crate) struct SessionTicket {
+    pub(crate) id: ConnectionId,
+    /// our side of the connection, which its resumption keeps.
+    pub(crate) role: Role,
+    /// when the ticket stops being accepted, in milliseconds since the UNIX epoch.
+    pub(crate) expires: u64,
+    pub(crate) secret: ResumptionSecret,
+    /// the identity key the remote opened the connection with.
+    pub(crate) remote_identity: PublicKey,
+    /// the remote's nym address, if it exposed it; otherwise we reply to the
+    /// ResumeRequest, and the rest of the connection, through its SURBs.
+    pub(crate) remote_recipient: Option<Recipient>,
+    pub(crate) remote_capabilities: Capabilities,
+    /// the largest message the remote advertised it accepts.
+    pub(crate) remote_max_message_size: u32,
+    pub(crate) remote_info: ConnectionInfo,
+    /// the service the connection was dialed to, so that its resumption is reported
+    /// on the same listener.
+    pub(crate) service: Option<u16>,
+}
+
+impl SessionTicket {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(self.id.as_bytes());
+        bytes.put_u8(match self.role {
+            Role::Dialer => 0,
+            Role::Listener => 1,
+        });
+        bytes.put_u64(self.expires);
+        bytes.extend_from_slice(self.secret.as_bytes());
+        bytes.put_u32(self.remote_capabilities.0);
+        bytes.put_u32(self.remote_max_message_size);
+        let identity = self.remote_identity.encode_protobuf();
+        bytes.put_u16(identity.len() as u16);
+        bytes.extend_from_slice(&identity);
+        match &self.remote_recipient {
+            Some(recipient) => {
+                bytes.put_u8(1);
+                bytes.extend_from_slice(&recipient.to_bytes());
+            }
+            None => bytes.put_u8(0),
+        }
+        match self.service {
+            Some(service) => {
+                bytes.put_u8(1);
+                bytes.put_u16(service);
+            }
+            None => bytes.put_u8(0),
+        }
+        self.remote_info.encode(&mut bytes);
+        bytes.to_vec()
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        const HEADER_LEN: usize = CONNECTION_ID_LEN
+            + 1
+            + EXPIRES_LEN
+            + SECRET_LEN
+            + CAPABILITIES_LEN
+            + MAX_MESSAGE_SIZE_LEN
+            + 2;
+        if bytes.len() < HEADER_LEN {
+            return Err(Error::InvalidSessionTicket);
+        }
+
+        let id = ConnectionId::from_bytes(bytes);
+        let (header, rest) = bytes[CONNECTION_ID_LEN..HEADER_LEN].split_at(1);
+        let role = match header[0] {
+            0 => Role::Dialer,
+            1 => Role::Listener,
+            _ => return Err(Error::InvalidSessionTicket),
+        };
+        let (expires, rest) = rest.split_at(EXPIRES_LEN);
+        let (secret, rest) = rest.split_at(SECRET_LEN);
+        let (capabilities, rest) = rest.split_at(CAPABILITIES_LEN);
+        let (max_message_size, identity_len) = rest.split_at(MAX_MESSAGE_SIZE_LEN);
+        let identity_len = u16::from_be_bytes([identity_len[0], identity_len[1]]) as usize;
+
+        let rest = &bytes[HEADER_LEN..];
+        let identity = rest
+            .get(..identity_len)
+            .ok_or(Error::InvalidSessionTicket)?;
+        let remote_identity =
+            PublicKey::try_decode_protobuf(identity).map_err(|_| Error::InvalidSessionTicket)?;
+        let rest = &rest[identity_len..];
+        let (remote_recipient, rest) = match rest.split_first() {
+            Some((0, rest)) => (None, rest),
+            Some((1, rest)) => {
+                let recipient: [u8; Recipient::LEN] = rest
+                    .get(..Recipient::LEN)
+                    .and_then(|recipient| recipient.try_into().ok())
+                    .ok_or(Error::InvalidSessionTicket)?;
+                let recipient = Recipient::try_from_bytes(recipient)
+                    .map_err(|_| Error::InvalidSessionTicket)?;
+                (Some(recipient), &rest[Recipient::LEN..])
+            }
+            _ => return Err(Error::InvalidSessionTicket),
+        };
+        let (service, rest) = match rest.split_first() {
+            Some((0, rest)) => (None, rest),
+            Some((1, [a, b, rest @ ..])) => (Some(u16::from_be_bytes([*a, *b])), rest),
+            _ => return Err(Error::InvalidSessionTicket),
+        };
+        let remote_info =
+            ConnectionInfo::try_from_bytes(rest).map_err(|_| Error::InvalidSessionTicket)?;
+
+        Ok(SessionTicket {
+            id,
+            role,
+            expires: u64::from_be_bytes(expires.try_into().expect("split at its length")),
+            secret: ResumptionSecret::from_bytes(secret.try_into().expect("split at its length")),
+            remote_identity,
+            remote_recipient,
+            remote_capabilities: Capabilities(u32::from_be_bytes(
+                capabilities.try_into().expect("split at its length"),
+            )),
+            remote_max_message_size: u32::from_be_bytes(
+                max_message_size.try_into().expect("split at its length"),
+            ),
+            remote_info,
+            service,
+        })
+    }
+}
+
+/// TicketKey seals the session tickets we issue, so that only we can open them, and
+/// no one can forge one. It's derived from the transport's identity key, so tickets
+/// issued before a restart can still be opened after it, if the identity key is kept.
+pub(crate) struct TicketKey(XChaCha20Poly1305);
+
+impl TicketKey {
+    /// new derives the ticket key from `keypair`. A key type we can't derive from gets
+    /// a random ticket key, whose tickets can't be opened after a restart.
+    pub(crate) fn new(keypair: &Keypair) -> Self {
+        let key = keypair.derive_secret(TICKET_KEY_DOMAIN).unwrap_or_else(|| {
+            let mut key = [0u8; 32];
+            OsRng.fill_bytes(&mut key);
+            key
+        });
+        TicketKey(XChaCha20Poly1305::new(&key.into()))
+    }
+
+    /// seal returns `ticket` encrypted with a random nonce, preceded by the nonce.
+    pub(crate) fn seal(&self, ticket: &SessionTicket) -> Result<Vec<u8>, Error> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, ticket.to_bytes().as_slice())
+            .map_err(|_| Error::EncryptionFailure)?;
+
+        let mut bytes = nonce.to_vec();
+        bytes.extend_from_slice(&ciphertext);
+        // the ticket is sent with its length as a u16
+        if bytes.len() > u16::MAX as usize {
+            return Err(Error::MessageTooLarge(bytes.len()));
+        }
+        Ok(bytes)
+    }
+
+    /// open decrypts a ticket sealed by [`TicketKey::seal`], failing if it wasn't sealed
+    /// with this key, or has expired by `now`, in milliseconds since the UNIX epoch.
+    pub(crate) fn open(&self, bytes: &[u8], now: u64) -> Result<SessionTicket, Error> {
+        if bytes.len() < XNONCE_LEN {
+            return Err(Error::InvalidSessionTicket);
+        }
+
+        let (nonce, ciphertext) = bytes.split_at(XNONCE_LEN);
+        let plaintext = self
+            .0
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Error::InvalidSessionTicket)?;
+        let ticket = SessionTicket::try_from_bytes(&plaintext)?;
+        if ticket.expires <= now {
+            return Err(Error::SessionTicketExpired);
+        }
+        Ok(ticket)
+    }
+}
+
+impl Debug for TicketKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TicketKey").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::backend::MixnetBackend;
+    use super::super::codec::{Codec, WireCodec};
+    use super::super::config::NymTransportConfig;
+    use super::super::events::{DropReason, NymEvent};
+    use super::super::handshake::{new_resume_nonce, proofs_match, sign_challenge, Handshake};
+    use super::super::memory::{InMemoryConfig, InMemoryMixnet};
+    use super::super::message::{
+        ChallengeResponseMessage, ConnectionMessage, Message, ResumeRequestMessage,
+    };
+    use super::super::runtime::unix_millis;
+    use super::super::testing::{connection_request, poll_incoming, recv_all};
+    use super::super::transport::NymTransport;
+    use super::*;
+    use futures::StreamExt;
+    use nym_sdk::mixnet::IncludedSurbs;
+    use std::time::Duration;
+
+    fn ticket(remote_recipient: Option<Recipient>, service: Option<u16>) -> SessionTicket {
+        SessionTicket {
+            id: ConnectionId::generate(),
+            role: Role::Listener,
+            expires: 2_000,
+            secret: ResumptionSecret::from_bytes([7u8; 32]),
+            remote_identity: Keypair::generate_ed25519().public(),
+            remote_recipient,
+            remote_capabilities: Capabilities::RETRANSMIT | Capabilities::RESUMPTION,
+            remote_max_message_size: 4096,
+            remote_info: ConnectionInfo {
+                agent_version: Some("test/1.0".to_string()),
+                extensions: vec!["a".to_string()],
+            },
+            service,
+        }
+    }
+
+    #[test]
+    fn test_session_ticket() {
+        let keypair = Keypair::generate_ed25519();
+        let key = TicketKey::new(&keypair);
+        let address = Recipient::try_from_base58_string(
+            "Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR",
+        )
+        .unwrap();
+
+        for (remote_recipient, service) in [(None, None), (Some(address), Some(8080))] {
+            let ticket = ticket(remote_recipient, service);
+            let sealed = key.seal(&ticket).unwrap();
+            let opened = key.open(&sealed, 1_000).unwrap();
+            assert_eq!(opened.id, ticket.id);
+            assert_eq!(opened.role, ticket.role);
+            assert_eq!(opened.secret, ticket.secret);
+            assert_eq!(opened.remote_identity, ticket.remote_identity);
+            assert_eq!(opened.remote_recipient, ticket.remote_recipient);
+            assert_eq!(opened.remote_capabilities, ticket.remote_capabilities);
+            assert_eq!(
+                opened.remote_max_message_size,
+                ticket.remote_max_message_size
+            );
+            assert_eq!(opened.remote_info, ticket.remote_info);
+            assert_eq!(opened.service, ticket.service);
+        }
+
+        // the key is the same after a restart with the same identity
+        let sealed = key.seal(&ticket(None, None)).unwrap();
+        assert!(TicketKey::new(&keypair).open(&sealed, 1_000).is_ok());
+
+        // but tickets can't be opened by anyone else, nor once they've expired
+        assert!(matches!(
+            TicketKey::new(&Keypair::generate_ed25519()).open(&sealed, 1_000),
+            Err(Error::InvalidSessionTicket)
+        ));
+        assert!(matches!(
+            key.open(&sealed, 2_000),
+            Err(Error::SessionTicketExpired)
+        ));
+
+        // nor once they've been modified
+        let mut modified = sealed.clone();
+        modified[XNONCE_LEN] ^= 1;
+        assert!(matches!(
+            key.open(&modified, 1_000),
+            Err(Error::InvalidSessionTicket)
+        ));
+        assert!(matches!(
+            key.open(&sealed[..XNONCE_LEN - 1], 1_000),
+            Err(Error::InvalidSessionTicket)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_replayed_resume_request_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let config = NymTransportConfig::default().with_session_tickets(Duration::from_secs(60));
+        let listener_key = Keypair::generate_ed25519();
+        let mut listener =
+            NymTransport::new_with_backend(mixnet.client(), listener_key.clone(), config.clone())
+                .await
+                .unwrap();
+
+        // a dialer which exposes its address, and so is issued a session ticket
+        let dialer_key = Keypair::generate_ed25519();
+        let mut dialer = mixnet.client();
+        let dialer_address = dialer.our_address();
+        let id = ConnectionId::generate();
+        let handshake = Handshake::new(&dialer_key, &id, Some(&dialer_address)).unwrap();
+        let request = Message::ConnectionRequest(ConnectionMessage {
+            capabilities: Capabilities::RESUMPTION,
+            recipient: Some(dialer_address),
+            handshake: handshake.payload(),
+            ..connection_request(&dialer_key, &id)
+        });
+        let listener_address = listener.local_nym_address();
+        let sender = dialer.sender();
+        let sender = sender.as_ref();
+        let send = |address: Recipient, message: Message| async move {
+            sender
+                .send(
+                    address,
+                    &WireCodec::encode(&message),
+                    IncludedSurbs::ExposeSelfAddress,
+                )
+                .await
+                .unwrap()
+        };
+        send(listener_address, request).await;
+        assert_eq!(
+            poll_incoming(&mut listener, Duration::from_millis(100)).await,
+            0
+        );
+        let responses = recv_all(&mut dialer).await;
+        let Message::ConnectionResponse(response) =
+            WireCodec::decode(responses[0].message.clone().into()).unwrap()
+        else {
+            panic!("expected Message::ConnectionResponse");
+        };
+        let secret = handshake
+            .finish(
+                &response.handshake,
+                &listener_key.public().to_peer_id(),
+                Some(&listener_address),
+                Role::Dialer,
+            )
+            .unwrap()
+            .resumption_secret()
+            .clone();
+        let signature = sign_challenge(&dialer_key, &id, &response.challenge.unwrap()).unwrap();
+        let challenge_response = Message::ChallengeResponse(ChallengeResponseMessage {
+            id: id.clone(),
+            signature,
+        });
+        send(listener_address, challenge_response).await;
+        assert_eq!(
+            poll_incoming(&mut listener, Duration::from_millis(100)).await,
+            1
+        );
+        let ticket = recv_all(&mut dialer)
+            .await
+            .into_iter()
+            .find_map(
+                |message| match WireCodec::decode(message.message.into()).unwrap() {
+                    Message::SessionTicket(ticket) => Some(ticket.ticket),
+                    _ => None,
+                },
+            )
+            .unwrap();
+
+        // the listener restarts, and the ResumeRequest the dialer sends it is replayed
+        drop(listener);
+        let mut listener = NymTransport::new_with_backend(mixnet.client(), listener_key, config)
+            .await
+            .unwrap();
+        let listener_address = listener.local_nym_address();
+        let mut dropped = Box::pin(listener.events().filter_map(|event| async move {
+            match event {
+                NymEvent::MessageDropped { reason } => Some(reason),
+                _ => None,
+            }
+        }));
+        let (nonce, timestamp) = (new_resume_nonce(), unix_millis());
+        let resume = Message::ResumeRequest(ResumeRequestMessage {
+            id: id.clone(),
+            timestamp,
+            nonce,
+            proof: secret.request_proof(&id, &nonce, timestamp),
+            ticket,
+        });
+        send(listener_address, resume.clone()).await;
+        send(listener_address, resume).await;
+
+        // the replay is dropped, and the connection is only handed to the swarm once
+        // the dialer signs the nonce of the listener's answer
+        assert_eq!(
+            poll_incoming(&mut listener, Duration::from_millis(100)).await,
+            0
+        );
+        assert_eq!(dropped.next().await, Some(DropReason::Replayed));
+        let responses: Vec<_> = recv_all(&mut dialer)
+            .await
+            .into_iter()
+            .filter_map(
+                |message| match WireCodec::decode(message.message.into()).unwrap() {
+                    Message::ResumeResponse(response) => Some(response),
+                    _ => None,
+                },
+            )
+            .collect();
+        assert_eq!(responses.len(), 1);
+        let response = &responses[0];
+        assert!(proofs_match(
+            &secret.response_proof(&id, &nonce, &response.nonce),
+            &response.proof
+        ));
+        let signature = sign_challenge(&dialer_key, &id, &response.nonce).unwrap();
+        let challenge_response = Message::ChallengeResponse(ChallengeResponseMessage {
+            id: id.clone(),
+            signature,
+        });
+        send(listener_address, challenge_response).await;
+        assert_eq!(
+            poll_incoming(&mut listener, Duration::from_millis(100)).await,
+            1
+        );
+    }
+}
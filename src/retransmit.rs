@@ -0,0 +1,291 @@
+use log::{debug, warn};
+use nym_sdk::mixnet::AnonymousSenderTag;
+use nym_sphinx::addressing::clients::Recipient;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::config::RetransmitConfig;
+use super::message::{
+    AckMessage, ConnectionId, Message, OutboundMessage, ResumeRequestMessage,
+    ResumeResponseMessage, TransportMessage,
+};
+use super::nonce::Nonce;
+use super::rtt::RttTable;
+use super::runtime::Instant;
+
+/// Unacked is a TransportMessage that's been sent, but not yet acknowledged by the remote.
+struct Unacked {
+    message: TransportMessage,
+    recipient: Option<Recipient>,
+    sender_tag: Option<AnonymousSenderTag>,
+    /// number of times the message has been retransmitted
+    retries: u32,
+    /// time waited for an acknowledgement since the message was last sent
+    timeout: Duration,
+    /// when the message is next retransmitted
+    deadline: Instant,
+}
+
+/// Retransmitter keeps every TransportMessage we send until the remote acknowledges it,
+/// and retransmits it with exponential backoff in case it was dropped by the mixnet.
+/// Retransmitted messages keep their nonce, so the remote drops any duplicates.
+/// Once a connection's round-trip time has been measured, its messages are first
+/// retransmitted after a timeout derived from it, rather than the configured one.
+pub(crate) struct Retransmitter {
+    config: RetransmitConfig,
+    rtt: RttTable,
+
+    /// (connection ID, nonce) -> message waiting to be acknowledged
+    unacked: HashMap<(ConnectionId, Nonce), Unacked>,
+}
+
+impl Retransmitter {
+    pub(crate) fn new(config: RetransmitConfig, rtt: RttTable) -> Self {
+        Retransmitter {
+            config,
+            rtt,
+            unacked: HashMap::new(),
+        }
+    }
+
+    /// on_send starts waiting for the given message to be acknowledged,
+    /// unless it isn't a TransportMessage, is a datagram, or is already being waited on.
+    /// A message resuming a connection discards the connection's unacknowledged
+    /// messages instead: they belong to the session it replaces, whose nonces and
+    /// keys the remote no longer knows.
+    pub(crate) fn on_send(&mut self, message: &OutboundMessage, now: Instant) {
+        let msg = match &message.message {
+            Message::TransportMessage(msg) => msg,
+            Message::ResumeRequest(ResumeRequestMessage { id, .. })
+            | Message::ResumeResponse(ResumeResponseMessage { id, .. }) => {
+                self.unacked.retain(|(unacked_id, _), _| unacked_id != id);
+                return;
+            }
+            _ => return,
+        };
+        if msg.message.is_datagram() {
+            return;
+        }
+
+        let timeout = match self.rtt.retransmit_timeout(&msg.id) {
+            Some(timeout) => timeout.min(self.config.max_timeout),
+            None => self.config.initial_timeout,
+        };
+        self.unacked
+            .entry((msg.id.clone(), msg.nonce))
+            .or_insert_with(|| Unacked {
+                message: msg.clone(),
+                recipient: message.recipient,
+                sender_tag: message.sender_tag.clone(),
+                retries: 0,
+                timeout,
+                deadline: now + timeout,
+            });
+    }
+
+    /// on_ack stops waiting for the acknowledged message.
+    pub(crate) fn on_ack(&mut self, ack: &AckMessage) {
+        if self.unacked.remove(&(ack.id.clone(), ack.nonce)).is_none() {
+            debug!("ignoring ack for unknown nonce {}", ack.nonce);
+        }
+    }
+
+    /// next_deadline returns when the next message is due to be retransmitted, if any.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.unacked.values().map(|unacked| unacked.deadline).min()
+    }
+
+    /// poll_due returns the messages due to be retransmitted at `now`, along with the
+    /// connections on which a message has run out of retries. Every message on those
+    /// connections is discarded, since the connection has failed.
+    pub(crate) fn poll_due(&mut self, now: Instant) -> (Vec<OutboundMessage>, Vec<ConnectionId>) {
+        let mut failed = vec![];
+        for ((id, nonce), unacked) in self.unacked.iter() {
+            if unacked.deadline <= now
+                && unacked.retries >= self.config.max_retries
+                && !failed.contains(id)
+            {
+                warn!(
+                    "message {} on connection {:?} not acknowledged after {} retries",
+                    nonce, id, unacked.retries
+                );
+                failed.push(id.clone());
+            }
+        }
+        self.unacked.retain(|(id, _), _| !failed.contains(id));
+
+        let mut due = vec![];
+        for unacked in self.unacked.values_mut() {
+            if unacked.deadline > now {
+                continue;
+            }
+
+            unacked.retries += 1;
+            unacked.timeout = self.config.next_timeout(unacked.timeout);
+            unacked.deadline = now + unacked.timeout;
+            due.push(OutboundMessage {
+                message: Message::TransportMessage(unacked.message.clone()),
+                recipient: unacked.recipient,
+                sender_tag: unacked.sender_tag.clone(),
+            });
+        }
+
+        // the remote handles messages in nonce order, so send the earliest first
+        due.sort_by_key(|message| match &message.message {
+            Message::TransportMessage(msg) => msg.nonce,
+            _ => Nonce::default(),
+        });
+        (due, failed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::message::{SubstreamId, SubstreamMessage, DATAGRAM_NONCE};
+    use super::*;
+    use bytes::Bytes;
+
+    fn outbound(id: &ConnectionId, nonce: Nonce) -> OutboundMessage {
+        OutboundMessage {
+            message: Message::TransportMessage(TransportMessage {
+                nonce,
+                id: id.clone(),
+                message: SubstreamMessage::new_with_data(
+                    SubstreamId::generate(),
+                    Bytes::from_static(&[1, 2, 3]),
+                ),
+                timestamp: None,
+            }),
+            recipient: None,
+            sender_tag: None,
+        }
+    }
+
+    #[test]
+    fn test_retransmit_until_acked() {
+        let config = RetransmitConfig::default()
+            .with_backoff(Duration::from_secs(1), Duration::from_secs(3))
+            .with_max_retries(3);
+        let mut retransmitter = Retransmitter::new(config, RttTable::default());
+        let id = ConnectionId::generate();
+        let start = Instant::now();
+
+        retransmitter.on_send(&outbound(&id, Nonce::FIRST), start);
+        retransmitter.on_send(&outbound(&id, Nonce::new(2)), start);
+        assert_eq!(
+            retransmitter.next_deadline(),
+            Some(start + Duration::from_secs(1))
+        );
+
+        // nothing is due before the timeout
+        let (due, failed) = retransmitter.poll_due(start);
+        assert!(due.is_empty() && failed.is_empty());
+
+        // an acknowledged message isn't retransmitted
+        retransmitter.on_ack(&AckMessage {
+            id: id.clone(),
+            nonce: Nonce::FIRST,
+        });
+        let now = start + Duration::from_secs(1);
+        let (due, failed) = retransmitter.poll_due(now);
+        assert_eq!(due.len(), 1);
+        assert!(failed.is_empty());
+        assert_eq!(due[0].message.connection_id(), &id);
+
+        // the timeout doubles, up to the maximum
+        assert_eq!(
+            retransmitter.next_deadline(),
+            Some(now + Duration::from_secs(2))
+        );
+        let now = now + Duration::from_secs(2);
+        assert_eq!(retransmitter.poll_due(now).0.len(), 1);
+        assert_eq!(
+            retransmitter.next_deadline(),
+            Some(now + Duration::from_secs(3))
+        );
+        let now = now + Duration::from_secs(3);
+        assert_eq!(retransmitter.poll_due(now).0.len(), 1);
+
+        // the connection fails once the retries run out
+        let now = now + Duration::from_secs(3);
+        let (due, failed) = retransmitter.poll_due(now);
+        assert!(due.is_empty());
+        assert_eq!(failed, vec![id]);
+        assert_eq!(retransmitter.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_retransmit_timeout_from_rtt() {
+        let config = RetransmitConfig::default()
+            .with_backoff(Duration::from_secs(10), Duration::from_secs(30));
+        let rtt = RttTable::default();
+        let mut retransmitter = Retransmitter::new(config, rtt.clone());
+        let id = ConnectionId::generate();
+        let start = Instant::now();
+
+        // a measured connection waits for its round-trip time plus four times its variance
+        rtt.observe(&id, Duration::from_millis(500));
+        retransmitter.on_send(&outbound(&id, Nonce::FIRST), start);
+        assert_eq!(
+            retransmitter.next_deadline(),
+            Some(start + Duration::from_millis(1500))
+        );
+
+        // up to the maximum timeout
+        let slow = ConnectionId::generate();
+        rtt.observe(&slow, Duration::from_secs(20));
+        retransmitter.on_ack(&AckMessage {
+            id,
+            nonce: Nonce::FIRST,
+        });
+        retransmitter.on_send(&outbound(&slow, Nonce::FIRST), start);
+        assert_eq!(
+            retransmitter.next_deadline(),
+            Some(start + Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn test_datagrams_not_retransmitted() {
+        let mut retransmitter =
+            Retransmitter::new(RetransmitConfig::default(), RttTable::default());
+        let id = ConnectionId::generate();
+        let mut datagram = outbound(&id, DATAGRAM_NONCE);
+        if let Message::TransportMessage(msg) = &mut datagram.message {
+            msg.message = SubstreamMessage::new_datagram(Bytes::from_static(&[1, 2, 3]));
+        }
+        retransmitter.on_send(&datagram, Instant::now());
+        assert_eq!(retransmitter.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_resumption_discards_unacked() {
+        let mut retransmitter =
+            Retransmitter::new(RetransmitConfig::default(), RttTable::default());
+        let id = ConnectionId::generate();
+        let other = ConnectionId::generate();
+        let start = Instant::now();
+        retransmitter.on_send(&outbound(&id, Nonce::FIRST), start);
+        retransmitter.on_send(&outbound(&other, Nonce::FIRST), start);
+
+        // the remote lost the session these were sent in, so they're never retransmitted
+        retransmitter.on_send(
+            &OutboundMessage {
+                message: Message::ResumeRequest(ResumeRequestMessage {
+                    id: id.clone(),
+                    timestamp: 0,
+                    nonce: [0u8; 32],
+                    proof: [0u8; 32],
+                    ticket: vec![],
+                }),
+                recipient: None,
+                sender_tag: None,
+            },
+            start,
+        );
+        let (due, failed) = retransmitter.poll_due(start + Duration::from_secs(60));
+        assert!(failed.is_empty());
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].message.connection_id(), &other);
+    }
+}
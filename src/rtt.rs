@@ -0,0 +1,136 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::message::ConnectionId;
+
+/// weight of a new sample in the smoothed round-trip time (1/8, as in TCP).
+const SMOOTHING_FACTOR: f64 = 0.125;
+
+/// weight of a new sample's deviation in the round-trip time variance (1/4, as in TCP).
+const VARIANCE_FACTOR: f64 = 0.25;
+
+/// RttEstimator smooths the round-trip times measured on a connection with an
+/// exponentially weighted moving average, the way TCP does (RFC 6298).
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RttEstimator {
+    smoothed: Option<Duration>,
+    variance: Duration,
+}
+
+impl RttEstimator {
+    /// observe adds a round-trip time sample to the estimate.
+    pub(crate) fn observe(&mut self, sample: Duration) {
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(sample);
+                self.variance = sample / 2;
+            }
+            Some(smoothed) => {
+                let deviation = smoothed.abs_diff(sample);
+                self.variance = self.variance.mul_f64(1.0 - VARIANCE_FACTOR)
+                    + deviation.mul_f64(VARIANCE_FACTOR);
+                self.smoothed = Some(
+                    smoothed.mul_f64(1.0 - SMOOTHING_FACTOR) + sample.mul_f64(SMOOTHING_FACTOR),
+                );
+            }
+        }
+    }
+
+    /// smoothed returns the smoothed round-trip time, once there's been a sample.
+    pub(crate) fn smoothed(&self) -> Option<Duration> {
+        self.smoothed
+    }
+
+    /// retransmit_timeout returns how long to wait for an acknowledgement before
+    /// assuming a message was lost, once there's been a sample.
+    pub(crate) fn retransmit_timeout(&self) -> Option<Duration> {
+        self.smoothed.map(|smoothed| smoothed + self.variance * 4)
+    }
+}
+
+/// RttTable holds the round-trip time estimate of every connection. It's shared by the
+/// connections, which measure their round-trip times with keepalives, and the mixnet
+/// task, which times retransmissions by them.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RttTable(Arc<Mutex<HashMap<ConnectionId, RttEstimator>>>);
+
+impl RttTable {
+    /// observe adds a sample to the connection's estimate, and returns its smoothed
+    /// round-trip time.
+    pub(crate) fn observe(&self, id: &ConnectionId, sample: Duration) -> Duration {
+        let mut estimates = self.0.lock();
+        let estimate = estimates.entry(id.clone()).or_default();
+        estimate.observe(sample);
+        estimate.smoothed().unwrap_or(sample)
+    }
+
+    /// retransmit_timeout returns the connection's retransmission timeout, if its
+    /// round-trip time has been measured.
+    pub(crate) fn retransmit_timeout(&self, id: &ConnectionId) -> Option<Duration> {
+        self.0
+            .lock()
+            .get(id)
+            .and_then(RttEstimator::retransmit_timeout)
+    }
+
+    /// smoothed returns the connection's smoothed round-trip time, if it's been measured.
+    pub(crate) fn smoothed(&self, id: &ConnectionId) -> Option<Duration> {
+        self.0.lock().get(id).and_then(RttEstimator::smoothed)
+    }
+
+    /// remove forgets the estimate of a closed connection.
+    pub(crate) fn remove(&self, id: &ConnectionId) {
+        self.0.lock().remove(id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rtt_estimator() {
+        let mut estimator = RttEstimator::default();
+        assert_eq!(estimator.smoothed(), None);
+        assert_eq!(estimator.retransmit_timeout(), None);
+
+        // the first sample is taken as is, with half of it as the variance
+        estimator.observe(Duration::from_secs(2));
+        assert_eq!(estimator.smoothed(), Some(Duration::from_secs(2)));
+        assert_eq!(estimator.retransmit_timeout(), Some(Duration::from_secs(6)));
+
+        // later samples move the estimate by an eighth of the difference
+        estimator.observe(Duration::from_secs(10));
+        assert_eq!(estimator.smoothed(), Some(Duration::from_secs(3)));
+
+        // a steady round-trip time converges, and the variance shrinks
+        for _ in 0..200 {
+            estimator.observe(Duration::from_secs(1));
+        }
+        let smoothed = estimator.smoothed().unwrap();
+        assert!(smoothed.abs_diff(Duration::from_secs(1)) < Duration::from_millis(1));
+        let timeout = estimator.retransmit_timeout().unwrap();
+        assert!(timeout.abs_diff(Duration::from_secs(1)) < Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_rtt_table() {
+        let table = RttTable::default();
+        let id = ConnectionId::generate();
+        assert_eq!(table.retransmit_timeout(&id), None);
+
+        assert_eq!(
+            table.observe(&id, Duration::from_millis(800)),
+            Duration::from_millis(800)
+        );
+        assert_eq!(
+            table.retransmit_timeout(&id),
+            Some(Duration::from_millis(2400))
+        );
+
+        table.remove(&id);
+        assert_eq!(table.retransmit_timeout(&id), None);
+    }
+}
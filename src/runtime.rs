@@ -0,0 +1,159 @@
+//! The parts of the async runtime that differ between tokio, used by default, and
+//! async-std (the `async-std` feature). tokio's channels work on both, so they're used
+//! directly.
+
+#[cfg(not(feature = "async-std"))]
+pub(crate) use tokio::time::{
+    interval_at, sleep, sleep_until, timeout, Instant, Interval, MissedTickBehavior,
+};
+
+#[cfg(feature = "async-std")]
+pub(crate) use self::async_std_time::{
+    interval_at, sleep, sleep_until, timeout, Instant, Interval, MissedTickBehavior,
+};
+
+use std::future::Future;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// unix_millis returns the wall-clock time in milliseconds since the UNIX epoch, which,
+/// unlike an [`Instant`], means the same to a remote peer as it does to us.
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// TaskHandle is a handle to a task started by [`spawn`], which can be waited on.
+/// Dropping it detaches the task.
+pub(crate) struct TaskHandle {
+    #[cfg(not(feature = "async-std"))]
+    inner: tokio::task::JoinHandle<()>,
+    #[cfg(feature = "async-std")]
+    inner: async_std::task::JoinHandle<()>,
+}
+
+impl TaskHandle {
+    /// join waits for the task to finish. It returns an error if the task panicked
+    /// or was cancelled. With async-std, a panic in the task is propagated instead.
+    #[cfg(not(feature = "async-std"))]
+    pub(crate) async fn join(self) -> Result<(), ()> {
+        self.inner.await.map_err(|_| ())
+    }
+
+    /// join waits for the task to finish. It returns an error if the task panicked
+    /// or was cancelled. With async-std, a panic in the task is propagated instead.
+    #[cfg(feature = "async-std")]
+    pub(crate) async fn join(self) -> Result<(), ()> {
+        self.inner.await;
+        Ok(())
+    }
+}
+
+/// spawn runs `future` in the background on the tokio runtime.
+#[cfg(not(feature = "async-std"))]
+pub(crate) fn spawn<F>(future: F) -> TaskHandle
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    TaskHandle {
+        inner: tokio::task::spawn(future),
+    }
+}
+
+/// spawn runs `future` in the background on the async-std executor.
+#[cfg(feature = "async-std")]
+pub(crate) fn spawn<F>(future: F) -> TaskHandle
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    TaskHandle {
+        inner: async_std::task::spawn(future),
+    }
+}
+
+/// spawn_blocking runs `f`, which blocks, e.g. on file I/O, on a thread set aside for
+/// blocking work, so that it doesn't hold up the tasks on the runtime's workers.
+#[cfg(not(feature = "async-std"))]
+pub(crate) fn spawn_blocking<F>(f: F) -> TaskHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    TaskHandle {
+        inner: tokio::task::spawn_blocking(f),
+    }
+}
+
+/// spawn_blocking runs `f`, which blocks, e.g. on file I/O, on async-std's blocking
+/// thread pool, so that it doesn't hold up the executor.
+#[cfg(feature = "async-std")]
+pub(crate) fn spawn_blocking<F>(f: F) -> TaskHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    TaskHandle {
+        inner: async_std::task::spawn_blocking(f),
+    }
+}
+
+/// The timers tokio would provide, built on async-io, the reactor async-std runs on.
+#[cfg(feature = "async-std")]
+mod async_std_time {
+    use async_io::Timer;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    pub(crate) use async_std::future::timeout;
+    pub(crate) use async_std::task::sleep;
+    pub(crate) use std::time::Instant;
+
+    /// sleep_until waits until `deadline`.
+    pub(crate) async fn sleep_until(deadline: Instant) {
+        Timer::at(deadline).await;
+    }
+
+    /// MissedTickBehavior is what an [`Interval`] does when it's polled late. Only
+    /// delaying the next tick by a whole period from the late one is supported,
+    /// which is all the transport uses.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub(crate) enum MissedTickBehavior {
+        Delay,
+    }
+
+    /// Interval ticks every period, starting at a given instant, like tokio's.
+    pub(crate) struct Interval {
+        timer: Timer,
+        period: Duration,
+    }
+
+    /// interval_at returns an interval which first ticks at `start`.
+    pub(crate) fn interval_at(start: Instant, period: Duration) -> Interval {
+        Interval {
+            timer: Timer::at(start),
+            period,
+        }
+    }
+
+    impl Interval {
+        pub(crate) fn set_missed_tick_behavior(&mut self, _behavior: MissedTickBehavior) {}
+
+        /// poll_tick resolves once the next tick is due, scheduling the one after
+        /// a period later.
+        pub(crate) fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<Instant> {
+            match Pin::new(&mut self.timer).poll(cx) {
+                Poll::Ready(_) => {
+                    let now = Instant::now();
+                    self.timer.set_at(now + self.period);
+                    // register the waker for the next tick, which callers that stop
+                    // polling after a tick would otherwise never be woken for
+                    if self.period > Duration::ZERO {
+                        let _ = Pin::new(&mut self.timer).poll(cx);
+                    }
+                    Poll::Ready(now)
+                }
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+}
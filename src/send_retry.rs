@@ -0,0 +1,210 @@
+use futures::future::{BoxFuture, FutureExt};
+use nym_sdk::mixnet::{AnonymousSenderTag, IncludedSurbs};
+use nym_sphinx::addressing::clients::Recipient;
+use std::future::Future;
+use tracing::debug;
+
+use super::backend::MixnetBackendSender;
+use super::config::SendRetryConfig;
+use super::error::Error;
+use super::runtime::sleep;
+
+/// RetryingSender is a [`MixnetBackendSender`] which retries writes that fail with a
+/// transient error, with jittered exponential backoff, up to the configured number of
+/// attempts. Permanent errors are returned at once.
+pub(crate) struct RetryingSender {
+    inner: Box<dyn MixnetBackendSender>,
+    config: SendRetryConfig,
+}
+
+impl RetryingSender {
+    pub(crate) fn new(inner: Box<dyn MixnetBackendSender>, config: SendRetryConfig) -> Self {
+        RetryingSender { inner, config }
+    }
+
+    async fn retry<'a, F, Fut>(&'a self, mut write: F) -> Result<(), Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(), Error>> + 'a,
+    {
+        let mut attempt = 1;
+        loop {
+            let err = match write().await {
+                Ok(()) => return Ok(()),
+                Err(e) if !e.is_transient_send_failure() => return Err(e),
+                Err(e) => e,
+            };
+            if attempt >= self.config.max_attempts {
+                return Err(Error::SendRetriesExhausted {
+                    attempts: attempt,
+                    source: Box::new(err),
+                });
+            }
+            let backoff = self.config.backoff(attempt);
+            debug!(
+                "write to the mixnet failed ({}); retrying in {:?}",
+                err, backoff
+            );
+            sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+}
+
+impl MixnetBackendSender for RetryingSender {
+    fn send<'a>(
+        &'a self,
+        recipient: Recipient,
+        message: &'a [u8],
+        surbs: IncludedSurbs,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.retry(move || self.inner.send(recipient, message, surbs.clone()))
+            .boxed()
+    }
+
+    fn send_reply<'a>(
+        &'a self,
+        sender_tag: AnonymousSenderTag,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.retry(move || self.inner.send_reply(sender_tag, message))
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Flaky is a sender which counts its writes, and fails all but every `succeed_on`th
+    /// of them with a transient error. With `succeed_on` 0, every write fails.
+    struct Flaky {
+        writes: Arc<AtomicU32>,
+        succeed_on: u32,
+    }
+
+    impl Flaky {
+        fn write(&self) -> Result<(), Error> {
+            let write = self.writes.fetch_add(1, Ordering::SeqCst) + 1;
+            if self.succeed_on != 0 && write % self.succeed_on == 0 {
+                return Ok(());
+            }
+            let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+            Err(Error::GatewayUnreachable(nym_sdk::Error::IoError(io)))
+        }
+    }
+
+    impl MixnetBackendSender for Flaky {
+        fn send<'a>(
+            &'a self,
+            _recipient: Recipient,
+            _message: &'a [u8],
+            _surbs: IncludedSurbs,
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            let res = self.write();
+            async move { res }.boxed()
+        }
+
+        fn send_reply<'a>(
+            &'a self,
+            _sender_tag: AnonymousSenderTag,
+            _message: &'a [u8],
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            let res = self.write();
+            async move { res }.boxed()
+        }
+    }
+
+    fn config(max_attempts: u32) -> SendRetryConfig {
+        SendRetryConfig::default()
+            .with_max_attempts(max_attempts)
+            .with_backoff(Duration::from_millis(1), Duration::from_millis(4))
+    }
+
+    /// Unroutable is a sender which counts its writes, and fails them with a permanent error.
+    struct Unroutable(Arc<AtomicU32>);
+
+    impl MixnetBackendSender for Unroutable {
+        fn send<'a>(
+            &'a self,
+            _recipient: Recipient,
+            _message: &'a [u8],
+            _surbs: IncludedSurbs,
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            async { Err(Error::OutboundSendFailure("unroutable".to_string())) }.boxed()
+        }
+
+        fn send_reply<'a>(
+            &'a self,
+            _sender_tag: AnonymousSenderTag,
+            _message: &'a [u8],
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            async { Err(Error::OutboundSendFailure("unroutable".to_string())) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_permanent_send_failure_not_retried() {
+        let writes = Arc::new(AtomicU32::new(0));
+        let sender = RetryingSender::new(
+            Box::new(Unroutable(writes.clone())),
+            SendRetryConfig::default(),
+        );
+        let sender_tag = AnonymousSenderTag::new_random(&mut rand::thread_rng());
+        let res = sender.send_reply(sender_tag, b"hello").await;
+        assert!(matches!(res, Err(Error::OutboundSendFailure(_))));
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_transient_send_failure_retried() {
+        let writes = Arc::new(AtomicU32::new(0));
+        let sender = RetryingSender::new(
+            Box::new(Flaky {
+                writes: writes.clone(),
+                succeed_on: 4,
+            }),
+            config(4),
+        );
+        let sender_tag = AnonymousSenderTag::new_random(&mut rand::thread_rng());
+        sender.send_reply(sender_tag, b"hello").await.unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_send_retries_exhausted() {
+        let writes = Arc::new(AtomicU32::new(0));
+        let sender = RetryingSender::new(
+            Box::new(Flaky {
+                writes: writes.clone(),
+                succeed_on: 0,
+            }),
+            config(3),
+        );
+        let sender_tag = AnonymousSenderTag::new_random(&mut rand::thread_rng());
+        let res = sender.send_reply(sender_tag, b"hello").await;
+        let Err(Error::SendRetriesExhausted { attempts, source }) = res else {
+            panic!("expected Error::SendRetriesExhausted");
+        };
+        assert_eq!(attempts, 3);
+        assert!(matches!(*source, Error::GatewayUnreachable(_)));
+        assert_eq!(writes.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_transient_send_failure() {
+        let io = |kind: std::io::ErrorKind| {
+            Error::GatewayUnreachable(nym_sdk::Error::IoError(kind.into()))
+        };
+        assert!(io(std::io::ErrorKind::TimedOut).is_transient_send_failure());
+        assert!(!io(std::io::ErrorKind::PermissionDenied).is_transient_send_failure());
+        assert!(!Error::MixnetClientDisconnected.is_transient_send_failure());
+        let sender_tag = AnonymousSenderTag::new_random(&mut rand::thread_rng());
+        assert!(!Error::SurbExhausted(sender_tag).is_transient_send_failure());
+    }
+}
@@ -0,0 +1,496 @@
+use bytes::Bytes;
+use futures::{future::BoxFuture, pin_mut, select, FutureExt};
+use libp2p_identity::Keypair;
+use log::debug;
+use nym_sdk::mixnet::{AnonymousSenderTag, IncludedSurbs};
+use nym_sphinx::addressing::clients::Recipient;
+use nym_sphinx::receiver::ReconstructedMessage;
+use parking_lot::Mutex;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+
+use super::backend::{MixnetBackend, MixnetBackendSender};
+use super::codec::{Codec, WireCodec};
+use super::config::NymTransportConfig;
+use super::error::Error;
+use super::message::{ConnectionId, Message};
+use super::runtime::{spawn, TaskHandle};
+use super::transport::NymTransport;
+
+/// Routes is where the messages the shared client receives are handed, shared by the
+/// routing task and the senders of the factory's backends.
+#[derive(Default)]
+struct Routes {
+    /// service -> channel of the messages received for the service's transport
+    services: HashMap<u16, UnboundedSender<ReconstructedMessage>>,
+    /// connection -> service of the transport which has sent on it
+    connections: HashMap<ConnectionId, u16>,
+}
+
+impl Routes {
+    /// service_of returns the service an inbound message is for. A connection request
+    /// names the service it's dialing, and every other message belongs to a
+    /// connection one of the transports has sent on.
+    fn service_of(&self, message: &Message) -> Option<u16> {
+        match message {
+            Message::ConnectionRequest(request) => {
+                let service = request.service?;
+                // a request can't take over a connection of another service
+                match self.connections.get(&request.id) {
+                    Some(&existing) if existing != service => None,
+                    _ => Some(service),
+                }
+            }
+            message => self.connections.get(message.connection_id()).copied(),
+        }
+    }
+
+    /// unbind removes `service` and the connections routed to it.
+    fn unbind(&mut self, service: u16) {
+        self.services.remove(&service);
+        self.connections.retain(|_, s| *s != service);
+    }
+}
+
+type SharedRoutes = Arc<Mutex<Routes>>;
+
+/// SharedNymTransportFactory lets several [`NymTransport`]s share one mixnet client,
+/// and so one nym address, e.g. so that independent swarms running different
+/// protocols can run over the same nym identity. Each transport is bound to a service,
+/// and is dialed at `/nym/<address>/svc/<service>`, which it has to listen on.
+///
+/// Messages are sent as they are by any other transport, so the shared transports talk
+/// to peers with a client of their own, and the other way round. The client routes a
+/// connection request to the transport bound to the service it names, and every other
+/// message to the transport which has sent on its connection, until the connection is
+/// closed. Messages nothing is bound to are dropped, including connection requests
+/// without a service, and the datagrams and relay requests of connections no transport
+/// has sent on.
+///
+/// The client is disconnected by [`SharedNymTransportFactory::disconnect`], or once the
+/// factory is dropped, after which its transports receive nothing, so it has to be
+/// kept for as long as they're used.
+pub struct SharedNymTransportFactory {
+    address: Recipient,
+    sender: Arc<dyn MixnetBackendSender>,
+    routes: SharedRoutes,
+    shutdown_tx: oneshot::Sender<()>,
+    handle: TaskHandle,
+}
+
+impl SharedNymTransportFactory {
+    /// New factory whose transports share `client`, which may be a nym-sdk
+    /// [`MixnetClient`](nym_sdk::mixnet::MixnetClient) or any other backend.
+    pub fn new(client: impl MixnetBackend) -> Self {
+        let address = client.our_address();
+        let sender = Arc::from(client.sender());
+        let routes = SharedRoutes::default();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let handle = spawn(route(client, routes.clone(), shutdown_rx));
+        SharedNymTransportFactory {
+            address,
+            sender,
+            routes,
+            shutdown_tx,
+            handle,
+        }
+    }
+
+    /// address returns the nym address all of the factory's transports share.
+    pub fn address(&self) -> Recipient {
+        self.address
+    }
+
+    /// backend returns a backend which sends and receives the messages of `service`
+    /// through the shared client. It fails with [`Error::ServiceListenerExists`] if the
+    /// service is already bound to another backend which hasn't been disconnected.
+    pub fn backend(&self, service: u16) -> Result<SharedBackend, Error> {
+        let (tx, rx) = unbounded_channel();
+        let mut routes = self.routes.lock();
+        if routes
+            .services
+            .get(&service)
+            .is_some_and(|tx| !tx.is_closed())
+        {
+            return Err(Error::ServiceListenerExists(service));
+        }
+        // the connections of a backend which went away without disconnecting
+        routes.unbind(service);
+        routes.services.insert(service, tx);
+        Ok(SharedBackend {
+            service,
+            address: self.address,
+            sender: self.sender.clone(),
+            routes: self.routes.clone(),
+            rx,
+        })
+    }
+
+    /// transport returns a transport with `keypair` and `config` which is bound to
+    /// `service` (see [`SharedNymTransportFactory::backend`]).
+    pub async fn transport(
+        &self,
+        service: u16,
+        keypair: Keypair,
+        config: NymTransportConfig,
+    ) -> Result<NymTransport, Error> {
+        NymTransport::new_with_backend(self.backend(service)?, keypair, config).await
+    }
+
+    /// disconnect disconnects the shared client, and waits for it to be disconnected.
+    /// Transports still bound to a service see their client disconnect.
+    pub async fn disconnect(self) -> Result<(), Error> {
+        // the task has already exited if the client disconnected by itself
+        self.shutdown_tx.send(()).ok();
+        self.handle
+            .join()
+            .await
+            .map_err(|_| Error::MixnetTaskFailure)
+    }
+}
+
+/// route hands every message `client` receives to the service it's for, until the
+/// client disconnects or the factory shuts it down.
+async fn route(
+    mut client: impl MixnetBackend,
+    routes: SharedRoutes,
+    shutdown_rx: oneshot::Receiver<()>,
+) {
+    let mut shutdown_rx = shutdown_rx.fuse();
+    loop {
+        let next = client.next().fuse();
+        pin_mut!(next);
+        let message = select! {
+            message = next => message,
+            // either an explicit disconnect, or the factory was dropped
+            _ = shutdown_rx => None,
+        };
+        let Some(message) = message else {
+            break;
+        };
+
+        let decoded = match WireCodec::decode(Bytes::copy_from_slice(&message.message)) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                debug!("dropping message which failed to decode: {}", e);
+                continue;
+            }
+        };
+        let mut table = routes.lock();
+        let Some(service) = table.service_of(&decoded) else {
+            debug!(
+                "dropping {} for no service on {:?}",
+                decoded.kind(),
+                decoded.connection_id()
+            );
+            continue;
+        };
+        let Some(tx) = table.services.get(&service).cloned() else {
+            debug!("dropping message for unbound service {}", service);
+            continue;
+        };
+        if let Message::ConnectionClose(close) = &decoded {
+            table.connections.remove(&close.id);
+        }
+        drop(table);
+        // the service's transport may have shut down since
+        tx.send(message).ok();
+    }
+
+    // every bound transport's backend now returns None, as a disconnected client's would
+    *routes.lock() = Routes::default();
+    Box::new(client).disconnect().await;
+}
+
+/// SharedBackend is the [`MixnetBackend`] of a single service of a
+/// [`SharedNymTransportFactory`].
+pub struct SharedBackend {
+    service: u16,
+    address: Recipient,
+    sender: Arc<dyn MixnetBackendSender>,
+    routes: SharedRoutes,
+    rx: UnboundedReceiver<ReconstructedMessage>,
+}
+
+impl MixnetBackend for SharedBackend {
+    fn our_address(&self) -> Recipient {
+        self.address
+    }
+
+    fn sender(&self) -> Box<dyn MixnetBackendSender> {
+        Box::new(SharedSender {
+            service: self.service,
+            sender: self.sender.clone(),
+            routes: self.routes.clone(),
+        })
+    }
+
+    fn next(&mut self) -> BoxFuture<'_, Option<ReconstructedMessage>> {
+        self.rx.recv().boxed()
+    }
+
+    /// disconnect frees the service for another backend, leaving the shared client
+    /// connected.
+    fn disconnect(self: Box<Self>) -> BoxFuture<'static, ()> {
+        let SharedBackend {
+            service,
+            routes,
+            rx,
+            ..
+        } = *self;
+        drop(rx);
+        // unless the service has been bound again since the client disconnected
+        let mut routes = routes.lock();
+        if routes
+            .services
+            .get(&service)
+            .is_some_and(|tx| tx.is_closed())
+        {
+            routes.unbind(service);
+        }
+        async {}.boxed()
+    }
+}
+
+/// SharedSender writes the messages of a single service to the shared client, routing
+/// the messages received on their connections to the service until they're closed.
+struct SharedSender {
+    service: u16,
+    sender: Arc<dyn MixnetBackendSender>,
+    routes: SharedRoutes,
+}
+
+impl SharedSender {
+    fn route(&self, message: &[u8]) {
+        let Ok(message) = WireCodec::decode(Bytes::copy_from_slice(message)) else {
+            return;
+        };
+        let mut routes = self.routes.lock();
+        match message {
+            Message::ConnectionClose(close) => {
+                routes.connections.remove(&close.id);
+            }
+            message => {
+                routes
+                    .connections
+                    .insert(message.connection_id().clone(), self.service);
+            }
+        }
+    }
+}
+
+impl MixnetBackendSender for SharedSender {
+    fn send<'a>(
+        &'a self,
+        recipient: Recipient,
+        message: &'a [u8],
+        surbs: IncludedSurbs,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.route(message);
+        self.sender.send(recipient, message, surbs)
+    }
+
+    fn send_reply<'a>(
+        &'a self,
+        sender_tag: AnonymousSenderTag,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.route(message);
+        self.sender.send_reply(sender_tag, message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::address::NymMultiaddr;
+    use super::super::memory::{InMemoryConfig, InMemoryMixnet};
+    use super::super::message::{
+        ConnectionCloseMessage, ConnectionMessage, KeepAliveMessage, KeepAliveType,
+    };
+    use super::super::presets;
+    use super::super::runtime::timeout;
+    use super::super::testing::{connection_request, swarm, swarm_with_transport};
+    use super::*;
+    use futures::StreamExt;
+    use libp2p::core::Multiaddr;
+    use libp2p::swarm::SwarmEvent;
+    use libp2p::{ping, Swarm};
+    use std::time::Duration;
+
+    fn request(id: &ConnectionId, service: Option<u16>) -> Bytes {
+        let request = connection_request(&Keypair::generate_ed25519(), id);
+        WireCodec::encode(&Message::ConnectionRequest(ConnectionMessage {
+            service,
+            ..request
+        }))
+    }
+
+    fn keepalive(id: &ConnectionId) -> Bytes {
+        WireCodec::encode(&Message::KeepAlive(KeepAliveMessage {
+            id: id.clone(),
+            keepalive_type: KeepAliveType::Ping,
+            seq: 1,
+            timestamp: None,
+        }))
+    }
+
+    fn close(id: &ConnectionId) -> Bytes {
+        WireCodec::encode(&Message::ConnectionClose(ConnectionCloseMessage {
+            id: id.clone(),
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_shared_backend_routes_by_service() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let mut remote = mixnet.client();
+        let factory = SharedNymTransportFactory::new(mixnet.client());
+        let mut backend = factory.backend(7).unwrap();
+        let mut other = factory.backend(8).unwrap();
+        assert_eq!(backend.our_address(), factory.address());
+
+        // a connection request reaches the service it names, unchanged, and requests
+        // without a service, or for one nothing is bound to, are dropped
+        let sender = remote.sender();
+        let surbs = || IncludedSurbs::ExposeSelfAddress;
+        let address = factory.address();
+        let (id, dialed) = (ConnectionId::generate(), ConnectionId::generate());
+        let accepted = request(&id, Some(7));
+        sender
+            .send(address, &request(&id, None), surbs())
+            .await
+            .unwrap();
+        sender
+            .send(address, &request(&id, Some(9)), surbs())
+            .await
+            .unwrap();
+        sender.send(address, &accepted, surbs()).await.unwrap();
+        assert_eq!(backend.next().await.unwrap().message, accepted);
+
+        // the rest of a connection is routed once the service has sent on it, and goes
+        // out unchanged
+        sender
+            .send(address, &keepalive(&id), surbs())
+            .await
+            .unwrap();
+        let reply = backend.sender();
+        reply
+            .send(remote.our_address(), &keepalive(&id), surbs())
+            .await
+            .unwrap();
+        assert_eq!(remote.next().await.unwrap().message, keepalive(&id));
+        sender
+            .send(address, &keepalive(&id), surbs())
+            .await
+            .unwrap();
+        assert_eq!(backend.next().await.unwrap().message, keepalive(&id));
+
+        // as are the replies to a service's own dials, and a request naming another
+        // service doesn't take over a connection
+        other
+            .sender()
+            .send(remote.our_address(), &request(&dialed, None), surbs())
+            .await
+            .unwrap();
+        remote.next().await.unwrap();
+        sender
+            .send(address, &request(&id, Some(8)), surbs())
+            .await
+            .unwrap();
+        sender
+            .send(address, &keepalive(&dialed), surbs())
+            .await
+            .unwrap();
+        assert_eq!(other.next().await.unwrap().message, keepalive(&dialed));
+
+        // a closed connection is no longer routed
+        sender.send(address, &close(&id), surbs()).await.unwrap();
+        assert_eq!(backend.next().await.unwrap().message, close(&id));
+        sender
+            .send(address, &keepalive(&id), surbs())
+            .await
+            .unwrap();
+        let next = timeout(Duration::from_millis(100), backend.next()).await;
+        assert!(next.is_err());
+
+        // a service is bound to one backend at a time
+        assert!(matches!(
+            factory.backend(7),
+            Err(Error::ServiceListenerExists(7))
+        ));
+        Box::new(backend).disconnect().await;
+        let mut backend = factory.backend(7).unwrap();
+
+        // which stops receiving once the shared client is disconnected
+        factory.disconnect().await.unwrap();
+        let next = timeout(Duration::from_secs(1), backend.next()).await;
+        assert!(matches!(next, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn test_shared_client_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let dialers = SharedNymTransportFactory::new(mixnet.client());
+        let listeners = SharedNymTransportFactory::new(mixnet.client());
+
+        fn behaviour(_: &Keypair) -> ping::Behaviour {
+            ping::Behaviour::new(presets::ping_config_for_nym())
+        }
+        async fn shared_swarm(
+            factory: &SharedNymTransportFactory,
+            service: u16,
+        ) -> (Swarm<ping::Behaviour>, Multiaddr) {
+            let keypair = Keypair::generate_ed25519();
+            let transport = factory
+                .transport(service, keypair.clone(), NymTransportConfig::default())
+                .await
+                .unwrap();
+            let addr = NymMultiaddr::new(factory.address())
+                .with_service(service)
+                .to_multiaddr()
+                .unwrap();
+            let mut swarm = swarm_with_transport(keypair, transport, behaviour);
+            swarm.listen_on(addr.clone()).unwrap();
+            (swarm, addr)
+        }
+
+        // the listening side runs two swarms, with their own peer IDs, over one nym
+        // address, each listening on a service of its own
+        let (mut dialer1, _) = shared_swarm(&dialers, 1).await;
+        let (mut listener1, addr1) = shared_swarm(&listeners, 1).await;
+        let (mut listener2, addr2) = shared_swarm(&listeners, 2).await;
+        assert_ne!(addr1, addr2);
+        assert!(matches!(
+            listeners.backend(1),
+            Err(Error::ServiceListenerExists(1))
+        ));
+
+        // and each dial reaches the swarm on the service dialed, whether the dialer
+        // shares its client or has one of its own
+        let (mut dialer2, _) = swarm(&mixnet, behaviour).await;
+        dialer1.dial(addr1).unwrap();
+        dialer2.dial(addr2).unwrap();
+        let (mut peer1, mut peer2) = (None, None);
+        while peer1.is_none() || peer2.is_none() {
+            tokio::select! {
+                event = dialer1.select_next_some() => {
+                    if let SwarmEvent::ConnectionEstablished { peer_id, .. } = event {
+                        peer1 = Some(peer_id);
+                    }
+                }
+                event = dialer2.select_next_some() => {
+                    if let SwarmEvent::ConnectionEstablished { peer_id, .. } = event {
+                        peer2 = Some(peer_id);
+                    }
+                }
+                _ = listener1.select_next_some() => {}
+                _ = listener2.select_next_some() => {}
+            }
+        }
+        assert_eq!(peer1, Some(*listener1.local_peer_id()));
+        assert_eq!(peer2, Some(*listener2.local_peer_id()));
+    }
+}
@@ -0,0 +1,179 @@
+//! A point-in-time description of a transport's state, returned by
+//! [`NymTransport::debug_snapshot`](crate::transport::NymTransport::debug_snapshot)
+//! for debugging connections that hang. With the `serde` feature, it can be serialized,
+//! e.g. to be dumped as JSON.
+
+use std::time::Duration;
+
+use super::state::StateKind;
+
+/// TransportSnapshot describes a transport's connections and queues.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TransportSnapshot {
+    /// the PeerId we authenticate as.
+    pub local_peer_id: String,
+    /// our nym address, as a `/nym/<address>` multiaddr.
+    pub listen_addr: String,
+    /// every connection that isn't closed, including pending dials.
+    pub connections: Vec<ConnectionSnapshot>,
+    /// number of dials waiting for the remote's response.
+    pub pending_dials: usize,
+    /// number of accepted connections the swarm hasn't taken yet.
+    pub pending_inbound: usize,
+    /// messages read from the mixnet that the transport hasn't handled yet.
+    pub inbound_queue_len: usize,
+    /// messages waiting to be written to the mixnet by the main client, across
+    /// all connections.
+    pub outbound_queue_len: usize,
+    /// the estimated number of our SURBs held by each peer we've sent them to,
+    /// through any of our mixnet clients.
+    pub surbs: Vec<SurbSnapshot>,
+    /// number of peers in the address book.
+    pub known_addresses: usize,
+    /// the main mixnet client's connection to the mixnet.
+    pub mixnet: MixnetSnapshot,
+}
+
+/// ConnectionSnapshot describes a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConnectionSnapshot {
+    /// the connection ID, hex-encoded.
+    pub id: String,
+    pub status: ConnectionStatus,
+    /// the remote's PeerId; None for a dial to an address without one.
+    pub peer_id: Option<String>,
+    /// the remote's nym address; None if it's only reachable through its SURBs.
+    pub remote_address: Option<String>,
+    /// number of open substreams; 0 for a pending dial.
+    pub open_substreams: usize,
+    /// nonce of the next inbound message the connection expects.
+    pub next_expected_nonce: u64,
+    /// inbound messages queued behind a missing one.
+    pub queued_inbound: usize,
+    /// outbound messages waiting to be written to the mixnet; 0 for a pending dial,
+    /// whose request is counted in the transport's outbound queue.
+    pub queued_outbound: usize,
+    /// the smoothed mixnet round-trip time, once keepalives have measured it.
+    pub smoothed_rtt: Option<Duration>,
+}
+
+/// ConnectionStatus is where a connection is in its lifecycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ConnectionStatus {
+    /// we sent a ConnectionRequest, and are waiting for the remote's response.
+    PendingOutbound,
+    /// we accepted a ConnectionRequest, and the swarm hasn't taken the connection yet.
+    PendingInbound,
+    Established,
+    /// the connection has been closed, and is waiting for the swarm to drop it.
+    Closing,
+}
+
+impl From<StateKind> for ConnectionStatus {
+    fn from(kind: StateKind) -> Self {
+        match kind {
+            StateKind::PendingOutbound => ConnectionStatus::PendingOutbound,
+            StateKind::PendingInbound => ConnectionStatus::PendingInbound,
+            StateKind::Established => ConnectionStatus::Established,
+            // closed connections aren't in the table, so they're never snapshotted
+            StateKind::Closing | StateKind::Closed => ConnectionStatus::Closing,
+        }
+    }
+}
+
+/// SurbSnapshot is the estimated number of our SURBs a peer holds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SurbSnapshot {
+    /// the peer's nym address.
+    pub recipient: String,
+    pub remaining: u64,
+}
+
+/// MixnetSnapshot describes the main mixnet client's connection to the mixnet, as
+/// returned by [`NymTransport::mixnet_status`](crate::transport::NymTransport::mixnet_status).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MixnetSnapshot {
+    /// the identity key (base58) of the gateway the client is connected to.
+    pub gateway: String,
+    /// the epoch of the network topology the client routes through; None until it's
+    /// first checked, or if topology checks are disabled.
+    pub topology_epoch: Option<u64>,
+    pub health: ClientHealth,
+}
+
+/// ClientHealth is the state of a mixnet client's connection to its gateway.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ClientHealth {
+    #[default]
+    Connected,
+    /// the client lost its gateway connection, and a replacement is being connected.
+    Reconnecting,
+    /// the client lost its gateway connection and won't be replaced, or the transport
+    /// was shut down.
+    Disconnected,
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::NymTransportConfig;
+    use super::super::memory::{InMemoryConfig, InMemoryMixnet};
+    use super::super::testing::{self, dial_opts, next_event};
+    use super::*;
+    use libp2p::core::{transport::TransportEvent, Transport};
+
+    #[tokio::test]
+    async fn test_debug_snapshot_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let mut dialer = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        let mut listener = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        let snapshot = dialer.debug_snapshot();
+        assert_eq!(snapshot.local_peer_id, dialer.peer_id().to_string());
+        assert!(snapshot.connections.is_empty());
+
+        let mut dial = dialer
+            .dial(listener.listen_addr().clone(), dial_opts())
+            .unwrap();
+        let snapshot = dialer.debug_snapshot();
+        assert_eq!(snapshot.pending_dials, 1);
+        assert_eq!(
+            snapshot.connections[0].status,
+            ConnectionStatus::PendingOutbound
+        );
+
+        let mut accepted = None;
+        let (_, dialed) = loop {
+            tokio::select! {
+                res = &mut dial => break res.unwrap(),
+                _ = next_event(&mut dialer) => {}
+                event = next_event(&mut listener) => {
+                    if let TransportEvent::Incoming { upgrade, .. } = event {
+                        accepted = Some(upgrade.await.unwrap());
+                    }
+                }
+            }
+        };
+
+        let snapshot = dialer.debug_snapshot();
+        assert_eq!(snapshot.pending_dials, 0);
+        let connection = &snapshot.connections[0];
+        assert_eq!(connection.status, ConnectionStatus::Established);
+        assert_eq!(connection.peer_id, Some(listener.peer_id().to_string()));
+        assert_eq!(
+            connection.remote_address,
+            Some(listener.local_nym_address().to_string())
+        );
+        // the dial sent our SURBs to the listener
+        assert_eq!(snapshot.surbs.len(), 1);
+
+        let snapshot = listener.debug_snapshot();
+        assert_eq!(snapshot.connections.len(), 1);
+        assert_eq!(snapshot.connections[0].remote_address, None);
+        drop((dialed, accepted));
+    }
+}
@@ -0,0 +1,553 @@
+use nym_sphinx::addressing::clients::Recipient;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Weak};
+use std::task::Context;
+
+use super::channel::BoundedSender;
+use super::connection::{ConnectionEvent, ConnectionHandle, PendingConnection};
+use super::error::Error;
+use super::message::{ConnectionId, OutboundMessage};
+
+/// number of abandoned dials remembered, so that the remote's end of one that's
+/// answered late can be closed; the oldest is forgotten first.
+const MAX_ABANDONED_DIALS: usize = 64;
+
+/// StateKind is the state of a connection tracked by the transport, without the
+/// data the transport holds for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum StateKind {
+    /// we sent a ConnectionRequest, and are waiting for the remote's response.
+    PendingOutbound,
+    /// we accepted a ConnectionRequest, and the swarm hasn't taken its upgrade yet.
+    PendingInbound,
+    Established,
+    /// the transport failed the connection, or the remote closed it, and the
+    /// Connection has been told; it's forgotten once the Connection is dropped.
+    Closing,
+    /// the connection is unknown to the transport: it never existed, or is gone.
+    Closed,
+}
+
+/// Event is something that happens to a connection, and moves it to another state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Event {
+    /// we sent a ConnectionRequest.
+    Dial,
+    /// we accepted a ConnectionRequest.
+    Accept,
+    /// the remote answered our ConnectionRequest.
+    Answer,
+    /// the dial failed, timed out or was cancelled.
+    Abort,
+    /// the swarm took the upgrade of an inbound connection.
+    Upgrade,
+    /// the transport failed the connection, or the remote closed it.
+    Close,
+    /// the Connection was dropped.
+    Dropped,
+}
+
+impl StateKind {
+    /// next returns the state a connection in this state moves to on `event`. This is
+    /// the transition table: any event not listed can't happen in that state.
+    pub(crate) fn next(self, event: Event) -> Result<StateKind, Error> {
+        use Event::*;
+        use StateKind::*;
+        match (self, event) {
+            (Closed, Dial) => Ok(PendingOutbound),
+            (Closed, Accept) => Ok(PendingInbound),
+            (PendingOutbound, Answer) => Ok(Established),
+            (PendingOutbound, Abort) => Ok(Closed),
+            (PendingInbound, Upgrade) => Ok(Established),
+            (PendingInbound | Established, Close) => Ok(Closing),
+            (PendingInbound | Established | Closing, Dropped) => Ok(Closed),
+            (state, event) => Err(Error::InvalidStateTransition(format!(
+                "{:?} on {:?}",
+                event, state
+            ))),
+        }
+    }
+}
+
+/// ConnectionState is the state of a connection, with what the transport holds for it.
+enum ConnectionState {
+    PendingOutbound(PendingConnection),
+    PendingInbound {
+        handle: ConnectionHandle,
+        /// dropped by the Upgrade once the swarm has taken the connection
+        upgrade: Weak<()>,
+    },
+    Established(ConnectionHandle),
+    Closing(ConnectionHandle),
+}
+
+impl ConnectionState {
+    fn kind(&self) -> StateKind {
+        match self {
+            ConnectionState::PendingOutbound(_) => StateKind::PendingOutbound,
+            ConnectionState::PendingInbound { .. } => StateKind::PendingInbound,
+            ConnectionState::Established(_) => StateKind::Established,
+            ConnectionState::Closing(_) => StateKind::Closing,
+        }
+    }
+
+    /// open_handle returns the handle of a connection that's accepted or established.
+    fn open_handle(&self) -> Option<&ConnectionHandle> {
+        match self {
+            ConnectionState::PendingInbound { handle, .. }
+            | ConnectionState::Established(handle) => Some(handle),
+            _ => None,
+        }
+    }
+}
+
+/// AnsweredDial is a dial the remote has answered, which has been taken out of the
+/// table while its handshake is finished. It's the only way to establish a dialed
+/// connection, with [`ConnectionTable::establish`]; dropping it aborts the dial.
+pub(crate) struct AnsweredDial {
+    id: ConnectionId,
+}
+
+/// AbandonedDial is where to send a ConnectionClose if a dial whose future was
+/// dropped, e.g. because it lost a race or timed out, is answered after all.
+pub(crate) struct AbandonedDial {
+    pub(crate) id: ConnectionId,
+    /// None if the dial was sent through a reply route, whose answer carries the sender tag
+    pub(crate) remote_recipient: Option<Recipient>,
+    pub(crate) outbound_tx: BoundedSender<OutboundMessage>,
+}
+
+/// ConnectionTable holds the state of every connection the transport knows of, and
+/// moves connections between states according to [`StateKind::next`].
+#[derive(Default)]
+pub(crate) struct ConnectionTable {
+    states: HashMap<ConnectionId, ConnectionState>,
+    /// dials whose future was dropped before they were answered, oldest first
+    abandoned: VecDeque<AbandonedDial>,
+}
+
+impl ConnectionTable {
+    /// kind returns the state of the connection `id`.
+    pub(crate) fn kind(&self, id: &ConnectionId) -> StateKind {
+        self.states
+            .get(id)
+            .map_or(StateKind::Closed, ConnectionState::kind)
+    }
+
+    /// transition checks that `event` can happen to the connection `id`.
+    fn transition(&self, id: &ConnectionId, event: Event) -> Result<StateKind, Error> {
+        self.kind(id).next(event)
+    }
+
+    /// contains returns true if the connection `id` isn't closed.
+    pub(crate) fn contains(&self, id: &ConnectionId) -> bool {
+        self.states.contains_key(id)
+    }
+
+    /// len returns the number of connections that aren't closed.
+    pub(crate) fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    /// handle returns the handle of the connection `id`, if it's accepted or established.
+    pub(crate) fn handle(&self, id: &ConnectionId) -> Option<&ConnectionHandle> {
+        self.states.get(id).and_then(ConnectionState::open_handle)
+    }
+
+    /// handles returns the handles of the connections that are accepted or established.
+    pub(crate) fn handles(&self) -> impl Iterator<Item = &ConnectionHandle> {
+        self.states
+            .values()
+            .filter_map(ConnectionState::open_handle)
+    }
+
+    /// pending_dials returns the dials waiting for the remote's response.
+    pub(crate) fn pending_dials(
+        &self,
+    ) -> impl Iterator<Item = (&ConnectionId, &PendingConnection)> {
+        self.states.iter().filter_map(|(id, state)| match state {
+            ConnectionState::PendingOutbound(pending) => Some((id, pending)),
+            _ => None,
+        })
+    }
+
+    /// entries returns every connection that isn't closed, with its state and either
+    /// its handle or, for a dial waiting for the remote's response, the pending dial.
+    pub(crate) fn entries(
+        &self,
+    ) -> impl Iterator<
+        Item = (
+            &ConnectionId,
+            StateKind,
+            Option<&ConnectionHandle>,
+            Option<&PendingConnection>,
+        ),
+    > {
+        self.states.iter().map(|(id, state)| match state {
+            ConnectionState::PendingOutbound(pending) => (id, state.kind(), None, Some(pending)),
+            ConnectionState::PendingInbound { handle, .. }
+            | ConnectionState::Established(handle)
+            | ConnectionState::Closing(handle) => (id, state.kind(), Some(handle), None),
+        })
+    }
+
+    /// pending_inbound returns the number of accepted connections the swarm hasn't taken yet.
+    pub(crate) fn pending_inbound(&self) -> usize {
+        self.states
+            .values()
+            .filter(|state| {
+                matches!(state, ConnectionState::PendingInbound { upgrade, .. } if upgrade.strong_count() > 0)
+            })
+            .count()
+    }
+
+    /// dial tracks a dial we've sent the ConnectionRequest of.
+    pub(crate) fn dial(
+        &mut self,
+        id: ConnectionId,
+        pending: PendingConnection,
+    ) -> Result<(), Error> {
+        self.transition(&id, Event::Dial)
+            .map_err(|_| Error::ConnectionIDExists)?;
+        self.states
+            .insert(id, ConnectionState::PendingOutbound(pending));
+        Ok(())
+    }
+
+    /// accept tracks a connection we've accepted, until `upgrade` is dropped.
+    pub(crate) fn accept(
+        &mut self,
+        id: ConnectionId,
+        handle: ConnectionHandle,
+        upgrade: &Arc<()>,
+    ) -> Result<(), Error> {
+        self.transition(&id, Event::Accept)
+            .map_err(|_| Error::ConnectionIDExists)?;
+        let upgrade = Arc::downgrade(upgrade);
+        self.states
+            .insert(id, ConnectionState::PendingInbound { handle, upgrade });
+        Ok(())
+    }
+
+    /// answer takes the dial `id` out of the table once the remote has answered it.
+    pub(crate) fn answer(
+        &mut self,
+        id: &ConnectionId,
+    ) -> Result<(AnsweredDial, PendingConnection), Error> {
+        self.transition(id, Event::Answer)?;
+        let Some(ConnectionState::PendingOutbound(pending)) = self.states.remove(id) else {
+            unreachable!("only a pending dial can be answered");
+        };
+        Ok((AnsweredDial { id: id.clone() }, pending))
+    }
+
+    /// establish tracks the connection of an answered dial.
+    pub(crate) fn establish(&mut self, dial: AnsweredDial, handle: ConnectionHandle) {
+        self.states
+            .insert(dial.id, ConnectionState::Established(handle));
+    }
+
+    /// abort forgets the dial `id`, returning it so that it can be failed.
+    pub(crate) fn abort(&mut self, id: &ConnectionId) -> Result<PendingConnection, Error> {
+        self.transition(id, Event::Abort)?;
+        let Some(ConnectionState::PendingOutbound(pending)) = self.states.remove(id) else {
+            unreachable!("only a pending dial can be aborted");
+        };
+        Ok(pending)
+    }
+
+    /// abandon aborts the dial `id` like [`ConnectionTable::abort`], but remembers it
+    /// like a dial whose future was dropped, in case the remote answers it late.
+    pub(crate) fn abandon(&mut self, id: &ConnectionId) -> Result<PendingConnection, Error> {
+        let pending = self.abort(id)?;
+        self.abandoned.push_back(AbandonedDial {
+            id: id.clone(),
+            remote_recipient: pending.remote_recipient,
+            outbound_tx: pending.outbound_tx.clone(),
+        });
+        while self.abandoned.len() > MAX_ABANDONED_DIALS {
+            self.abandoned.pop_front();
+        }
+        Ok(pending)
+    }
+
+    /// close moves the connection `id` to Closing, and delivers `event` (the reason
+    /// it's closed) to the Connection.
+    pub(crate) fn close(&mut self, id: &ConnectionId, event: ConnectionEvent) -> Result<(), Error> {
+        self.transition(id, Event::Close)?;
+        let state = self.states.get_mut(id).expect("connection is open");
+        let (ConnectionState::PendingInbound { handle, .. } | ConnectionState::Established(handle)) =
+            state
+        else {
+            unreachable!("only an open connection can be closed");
+        };
+        // the connection may already have been dropped
+        handle.inbound_tx.send(event).ok();
+        *state = ConnectionState::Closing(handle.clone());
+        Ok(())
+    }
+
+    /// poll moves connections whose Connection was dropped to Closed, and accepted
+    /// connections the swarm has taken to Established. It aborts dials whose dial
+    /// future was dropped (e.g. because it timed out), and returns the closed connections.
+    pub(crate) fn poll(&mut self, cx: &mut Context<'_>) -> Vec<ConnectionId> {
+        let mut closed = vec![];
+        for (id, state) in self.states.iter_mut() {
+            let event = match state {
+                ConnectionState::PendingOutbound(pending) => {
+                    if pending.connection_tx.poll_closed(cx).is_pending() {
+                        continue;
+                    }
+                    self.abandoned.push_back(AbandonedDial {
+                        id: id.clone(),
+                        remote_recipient: pending.remote_recipient,
+                        outbound_tx: pending.outbound_tx.clone(),
+                    });
+                    Event::Abort
+                }
+                ConnectionState::PendingInbound { handle, .. }
+                | ConnectionState::Established(handle)
+                | ConnectionState::Closing(handle)
+                    if handle.is_closed() =>
+                {
+                    Event::Dropped
+                }
+                ConnectionState::PendingInbound { upgrade, .. } if upgrade.strong_count() == 0 => {
+                    Event::Upgrade
+                }
+                _ => continue,
+            };
+
+            match state.kind().next(event) {
+                Ok(StateKind::Established) => {
+                    let ConnectionState::PendingInbound { handle, .. } = state else {
+                        unreachable!("only an accepted connection is upgraded");
+                    };
+                    *state = ConnectionState::Established(handle.clone());
+                }
+                Ok(StateKind::Closed) => closed.push(id.clone()),
+                res => unreachable!("unexpected transition to {:?}", res),
+            }
+        }
+        for id in &closed {
+            self.states.remove(id);
+        }
+        while self.abandoned.len() > MAX_ABANDONED_DIALS {
+            self.abandoned.pop_front();
+        }
+        closed
+    }
+
+    /// take_abandoned returns the dial `id` if its future was dropped before it was
+    /// answered, and forgets it.
+    pub(crate) fn take_abandoned(&mut self, id: &ConnectionId) -> Option<AbandonedDial> {
+        let index = self.abandoned.iter().position(|dial| dial.id == *id)?;
+        self.abandoned.remove(index)
+    }
+
+    /// drain forgets every connection, returning the handles of those that are
+    /// accepted or established.
+    pub(crate) fn drain(&mut self) -> Vec<ConnectionHandle> {
+        self.states
+            .drain()
+            .filter_map(|(_, state)| match state {
+                ConnectionState::PendingInbound { handle, .. }
+                | ConnectionState::Established(handle) => Some(handle),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::channel::bounded;
+    use super::super::config::OverflowPolicy;
+    use super::super::connection::Connection;
+    use super::super::handshake::Handshake;
+    use super::*;
+    use futures::task::noop_waker;
+    use libp2p_identity::{Keypair, PeerId};
+    use tokio::sync::{mpsc::unbounded_channel, oneshot};
+
+    #[test]
+    fn test_transition_table() {
+        use Event::*;
+        use StateKind::*;
+
+        // every transition that can happen
+        assert_eq!(Closed.next(Dial).unwrap(), PendingOutbound);
+        assert_eq!(Closed.next(Accept).unwrap(), PendingInbound);
+        assert_eq!(PendingOutbound.next(Answer).unwrap(), Established);
+        assert_eq!(PendingOutbound.next(Abort).unwrap(), Closed);
+        assert_eq!(PendingInbound.next(Upgrade).unwrap(), Established);
+        assert_eq!(PendingInbound.next(Close).unwrap(), Closing);
+        assert_eq!(Established.next(Close).unwrap(), Closing);
+        assert_eq!(PendingInbound.next(Dropped).unwrap(), Closed);
+        assert_eq!(Established.next(Dropped).unwrap(), Closed);
+        assert_eq!(Closing.next(Dropped).unwrap(), Closed);
+
+        // and none of the others
+        let states = [
+            PendingOutbound,
+            PendingInbound,
+            Established,
+            Closing,
+            Closed,
+        ];
+        let events = [Dial, Accept, Answer, Abort, Upgrade, Close, Dropped];
+        let valid = states
+            .iter()
+            .flat_map(|state| events.iter().map(move |event| (*state, *event)))
+            .filter(|(state, event)| state.next(*event).is_ok())
+            .count();
+        assert_eq!(valid, 10);
+        assert!(matches!(
+            Closing.next(Close),
+            Err(Error::InvalidStateTransition(_))
+        ));
+        assert!(Established.next(Answer).is_err());
+        assert!(Closed.next(Dropped).is_err());
+    }
+
+    fn pending_dial() -> (
+        PendingConnection,
+        oneshot::Receiver<Result<Connection, Error>>,
+    ) {
+        let (outbound_tx, _) = bounded(16, OverflowPolicy::Backpressure);
+        let (connection_tx, connection_rx) = oneshot::channel();
+        let keypair = Keypair::generate_ed25519();
+        let handshake = Handshake::new(&keypair, &ConnectionId::generate(), None).unwrap();
+        let pending =
+            PendingConnection::new(None, None, keypair, handshake, connection_tx, outbound_tx);
+        (pending, connection_rx)
+    }
+
+    fn new_connection(id: &ConnectionId) -> (Connection, ConnectionHandle) {
+        let (outbound_tx, _) = bounded(16, OverflowPolicy::Backpressure);
+        let (inbound_tx, inbound_rx) = unbounded_channel();
+        let connection = Connection::new_with_sender_tag(
+            PeerId::random(),
+            None,
+            id.clone(),
+            inbound_rx,
+            outbound_tx,
+            None,
+        );
+        let handle = connection.handle(inbound_tx);
+        (connection, handle)
+    }
+
+    #[test]
+    fn test_dial_transitions() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut table = ConnectionTable::default();
+
+        // Closed -> PendingOutbound -> Established
+        let id = ConnectionId::generate();
+        let (pending, _connection_rx) = pending_dial();
+        table.dial(id.clone(), pending).unwrap();
+        assert_eq!(table.kind(&id), StateKind::PendingOutbound);
+        assert_eq!(table.pending_dials().count(), 1);
+        assert!(table.handle(&id).is_none());
+        let (pending, _) = pending_dial();
+        assert!(matches!(
+            table.dial(id.clone(), pending),
+            Err(Error::ConnectionIDExists)
+        ));
+
+        let (dial, _pending) = table.answer(&id).unwrap();
+        let (connection, handle) = new_connection(&id);
+        table.establish(dial, handle);
+        assert_eq!(table.kind(&id), StateKind::Established);
+        assert!(table.answer(&id).is_err());
+        assert!(table.abort(&id).is_err());
+
+        // Established -> Closed, once the Connection is dropped
+        drop(connection);
+        assert_eq!(table.poll(&mut cx), vec![id.clone()]);
+        assert_eq!(table.kind(&id), StateKind::Closed);
+
+        // PendingOutbound -> Closed, when the dial is aborted
+        let id = ConnectionId::generate();
+        let (pending, _connection_rx) = pending_dial();
+        table.dial(id.clone(), pending).unwrap();
+        table.abort(&id).unwrap();
+        assert_eq!(table.kind(&id), StateKind::Closed);
+
+        // or when its dial future is dropped
+        let id = ConnectionId::generate();
+        let (pending, connection_rx) = pending_dial();
+        table.dial(id.clone(), pending).unwrap();
+        assert!(table.poll(&mut cx).is_empty());
+        drop(connection_rx);
+        assert_eq!(table.poll(&mut cx), vec![id.clone()]);
+        assert_eq!(table.kind(&id), StateKind::Closed);
+
+        // which is remembered, in case it's answered late, until it's taken
+        assert!(table.take_abandoned(&id).is_some());
+        assert!(table.take_abandoned(&id).is_none());
+
+        // as is a dial that's abandoned, e.g. because its handshake timed out
+        let id = ConnectionId::generate();
+        let (pending, _connection_rx) = pending_dial();
+        table.dial(id.clone(), pending).unwrap();
+        table.abandon(&id).unwrap();
+        assert_eq!(table.kind(&id), StateKind::Closed);
+        assert!(table.take_abandoned(&id).is_some());
+        assert!(table.abandon(&id).is_err());
+    }
+
+    #[test]
+    fn test_accept_transitions() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut table = ConnectionTable::default();
+
+        // Closed -> PendingInbound -> Established, once the swarm takes the upgrade
+        let id = ConnectionId::generate();
+        let (mut connection, handle) = new_connection(&id);
+        let upgrade = Arc::new(());
+        table.accept(id.clone(), handle.clone(), &upgrade).unwrap();
+        assert_eq!(table.kind(&id), StateKind::PendingInbound);
+        assert_eq!(table.pending_inbound(), 1);
+        assert!(table.handle(&id).is_some());
+        assert!(matches!(
+            table.accept(id.clone(), handle, &upgrade),
+            Err(Error::ConnectionIDExists)
+        ));
+        assert!(table.poll(&mut cx).is_empty());
+        assert_eq!(table.kind(&id), StateKind::PendingInbound);
+
+        drop(upgrade);
+        assert_eq!(table.pending_inbound(), 0);
+        assert!(table.poll(&mut cx).is_empty());
+        assert_eq!(table.kind(&id), StateKind::Established);
+
+        // Established -> Closing, and the Connection is told why
+        table
+            .close(&id, ConnectionEvent::Failed(Error::DeliveryFailed))
+            .unwrap();
+        assert_eq!(table.kind(&id), StateKind::Closing);
+        assert!(table.handle(&id).is_none());
+        assert!(table.close(&id, ConnectionEvent::Reset).is_err());
+        assert!(connection.inbound_rx.try_recv().is_ok());
+
+        // Closing -> Closed, once the Connection is dropped
+        assert!(table.poll(&mut cx).is_empty());
+        drop(connection);
+        assert_eq!(table.poll(&mut cx), vec![id.clone()]);
+        assert_eq!(table.kind(&id), StateKind::Closed);
+
+        // PendingInbound -> Closing, if it's closed before the swarm takes it
+        let id = ConnectionId::generate();
+        let (_connection, handle) = new_connection(&id);
+        let upgrade = Arc::new(());
+        table.accept(id.clone(), handle, &upgrade).unwrap();
+        table.close(&id, ConnectionEvent::Reset).unwrap();
+        assert_eq!(table.kind(&id), StateKind::Closing);
+        assert!(table.drain().is_empty());
+        assert_eq!(table.len(), 0);
+    }
+}
@@ -0,0 +1,241 @@
+//! Traffic statistics of connections, returned by
+//! [`Connection::stats`](crate::connection::Connection::stats) for a single connection
+//! and [`NymTransport::stats`](crate::transport::NymTransport::stats) for the whole
+//! transport, e.g. for an application to account for the bandwidth its peers use.
+
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::message::{ConnectionId, Message};
+use super::runtime::Instant;
+
+/// ConnectionStats is what's been sent and received on a connection.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// bytes written to the mixnet, as encoded on the wire.
+    pub bytes_sent: u64,
+    /// bytes read from the mixnet, as encoded on the wire.
+    pub bytes_received: u64,
+    /// frames written to the mixnet, by type, e.g. `data` or `keepalive`. The frames
+    /// of a batch are counted on their own, as well as the batch.
+    pub frames_sent: BTreeMap<&'static str, u64>,
+    /// frames read from the mixnet, by type.
+    pub frames_received: BTreeMap<&'static str, u64>,
+    /// number of substreams open on the connection.
+    pub open_substreams: usize,
+    /// when a message was last sent or received on the connection; None if none has been.
+    pub last_activity: Option<Instant>,
+    /// the smoothed mixnet round-trip time, once keepalives have measured it.
+    pub rtt: Option<Duration>,
+}
+
+impl ConnectionStats {
+    fn on_sent(&mut self, message: &Message, bytes: usize, now: Instant) {
+        self.bytes_sent += bytes as u64;
+        count_frames(&mut self.frames_sent, message);
+        self.last_activity = Some(now);
+    }
+
+    fn on_received(&mut self, message: &Message, bytes: usize, now: Instant) {
+        self.bytes_received += bytes as u64;
+        count_frames(&mut self.frames_received, message);
+        self.last_activity = Some(now);
+    }
+}
+
+/// TransportStats is what's been sent and received by a transport.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransportStats {
+    /// number of connections that are accepted or established.
+    pub connections: usize,
+    /// traffic of every connection since the transport started, including connections
+    /// that have since closed, handshakes and datagrams. `open_substreams` is summed over
+    /// the established connections, `last_activity` is the latest of any message, and
+    /// `rtt` is always None.
+    pub total: ConnectionStats,
+}
+
+fn count_frames(frames: &mut BTreeMap<&'static str, u64>, message: &Message) {
+    *frames.entry(message.kind()).or_default() += 1;
+    if let Message::Batch(batch) = message {
+        for msg in &batch.messages {
+            *frames.entry(msg.message.message_type.kind()).or_default() += 1;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    connections: HashMap<ConnectionId, ConnectionStats>,
+    total: ConnectionStats,
+}
+
+/// StatsTable holds the traffic statistics of every established connection, and the
+/// transport's totals. It's shared by the mixnet task, which counts the messages it
+/// writes and reads, and the connections and transport, which report them.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct StatsTable(Arc<Mutex<Inner>>);
+
+impl StatsTable {
+    /// register starts counting the messages of an established connection. Until then,
+    /// its messages only count towards the totals.
+    pub(crate) fn register(&self, id: &ConnectionId) {
+        self.0.lock().connections.entry(id.clone()).or_default();
+    }
+
+    /// remove forgets the statistics of a closed connection.
+    pub(crate) fn remove(&self, id: &ConnectionId) {
+        self.0.lock().connections.remove(id);
+    }
+
+    /// on_sent counts a message written to the mixnet, encoded in `bytes` bytes.
+    pub(crate) fn on_sent(&self, message: &Message, bytes: usize) {
+        let now = Instant::now();
+        let mut inner = self.0.lock();
+        inner.total.on_sent(message, bytes, now);
+        if let Some(stats) = inner.connections.get_mut(message.connection_id()) {
+            stats.on_sent(message, bytes, now);
+        }
+    }
+
+    /// on_received counts a message read from the mixnet, encoded in `bytes` bytes.
+    pub(crate) fn on_received(&self, message: &Message, bytes: usize) {
+        let now = Instant::now();
+        let mut inner = self.0.lock();
+        inner.total.on_received(message, bytes, now);
+        if let Some(stats) = inner.connections.get_mut(message.connection_id()) {
+            stats.on_received(message, bytes, now);
+        }
+    }
+
+    /// connection returns the traffic counted on a connection so far; the caller fills
+    /// in its open substreams and round-trip time.
+    pub(crate) fn connection(&self, id: &ConnectionId) -> ConnectionStats {
+        self.0
+            .lock()
+            .connections
+            .get(id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// total returns the traffic counted by the transport so far.
+    pub(crate) fn total(&self) -> ConnectionStats {
+        self.0.lock().total.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::NymTransportConfig;
+    use super::super::memory::{InMemoryConfig, InMemoryMixnet};
+    use super::super::message::{
+        AckMessage, BatchMessage, SubstreamId, SubstreamMessage, TransportMessage,
+    };
+    use super::super::nonce::Nonce;
+    use super::super::runtime::sleep;
+    use super::super::testing::{self, connect, next_event};
+    use super::*;
+    use bytes::Bytes;
+    use futures::future::poll_fn;
+    use libp2p::core::muxing::StreamMuxerExt;
+
+    fn data(id: &ConnectionId, nonce: u64) -> TransportMessage {
+        TransportMessage {
+            nonce: Nonce::new(nonce),
+            id: id.clone(),
+            message: SubstreamMessage::new_with_data(
+                SubstreamId::generate(),
+                Bytes::from_static(b"hello"),
+            ),
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_stats_table() {
+        let table = StatsTable::default();
+        let id = ConnectionId::generate();
+        let other = ConnectionId::generate();
+        table.register(&id);
+
+        table.on_sent(&Message::TransportMessage(data(&id, 1)), 100);
+        table.on_sent(
+            &Message::Batch(BatchMessage {
+                id: id.clone(),
+                messages: vec![data(&id, 2), data(&id, 3)],
+            }),
+            250,
+        );
+        table.on_received(
+            &Message::Ack(AckMessage {
+                id: id.clone(),
+                nonce: Nonce::FIRST,
+            }),
+            40,
+        );
+        // an unregistered connection only counts towards the totals
+        table.on_received(&Message::TransportMessage(data(&other, 1)), 60);
+
+        let stats = table.connection(&id);
+        assert_eq!(stats.bytes_sent, 350);
+        assert_eq!(stats.bytes_received, 40);
+        assert_eq!(stats.frames_sent.get("data"), Some(&3));
+        assert_eq!(stats.frames_sent.get("batch"), Some(&1));
+        assert_eq!(stats.frames_received.get("ack"), Some(&1));
+        assert!(stats.last_activity.is_some());
+        assert_eq!(table.connection(&other), ConnectionStats::default());
+
+        let total = table.total();
+        assert_eq!(total.bytes_sent, 350);
+        assert_eq!(total.bytes_received, 100);
+        assert_eq!(total.frames_received.get("data"), Some(&1));
+
+        table.remove(&id);
+        assert_eq!(table.connection(&id), ConnectionStats::default());
+        assert_eq!(table.total(), total);
+    }
+
+    #[tokio::test]
+    async fn test_stats_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let mut dialer = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        let mut listener = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        let listener_addr = listener.listen_addr().clone();
+        let ((_, mut dialed), (_, mut accepted)) =
+            connect(&mut dialer, &mut listener, listener_addr).await;
+        assert_eq!(dialed.stats().open_substreams, 0);
+
+        let _substream = poll_fn(|cx| dialed.poll_outbound_unpin(cx)).await.unwrap();
+        // long enough for the OpenRequest to be answered
+        let answered = sleep(Duration::from_millis(200));
+        futures::pin_mut!(answered);
+        loop {
+            tokio::select! {
+                _ = &mut answered => break,
+                _ = next_event(&mut dialer) => {}
+                _ = next_event(&mut listener) => {}
+                _ = poll_fn(|cx| dialed.poll_unpin(cx)) => {}
+                _ = poll_fn(|cx| accepted.poll_unpin(cx)) => {}
+            }
+        }
+
+        let stats = dialed.stats();
+        assert_eq!(stats.open_substreams, 1);
+        assert_eq!(stats.frames_sent.get("open_request"), Some(&1));
+        assert_eq!(stats.frames_received.get("open_response"), Some(&1));
+        assert!(stats.bytes_sent > 0 && stats.bytes_received > 0);
+        assert!(stats.last_activity.is_some());
+        let stats = accepted.stats();
+        assert_eq!(stats.frames_received.get("open_request"), Some(&1));
+
+        // the transport's totals include the handshake, which came before the connection
+        let stats = dialer.stats();
+        assert_eq!(stats.connections, 1);
+        assert_eq!(stats.total.open_substreams, 1);
+        assert_eq!(stats.total.frames_sent.get("connection_request"), Some(&1));
+        assert!(stats.total.bytes_sent >= dialed.stats().bytes_sent);
+    }
+}
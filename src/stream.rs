@@ -0,0 +1,323 @@
+use futures::{
+    future::{poll_fn, BoxFuture},
+    io::{Error as IoError, ErrorKind},
+    AsyncRead, AsyncWrite, FutureExt,
+};
+use libp2p::core::{
+    multiaddr::Multiaddr,
+    muxing::StreamMuxer,
+    transport::{DialOpts, ListenerId, TransportError, TransportEvent},
+    Transport,
+};
+use libp2p_identity::PeerId;
+use log::debug;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use super::connection::Connection;
+use super::error::Error;
+use super::substream::Substream;
+use super::transport::NymTransport;
+
+/// NymStream is a Nym connection carrying a single substream, exposed as a plain
+/// byte stream so that standard libp2p upgrades (e.g. noise and yamux) can be
+/// layered on top of it. See [`NymStreamTransport`].
+#[derive(Debug)]
+pub struct NymStream {
+    connection: Connection,
+    substream: Substream,
+}
+
+impl NymStream {
+    /// open opens the stream's substream on a connection we dialed.
+    pub(crate) async fn open(mut connection: Connection) -> Result<Self, Error> {
+        let substream = poll_fn(|cx| Pin::new(&mut connection).poll_outbound(cx)).await?;
+        Ok(NymStream {
+            connection,
+            substream,
+        })
+    }
+
+    /// accept waits for the dialer to open the stream's substream on a connection we accepted.
+    pub(crate) async fn accept(mut connection: Connection) -> Result<Self, Error> {
+        let substream = poll_fn(|cx| {
+            if let Poll::Ready(Err(e)) = Pin::new(&mut connection).poll(cx) {
+                return Poll::Ready(Err(e));
+            }
+            Pin::new(&mut connection).poll_inbound(cx)
+        })
+        .await?;
+        Ok(NymStream {
+            connection,
+            substream,
+        })
+    }
+
+    /// peer_id returns the PeerId the remote authenticated as in the connection handshake.
+    pub fn peer_id(&self) -> PeerId {
+        self.connection.peer_id
+    }
+
+    /// poll_connection handles messages that have arrived on the connection,
+    /// delivering data to the substream and answering keepalives.
+    fn poll_connection(&mut self, cx: &mut Context<'_>) -> Result<(), IoError> {
+        loop {
+            match Pin::new(&mut self.connection).poll(cx) {
+                Poll::Ready(Ok(event)) => debug!("ignoring connection event {:?}", event),
+                Poll::Ready(Err(e)) => return Err(IoError::new(ErrorKind::Other, e.to_string())),
+                Poll::Pending => break,
+            }
+        }
+
+        // the remote should only ever open the one substream
+        while let Poll::Ready(res) = Pin::new(&mut self.connection).poll_inbound(cx) {
+            if let Ok(substream) = res {
+                debug!("ignoring unexpected substream {:?}", substream.substream_id);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for NymStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, IoError>> {
+        self.poll_connection(cx)?;
+        Pin::new(&mut self.substream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for NymStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoError>> {
+        self.poll_connection(cx)?;
+        Pin::new(&mut self.substream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        self.poll_connection(cx)?;
+        Pin::new(&mut self.substream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.substream).poll_close(cx)
+    }
+}
+
+/// NymStreamTransport wraps a [`NymTransport`] so that each connection is output as a
+/// [`NymStream`] instead of the transport's own stream muxer. This lets the connection
+/// be upgraded like any other libp2p transport, e.g.
+/// `NymStreamTransport::new(transport).upgrade(Version::V1).authenticate(noise).multiplex(yamux)`,
+/// at the cost of a second handshake and of muxing every stream over a single substream.
+pub struct NymStreamTransport {
+    inner: NymTransport,
+}
+
+impl NymStreamTransport {
+    /// New stream transport wrapping the given transport.
+    pub fn new(inner: NymTransport) -> Self {
+        NymStreamTransport { inner }
+    }
+
+    /// into_inner returns the wrapped transport.
+    pub fn into_inner(self) -> NymTransport {
+        self.inner
+    }
+}
+
+impl Transport for NymStreamTransport {
+    type Output = NymStream;
+    type Error = Error;
+    type ListenerUpgrade = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+    type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn listen_on(
+        &mut self,
+        id: ListenerId,
+        addr: Multiaddr,
+    ) -> Result<(), TransportError<Self::Error>> {
+        self.inner.listen_on(id, addr)
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.inner.remove_listener(id)
+    }
+
+    fn dial(
+        &mut self,
+        addr: Multiaddr,
+        dial_opts: DialOpts,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let dial = self.inner.dial(addr, dial_opts)?;
+        Ok(async move {
+            let (_, connection) = dial.await?;
+            NymStream::open(connection).await
+        }
+        .boxed())
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        Pin::new(&mut self.inner).poll(cx).map(|event| {
+            event.map_upgrade(|upgrade| {
+                async move {
+                    let (_, connection) = upgrade.await?;
+                    NymStream::accept(connection).await
+                }
+                .boxed()
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::NymTransportConfig;
+    use super::super::memory::{InMemoryConfig, InMemoryMixnet};
+    use super::super::message::MIN_MAX_MESSAGE_SIZE;
+    use super::super::testing::{self, accept, dial_opts, spawn_driven};
+    use super::super::transport::connect_test_client;
+    use super::*;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use libp2p::core::{transport::PortUse, Endpoint};
+    use libp2p_identity::Keypair;
+    use std::time::Duration;
+
+    async fn new_stream_transport() -> NymStreamTransport {
+        let client = connect_test_client().await;
+        let transport = NymTransport::new(client, Keypair::generate_ed25519())
+            .await
+            .unwrap();
+        NymStreamTransport::new(transport)
+    }
+
+    // keeps a transport handling inbound mixnet messages for its connections
+    async fn drive(mut transport: NymStreamTransport) {
+        loop {
+            poll_fn(|cx| Pin::new(&mut transport).poll(cx)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_transport() {
+        let mut dialer_transport = new_stream_transport().await;
+        let mut listener_transport = new_stream_transport().await;
+        let listener_multiaddr = listener_transport.inner.listen_addr.clone();
+
+        let dial_opts = DialOpts {
+            role: Endpoint::Dialer,
+            port_use: PortUse::Reuse,
+        };
+        let dial = tokio::spawn(
+            dialer_transport
+                .dial(listener_multiaddr, dial_opts)
+                .unwrap(),
+        );
+        tokio::spawn(drive(dialer_transport));
+
+        let upgrade = loop {
+            if let TransportEvent::Incoming { upgrade, .. } =
+                poll_fn(|cx| Pin::new(&mut listener_transport).poll(cx)).await
+            {
+                break upgrade;
+            }
+        };
+        tokio::spawn(drive(listener_transport));
+
+        let mut dialer_stream = dial.await.unwrap().unwrap();
+        dialer_stream.write_all(b"hello").await.unwrap();
+
+        let mut listener_stream = upgrade.await.unwrap();
+        let mut buf = [0u8; 5];
+        listener_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        listener_stream.write_all(b"world").await.unwrap();
+        dialer_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    /// new_transport returns a stream transport over a new client of `mixnet`, and the
+    /// address it listens on.
+    async fn new_transport(
+        mixnet: &InMemoryMixnet,
+        config: NymTransportConfig,
+    ) -> (NymStreamTransport, Multiaddr) {
+        let transport = testing::transport(mixnet, config).await;
+        let listen_addr = transport.listen_addr().clone();
+        (NymStreamTransport::new(transport), listen_addr)
+    }
+
+    #[tokio::test]
+    async fn test_transport_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(
+            InMemoryConfig::default().with_delay(Duration::ZERO, Duration::from_millis(10)),
+        );
+        let (mut dialer_transport, _) = new_transport(&mixnet, NymTransportConfig::default()).await;
+        let (mut listener_transport, listener_multiaddr) =
+            new_transport(&mixnet, NymTransportConfig::default()).await;
+
+        let dial = tokio::spawn(
+            dialer_transport
+                .dial(listener_multiaddr, dial_opts())
+                .unwrap(),
+        );
+        spawn_driven(dialer_transport);
+        let upgrade = accept(&mut listener_transport).await;
+        spawn_driven(listener_transport);
+
+        let mut dialer_stream = dial.await.unwrap().unwrap();
+        dialer_stream.write_all(b"hello").await.unwrap();
+        let mut listener_stream = upgrade.await.unwrap();
+        let mut buf = [0u8; 5];
+        listener_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        listener_stream.write_all(b"world").await.unwrap();
+        dialer_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_max_message_size_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let (mut dialer_transport, _) = new_transport(&mixnet, NymTransportConfig::default()).await;
+        // the listener takes messages smaller than the dialer's fragments, and blocks a
+        // sender at its first message that's too large
+        let config = NymTransportConfig::default()
+            .with_max_message_size(MIN_MAX_MESSAGE_SIZE as usize)
+            .with_max_invalid_messages(1);
+        let (mut listener_transport, listener_multiaddr) = new_transport(&mixnet, config).await;
+
+        let dial = tokio::spawn(
+            dialer_transport
+                .dial(listener_multiaddr, dial_opts())
+                .unwrap(),
+        );
+        spawn_driven(dialer_transport);
+        let upgrade = accept(&mut listener_transport).await;
+        spawn_driven(listener_transport);
+
+        // the dialer fragments to the listener's limit, so every fragment gets through
+        let payload: Vec<u8> = (0..=255u8).cycle().take(8 * 1024).collect();
+        let mut dialer_stream = dial.await.unwrap().unwrap();
+        dialer_stream.write_all(&payload).await.unwrap();
+        let mut listener_stream = upgrade.await.unwrap();
+        let mut buf = vec![0u8; payload.len()];
+        tokio::time::timeout(Duration::from_secs(5), listener_stream.read_exact(&mut buf))
+            .await
+            .expect("a fragment was dropped")
+            .unwrap();
+        assert_eq!(buf, payload);
+    }
+}
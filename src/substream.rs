@@ -1,26 +1,112 @@
+use super::channel::BoundedSender;
+use super::compression::{Compression, COMPRESSION_OVERHEAD};
+use super::config::DEFAULT_MAX_FRAGMENT_SIZE;
+use super::error::Error;
+use super::events::{EmitOnDrop, EventSender, NymEvent};
+use super::handshake::{SessionCipher, CIPHER_OVERHEAD};
 use super::message::{
-    ConnectionId, Message, OutboundMessage, SubstreamId, SubstreamMessage, TransportMessage,
+    fragment, ConnectionId, Fragment, Message, OutboundMessage, SubstreamId, SubstreamMessage,
+    SubstreamMessageType, TransportMessage,
 };
+use super::metrics::{Metrics, Tracked};
+use super::nonce::NonceCounter;
+use bytes::{Buf, Bytes, BytesMut};
 use futures::{
     io::{Error as IoError, ErrorKind},
-    AsyncRead, AsyncWrite,
+    ready, AsyncRead, AsyncWrite,
 };
+use libp2p_identity::PeerId;
 use log::debug;
 use nym_sdk::mixnet::AnonymousSenderTag;
 use nym_sphinx::addressing::clients::Recipient;
 use parking_lot::Mutex;
 use std::{
+    collections::{HashSet, VecDeque},
     pin::Pin,
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
-    },
-    task::{Context, Poll},
-};
-use tokio::sync::{
-    mpsc::{UnboundedReceiver, UnboundedSender},
-    oneshot::Receiver,
+    sync::Arc,
+    task::{Context, Poll, Waker},
 };
+use tokio::sync::{mpsc::UnboundedReceiver, oneshot::Receiver};
+
+/// SendWindow is how far into a substream the remote allows us to write.
+/// It's shared with the connection, which raises it when the remote sends a window update.
+#[derive(Debug)]
+pub(crate) struct SendWindow {
+    /// total number of bytes we may write to the substream
+    limit: u64,
+    /// writer waiting for the limit to be raised
+    waker: Option<Waker>,
+}
+
+impl SendWindow {
+    pub(crate) fn new(limit: u64) -> Self {
+        SendWindow { limit, waker: None }
+    }
+
+    /// set_limit replaces the limit, e.g. with the window the remote sent in its OpenResponse.
+    pub(crate) fn set_limit(&mut self, limit: u64) {
+        self.limit = limit;
+        self.wake();
+    }
+
+    /// raise raises the limit to `limit`, ignoring updates that would lower it.
+    pub(crate) fn raise(&mut self, limit: u64) {
+        if limit > self.limit {
+            self.set_limit(limit);
+        }
+    }
+
+    /// wake wakes a writer blocked on the window, e.g. so that it notices the substream closed.
+    pub(crate) fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// ReceiveBuffer counts what the connection has handed a substream that the application
+/// hasn't read yet. A remote that respects our receive window never has more than the
+/// window unread, so one that goes past it, or sends it in too many frames, is cut off
+/// rather than buffered without bound.
+#[derive(Debug, Default)]
+pub(crate) struct ReceiveBuffer {
+    /// unread bytes
+    bytes: u64,
+    /// unread frames, each held in its own buffer until it's read
+    frames: usize,
+}
+
+impl ReceiveBuffer {
+    /// push counts a frame of `len` bytes handed to the substream, returning false
+    /// instead if it would take the buffer past `max_bytes` or `max_frames`.
+    pub(crate) fn push(&mut self, len: usize, max_bytes: u64, max_frames: usize) -> bool {
+        if self.bytes + len as u64 > max_bytes || self.frames >= max_frames {
+            return false;
+        }
+        self.bytes += len as u64;
+        self.frames += 1;
+        true
+    }
+
+    /// bytes returns the number of unread bytes.
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+/// FlowControl tracks both directions of a substream's credit-based flow control.
+#[derive(Debug)]
+struct FlowControl {
+    send_window: Arc<Mutex<SendWindow>>,
+    /// total number of bytes written to the substream
+    sent: u64,
+    /// number of unread bytes we allow the remote to have in flight
+    receive_window: u64,
+    /// total number of bytes read from the substream by the application
+    consumed: u64,
+    /// the receive limit we last sent the remote
+    advertised: u64,
+}
 
 #[derive(Debug)]
 pub struct Substream {
@@ -29,22 +115,58 @@ pub struct Substream {
     pub(crate) substream_id: SubstreamId,
 
     /// inbound messages; inbound_tx is in the corresponding Connection
-    pub(crate) inbound_rx: UnboundedReceiver<Vec<u8>>,
+    pub(crate) inbound_rx: UnboundedReceiver<Bytes>,
 
     /// outbound messages; go directly to the mixnet
-    outbound_tx: UnboundedSender<OutboundMessage>,
+    outbound_tx: BoundedSender<OutboundMessage>,
 
     sender_tag: Option<AnonymousSenderTag>,
 
-    /// used to signal when the substream is closed
-    close_rx: Receiver<()>,
+    /// used to signal when the substream is closed, along with the error
+    /// it failed with, if it was closed by a failure rather than by the remote
+    close_rx: Receiver<Option<Error>>,
+    /// set once our half is closed, or the connection has closed the substream;
+    /// writes fail from then on
     closed: Mutex<bool>,
+    /// set once the substream has failed; reads fail from then on too
+    failed: bool,
 
     // buffer of data that's been written to the stream,
     // but not yet read by the application.
-    unread_data: Mutex<Vec<u8>>,
+    unread_data: Mutex<BytesMut>,
+
+    message_nonce: NonceCounter,
+
+    /// the connection's set of open substreams; the substream removes itself
+    /// when it sends a Close, so that the connection doesn't send another one
+    open_substreams: Arc<Mutex<HashSet<SubstreamId>>>,
+
+    /// maximum size of the payload of a single message, once compressed and
+    /// encrypted; each write sends at most one message's worth
+    max_fragment_size: usize,
+    /// ID of the next fragmented payload written to the substream
+    next_payload_id: u32,
+    /// fragments of written payloads that haven't been sent to the mixnet yet
+    pending_fragments: VecDeque<Fragment>,
+
+    /// encrypts written payloads; None if they're sent in plaintext
+    cipher: Option<Arc<SessionCipher>>,
+
+    /// compresses written payloads before they're encrypted; None if the
+    /// connection doesn't use compression
+    compression: Option<Compression>,
+
+    /// flow control state; None if writes aren't limited by the remote
+    flow: Option<FlowControl>,
 
-    message_nonce: Arc<AtomicU64>,
+    /// what's been handed to us and not read yet; shared with the connection
+    receive_buffer: Arc<Mutex<ReceiveBuffer>>,
+
+    /// counts this substream in the open substreams gauge while it's alive
+    _tracked: Tracked,
+
+    /// emits a SubstreamClosed event when the substream is dropped
+    _closed_event: EmitOnDrop,
 }
 
 impl Substream {
@@ -52,11 +174,12 @@ impl Substream {
         remote_recipient: Option<Recipient>,
         connection_id: ConnectionId,
         substream_id: SubstreamId,
-        inbound_rx: UnboundedReceiver<Vec<u8>>,
-        outbound_tx: UnboundedSender<OutboundMessage>,
-        close_rx: Receiver<()>,
-        message_nonce: Arc<AtomicU64>,
+        inbound_rx: UnboundedReceiver<Bytes>,
+        outbound_tx: BoundedSender<OutboundMessage>,
+        close_rx: Receiver<Option<Error>>,
+        message_nonce: NonceCounter,
         sender_tag: Option<AnonymousSenderTag>,
+        open_substreams: Arc<Mutex<HashSet<SubstreamId>>>,
     ) -> Self {
         Substream {
             remote_recipient,
@@ -67,20 +190,86 @@ impl Substream {
             sender_tag,
             close_rx,
             closed: Mutex::new(false),
-            unread_data: Mutex::new(vec![]),
+            failed: false,
+            unread_data: Mutex::new(BytesMut::new()),
             message_nonce,
+            open_substreams,
+            max_fragment_size: DEFAULT_MAX_FRAGMENT_SIZE,
+            next_payload_id: 0,
+            pending_fragments: VecDeque::new(),
+            cipher: None,
+            compression: None,
+            flow: None,
+            receive_buffer: Arc::default(),
+            _tracked: Tracked::default(),
+            _closed_event: EmitOnDrop::default(),
         }
     }
 
+    /// Set the maximum payload size of a single message and return self.
+    pub(crate) fn with_max_fragment_size(mut self, max_fragment_size: usize) -> Self {
+        self.max_fragment_size = max_fragment_size;
+        self
+    }
+
+    /// Encrypt written payloads with the connection's cipher and return self.
+    pub(crate) fn with_cipher(mut self, cipher: Arc<SessionCipher>) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// Compress written payloads before encrypting them and return self.
+    pub(crate) fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Limit writes to the window the remote allows, and let the remote have up to
+    /// `receive_window` unread bytes in flight, and return self.
+    pub(crate) fn with_flow_control(
+        mut self,
+        send_window: Arc<Mutex<SendWindow>>,
+        receive_window: u32,
+    ) -> Self {
+        self.flow = Some(FlowControl {
+            send_window,
+            sent: 0,
+            receive_window: receive_window as u64,
+            consumed: 0,
+            advertised: receive_window as u64,
+        });
+        self
+    }
+
+    /// Count unread data in the given buffer, shared with the connection, and return self.
+    pub(crate) fn with_receive_buffer(mut self, buffer: Arc<Mutex<ReceiveBuffer>>) -> Self {
+        self.receive_buffer = buffer;
+        self
+    }
+
+    /// Record the substream in the given metrics and return self.
+    pub(crate) fn with_metrics(mut self, metrics: &Metrics) -> Self {
+        self._tracked = metrics.track_substream();
+        self
+    }
+
+    /// Emit events for the substream, which is on a connection to `peer_id`, and return self.
+    pub(crate) fn with_events(mut self, events: &EventSender, peer_id: PeerId) -> Self {
+        events.emit(NymEvent::SubstreamOpened { peer_id });
+        self._closed_event = events.emit_on_drop(NymEvent::SubstreamClosed { peer_id });
+        self
+    }
+
     pub(crate) fn new(
         remote_recipient: Option<Recipient>,
         connection_id: ConnectionId,
         substream_id: SubstreamId,
-        inbound_rx: UnboundedReceiver<Vec<u8>>,
-        outbound_tx: UnboundedSender<OutboundMessage>,
-        close_rx: Receiver<()>,
-        message_nonce: Arc<AtomicU64>,
+        inbound_rx: UnboundedReceiver<Bytes>,
+        outbound_tx: BoundedSender<OutboundMessage>,
+        close_rx: Receiver<Option<Error>>,
+        message_nonce: NonceCounter,
     ) -> Self {
+        let open_substreams = Arc::new(Mutex::new(HashSet::from([substream_id.clone()])));
         Self::new_with_sender_tag(
             remote_recipient,
             connection_id,
@@ -90,26 +279,161 @@ impl Substream {
             close_rx,
             message_nonce,
             None,
+            open_substreams,
         )
     }
 
-    fn check_closed(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Result<(), IoError> {
-        let closed_err = IoError::new(ErrorKind::Other, "stream closed");
+    /// poll_send_fragments sends pending fragments to the mixnet for as long as
+    /// the outbound channel has room, resolving once they've all been sent.
+    fn poll_send_fragments(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        while !self.pending_fragments.is_empty() {
+            if ready!(self.outbound_tx.poll_ready(cx)).is_err() {
+                return Poll::Ready(Err(IoError::new(
+                    ErrorKind::Other,
+                    "poll_send_fragments outbound_tx error: channel closed",
+                )));
+            }
 
-        // close_rx will return an error if the channel is closed (ie. sender was dropped),
-        // or if it's empty
-        let received_closed = self.close_rx.try_recv();
+            let fragment = self.pending_fragments.pop_front().unwrap();
+            let nonce = self
+                .message_nonce
+                .next()
+                .map_err(|e| IoError::new(ErrorKind::Other, e))?;
+            self.outbound_tx
+                .try_send(OutboundMessage {
+                    recipient: self.remote_recipient,
+                    message: Message::TransportMessage(TransportMessage {
+                        nonce,
+                        id: self.connection_id.clone(),
+                        message: SubstreamMessage {
+                            substream_id: self.substream_id.clone(),
+                            message_type: SubstreamMessageType::Fragment(fragment),
+                        },
+                        timestamp: None,
+                    }),
+                    sender_tag: self.sender_tag.clone(),
+                })
+                .map_err(|e| {
+                    IoError::new(
+                        ErrorKind::Other,
+                        format!("poll_send_fragments outbound_tx error: {}", e),
+                    )
+                })?;
+        }
 
-        let mut closed = self.closed.lock();
-        if *closed {
-            return Err(closed_err);
+        Poll::Ready(Ok(()))
+    }
+
+    /// frame_size returns how many bytes of a write fit in a single message, once
+    /// they've been compressed and encrypted.
+    fn frame_size(&self) -> usize {
+        let mut overhead = 0;
+        if self.compression.is_some() {
+            overhead += COMPRESSION_OVERHEAD;
+        }
+        if self.cipher.is_some() {
+            overhead += CIPHER_OVERHEAD;
         }
+        self.max_fragment_size.saturating_sub(overhead).max(1)
+    }
+
+    /// poll_send_credit returns how many of `len` bytes may be written now,
+    /// resolving once the remote's window allows at least one.
+    fn poll_send_credit(&mut self, cx: &mut Context<'_>, len: usize) -> Poll<usize> {
+        let Some(flow) = &mut self.flow else {
+            return Poll::Ready(len);
+        };
 
-        if received_closed.is_ok() {
-            *closed = true;
-            return Err(closed_err);
+        let mut window = flow.send_window.lock();
+        let available = window.limit.saturating_sub(flow.sent);
+        if available == 0 && len > 0 {
+            debug!("substream {:?} send window exhausted", self.substream_id);
+            window.waker = Some(cx.waker().clone());
+            return Poll::Pending;
         }
 
+        let len = (len as u64).min(available);
+        flow.sent += len;
+        Poll::Ready(len as usize)
+    }
+
+    /// poll_window_update sends the remote a larger window once the application
+    /// has read through half of the last one we sent.
+    fn poll_window_update(&mut self, cx: &mut Context<'_>) -> Result<(), IoError> {
+        let Some(flow) = &mut self.flow else {
+            return Ok(());
+        };
+
+        if flow.advertised.saturating_sub(flow.consumed) > flow.receive_window / 2 {
+            return Ok(());
+        }
+
+        match self.outbound_tx.poll_ready(cx) {
+            // retried on the next read, or once the channel has room
+            Poll::Pending => return Ok(()),
+            Poll::Ready(Err(_)) => {
+                return Err(IoError::new(
+                    ErrorKind::Other,
+                    "poll_window_update outbound_tx error: channel closed",
+                ))
+            }
+            Poll::Ready(Ok(())) => {}
+        }
+
+        let limit = flow.consumed + flow.receive_window;
+        let nonce = self
+            .message_nonce
+            .next()
+            .map_err(|e| IoError::new(ErrorKind::Other, e))?;
+        self.outbound_tx
+            .try_send(OutboundMessage {
+                recipient: self.remote_recipient,
+                message: Message::TransportMessage(TransportMessage {
+                    nonce,
+                    id: self.connection_id.clone(),
+                    message: SubstreamMessage {
+                        substream_id: self.substream_id.clone(),
+                        message_type: SubstreamMessageType::WindowUpdate(limit),
+                    },
+                    timestamp: None,
+                }),
+                sender_tag: self.sender_tag.clone(),
+            })
+            .map_err(|e| {
+                IoError::new(
+                    ErrorKind::Other,
+                    format!("poll_window_update outbound_tx error: {}", e),
+                )
+            })?;
+        flow.advertised = limit;
+        Ok(())
+    }
+
+    /// recv_close takes the connection's notice that the substream is closed, if it
+    /// has sent one, and returns the error the substream failed with, if any. Without
+    /// an error, the notice means that both halves are closed, and what the remote
+    /// sent before closing its half can still be read.
+    fn recv_close(&mut self) -> Result<(), IoError> {
+        // close_rx will return an error if the channel is closed (ie. sender was dropped),
+        // or if it's empty
+        if let Ok(reason) = self.close_rx.try_recv() {
+            *self.closed.lock() = true;
+            if let Some(e) = reason {
+                self.failed = true;
+                return Err(IoError::new(ErrorKind::Other, e));
+            }
+        }
+        if self.failed {
+            return Err(IoError::new(ErrorKind::Other, "stream closed"));
+        }
+        Ok(())
+    }
+
+    fn check_closed(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Result<(), IoError> {
+        self.recv_close()?;
+        if *self.closed.lock() {
+            return Err(IoError::new(ErrorKind::Other, "stream closed"));
+        }
         Ok(())
     }
 }
@@ -120,12 +444,38 @@ impl AsyncRead for Substream {
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<Result<usize, IoError>> {
-        let closed_result = self.as_mut().check_closed(cx);
-        if let Err(e) = closed_result {
+        let res = self.as_mut().poll_read_buffered(cx, buf);
+        match &res {
+            Poll::Ready(Err(_)) => return res,
+            Poll::Ready(Ok(len)) => {
+                if let Some(flow) = &mut self.flow {
+                    flow.consumed += *len as u64;
+                }
+                let mut buffer = self.receive_buffer.lock();
+                buffer.bytes = buffer.bytes.saturating_sub(*len as u64);
+            }
+            Poll::Pending => {}
+        }
+
+        self.poll_window_update(cx)?;
+        res
+    }
+}
+
+impl Substream {
+    fn poll_read_buffered(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, IoError>> {
+        // closing our half doesn't stop us reading what the remote sends
+        if let Err(e) = self.recv_close() {
             return Poll::Ready(Err(e));
         }
 
         let inbound_rx_data = self.inbound_rx.poll_recv(cx);
+        // the connection drops the channel once the remote has closed its half
+        let eof = matches!(inbound_rx_data, Poll::Ready(None));
 
         // first, write any previously unread data to the buf
         let mut unread_data = self.unread_data.lock();
@@ -134,19 +484,20 @@ impl AsyncRead for Substream {
             let buf_len = buf.len();
             let copy_len = std::cmp::min(unread_len, buf_len);
             buf[..copy_len].copy_from_slice(&unread_data[..copy_len]);
-            *unread_data = unread_data[copy_len..].to_vec();
+            unread_data.advance(copy_len);
             copy_len
         } else {
             0
         };
 
         if let Poll::Ready(Some(data)) = inbound_rx_data {
+            {
+                let mut buffer = self.receive_buffer.lock();
+                buffer.frames = buffer.frames.saturating_sub(1);
+            }
             if filled_len == buf.len() {
                 // we've filled the buffer, so we'll have to save the rest for later
-                let mut new = vec![];
-                new.extend(unread_data.drain(..));
-                new.extend(data.iter());
-                *unread_data = new;
+                unread_data.extend_from_slice(&data);
                 return Poll::Ready(Ok(filled_len));
             }
 
@@ -162,8 +513,8 @@ impl AsyncRead for Substream {
             let copied = std::cmp::min(remaining_len, data_len);
             buf[filled_len..filled_len + copied].copy_from_slice(&data[..copied]);
             // debug!("poll_read copied {} bytes: data {:?}", copied, buf);
-            debug!("poll_read copied {} bytes", copied);
-            return Poll::Ready(Ok(copied));
+            debug!("poll_read copied {} bytes", filled_len + copied);
+            return Poll::Ready(Ok(filled_len + copied));
         }
 
         if filled_len > 0 {
@@ -171,6 +522,15 @@ impl AsyncRead for Substream {
             debug!("poll_read copied {} bytes", filled_len);
             return Poll::Ready(Ok(filled_len));
         }
+        drop(unread_data);
+
+        if eof {
+            // the channel is also dropped when the substream fails, which isn't an EOF
+            if let Err(e) = self.recv_close() {
+                return Poll::Ready(Err(e));
+            }
+            return Poll::Ready(Ok(0));
+        }
 
         Poll::Pending
     }
@@ -185,19 +545,75 @@ impl AsyncWrite for Substream {
         if let Err(e) = self.as_mut().check_closed(cx) {
             return Poll::Ready(Err(e));
         }
+        // an empty message would be read as nothing at all, so there's no need to send one
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // fragments of earlier writes go out first, so that payloads aren't interleaved
+        ready!(self.poll_send_fragments(cx))?;
+
+        // wait for room in the outbound channel; this is how backpressure
+        // from the mixnet reaches the writer.
+        if ready!(self.outbound_tx.poll_ready(cx)).is_err() {
+            return Poll::Ready(Err(IoError::new(
+                ErrorKind::Other,
+                "poll_write outbound_tx error: channel closed",
+            )));
+        }
+
+        // a write is framed into a single message as it arrives, rather than
+        // buffered whole, so it holds no more than a message's worth in memory;
+        // write_all sends the rest a frame at a time. Only as much as the remote's
+        // window allows is written.
+        let len = buf.len().min(self.frame_size());
+        let len = ready!(self.poll_send_credit(cx, len));
+        let buf = &buf[..len];
+
+        let compressed = self
+            .compression
+            .map(|compression| compression.compress(buf));
+        let plaintext = compressed.as_deref().unwrap_or(buf);
+        let payload = match &self.cipher {
+            Some(cipher) => cipher
+                .encrypt(plaintext)
+                .map_err(|e| IoError::new(ErrorKind::Other, e.to_string()))?
+                .into(),
+            None => match compressed {
+                Some(compressed) => compressed,
+                None => Bytes::copy_from_slice(buf),
+            },
+        };
+
+        // only if max_fragment_size leaves no room for the overhead
+        if payload.len() > self.max_fragment_size {
+            let payload_id = self.next_payload_id;
+            self.next_payload_id = self.next_payload_id.wrapping_add(1);
+            let fragments = fragment(payload_id, &payload, self.max_fragment_size)
+                .map_err(|e| IoError::new(ErrorKind::InvalidInput, e.to_string()))?;
+            self.pending_fragments.extend(fragments);
+
+            // send as many fragments as fit now; the rest are sent by the
+            // next call to poll_write, poll_flush or poll_close.
+            if let Poll::Ready(Err(e)) = self.poll_send_fragments(cx) {
+                return Poll::Ready(Err(e));
+            }
+            return Poll::Ready(Ok(buf.len()));
+        }
 
-        let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
+        let nonce = self
+            .message_nonce
+            .next()
+            .map_err(|e| IoError::new(ErrorKind::Other, e))?;
 
         self.outbound_tx
-            .send(OutboundMessage {
+            .try_send(OutboundMessage {
                 recipient: self.remote_recipient,
                 message: Message::TransportMessage(TransportMessage {
                     nonce,
                     id: self.connection_id.clone(),
-                    message: SubstreamMessage::new_with_data(
-                        self.substream_id.clone(),
-                        buf.to_vec(),
-                    ),
+                    message: SubstreamMessage::new_with_data(self.substream_id.clone(), payload),
+                    timestamp: None,
                 }),
                 sender_tag: self.sender_tag.clone(),
             })
@@ -211,24 +627,46 @@ impl AsyncWrite for Substream {
         Poll::Ready(Ok(buf.len()))
     }
 
-    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
-        let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        // closing a closed substream again is fine, unless it failed
+        if let Err(e) = self.recv_close() {
+            return Poll::Ready(Err(e));
+        }
+        if *self.closed.lock() {
+            return Poll::Ready(Ok(()));
+        }
 
-        let mut closed = self.closed.lock();
-        if *closed {
-            return Poll::Ready(Err(IoError::new(ErrorKind::Other, "stream closed")));
+        ready!(self.poll_send_fragments(cx))?;
+
+        if ready!(self.outbound_tx.poll_ready(cx)).is_err() {
+            return Poll::Ready(Err(IoError::new(
+                ErrorKind::Other,
+                "poll_close outbound_rx error: channel closed",
+            )));
         }
 
+        let mut closed = self.closed.lock();
         *closed = true;
 
+        // the connection may have already sent a Close for us, e.g. on transport shutdown
+        if !self.open_substreams.lock().remove(&self.substream_id) {
+            return Poll::Ready(Ok(()));
+        }
+
+        let nonce = self
+            .message_nonce
+            .next()
+            .map_err(|e| IoError::new(ErrorKind::Other, e))?;
+
         // send a close message to the mixnet
         self.outbound_tx
-            .send(OutboundMessage {
+            .try_send(OutboundMessage {
                 recipient: self.remote_recipient,
                 message: Message::TransportMessage(TransportMessage {
                     nonce,
                     id: self.connection_id.clone(),
                     message: SubstreamMessage::new_close(self.substream_id.clone()),
+                    timestamp: None,
                 }),
                 sender_tag: self.sender_tag.clone(),
             })
@@ -242,31 +680,42 @@ impl AsyncWrite for Substream {
         Poll::Ready(Ok(()))
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
-        if let Err(e) = self.check_closed(cx) {
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        if let Err(e) = self.as_mut().check_closed(cx) {
             return Poll::Ready(Err(e));
         }
 
-        Poll::Ready(Ok(()))
+        self.poll_send_fragments(cx)
     }
 }
 
 #[cfg(test)]
 mod test {
+    use super::super::channel::{bounded, BoundedReceiver};
+    use super::super::config::{NymTransportConfig, OverflowPolicy};
+    use super::super::datagram::DatagramRouter;
+    use super::super::events::EventSender;
     use super::super::message::{
-        ConnectionId, Message, SubstreamId, SubstreamMessage, TransportMessage,
+        ConnectionId, Message, OutboundMessage, SubstreamId, SubstreamMessage,
+        SubstreamMessageType, TransportMessage,
     };
+    use super::super::migration::MigrationTable;
     use super::super::mixnet::initialize_mixnet;
-    use super::Substream;
-    use futures::{AsyncReadExt, AsyncWriteExt};
-    use nym_sdk::mixnet::MixnetClient;
+    use super::super::nonce::{Nonce, NonceCounter};
+    use super::super::relay::RelayTable;
+    use super::super::rtt::RttTable;
+    use super::super::stats::StatsTable;
+    use super::super::transport::connect_test_client;
+    use super::{Compression, ReceiveBuffer, SendWindow, Substream};
+    use bytes::Bytes;
+    use futures::{AsyncReadExt, AsyncWriteExt, FutureExt};
     use nym_sphinx::addressing::clients::Recipient;
-    use std::sync::atomic::AtomicU64;
+    use parking_lot::Mutex;
     use std::sync::Arc;
 
     #[tokio::test]
     async fn test_substream_poll_read_unread_data() {
-        let (outbound_tx, _) = tokio::sync::mpsc::unbounded_channel();
+        let (outbound_tx, _) = bounded(1, OverflowPolicy::Backpressure);
         let connection_id = ConnectionId::generate();
         let substream_id = SubstreamId::generate();
 
@@ -280,12 +729,12 @@ mod test {
             inbound_rx,
             outbound_tx,
             close_rx,
-            Arc::new(AtomicU64::new(1)),
+            NonceCounter::new(),
         );
 
         // test writing and reading w/ same length data
         let data = b"hello".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx.send(data.clone().into()).unwrap();
         let mut buf = [0u8; 5];
         let read_len = substream.read(&mut buf).await.unwrap();
         assert_eq!(read_len, data.len());
@@ -293,7 +742,7 @@ mod test {
 
         // test writing data longer than read buffer
         let data = b"nootwashere".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx.send(data.clone().into()).unwrap();
 
         let mut buf = [0u8; 4];
         let read_len = substream.read(&mut buf).await.unwrap();
@@ -307,7 +756,7 @@ mod test {
 
         // test read buffer larger than written data
         let data = b"nootwashere".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx.send(data.clone().into()).unwrap();
         let mut buf = [0u8; 16];
         let read_len = substream.read(&mut buf).await.unwrap();
         assert_eq!(read_len, data.len());
@@ -316,7 +765,7 @@ mod test {
 
         // test writing data longer than read buffer multiple times
         let data = b"nootwashere".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx.send(data.clone().into()).unwrap();
 
         let mut buf = [0u8; 4];
         let read_len = substream.read(&mut buf).await.unwrap();
@@ -324,7 +773,7 @@ mod test {
         assert_eq!(buf.to_vec(), b"noot".to_vec());
 
         let data = b"asdf".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx.send(data.clone().into()).unwrap();
 
         let mut buf = [0u8; 4];
         let read_len = substream.read(&mut buf).await.unwrap();
@@ -337,11 +786,157 @@ mod test {
         assert_eq!(buf[..7], b"ereasdf".to_vec());
     }
 
+    #[tokio::test]
+    async fn test_substream_receive_buffer() {
+        let (outbound_tx, _) = bounded(1, OverflowPolicy::Backpressure);
+        let (inbound_tx, inbound_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_, close_rx) = tokio::sync::oneshot::channel();
+        let buffer = Arc::new(Mutex::new(ReceiveBuffer::default()));
+
+        let mut substream = Substream::new(
+            None,
+            ConnectionId::generate(),
+            SubstreamId::generate(),
+            inbound_rx,
+            outbound_tx,
+            close_rx,
+            NonceCounter::new(),
+        )
+        .with_receive_buffer(buffer.clone());
+
+        // the connection counts what it hands over, up to the limits
+        assert!(buffer.lock().push(6, 10, 2));
+        inbound_tx.send(Bytes::from_static(b"noot w")).unwrap();
+        assert!(!buffer.lock().push(6, 10, 2));
+        assert!(buffer.lock().push(4, 10, 2));
+        inbound_tx.send(Bytes::from_static(b"ashe")).unwrap();
+        assert!(!buffer.lock().push(0, 10, 2));
+
+        // and reading takes it off again; what's left over from one frame is
+        // read along with the next
+        let mut buf = [0u8; 4];
+        substream.read_exact(&mut buf).await.unwrap();
+        assert_eq!((buffer.lock().bytes, buffer.lock().frames), (6, 1));
+        let mut buf = [0u8; 8];
+        assert_eq!(substream.read(&mut buf).await.unwrap(), 6);
+        assert_eq!(&buf[..6], b" washe");
+        assert_eq!((buffer.lock().bytes, buffer.lock().frames), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_substream_flow_control() {
+        let (outbound_tx, mut outbound_rx) = bounded(16, OverflowPolicy::Backpressure);
+        let (inbound_tx, inbound_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_, close_rx) = tokio::sync::oneshot::channel();
+        let send_window = Arc::new(Mutex::new(SendWindow::new(4)));
+
+        let mut substream = Substream::new(
+            Some(Recipient::try_from_base58_string("D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN").unwrap()),
+            ConnectionId::generate(),
+            SubstreamId::generate(),
+            inbound_rx,
+            outbound_tx,
+            close_rx,
+            NonceCounter::new(),
+        )
+        .with_flow_control(send_window.clone(), 8);
+
+        fn sent_message_type(
+            outbound_rx: &mut BoundedReceiver<OutboundMessage>,
+        ) -> SubstreamMessageType {
+            match outbound_rx.try_recv().unwrap().message {
+                Message::TransportMessage(TransportMessage {
+                    message: SubstreamMessage { message_type, .. },
+                    ..
+                }) => message_type,
+                msg => panic!("expected TransportMessage, got {:?}", msg),
+            }
+        }
+
+        // only as much as the window allows is written, then writes wait
+        assert_eq!(substream.write(b"nootwashere").await.unwrap(), 4);
+        assert!(
+            matches!(sent_message_type(&mut outbound_rx), SubstreamMessageType::Data(data) if data.len() == 4)
+        );
+        assert!(substream.write(b"ashere").now_or_never().is_none());
+
+        send_window.lock().raise(10);
+        assert_eq!(substream.write(b"washere").await.unwrap(), 6);
+        assert!(
+            matches!(sent_message_type(&mut outbound_rx), SubstreamMessageType::Data(data) if data.len() == 6)
+        );
+
+        // reading through half of our window sends the remote a larger one
+        inbound_tx.send(Bytes::from_static(b"hello")).unwrap();
+        let mut buf = [0u8; 5];
+        substream.read_exact(&mut buf).await.unwrap();
+        assert!(matches!(
+            sent_message_type(&mut outbound_rx),
+            SubstreamMessageType::WindowUpdate(13)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_substream_streams_writes_in_frames() {
+        let (outbound_tx, mut outbound_rx) = bounded(64, OverflowPolicy::Backpressure);
+        let (_, inbound_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_, close_rx) = tokio::sync::oneshot::channel();
+        let compression = Compression::new(usize::MAX);
+
+        let mut substream = Substream::new(
+            None,
+            ConnectionId::generate(),
+            SubstreamId::generate(),
+            inbound_rx,
+            outbound_tx,
+            close_rx,
+            NonceCounter::new(),
+        )
+        .with_max_fragment_size(64)
+        .with_compression(compression);
+
+        // a write takes only what fits in one message, flag and all
+        let payload: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        assert_eq!(substream.write(&payload).await.unwrap(), 63);
+        substream.write_all(&payload[63..]).await.unwrap();
+
+        // and the rest is sent a frame at a time, without fragmenting any of it
+        let mut received = vec![];
+        while let Some(msg) = outbound_rx.try_recv() {
+            match msg.message {
+                Message::TransportMessage(TransportMessage {
+                    message:
+                        SubstreamMessage {
+                            message_type: SubstreamMessageType::Data(data),
+                            ..
+                        },
+                    ..
+                }) => {
+                    assert!(data.len() <= 64);
+                    received.extend_from_slice(&compression.decompress(data, 64).unwrap());
+                }
+                msg => panic!("expected Data, got {:?}", msg),
+            }
+        }
+        assert_eq!(received, payload);
+    }
+
     #[tokio::test]
     async fn test_substream_read_write() {
-        let client = MixnetClient::connect_new().await.unwrap();
-        let (self_address, mut mixnet_inbound_rx, outbound_tx) =
-            initialize_mixnet(client, None).await.unwrap();
+        let client = connect_test_client().await;
+        let (self_address, mut mixnet_inbound_rx, outbound_tx, _mixnet_task) = initialize_mixnet(
+            client,
+            None,
+            EventSender::default(),
+            DatagramRouter::default(),
+            RttTable::default(),
+            StatsTable::default(),
+            MigrationTable::default(),
+            RelayTable::default(),
+            &NymTransportConfig::default(),
+        )
+        .await
+        .unwrap();
 
         const MSG_INNER: &[u8] = "hello".as_bytes();
         let connection_id = ConnectionId::generate();
@@ -357,7 +952,7 @@ mod test {
             inbound_rx,
             outbound_tx,
             close_rx,
-            Arc::new(AtomicU64::new(1)),
+            NonceCounter::new(),
         );
 
         // send message to ourselves over the mixnet
@@ -374,8 +969,9 @@ mod test {
                         substream_id: _,
                         message_type: msg,
                     },
+                timestamp: _,
             }) => {
-                assert_eq!(nonce, 1);
+                assert_eq!(nonce, Nonce::FIRST);
                 match msg {
                     super::super::message::SubstreamMessageType::Data(data) => {
                         assert_eq!(data, MSG_INNER);
@@ -393,12 +989,17 @@ mod test {
         substream.read_exact(&mut buf).await.unwrap();
         assert_eq!(buf, MSG_INNER);
 
-        // close substream
+        // close our half of the substream; writes fail, and closing it again is fine
         substream.close().await.unwrap();
-
-        // try to read/write to closed substream; should error
         substream.write_all(MSG_INNER).await.unwrap_err();
-        substream.read_exact(&mut buf).await.unwrap_err();
+        substream.close().await.unwrap();
+
+        // but the remote can still send until it closes its half, which reads as EOF
+        inbound_tx.send(Bytes::from_static(MSG_INNER)).unwrap();
+        drop(inbound_tx);
+        let mut data = vec![];
+        substream.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, MSG_INNER);
 
         // assert a close message was sent over the mixnet
         let recv_msg = mixnet_inbound_rx.recv().await.unwrap();
@@ -411,6 +1012,7 @@ mod test {
                         substream_id: _,
                         message_type: msg,
                     },
+                timestamp: _,
             }) => match msg {
                 super::super::message::SubstreamMessageType::Close => {}
                 _ => panic!("unexpected message type"),
@@ -421,8 +1023,20 @@ mod test {
 
     #[tokio::test]
     async fn test_substream_recv_close() {
-        let client = MixnetClient::connect_new().await.unwrap();
-        let (self_address, _, outbound_tx) = initialize_mixnet(client, None).await.unwrap();
+        let client = connect_test_client().await;
+        let (self_address, _, outbound_tx, _mixnet_task) = initialize_mixnet(
+            client,
+            None,
+            EventSender::default(),
+            DatagramRouter::default(),
+            RttTable::default(),
+            StatsTable::default(),
+            MigrationTable::default(),
+            RelayTable::default(),
+            &NymTransportConfig::default(),
+        )
+        .await
+        .unwrap();
 
         const MSG_INNER: &[u8] = "hello".as_bytes();
         let connection_id = ConnectionId::generate();
@@ -438,11 +1052,11 @@ mod test {
             inbound_rx,
             outbound_tx,
             close_rx,
-            Arc::new(AtomicU64::new(1)),
+            NonceCounter::new(),
         );
 
         // close substream
-        close_tx.send(()).unwrap();
+        close_tx.send(None).unwrap();
 
         // try to read/write to closed substream; should error
         substream.write_all(MSG_INNER).await.unwrap_err();
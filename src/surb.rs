@@ -0,0 +1,296 @@
+use libp2p_identity::PeerId;
+use log::debug;
+use nym_sphinx::addressing::clients::Recipient;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use super::config::SurbConfig;
+use super::message::ConnectionId;
+use super::runtime::Instant;
+
+/// SurbBudget estimates how many of our reply SURBs each remote peer still holds,
+/// so that we can attach more to our messages before the peer runs out.
+/// Every message we send to a nym address carries some SURBs, and every message
+/// a peer sends back to us over a connection we dialed uses up at least one.
+/// The estimate can only be a lower bound on usage, since a large reply may be
+/// split into several packets, each consuming its own SURB.
+pub(crate) struct SurbBudget {
+    config: SurbConfig,
+
+    /// remote peer -> estimated number of SURBs it holds
+    remaining: HashMap<Recipient, u64>,
+
+    /// connection ID -> remote peer, for connections we've sent messages
+    /// to a nym address on; used to attribute replies to a peer
+    connections: HashMap<ConnectionId, Recipient>,
+
+    /// connections on which the dialer exposed its nym address; neither side
+    /// sends SURBs on these, since replies go to the exposed address.
+    exposed: HashSet<ConnectionId>,
+}
+
+impl SurbBudget {
+    pub(crate) fn new(config: SurbConfig) -> Self {
+        SurbBudget {
+            config,
+            remaining: HashMap::new(),
+            connections: HashMap::new(),
+            exposed: HashSet::new(),
+        }
+    }
+
+    /// remaining returns the estimated number of our SURBs held by each recipient.
+    pub(crate) fn remaining(&self) -> impl Iterator<Item = (&Recipient, u64)> {
+        self.remaining
+            .iter()
+            .map(|(recipient, remaining)| (recipient, *remaining))
+    }
+
+    /// on_send records a message sent to `recipient` on the given connection and
+    /// returns the number of SURBs to attach to it.
+    pub(crate) fn on_send(&mut self, recipient: Recipient, id: &ConnectionId) -> u32 {
+        self.connections.entry(id.clone()).or_insert(recipient);
+
+        let remaining = self.remaining.entry(recipient).or_insert(0);
+        let count = if *remaining < self.config.replenish_threshold as u64 {
+            debug!(
+                "replenishing SURBs for {}: ~{} remaining, sending {}",
+                recipient, remaining, self.config.replenish_count
+            );
+            self.config.replenish_count.max(self.config.per_message)
+        } else {
+            self.config.per_message
+        };
+
+        *remaining = remaining.saturating_add(count as u64);
+        count
+    }
+
+    /// on_dial records the ConnectionRequest of a dial to `recipient`, and returns the
+    /// number of SURBs to attach to it, which is at least the configured initial count.
+    pub(crate) fn on_dial(&mut self, recipient: Recipient, id: &ConnectionId) -> u32 {
+        let count = self.on_send(recipient, id);
+        let extra = self.config.initial_count.saturating_sub(count);
+        if let Some(remaining) = self.remaining.get_mut(&recipient) {
+            *remaining = remaining.saturating_add(extra as u64);
+        }
+        count + extra
+    }
+
+    /// low_remaining returns the estimated number of SURBs held by `recipient` if
+    /// we've sent it SURBs before and it's running low, so that the next message replenishes them.
+    pub(crate) fn low_remaining(&self, recipient: &Recipient) -> Option<u64> {
+        self.remaining
+            .get(recipient)
+            .copied()
+            .filter(|remaining| *remaining < self.config.replenish_threshold as u64)
+    }
+
+    /// total_remaining returns the estimated number of our SURBs held across all peers.
+    pub(crate) fn total_remaining(&self) -> u64 {
+        self.remaining.values().sum()
+    }
+
+    /// expose_self_address records that the dialer of the given connection sent its nym address.
+    pub(crate) fn expose_self_address(&mut self, id: &ConnectionId) {
+        self.exposed.insert(id.clone());
+    }
+
+    pub(crate) fn is_exposed(&self, id: &ConnectionId) -> bool {
+        self.exposed.contains(id)
+    }
+
+    /// remove forgets connection `id` once it's closed, along with the estimate for its
+    /// remote, unless we still send SURBs to it on another connection.
+    pub(crate) fn remove(&mut self, id: &ConnectionId) {
+        self.exposed.remove(id);
+        let Some(recipient) = self.connections.remove(id) else {
+            return;
+        };
+        if !self.connections.values().any(|other| *other == recipient) {
+            self.remaining.remove(&recipient);
+        }
+    }
+
+    /// on_reply records a reply received over the given connection, which the
+    /// remote peer must have sent using one of our SURBs.
+    pub(crate) fn on_reply(&mut self, id: &ConnectionId) {
+        let Some(recipient) = self.connections.get(id) else {
+            return;
+        };
+
+        if let Some(remaining) = self.remaining.get_mut(recipient) {
+            *remaining = remaining.saturating_sub(1);
+        }
+    }
+}
+
+/// ReplyRoutes tracks when we last received SURBs on each connection we can only
+/// reply to through them, so that the application can be told before they expire.
+#[derive(Debug, Default)]
+pub(crate) struct ReplyRoutes {
+    routes: HashMap<ConnectionId, ReplyRoute>,
+}
+
+#[derive(Debug)]
+struct ReplyRoute {
+    peer_id: PeerId,
+    refreshed: Instant,
+    /// whether the route has been reported as expiring since it was last refreshed.
+    reported: bool,
+}
+
+impl ReplyRoutes {
+    /// track starts tracking the reply route of connection `id` to `peer_id`, whose
+    /// SURBs were received at `now`.
+    pub(crate) fn track(&mut self, id: ConnectionId, peer_id: PeerId, now: Instant) {
+        self.routes.insert(
+            id,
+            ReplyRoute {
+                peer_id,
+                refreshed: now,
+                reported: false,
+            },
+        );
+    }
+
+    /// refresh records that the remote of connection `id` sent us more SURBs, if its
+    /// reply route is tracked.
+    pub(crate) fn refresh(&mut self, id: &ConnectionId, now: Instant) {
+        if let Some(route) = self.routes.get_mut(id) {
+            route.refreshed = now;
+            route.reported = false;
+        }
+    }
+
+    pub(crate) fn remove(&mut self, id: &ConnectionId) {
+        self.routes.remove(id);
+    }
+
+    /// expiring returns the peers and ages of the routes that have grown older than
+    /// `max_age` since they were last refreshed. Each route is returned only once.
+    pub(crate) fn expiring(&mut self, max_age: Duration, now: Instant) -> Vec<(PeerId, Duration)> {
+        self.routes
+            .values_mut()
+            .filter_map(|route| {
+                let age = now.saturating_duration_since(route.refreshed);
+                if route.reported || age < max_age {
+                    return None;
+                }
+                route.reported = true;
+                Some((route.peer_id, age))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    const RECIPIENT: &str = "Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR";
+
+    #[test]
+    fn test_surb_budget_replenish() {
+        let recipient = Recipient::from_str(RECIPIENT).unwrap();
+        let id = ConnectionId::generate();
+        let mut budget = SurbBudget::new(SurbConfig {
+            per_message: 2,
+            replenish_threshold: 4,
+            replenish_count: 10,
+            initial_count: 0,
+        });
+
+        // the peer starts out with no SURBs, so the first message replenishes them
+        assert_eq!(budget.on_send(recipient, &id), 10);
+        assert_eq!(budget.on_send(recipient, &id), 2);
+
+        // 12 sent; after 9 replies the peer has ~3 left, which is below the threshold
+        for _ in 0..9 {
+            budget.on_reply(&id);
+        }
+        assert_eq!(budget.on_send(recipient, &id), 10);
+        assert_eq!(budget.on_send(recipient, &id), 2);
+
+        // replies on unknown connections are ignored
+        budget.on_reply(&ConnectionId::generate());
+        assert_eq!(budget.on_send(recipient, &id), 2);
+    }
+
+    #[test]
+    fn test_surb_budget_initial_count() {
+        let recipient = Recipient::from_str(RECIPIENT).unwrap();
+        let mut budget = SurbBudget::new(SurbConfig {
+            per_message: 2,
+            replenish_threshold: 4,
+            replenish_count: 10,
+            initial_count: 30,
+        });
+
+        // a dial sends at least the initial count, even to a peer with SURBs left
+        assert_eq!(budget.on_dial(recipient, &ConnectionId::generate()), 30);
+        assert_eq!(budget.on_dial(recipient, &ConnectionId::generate()), 30);
+        assert_eq!(budget.total_remaining(), 60);
+        assert_eq!(budget.on_send(recipient, &ConnectionId::generate()), 2);
+    }
+
+    #[test]
+    fn test_surb_budget_remove() {
+        let recipient = Recipient::from_str(RECIPIENT).unwrap();
+        let mut budget = SurbBudget::new(SurbConfig {
+            per_message: 2,
+            replenish_threshold: 4,
+            replenish_count: 10,
+            initial_count: 30,
+        });
+        let (first, second) = (ConnectionId::generate(), ConnectionId::generate());
+        budget.on_dial(recipient, &first);
+        budget.on_dial(recipient, &second);
+        budget.expose_self_address(&first);
+
+        // the remote's estimate outlives the first of its connections
+        budget.remove(&first);
+        assert!(!budget.is_exposed(&first));
+        assert_eq!(budget.total_remaining(), 60);
+        budget.on_reply(&first);
+        assert_eq!(budget.total_remaining(), 60);
+
+        // but not the last
+        budget.remove(&second);
+        assert_eq!(budget.total_remaining(), 0);
+        assert!(budget.connections.is_empty());
+        assert_eq!(budget.remaining().count(), 0);
+    }
+
+    #[test]
+    fn test_reply_routes_expiring() {
+        let max_age = Duration::from_secs(60);
+        let mut routes = ReplyRoutes::default();
+        let id = ConnectionId::generate();
+        let peer_id = PeerId::random();
+        let start = Instant::now();
+        routes.track(id.clone(), peer_id, start);
+        assert!(routes.expiring(max_age, start + max_age / 2).is_empty());
+
+        // refreshing the route restarts its clock
+        routes.refresh(&id, start + max_age / 2);
+        assert!(routes.expiring(max_age, start + max_age).is_empty());
+
+        let now = start + max_age * 2;
+        assert_eq!(
+            routes.expiring(max_age, now),
+            vec![(peer_id, max_age * 3 / 2)]
+        );
+        // it's only reported once, until it's refreshed
+        assert!(routes.expiring(max_age, now).is_empty());
+        routes.refresh(&id, now);
+        assert_eq!(
+            routes.expiring(max_age, now + max_age),
+            vec![(peer_id, max_age)]
+        );
+
+        routes.remove(&id);
+        assert!(routes.expiring(max_age, now + max_age * 2).is_empty());
+    }
+}
@@ -0,0 +1,195 @@
+//! Helpers shared by the tests that run transports and swarms over the in-memory mixnet.
+
+use futures::future::poll_fn;
+use futures::{pin_mut, FutureExt};
+use libp2p::core::{
+    transport::{DialOpts, PortUse, TransportEvent},
+    Endpoint, Multiaddr, Transport,
+};
+use libp2p::swarm::NetworkBehaviour;
+use libp2p::{Swarm, SwarmBuilder};
+use libp2p_identity::{Keypair, PeerId};
+use nym_sphinx::receiver::ReconstructedMessage;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use super::backend::MixnetBackend;
+use super::config::NymTransportConfig;
+use super::connection::Connection;
+use super::handshake::Handshake;
+use super::memory::{InMemoryClient, InMemoryMixnet};
+use super::message::{
+    Capabilities, ConnectionId, ConnectionInfo, ConnectionMessage, MIN_MAX_MESSAGE_SIZE,
+};
+use super::presets;
+use super::runtime::sleep;
+use super::transport::NymTransport;
+
+/// transport returns a transport with a new identity over a new client of `mixnet`.
+pub(crate) async fn transport(mixnet: &InMemoryMixnet, config: NymTransportConfig) -> NymTransport {
+    NymTransport::new_with_backend(mixnet.client(), Keypair::generate_ed25519(), config)
+        .await
+        .unwrap()
+}
+
+/// dial_opts are the options of an ordinary dial, made as the dialer.
+pub(crate) fn dial_opts() -> DialOpts {
+    DialOpts {
+        role: Endpoint::Dialer,
+        port_use: PortUse::Reuse,
+    }
+}
+
+/// next_event polls `transport` until it reports an event.
+pub(crate) async fn next_event<T: Transport + Unpin>(
+    transport: &mut T,
+) -> TransportEvent<T::ListenerUpgrade, T::Error> {
+    poll_fn(|cx| Pin::new(&mut *transport).poll(cx)).await
+}
+
+/// accept polls `listener` until it reports an inbound connection, and returns its upgrade.
+pub(crate) async fn accept<T: Transport + Unpin>(listener: &mut T) -> T::ListenerUpgrade {
+    loop {
+        if let TransportEvent::Incoming { upgrade, .. } = next_event(listener).await {
+            return upgrade;
+        }
+    }
+}
+
+/// spawn_driven keeps `transport` handling inbound mixnet messages for its connections
+/// on a task of its own, for tests that are done with its events.
+pub(crate) fn spawn_driven<T>(mut transport: T)
+where
+    T: Transport + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            next_event(&mut transport).await;
+        }
+    });
+}
+
+/// connect dials `listener` from `dialer` at `addr`, polling both until the dial has
+/// completed and the listener has accepted it, and returns the connection at each end.
+pub(crate) async fn connect(
+    dialer: &mut NymTransport,
+    listener: &mut NymTransport,
+    addr: Multiaddr,
+) -> ((PeerId, Connection), (PeerId, Connection)) {
+    let mut dial = dialer.dial(addr, dial_opts()).unwrap();
+    let (mut dialed, mut accepted) = (None, None);
+    while dialed.is_none() || accepted.is_none() {
+        tokio::select! {
+            res = &mut dial, if dialed.is_none() => dialed = Some(res.unwrap()),
+            _ = next_event(&mut *dialer) => {}
+            event = next_event(&mut *listener) => {
+                if let TransportEvent::Incoming { upgrade, .. } = event {
+                    accepted = Some(upgrade.await.unwrap());
+                }
+            }
+        }
+    }
+    (dialed.unwrap(), accepted.unwrap())
+}
+
+/// drive polls `transports` for `duration`, ignoring their events.
+pub(crate) async fn drive(transports: &mut [&mut NymTransport], duration: Duration) {
+    let deadline = sleep(duration);
+    pin_mut!(deadline);
+    poll_fn(|cx| {
+        for transport in transports.iter_mut() {
+            while Pin::new(&mut **transport).poll(cx).is_ready() {}
+        }
+        deadline.as_mut().poll(cx)
+    })
+    .await
+}
+
+/// poll_incoming polls `listener` for `duration`, and returns the number of inbound
+/// connections it handed over.
+pub(crate) async fn poll_incoming(listener: &mut NymTransport, duration: Duration) -> usize {
+    let timeout = sleep(duration);
+    pin_mut!(timeout);
+    let mut incoming = 0;
+    loop {
+        tokio::select! {
+            _ = &mut timeout => return incoming,
+            event = next_event(&mut *listener) => {
+                if let TransportEvent::Incoming { .. } = event {
+                    incoming += 1;
+                }
+            }
+        }
+    }
+}
+
+/// recv_all returns every message `client` has received, once the messages still on
+/// their way have had time to arrive.
+pub(crate) async fn recv_all(client: &mut InMemoryClient) -> Vec<ReconstructedMessage> {
+    // long enough for every delayed message to arrive
+    sleep(Duration::from_millis(100)).await;
+    let mut messages = vec![];
+    while let Some(Some(message)) = client.next().now_or_never() {
+        messages.push(message);
+    }
+    messages
+}
+
+/// connection_request returns a ConnectionRequest for connection `id` signed by `key`,
+/// as sent by a dialer which doesn't expose its address, for tests that write raw
+/// messages to a transport.
+pub(crate) fn connection_request(key: &Keypair, id: &ConnectionId) -> ConnectionMessage {
+    ConnectionMessage {
+        peer_id: key.public().to_peer_id(),
+        id: id.clone(),
+        capabilities: Capabilities::default(),
+        recipient: None,
+        handshake: Handshake::new(key, id, None).unwrap().payload(),
+        challenge: None,
+        observed: None,
+        max_message_size: MIN_MAX_MESSAGE_SIZE,
+        service: None,
+        info: ConnectionInfo::default(),
+    }
+}
+
+/// swarm returns a swarm running `behaviour` over a transport on `mixnet`, and the
+/// address it listens on.
+pub(crate) async fn swarm<B: NetworkBehaviour>(
+    mixnet: &InMemoryMixnet,
+    behaviour: impl FnOnce(&Keypair) -> B,
+) -> (Swarm<B>, Multiaddr) {
+    swarm_with_config(mixnet, NymTransportConfig::default(), behaviour).await
+}
+
+/// swarm_with_config returns a swarm like [`swarm`], whose transport uses `config`.
+pub(crate) async fn swarm_with_config<B: NetworkBehaviour>(
+    mixnet: &InMemoryMixnet,
+    config: NymTransportConfig,
+    behaviour: impl FnOnce(&Keypair) -> B,
+) -> (Swarm<B>, Multiaddr) {
+    let keypair = Keypair::generate_ed25519();
+    let transport = NymTransport::new_with_backend(mixnet.client(), keypair.clone(), config)
+        .await
+        .unwrap();
+    let addr = transport.listen_addr().clone();
+    (swarm_with_transport(keypair, transport, behaviour), addr)
+}
+
+/// swarm_with_transport returns a swarm with the identity `keypair` running
+/// `behaviour` over `transport`.
+pub(crate) fn swarm_with_transport<B: NetworkBehaviour>(
+    keypair: Keypair,
+    transport: NymTransport,
+    behaviour: impl FnOnce(&Keypair) -> B,
+) -> Swarm<B> {
+    SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_other_transport(|_| transport)
+        .unwrap()
+        .with_behaviour(behaviour)
+        .unwrap()
+        .with_swarm_config(presets::swarm_config_for_nym)
+        .build()
+}
@@ -0,0 +1,132 @@
+use futures::future::BoxFuture;
+use nym_sdk::mixnet::{AnonymousSenderTag, IncludedSurbs};
+use nym_sphinx::addressing::clients::Recipient;
+use parking_lot::Mutex;
+use std::{sync::Arc, time::Duration};
+
+use super::backend::MixnetBackendSender;
+use super::bandwidth::packet_count;
+use super::config::ThrottleConfig;
+use super::error::Error;
+use super::limit::TokenBucket;
+use super::metrics::Metrics;
+use super::runtime::{sleep, Instant};
+
+struct State {
+    packets: TokenBucket,
+    bytes: TokenBucket,
+}
+
+/// Throttle paces writes to the mixnet to the configured packet and byte rates. Every
+/// write is charged when it's made, going into debt if it's beyond the rates, and the
+/// next outbound message isn't taken from the queue until the debt is repaid. Waiting
+/// before a message is taken, rather than once it's being written, means a wait that's
+/// cut short by the mixnet task loses nothing. It's shared by the senders of every client
+/// the mixnet task has had, so that a reconnect doesn't reset it.
+#[derive(Clone)]
+pub(crate) struct Throttle {
+    state: Arc<Mutex<State>>,
+    metrics: Metrics,
+}
+
+impl Throttle {
+    pub(crate) fn new(config: ThrottleConfig, metrics: Metrics) -> Self {
+        // a bucket that never refills would never repay its debt
+        let bucket = |rate: u32| {
+            let rate = rate.max(1);
+            let capacity = (rate as f64 * config.burst.as_secs_f64()).ceil().max(1.0);
+            TokenBucket::new(rate, capacity as u32)
+        };
+        Throttle {
+            state: Arc::new(Mutex::new(State {
+                packets: bucket(config.packets_per_second),
+                bytes: bucket(config.bytes_per_second),
+            })),
+            metrics,
+        }
+    }
+
+    /// charge charges a write of `len` bytes at time `now`.
+    pub(crate) fn charge(&self, len: usize, now: Instant) {
+        let mut state = self.state.lock();
+        state.packets.charge(now, packet_count(len) as f64);
+        state.bytes.charge(now, len as f64);
+    }
+
+    /// delay returns how long after `now` the writes charged so far fit within the rates.
+    pub(crate) fn delay(&self, now: Instant) -> Duration {
+        let mut state = self.state.lock();
+        std::cmp::max(state.packets.delay(now), state.bytes.delay(now))
+    }
+
+    /// ready waits until the writes charged so far fit within the rates.
+    pub(crate) async fn ready(&self) {
+        let delay = self.delay(Instant::now());
+        if delay.is_zero() {
+            return;
+        }
+        sleep(delay).await;
+        self.metrics.observe_throttle_time(delay);
+    }
+}
+
+/// ThrottledSender is a [`MixnetBackendSender`] which charges every message it writes
+/// against the throttle. It doesn't wait itself; see [`Throttle`].
+pub(crate) struct ThrottledSender {
+    inner: Box<dyn MixnetBackendSender>,
+    throttle: Throttle,
+}
+
+impl ThrottledSender {
+    pub(crate) fn new(inner: Box<dyn MixnetBackendSender>, throttle: Throttle) -> Self {
+        ThrottledSender { inner, throttle }
+    }
+}
+
+impl MixnetBackendSender for ThrottledSender {
+    fn send<'a>(
+        &'a self,
+        recipient: Recipient,
+        message: &'a [u8],
+        surbs: IncludedSurbs,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.throttle.charge(message.len(), Instant::now());
+        self.inner.send(recipient, message, surbs)
+    }
+
+    fn send_reply<'a>(
+        &'a self,
+        sender_tag: AnonymousSenderTag,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.throttle.charge(message.len(), Instant::now());
+        self.inner.send_reply(sender_tag, message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        // a burst of one packet, and plenty of bytes
+        let config = ThrottleConfig::new(4, 1_000_000).with_burst(Duration::from_millis(250));
+        let throttle = Throttle::new(config, Metrics::default());
+        let now = Instant::now();
+
+        throttle.charge(100, now);
+        assert_eq!(throttle.delay(now), Duration::ZERO);
+
+        // writes beyond the burst wait for the packet rate
+        throttle.charge(100, now);
+        throttle.charge(100, now);
+        assert_eq!(throttle.delay(now), Duration::from_millis(500));
+        let now = now + Duration::from_millis(500);
+        assert_eq!(throttle.delay(now), Duration::ZERO);
+
+        // and large messages count every packet they're sent in
+        throttle.charge(5000, now);
+        assert_eq!(throttle.delay(now), Duration::from_millis(750));
+    }
+}
@@ -1,44 +1,209 @@
-use futures::prelude::*;
+use futures::{future::BoxFuture, prelude::*, ready, stream::FuturesUnordered};
 use libp2p::core::{
     multiaddr::{Multiaddr, Protocol},
     transport::{DialOpts, ListenerId, TransportError, TransportEvent},
     Transport,
 };
-use libp2p_identity::{Keypair, PeerId};
-use log::debug;
-use nym_sdk::mixnet::{AnonymousSenderTag, MixnetClient};
+use libp2p_identity::{Keypair, PeerId, PublicKey};
+use nym_credentials_interface::TicketType;
+use nym_sdk::mixnet::{AnonymousSenderTag, MixnetClient, MixnetClientBuilder, StoragePaths};
 use nym_sphinx::addressing::clients::Recipient;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    path::Path,
     pin::Pin,
-    str::FromStr,
-    task::{Context, Poll, Waker},
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
 };
-use tokio::{
-    sync::{
-        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        oneshot,
-    },
-    time::{timeout, Duration},
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot, Semaphore,
 };
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use tracing::info;
+use tracing::{debug, info, warn, Span};
 
-use super::connection::{Connection, PendingConnection};
+use super::address::{nym_address_to_multiaddr, NymMultiaddr};
+use super::address_book::AddressBook;
+use super::backend::MixnetBackend;
+use super::binding::SenderTagBindings;
+use super::channel::{BoundedReceiver, BoundedSender};
+use super::config::{AdmissionHook, AnonymityMode, Decision, NymTransportConfig};
+use super::connection::{Connection, ConnectionEvent, ConnectionHandle, PendingConnection};
+use super::datagram::{DatagramClient, DatagramRequests, DatagramRouter};
+use super::dedup::DuplicateFilter;
 use super::error::Error;
+use super::events::{DropReason, EventSender, NymEvent};
+use super::handshake::{
+    new_challenge, new_resume_nonce, proofs_match, sign_challenge, sign_relay_reservation,
+    verify_challenge, verify_migration, verify_relay_reservation, Handshake, ResumptionSecret,
+    Role, SessionCipher, CHALLENGE_LEN,
+};
+use super::limit::TokenBucket;
 use super::message::{
-    ConnectionId, ConnectionMessage, InboundMessage, Message, OutboundMessage, SubstreamMessage,
-    TransportMessage,
+    is_stale, negotiated_fragment_size, Capabilities, ChallengeResponseMessage,
+    ConnectionCloseMessage, ConnectionId, ConnectionMessage, Direction, InboundMessage,
+    KeepAliveMessage, KeepAliveType, Message, MigrateMessage, OutboundMessage, RelayHop,
+    RelayMessage, RelayReserveMessage, RelayStatus, RelayStatusMessage, ResumeRequestMessage,
+    ResumeResponseMessage, SessionTicketMessage, SubstreamMessageType, TransportMessage,
+    VersionMismatch, MIN_MAX_MESSAGE_SIZE, PROTOCOL_VERSION,
 };
-use super::mixnet::initialize_mixnet;
+use super::metrics::QueueDepths;
+use super::migration::MigrationTable;
+use super::mixnet::{initialize_mixnet, MixnetStatus, MixnetTask};
 use super::queue::MessageQueue;
-use super::DEFAULT_HANDSHAKE_TIMEOUT_SECS;
+use super::relay::{RelayServer, RelayTable};
+use super::replay::ReplayCache;
+use super::resumption::{SessionTicket, TicketKey};
+use super::rtt::RttTable;
+use super::runtime::{
+    interval_at, sleep, timeout, unix_millis, Instant, Interval, MissedTickBehavior,
+};
+use super::snapshot::{
+    ClientHealth, ConnectionSnapshot, MixnetSnapshot, SurbSnapshot, TransportSnapshot,
+};
+use super::state::{ConnectionTable, StateKind};
+use super::stats::{StatsTable, TransportStats};
+use super::surb::ReplyRoutes;
+
+pub use super::message::ConnectionInfo;
+
+/// The number of events buffered for each subscriber of [`NymTransport::events`].
+const EVENT_CAPACITY: usize = 256;
+
+/// The shortest interval at which message queues are checked for expired gaps.
+const MIN_GAP_CHECK_PERIOD: Duration = Duration::from_millis(100);
 
 /// InboundTransportEvent represents an inbound event from the mixnet.
+#[doc(hidden)]
 pub enum InboundTransportEvent {
-    ConnectionRequest(Upgrade),
+    /// carries the service the dialer asked for, if any, and the address to report the
+    /// dialer at (see [`TransportEvent::Incoming`]).
+    ConnectionRequest(Upgrade, Option<u16>, Multiaddr),
     ConnectionResponse,
     TransportMessage,
+    KeepAlive,
+    Ack,
+    Datagram,
+    ConnectionRejected,
+    /// a ConnectionRequest was handed to the admission hook.
+    ConnectionAdmitting,
+    /// a ConnectionRequest was accepted, and its dialer sent a challenge to answer
+    /// before the connection is handed to the swarm.
+    ConnectionChallenged,
+    ConnectionClose,
+    Migrate,
+    SessionTicket,
+    /// the remote answered our ResumeRequest.
+    ConnectionResumed,
+    /// a ConnectionRequest that came in through a relay we listen on: carries the
+    /// listener it's reported on, and the relayed address the dialer is reported at.
+    RelayedConnectionRequest(Upgrade, ListenerId, Multiaddr),
+    /// a relay reservation or relayed message was handled, as the relay or as one of
+    /// its peers.
+    Relay,
+    /// the message was dropped before it was handled, since it came with another sender
+    /// tag than its connection is bound to.
+    Dropped,
+}
+
+/// PendingChallenge is a connection we accepted or resumed, which is held back from
+/// the swarm until its dialer returns the challenge we sent it signed by the identity
+/// it dialed with. Until then, the messages it sends on the connection are queued.
+/// The challenge of a resumed connection is the nonce of our ResumeResponse.
+struct PendingChallenge {
+    challenge: [u8; CHALLENGE_LEN],
+    /// the identity key the dialer signed its half of the handshake with
+    identity: PublicKey,
+    upgrade: Upgrade,
+    send_back_addr: Multiaddr,
+    /// the relay the connection came in through, if it's relayed
+    relay: Option<Recipient>,
+    /// the service the dialer asked for, whose listener the connection is reported on
+    service: Option<u16>,
+    sent: Instant,
+    /// the session ticket issued to the dialer once it's answered, if any
+    ticket: Option<OutboundMessage>,
+}
+
+/// Remote is what a new connection is told about its remote.
+struct Remote<'a> {
+    peer_id: PeerId,
+    id: &'a ConnectionId,
+    capabilities: Capabilities,
+    /// the largest message the remote accepts
+    max_message_size: u32,
+    info: &'a ConnectionInfo,
+    /// the identity key the remote opened the connection with
+    identity: &'a PublicKey,
+}
+
+impl<'a> From<&'a ConnectionMessage> for Remote<'a> {
+    fn from(msg: &'a ConnectionMessage) -> Self {
+        Remote {
+            peer_id: msg.peer_id,
+            id: &msg.id,
+            capabilities: msg.capabilities,
+            max_message_size: msg.max_message_size,
+            info: &msg.info,
+            identity: msg.handshake.identity(),
+        }
+    }
+}
+
+/// Admission is an inbound ConnectionRequest the admission hook has decided on.
+type Admission = (Decision, ConnectionMessage, Option<AnonymousSenderTag>);
+
+/// Accepted is an inbound connection we accepted: the Connection, the token its Upgrade
+/// holds, the challenge its dialer has to answer, and the session ticket it's sent then.
+type Accepted = (
+    Connection,
+    Arc<()>,
+    [u8; CHALLENGE_LEN],
+    Option<OutboundMessage>,
+);
+
+/// DialClient is an additional mixnet client that connections we dial are sent through.
+/// It doesn't listen: connection requests sent to its nym address are dropped.
+struct DialClient {
+    /// the client's nym address, which remotes dialed through it reply to
+    address: Recipient,
+
+    /// inbound mixnet messages
+    inbound_stream: BoundedReceiver<InboundMessage>,
+
+    /// outbound mixnet messages
+    outbound_tx: BoundedSender<OutboundMessage>,
+
+    /// mixnet client status changes; dial clients aren't reconnected
+    status_rx: UnboundedReceiver<MixnetStatus>,
+
+    /// false once the client has disconnected, after which nothing is dialed through it
+    connected: bool,
+
+    /// the background task reading from and writing to the mixnet
+    mixnet_task: MixnetTask,
+}
+
+/// ServiceListener is a listener on a service of our nym address, which is only handed
+/// the connections dialed to `/nym/<address>/svc/<service>`.
+struct ServiceListener {
+    listener_id: ListenerId,
+    /// our address on the service; it changes along with our nym address
+    listen_addr: Multiaddr,
+}
+
+/// RelayListener is a listener on a relay's nym address rather than ours: a reservation
+/// with the relay, which forwards us the circuits that peers open to us through it.
+struct RelayListener {
+    listener_id: ListenerId,
+    relay: Recipient,
+    /// the address we're reachable at through the relay, `/nym/<relay>/p2p-circuit`
+    listen_addr: Multiaddr,
+    /// false until the relay accepts the reservation, after which the address is reported
+    reserved: bool,
+    /// when the reservation is next sent: halfway through its TTL once it's accepted,
+    /// and after the handshake timeout while the relay hasn't answered
+    renew_at: Instant,
 }
 
 /// NymTransport implements the Transport trait using the Nym mixnet.
@@ -46,26 +211,53 @@ pub struct NymTransport {
     /// our Nym address
     self_address: Recipient,
     pub(crate) listen_addr: Multiaddr,
-    pub(crate) listener_id: ListenerId,
+    /// open listeners, oldest first. Every listener listens on our nym address, and
+    /// inbound connections are reported on the oldest; with none open, they're rejected.
+    pub(crate) listeners: Vec<ListenerId>,
+    /// listeners on a service of our nym address, by service. Connection requests to a
+    /// service are only reported on its listener, and rejected without one.
+    service_listeners: HashMap<u16, ServiceListener>,
 
-    /// our libp2p keypair; currently not really used
+    /// our libp2p keypair; signs our half of the handshake on connections we accept
     keypair: Keypair,
 
-    /// established connections -> channel which sends messages received from
-    /// the mixnet to the corresponding Connection
-    connections: HashMap<ConnectionId, UnboundedSender<SubstreamMessage>>,
+    /// seals the session tickets we issue; None unless session tickets are enabled
+    ticket_key: Option<TicketKey>,
 
-    /// outbound pending dials
-    pending_dials: HashMap<ConnectionId, PendingConnection>,
+    /// state of every connection, dialed or accepted; open connections hold the
+    /// handle which sends messages received from the mixnet to the Connection
+    connections: ConnectionTable,
 
     /// connection message queues
     message_queues: HashMap<ConnectionId, MessageQueue>,
 
+    /// accepted connections whose dialer hasn't answered our challenge yet
+    challenges: HashMap<ConnectionId, PendingChallenge>,
+
+    /// when the ConnectionRequests of dials that haven't been answered yet were written
+    /// to the mixnet
+    sent_requests: HashMap<ConnectionId, Instant>,
+
+    /// inbound ConnectionRequests the admission hook is deciding on, and their ids
+    admissions: FuturesUnordered<BoxFuture<'static, Admission>>,
+    admitting: HashSet<ConnectionId>,
+
+    /// recently handled TransportMessages, whose duplicates are dropped
+    duplicates: DuplicateFilter,
+    /// recently answered ConnectionRequests, whose replays are dropped
+    replays: ReplayCache,
+
+    /// ticks whenever the message queues should be checked for expired gaps
+    gap_check: Interval,
+
+    /// rate-limits inbound ConnectionRequests
+    connection_requests: TokenBucket,
+
     /// inbound mixnet messages
-    inbound_stream: UnboundedReceiverStream<InboundMessage>,
+    inbound_stream: BoundedReceiver<InboundMessage>,
 
     /// outbound mixnet messages
-    outbound_tx: UnboundedSender<OutboundMessage>,
+    outbound_tx: BoundedSender<OutboundMessage>,
 
     /// inbound messages for Transport.poll()
     poll_rx: UnboundedReceiver<TransportEvent<Upgrade, Error>>,
@@ -73,17 +265,185 @@ pub struct NymTransport {
     /// outbound messages to Transport.poll()
     poll_tx: UnboundedSender<TransportEvent<Upgrade, Error>>,
 
-    waker: Option<Waker>,
+    /// mixnet client status changes (disconnects and reconnects)
+    mixnet_status_rx: UnboundedReceiver<MixnetStatus>,
+
+    /// the state of the main mixnet client's gateway connection
+    health: ClientHealth,
+
+    /// the epoch of the network topology the main mixnet client routes through, once known
+    topology_epoch: Option<u64>,
+
+    /// the background task reading from and writing to the mixnet;
+    /// None once the transport has been shut down
+    mixnet_task: Option<MixnetTask>,
+
+    /// additional mixnet clients which connections we dial without exposing our
+    /// address are spread across, along with the main client
+    dial_clients: Vec<DialClient>,
 
-    /// Timeout for the [`Upgrade`] future.
+    /// the client the next such dial is sent through; 0 is the main client,
+    /// and i the (i - 1)th dial client
+    next_dial_client: usize,
+
+    /// time allowed for the remote to answer our ConnectionRequest once it's written,
+    /// or our challenge
     handshake_timeout: Duration,
+
+    /// a permit for every dial that may be in progress; None if dials aren't limited
+    dial_slots: Option<Arc<Semaphore>>,
+
+    config: NymTransportConfig,
+
+    /// emits events to the streams returned by events()
+    events: EventSender,
+
+    /// routes responses to the clients returned by datagram_client()
+    datagrams: DatagramRouter,
+
+    /// inbound datagram requests; None once taken by datagram_requests()
+    datagram_requests: Option<DatagramRequests>,
+
+    /// round-trip time estimates of the connections, shared with the mixnet task
+    rtt: RttTable,
+
+    /// traffic statistics of the connections, counted by the mixnet tasks
+    stats: StatsTable,
+
+    /// nym addresses the remotes of connections moved to, which the mixnet tasks send to
+    migrations: MigrationTable,
+
+    /// circuits of the connections relayed to or from us, whose messages the mixnet
+    /// tasks wrap for their relay
+    relays: RelayTable,
+
+    /// the reservations and circuits we hold as a relay; None unless we relay
+    relay_server: Option<RelayServer>,
+
+    /// listeners on relays' addresses, by the ID of their reservation
+    relay_listeners: HashMap<ConnectionId, RelayListener>,
+
+    /// the number of times we've moved to a new nym address, which orders our
+    /// MigrateMessages
+    migration_seq: u64,
+
+    /// nym addresses of the peers we know, so that they can be dialed by PeerId
+    address_book: AddressBook,
+
+    /// age of the SURBs of connections we can only reply to through them
+    reply_routes: ReplyRoutes,
+
+    /// sender tags that the connections we can only reply to through SURBs are bound to
+    bindings: SenderTagBindings,
 }
 
 impl NymTransport {
     /// New transport.
     #[allow(unused)]
     pub async fn new(client: MixnetClient, keypair: Keypair) -> Result<Self, Error> {
-        Self::new_maybe_with_notify_inbound(client, keypair, None, None).await
+        Self::new_with_config(client, keypair, NymTransportConfig::default()).await
+    }
+
+    /// New transport with the given config.
+    pub async fn new_with_config(
+        client: MixnetClient,
+        keypair: Keypair,
+        config: NymTransportConfig,
+    ) -> Result<Self, Error> {
+        Self::new_with_backend(client, keypair, config).await
+    }
+
+    /// New transport which reads from and writes to the mixnet through `backend`
+    /// rather than a nym-sdk [`MixnetClient`], e.g. an in-memory mixnet in tests.
+    pub async fn new_with_backend(
+        backend: impl MixnetBackend,
+        keypair: Keypair,
+        config: NymTransportConfig,
+    ) -> Result<Self, Error> {
+        Self::new_maybe_with_timeout(backend, vec![], keypair, None, config).await
+    }
+
+    /// New transport which listens on `client`'s nym address, and spreads the
+    /// connections it dials without exposing that address across `client` and
+    /// `dial_clients`. Each dial client has its own nym address and gateway, so the
+    /// connections dialed through it can't be linked to the others, and don't share
+    /// their bandwidth.
+    pub async fn new_with_dial_clients(
+        client: MixnetClient,
+        dial_clients: Vec<MixnetClient>,
+        keypair: Keypair,
+        config: NymTransportConfig,
+    ) -> Result<Self, Error> {
+        Self::new_with_backends(client, dial_clients, keypair, config).await
+    }
+
+    /// New transport with dial clients (see [`NymTransport::new_with_dial_clients`])
+    /// which reads from and writes to the mixnet through the given backends.
+    pub async fn new_with_backends<B: MixnetBackend>(
+        backend: B,
+        dial_backends: Vec<B>,
+        keypair: Keypair,
+        config: NymTransportConfig,
+    ) -> Result<Self, Error> {
+        Self::new_maybe_with_timeout(backend, dial_backends, keypair, None, config).await
+    }
+
+    /// New transport with a mixnet client that keeps its keys and gateway registration
+    /// in the directory at `path`. If the directory already holds keys they're reused,
+    /// so the transport keeps the same nym address (and listen address) across restarts.
+    pub async fn new_from_storage(path: impl AsRef<Path>, keypair: Keypair) -> Result<Self, Error> {
+        Self::new_from_storage_with_config(path, keypair, NymTransportConfig::default()).await
+    }
+
+    /// New transport with persistent storage (see [`NymTransport::new_from_storage`])
+    /// and the given config. The config's gateway selection only applies the first time
+    /// the directory is used; afterwards the stored gateway registration is reused.
+    /// The config's dial clients are ephemeral.
+    pub async fn new_from_storage_with_config(
+        path: impl AsRef<Path>,
+        keypair: Keypair,
+        config: NymTransportConfig,
+    ) -> Result<Self, Error> {
+        let storage_paths =
+            StoragePaths::new_from_dir(path.as_ref()).map_err(Error::MixnetClientFailure)?;
+        let mut builder = MixnetClientBuilder::new_with_default_storage(storage_paths)
+            .await
+            .map_err(Error::MixnetClientFailure)?
+            .latency_based_selection(config.gateway.is_latency_based())
+            .debug_config(config.traffic.debug_config());
+        if let Some(gateway) = config.gateway.requested_gateway() {
+            builder = builder.request_gateway(gateway);
+        }
+        if config.credentials.is_some() {
+            builder = builder.enable_credentials_mode();
+        }
+        let client = builder.build().map_err(Error::MixnetClientFailure)?;
+        if let Some(mnemonic) = config.credentials_mnemonic() {
+            client
+                .create_bandwidth_client(mnemonic, TicketType::V1MixnetEntry)
+                .await
+                .map_err(Error::MixnetClientFailure)?
+                .acquire()
+                .await
+                .map_err(Error::MixnetClientFailure)?;
+        }
+        let client = client
+            .connect_to_mixnet()
+            .await
+            .map_err(Error::MixnetClientFailure)?;
+        let dial_clients = connect_dial_clients(&config).await?;
+        Self::new_with_dial_clients(client, dial_clients, keypair, config).await
+    }
+
+    /// New transport with an ephemeral mixnet client, which connects to the gateway
+    /// chosen by the config's gateway selection.
+    pub async fn new_ephemeral_with_config(
+        keypair: Keypair,
+        config: NymTransportConfig,
+    ) -> Result<Self, Error> {
+        let client = connect_ephemeral(&config).await?;
+        let dial_clients = connect_dial_clients(&config).await?;
+        Self::new_with_dial_clients(client, dial_clients, keypair, config).await
     }
 
     /// New transport with a timeout.
@@ -93,7 +453,14 @@ impl NymTransport {
         keypair: Keypair,
         timeout: Duration,
     ) -> Result<Self, Error> {
-        Self::new_maybe_with_notify_inbound(client, keypair, None, Some(timeout)).await
+        Self::new_maybe_with_timeout(
+            client,
+            vec![],
+            keypair,
+            Some(timeout),
+            NymTransportConfig::default(),
+        )
+        .await
     }
 
     /// Add timeout to transport and return self.
@@ -103,44 +470,152 @@ impl NymTransport {
         self
     }
 
-    async fn new_maybe_with_notify_inbound(
-        client: MixnetClient,
+    async fn new_maybe_with_timeout<B: MixnetBackend>(
+        client: B,
+        dial_backends: Vec<B>,
         keypair: Keypair,
-        notify_inbound_tx: Option<UnboundedSender<()>>,
         timeout: Option<Duration>,
+        config: NymTransportConfig,
     ) -> Result<Self, Error> {
-        let (self_address, inbound_rx, outbound_tx) =
-            initialize_mixnet(client, notify_inbound_tx).await?;
-        let listen_addr = nym_address_to_multiaddress(self_address)?;
+        let (mixnet_status_tx, mixnet_status_rx) = unbounded_channel();
+        let events = EventSender::new(EVENT_CAPACITY);
+        let (datagrams, datagram_requests_rx) =
+            DatagramRouter::new(config.inbound_channel_capacity);
+        let rtt = RttTable::default();
+        let stats = StatsTable::default();
+        let migrations = MigrationTable::default();
+        let relays = RelayTable::default();
+        let address_book = match &config.address_book_path {
+            Some(path) => AddressBook::open(path)?,
+            None => AddressBook::default(),
+        };
+        let (max_age, clock_skew) = (config.handshake_max_age, config.clock_skew_tolerance);
+        let replays = match &config.replay_cache_path {
+            Some(path) => ReplayCache::open(path, max_age, clock_skew, unix_millis())?,
+            None => ReplayCache::new(max_age, clock_skew),
+        };
+        let (self_address, inbound_stream, outbound_tx, mixnet_task) = initialize_mixnet(
+            client,
+            Some(mixnet_status_tx),
+            events.clone(),
+            datagrams.clone(),
+            rtt.clone(),
+            stats.clone(),
+            migrations.clone(),
+            relays.clone(),
+            &config,
+        )
+        .await?;
+
+        // dial clients aren't reconnected, since a replacement built by `config.reconnect`
+        // would take over the main client's identity
+        // nor do they check the topology, which is the main client's
+        let dial_config = NymTransportConfig {
+            reconnect: None,
+            topology_check_interval: None,
+            ..config.clone()
+        };
+        let mut dial_clients = Vec::with_capacity(dial_backends.len());
+        for backend in dial_backends {
+            let (status_tx, status_rx) = unbounded_channel();
+            let (address, inbound_stream, outbound_tx, mixnet_task) = initialize_mixnet(
+                backend,
+                Some(status_tx),
+                events.clone(),
+                datagrams.clone(),
+                rtt.clone(),
+                stats.clone(),
+                migrations.clone(),
+                relays.clone(),
+                &dial_config,
+            )
+            .await?;
+            info!("dial client connected as {}", address);
+            dial_clients.push(DialClient {
+                address,
+                inbound_stream,
+                outbound_tx,
+                status_rx,
+                connected: true,
+                mixnet_task,
+            });
+        }
+
+        let datagram_requests = DatagramRequests::new(datagram_requests_rx, outbound_tx.clone());
+        let listen_addr = nym_address_to_multiaddr(self_address)?;
         let listener_id = ListenerId::next();
 
         let (poll_tx, poll_rx) = unbounded_channel::<TransportEvent<Upgrade, Error>>();
 
-        poll_tx
-            .send(TransportEvent::NewAddress {
-                listener_id,
-                listen_addr: listen_addr.clone(),
-            })
-            .map_err(|_| Error::SendErrorTransportEvent)?;
+        if config.reports_listen_addr(&listen_addr) {
+            poll_tx
+                .send(TransportEvent::NewAddress {
+                    listener_id,
+                    listen_addr: listen_addr.clone(),
+                })
+                .map_err(|_| Error::SendErrorTransportEvent)?;
+        }
+
+        let handshake_timeout = timeout.unwrap_or(config.handshake_timeout);
 
-        let inbound_stream = UnboundedReceiverStream::new(inbound_rx);
-        let handshake_timeout =
-            timeout.unwrap_or_else(|| Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT_SECS));
+        // an expired gap or handshake is noticed at most half its timeout late
+        let gap_check_period = std::cmp::max(
+            std::cmp::min(config.gap_timeout, handshake_timeout) / 2,
+            MIN_GAP_CHECK_PERIOD,
+        );
+        let mut gap_check = interval_at(Instant::now() + gap_check_period, gap_check_period);
+        gap_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
         Ok(Self {
             self_address,
             listen_addr,
-            listener_id,
+            listeners: vec![listener_id],
+            service_listeners: HashMap::new(),
+            ticket_key: config
+                .session_ticket_lifetime
+                .map(|_| TicketKey::new(&keypair)),
             keypair,
-            connections: HashMap::new(),
-            pending_dials: HashMap::new(),
+            connections: ConnectionTable::default(),
             message_queues: HashMap::new(),
+            challenges: HashMap::new(),
+            sent_requests: HashMap::new(),
+            admissions: FuturesUnordered::new(),
+            admitting: HashSet::new(),
+            duplicates: DuplicateFilter::new(config.duplicate_cache_size),
+            replays,
+            gap_check,
+            connection_requests: TokenBucket::new(
+                config.limits.requests_per_second,
+                config.limits.burst,
+            ),
             inbound_stream,
             outbound_tx,
             poll_rx,
             poll_tx,
-            waker: None,
+            mixnet_status_rx,
+            health: ClientHealth::Connected,
+            topology_epoch: None,
+            mixnet_task: Some(mixnet_task),
+            dial_clients,
+            next_dial_client: 0,
             handshake_timeout,
+            dial_slots: config
+                .max_concurrent_dials
+                .map(|max| Arc::new(Semaphore::new(max))),
+            relay_server: config.relay.map(RelayServer::new),
+            config,
+            events,
+            datagrams,
+            datagram_requests: Some(datagram_requests),
+            rtt,
+            stats,
+            migrations,
+            relays,
+            relay_listeners: HashMap::new(),
+            migration_seq: 0,
+            address_book,
+            reply_routes: ReplyRoutes::default(),
+            bindings: SenderTagBindings::default(),
         })
     }
 
@@ -148,12 +623,511 @@ impl NymTransport {
         PeerId::from_public_key(&self.keypair.public())
     }
 
+    /// Shut down the transport.
+    /// A Close is sent for every open substream, every queued outbound message is
+    /// written to the mixnet, and the mixnet client is disconnected.
+    /// The listener is closed, and existing connections stop receiving messages.
+    pub async fn shutdown(&mut self) -> Result<(), Error> {
+        let Some(mixnet_task) = self.mixnet_task.take() else {
+            return Ok(());
+        };
+
+        for handle in self.connections.drain() {
+            for msg in handle.close_messages() {
+                handle
+                    .outbound_tx
+                    .send(msg)
+                    .await
+                    .map_err(|e| Error::OutboundSendFailure(e.to_string()))?;
+            }
+        }
+        self.message_queues.clear();
+
+        let services = std::mem::take(&mut self.service_listeners);
+        let listeners = std::mem::take(&mut self.listeners)
+            .into_iter()
+            .chain(services.into_values().map(|listener| listener.listener_id));
+        for listener_id in listeners {
+            self.poll_tx
+                .send(TransportEvent::ListenerClosed {
+                    listener_id,
+                    reason: Ok(()),
+                })
+                .map_err(|_| Error::SendErrorTransportEvent)?;
+        }
+
+        for client in self.dial_clients.drain(..) {
+            client.mixnet_task.shutdown().await?;
+        }
+        self.health = ClientHealth::Disconnected;
+        mixnet_task.shutdown().await
+    }
+
+    /// events returns a stream of events describing the health of the transport,
+    /// emitted from now on. A subscriber that falls too far behind misses events.
+    pub fn events(&self) -> impl Stream<Item = NymEvent> + Send + 'static {
+        self.events.subscribe()
+    }
+
+    /// datagram_client returns a client for sending requests in single mixnet messages,
+    /// outside of any connection; see [`DatagramClient`].
+    pub fn datagram_client(&self) -> DatagramClient {
+        self.datagrams
+            .client(self.outbound_tx.clone(), self.config.datagram_timeout)
+    }
+
+    /// datagram_requests returns the stream of datagram requests sent to us.
+    /// It can only be taken once; later calls return None.
+    pub fn datagram_requests(&mut self) -> Option<DatagramRequests> {
+        self.datagram_requests.take()
+    }
+
+    /// gateway returns the identity key (base58) of the gateway the mixnet client is connected to.
+    pub fn gateway(&self) -> String {
+        self.self_address.gateway().to_base58_string()
+    }
+
+    /// listen_addr returns the multiaddr of our nym address, which is what
+    /// [`Transport::listen_on`] accepts.
+    pub fn listen_addr(&self) -> &Multiaddr {
+        &self.listen_addr
+    }
+
+    /// local_nym_address returns our nym address, which peers dial us at.
+    pub fn local_nym_address(&self) -> Recipient {
+        self.self_address
+    }
+
+    /// local_peer_id returns the PeerId we authenticate as in connection handshakes.
+    pub fn local_peer_id(&self) -> PeerId {
+        self.peer_id()
+    }
+
+    /// listen_multiaddr returns our contactable address, `/nym/<address>/p2p/<peer id>`,
+    /// for the application to print or register so that peers can dial us.
+    pub fn listen_multiaddr(&self) -> Multiaddr {
+        self.listen_addr.clone().with(Protocol::P2p(self.peer_id()))
+    }
+
+    /// mixnet_status describes the main mixnet client's connection to the mixnet: the
+    /// gateway it's connected through, the epoch of the topology it routes through, and
+    /// whether it's connected at all, e.g. to tell whether a connection problem is the
+    /// mixnet's.
+    pub fn mixnet_status(&self) -> MixnetSnapshot {
+        MixnetSnapshot {
+            gateway: self.gateway(),
+            topology_epoch: self.topology_epoch,
+            health: self.health,
+        }
+    }
+
+    /// debug_snapshot describes the transport's connections, queues and SURB budgets
+    /// as they are now, e.g. to be logged when connections over the mixnet hang.
+    pub fn debug_snapshot(&self) -> TransportSnapshot {
+        let connections = self
+            .connections
+            .entries()
+            .map(|(id, kind, handle, pending)| {
+                let queue = self.message_queues.get(id);
+                ConnectionSnapshot {
+                    id: format!("{:?}", id),
+                    status: kind.into(),
+                    peer_id: handle
+                        .map(|handle| handle.remote_peer_id())
+                        .or_else(|| pending.and_then(|pending| pending.remote_peer_id))
+                        .map(|peer_id| peer_id.to_string()),
+                    remote_address: handle
+                        .and_then(|handle| handle.remote_recipient().copied())
+                        .or_else(|| pending.and_then(|pending| pending.remote_recipient))
+                        .map(|recipient| recipient.to_string()),
+                    open_substreams: handle.map_or(0, ConnectionHandle::open_substreams),
+                    next_expected_nonce: queue.map_or(0, |queue| queue.next_expected_nonce().get()),
+                    queued_inbound: queue.map_or(0, MessageQueue::len),
+                    queued_outbound: handle.map_or(0, |handle| handle.outbound_tx.len()),
+                    smoothed_rtt: self.rtt.smoothed(id),
+                }
+            })
+            .collect();
+
+        let surbs = self
+            .mixnet_task
+            .iter()
+            .chain(self.dial_clients.iter().map(|client| &client.mixnet_task))
+            .flat_map(MixnetTask::surbs_remaining)
+            .map(|(recipient, remaining)| SurbSnapshot {
+                recipient: recipient.to_string(),
+                remaining,
+            })
+            .collect();
+
+        TransportSnapshot {
+            local_peer_id: self.peer_id().to_string(),
+            listen_addr: self.listen_addr.to_string(),
+            connections,
+            pending_dials: self.connections.pending_dials().count(),
+            pending_inbound: self.connections.pending_inbound(),
+            inbound_queue_len: self.inbound_stream.len(),
+            outbound_queue_len: self.outbound_tx.channel_len(),
+            surbs,
+            known_addresses: self.address_book.len(),
+            mixnet: self.mixnet_status(),
+        }
+    }
+
+    /// stats returns the traffic the transport has sent and received since it started,
+    /// through any of its mixnet clients, e.g. for bandwidth accounting.
+    pub fn stats(&self) -> TransportStats {
+        let mut total = self.stats.total();
+        total.open_substreams = self
+            .connections
+            .handles()
+            .map(ConnectionHandle::open_substreams)
+            .sum();
+        TransportStats {
+            connections: self.connections.handles().count(),
+            total,
+        }
+    }
+
+    /// add_address records a nym address of `peer_id`, e.g. as learned from identify,
+    /// so that it can be dialed as `/p2p/<peer id>`. Peers we complete a handshake with
+    /// at a known nym address are recorded automatically. A peer may be known at several
+    /// addresses, e.g. one per mixnet client, in which case dials to it race them all.
+    /// It fails with [`Error::AddressPinned`] if the peer is pinned to other addresses.
+    pub fn add_address(&mut self, peer_id: PeerId, addr: &Multiaddr) -> Result<(), Error> {
+        let addr = nym_multiaddr_of(peer_id, addr)?;
+        self.address_book.insert_unverified(peer_id, addr.recipient)
+    }
+
+    /// remove_address forgets every nym address of `peer_id`, and unpins it.
+    pub fn remove_address(&mut self, peer_id: &PeerId) {
+        self.address_book.remove(peer_id);
+    }
+
+    /// pin_address records `addr` as a nym address of `peer_id`, and pins the peer to the
+    /// addresses it's known at: from then on, [`NymTransport::add_address`] rejects new
+    /// addresses for it, and it's only learned at others by completing a handshake there,
+    /// which proves that it holds its PeerId's key at that address.
+    pub fn pin_address(&mut self, peer_id: PeerId, addr: &Multiaddr) -> Result<(), Error> {
+        let addr = nym_multiaddr_of(peer_id, addr)?;
+        self.address_book.pin(peer_id, addr.recipient);
+        Ok(())
+    }
+
+    /// unpin_address lets `peer_id` be learned at new addresses however they're learned,
+    /// keeping the ones it's known at. It returns false if the peer wasn't pinned.
+    pub fn unpin_address(&mut self, peer_id: &PeerId) -> bool {
+        self.address_book.unpin(peer_id)
+    }
+
+    /// is_pinned returns true if `peer_id` is pinned to the addresses it's known at.
+    pub fn is_pinned(&self, peer_id: &PeerId) -> bool {
+        self.address_book.is_pinned(peer_id)
+    }
+
+    /// address_of returns the multiaddr `peer_id` was most recently known at, if any.
+    pub fn address_of(&self, peer_id: &PeerId) -> Option<Multiaddr> {
+        self.addresses_of(peer_id).into_iter().next()
+    }
+
+    /// addresses_of returns every multiaddr `peer_id` is known at, most recent first.
+    pub fn addresses_of(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.address_book
+            .get_all(peer_id)
+            .iter()
+            .filter_map(|recipient| {
+                NymMultiaddr::new(*recipient)
+                    .with_peer_id(*peer_id)
+                    .to_multiaddr()
+                    .ok()
+            })
+            .collect()
+    }
+
+    /// remote_info returns what `peer_id` told us about itself when our open connection
+    /// to it was opened, if there is one.
+    pub fn remote_info(&self, peer_id: &PeerId) -> Option<ConnectionInfo> {
+        self.connections
+            .handles()
+            .find(|handle| !handle.is_closed() && handle.remote_peer_id() == *peer_id)
+            .map(|handle| handle.remote_info().clone())
+    }
+
+    /// resolve_peer_addrs replaces a `/p2p/<peer id>` multiaddr with the nym addresses
+    /// the peer is known at, most recent first; other multiaddrs are returned as they are.
+    fn resolve_peer_addrs(&self, addr: Multiaddr) -> Result<Vec<Multiaddr>, TransportError<Error>> {
+        let mut protocols = addr.iter();
+        let (Some(Protocol::P2p(peer_id)), None) = (protocols.next(), protocols.next()) else {
+            return Ok(vec![addr]);
+        };
+        let addrs = self.addresses_of(&peer_id);
+        if addrs.is_empty() {
+            return Err(TransportError::Other(Error::UnknownPeerAddress(peer_id)));
+        }
+        Ok(addrs)
+    }
+
+    /// reply_route returns the outbound channel and sender tag of an open connection
+    /// to `peer_id` over which we only hold its SURBs, if there is one.
+    fn reply_route(
+        &self,
+        peer_id: &PeerId,
+    ) -> Option<(BoundedSender<OutboundMessage>, AnonymousSenderTag)> {
+        self.connections
+            .handles()
+            .filter(|handle| !handle.is_closed() && handle.remote_peer_id() == *peer_id)
+            .find_map(
+                |handle| match (handle.remote_recipient(), handle.sender_tag()) {
+                    (None, Some(sender_tag)) => {
+                        Some((handle.outbound_tx.clone(), sender_tag.clone()))
+                    }
+                    _ => None,
+                },
+            )
+    }
+
+    /// next_dial_outbound returns the outbound channel of the client the next dial that
+    /// doesn't expose our address is sent through, cycling through the main client and
+    /// every dial client that's still connected.
+    fn next_dial_outbound(&mut self) -> BoundedSender<OutboundMessage> {
+        let clients = self.dial_clients.len() + 1;
+        loop {
+            let index = self.next_dial_client % clients;
+            self.next_dial_client = index + 1;
+            let Some(client) = index.checked_sub(1).map(|i| &self.dial_clients[i]) else {
+                return self.outbound_tx.clone();
+            };
+            if client.connected {
+                debug!("dialing through dial client {}", client.address);
+                return client.outbound_tx.clone();
+            }
+        }
+    }
+
+    /// dial_addr dials the nym address `addr`, to which a `/p2p/<peer id>` multiaddr
+    /// has already been resolved.
+    fn dial_addr(
+        &mut self,
+        addr: Multiaddr,
+    ) -> Result<<Self as Transport>::Dial, TransportError<Error>> {
+        let id = ConnectionId::generate();
+
+        // create remote recipient address
+        let NymMultiaddr {
+            recipient,
+            expose_self_address: expose_suffix,
+            peer_id: remote_peer_id,
+            relayed,
+            service,
+        } = NymMultiaddr::try_from(&addr).map_err(|e| match e {
+            // lets the swarm try another transport for non-nym addresses
+            Error::InvalidProtocolForMultiaddr => {
+                TransportError::MultiaddrNotSupported(addr.clone())
+            }
+            e => TransportError::Other(e),
+        })?;
+
+        // a relayed dial is to the peer behind the relay, which has to be named; we
+        // don't relay for ourselves, and relays don't forward to services
+        if relayed {
+            if recipient == self.self_address || service.is_some() {
+                return Err(TransportError::MultiaddrNotSupported(addr));
+            }
+            if remote_peer_id.is_none() {
+                return Err(TransportError::Other(Error::MissingRelayedPeerId));
+            }
+        }
+
+        // an anonymous peer is reported at our own address (see handle_inbound)
+        let reply_route = match remote_peer_id {
+            Some(peer_id) if recipient == self.self_address && !relayed => {
+                let route = self
+                    .reply_route(&peer_id)
+                    .ok_or(TransportError::Other(Error::NoReplyRoute(peer_id)))?;
+                Some(route)
+            }
+            _ => None,
+        };
+
+        // the remote of a reply route dialed our address, so it already knows it; a
+        // relayed dial is answered through the relay, so the remote never learns it
+        let expose_self_address = !relayed
+            && (reply_route.is_some()
+                || match self.config.anonymity {
+                    AnonymityMode::SenderAnonymous => false,
+                    AnonymityMode::ExposeSelfAddress => true,
+                    AnonymityMode::PerDial => expose_suffix,
+                });
+
+        // dials use a fresh identity each time, so that the remote can't link them;
+        // the handshake still proves we hold the key our PeerId is derived from.
+        // a dial that exposes our address identifies us anyway, so it uses our own
+        // identity, which lets protocols such as identify authenticate us.
+        let local_key = if expose_self_address {
+            self.keypair.clone()
+        } else {
+            Keypair::generate_ed25519()
+        };
+        let connection_peer_id = PeerId::from(local_key.public());
+        let self_address = expose_self_address.then_some(self.self_address);
+        // a dial that exposes our address is answered at it, so it has to go
+        // through the main client, and a reply route through the client holding its SURBs.
+        // a relayed dial's messages are sent to the relay once its circuit is opened below
+        let (outbound_tx, remote_recipient, sender_tag) = match reply_route {
+            Some((outbound_tx, sender_tag)) => (outbound_tx, None, Some(sender_tag)),
+            None if relayed => (self.next_dial_outbound(), None, None),
+            None if expose_self_address => (self.outbound_tx.clone(), Some(recipient), None),
+            None => (self.next_dial_outbound(), Some(recipient), None),
+        };
+        let handshake = Handshake::new(&local_key, &id, self_address.as_ref())
+            .map_err(TransportError::Other)?;
+
+        let mut capabilities = self.capabilities();
+        if sender_tag.is_some() {
+            capabilities = capabilities | Capabilities::REPLY_ROUTE;
+        }
+
+        // put ConnectionRequest message into outbound message channel
+        let msg = ConnectionMessage {
+            peer_id: connection_peer_id,
+            id: id.clone(),
+            capabilities,
+            recipient: self_address,
+            handshake: handshake.payload(),
+            challenge: None,
+            observed: None,
+            max_message_size: self.max_message_size(),
+            info: self.config.info.clone(),
+            service,
+        };
+
+        // create pending conn structs and store
+        let (connection_tx, connection_rx) = oneshot::channel::<Result<Connection, Error>>();
+
+        let inner_pending_conn = PendingConnection::new(
+            remote_recipient,
+            remote_peer_id,
+            local_key,
+            handshake,
+            connection_tx,
+            outbound_tx.clone(),
+        );
+        self.connections
+            .dial(id.clone(), inner_pending_conn)
+            .map_err(TransportError::Other)?;
+        if let (true, Some(peer_id)) = (relayed, remote_peer_id) {
+            self.relays.dial(&id, recipient, peer_id);
+        }
+
+        let dial_timeout = self.config.dial_timeout;
+        let dial_slots = self.dial_slots.clone();
+        let metrics = self.config.metrics.clone();
+        // if this future is dropped, or times out, connection_rx is dropped
+        // with it and the transport discards the pending dial. The handshake
+        // timeout is the transport's, since only it learns when the request is written.
+        Ok(async move {
+            // held until the dial completes or fails; dials waiting for one are
+            // handed them in the order they were made
+            let _slot = match dial_slots {
+                Some(slots) => {
+                    let queued = Instant::now();
+                    let slot = slots
+                        .acquire_owned()
+                        .await
+                        .map_err(|_| Error::MixnetTaskShutdown)?;
+                    metrics.observe_dial_queue_time(queued.elapsed());
+                    Some(slot)
+                }
+                None => None,
+            };
+
+            let dial = async {
+                outbound_tx
+                    .send(OutboundMessage {
+                        message: Message::ConnectionRequest(msg),
+                        recipient: remote_recipient,
+                        sender_tag,
+                    })
+                    .await
+                    .map_err(|e| Error::OutboundSendFailure(e.to_string()))?;
+
+                debug!("sent outbound ConnectionRequest");
+
+                connection_rx.await?
+            };
+
+            let conn = timeout(dial_timeout, dial)
+                .await
+                .map_err(|_| Error::DialTimeout)??;
+            Ok((conn.peer_id, conn))
+        }
+        .boxed())
+    }
+
+    /// emit_to_listeners queues an event for every open listener on our own address,
+    /// including those on a service, to be returned by poll.
+    fn emit_to_listeners(&self, event: impl Fn(ListenerId) -> TransportEvent<Upgrade, Error>) {
+        let services = self
+            .service_listeners
+            .values()
+            .map(|listener| listener.listener_id);
+        for listener_id in self.listeners.iter().copied().chain(services) {
+            // poll_rx is owned by self, so this can't fail
+            self.poll_tx.send(event(listener_id)).ok();
+        }
+    }
+
+    /// set_self_address updates our nym address after the mixnet client reconnected,
+    /// moving every listener over to the new address if it changed.
+    fn set_self_address(&mut self, address: Recipient) -> Result<(), Error> {
+        self.self_address = address;
+        let listen_addr = nym_address_to_multiaddr(address)?;
+        if listen_addr == self.listen_addr {
+            return Ok(());
+        }
+
+        let old = std::mem::replace(&mut self.listen_addr, listen_addr.clone());
+        let mut moved: Vec<_> = self
+            .listeners
+            .iter()
+            .map(|&listener_id| (listener_id, old.clone(), listen_addr.clone()))
+            .collect();
+        for (&service, listener) in self.service_listeners.iter_mut() {
+            let listen_addr = NymMultiaddr::new(address)
+                .with_service(service)
+                .to_multiaddr()?;
+            let old = std::mem::replace(&mut listener.listen_addr, listen_addr.clone());
+            moved.push((listener.listener_id, old, listen_addr));
+        }
+
+        // poll_rx is owned by self, so these can't fail
+        for (listener_id, old, listen_addr) in moved {
+            if self.config.reports_listen_addr(&old) {
+                self.poll_tx
+                    .send(TransportEvent::AddressExpired {
+                        listener_id,
+                        listen_addr: old,
+                    })
+                    .ok();
+            }
+            if self.config.reports_listen_addr(&listen_addr) {
+                self.poll_tx
+                    .send(TransportEvent::NewAddress {
+                        listener_id,
+                        listen_addr,
+                    })
+                    .ok();
+            }
+        }
+        Ok(())
+    }
+
     fn handle_message_queue_on_connection_initiation(
         &mut self,
         id: &ConnectionId,
     ) -> Result<(), Error> {
         debug!("handle_message_queue_on_connection_initiation");
-        let Some(inbound_tx) = self.connections.get(id) else {
+        let Some(handle) = self.connections.handle(id) else {
             // this should not happen
             return Err(Error::NoConnectionForTransportMessage);
         };
@@ -161,25 +1135,29 @@ impl NymTransport {
         match self.message_queues.get_mut(id) {
             Some(queue) => {
                 // update expected nonce
-                queue.set_connection_message_received();
+                queue.set_connection_message_received()?;
 
                 // push pending inbound some messages in this case
-                while let Some(msg) = queue.pop() {
+                while let Some(msg) = queue.pop()? {
                     debug!(
                         "popped queued message with nonce {} for connection",
                         msg.nonce
                     );
-                    inbound_tx
-                        .send(msg.message.clone())
+                    handle
+                        .inbound_tx
+                        .send(ConnectionEvent::Substream(
+                            msg.message.clone(),
+                            msg.span(Direction::Inbound),
+                        ))
                         .map_err(|e| Error::InboundSendFailure(e.to_string()))?;
                 }
             }
             None => {
                 // no queue exists for this connection, create one
-                let queue = MessageQueue::new();
+                let queue = MessageQueue::new(self.config.replay_window, self.config.gap_timeout);
                 self.message_queues.insert(id.clone(), queue);
                 let queue = self.message_queues.get_mut(id).unwrap();
-                queue.set_connection_message_received();
+                queue.set_connection_message_received()?;
             }
         };
 
@@ -194,155 +1172,1634 @@ impl NymTransport {
         msg: &ConnectionMessage,
         sender_tag: Option<AnonymousSenderTag>,
     ) -> Result<(), Error> {
-        if self.connections.contains_key(&msg.id) {
-            return Err(Error::ConnectionAlreadyEstablished);
+        match self.connections.kind(&msg.id) {
+            StateKind::PendingOutbound => {}
+            StateKind::Closed => {
+                let Some(abandoned) = self.connections.take_abandoned(&msg.id) else {
+                    return Err(Error::NoConnectionForResponse);
+                };
+                // the dial lost a race, or timed out, but the remote accepted it, so close
+                // its end rather than leave it for its keepalives to notice
+                debug!("closing abandoned dial {:?}", msg.id);
+                return abandoned
+                    .outbound_tx
+                    .try_send(OutboundMessage {
+                        message: Message::ConnectionClose(ConnectionCloseMessage {
+                            id: msg.id.clone(),
+                        }),
+                        recipient: abandoned.remote_recipient,
+                        sender_tag,
+                    })
+                    .map_err(|e| Error::OutboundSendFailure(e.to_string()));
+            }
+            _ => return Err(Error::ConnectionAlreadyEstablished),
         }
 
-        if let Some(pending_conn) = self.pending_dials.remove(&msg.id) {
-            // Create connection with sender_tag
-            let (conn, conn_tx) = self.create_connection_types(
-                msg.peer_id,
-                Some(pending_conn.remote_recipient), // Dialer knows recipient,
-                msg.id.clone(),
-                sender_tag,
-            );
-
-            self.connections.insert(msg.id.clone(), conn_tx);
-            self.handle_message_queue_on_connection_initiation(&msg.id)?;
-
-            pending_conn
-                .connection_tx
-                .send(conn)
-                .map_err(|_| Error::ConnectionSendFailure)?;
-
-            if let Some(waker) = self.waker.take() {
-                waker.wake();
+        let (dial, pending_conn) = self.connections.answer(&msg.id)?;
+        // the remote can follow us to a new address only if it knows the old one
+        let exposed_address = pending_conn.handshake.address().is_some();
+        // a failed handshake fails the dial, rather than the listener
+        let cipher = match pending_conn.remote_peer_id {
+            Some(expected) if expected != msg.peer_id => Err(Error::UnexpectedPeerId),
+            _ if msg.max_message_size < MIN_MAX_MESSAGE_SIZE => {
+                Err(Error::MaxMessageSizeTooSmall(msg.max_message_size))
+            }
+            _ => pending_conn.handshake.finish(
+                &msg.handshake,
+                &msg.peer_id,
+                pending_conn.remote_recipient.as_ref(),
+                Role::Dialer,
+            ),
+        };
+        let cipher = match cipher {
+            Ok(cipher) => cipher,
+            Err(e) => {
+                debug!("handshake with {} failed: {}", msg.peer_id, e);
+                pending_conn.connection_tx.send(Err(e)).ok();
+                return Ok(());
             }
+        };
 
-            Ok(())
-        } else {
-            Err(Error::NoConnectionForResponse)
+        // prove to the listener that we sent the request it answered, rather than
+        // someone replaying it; it holds the connection back until we have
+        let challenge_response = match msg.challenge {
+            Some(challenge) => match sign_challenge(&pending_conn.local_key, &msg.id, &challenge) {
+                Ok(signature) => Some(OutboundMessage {
+                    message: Message::ChallengeResponse(ChallengeResponseMessage {
+                        id: msg.id.clone(),
+                        signature,
+                    }),
+                    recipient: pending_conn.remote_recipient,
+                    sender_tag: sender_tag.clone(),
+                }),
+                Err(e) => {
+                    pending_conn.connection_tx.send(Err(e)).ok();
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
+        if let Some(challenge_response) = challenge_response {
+            pending_conn
+                .outbound_tx
+                .try_send(challenge_response)
+                .map_err(|e| Error::OutboundSendFailure(e.to_string()))?;
         }
+
+        // a dial through a reply route is answered through the SURBs of the remote
+        let reply_route = sender_tag.clone();
+        // Create connection with sender_tag
+        let (role, secret) = (cipher.role(), cipher.resumption_secret().clone());
+        let (conn, conn_handle) = self.create_connection_types(
+            msg.into(),
+            pending_conn.remote_recipient, // Dialer knows recipient, unless it used a reply route
+            sender_tag,
+            cipher,
+            pending_conn.outbound_tx,
+        );
+
+        // the handshake proved the listener holds its PeerId's key at the address we dialed
+        if let Some(recipient) = pending_conn.remote_recipient {
+            self.address_book.insert(msg.peer_id, recipient);
+        }
+        // the address the listener saw isn't covered by its signature, so it's only
+        // reported as a candidate, and only if we really sent it one
+        if let Some(observed) = msg.observed.filter(|_| exposed_address) {
+            match nym_address_to_multiaddr(observed) {
+                // nor if it'd advertise an address the listen filter keeps to itself
+                Ok(address) if !self.config.reports_listen_addr(&address) => {}
+                Ok(address) => self.events.emit(NymEvent::ObservedAddress {
+                    peer_id: msg.peer_id,
+                    address,
+                }),
+                Err(e) => debug!("ignoring address observed by {}: {}", msg.peer_id, e),
+            }
+        }
+        let mut conn_handle = conn_handle.with_local_key(pending_conn.local_key);
+        if exposed_address {
+            conn_handle = conn_handle.with_exposed_address();
+        }
+        if let Some(ticket) = self.session_ticket(&msg.id, &conn_handle, role, secret, None) {
+            if let Err(e) = conn_handle.outbound_tx.try_send(ticket) {
+                debug!("failed to queue session ticket: {}", e);
+            }
+        }
+        self.connections.establish(dial, conn_handle);
+        if let Some(sender_tag) = reply_route {
+            self.bind_reply_route(&msg.id, sender_tag, msg.peer_id);
+        }
+        self.handle_message_queue_on_connection_initiation(&msg.id)?;
+
+        pending_conn
+            .connection_tx
+            .send(Ok(conn))
+            .map_err(|_| Error::ConnectionSendFailure)?;
+
+        Ok(())
     }
 
     /// handle_connection_request handles an incoming connection request, sends back a
-    /// connection response, and finally completes the upgrade into a Connection.
+    /// connection response with a challenge for the dialer, and finally completes the
+    /// upgrade into a Connection. The connection stays pending until the returned token,
+    /// held by its Upgrade, is dropped, and its messages are queued until the dialer has
+    /// answered the returned challenge, after which it's sent the returned session
+    /// ticket, if any.
     fn handle_connection_request(
         &mut self,
         msg: &ConnectionMessage,
         sender_tag: Option<AnonymousSenderTag>,
-    ) -> Result<Connection, Error> {
-        // ensure we don't already have a conn with the same id
-        if self.connections.contains_key(&msg.id) {
+    ) -> Result<Accepted, Error> {
+        // the connection could have been dialed, or our listeners closed, while the
+        // admission hook was deciding
+        if self.connections.contains(&msg.id) {
             return Err(Error::ConnectionIDExists);
         }
+        let relay = self.relays.relay_of(&msg.id);
+        if !self.is_listening(relay.as_ref(), msg.service) {
+            return Err(Error::ConnectionRejected("not_listening"));
+        }
+        if msg.max_message_size < MIN_MAX_MESSAGE_SIZE {
+            return Err(Error::ConnectionRejected("max_message_size"));
+        }
+        // a request to a service is for a listener of its own, so it never stands in
+        // for a dial of ours
+        if !msg.capabilities.contains(Capabilities::REPLY_ROUTE) && msg.service.is_none() {
+            self.resolve_simultaneous_dial(msg)?;
+        }
+
+        // a request through a reply route comes from a peer we dialed, which only knows us
+        // by the identity we dialed it with, and must not learn our address. It's answered
+        // through the client we dialed it with.
+        let (local_key, self_address, outbound_tx) =
+            if msg.capabilities.contains(Capabilities::REPLY_ROUTE) {
+                let handle = self
+                    .connections
+                    .handles()
+                    .find(|handle| {
+                        !handle.is_closed()
+                            && handle.remote_peer_id() == msg.peer_id
+                            && handle.remote_recipient() == msg.recipient.as_ref()
+                    })
+                    .ok_or(Error::ConnectionRejected("no_reply_route"))?;
+                let local_key = handle
+                    .local_key()
+                    .cloned()
+                    .ok_or(Error::ConnectionRejected("no_reply_route"))?;
+                (local_key, None, handle.outbound_tx.clone())
+            } else if relay.is_some() {
+                // a request through a relay comes from a peer that only knows the relay's
+                // address, and must not learn ours either
+                (self.keypair.clone(), None, self.outbound_tx.clone())
+            } else {
+                (
+                    self.keypair.clone(),
+                    Some(self.self_address),
+                    self.outbound_tx.clone(),
+                )
+            };
+
+        // the dialer dialed our address, and binds its own to its PeerId if it exposed it
+        let handshake = Handshake::new(&local_key, &msg.id, self_address.as_ref())?;
+        let payload = handshake.payload();
+        let cipher = handshake.finish(
+            &msg.handshake,
+            &msg.peer_id,
+            msg.recipient.as_ref(),
+            Role::Listener,
+        )?;
+        // the dialer's handshake binds its PeerId to the address it exposed
+        if let Some(recipient) = msg.recipient {
+            self.address_book.insert(msg.peer_id, recipient);
+        }
+
+        // if the dialer exposed its address we reply to it directly,
+        // otherwise we only have the sender_tag to reply with.
+        let sender_tag = if msg.recipient.is_some() {
+            None
+        } else {
+            sender_tag
+        };
 
         // Create connection with sender_tag
-        let (conn, conn_tx) = self.create_connection_types(
-            msg.peer_id,
-            None, // Receiver doesn't know dialer address
-            msg.id.clone(),
+        let (role, secret) = (cipher.role(), cipher.resumption_secret().clone());
+        let (conn, conn_handle) = self.create_connection_types(
+            msg.into(),
+            msg.recipient, // None unless the dialer exposed its address
             sender_tag.clone(),
+            cipher,
+            outbound_tx.clone(),
         );
 
         info!("Created connection: {:?}", conn);
 
-        self.connections.insert(msg.id.clone(), conn_tx);
+        let mut conn_handle = conn_handle.with_local_key(local_key.clone());
+        if self_address.is_some() {
+            conn_handle = conn_handle.with_exposed_address();
+        }
+        let ticket = self.session_ticket(&msg.id, &conn_handle, role, secret, msg.service);
+        let upgrade = Arc::new(());
+        self.connections
+            .accept(msg.id.clone(), conn_handle, &upgrade)?;
+        // messages sent before the challenge is answered wait here for it
+        let queue = MessageQueue::new(self.config.replay_window, self.config.gap_timeout);
+        self.message_queues.insert(msg.id.clone(), queue);
+        if let Some(sender_tag) = &sender_tag {
+            self.bind_reply_route(&msg.id, sender_tag.clone(), msg.peer_id);
+        }
         info!("Current active connections: {}", self.connections.len());
 
-        self.handle_message_queue_on_connection_initiation(&msg.id)?;
-
+        let challenge = new_challenge();
         let resp = ConnectionMessage {
-            peer_id: self.peer_id(),
+            peer_id: PeerId::from(local_key.public()),
             id: msg.id.clone(),
+            capabilities: self.capabilities(),
+            recipient: None,
+            handshake: payload,
+            challenge: Some(challenge),
+            // tell the dialer where its request came from, like a TCP listener would
+            observed: msg.recipient,
+            max_message_size: self.max_message_size(),
+            info: self.config.info.clone(),
+            service: None,
         };
 
         // Send response using sender_tag if available
-        self.outbound_tx
-            .send(OutboundMessage {
+        outbound_tx
+            .try_send(OutboundMessage {
                 message: Message::ConnectionResponse(resp),
-                recipient: None,
+                recipient: msg.recipient,
                 sender_tag,
             })
             .map_err(|e| Error::OutboundSendFailure(e.to_string()))?;
 
-        if let Some(waker) = self.waker.take() {
-            waker.wake();
+        Ok((conn, upgrade, challenge, ticket))
+    }
+
+    /// resolve_simultaneous_dial handles a ConnectionRequest from a peer we're dialing
+    /// ourselves, where both dials would otherwise establish a connection. Both sides
+    /// break the tie the same way: the dial of the peer with the lower PeerId is kept.
+    /// If that's us, the request is rejected; otherwise our dial is aborted, and the
+    /// remote told to close it in case it already accepted it.
+    fn resolve_simultaneous_dial(&mut self, msg: &ConnectionMessage) -> Result<(), Error> {
+        let local_peer_id = self.peer_id();
+        // the remote can only tell it's being dialed by us if we dialed with our identity
+        let Some(id) = self.connections.pending_dials().find_map(|(id, pending)| {
+            let to_remote = match pending.remote_peer_id {
+                Some(peer_id) => peer_id == msg.peer_id,
+                None => msg.recipient.is_some() && pending.remote_recipient == msg.recipient,
+            };
+            (to_remote && PeerId::from(pending.local_key.public()) == local_peer_id)
+                .then(|| id.clone())
+        }) else {
+            return Ok(());
+        };
+
+        if local_peer_id < msg.peer_id {
+            debug!("keeping our dial {:?} to {}", id, msg.peer_id);
+            return Err(Error::ConnectionRejected("simultaneous_dial"));
         }
 
-        Ok(conn)
+        debug!("aborting our dial {:?} to {}", id, msg.peer_id);
+        let pending = self.connections.abort(&id)?;
+        let close = OutboundMessage {
+            message: Message::ConnectionClose(ConnectionCloseMessage { id }),
+            recipient: pending.remote_recipient,
+            sender_tag: None,
+        };
+        if let Err(e) = pending.outbound_tx.try_send(close) {
+            debug!("failed to queue ConnectionClose for aborted dial: {}", e);
+        }
+        // the dial future may have been dropped
+        pending
+            .connection_tx
+            .send(Err(Error::SimultaneousDial(msg.peer_id)))
+            .ok();
+        Ok(())
     }
 
-    fn handle_transport_message(&mut self, msg: TransportMessage) -> Result<(), Error> {
-        let queue = match self.message_queues.get_mut(&msg.id) {
-            Some(queue) => queue,
-            None => {
-                // no queue exists for this connection, create one
-                let queue = MessageQueue::new();
-                self.message_queues.insert(msg.id.clone(), queue);
-                self.message_queues.get_mut(&msg.id).unwrap()
+    /// check_connection_request checks that we can take on an inbound connection request,
+    /// before it's handed to the admission hook, if any, and that it isn't a replay.
+    fn check_connection_request(
+        &mut self,
+        msg: &ConnectionMessage,
+        sender_tag: Option<&AnonymousSenderTag>,
+    ) -> Result<(), Error> {
+        // ensure we don't already have a conn with the same id
+        if self.connections.contains(&msg.id) || self.admitting.contains(&msg.id) {
+            return Err(Error::ConnectionIDExists);
+        }
+
+        if !self.is_listening(self.relays.relay_of(&msg.id).as_ref(), msg.service) {
+            return Err(Error::ConnectionRejected("not_listening"));
+        }
+        self.check_limits(sender_tag)?;
+
+        // the hook is told who is dialing, so the dialer has to show it holds the key
+        // first, and only a request it signed is remembered, so that forged requests
+        // can't fill the replay cache
+        msg.handshake
+            .verify(&msg.id, &msg.peer_id, msg.recipient.as_ref())?;
+        // a dialer sends its request once, so one we've answered before was replayed
+        self.replays
+            .insert(&msg.id, msg.handshake.timestamp(), unix_millis())
+    }
+
+    /// is_listening returns true if we have a listener that a connection request which
+    /// came in through `relay`, or to our own address if None, is reported on. A request
+    /// to a service is only reported on the listener on it, which is never on a relay.
+    fn is_listening(&self, relay: Option<&Recipient>, service: Option<u16>) -> bool {
+        match (relay, service) {
+            (Some(relay), None) => self.relay_listener(relay).is_some(),
+            (Some(_), Some(_)) => false,
+            (None, None) => !self.listeners.is_empty(),
+            (None, Some(service)) => self.service_listeners.contains_key(&service),
+        }
+    }
+
+    /// listener_of returns the listener a connection request to `service` on our own
+    /// address is reported on, and its listen address.
+    fn listener_of(&self, service: Option<u16>) -> Option<(ListenerId, Multiaddr)> {
+        match service {
+            Some(service) => self
+                .service_listeners
+                .get(&service)
+                .map(|listener| (listener.listener_id, listener.listen_addr.clone())),
+            None => self
+                .listeners
+                .first()
+                .map(|&listener_id| (listener_id, self.listen_addr.clone())),
+        }
+    }
+
+    /// relay_listener returns our listener on `relay`'s address, if the relay has
+    /// accepted its reservation.
+    fn relay_listener(&self, relay: &Recipient) -> Option<&RelayListener> {
+        self.relay_listeners
+            .values()
+            .find(|listener| listener.reserved && listener.relay == *relay)
+    }
+
+    /// close_unaccepted_circuit closes the circuit that a relayed ConnectionRequest
+    /// opened, unless the request was accepted, or is being decided on.
+    fn close_unaccepted_circuit(&self, id: &ConnectionId) {
+        if !self.connections.contains(id) && !self.admitting.contains(id) {
+            self.relays.remove(id);
+        }
+    }
+
+    /// admit_connection_request hands an inbound connection request to the admission
+    /// hook. The request is accepted or rejected once the hook decides, in poll.
+    fn admit_connection_request(
+        &mut self,
+        admission: &AdmissionHook,
+        msg: ConnectionMessage,
+        sender_tag: Option<AnonymousSenderTag>,
+    ) -> Result<InboundTransportEvent, Error> {
+        let decision = timeout(
+            self.handshake_timeout,
+            admission.admit(&msg.peer_id, &msg.info),
+        );
+        self.admitting.insert(msg.id.clone());
+        self.admissions.push(
+            async move {
+                // a hook that takes too long has the request rejected
+                let decision = decision.await.unwrap_or(Decision::Reject);
+                (decision, msg, sender_tag)
+            }
+            .boxed(),
+        );
+        Ok(InboundTransportEvent::ConnectionAdmitting)
+    }
+
+    /// accept_connection_request answers an inbound connection request, and holds its
+    /// connection back from the swarm until the dialer answers our challenge.
+    fn accept_connection_request(
+        &mut self,
+        msg: ConnectionMessage,
+        sender_tag: Option<AnonymousSenderTag>,
+    ) -> Result<InboundTransportEvent, Error> {
+        let (conn, pending, challenge, ticket) =
+            match self.handle_connection_request(&msg, sender_tag) {
+                Ok(accepted) => accepted,
+                Err(e) => return self.reject_connection_request(e),
+            };
+        let (connection_tx, connection_rx) = oneshot::channel::<(PeerId, Connection)>();
+        let upgrade = Upgrade::new(connection_rx, pending);
+        connection_tx
+            .send((msg.peer_id, conn))
+            .map_err(|_| Error::ConnectionSendFailure)?;
+        // an anonymous dialer has no address to report, so it's
+        // reported at ours, which address_translation ignores, or at
+        // the relayed address it dialed if it came through a relay
+        let relay = self.relays.relay_of(&msg.id);
+        let send_back_addr = match relay.as_ref().and_then(|relay| self.relay_listener(relay)) {
+            Some(listener) => listener.listen_addr.clone(),
+            None => msg
+                .recipient
+                .and_then(|recipient| nym_address_to_multiaddr(recipient).ok())
+                .unwrap_or_else(|| self.listen_addr.clone()),
+        };
+        self.challenges.insert(
+            msg.id.clone(),
+            PendingChallenge {
+                challenge,
+                identity: msg.handshake.identity().clone(),
+                upgrade,
+                send_back_addr,
+                relay,
+                service: msg.service,
+                sent: Instant::now(),
+                ticket,
+            },
+        );
+        Ok(InboundTransportEvent::ConnectionChallenged)
+    }
+
+    /// reject_connection_request records the rejection of an inbound connection request,
+    /// if `err` is one, or that it was dropped as a replay; other errors are passed on.
+    fn reject_connection_request(&mut self, err: Error) -> Result<InboundTransportEvent, Error> {
+        let reason = match err {
+            Error::ConnectionRejected(reason) => reason,
+            Error::ReplayedConnectionRequest => {
+                debug!("dropping replayed connection request");
+                self.config.metrics.message_duplicate();
+                self.events.emit(NymEvent::MessageDropped {
+                    reason: DropReason::Replayed,
+                });
+                return Ok(InboundTransportEvent::Dropped);
+            }
+            Error::StaleConnectionRequest(timestamp) => {
+                debug!("dropping stale connection request signed at {}", timestamp);
+                self.config.metrics.message_stale("connection_request");
+                self.events.emit(NymEvent::MessageDropped {
+                    reason: DropReason::Stale,
+                });
+                return Ok(InboundTransportEvent::Dropped);
             }
+            err => return Err(err),
+        };
+        // dropped without a response, so that spamming requests costs us little
+        debug!("rejected connection request: {}", reason);
+        self.config.metrics.connection_rejected(reason);
+        self.events.emit(NymEvent::MessageDropped {
+            reason: DropReason::ConnectionRejected,
+        });
+        Ok(InboundTransportEvent::ConnectionRejected)
+    }
+
+    /// poll_admissions accepts or rejects the inbound connection requests the admission
+    /// hook has decided on.
+    fn poll_admissions(&mut self, cx: &mut Context<'_>) {
+        while let Poll::Ready(Some((decision, msg, sender_tag))) =
+            self.admissions.poll_next_unpin(cx)
+        {
+            self.admitting.remove(&msg.id);
+            let id = msg.id.clone();
+            let res = match decision {
+                Decision::Accept => self.accept_connection_request(msg, sender_tag),
+                Decision::Reject => {
+                    self.reject_connection_request(Error::ConnectionRejected("admission"))
+                }
+            };
+            self.close_unaccepted_circuit(&id);
+            if let Err(e) = res {
+                debug!("failed to accept admitted connection request: {}", e);
+            }
+        }
+    }
+
+    /// check_limits returns an error if a ConnectionRequest from `sender_tag`
+    /// would exceed the configured connection limits.
+    fn check_limits(&mut self, sender_tag: Option<&AnonymousSenderTag>) -> Result<(), Error> {
+        let limits = self.config.limits;
+        if !self.connection_requests.try_take(Instant::now()) {
+            return Err(Error::ConnectionRejected("rate_limited"));
+        }
+
+        // requests the admission hook is deciding on will be pending once accepted
+        let pending = self.connections.pending_inbound() + self.admitting.len();
+        if pending >= limits.max_pending_inbound {
+            return Err(Error::ConnectionRejected("too_many_pending"));
+        }
+
+        if let Some(sender_tag) = sender_tag {
+            let count = self
+                .connections
+                .handles()
+                .filter(|handle| !handle.is_closed() && handle.sender_tag() == Some(sender_tag))
+                .count();
+            if count >= limits.max_connections_per_sender {
+                return Err(Error::ConnectionRejected("too_many_from_sender"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// capabilities returns the optional features we advertise in our ConnectionMessages.
+    fn capabilities(&self) -> Capabilities {
+        let mut capabilities = Capabilities::DATAGRAMS | Capabilities::MIGRATION;
+        if self.config.retransmit.is_some() {
+            capabilities = capabilities | Capabilities::RETRANSMIT;
+        }
+        if cfg!(feature = "compression") && self.config.compression_threshold.is_some() {
+            capabilities = capabilities | Capabilities::COMPRESSION;
+        }
+        if self.config.message_max_age.is_some() {
+            capabilities = capabilities | Capabilities::TIMESTAMPS;
+        }
+        if self.ticket_key.is_some() {
+            capabilities = capabilities | Capabilities::RESUMPTION;
+        }
+        capabilities
+    }
+
+    /// max_message_size returns the maximum message size we advertise in our
+    /// ConnectionMessages, which the remote fragments what it sends us to fit.
+    fn max_message_size(&self) -> u32 {
+        u32::try_from(self.config.max_message_size).unwrap_or(u32::MAX)
+    }
+
+    /// session_ticket returns a SessionTicket for the remote of connection `id`, which
+    /// lets it resume the connection with `secret` once we've lost its state. It's only
+    /// issued to a remote that can resume, on a connection it reaches us on at our nym
+    /// address with our identity, since that's where and to whom it would resume it.
+    fn session_ticket(
+        &self,
+        id: &ConnectionId,
+        handle: &ConnectionHandle,
+        role: Role,
+        secret: ResumptionSecret,
+        service: Option<u16>,
+    ) -> Option<OutboundMessage> {
+        let (Some(ticket_key), Some(lifetime)) =
+            (&self.ticket_key, self.config.session_ticket_lifetime)
+        else {
+            return None;
+        };
+        if !handle
+            .remote_capabilities()
+            .contains(Capabilities::RESUMPTION)
+            || !handle.exposes_address()
+            || handle.local_key().map(Keypair::public) != Some(self.keypair.public())
+        {
+            return None;
+        }
+
+        let ticket = SessionTicket {
+            id: id.clone(),
+            role,
+            expires: unix_millis().saturating_add(lifetime.as_millis() as u64),
+            secret,
+            remote_identity: handle.remote_identity()?.clone(),
+            remote_recipient: handle.remote_recipient().copied(),
+            remote_capabilities: handle.remote_capabilities(),
+            remote_max_message_size: handle.remote_max_message_size(),
+            remote_info: handle.remote_info().clone(),
+            service,
+        };
+        let ticket = match ticket_key.seal(&ticket) {
+            Ok(ticket) => ticket,
+            Err(e) => {
+                debug!("failed to seal session ticket for {:?}: {}", id, e);
+                return None;
+            }
+        };
+        Some(OutboundMessage {
+            message: Message::SessionTicket(SessionTicketMessage {
+                id: id.clone(),
+                ticket,
+            }),
+            recipient: handle.remote_recipient().copied(),
+            sender_tag: handle.sender_tag().cloned(),
+        })
+    }
+
+    /// handle_version_mismatch handles a ConnectionRequest or ConnectionResponse of a
+    /// protocol version we don't speak. A request is answered with our own version, if it
+    /// came with SURBs to reply with, and a response fails the dial it belongs to.
+    fn handle_version_mismatch(
+        &mut self,
+        msg: VersionMismatch,
+        sender_tag: Option<AnonymousSenderTag>,
+    ) -> Result<(), Error> {
+        if !msg.request {
+            if self.connections.kind(&msg.id) != StateKind::PendingOutbound {
+                return Err(Error::NoConnectionForResponse);
+            }
+            let pending_conn = self.connections.abort(&msg.id)?;
+            pending_conn
+                .connection_tx
+                .send(Err(Error::UnsupportedVersion(msg.version)))
+                .ok();
+            return Ok(());
+        }
+
+        self.config
+            .metrics
+            .connection_rejected("unsupported_version");
+        self.events.emit(NymEvent::MessageDropped {
+            reason: DropReason::UnsupportedVersion,
+        });
+        // the dialer's address, if it exposed one, is past the header we can parse
+        let Some(sender_tag) = sender_tag else {
+            return Ok(());
+        };
+        // answering costs us a message, so it's subject to the same rate limit as connecting
+        if !self.connection_requests.try_take(Instant::now()) {
+            return Ok(());
+        }
+
+        self.outbound_tx
+            .try_send(OutboundMessage {
+                message: Message::VersionMismatch(VersionMismatch {
+                    id: msg.id,
+                    request: false,
+                    version: PROTOCOL_VERSION,
+                }),
+                recipient: None,
+                sender_tag: Some(sender_tag),
+            })
+            .map_err(|e| Error::OutboundSendFailure(e.to_string()))
+    }
+
+    fn handle_transport_message(&mut self, msg: TransportMessage) -> Result<(), Error> {
+        if msg.message.is_datagram() {
+            return self.handle_datagram(msg);
+        }
+        // messages are only taken on connections we've accepted or established, so
+        // that a sender can't have us hold a queue for every ID it makes up. Messages
+        // that arrive before the connection message are retransmitted, since they
+        // aren't acknowledged
+        if self.connections.handle(&msg.id).is_none() {
+            debug!("dropping message for unknown connection {:?}", msg.id);
+            return Ok(());
+        }
+        self.send_ack(&msg)?;
+
+        if self.duplicates.is_duplicate(&msg.id, msg.nonce) {
+            debug!("dropping duplicate message with nonce {}", msg.nonce);
+            self.config.metrics.message_duplicate();
+            self.events.emit(NymEvent::MessageDropped {
+                reason: DropReason::Replayed,
+            });
+            return Ok(());
+        }
+
+        let Some(queue) = self.message_queues.get_mut(&msg.id) else {
+            debug!(
+                "dropping message for connection {:?} without a queue",
+                msg.id
+            );
+            return Ok(());
         };
 
         queue.print_nonces();
 
-        let nonce = msg.nonce;
-        let Some(msg) = queue.try_push(msg) else {
-            // don't push the message yet, it's been queued
-            debug!("message with nonce {} queued for connection", nonce);
+        if let Err(e) = queue.check_nonce(msg.nonce) {
+            debug!("dropping message: {}", e);
+            let reason = match e {
+                Error::NonceOutsideWindow(_) => DropReason::OutsideReplayWindow,
+                _ => DropReason::Replayed,
+            };
+            self.events.emit(NymEvent::MessageDropped { reason });
             return Ok(());
+        }
+        // only messages the queue takes are remembered, since dropped ones are
+        // retransmitted with the same nonce
+        self.duplicates.insert(&msg.id, msg.nonce);
+
+        let (id, nonce) = (msg.id.clone(), msg.nonce);
+        let msg = match queue.try_push(msg) {
+            Ok(Some(msg)) => msg,
+            Ok(None) => {
+                // don't push the message yet, it's been queued (or dropped as a replay)
+                debug!(
+                    "message with nonce {} not yet handled for connection",
+                    nonce
+                );
+                return Ok(());
+            }
+            Err(e) => {
+                self.fail_connection(&id, e);
+                return Ok(());
+            }
         };
 
-        let Some(inbound_tx) = self.connections.get(&msg.id) else {
+        let Some(handle) = self.connections.handle(&msg.id) else {
             return Err(Error::NoConnectionForTransportMessage);
         };
+        let inbound_tx = &handle.inbound_tx;
 
         // send original message
         debug!(
             "sending original message with nonce {} for connection",
             nonce
         );
-        inbound_tx
-            .send(msg.message.clone())
-            .map_err(|e| Error::InboundSendFailure(e.to_string()))?;
+        if !is_stale_open_request(&self.config, &self.events, &msg) {
+            inbound_tx
+                .send(ConnectionEvent::Substream(
+                    msg.message.clone(),
+                    Span::current(),
+                ))
+                .map_err(|e| Error::InboundSendFailure(e.to_string()))?;
+        }
 
         // try to pop queued messages and send them on inbound channel
-        while let Some(msg) = queue.pop() {
+        loop {
+            let msg = match queue.pop() {
+                Ok(Some(msg)) => msg,
+                Ok(None) => break,
+                Err(e) => {
+                    self.fail_connection(&id, e);
+                    return Ok(());
+                }
+            };
             debug!(
                 "popped queued message with nonce {} for connection",
                 msg.nonce
             );
+            if is_stale_open_request(&self.config, &self.events, &msg) {
+                continue;
+            }
             inbound_tx
-                .send(msg.message.clone())
+                .send(ConnectionEvent::Substream(
+                    msg.message.clone(),
+                    msg.span(Direction::Inbound),
+                ))
                 .map_err(|e| Error::InboundSendFailure(e.to_string()))?;
         }
-
-        if let Some(waker) = self.waker.clone().take() {
-            waker.wake();
+
+        // the remote's nonces are running out, so it has to redial before they repeat
+        if queue.next_expected_nonce().needs_rehandshake() {
+            debug!("nonces exhausted on connection {:?}", msg.id);
+            self.fail_connection(&msg.id, Error::NoncesExhausted);
+        }
+
+        Ok(())
+    }
+
+    /// handle_datagram hands a datagram straight to its connection. Datagrams have no
+    /// nonce to order them by, and aren't acknowledged, since they're never retransmitted.
+    fn handle_datagram(&mut self, msg: TransportMessage) -> Result<(), Error> {
+        let Some(handle) = self.connections.handle(&msg.id) else {
+            debug!("dropping datagram for unknown connection {:?}", msg.id);
+            return Ok(());
+        };
+        handle
+            .inbound_tx
+            .send(ConnectionEvent::Substream(msg.message, Span::current()))
+            .map_err(|e| Error::InboundSendFailure(e.to_string()))?;
+        Ok(())
+    }
+
+    /// send_ack acknowledges a TransportMessage so that the remote stops retransmitting it,
+    /// if the remote asked for acks when opening the connection.
+    /// Replayed messages are acknowledged too, in case our first ack was lost.
+    fn send_ack(&self, msg: &TransportMessage) -> Result<(), Error> {
+        let Some(handle) = self.connections.handle(&msg.id) else {
+            // the message is acknowledged when it's retransmitted,
+            // once the connection has been established
+            return Ok(());
+        };
+        if !handle.wants_acks() {
+            return Ok(());
+        }
+
+        if let Some(queue) = self.message_queues.get(&msg.id) {
+            if let Err(Error::NonceOutsideWindow(_)) = queue.check_nonce(msg.nonce) {
+                // dropped without being queued, so it has to be retransmitted
+                return Ok(());
+            }
+        }
+
+        handle
+            .outbound_tx
+            .try_send(handle.ack_message(msg.nonce))
+            .map_err(|e| Error::OutboundSendFailure(e.to_string()))
+    }
+
+    /// close_expired_gaps closes every connection that's been waiting on a
+    /// missing message for longer than the gap timeout.
+    fn close_expired_gaps(&mut self) {
+        let expired: Vec<ConnectionId> = self
+            .message_queues
+            .iter()
+            .filter(|(_, queue)| queue.gap_expired())
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in expired {
+            debug!("gap timeout on connection {:?}", id);
+            self.fail_connection(&id, Error::MessageGapTimeout);
+        }
+    }
+
+    /// sample_queue_depths records how many messages, dials and substreams are waiting,
+    /// if metrics are recorded.
+    fn sample_queue_depths(&self) {
+        let metrics = &self.config.metrics;
+        if !metrics.is_enabled() {
+            return;
+        }
+        metrics.set_queue_depths(QueueDepths {
+            inbound: self.inbound_stream.len()
+                + self
+                    .dial_clients
+                    .iter()
+                    .map(|client| client.inbound_stream.len())
+                    .sum::<usize>(),
+            outbound: self.outbound_tx.channel_len()
+                + self
+                    .dial_clients
+                    .iter()
+                    .map(|client| client.outbound_tx.channel_len())
+                    .sum::<usize>(),
+            pending_dials: self.connections.pending_dials().count(),
+            pending_substreams: self
+                .connections
+                .handles()
+                .map(ConnectionHandle::pending_substreams)
+                .sum(),
+        });
+    }
+
+    /// bind_reply_route starts tracking the SURBs of connection `id`, which we can only
+    /// reply to through the SURBs of `peer_id`'s `sender_tag`, and binds it to the tag.
+    fn bind_reply_route(
+        &mut self,
+        id: &ConnectionId,
+        sender_tag: AnonymousSenderTag,
+        peer_id: PeerId,
+    ) {
+        let now = Instant::now();
+        self.reply_routes.track(id.clone(), peer_id, now);
+        if !self.bindings.bind(id.clone(), sender_tag, peer_id, now) {
+            debug!("connection {:?} is already bound to another sender tag", id);
+        }
+    }
+
+    /// report_expiring_reply_routes emits an event for every connection whose SURBs
+    /// have grown older than the configured maximum age.
+    fn report_expiring_reply_routes(&mut self) {
+        let Some(max_age) = self.config.reply_route_max_age else {
+            return;
+        };
+        for (peer_id, age) in self.reply_routes.expiring(max_age, Instant::now()) {
+            debug!("reply route to {} is {:?} old", peer_id, age);
+            self.events
+                .emit(NymEvent::ReplyRouteExpiring { peer_id, age });
+        }
+    }
+
+    /// expire_bindings fails every connection whose remote hasn't sent anything from the
+    /// sender tag it's bound to for the configured TTL.
+    fn expire_bindings(&mut self) {
+        let Some(ttl) = self.config.sender_tag_binding_ttl else {
+            return;
+        };
+        for (id, peer_id) in self.bindings.expire(ttl, Instant::now()) {
+            debug!("sender tag binding of {} on {:?} expired", peer_id, id);
+            self.fail_connection(&id, Error::SenderTagExpired);
+        }
+    }
+
+    /// expire_challenges fails every accepted connection whose dialer hasn't answered
+    /// our challenge within the handshake timeout, most likely because someone other
+    /// than the dialer replayed its ConnectionRequest.
+    fn expire_challenges(&mut self) {
+        let expired: Vec<ConnectionId> = self
+            .challenges
+            .iter()
+            .filter(|(_, pending)| pending.sent.elapsed() > self.handshake_timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in expired {
+            debug!("challenge timeout on connection {:?}", id);
+            self.fail_connection(&id, Error::ChallengeTimeout);
+        }
+    }
+
+    /// on_request_sent starts the handshake timeout of dial `id`, whose ConnectionRequest
+    /// was written to the mixnet.
+    fn on_request_sent(&mut self, id: ConnectionId) {
+        if self.connections.kind(&id) == StateKind::PendingOutbound {
+            self.sent_requests.insert(id, Instant::now());
+        }
+    }
+
+    /// expire_handshakes fails the dials whose ConnectionRequest has gone unanswered for
+    /// longer than the handshake timeout since it was written, and forgets those that
+    /// were answered, or failed otherwise.
+    fn expire_handshakes(&mut self) {
+        let connections = &self.connections;
+        self.sent_requests
+            .retain(|id, _| connections.kind(id) == StateKind::PendingOutbound);
+        let expired: Vec<ConnectionId> = self
+            .sent_requests
+            .iter()
+            .filter(|(_, sent)| sent.elapsed() > self.handshake_timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in expired {
+            debug!("handshake timeout on dial {:?}", id);
+            self.sent_requests.remove(&id);
+            self.relays.remove(&id);
+            self.forget_surbs(&id);
+            // the request may still be answered, in which case the remote's end is closed
+            if let Ok(pending_conn) = self.connections.abandon(&id) {
+                pending_conn
+                    .connection_tx
+                    .send(Err(Error::HandshakeTimeout))
+                    .ok();
+            }
+        }
+    }
+
+    /// fail_dial fails dial `id` with `error`, unless it's been answered already.
+    fn fail_dial(&mut self, id: &ConnectionId, error: Error) {
+        self.sent_requests.remove(id);
+        if self.connections.kind(id) != StateKind::PendingOutbound {
+            return;
+        }
+        if let Ok(pending_conn) = self.connections.abort(id) {
+            pending_conn.connection_tx.send(Err(error)).ok();
+        }
+        self.relays.remove(id);
+        self.forget_surbs(id);
+    }
+
+    /// forget_surbs stops the mixnet tasks attributing replies to connection `id`, once
+    /// it's closed or its dial has failed, so that they don't remember it forever.
+    fn forget_surbs(&self, id: &ConnectionId) {
+        let tasks = self
+            .mixnet_task
+            .iter()
+            .chain(self.dial_clients.iter().map(|client| &client.mixnet_task));
+        for task in tasks {
+            task.forget_connection(id);
+        }
+    }
+
+    /// handle_challenge_response hands an accepted connection to the swarm once its
+    /// dialer has signed the challenge we sent it with the identity it dialed with,
+    /// and lets the messages it sent in the meantime through to the connection. A
+    /// response that isn't signed by that identity is dropped.
+    fn handle_challenge_response(
+        &mut self,
+        msg: ChallengeResponseMessage,
+    ) -> Result<InboundTransportEvent, Error> {
+        let Some(pending) = self.challenges.get(&msg.id) else {
+            return Err(Error::NoConnectionForChallengeResponse);
+        };
+        if let Err(e) = verify_challenge(&pending.identity, &pending.challenge, &msg) {
+            debug!(
+                "dropping challenge response on connection {:?}: {}",
+                msg.id, e
+            );
+            self.events.emit(NymEvent::MessageDropped {
+                reason: DropReason::Invalid,
+            });
+            return Ok(InboundTransportEvent::ConnectionRejected);
+        }
+
+        // the connection can't be reported if every listener was removed in the meantime
+        let (relay, service) = (pending.relay, pending.service);
+        if !self.is_listening(relay.as_ref(), service) {
+            self.fail_connection(&msg.id, Error::ConnectionRejected("not_listening"));
+            return Ok(InboundTransportEvent::ConnectionRejected);
+        }
+        let Some(pending) = self.challenges.remove(&msg.id) else {
+            return Err(Error::NoConnectionForChallengeResponse);
+        };
+        if let (Some(ticket), Some(handle)) = (pending.ticket, self.connections.handle(&msg.id)) {
+            if let Err(e) = handle.outbound_tx.try_send(ticket) {
+                debug!("failed to queue session ticket: {}", e);
+            }
+        }
+        if let Err(e) = self.handle_message_queue_on_connection_initiation(&msg.id) {
+            debug!("failing connection {:?}: {}", msg.id, e);
+            self.fail_connection(&msg.id, e);
+            return Ok(InboundTransportEvent::ConnectionRejected);
+        }
+        if let Some(listener) = relay.and_then(|relay| self.relay_listener(&relay)) {
+            return Ok(InboundTransportEvent::RelayedConnectionRequest(
+                pending.upgrade,
+                listener.listener_id,
+                pending.send_back_addr,
+            ));
+        }
+        Ok(InboundTransportEvent::ConnectionRequest(
+            pending.upgrade,
+            service,
+            pending.send_back_addr,
+        ))
+    }
+
+    /// fail_connection closes the given connection with `error`.
+    fn fail_connection(&mut self, id: &ConnectionId, error: Error) {
+        self.message_queues.remove(id);
+        if let Err(e) = self.connections.close(id, ConnectionEvent::Failed(error)) {
+            debug!("not failing connection {:?}: {}", id, e);
+        }
+        // a connection that's never been handed to the swarm is dropped with its upgrade
+        self.challenges.remove(id);
+    }
+
+    /// handle_connection_close forgets a connection the remote has closed, and resets
+    /// the Connection so that its open substreams fail.
+    fn handle_connection_close(&mut self, msg: ConnectionCloseMessage) {
+        self.message_queues.remove(&msg.id);
+        // the remote that closed the connection is done with its ID
+        self.bindings.unbind(&msg.id);
+        // both sides may close the connection at once
+        if let Err(e) = self.connections.close(&msg.id, ConnectionEvent::Reset) {
+            debug!("no connection for ConnectionClose {:?}: {}", msg.id, e);
+        }
+        self.challenges.remove(&msg.id);
+    }
+
+    /// handle_migrate sends the messages of a connection to the new nym address of its
+    /// remote, once it's proven the move was made by the identity it opened the
+    /// connection with. A move it told us about before this one is ignored.
+    fn handle_migrate(&mut self, msg: MigrateMessage) -> Result<(), Error> {
+        let Some(handle) = self.connections.handle(&msg.id) else {
+            return Err(Error::NoConnectionForMigrate);
+        };
+        let Some(identity) = handle.remote_identity() else {
+            return Err(Error::NoConnectionForMigrate);
+        };
+        if let Err(e) = verify_migration(identity, &msg) {
+            debug!("dropping migrate on connection {:?}: {}", msg.id, e);
+            self.events.emit(NymEvent::MessageDropped {
+                reason: DropReason::Invalid,
+            });
+            return Ok(());
+        }
+
+        let peer_id = handle.remote_peer_id();
+        if !self.migrations.migrate(&msg.id, msg.recipient, msg.seq) {
+            debug!("ignoring outdated migrate on connection {:?}", msg.id);
+            return Ok(());
+        }
+        info!("peer {} moved to {}", peer_id, msg.recipient);
+        self.address_book.insert(peer_id, msg.recipient);
+        self.events.emit(NymEvent::ConnectionMigrated {
+            peer_id,
+            address: msg.recipient,
+        });
+        Ok(())
+    }
+
+    /// migrate_connections tells the remote of every connection that can follow us that
+    /// we moved to `address`. Connections it can't follow are left to time out.
+    fn migrate_connections(&mut self, address: &Recipient) {
+        self.migration_seq += 1;
+        for handle in self.connections.handles() {
+            if handle.is_closed() {
+                continue;
+            }
+            let msg = match handle.migrate_message(address, self.migration_seq) {
+                Ok(Some(msg)) => msg,
+                Ok(None) => continue,
+                Err(e) => {
+                    debug!("failed to sign migrate to {}: {}", address, e);
+                    continue;
+                }
+            };
+            if let Err(e) = handle.outbound_tx.try_send(msg) {
+                debug!(
+                    "failed to queue migrate to {}: {}",
+                    handle.remote_peer_id(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// listen_on_service listens on `service` of our own address. A service has a single
+    /// listener at a time.
+    fn listen_on_service(&mut self, listener_id: ListenerId, service: u16) -> Result<(), Error> {
+        if self.service_listeners.contains_key(&service) {
+            return Err(Error::ServiceListenerExists(service));
+        }
+        let listen_addr = NymMultiaddr::new(self.self_address)
+            .with_service(service)
+            .to_multiaddr()?;
+        info!("listening on {} with {:?}", listen_addr, listener_id);
+        if self.config.reports_listen_addr(&listen_addr) {
+            self.poll_tx
+                .send(TransportEvent::NewAddress {
+                    listener_id,
+                    listen_addr: listen_addr.clone(),
+                })
+                .map_err(|_| Error::SendErrorTransportEvent)?;
+        }
+        self.service_listeners.insert(
+            service,
+            ServiceListener {
+                listener_id,
+                listen_addr,
+            },
+        );
+        Ok(())
+    }
+
+    /// close_listener reports that the listener on our own `listen_addr` was removed.
+    fn close_listener(&self, listener_id: ListenerId, listen_addr: Multiaddr) {
+        // poll_rx is owned by self, so these can't fail
+        if self.config.reports_listen_addr(&listen_addr) {
+            self.poll_tx
+                .send(TransportEvent::AddressExpired {
+                    listener_id,
+                    listen_addr,
+                })
+                .ok();
+        }
+        self.poll_tx
+            .send(TransportEvent::ListenerClosed {
+                listener_id,
+                reason: Ok(()),
+            })
+            .ok();
+    }
+
+    /// listen_on_relay asks `relay` to forward us the circuits that peers open to us
+    /// through it. The listener's address is reported once the relay accepts.
+    fn listen_on_relay(&mut self, listener_id: ListenerId, relay: Recipient) -> Result<(), Error> {
+        let id = ConnectionId::generate();
+        let reservation = relay_reservation(&self.keypair, &id, relay)?;
+        self.outbound_tx
+            .try_send(reservation)
+            .map_err(|e| Error::OutboundSendFailure(e.to_string()))?;
+
+        info!("listening through relay {} with {:?}", relay, listener_id);
+        let listen_addr = NymMultiaddr::new(relay).with_relayed(true).to_multiaddr()?;
+        self.relays.reserve(relay);
+        self.relay_listeners.insert(
+            id,
+            RelayListener {
+                listener_id,
+                relay,
+                listen_addr,
+                reserved: false,
+                renew_at: Instant::now() + self.handshake_timeout,
+            },
+        );
+        Ok(())
+    }
+
+    /// renew_relay_reservations resends the reservations that are due, so that they're
+    /// renewed before the relay drops them, or retried if it hasn't answered.
+    fn renew_relay_reservations(&mut self) {
+        let now = Instant::now();
+        for (id, listener) in self.relay_listeners.iter_mut() {
+            if listener.renew_at > now {
+                continue;
+            }
+            listener.renew_at = now + self.handshake_timeout;
+            let res = relay_reservation(&self.keypair, id, listener.relay).and_then(|msg| {
+                self.outbound_tx
+                    .try_send(msg)
+                    .map_err(|e| Error::OutboundSendFailure(e.to_string()))
+            });
+            if let Err(e) = res {
+                debug!("failed to renew reservation with {}: {}", listener.relay, e);
+            }
+        }
+    }
+
+    /// close_relay_listener forgets a listener on a relay's address, and closes it with
+    /// `reason`.
+    fn close_relay_listener(&self, listener: RelayListener, reason: Result<(), Error>) {
+        self.relays.release(&listener.relay);
+        // poll_rx is owned by self, so these can't fail
+        if listener.reserved && self.config.reports_listen_addr(&listener.listen_addr) {
+            self.poll_tx
+                .send(TransportEvent::AddressExpired {
+                    listener_id: listener.listener_id,
+                    listen_addr: listener.listen_addr,
+                })
+                .ok();
+        }
+        self.poll_tx
+            .send(TransportEvent::ListenerClosed {
+                listener_id: listener.listener_id,
+                reason,
+            })
+            .ok();
+    }
+
+    /// handle_relay_status handles a relay's answer to one of our reservations, or to
+    /// a circuit it couldn't open for one of our dials. An accepted reservation reports
+    /// its listener's address the first time, and is renewed halfway through its TTL.
+    fn handle_relay_status(&mut self, msg: RelayStatusMessage) -> Result<(), Error> {
+        if let Some(listener) = self.relay_listeners.get_mut(&msg.id) {
+            if msg.status != RelayStatus::Reserved {
+                let listener = self.relay_listeners.remove(&msg.id).expect("found above");
+                info!(
+                    "relay {} refused reservation: {}",
+                    listener.relay, msg.status
+                );
+                self.close_relay_listener(listener, Err(Error::RelayRefused(msg.status)));
+                return Ok(());
+            }
+
+            let ttl = Duration::from_secs(msg.ttl_secs.into());
+            listener.renew_at = Instant::now() + ttl / 2;
+            if !listener.reserved {
+                listener.reserved = true;
+                if self.config.reports_listen_addr(&listener.listen_addr) {
+                    self.poll_tx
+                        .send(TransportEvent::NewAddress {
+                            listener_id: listener.listener_id,
+                            listen_addr: listener.listen_addr.clone(),
+                        })
+                        .map_err(|_| Error::SendErrorTransportEvent)?;
+                }
+            }
+            return Ok(());
+        }
+
+        let Some(relay) = self.relays.relay_of(&msg.id) else {
+            return Err(Error::NoRelayForRelayStatus);
+        };
+        info!(
+            "relay {} failed circuit {:?}: {}",
+            relay, msg.id, msg.status
+        );
+        if self.connections.kind(&msg.id) == StateKind::PendingOutbound {
+            self.fail_dial(&msg.id, Error::RelayRefused(msg.status));
+        } else {
+            self.fail_connection(&msg.id, Error::RelayRefused(msg.status));
+        }
+        Ok(())
+    }
+
+    /// handle_relay_reserve makes or renews a peer's reservation with us as a relay,
+    /// once it's proven it was made by the identity it's for, and answers it through
+    /// the SURBs it came with.
+    fn handle_relay_reserve(
+        &mut self,
+        msg: RelayReserveMessage,
+        sender_tag: Option<AnonymousSenderTag>,
+    ) -> Result<(), Error> {
+        // the peer's circuits are forwarded to it through its SURBs
+        let Some(sender_tag) = sender_tag else {
+            debug!("dropping reservation {:?} without SURBs", msg.id);
+            return Ok(());
+        };
+
+        let res = match &mut self.relay_server {
+            None => Err(RelayStatus::Refused),
+            Some(_) if verify_relay_reservation(&msg, &self.self_address).is_err() => {
+                Err(RelayStatus::Refused)
+            }
+            Some(server) => server.reserve(msg.peer_id(), sender_tag.clone(), Instant::now()),
+        };
+        let (status, ttl_secs) = match res {
+            Ok(ttl) => (
+                RelayStatus::Reserved,
+                ttl.as_secs().try_into().unwrap_or(u32::MAX),
+            ),
+            Err(status) => (status, 0),
+        };
+        self.outbound_tx
+            .try_send(OutboundMessage {
+                message: Message::RelayStatus(RelayStatusMessage {
+                    id: msg.id,
+                    status,
+                    ttl_secs,
+                }),
+                recipient: None,
+                sender_tag: Some(sender_tag),
+            })
+            .map_err(|e| Error::OutboundSendFailure(e.to_string()))
+    }
+
+    /// handle_relay forwards a message on a circuit we relay to its other end. A dialer
+    /// whose circuit can't be opened is told why; anything else that can't be forwarded
+    /// is dropped.
+    fn handle_relay(
+        &mut self,
+        msg: RelayMessage,
+        sender_tag: Option<AnonymousSenderTag>,
+    ) -> Result<(), Error> {
+        // both ends of a circuit send through their SURBs, and are forwarded through them
+        let Some(sender_tag) = sender_tag else {
+            debug!("dropping relayed message {:?} without SURBs", msg.id);
+            return Ok(());
+        };
+
+        let res = match &mut self.relay_server {
+            Some(server) => server.forward(&msg.id, &msg.hop, &sender_tag, Instant::now()),
+            None => Err(RelayStatus::Refused),
+        };
+        let message = match res {
+            Ok(forward_tag) => OutboundMessage {
+                message: Message::Relay(RelayMessage {
+                    id: msg.id,
+                    hop: RelayHop::Delivered(self.self_address),
+                    payload: msg.payload,
+                }),
+                recipient: None,
+                sender_tag: Some(forward_tag),
+            },
+            Err(status) if matches!(msg.hop, RelayHop::ToListener(_)) => OutboundMessage {
+                message: Message::RelayStatus(RelayStatusMessage {
+                    id: msg.id,
+                    status,
+                    ttl_secs: 0,
+                }),
+                recipient: None,
+                sender_tag: Some(sender_tag),
+            },
+            Err(status) => {
+                debug!("not relaying message {:?}: {}", msg.id, status);
+                return Ok(());
+            }
+        };
+        self.outbound_tx
+            .try_send(message)
+            .map_err(|e| Error::OutboundSendFailure(e.to_string()))
+    }
+
+    /// handle_keepalive hands a keepalive message to its connection, which
+    /// answers pings and tracks pongs itself. A ping that's too old for its
+    /// answer to be of use is dropped.
+    fn handle_keepalive(&mut self, msg: KeepAliveMessage) -> Result<(), Error> {
+        let Some(handle) = self.connections.handle(&msg.id) else {
+            return Err(Error::NoConnectionForKeepAlive);
+        };
+        if msg.keepalive_type == KeepAliveType::Ping
+            && is_stale_control(&self.config, &self.events, "keepalive", msg.timestamp)
+        {
+            return Ok(());
+        }
+
+        handle
+            .inbound_tx
+            .send(ConnectionEvent::KeepAlive(msg))
+            .map_err(|e| Error::InboundSendFailure(e.to_string()))
+    }
+
+    /// create_connection_types creates the Connection opened by the remote's ConnectionRequest,
+    /// ConnectionResponse or ResumeRequest, and the transport's handle to it.
+    fn create_connection_types(
+        &self,
+        remote: Remote<'_>,
+        remote_recipient: Option<Recipient>,
+        sender_tag: Option<AnonymousSenderTag>,
+        cipher: SessionCipher,
+        outbound_tx: BoundedSender<OutboundMessage>,
+    ) -> (Connection, ConnectionHandle) {
+        let remote_capabilities = remote.capabilities;
+        let (inbound_tx, inbound_rx) = unbounded_channel::<ConnectionEvent>();
+        // each connection gets its own outbound queue, which the mixnet task drains in
+        // turn with the others, so a bulk transfer can't starve other connections
+        let outbound_tx = outbound_tx.split();
+
+        let mut conn = Connection::new_with_sender_tag(
+            remote.peer_id,
+            remote_recipient,
+            remote.id.clone(),
+            inbound_rx,
+            outbound_tx,
+            sender_tag,
+        )
+        .with_fragmentation(
+            negotiated_fragment_size(self.config.max_fragment_size, remote.max_message_size),
+            self.config.reassembly_timeout,
+        )
+        .with_receive_window(self.config.receive_window)
+        .with_max_buffered_frames(self.config.max_buffered_frames)
+        .with_open_timeout(self.config.substream_open_timeout)
+        .with_cipher(cipher)
+        .with_metrics(self.config.metrics.clone())
+        .with_events(self.events.clone())
+        .with_rtt(self.rtt.clone())
+        .with_stats(self.stats.clone())
+        .with_remote_info(remote.info.clone());
+        if let Some(interval) = self.config.keepalive_interval {
+            conn = conn.with_keepalive(interval, self.config.keepalive_max_missed);
+        }
+        if let Some(timeout) = self.config.idle_timeout {
+            conn = conn.with_idle_timeout(timeout);
+        }
+        if let Some(threshold) = self.config.compression_threshold {
+            let both = self.capabilities().contains(Capabilities::COMPRESSION)
+                && remote_capabilities.contains(Capabilities::COMPRESSION);
+            if both {
+                conn = conn.with_compression(threshold);
+            }
+        }
+        if remote_capabilities.contains(Capabilities::TIMESTAMPS) {
+            conn = conn.with_timestamps();
+        }
+        if remote_capabilities.contains(Capabilities::DATAGRAMS) {
+            conn = conn.with_datagrams();
         }
 
+        let handle = conn
+            .handle(inbound_tx)
+            .with_remote_capabilities(remote_capabilities)
+            .with_remote_max_message_size(remote.max_message_size)
+            .with_remote_identity(remote.identity.clone());
+        (conn, handle)
+    }
+
+    /// handle_session_ticket keeps the session ticket the remote issued us for a
+    /// connection, which it's resumed with if the remote loses its state.
+    fn handle_session_ticket(&mut self, msg: SessionTicketMessage) -> Result<(), Error> {
+        let Some(handle) = self.connections.handle(&msg.id) else {
+            return Err(Error::NoConnectionForSessionTicket);
+        };
+        handle.set_session_ticket(msg.ticket);
         Ok(())
     }
 
-    fn create_connection_types(
-        &self,
-        remote_peer_id: PeerId,
-        remote_recipient: Option<Recipient>,
-        id: ConnectionId,
+    /// handle_resume_request takes back up a connection we lost the state of, e.g. by
+    /// restarting, from the session ticket we issued its remote, and hands it to the
+    /// swarm as a new inbound connection once the remote has answered our challenge.
+    /// Neither side has to shake hands again: both derive new keys from the ticket's
+    /// resumption secret, once the remote has proven it holds it. The admission hook
+    /// isn't asked, since we accepted the connection when we issued the ticket. A
+    /// replayed request is dropped if we remember the original, and otherwise never
+    /// reaches the swarm, since whoever replayed it can't answer the challenge.
+    fn handle_resume_request(
+        &mut self,
+        msg: ResumeRequestMessage,
         sender_tag: Option<AnonymousSenderTag>,
-    ) -> (Connection, UnboundedSender<SubstreamMessage>) {
-        let (inbound_tx, inbound_rx) = unbounded_channel::<SubstreamMessage>();
+    ) -> Result<InboundTransportEvent, Error> {
+        let Some(ticket_key) = &self.ticket_key else {
+            return self.reject_connection_request(Error::ConnectionRejected("no_resumption"));
+        };
+        let ticket = ticket_key
+            .open(&msg.ticket, unix_millis())
+            .and_then(|ticket| {
+                let proof = ticket
+                    .secret
+                    .request_proof(&msg.id, &msg.nonce, msg.timestamp);
+                if ticket.id != msg.id || !proofs_match(&proof, &msg.proof) {
+                    return Err(Error::InvalidResumptionProof);
+                }
+                Ok(ticket)
+            });
+        let ticket = match ticket {
+            Ok(ticket) => ticket,
+            Err(e) => {
+                debug!("dropping resume request on connection {:?}: {}", msg.id, e);
+                self.events.emit(NymEvent::MessageDropped {
+                    reason: DropReason::Invalid,
+                });
+                return Ok(InboundTransportEvent::ConnectionRejected);
+            }
+        };
+        // a resumer picks a new nonce for every request, which, like the ID of a
+        // connection, no two requests share
+        let key = ConnectionId::from_bytes(&msg.nonce);
+        if let Err(e) = self.replays.insert(&key, msg.timestamp, unix_millis()) {
+            return self.reject_connection_request(e);
+        }
+        // the connection may not be lost at all, and only its keepalives were
+        if self.connections.contains(&msg.id) || self.admitting.contains(&msg.id) {
+            return Err(Error::ConnectionIDExists);
+        }
+        // the connection goes back to the listener it was accepted on
+        if !self.is_listening(None, ticket.service) {
+            return self.reject_connection_request(Error::ConnectionRejected("not_listening"));
+        }
+        if let Err(e) = self.check_limits(sender_tag.as_ref()) {
+            return self.reject_connection_request(e);
+        }
 
-        let conn = Connection::new_with_sender_tag(
-            remote_peer_id,
-            remote_recipient,
-            id,
-            inbound_rx,
+        let nonce = new_resume_nonce();
+        let proof = ticket.secret.response_proof(&msg.id, &msg.nonce, &nonce);
+        let cipher = ticket
+            .secret
+            .resume(&msg.id, &msg.nonce, &nonce, ticket.role);
+        let (role, secret) = (cipher.role(), cipher.resumption_secret().clone());
+        let peer_id = PeerId::from_public_key(&ticket.remote_identity);
+        let sender_tag = if ticket.remote_recipient.is_some() {
+            None
+        } else {
+            sender_tag
+        };
+
+        let remote = Remote {
+            peer_id,
+            id: &msg.id,
+            capabilities: ticket.remote_capabilities,
+            max_message_size: ticket.remote_max_message_size,
+            info: &ticket.remote_info,
+            identity: &ticket.remote_identity,
+        };
+        let (conn, conn_handle) = self.create_connection_types(
+            remote,
+            ticket.remote_recipient,
+            sender_tag.clone(),
+            cipher,
             self.outbound_tx.clone(),
-            sender_tag,
         );
+        let conn_handle = conn_handle
+            .with_local_key(self.keypair.clone())
+            .with_exposed_address();
+        let ticket_msg = self.session_ticket(&msg.id, &conn_handle, role, secret, ticket.service);
+        let upgrade = Arc::new(());
+        self.connections
+            .accept(msg.id.clone(), conn_handle, &upgrade)?;
+        if let Some(sender_tag) = &sender_tag {
+            self.bind_reply_route(&msg.id, sender_tag.clone(), peer_id);
+        }
+        // the remote's nonces start over, so whatever it sent before is forgotten
+        let queue = MessageQueue::new(self.config.replay_window, self.config.gap_timeout);
+        self.message_queues.insert(msg.id.clone(), queue);
+        self.duplicates.forget(&msg.id);
+        info!("resuming connection {:?} with {}", msg.id, peer_id);
+
+        self.outbound_tx
+            .try_send(OutboundMessage {
+                message: Message::ResumeResponse(ResumeResponseMessage {
+                    id: msg.id.clone(),
+                    nonce,
+                    proof,
+                }),
+                recipient: ticket.remote_recipient,
+                sender_tag: sender_tag.clone(),
+            })
+            .map_err(|e| Error::OutboundSendFailure(e.to_string()))?;
+
+        let (connection_tx, connection_rx) = oneshot::channel::<(PeerId, Connection)>();
+        connection_tx
+            .send((peer_id, conn))
+            .map_err(|_| Error::ConnectionSendFailure)?;
+        let send_back_addr = ticket
+            .remote_recipient
+            .and_then(|recipient| nym_address_to_multiaddr(recipient).ok())
+            .unwrap_or_else(|| self.listen_addr.clone());
+        // a request replayed once the resumer's is forgotten would be answered again, so
+        // the connection is held back until the resumer signs our nonce with its identity
+        self.challenges.insert(
+            msg.id.clone(),
+            PendingChallenge {
+                challenge: nonce,
+                identity: ticket.remote_identity,
+                upgrade: Upgrade::new(connection_rx, upgrade),
+                send_back_addr,
+                relay: None,
+                service: ticket.service,
+                sent: Instant::now(),
+                ticket: ticket_msg,
+            },
+        );
+        Ok(InboundTransportEvent::ConnectionChallenged)
+    }
+
+    /// handle_resume_response carries on a connection we resumed with the keys the remote
+    /// derived along with us, and signs the nonce of the response, which the remote
+    /// holds the connection back until we return. A response that doesn't prove the
+    /// remote opened our ticket is dropped.
+    fn handle_resume_response(&mut self, msg: ResumeResponseMessage) -> Result<(), Error> {
+        let Some(handle) = self.connections.handle(&msg.id) else {
+            return Err(Error::NoConnectionForResumeResponse);
+        };
+        let Some(local_key) = handle.local_key() else {
+            return Err(Error::HandshakeSigningFailure);
+        };
+        let signature = sign_challenge(local_key, &msg.id, &msg.nonce)?;
+        let cipher = match handle.finish_resume(&msg) {
+            Ok(cipher) => cipher,
+            Err(Error::InvalidResumptionProof) => {
+                debug!("dropping resume response on connection {:?}", msg.id);
+                self.events.emit(NymEvent::MessageDropped {
+                    reason: DropReason::Invalid,
+                });
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        handle
+            .outbound_tx
+            .try_send(OutboundMessage {
+                message: Message::ChallengeResponse(ChallengeResponseMessage {
+                    id: msg.id.clone(),
+                    signature,
+                }),
+                recipient: handle.remote_recipient().copied(),
+                sender_tag: None,
+            })
+            .map_err(|e| Error::OutboundSendFailure(e.to_string()))?;
+        let inbound_tx = handle.inbound_tx.clone();
 
-        (conn, inbound_tx)
+        // the remote's nonces start over too
+        self.message_queues.remove(&msg.id);
+        self.duplicates.forget(&msg.id);
+        self.handle_message_queue_on_connection_initiation(&msg.id)?;
+        inbound_tx
+            .send(ConnectionEvent::Resumed(cipher))
+            .map_err(|e| Error::InboundSendFailure(e.to_string()))
     }
 
     /// handle_inbound handles an inbound message from the mixnet, received via self.inbound_stream.
@@ -351,21 +2808,53 @@ impl NymTransport {
         msg: Message,
         sender_tag: Option<AnonymousSenderTag>,
     ) -> Result<InboundTransportEvent, Error> {
+        let span = msg.span(Direction::Inbound);
+        let _entered = span.enter();
+        // messages opening a connection, or that aren't on one of ours, aren't bound to
+        // a sender tag; the ID of a connection that's open is refused by the handlers
+        let bound = !matches!(
+            msg,
+            Message::ConnectionRequest(_)
+                | Message::ResumeRequest(_)
+                | Message::VersionMismatch(_)
+                | Message::RelayReserve(_)
+                | Message::Relay(_)
+        );
+        if bound
+            && !self
+                .bindings
+                .verify(msg.connection_id(), sender_tag.as_ref(), Instant::now())
+        {
+            debug!(
+                "dropping message on connection {:?} from another sender tag",
+                msg.connection_id()
+            );
+            self.config.metrics.message_invalid("sender_tag_mismatch");
+            self.events.emit(NymEvent::MessageDropped {
+                reason: DropReason::SenderTagMismatch,
+            });
+            return Ok(InboundTransportEvent::Dropped);
+        }
+        // every message sent to our address comes with fresh SURBs
+        if sender_tag.is_some() {
+            self.reply_routes
+                .refresh(msg.connection_id(), Instant::now());
+        }
         match msg {
             Message::ConnectionRequest(inner) => {
                 debug!("got inbound connection request {:?}", inner);
-                match self.handle_connection_request(&inner, sender_tag) {
-                    Ok(conn) => {
-                        let (connection_tx, connection_rx) =
-                            oneshot::channel::<(PeerId, Connection)>();
-                        let upgrade = Upgrade::new(connection_rx);
-                        connection_tx
-                            .send((inner.peer_id, conn))
-                            .map_err(|_| Error::ConnectionSendFailure)?;
-                        Ok(InboundTransportEvent::ConnectionRequest(upgrade))
-                    }
-                    Err(e) => Err(e),
-                }
+                let id = inner.id.clone();
+                let res = match self.check_connection_request(&inner, sender_tag.as_ref()) {
+                    Err(e) => self.reject_connection_request(e),
+                    Ok(()) => match self.config.admission.clone() {
+                        Some(admission) => {
+                            self.admit_connection_request(&admission, inner, sender_tag)
+                        }
+                        None => self.accept_connection_request(inner, sender_tag),
+                    },
+                };
+                self.close_unaccepted_circuit(&id);
+                res
             }
             Message::ConnectionResponse(msg) => {
                 debug!("got inbound connection response {:?}", msg);
@@ -373,15 +2862,239 @@ impl NymTransport {
                     .map(|_| InboundTransportEvent::ConnectionResponse)
             }
             Message::TransportMessage(msg) => {
-                debug!(
-                    "Transport received TransportMessage: nonce={}, substream={:?}, msg_type={:?}",
-                    msg.nonce, msg.message.substream_id, msg.message.message_type
-                );
+                debug!("got inbound transport message");
                 self.handle_transport_message(msg)
                     .map(|_| InboundTransportEvent::TransportMessage)
             }
+            Message::KeepAlive(msg) => {
+                debug!("got inbound keepalive {:?}", msg);
+                self.handle_keepalive(msg)
+                    .map(|_| InboundTransportEvent::KeepAlive)
+            }
+            Message::Ack(msg) => {
+                // acks are consumed by the mixnet task, which does the retransmitting
+                debug!("ignoring inbound ack {:?}", msg);
+                Ok(InboundTransportEvent::Ack)
+            }
+            Message::Datagram(msg) => {
+                // datagrams are routed by the mixnet task, since they don't belong to a connection
+                debug!("ignoring inbound datagram {:?}", msg.id);
+                Ok(InboundTransportEvent::Datagram)
+            }
+            Message::Batch(msg) => {
+                // batches are unpacked by the mixnet task
+                debug!("ignoring inbound batch {:?}", msg.id);
+                Ok(InboundTransportEvent::TransportMessage)
+            }
+            Message::ConnectionClose(msg) => {
+                debug!("got inbound connection close {:?}", msg.id);
+                self.handle_connection_close(msg);
+                Ok(InboundTransportEvent::ConnectionClose)
+            }
+            Message::Migrate(msg) => {
+                debug!("got inbound migrate {:?}", msg.id);
+                self.handle_migrate(msg)
+                    .map(|_| InboundTransportEvent::Migrate)
+            }
+            Message::ChallengeResponse(msg) => {
+                debug!("got inbound challenge response {:?}", msg.id);
+                self.handle_challenge_response(msg)
+            }
+            Message::SessionTicket(msg) => {
+                debug!("got inbound session ticket {:?}", msg.id);
+                self.handle_session_ticket(msg)
+                    .map(|_| InboundTransportEvent::SessionTicket)
+            }
+            Message::ResumeRequest(msg) => {
+                debug!("got inbound resume request {:?}", msg.id);
+                self.handle_resume_request(msg, sender_tag)
+            }
+            Message::ResumeResponse(msg) => {
+                debug!("got inbound resume response {:?}", msg.id);
+                self.handle_resume_response(msg)
+                    .map(|_| InboundTransportEvent::ConnectionResumed)
+            }
+            Message::RelayReserve(msg) => {
+                debug!("got inbound relay reservation {:?}", msg.id);
+                self.handle_relay_reserve(msg, sender_tag)
+                    .map(|_| InboundTransportEvent::Relay)
+            }
+            Message::RelayStatus(msg) => {
+                debug!("got inbound relay status {:?}: {}", msg.id, msg.status);
+                self.handle_relay_status(msg)
+                    .map(|_| InboundTransportEvent::Relay)
+            }
+            Message::Relay(msg) => {
+                debug!("got inbound message to relay {:?}", msg.id);
+                self.handle_relay(msg, sender_tag)
+                    .map(|_| InboundTransportEvent::Relay)
+            }
+            Message::VersionMismatch(msg) => {
+                debug!(
+                    "got inbound connection message of protocol version {}",
+                    msg.version
+                );
+                let request = msg.request;
+                self.handle_version_mismatch(msg, sender_tag).map(|_| {
+                    if request {
+                        InboundTransportEvent::ConnectionRejected
+                    } else {
+                        InboundTransportEvent::ConnectionResponse
+                    }
+                })
+            }
+        }
+    }
+}
+
+impl Drop for NymTransport {
+    fn drop(&mut self) {
+        if self.mixnet_task.is_none() {
+            return;
+        }
+
+        // we can't wait for room in the outbound channel here, so Closes that
+        // don't fit are dropped; the remote will time the substreams out instead.
+        for handle in self.connections.drain() {
+            for msg in handle.close_messages() {
+                if let Err(e) = handle.outbound_tx.try_send(msg) {
+                    debug!("failed to queue Close on drop: {}", e);
+                }
+            }
         }
+
+        // dropping the task handles makes the tasks flush their outbound channels,
+        // disconnect their mixnet clients and exit
+        self.dial_clients.clear();
+        self.mixnet_task.take();
+    }
+}
+
+/// connect_ephemeral connects a mixnet client with ephemeral keys to the gateway
+/// chosen by the config's gateway selection.
+/// nym_multiaddr_of parses `addr` as a nym address of `peer_id`, failing if it's of
+/// another peer.
+fn nym_multiaddr_of(peer_id: PeerId, addr: &Multiaddr) -> Result<NymMultiaddr, Error> {
+    let addr = NymMultiaddr::try_from(addr)?;
+    // the address book holds the nym addresses peers are reached at directly; a
+    // relayed address is the relay's
+    if addr.relayed {
+        return Err(Error::InvalidProtocolForMultiaddr);
+    }
+    if addr.peer_id.is_some_and(|p| p != peer_id) {
+        return Err(Error::UnexpectedPeerId);
+    }
+    Ok(addr)
+}
+
+/// relay_reservation returns the RelayReserve with ID `id` that asks `relay` to forward
+/// us the circuits opened to our identity. It's sent with SURBs, which the relay
+/// forwards the circuits through.
+fn relay_reservation(
+    keypair: &Keypair,
+    id: &ConnectionId,
+    relay: Recipient,
+) -> Result<OutboundMessage, Error> {
+    Ok(OutboundMessage {
+        message: Message::RelayReserve(RelayReserveMessage {
+            id: id.clone(),
+            identity: keypair.public(),
+            signature: sign_relay_reservation(keypair, id, &relay)?,
+        }),
+        recipient: Some(relay),
+        sender_tag: None,
+    })
+}
+
+/// is_stale_control returns true if a control frame stamped with `timestamp` arrived
+/// more than the configured maximum message age after it was sent, and records that
+/// it's dropped. Unstamped frames are never stale.
+fn is_stale_control(
+    config: &NymTransportConfig,
+    events: &EventSender,
+    kind: &'static str,
+    timestamp: Option<u64>,
+) -> bool {
+    let (Some(max_age), Some(timestamp)) = (config.message_max_age, timestamp) else {
+        return false;
+    };
+    if !is_stale(
+        timestamp,
+        unix_millis(),
+        max_age,
+        config.clock_skew_tolerance,
+    ) {
+        return false;
+    }
+    debug!("dropping stale {} sent at {}", kind, timestamp);
+    config.metrics.message_stale(kind);
+    events.emit(NymEvent::MessageDropped {
+        reason: DropReason::Stale,
+    });
+    true
+}
+
+/// is_stale_open_request returns true if `msg` is an OpenRequest too old to answer.
+/// The opener has given up on it or soon will, and closes its end once it does, so
+/// dropping it after it's taken its place in the connection's order is harmless.
+fn is_stale_open_request(
+    config: &NymTransportConfig,
+    events: &EventSender,
+    msg: &TransportMessage,
+) -> bool {
+    let SubstreamMessageType::OpenRequest(_) = msg.message.message_type else {
+        return false;
+    };
+    is_stale_control(
+        config,
+        events,
+        msg.message.message_type.kind(),
+        msg.timestamp,
+    )
+}
+
+async fn connect_ephemeral(config: &NymTransportConfig) -> Result<MixnetClient, Error> {
+    let mut builder = MixnetClientBuilder::new_ephemeral()
+        .latency_based_selection(config.gateway.is_latency_based())
+        .debug_config(config.traffic.debug_config());
+    if let Some(gateway) = config.gateway.requested_gateway() {
+        builder = builder.request_gateway(gateway);
+    }
+    if config.credentials.is_some() {
+        builder = builder.enable_credentials_mode();
+    }
+    let client = builder.build().map_err(Error::MixnetClientFailure)?;
+    if let Some(mnemonic) = config.credentials_mnemonic() {
+        client
+            .create_bandwidth_client(mnemonic, TicketType::V1MixnetEntry)
+            .await
+            .map_err(Error::MixnetClientFailure)?
+            .acquire()
+            .await
+            .map_err(Error::MixnetClientFailure)?;
+    }
+    client
+        .connect_to_mixnet()
+        .await
+        .map_err(Error::MixnetClientFailure)
+}
+
+/// connect_test_client connects the ephemeral mixnet client a test runs against, with
+/// the default config's traffic shaping, so that the `test-fast` feature speeds it up.
+#[cfg(test)]
+pub(crate) async fn connect_test_client() -> MixnetClient {
+    connect_ephemeral(&NymTransportConfig::default())
+        .await
+        .unwrap()
+}
+
+/// connect_dial_clients connects the config's number of ephemeral dial clients.
+async fn connect_dial_clients(config: &NymTransportConfig) -> Result<Vec<MixnetClient>, Error> {
+    let mut clients = Vec::with_capacity(config.dial_clients);
+    for _ in 0..config.dial_clients {
+        clients.push(connect_ephemeral(config).await?);
     }
+    Ok(clients)
 }
 
 /// Upgrade represents a transport listener upgrade.
@@ -389,11 +3102,16 @@ impl NymTransport {
 /// so this only contains a channel for receiving that connection.
 pub struct Upgrade {
     connection_tx: oneshot::Receiver<(PeerId, Connection)>,
+    /// counts the upgrade as a pending inbound connection until it resolves
+    _pending: Option<Arc<()>>,
 }
 
 impl Upgrade {
-    fn new(connection_tx: oneshot::Receiver<(PeerId, Connection)>) -> Upgrade {
-        Upgrade { connection_tx }
+    fn new(connection_tx: oneshot::Receiver<(PeerId, Connection)>, pending: Arc<()>) -> Upgrade {
+        Upgrade {
+            connection_tx,
+            _pending: Some(pending),
+        }
     }
 }
 
@@ -402,115 +3120,359 @@ impl Future for Upgrade {
 
     // poll checks if the upgrade has turned into a connection yet
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        self.connection_tx
-            .poll_unpin(cx)
-            .map_err(|_| Error::RecvFailure)
+        let res = ready!(self.connection_tx.poll_unpin(cx));
+        self._pending = None;
+        Poll::Ready(res.map_err(|_| Error::RecvFailure))
     }
 }
+
+/// race_dials runs dials to the same peer at different addresses, each started
+/// `stagger` after the one before, and returns the first to succeed. The others are
+/// dropped, which aborts them. If every dial fails, it fails with the last error.
+async fn race_dials(
+    dials: Vec<<NymTransport as Transport>::Dial>,
+    stagger: Duration,
+) -> Result<(PeerId, Connection), Error> {
+    let staggered = dials.into_iter().enumerate().map(|(i, dial)| {
+        async move {
+            // a dial that's aborted while it waits never sends its ConnectionRequest
+            sleep(stagger * i as u32).await;
+            dial.await
+        }
+        .boxed()
+    });
+    let (output, _aborted) = future::select_ok(staggered).await?;
+    Ok(output)
+}
+
 impl Transport for NymTransport {
     type Output = (PeerId, Connection);
     type Error = Error;
     type ListenerUpgrade = Upgrade;
     type Dial = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
 
-    // Since we're setting up our listener in mixnet::initialize_mixnet() then just impl the required function signature for the trait but we won't use it
-    // this is maybe a bit weird in libp2p world since the creation of a Nym Transport means you are automatically listening, and the port allocation is handled by the Nym SDK not the upstream app cf. https://docs.libp2p.io/concepts/transports/listen-and-dial/#common-transport-interfaces
+    // a transport starts out with a listener on its nym address, since the address is
+    // allocated by the mixnet client rather than chosen by the application.
+    // listen_on adds another listener on the same address, or a listener on one of its
+    // services, `/nym/<address>/svc/<service>`, which only the dials to it are reported on.
+    // cf. https://docs.libp2p.io/concepts/transports/listen-and-dial/#common-transport-interfaces
     fn listen_on(
         &mut self,
-        _listner_id: ListenerId,
-        _multi_addr: libp2p::Multiaddr,
+        id: ListenerId,
+        addr: Multiaddr,
     ) -> Result<(), TransportError<Self::Error>> {
-        info!("called listen_on, this is currently just a dummy function - client starts listening on new()");
+        // we can only listen on our own nym address, or through a relay other than us
+        let Ok(NymMultiaddr {
+            recipient,
+            peer_id,
+            relayed,
+            service,
+            ..
+        }) = NymMultiaddr::try_from(&addr)
+        else {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        };
+        if relayed == (recipient == self.self_address)
+            || relayed && (service.is_some() || peer_id.is_some_and(|id| id != self.peer_id()))
+        {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        }
+        if self.mixnet_task.is_none() {
+            return Err(TransportError::Other(Error::MixnetTaskShutdown));
+        }
+        if relayed {
+            return self
+                .listen_on_relay(id, recipient)
+                .map_err(TransportError::Other);
+        }
+        if let Some(service) = service {
+            return self
+                .listen_on_service(id, service)
+                .map_err(TransportError::Other);
+        }
+
+        info!("listening on {} with {:?}", self.listen_addr, id);
+        self.listeners.push(id);
+        if self.config.reports_listen_addr(&self.listen_addr) {
+            self.poll_tx
+                .send(TransportEvent::NewAddress {
+                    listener_id: id,
+                    listen_addr: self.listen_addr.clone(),
+                })
+                .map_err(|_| TransportError::Other(Error::SendErrorTransportEvent))?;
+        }
         Ok(())
     }
 
     fn remove_listener(&mut self, id: ListenerId) -> bool {
-        if self.listener_id != id {
-            return false;
+        let reservation = self
+            .relay_listeners
+            .iter()
+            .find(|(_, listener)| listener.listener_id == id)
+            .map(|(reservation, _)| reservation.clone());
+        if let Some(listener) = reservation.and_then(|id| self.relay_listeners.remove(&id)) {
+            self.close_relay_listener(listener, Ok(()));
+            return true;
+        }
+        let service = self
+            .service_listeners
+            .iter()
+            .find(|(_, listener)| listener.listener_id == id)
+            .map(|(&service, _)| service);
+        if let Some(listener) = service.and_then(|service| self.service_listeners.remove(&service))
+        {
+            self.close_listener(id, listener.listen_addr);
+            return true;
         }
 
-        // TODO: close channels?
-        self.poll_tx
-            .send(TransportEvent::ListenerClosed {
-                listener_id: id,
-                reason: Ok(()),
-            })
-            .expect("failed to send listener closed event");
+        let Some(index) = self
+            .listeners
+            .iter()
+            .position(|&listener_id| listener_id == id)
+        else {
+            return false;
+        };
+        self.listeners.remove(index);
+        self.close_listener(id, self.listen_addr.clone());
         true
     }
 
+    // dial_opts.role is Endpoint::Listener when the swarm dials as the listener, e.g. to
+    // hole punch. Connections are multiplexed without an upgrade, so there's nothing for
+    // the role to apply to, and such dials are made like any other. In particular, the
+    // address of a peer that dialed us anonymously (our own address, with its /p2p/ suffix)
+    // can be dialed, through the SURBs it sent us.
     fn dial(
         &mut self,
         addr: Multiaddr,
-        _dial_opts: DialOpts, // TODO unused for the moment - check where used elsewhere and bring in
+        dial_opts: DialOpts,
     ) -> Result<Self::Dial, TransportError<Self::Error>> {
-        debug!("dialing {}", addr);
-
-        let id = ConnectionId::generate();
+        debug!("dialing {} as {:?}", addr, dial_opts.role);
 
-        // create remote recipient address
-        let recipient = multiaddress_to_nym_address(addr).map_err(TransportError::Other)?;
+        let mut addrs = self.resolve_peer_addrs(addr)?;
+        if addrs.len() == 1 {
+            return self.dial_addr(addrs.remove(0));
+        }
+        debug!("racing dials to {} addresses", addrs.len());
+        let dials = addrs
+            .into_iter()
+            .map(|addr| self.dial_addr(addr))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(race_dials(dials, self.config.dial_race_stagger).boxed())
+    }
 
-        // create pending conn structs and store
-        let (connection_tx, connection_rx) = oneshot::channel::<Connection>();
+    // poll registers the task's waker with everything it reads from (the mixnet clients'
+    // inbound and status channels, the listener events, the connection table and the gap
+    // check), so it's woken exactly when one of them has something for it.
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        // sampled before anything is handled, so that a transport that's falling behind
+        // shows its queues at their deepest
+        self.sample_queue_depths();
 
-        let inner_pending_conn = PendingConnection::new(recipient, connection_tx);
-        self.pending_dials.insert(id.clone(), inner_pending_conn);
+        // mixnet client disconnects and reconnects; the listener events
+        // they cause are queued, and returned below
+        while let Poll::Ready(Some(status)) = self.mixnet_status_rx.poll_recv(cx) {
+            match status {
+                MixnetStatus::Reconnecting => {
+                    self.health = ClientHealth::Reconnecting;
+                    self.events.emit(NymEvent::GatewayReconnecting);
+                    self.emit_to_listeners(|listener_id| TransportEvent::ListenerError {
+                        listener_id,
+                        error: Error::MixnetClientDisconnected,
+                    });
+                }
+                MixnetStatus::Reconnected(address) => {
+                    info!("mixnet client reconnected as {}", address);
+                    self.health = ClientHealth::Connected;
+                    if address != self.self_address {
+                        self.migrate_connections(&address);
+                    }
+                    if let Err(e) = self.set_self_address(address) {
+                        debug!("failed to update listen address: {}", e);
+                    }
+                    self.events.emit(NymEvent::MixnetConnected(address));
+                }
+                MixnetStatus::DeliveryFailed(id) => {
+                    debug!("delivery failed on connection {:?}", id);
+                    self.fail_connection(&id, Error::DeliveryFailed);
+                }
+                MixnetStatus::RequestSent(id) => self.on_request_sent(id),
+                MixnetStatus::RequestFailed(id, reason) => {
+                    debug!("failed to send request of dial {:?}: {}", id, reason);
+                    self.fail_dial(&id, Error::OutboundSendFailure(reason));
+                }
+                MixnetStatus::TopologyChanged(epoch) => {
+                    self.topology_epoch = Some(epoch);
+                    self.events.emit(NymEvent::TopologyChanged { epoch });
+                }
+                MixnetStatus::Disconnected => {
+                    self.health = ClientHealth::Disconnected;
+                    self.events.emit(NymEvent::MixnetDisconnected);
+                    self.emit_to_listeners(|listener_id| TransportEvent::ListenerClosed {
+                        listener_id,
+                        reason: Err(Error::MixnetClientDisconnected),
+                    });
+                    self.listeners.clear();
+                    self.service_listeners.clear();
+                    // our reservations can't be renewed without the client either
+                    for (_, listener) in std::mem::take(&mut self.relay_listeners) {
+                        self.close_relay_listener(listener, Err(Error::MixnetClientDisconnected));
+                    }
+                }
+            }
+        }
 
-        let local_key = Keypair::generate_ed25519();
-        let connection_peer_id = PeerId::from(local_key.public());
+        // dial clients aren't reconnected, so one that disconnects is skipped by later dials
+        for i in 0..self.dial_clients.len() {
+            while let Poll::Ready(Some(status)) = self.dial_clients[i].status_rx.poll_recv(cx) {
+                match status {
+                    MixnetStatus::DeliveryFailed(id) => {
+                        debug!("delivery failed on connection {:?}", id);
+                        self.fail_connection(&id, Error::DeliveryFailed);
+                    }
+                    MixnetStatus::RequestSent(id) => self.on_request_sent(id),
+                    MixnetStatus::RequestFailed(id, reason) => {
+                        debug!("failed to send request of dial {:?}: {}", id, reason);
+                        self.fail_dial(&id, Error::OutboundSendFailure(reason));
+                    }
+                    MixnetStatus::Disconnected => {
+                        let client = &mut self.dial_clients[i];
+                        warn!("dial client {} disconnected", client.address);
+                        client.connected = false;
+                    }
+                    status => debug!("dial client status: {:?}", status),
+                }
+            }
+        }
 
-        // put ConnectionRequest message into outbound message channel
-        let msg = ConnectionMessage {
-            peer_id: connection_peer_id,
-            id,
-        };
+        // new and expired addresses + listener close events
+        if let Poll::Ready(Some(res)) = self.poll_rx.poll_recv(cx) {
+            return Poll::Ready(res);
+        }
 
-        let outbound_tx = self.outbound_tx.clone();
+        // forget connections that have been dropped, and dials that timed out or were
+        // cancelled; connections the swarm has taken are established
+        for id in self.connections.poll(cx) {
+            debug!("connection {:?} closed", id);
+            self.forget_surbs(&id);
+            self.message_queues.remove(&id);
+            self.reply_routes.remove(&id);
+            self.bindings.unbind(&id);
+            self.migrations.remove(&id);
+            self.relays.remove(&id);
+            self.sent_requests.remove(&id);
+        }
 
-        let mut waker = self.waker.clone();
-        let handshake_timeout = self.handshake_timeout;
-        Ok(async move {
-            outbound_tx
-                .send(OutboundMessage {
-                    message: Message::ConnectionRequest(msg),
-                    recipient: Some(recipient),
-                    sender_tag: None, // Add this field
-                })
-                .map_err(|e| Error::OutboundSendFailure(e.to_string()))?;
+        while self.gap_check.poll_tick(cx).is_ready() {
+            self.replays.flush(unix_millis());
+            self.close_expired_gaps();
+            self.expire_challenges();
+            self.expire_handshakes();
+            self.report_expiring_reply_routes();
+            self.expire_bindings();
+            self.renew_relay_reservations();
+            if let Some(server) = &mut self.relay_server {
+                server.expire(Instant::now());
+            }
+        }
+        self.poll_admissions(cx);
 
-            debug!("sent outbound ConnectionRequest");
-            if let Some(waker) = waker.take() {
-                waker.wake();
-            };
+        // inbound messages to the dial clients only belong to connections we dialed
+        for i in 0..self.dial_clients.len() {
+            loop {
+                let client = &mut self.dial_clients[i];
+                if client.outbound_tx.poll_ready(cx).is_pending() {
+                    break;
+                }
+                let Poll::Ready(Some((msg, sender_tag))) =
+                    client.inbound_stream.poll_next_unpin(cx)
+                else {
+                    break;
+                };
 
-            let conn = timeout(handshake_timeout, connection_rx).await??;
-            Ok((conn.peer_id, conn))
-        }
-        .boxed())
-    }
+                // except through a reply route to a connection dialed through the client
+                let request = match &msg {
+                    Message::ConnectionRequest(msg) => {
+                        !msg.capabilities.contains(Capabilities::REPLY_ROUTE)
+                    }
+                    Message::VersionMismatch(msg) => msg.request,
+                    Message::ResumeRequest(_) => true,
+                    // as is relaying, which is done at the main client's address
+                    Message::RelayReserve(_) | Message::Relay(_) => true,
+                    _ => false,
+                };
+                if request {
+                    debug!(
+                        "dropping connection request to dial client {}",
+                        client.address
+                    );
+                    self.config.metrics.connection_rejected("not_listening");
+                    continue;
+                }
 
-    fn poll(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
-        // new addresses + listener close events
-        if let Poll::Ready(Some(res)) = self.poll_rx.recv().boxed().poll_unpin(cx) {
-            return Poll::Ready(res);
+                match self.handle_inbound(msg, sender_tag) {
+                    Ok(InboundTransportEvent::ConnectionRequest(
+                        upgrade,
+                        service,
+                        send_back_addr,
+                    )) => {
+                        if let Some((listener_id, local_addr)) = self.listener_of(service) {
+                            return Poll::Ready(TransportEvent::Incoming {
+                                listener_id,
+                                upgrade,
+                                local_addr,
+                                send_back_addr,
+                            });
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => match self.listeners.first() {
+                        Some(&listener_id) => {
+                            return Poll::Ready(TransportEvent::ListenerError {
+                                listener_id,
+                                error: e,
+                            });
+                        }
+                        None => debug!("failed to handle inbound message: {}", e),
+                    },
+                }
+            }
         }
 
         // check for and handle inbound messages
-        while let Poll::Ready(Some(msg)) = self.inbound_stream.poll_next_unpin(cx) {
+        loop {
+            // a ConnectionRequest is answered with a ConnectionResponse, so leave
+            // inbound messages queued while the outbound channel is full.
+            if self.outbound_tx.poll_ready(cx).is_pending() {
+                return Poll::Pending;
+            }
+            // and leave ConnectionRequests queued while the swarm is behind on the
+            // connections it has, so that they're accepted once it catches up
+            if let Some(backlog) = self.config.accept_backlog {
+                let pending = self.connections.pending_inbound() + self.admitting.len();
+                self.inbound_stream.hold_deferred(pending >= backlog);
+            }
+
+            let Poll::Ready(Some(msg)) = self.inbound_stream.poll_next_unpin(cx) else {
+                break;
+            };
+
             match self.handle_inbound(msg.0, msg.1) {
                 Ok(event) => match event {
-                    InboundTransportEvent::ConnectionRequest(upgrade) => {
+                    InboundTransportEvent::ConnectionRequest(upgrade, service, send_back_addr) => {
                         info!("InboundTransportEvent::ConnectionRequest");
-                        return Poll::Ready(TransportEvent::Incoming {
-                            listener_id: self.listener_id,
-                            upgrade,
-                            local_addr: self.listen_addr.clone(),
-                            send_back_addr: self.listen_addr.clone(),
-                        });
+                        // requests are rejected while there's no listener to report them on
+                        if let Some((listener_id, local_addr)) = self.listener_of(service) {
+                            return Poll::Ready(TransportEvent::Incoming {
+                                listener_id,
+                                upgrade,
+                                local_addr,
+                                send_back_addr,
+                            });
+                        }
                     }
                     InboundTransportEvent::ConnectionResponse => {
                         info!("InboundTransportEvent::ConnectionResponse");
@@ -518,65 +3480,120 @@ impl Transport for NymTransport {
                     InboundTransportEvent::TransportMessage => {
                         debug!("InboundTransportEvent::TransportMessage");
                     }
+                    InboundTransportEvent::KeepAlive => {
+                        debug!("InboundTransportEvent::KeepAlive");
+                    }
+                    InboundTransportEvent::Ack => {
+                        debug!("InboundTransportEvent::Ack");
+                    }
+                    InboundTransportEvent::Datagram => {
+                        debug!("InboundTransportEvent::Datagram");
+                    }
+                    InboundTransportEvent::ConnectionClose => {
+                        debug!("InboundTransportEvent::ConnectionClose");
+                    }
+                    InboundTransportEvent::ConnectionRejected => {
+                        debug!("InboundTransportEvent::ConnectionRejected");
+                    }
+                    InboundTransportEvent::ConnectionAdmitting => {
+                        debug!("InboundTransportEvent::ConnectionAdmitting");
+                    }
+                    InboundTransportEvent::ConnectionChallenged => {
+                        debug!("InboundTransportEvent::ConnectionChallenged");
+                    }
+                    InboundTransportEvent::Migrate => {
+                        debug!("InboundTransportEvent::Migrate");
+                    }
+                    InboundTransportEvent::SessionTicket => {
+                        debug!("InboundTransportEvent::SessionTicket");
+                    }
+                    InboundTransportEvent::ConnectionResumed => {
+                        debug!("InboundTransportEvent::ConnectionResumed");
+                    }
+                    InboundTransportEvent::RelayedConnectionRequest(
+                        upgrade,
+                        listener_id,
+                        listen_addr,
+                    ) => {
+                        info!("InboundTransportEvent::RelayedConnectionRequest");
+                        return Poll::Ready(TransportEvent::Incoming {
+                            listener_id,
+                            upgrade,
+                            local_addr: listen_addr.clone(),
+                            send_back_addr: listen_addr,
+                        });
+                    }
+                    InboundTransportEvent::Relay => {
+                        debug!("InboundTransportEvent::Relay");
+                    }
+                    InboundTransportEvent::Dropped => {
+                        debug!("InboundTransportEvent::Dropped");
+                    }
+                },
+                Err(e) => match self.listeners.first() {
+                    Some(&listener_id) => {
+                        return Poll::Ready(TransportEvent::ListenerError {
+                            listener_id,
+                            error: e,
+                        });
+                    }
+                    None => debug!("failed to handle inbound message: {}", e),
                 },
-                Err(e) => {
-                    return Poll::Ready(TransportEvent::ListenerError {
-                        listener_id: self.listener_id,
-                        error: e,
-                    });
-                }
             };
         }
 
-        self.waker = Some(cx.waker().clone());
         Poll::Pending
     }
 }
 
-fn nym_address_to_multiaddress(addr: Recipient) -> Result<Multiaddr, Error> {
-    Multiaddr::from_str(&format!("/nym/{}", addr)).map_err(Error::FailedToFormatMultiaddr)
-}
-
-fn multiaddress_to_nym_address(multiaddr: Multiaddr) -> Result<Recipient, Error> {
-    let mut multiaddr = multiaddr;
-    match multiaddr.pop().unwrap() {
-        Protocol::Nym(addr) => Recipient::from_str(&addr).map_err(Error::InvalidRecipientBytes),
-        _ => Err(Error::InvalidProtocolForMultiaddr),
-    }
-}
-
 #[cfg(test)]
 mod test {
+    use super::super::address::{nym_address_to_multiaddr, NymMultiaddr};
+    use super::super::backend::MixnetBackend;
+    use super::super::codec::{Codec, WireCodec};
+    use super::super::config::{AnonymityMode, Decision, NymTransportConfig};
     use super::super::connection::Connection;
     use super::super::error::Error;
+    use super::super::events::{DropReason, NymEvent};
+    use super::super::handshake::sign_challenge;
+    use super::super::memory::{InMemoryConfig, InMemoryMixnet};
     use super::super::message::{
-        Message, OutboundMessage, SubstreamId, SubstreamMessage, SubstreamMessageType,
-        TransportMessage,
+        ChallengeResponseMessage, ConnectionId, Message, OutboundMessage, SubstreamId,
+        SubstreamMessage, SubstreamMessageType, TransportMessage,
     };
+    use super::super::nonce::Nonce;
+    use super::super::runtime::sleep;
+    use super::super::snapshot::ConnectionStatus;
+    use super::super::stream::NymStreamTransport;
     use super::super::substream::Substream;
-    use super::{nym_address_to_multiaddress, NymTransport};
-    use futures::{future::poll_fn, AsyncReadExt, AsyncWriteExt, FutureExt};
+    use super::super::testing::{
+        self, accept, connect, connection_request, dial_opts, next_event, poll_incoming, recv_all,
+        spawn_driven,
+    };
+    use super::{connect_test_client, NymTransport, Upgrade};
+    use bytes::Bytes;
+    use futures::{future::poll_fn, AsyncReadExt, AsyncWriteExt, FutureExt, StreamExt};
     use libp2p::core::{
-        transport::{DialOpts, PortUse, Transport, TransportEvent},
+        transport::{DialOpts, ListenerId, PortUse, Transport, TransportError, TransportEvent},
         Endpoint, Multiaddr, StreamMuxer,
     };
-    use libp2p_identity::Keypair;
+    use libp2p_identity::{Keypair, PeerId};
     use log::{info, LevelFilter};
     use nym_bin_common::logging::setup_logging;
-    use nym_sdk::mixnet::MixnetClient;
-    use std::{pin::Pin, str::FromStr, sync::atomic::Ordering};
-    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+    use nym_sdk::mixnet::{IncludedSurbs, MixnetClient};
+    use std::{collections::HashMap, pin::Pin, str::FromStr, task::Poll, time::Duration};
 
     impl Connection {
         fn write(&self, msg: SubstreamMessage) -> Result<(), Error> {
-            let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
+            let nonce = self.message_nonce.next()?;
             self.mixnet_outbound_tx
-                .send(OutboundMessage {
+                .try_send(OutboundMessage {
                     recipient: None,
                     message: Message::TransportMessage(TransportMessage {
                         nonce,
                         id: self.id.clone(),
                         message: msg,
+                        timestamp: None,
                     }),
                     sender_tag: self.sender_tag.clone(),
                 })
@@ -586,16 +3603,29 @@ mod test {
     }
 
     impl NymTransport {
-        async fn new_with_notify_inbound(
-            client: MixnetClient,
-            notify_inbound_tx: UnboundedSender<()>,
-        ) -> Result<Self, Error> {
-            let local_key = Keypair::generate_ed25519();
-            Self::new_maybe_with_notify_inbound(client, local_key, Some(notify_inbound_tx), None)
-                .await
+        async fn new_with_random_key(client: MixnetClient) -> Result<Self, Error> {
+            Self::new(client, Keypair::generate_ed25519()).await
         }
     }
 
+    /// poll_inbound waits until a message from the mixnet reaches the transport, and
+    /// polls the transport to handle it. It returns the event the transport returned,
+    /// if any. The transport is only woken once something is ready for it, so a wakeup
+    /// without a message waiting (e.g. for the gap check) is polled through.
+    async fn poll_inbound(
+        mut transport: Pin<&mut NymTransport>,
+    ) -> Option<TransportEvent<Upgrade, Error>> {
+        poll_fn(|cx| {
+            let arrived = transport.inbound_stream.len() > 0;
+            match transport.as_mut().poll(cx) {
+                Poll::Ready(event) => Poll::Ready(Some(event)),
+                Poll::Pending if arrived => Poll::Ready(None),
+                Poll::Pending => Poll::Pending,
+            }
+        })
+        .await
+    }
+
     // #[tokio::test]
     // async fn test_transport_connection() {
     // TODO rewrite this test: now that we're using SURBs for all replies when dialed, we have to test with live SDK clients / cannot mock connections in the same way, since the SURB is parsed from the underlying ReconstructedMessage coming from the Mixnet
@@ -607,7 +3637,7 @@ mod test {
                 listener_id,
                 listen_addr,
             } => {
-                assert_eq!(listener_id, transport.listener_id);
+                assert_eq!(listener_id, transport.listeners[0]);
                 assert_eq!(listen_addr, transport.listen_addr);
             }
             _ => panic!("expected TransportEvent::NewAddress"),
@@ -616,23 +3646,14 @@ mod test {
 
     #[tokio::test]
     async fn test_transport_substream() {
-        let client = MixnetClient::connect_new().await.unwrap();
+        let client = connect_test_client().await;
 
-        let (dialer_notify_inbound_tx, mut dialer_notify_inbound_rx) = unbounded_channel();
-        let mut dialer_transport =
-            NymTransport::new_with_notify_inbound(client, dialer_notify_inbound_tx)
-                .await
-                .unwrap();
+        let mut dialer_transport = NymTransport::new_with_random_key(client).await.unwrap();
 
-        let client2 = MixnetClient::connect_new().await.unwrap();
+        let client2 = connect_test_client().await;
 
-        let (listener_notify_inbound_tx, mut listener_notify_inbound_rx) = unbounded_channel();
-        let mut listener_transport =
-            NymTransport::new_with_notify_inbound(client2, listener_notify_inbound_tx)
-                .await
-                .unwrap();
-        let listener_multiaddr =
-            nym_address_to_multiaddress(listener_transport.self_address).unwrap();
+        let mut listener_transport = NymTransport::new_with_random_key(client2).await.unwrap();
+        let listener_multiaddr = nym_address_to_multiaddr(listener_transport.self_address).unwrap();
         assert_new_address_event(Pin::new(&mut dialer_transport)).await;
         assert_new_address_event(Pin::new(&mut listener_transport)).await;
 
@@ -649,8 +3670,6 @@ mod test {
         assert!(poll_fn(|cx| Pin::new(&mut dial).as_mut().poll_unpin(cx))
             .now_or_never()
             .is_none());
-        listener_notify_inbound_rx.recv().await.unwrap();
-
         // should receive the connection request from the mixnet and send the connection response
         let res = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)).await;
         let mut upgrade = match res {
@@ -660,21 +3679,17 @@ mod test {
                 local_addr,
                 send_back_addr,
             } => {
-                assert_eq!(listener_id, listener_transport.listener_id);
+                assert_eq!(listener_id, listener_transport.listeners[0]);
                 assert_eq!(local_addr, listener_transport.listen_addr);
                 assert_eq!(send_back_addr, listener_transport.listen_addr);
                 upgrade
             }
             _ => panic!("expected TransportEvent::Incoming, got {:?}", res),
         };
-        dialer_notify_inbound_rx.recv().await.unwrap();
-
         // should receive the connection response from the mixnet
-        assert!(
-            poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx))
-                .now_or_never()
-                .is_none()
-        );
+        assert!(poll_inbound(Pin::new(&mut dialer_transport))
+            .await
+            .is_none());
         info!("waiting for connections...");
 
         // should be able to resolve the connections now
@@ -693,14 +3708,10 @@ mod test {
             poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll_outbound(cx))
                 .await
                 .unwrap();
-        listener_notify_inbound_rx.recv().await.unwrap();
-
         // accept the substream on the listener
-        assert!(
-            poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx))
-                .now_or_never()
-                .is_none()
-        );
+        assert!(poll_inbound(Pin::new(&mut listener_transport))
+            .await
+            .is_none());
         poll_fn(|cx| Pin::new(&mut listener_conn).as_mut().poll(cx)).now_or_never();
 
         // poll recipient's poll_inbound to receive the substream; sends a response to the sender
@@ -710,14 +3721,10 @@ mod test {
                 .unwrap()
                 .unwrap();
         info!("got listener substream");
-        dialer_notify_inbound_rx.recv().await.unwrap();
-
         // poll sender to finalize the substream
-        assert!(
-            poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx))
-                .now_or_never()
-                .is_none()
-        );
+        assert!(poll_inbound(Pin::new(&mut dialer_transport))
+            .await
+            .is_none());
         poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll(cx)).now_or_never();
         info!("got dialer substream");
 
@@ -728,7 +3735,6 @@ mod test {
             Pin::new(&mut listener_substream),
             Pin::new(&mut listener_transport),
             Pin::new(&mut listener_conn),
-            &mut listener_notify_inbound_rx,
         )
         .await;
 
@@ -739,20 +3745,18 @@ mod test {
             Pin::new(&mut dialer_substream),
             Pin::new(&mut dialer_transport),
             Pin::new(&mut dialer_conn),
-            &mut dialer_notify_inbound_rx,
         )
         .await;
 
         // close the substream from the dialer side
         info!("closing dialer substream");
         dialer_substream.close().await.unwrap();
-        listener_notify_inbound_rx.recv().await.unwrap();
         info!("dialer substream closed");
 
         // assert we can't read or write to either substream
         dialer_substream.write_all(b"hello").await.unwrap_err();
         // poll listener transport and conn to receive the substream close message
-        poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)).now_or_never();
+        poll_inbound(Pin::new(&mut listener_transport)).await;
         poll_fn(|cx| Pin::new(&mut listener_conn).as_mut().poll(cx)).now_or_never();
         listener_substream.write_all(b"hello").await.unwrap_err();
         let mut buf = vec![0u8; 5];
@@ -768,14 +3772,12 @@ mod test {
         mut recipient_substream: Pin<&mut Substream>,
         mut recipient_transport: Pin<&mut NymTransport>,
         mut recipient_conn: Pin<&mut Connection>,
-        recipient_notify_inbound_rx: &mut UnboundedReceiver<()>,
     ) {
         // write message
         sender_substream.write_all(&data).await.unwrap();
-        recipient_notify_inbound_rx.recv().await.unwrap();
 
         // poll recipient for message
-        poll_fn(|cx| recipient_transport.as_mut().poll(cx)).now_or_never();
+        poll_inbound(recipient_transport.as_mut()).await;
         poll_fn(|cx| recipient_conn.as_mut().poll(cx)).now_or_never();
         let mut buf = vec![0u8; data.len()];
         let n = recipient_substream.read(&mut buf).await.unwrap();
@@ -785,14 +3787,12 @@ mod test {
 
     #[tokio::test]
     async fn test_transport_timeout() {
-        let client = MixnetClient::connect_new().await.unwrap();
+        let client = connect_test_client().await;
 
-        let (dialer_notify_inbound_tx, _) = unbounded_channel();
-        let mut dialer_transport =
-            NymTransport::new_with_notify_inbound(client, dialer_notify_inbound_tx)
-                .await
-                .unwrap()
-                .with_timeout(std::time::Duration::from_millis(100));
+        let mut dialer_transport = NymTransport::new_with_random_key(client)
+            .await
+            .unwrap()
+            .with_timeout(std::time::Duration::from_millis(100));
 
         // mock a transport that will never resolve the connection.
         let empty_addr = Multiaddr::from_str(
@@ -812,24 +3812,89 @@ mod test {
             .contains("dial timed out"));
     }
 
+    #[tokio::test]
+    async fn test_transport_dial_cancelled() {
+        let client = connect_test_client().await;
+
+        let mut dialer_transport = NymTransport::new_with_random_key(client).await.unwrap();
+
+        let empty_addr = Multiaddr::from_str(
+            "/nym/Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR"
+        )
+        .expect("unable to parse multiaddress");
+
+        let dial_opts = DialOpts {
+            role: Endpoint::Dialer,
+            port_use: PortUse::Reuse,
+        };
+        let dial = dialer_transport.dial(empty_addr, dial_opts).unwrap();
+        assert_eq!(dialer_transport.connections.pending_dials().count(), 1);
+
+        // the swarm drops the dial, e.g. because another address connected first
+        drop(dial);
+        poll_fn(|cx| Pin::new(&mut dialer_transport).poll(cx)).now_or_never();
+        assert_eq!(dialer_transport.connections.pending_dials().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_transport_shutdown() {
+        let client = connect_test_client().await;
+
+        let mut transport = NymTransport::new_with_random_key(client).await.unwrap();
+        assert_new_address_event(Pin::new(&mut transport)).await;
+
+        let id = transport.listeners[0];
+        transport.shutdown().await.unwrap();
+        match poll_fn(|cx| Pin::new(&mut transport).poll(cx)).await {
+            TransportEvent::ListenerClosed {
+                listener_id,
+                reason,
+            } => {
+                assert_eq!(listener_id, id);
+                assert!(reason.is_ok());
+            }
+            _ => panic!("expected TransportEvent::ListenerClosed"),
+        }
+
+        // shutting down twice is a no-op
+        transport.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transport_datagram() {
+        let client = connect_test_client().await;
+        let requester = NymTransport::new_with_random_key(client).await.unwrap();
+
+        let client2 = connect_test_client().await;
+        let mut responder = NymTransport::new_with_random_key(client2).await.unwrap();
+        let responder_multiaddr = nym_address_to_multiaddr(responder.self_address).unwrap();
+        let mut requests = responder.datagram_requests().unwrap();
+        assert!(responder.datagram_requests().is_none());
+
+        // datagrams are routed by the mixnet task, so neither transport needs to be polled
+        let respond = tokio::spawn(async move {
+            let request = requests.next().await.unwrap();
+            assert_eq!(request.payload, b"ping".to_vec());
+            request.respond(b"pong".to_vec()).await.unwrap();
+        });
+        let response = requester
+            .datagram_client()
+            .request(&responder_multiaddr, b"ping".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(response, b"pong".to_vec());
+        respond.await.unwrap();
+    }
+
     #[tokio::test]
     async fn new_peer_id_per_conn() {
         // setup_logging();
-        let client = MixnetClient::connect_new().await.unwrap();
-        let (dialer_notify_inbound_tx, mut dialer_notify_inbound_rx) = unbounded_channel();
-        let mut dialer_transport =
-            NymTransport::new_with_notify_inbound(client, dialer_notify_inbound_tx)
-                .await
-                .unwrap();
+        let client = connect_test_client().await;
+        let mut dialer_transport = NymTransport::new_with_random_key(client).await.unwrap();
 
-        let client2 = MixnetClient::connect_new().await.unwrap();
-        let (listener_notify_inbound_tx, mut listener_notify_inbound_rx) = unbounded_channel();
-        let mut listener_transport =
-            NymTransport::new_with_notify_inbound(client2, listener_notify_inbound_tx)
-                .await
-                .unwrap();
-        let listener_multiaddr =
-            nym_address_to_multiaddress(listener_transport.self_address).unwrap();
+        let client2 = connect_test_client().await;
+        let mut listener_transport = NymTransport::new_with_random_key(client2).await.unwrap();
+        let listener_multiaddr = nym_address_to_multiaddr(listener_transport.self_address).unwrap();
         assert_new_address_event(Pin::new(&mut dialer_transport)).await;
         assert_new_address_event(Pin::new(&mut listener_transport)).await;
 
@@ -846,8 +3911,6 @@ mod test {
         assert!(poll_fn(|cx| Pin::new(&mut dial).as_mut().poll_unpin(cx))
             .now_or_never()
             .is_none());
-        listener_notify_inbound_rx.recv().await.unwrap();
-
         // should receive the connection request from the mixnet and send the connection response
         let res = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)).await;
         let mut upgrade = match res {
@@ -857,21 +3920,17 @@ mod test {
                 local_addr,
                 send_back_addr,
             } => {
-                assert_eq!(listener_id, listener_transport.listener_id);
+                assert_eq!(listener_id, listener_transport.listeners[0]);
                 assert_eq!(local_addr, listener_transport.listen_addr);
                 assert_eq!(send_back_addr, listener_transport.listen_addr);
                 upgrade
             }
             _ => panic!("expected TransportEvent::Incoming, got {:?}", res),
         };
-        dialer_notify_inbound_rx.recv().await.unwrap();
-
         // should receive the connection response from the mixnet
-        assert!(
-            poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx))
-                .now_or_never()
-                .is_none()
-        );
+        assert!(poll_inbound(Pin::new(&mut dialer_transport))
+            .await
+            .is_none());
         info!("waiting for connections...");
 
         let (_, listener_conn) = poll_fn(|cx| Pin::new(&mut upgrade).as_mut().poll_unpin(cx))
@@ -892,30 +3951,40 @@ mod test {
         let conn1_dialer_peer_id = dialer_conn.peer_id;
         info!("dialer connection 1 has PeerId {}", conn1_dialer_peer_id);
 
-        let id = dialer_transport.listener_id;
-        dialer_transport.remove_listener(id);
-        let res = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)).await;
-        match res {
-            TransportEvent::ListenerClosed {
-                reason: Ok(()),
-                listener_id: id,
-            } => {
-                info!("emitted ListenerClosed event for listener_id {}", id)
-            }
-            _ => info!("listener not removed, something went wrong"),
-        };
-        let id = listener_transport.listener_id;
-        listener_transport.remove_listener(id);
-        let res = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)).await;
-        match res {
-            TransportEvent::ListenerClosed {
-                reason: Ok(()),
-                listener_id: id,
-            } => {
-                info!("emitted ListenerClosed event for listener_id {}", id)
-            }
-            _ => info!("listener not removed, something went wrong"),
-        };
+        // removing a listener expires its address and closes it
+        for transport in [&mut dialer_transport, &mut listener_transport] {
+            let id = transport.listeners[0];
+            assert!(transport.remove_listener(id));
+            assert!(!transport.remove_listener(id));
+            let res = poll_fn(|cx| Pin::new(&mut *transport).as_mut().poll(cx)).await;
+            assert!(
+                matches!(res, TransportEvent::AddressExpired { listener_id, .. } if listener_id == id)
+            );
+            let res = poll_fn(|cx| Pin::new(&mut *transport).as_mut().poll(cx)).await;
+            match res {
+                TransportEvent::ListenerClosed {
+                    reason: Ok(()),
+                    listener_id,
+                } => {
+                    assert_eq!(listener_id, id);
+                    info!("emitted ListenerClosed event for listener_id {}", id)
+                }
+                _ => panic!("expected TransportEvent::ListenerClosed, got {:?}", res),
+            };
+        }
+
+        // listeners can only be added on the transport's own nym address
+        let other_addr = dialer_transport.listen_addr().clone();
+        assert!(listener_transport
+            .listen_on(ListenerId::next(), other_addr)
+            .is_err());
+
+        // the listener has to listen again to accept another connection
+        let listen_addr = listener_transport.listen_addr().clone();
+        listener_transport
+            .listen_on(ListenerId::next(), listen_addr)
+            .unwrap();
+        assert_new_address_event(Pin::new(&mut listener_transport)).await;
 
         // make another conn between the same peers
         let mut dial = dialer_transport
@@ -925,7 +3994,6 @@ mod test {
         assert!(poll_fn(|cx| Pin::new(&mut dial).as_mut().poll_unpin(cx))
             .now_or_never()
             .is_none());
-        listener_notify_inbound_rx.recv().await.unwrap();
 
         let res = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)).await;
         let mut upgrade = match res {
@@ -935,20 +4003,16 @@ mod test {
                 local_addr,
                 send_back_addr,
             } => {
-                assert_eq!(listener_id, listener_transport.listener_id);
+                assert_eq!(listener_id, listener_transport.listeners[0]);
                 assert_eq!(local_addr, listener_transport.listen_addr);
                 assert_eq!(send_back_addr, listener_transport.listen_addr);
                 upgrade
             }
             _ => panic!("expected TransportEvent::Incoming, got {:?}", res),
         };
-        dialer_notify_inbound_rx.recv().await.unwrap();
-
-        assert!(
-            poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx))
-                .now_or_never()
-                .is_none()
-        );
+        assert!(poll_inbound(Pin::new(&mut dialer_transport))
+            .await
+            .is_none());
         info!("waiting for connections...");
 
         let (_, listener_conn) = poll_fn(|cx| Pin::new(&mut upgrade).as_mut().poll_unpin(cx))
@@ -973,4 +4037,675 @@ mod test {
         // we want to check that these two don't match, as they're the PeerIds generated by the dialer and sent along when trying to connect to the listener
         assert_ne!(conn1_listener_peer_id, conn2_listener_peer_id);
     }
+
+    #[tokio::test]
+    async fn test_transport_with_dial_clients_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let dialer = NymTransport::new_with_backends(
+            mixnet.client(),
+            vec![mixnet.client()],
+            Keypair::generate_ed25519(),
+            NymTransportConfig::default(),
+        )
+        .await
+        .unwrap();
+        let mut dialer_transport = NymStreamTransport::new(dialer);
+        let listener = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        let listener_multiaddr = listener.listen_addr().clone();
+        let mut listener_transport = NymStreamTransport::new(listener);
+
+        // the first dial goes through the main client, and the second through the dial client
+        let dials: Vec<_> = (0..2)
+            .map(|_| {
+                tokio::spawn(
+                    dialer_transport
+                        .dial(listener_multiaddr.clone(), dial_opts())
+                        .unwrap(),
+                )
+            })
+            .collect();
+        spawn_driven(dialer_transport);
+
+        let mut upgrades = vec![];
+        while upgrades.len() < 2 {
+            upgrades.push(accept(&mut listener_transport).await);
+        }
+        spawn_driven(listener_transport);
+
+        // each connection is answered through the client it was dialed through
+        let mut dialer_streams = vec![];
+        for dial in dials {
+            let mut dialer_stream = dial.await.unwrap().unwrap();
+            dialer_stream.write_all(b"hello").await.unwrap();
+            dialer_streams.push(dialer_stream);
+        }
+        for upgrade in upgrades {
+            let mut listener_stream = upgrade.await.unwrap();
+            let mut buf = [0u8; 5];
+            listener_stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dial_reply_route_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let mut dialer = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        let mut listener = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        let listener_addr = listener.listen_addr().clone();
+
+        // the dialer doesn't expose its address, so the listener only holds its SURBs
+        let (_outbound, (dialer_peer_id, _inbound)) =
+            connect(&mut dialer, &mut listener, listener_addr.clone()).await;
+
+        // the listener reported the dialer at its own address, and can dial it back there
+        let dialer_addr: Multiaddr = format!("{}/p2p/{}", listener_addr, dialer_peer_id)
+            .parse()
+            .unwrap();
+        let dial_back_opts = DialOpts {
+            role: Endpoint::Listener,
+            port_use: PortUse::Reuse,
+        };
+        let mut dial_back = listener.dial(dialer_addr, dial_back_opts).unwrap();
+        let (listener_peer_id, _inbound) = loop {
+            tokio::select! {
+                upgrade = accept(&mut dialer) => break upgrade.await.unwrap(),
+                _ = next_event(&mut listener) => {}
+            }
+        };
+        assert_eq!(listener_peer_id, listener.peer_id());
+
+        // the dialer answers as the identity it dialed with
+        let (peer_id, _outbound) = loop {
+            tokio::select! {
+                res = &mut dial_back => break res.unwrap(),
+                _ = next_event(&mut listener) => {}
+                _ = next_event(&mut dialer) => {}
+            }
+        };
+        assert_eq!(peer_id, dialer_peer_id);
+
+        // a peer we have no connection to can't be reached that way
+        let unknown: Multiaddr = format!("{}/p2p/{}", listener_addr, PeerId::random())
+            .parse()
+            .unwrap();
+        assert!(matches!(
+            listener.dial(unknown, dial_back_opts),
+            Err(TransportError::Other(Error::NoReplyRoute(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_simultaneous_dial_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let config =
+            || NymTransportConfig::default().with_anonymity(AnonymityMode::ExposeSelfAddress);
+        let mut a = testing::transport(&mixnet, config()).await;
+        let mut b = testing::transport(&mixnet, config()).await;
+        let addr = |transport: &NymTransport| -> Multiaddr {
+            format!("{}/p2p/{}", transport.listen_addr(), transport.peer_id())
+                .parse()
+                .unwrap()
+        };
+
+        // both requests are sent before either peer sees the other's
+        let mut a_dial = a.dial(addr(&b), dial_opts()).unwrap();
+        let mut b_dial = b.dial(addr(&a), dial_opts()).unwrap();
+        let (mut a_res, mut b_res) = (None, None);
+        let (mut a_incoming, mut b_incoming) = (Vec::new(), Vec::new());
+        while a_res.is_none() || b_res.is_none() {
+            tokio::select! {
+                res = &mut a_dial, if a_res.is_none() => a_res = Some(res),
+                res = &mut b_dial, if b_res.is_none() => b_res = Some(res),
+                event = next_event(&mut a) => {
+                    if let TransportEvent::Incoming { upgrade, .. } = event {
+                        a_incoming.push(upgrade.await.unwrap());
+                    }
+                }
+                event = next_event(&mut b) => {
+                    if let TransportEvent::Incoming { upgrade, .. } = event {
+                        b_incoming.push(upgrade.await.unwrap());
+                    }
+                }
+            }
+        }
+
+        // only the dial of the peer with the lower PeerId is kept
+        let (winner, loser, winner_incoming, loser_incoming) = if a.peer_id() < b.peer_id() {
+            (a_res.unwrap(), b_res.unwrap(), a_incoming, b_incoming)
+        } else {
+            (b_res.unwrap(), a_res.unwrap(), b_incoming, a_incoming)
+        };
+        let (min, max) = (a.peer_id().min(b.peer_id()), a.peer_id().max(b.peer_id()));
+        assert_eq!(winner.unwrap().0, max);
+        assert!(matches!(loser, Err(Error::SimultaneousDial(peer_id)) if peer_id == min));
+        assert!(winner_incoming.is_empty());
+        assert_eq!(loser_incoming.len(), 1);
+        assert_eq!(loser_incoming[0].0, min);
+    }
+
+    #[tokio::test]
+    async fn test_dial_by_peer_id_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let mut dialer = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        let mut listener = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        let listener_peer_id = listener.peer_id();
+        let by_peer_id: Multiaddr = format!("/p2p/{}", listener_peer_id).parse().unwrap();
+
+        // the listener's address isn't known yet
+        assert!(matches!(
+            dialer.dial(by_peer_id.clone(), dial_opts()),
+            Err(TransportError::Other(Error::UnknownPeerAddress(peer_id))) if peer_id == listener_peer_id
+        ));
+
+        // it's learned from the handshake of a dial to its nym address
+        for addr in [listener.listen_addr().clone(), by_peer_id] {
+            let ((peer_id, _), _) = connect(&mut dialer, &mut listener, addr).await;
+            assert_eq!(peer_id, listener_peer_id);
+        }
+        assert!(dialer.address_of(&listener_peer_id).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_dial_race_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let mut dialer = testing::transport(
+            &mixnet,
+            NymTransportConfig::default().with_dial_race_stagger(Duration::ZERO),
+        )
+        .await;
+        // a peer running two clients, each with its own nym address
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let listener = || {
+            NymTransport::new_with_backend(
+                mixnet.client(),
+                keypair.clone(),
+                NymTransportConfig::default(),
+            )
+        };
+        let mut first = listener().await.unwrap();
+        let mut second = listener().await.unwrap();
+        // and an address it's no longer at, which never answers
+        let silent = mixnet.client();
+
+        dialer.add_address(peer_id, first.listen_addr()).unwrap();
+        dialer.add_address(peer_id, second.listen_addr()).unwrap();
+        let silent_addr = nym_address_to_multiaddr(silent.address()).unwrap();
+        dialer.add_address(peer_id, &silent_addr).unwrap();
+        assert_eq!(dialer.addresses_of(&peer_id).len(), 3);
+
+        let by_peer_id: Multiaddr = format!("/p2p/{}", peer_id).parse().unwrap();
+        let mut dial = dialer.dial(by_peer_id, dial_opts()).unwrap();
+
+        let mut accepted = vec![];
+        let dialed = loop {
+            tokio::select! {
+                res = &mut dial => break res.unwrap(),
+                _ = next_event(&mut dialer) => {}
+                event = next_event(&mut first) => {
+                    if let TransportEvent::Incoming { upgrade, .. } = event {
+                        accepted.push(upgrade.await.unwrap());
+                    }
+                }
+                event = next_event(&mut second) => {
+                    if let TransportEvent::Incoming { upgrade, .. } = event {
+                        accepted.push(upgrade.await.unwrap());
+                    }
+                }
+            }
+        };
+        assert_eq!(dialed.0, peer_id);
+
+        // the losing dial is closed at the listener that answered it, and the one
+        // that was never answered is forgotten
+        testing::drive(
+            &mut [&mut dialer, &mut first, &mut second],
+            Duration::from_millis(200),
+        )
+        .await;
+        assert_eq!(accepted.len(), 2);
+        assert_eq!(dialer.debug_snapshot().connections.len(), 1);
+        let mut statuses: Vec<ConnectionStatus> = [&first, &second]
+            .iter()
+            .flat_map(|listener| listener.debug_snapshot().connections)
+            .map(|connection| connection.status)
+            .collect();
+        statuses.sort_by_key(|status| *status == ConnectionStatus::Closing);
+        assert_eq!(
+            statuses,
+            vec![ConnectionStatus::Established, ConnectionStatus::Closing]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_info_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let config = |agent_version: &str| {
+            NymTransportConfig::default()
+                .with_agent_version(agent_version)
+                .with_extensions(vec![format!("{}-ext", agent_version)])
+        };
+        let mut dialer = testing::transport(&mixnet, config("dialer")).await;
+        let mut listener = testing::transport(&mixnet, config("listener")).await;
+        let listener_peer_id = listener.peer_id();
+        let listener_addr = listener.listen_addr().clone();
+        let ((_, dialed), (_, accepted)) = connect(&mut dialer, &mut listener, listener_addr).await;
+
+        // each side learns the other's info from the handshake alone
+        assert_eq!(
+            dialed.remote_info().agent_version.as_deref(),
+            Some("listener")
+        );
+        assert_eq!(dialed.remote_info().extensions, vec!["listener-ext"]);
+        assert_eq!(
+            accepted.remote_info().agent_version.as_deref(),
+            Some("dialer")
+        );
+        assert_eq!(
+            dialer
+                .remote_info(&listener_peer_id)
+                .unwrap()
+                .agent_version
+                .as_deref(),
+            Some("listener")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admission_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let mut listener = testing::transport(
+            &mixnet,
+            NymTransportConfig::default().with_admission(|_, info| {
+                let allowed = info.agent_version.as_deref() == Some("allowed");
+                async move {
+                    if allowed {
+                        Decision::Accept
+                    } else {
+                        Decision::Reject
+                    }
+                }
+            }),
+        )
+        .await;
+        let config = |agent_version: &str| {
+            NymTransportConfig::default()
+                .with_agent_version(agent_version)
+                .with_dial_timeout(Duration::from_millis(200))
+        };
+        let mut allowed = testing::transport(&mixnet, config("allowed")).await;
+        let mut denied = testing::transport(&mixnet, config("denied")).await;
+
+        let listener_addr = listener.listen_addr().clone();
+        let mut allowed_dial = allowed.dial(listener_addr.clone(), dial_opts()).unwrap();
+        let mut denied_dial = denied.dial(listener_addr, dial_opts()).unwrap();
+
+        // the rejected request is dropped without a response, so its dial times out
+        let mut incoming = 0;
+        let (mut allowed_done, mut denied_done) = (false, false);
+        while !(allowed_done && denied_done) {
+            tokio::select! {
+                res = &mut allowed_dial, if !allowed_done => {
+                    res.unwrap();
+                    allowed_done = true;
+                }
+                res = &mut denied_dial, if !denied_done => {
+                    assert!(matches!(res, Err(Error::DialTimeout)));
+                    denied_done = true;
+                }
+                _ = next_event(&mut allowed) => {}
+                _ = next_event(&mut denied) => {}
+                event = next_event(&mut listener) => {
+                    if let TransportEvent::Incoming { upgrade, .. } = event {
+                        incoming += 1;
+                        tokio::spawn(upgrade);
+                    }
+                }
+            }
+        }
+        assert_eq!(incoming, 1);
+    }
+
+    #[tokio::test]
+    async fn test_listen_multiaddr_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let client = mixnet.client();
+        let address = client.address();
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let transport =
+            NymTransport::new_with_backend(client, keypair, NymTransportConfig::default())
+                .await
+                .unwrap();
+        assert_eq!(transport.local_nym_address(), address);
+        assert_eq!(transport.local_peer_id(), peer_id);
+
+        let addr = NymMultiaddr::try_from(&transport.listen_multiaddr()).unwrap();
+        assert_eq!(addr, NymMultiaddr::new(address).with_peer_id(peer_id));
+    }
+
+    #[tokio::test]
+    async fn test_accept_backlog_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        // a short handshake timeout has the listener check its backlog often
+        let mut listener = testing::transport(
+            &mixnet,
+            NymTransportConfig::default()
+                .with_accept_backlog(1)
+                .with_handshake_timeout(Duration::from_millis(400)),
+        )
+        .await;
+        let mut first = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        let mut second = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        let listener_addr = listener.listen_addr().clone();
+
+        let mut first_dial = first.dial(listener_addr.clone(), dial_opts()).unwrap();
+        let mut first_conn = None;
+        // the swarm doesn't get round to the first connection's upgrade
+        let upgrade = loop {
+            tokio::select! {
+                res = &mut first_dial, if first_conn.is_none() => {
+                    first_conn = Some(res.unwrap());
+                }
+                _ = next_event(&mut first) => {}
+                upgrade = accept(&mut listener) => break upgrade,
+            }
+        };
+
+        // so the second request is left unread, rather than accepted
+        let mut second_dial = second.dial(listener_addr, dial_opts()).unwrap();
+        let wait = sleep(Duration::from_secs(1));
+        tokio::pin!(wait);
+        loop {
+            tokio::select! {
+                _ = &mut wait => break,
+                _ = &mut second_dial => panic!("second dial answered while the backlog is full"),
+                _ = next_event(&mut second) => {}
+                event = next_event(&mut listener) => {
+                    assert!(!matches!(event, TransportEvent::Incoming { .. }));
+                }
+            }
+        }
+        assert_eq!(listener.debug_snapshot().inbound_queue_len, 1);
+
+        // until the first upgrade is taken
+        tokio::spawn(upgrade);
+        let mut second_done = false;
+        let mut incoming = false;
+        while !(second_done && incoming) {
+            tokio::select! {
+                res = &mut second_dial, if !second_done => {
+                    res.unwrap();
+                    second_done = true;
+                }
+                _ = next_event(&mut second) => {}
+                upgrade = accept(&mut listener) => {
+                    tokio::spawn(upgrade);
+                    incoming = true;
+                }
+            }
+        }
+        assert!(first_conn.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let mut dialer = testing::transport(
+            &mixnet,
+            NymTransportConfig::default().with_handshake_timeout(Duration::from_millis(200)),
+        )
+        .await;
+        // a client with no transport behind it takes the request, but never answers
+        let silent = mixnet.client();
+
+        let silent_addr = nym_address_to_multiaddr(silent.address()).unwrap();
+        let mut dial = dialer.dial(silent_addr, dial_opts()).unwrap();
+        loop {
+            tokio::select! {
+                res = &mut dial => {
+                    assert!(matches!(res, Err(Error::HandshakeTimeout)));
+                    break;
+                }
+                _ = next_event(&mut dialer) => {}
+            }
+        }
+        // the dial is forgotten along with its future
+        assert_eq!(dialer.debug_snapshot().pending_dials, 0);
+    }
+
+    #[tokio::test]
+    async fn test_dial_queue_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let mut dialer = testing::transport(
+            &mixnet,
+            NymTransportConfig::default()
+                .with_max_concurrent_dials(1)
+                .with_dial_timeout(Duration::from_millis(200)),
+        )
+        .await;
+        let mut listener = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        // a client with no transport behind it never answers
+        let silent = mixnet.client();
+
+        let silent_addr = nym_address_to_multiaddr(silent.address()).unwrap();
+        let mut first = dialer.dial(silent_addr, dial_opts()).unwrap();
+        let mut second = dialer
+            .dial(listener.listen_addr().clone(), dial_opts())
+            .unwrap();
+
+        // the second dial waits for the first to time out before it's sent
+        let mut first_failed = false;
+        loop {
+            tokio::select! {
+                res = &mut first, if !first_failed => {
+                    assert!(matches!(res, Err(Error::DialTimeout)));
+                    first_failed = true;
+                }
+                res = &mut second => {
+                    assert!(first_failed);
+                    res.unwrap();
+                    break;
+                }
+                _ = next_event(&mut dialer) => {}
+                upgrade = accept(&mut listener) => {
+                    tokio::spawn(upgrade);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_service_listeners_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let config =
+            || NymTransportConfig::default().with_handshake_timeout(Duration::from_millis(500));
+        let mut dialer = testing::transport(&mixnet, config()).await;
+        let mut listener = testing::transport(&mixnet, config()).await;
+        let address = listener.local_nym_address();
+        let service_addr = |service| {
+            NymMultiaddr::new(address)
+                .with_service(service)
+                .to_multiaddr()
+                .unwrap()
+        };
+
+        // a service has one listener at a time, and isn't listened on through a relay
+        let plain_id = listener.listeners[0];
+        let service_id = ListenerId::next();
+        listener.listen_on(service_id, service_addr(80)).unwrap();
+        assert!(matches!(
+            listener.listen_on(ListenerId::next(), service_addr(80)),
+            Err(TransportError::Other(Error::ServiceListenerExists(80)))
+        ));
+        let relayed = NymMultiaddr::new(dialer.local_nym_address())
+            .with_relayed(true)
+            .with_service(80)
+            .to_multiaddr()
+            .unwrap();
+        assert!(matches!(
+            listener.listen_on(ListenerId::next(), relayed),
+            Err(TransportError::MultiaddrNotSupported(_))
+        ));
+
+        let mut plain = dialer
+            .dial(listener.listen_addr().clone(), dial_opts())
+            .unwrap();
+        let mut service = dialer.dial(service_addr(80), dial_opts()).unwrap();
+        // nothing listens on service 81, so its dial is never answered
+        let mut unanswered = dialer.dial(service_addr(81), dial_opts()).unwrap();
+
+        let mut new_addresses = vec![];
+        let mut incoming = HashMap::new();
+        let (mut plain_dialed, mut service_dialed, mut timed_out) = (false, false, false);
+        while !(plain_dialed && service_dialed && timed_out && incoming.len() == 2) {
+            tokio::select! {
+                res = &mut plain, if !plain_dialed => {
+                    res.unwrap();
+                    plain_dialed = true;
+                }
+                res = &mut service, if !service_dialed => {
+                    res.unwrap();
+                    service_dialed = true;
+                }
+                res = &mut unanswered, if !timed_out => {
+                    assert!(matches!(res, Err(Error::HandshakeTimeout)));
+                    timed_out = true;
+                }
+                _ = next_event(&mut dialer) => {}
+                event = next_event(&mut listener) => match event {
+                    TransportEvent::NewAddress { listener_id, listen_addr } => {
+                        new_addresses.push((listener_id, listen_addr));
+                    }
+                    TransportEvent::Incoming { listener_id, local_addr, upgrade, .. } => {
+                        assert!(incoming.insert(listener_id, (local_addr, upgrade)).is_none());
+                    }
+                    _ => {}
+                },
+            }
+        }
+
+        // each dial is reported on the listener of the service it was to
+        assert!(new_addresses.contains(&(service_id, service_addr(80))));
+        assert_eq!(incoming[&plain_id].0, *listener.listen_addr());
+        assert_eq!(incoming[&service_id].0, service_addr(80));
+
+        // and a removed service listener takes its address with it
+        assert!(listener.remove_listener(service_id));
+        let event = next_event(&mut listener).await;
+        assert!(matches!(
+            event,
+            TransportEvent::AddressExpired { listener_id, listen_addr }
+                if listener_id == service_id && listen_addr == service_addr(80)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_nonce_zero_before_challenge_response_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let mut listener = testing::transport(&mixnet, NymTransportConfig::default()).await;
+        let listener_address = listener.local_nym_address();
+        let mut dropped = Box::pin(listener.events().filter_map(|event| async move {
+            match event {
+                NymEvent::MessageDropped { reason } => Some(reason),
+                _ => None,
+            }
+        }));
+
+        let dialer_key = Keypair::generate_ed25519();
+        let id = ConnectionId::generate();
+        let mut dialer = mixnet.client();
+        let sender = dialer.sender();
+        let sender = sender.as_ref();
+        let send = |message: Message| async move {
+            sender
+                .send(
+                    listener_address,
+                    &WireCodec::encode(&message),
+                    IncludedSurbs::Amount(10),
+                )
+                .await
+                .unwrap()
+        };
+        send(Message::ConnectionRequest(connection_request(
+            &dialer_key,
+            &id,
+        )))
+        .await;
+        assert_eq!(
+            poll_incoming(&mut listener, Duration::from_millis(100)).await,
+            0
+        );
+        let responses = recv_all(&mut dialer).await;
+        let Message::ConnectionResponse(response) =
+            WireCodec::decode(responses[0].message.clone().into()).unwrap()
+        else {
+            panic!("expected Message::ConnectionResponse");
+        };
+
+        // a TransportMessage with the connection message's nonce, sent while the
+        // challenge is outstanding, is dropped rather than handed to the connection
+        send(Message::TransportMessage(TransportMessage {
+            nonce: Nonce::default(),
+            id: id.clone(),
+            message: SubstreamMessage::new_with_data(
+                SubstreamId::generate(),
+                Bytes::from_static(b"hello"),
+            ),
+            timestamp: None,
+        }))
+        .await;
+        assert_eq!(
+            poll_incoming(&mut listener, Duration::from_millis(100)).await,
+            0
+        );
+        assert_eq!(dropped.next().await, Some(DropReason::Replayed));
+
+        // so the connection is still handed to the swarm once the challenge is answered
+        let signature = sign_challenge(&dialer_key, &id, &response.challenge.unwrap()).unwrap();
+        send(Message::ChallengeResponse(ChallengeResponseMessage {
+            id: id.clone(),
+            signature,
+        }))
+        .await;
+        assert_eq!(
+            poll_incoming(&mut listener, Duration::from_millis(100)).await,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transport_message_for_unknown_connection_over_in_memory_mixnet() {
+        let mixnet = InMemoryMixnet::new(InMemoryConfig::default());
+        let mut listener = testing::transport(&mixnet, NymTransportConfig::default()).await;
+
+        // messages on IDs the listener never accepted are dropped, without a queue
+        // being made for them
+        let sender = mixnet.client().sender();
+        for nonce in [0, 1, 2] {
+            let message = Message::TransportMessage(TransportMessage {
+                nonce: Nonce::new(nonce),
+                id: ConnectionId::generate(),
+                message: SubstreamMessage::new_with_data(
+                    SubstreamId::generate(),
+                    Bytes::from_static(b"hello"),
+                ),
+                timestamp: None,
+            });
+            sender
+                .send(
+                    listener.local_nym_address(),
+                    &WireCodec::encode(&message),
+                    IncludedSurbs::Amount(1),
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            poll_incoming(&mut listener, Duration::from_millis(100)).await,
+            0
+        );
+        assert!(listener.message_queues.is_empty());
+    }
 }
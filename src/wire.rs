@@ -0,0 +1,77 @@
+//! The transport's wire format, for tools that read or write its messages without
+//! running a transport, e.g. a proxy in front of a nym client, or a dissector for
+//! recorded traffic. Messages are decoded and encoded with the codec the crate was
+//! built with (see the `borsh-codec` feature), and are otherwise opaque: [`Frame`]
+//! only exposes what's needed to tell them apart and route them.
+
+use bytes::Bytes;
+
+use super::codec::{decode_inbound, Codec, WireCodec};
+use super::error::Error;
+use super::message::Message;
+
+pub use super::message::test_vectors;
+pub use super::message::{ConnectionInfo, SubstreamId};
+
+/// PROTOCOL_VERSION is the version of the wire format this crate speaks, sent at the
+/// start of every connection request and response.
+pub const PROTOCOL_VERSION: u8 = super::message::PROTOCOL_VERSION;
+
+/// Frame is a single message, as sent in the payload of a mixnet packet.
+#[derive(Debug)]
+pub struct Frame(Message);
+
+impl Frame {
+    /// decode decodes a message received from the mixnet, rejecting messages larger
+    /// than `max_size` bytes before looking at them. A connection request or response
+    /// of another protocol version decodes as a `version_mismatch`.
+    pub fn decode(data: Bytes, max_size: usize) -> Result<Self, Error> {
+        decode_inbound::<WireCodec>(data, None, max_size).map(|msg| Frame(msg.0))
+    }
+
+    /// encode encodes the message as it's sent to the mixnet.
+    pub fn encode(&self) -> Bytes {
+        WireCodec::encode(&self.0)
+    }
+
+    /// kind returns a short name for the type of the message, e.g. `ack` or
+    /// `connection_request`; a message carrying substream data is named for that,
+    /// e.g. `data` or `window_update`.
+    pub fn kind(&self) -> &'static str {
+        self.0.kind()
+    }
+
+    /// connection_id returns the ID of the connection the message belongs to.
+    pub fn connection_id(&self) -> [u8; 32] {
+        let mut id = [0u8; 32];
+        id.copy_from_slice(self.0.connection_id().as_bytes());
+        id
+    }
+}
+
+#[cfg(all(test, not(feature = "borsh-codec")))]
+mod test {
+    use super::super::config::DEFAULT_MAX_MESSAGE_SIZE;
+    use super::test_vectors::{test_vectors, CONNECTION_ID};
+    use super::*;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        for vector in test_vectors() {
+            let bytes = Bytes::from(hex::decode(&vector.hex).unwrap());
+            // our own version mismatch is only readable by peers of other versions
+            let Ok(frame) = Frame::decode(bytes.clone(), DEFAULT_MAX_MESSAGE_SIZE) else {
+                assert_eq!(vector.name, "version_mismatch");
+                continue;
+            };
+            assert_eq!(frame.encode(), bytes, "{}", vector.name);
+            assert_eq!(frame.connection_id(), CONNECTION_ID, "{}", vector.name);
+        }
+
+        let too_large = Bytes::from(vec![0u8; 64]);
+        assert!(matches!(
+            Frame::decode(too_large, 32),
+            Err(Error::InboundMessageTooLarge(64))
+        ));
+    }
+}
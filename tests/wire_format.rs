@@ -0,0 +1,147 @@
+//! Golden copies of the wire format test vectors. If a change to the wire format breaks
+//! them, either it's a mistake, or the protocol version has to be bumped and these
+//! regenerated from `rust_libp2p_nym::wire::test_vectors::test_vectors()`.
+
+use rust_libp2p_nym::wire::test_vectors::{roundtrip, test_vectors};
+
+const GOLDEN: &[(&str, &str)] = &[
+    (
+        "connection_request",
+        "000800000001000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f0042424242424242424242424242424242424242424242424242424242424242420000018bcfe56800002408011220ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c00404acb20f720cd513483979d66ef98cec92dd8791a40d2bda97b8ddabdf646e95de026b0549e491a2d669cf2f39e52be7b30d903d6464e2bdb09d14f40efecfc0026002408011220ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c0000001000000010746573742d766563746f72732f312e3002056578742d61056578742d62",
+    ),
+    (
+        "connection_response",
+        "010800000001000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f0042424242424242424242424242424242424242424242424242424242424242420000018bcfe56800002408011220ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c00404acb20f720cd513483979d66ef98cec92dd8791a40d2bda97b8ddabdf646e95de026b0549e491a2d669cf2f39e52be7b30d903d6464e2bdb09d14f40efecfc0026002408011220ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c01404142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f0000100000000000",
+    ),
+    (
+        "version_mismatch",
+        "010800000000000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+    ),
+    (
+        "transport_open_request",
+        "020000000000000001000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f0000040000",
+    ),
+    (
+        "transport_open_response",
+        "020000000000000002000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f0100010000",
+    ),
+    (
+        "transport_data",
+        "020000000000000003000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f0368656c6c6f",
+    ),
+    (
+        "transport_fragment",
+        "020000000000000004000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f04000000090001000366726167",
+    ),
+    (
+        "transport_window_update",
+        "020000000000000005000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f050000000000080000",
+    ),
+    (
+        "transport_close",
+        "020000000000000006000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f02",
+    ),
+    (
+        "keepalive_ping",
+        "03000000000000000001000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+    ),
+    (
+        "keepalive_pong",
+        "03010000000000000001000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+    ),
+    (
+        "ack",
+        "040000000000000003000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+    ),
+    (
+        "datagram_request",
+        "0500000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f70696e67",
+    ),
+    (
+        "datagram_response",
+        "0501000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f706f6e67",
+    ),
+    (
+        "batch",
+        "06000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f002a0000000000000007202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f036100290000000000000008202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f02",
+    ),
+    (
+        "connection_close",
+        "07000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+    ),    (
+        "stamped_transport_open_request",
+        "080000018bcfe568000000000000000001000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f0000040000",
+    ),
+    (
+        "stamped_keepalive_ping",
+        "090000018bcfe56800000000000000000001000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+    ),
+    (
+        "transport_datagram",
+        "020000000000000000000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f00000000000000000000000000000000000000000000000000000000000000000662656174",
+    ),
+    (
+        "migrate",
+        "0a000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f0000000000000001f92b56ea1ebfc348ad0520a27767fd3ecc6d48250f313f21cea924327843e0d118dcd9a1f60a66c8b0673de906221a2043b4687837d7382638364362298505219173d1e8947f139a27eff083a75116f74c58c17d044646f7b2d8a4ed1821bb0a0040121b5a3f3e8c52f2de6c321ca4069ed493500b778b46fc034332d878152edba9f6d8696e80f71e60a56f4b8bacd6b4c1280ce9899d15bb0e96efbb963bad9c0d",
+    ),
+    (
+        "challenge_response",
+        "0b000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f00403d73e5dc74a69a04e6639332287bc24c8820103e51595b80ebf3f23f2ec890164177fcb331e0e606bdb960e3a583b6a727d98d8f8744ee752159437acf6c250f",
+    ),
+    (
+        "session_ticket",
+        "0c000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f00157365616c65642073657373696f6e207469636b6574",
+    ),
+    (
+        "resume_request",
+        "0d000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f0000018bcfe56800606162636465666768696a6b6c6d6e6f707172737475767778797a7b7c7d7e7f8263fa9722d6120c96bffd032486c324803f82d591686ec047df52ec79967b3000157365616c65642073657373696f6e207469636b6574",
+    ),
+    (
+        "resume_response",
+        "0e000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f6c6ef15ad7e4938b4844b0288d35f07473a58909156c76febeea5f6fd59dca75",
+    ),
+    (
+        "relay_reserve",
+        "0f000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f002408011220ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c004093962a37eff3abab0bf0efcc72d44031cf1ccf0ac5d72a36d293001d35699622089d986699f99a84637a471f27d1af83d92c3836102f4f6e8e86ea17981f9d02",
+    ),
+    (
+        "relay_status",
+        "10000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f0000000e10",
+    ),
+    (
+        "relay_to_listener",
+        "11000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f000026002408011220ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c07000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+    ),
+    (
+        "relay_to_dialer",
+        "11000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f0107000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+    ),
+    (
+        "relay_delivered",
+        "11000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f02b2849e728e3eb226d2d864963f8ca9f5ec81c92558a9f263bdbea29d34edf8a07d7e8a80c987c261bd435fda2099b1d714cc6c46f404b54b82c2fc92a67e6612e371277338835b77367d464fc87d5cbe735b44c29cca0ae3633ca54d92733e9907000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+    ),
+];
+
+#[test]
+fn test_vectors_match_golden() {
+    let vectors = test_vectors();
+    assert_eq!(vectors.len(), GOLDEN.len());
+    for (vector, (name, hex)) in vectors.iter().zip(GOLDEN) {
+        assert_eq!(vector.name, *name);
+        assert_eq!(vector.hex, *hex, "encoding of {} changed", name);
+    }
+}
+
+#[test]
+fn test_golden_vectors_roundtrip() {
+    for (name, hex) in GOLDEN {
+        // a rejection of our own version is only read by peers of other versions
+        if *name == "version_mismatch" {
+            continue;
+        }
+        let bytes = hex::decode(hex).unwrap();
+        let encoded =
+            roundtrip(&bytes).unwrap_or_else(|e| panic!("{} failed to decode: {}", name, e));
+        assert_eq!(encoded, bytes, "{} changed when re-encoded", name);
+    }
+}